@@ -0,0 +1,130 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::ParseHashError;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// The expected checksum of a fetched artifact, such as a `.crate` file or an npm tarball.
+///
+/// Serialized as `<algorithm>:<hex>`, for example `sha256:0123...`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Checksum {
+    Sha256(Sha256Hash),
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checksum::Sha256(hash) => write!(f, "{}{}", Sha256Hash::PREFIX, hash),
+        }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(Sha256Hash::PREFIX) {
+            Some(hash) => hash.parse().map(Checksum::Sha256),
+            None => Err(ParseHashError {
+                description: "checksum",
+                input: s.into(),
+                err: "checksum prefix unrecognized".into(),
+            }),
+        }
+    }
+}
+
+impl Serialize for Checksum {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+/// A SHA-256 hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sha256Hash {
+    bytes: [u8; Self::BYTES],
+}
+
+impl Sha256Hash {
+    /// The prefix used while serializing a hash.
+    pub const PREFIX: &'static str = "sha256:";
+
+    /// The width of this hash, in bytes.
+    pub const BYTES: usize = 32;
+
+    /// Creates a new `Sha256Hash` from big-endian bytes.
+    #[inline]
+    pub fn from_be_bytes(bytes: [u8; Self::BYTES]) -> Self {
+        Self { bytes }
+    }
+
+    /// Returns a big-endian representation.
+    #[inline]
+    pub fn to_be_bytes(&self) -> [u8; Self::BYTES] {
+        self.bytes
+    }
+
+    /// The prefix for this hash as a [`FileHash`](crate::FileHash) in the database.
+    pub(crate) const DB_PREFIX: [u8; 2] = *b"02";
+
+    const DESCRIPTION: &'static str = "sha256 hash";
+}
+
+hash_impls!(Sha256Hash, sha256_hash);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn checksum_basic() {
+        const CHECKSUM_STR: &str =
+            "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let checksum: Checksum = CHECKSUM_STR.parse().expect("checksum parsed");
+        assert_eq!(checksum.to_string(), CHECKSUM_STR);
+
+        let serialized = serde_json::to_string(&checksum).expect("serialization succeeded");
+        assert_eq!(serialized, format!("\"{}\"", CHECKSUM_STR));
+
+        for invalid in [
+            "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+            "md5:0123456789abcdef0123456789abcdef",
+            "sha256:0123",
+            "sha256:0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF",
+        ] {
+            assert!(
+                invalid.parse::<Checksum>().is_err(),
+                "{} is an invalid checksum",
+                invalid
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn checksum_to_string_roundtrip(bytes: [u8; Sha256Hash::BYTES]) {
+            let checksum = Checksum::Sha256(Sha256Hash::from_be_bytes(bytes));
+            let to_string = checksum.to_string();
+            let roundtrip = to_string.parse().expect("to_string roundtrip succeeded");
+            assert_eq!(checksum, roundtrip, "checksum matches display roundtrip");
+        }
+    }
+}
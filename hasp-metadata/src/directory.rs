@@ -2,8 +2,10 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{DirectoryHash, DirectoryVersion};
+use camino::Utf8PathBuf;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// Information about a directory installation for a single package.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -28,11 +30,116 @@ pub struct PackageDirectory {
 }
 
 /// Specific information associated with Cargo.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct CargoDirectory {
     /// Whether default features were requested.
     pub default_features: bool,
+
+    /// Features that were requested, in addition to default features.
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+
+    /// The Cargo profile the package was built with. `None` means the release profile.
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// The index URL of the registry the package was installed from. `None` means crates.io.
+    #[serde(default)]
+    pub registry: Option<String>,
+
+    /// The binaries that were requested. Empty means all binaries in the package.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub bins: BTreeSet<String>,
+
+    /// Whether examples were installed as well as binaries.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub examples: bool,
+
+    /// Environment variables set for the build that affect the built artifacts, e.g. `RUSTFLAGS`.
+    ///
+    /// Where directories are recorded, values are replaced by hashes of them (see
+    /// [`CargoDirectory::redacted`]).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+
+    /// Values passed to Cargo with `--config`, e.g. `profile.release.lto=true`, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub config: Vec<String>,
+
+    /// Extra arguments passed to `cargo build`, e.g. `--locked`, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+
+    /// The local directory the package was built from, through `hasp install --path`. `None`
+    /// means it was fetched from a registry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<Utf8PathBuf>,
+}
+
+impl CargoDirectory {
+    /// Returns this directory as it's recorded, with the values of environment variables replaced
+    /// by hashes of them. Values may be secrets such as tokens, so they aren't stored.
+    pub fn redacted(&self) -> Self {
+        Self {
+            env: redact_env(&self.env),
+            ..self.clone()
+        }
+    }
+}
+
+/// The prefix of environment variable values that have been replaced by hashes of them.
+pub const REDACTED_ENV_PREFIX: &str = "blake3:";
+
+/// Replaces the value of each environment variable with a hash of it. Values that were redacted
+/// already are left alone.
+pub fn redact_env(env: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    env.iter()
+        .map(|(name, value)| (name.clone(), redact_env_value(value)))
+        .collect()
+}
+
+/// Replaces the value of an environment variable with a hash of it, unless it was redacted
+/// already.
+pub fn redact_env_value(value: &str) -> String {
+    if is_redacted_env_value(value) {
+        value.to_owned()
+    } else {
+        format!(
+            "{}{}",
+            REDACTED_ENV_PREFIX,
+            blake3::hash(value.as_bytes()).to_hex()
+        )
+    }
+}
+
+/// Returns true if this value of an environment variable was replaced by a hash of it.
+pub fn is_redacted_env_value(value: &str) -> bool {
+    matches!(
+        value.strip_prefix(REDACTED_ENV_PREFIX),
+        Some(hash) if hash.len() == blake3::OUT_LEN * 2 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+    )
+}
+
+#[inline]
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 //
 // json_impls!(CargoDirectory);
+
+/// Specific information associated with npm.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NpmDirectory {
+    /// Whether package lifecycle scripts are skipped during installation.
+    pub ignore_scripts: bool,
+}
+
+/// Specific information associated with container images.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OciDirectory {
+    /// The name of the generated wrapper script.
+    pub binary: String,
+}
@@ -15,6 +15,12 @@ pub enum DirectoryVersion {
     ///
     /// This can be any sort of arbitrary byte sequence.
     Literal(String),
+
+    /// A floating version like a `latest` tag, along with the literal version it resolved to.
+    ///
+    /// Requirements are compared against the floating part, so the same requirement keeps
+    /// matching even after it's been re-resolved to a different literal version.
+    Floating(FloatingVersion),
 }
 
 impl DirectoryVersion {
@@ -24,6 +30,9 @@ impl DirectoryVersion {
     /// The prefix used while serializing literal versions.
     pub const LIT_PREFIX: &'static str = "lit:";
 
+    /// The prefix used while serializing floating versions.
+    pub const FLT_PREFIX: &'static str = "flt:";
+
     /// Creates a new semantic version.
     #[inline]
     pub fn new_semantic(version: semver::Version) -> Self {
@@ -36,6 +45,15 @@ impl DirectoryVersion {
         DirectoryVersion::Literal(version.into())
     }
 
+    /// Creates a new floating version, resolved to the given literal version.
+    #[inline]
+    pub fn new_floating(spec: impl Into<String>, resolved: impl Into<String>) -> Self {
+        DirectoryVersion::Floating(FloatingVersion {
+            spec: spec.into(),
+            resolved: resolved.into(),
+        })
+    }
+
     /// Returns the semantic version.
     pub fn as_semantic(&self) -> Option<&semver::Version> {
         match self {
@@ -52,6 +70,14 @@ impl DirectoryVersion {
         }
     }
 
+    /// Returns the floating version.
+    pub fn as_floating(&self) -> Option<&FloatingVersion> {
+        match self {
+            DirectoryVersion::Floating(v) => Some(v),
+            _ => None,
+        }
+    }
+
     /// Returns an implementation of `Display` that prints out just the version number or string.
     ///
     /// This is not the default because `FromStr` and `Display` roundtrip.
@@ -59,6 +85,7 @@ impl DirectoryVersion {
         match self {
             DirectoryVersion::Semantic(version) => version,
             DirectoryVersion::Literal(version) => version,
+            DirectoryVersion::Floating(version) => version,
         }
     }
 }
@@ -68,6 +95,13 @@ impl fmt::Display for DirectoryVersion {
         match self {
             DirectoryVersion::Semantic(version) => write!(f, "{}{}", Self::SEM_PREFIX, version),
             DirectoryVersion::Literal(version) => write!(f, "{}{}", Self::LIT_PREFIX, version),
+            DirectoryVersion::Floating(version) => write!(
+                f,
+                "{}{}@{}",
+                Self::FLT_PREFIX,
+                version.spec,
+                version.resolved
+            ),
         }
     }
 }
@@ -84,10 +118,20 @@ impl FromStr for DirectoryVersion {
             Ok(DirectoryVersion::Semantic(version))
         } else if let Some(v) = s.strip_prefix("lit:") {
             Ok(DirectoryVersion::Literal(v.into()))
+        } else if let Some(v) = s.strip_prefix("flt:") {
+            match v.split_once('@') {
+                Some((spec, resolved)) if !spec.is_empty() => {
+                    Ok(DirectoryVersion::new_floating(spec, resolved))
+                }
+                _ => Err(ParseDirectoryVersionError {
+                    input: s.into(),
+                    err: Either::Right("floating version must be of the form 'spec@resolved'"),
+                }),
+            }
         } else {
             Err(ParseDirectoryVersionError {
                 input: s.into(),
-                err: Either::Right("input begins with neither 'sem:' nor 'lit:'"),
+                err: Either::Right("input begins with none of 'sem:', 'lit:' or 'flt:'"),
             })
         }
     }
@@ -112,6 +156,36 @@ impl<'de> Deserialize<'de> for DirectoryVersion {
     }
 }
 
+/// A floating version, like a `latest` tag or a release channel, and the literal version it
+/// resolved to when it was installed. Part of [`DirectoryVersion`].
+///
+/// Displays as the floating part followed by the resolved version, e.g. `latest (sha256:...)`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FloatingVersion {
+    spec: String,
+    resolved: String,
+}
+
+impl FloatingVersion {
+    /// Returns the floating part, which is re-resolved on update.
+    #[inline]
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+
+    /// Returns the literal version that the floating part resolved to.
+    #[inline]
+    pub fn resolved(&self) -> &str {
+        &self.resolved
+    }
+}
+
+impl fmt::Display for FloatingVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.spec, self.resolved)
+    }
+}
+
 #[cfg(feature = "rusqlite")]
 mod rusqlite_impls {
     use super::*;
@@ -187,6 +261,21 @@ mod tests {
         // TODO: also test literal versions
     }
 
+    #[test]
+    fn directory_version_floating() {
+        const FLOATING_STR: &str = "flt:latest@sha256:abcd";
+
+        let version: DirectoryVersion = FLOATING_STR.parse().expect("floating version parsed");
+        let floating = version.as_floating().expect("version is floating");
+        assert_eq!(floating.spec(), "latest");
+        assert_eq!(floating.resolved(), "sha256:abcd");
+        assert_eq!(version.to_string(), FLOATING_STR);
+        assert_eq!(version.short_display().to_string(), "latest (sha256:abcd)");
+
+        assert!("flt:latest".parse::<DirectoryVersion>().is_err());
+        assert!("flt:@sha256:abcd".parse::<DirectoryVersion>().is_err());
+    }
+
     impl Arbitrary for DirectoryVersion {
         type Parameters = ();
         type Strategy = BoxedStrategy<Self>;
@@ -213,9 +302,13 @@ mod tests {
                 },
             );
 
+            let floating_strategy = (VERSION_REGEX, VERSION_REGEX)
+                .prop_map(|(spec, resolved)| DirectoryVersion::new_floating(spec, resolved));
+
             prop_oneof![
                 VERSION_REGEX.prop_map(DirectoryVersion::Literal),
-                semver_strategy
+                semver_strategy,
+                floating_strategy,
             ]
             .boxed()
         }
@@ -0,0 +1,399 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    DirectoryVersion, InstallFailed, InstallStarted, InstallSuccess, LifecycleEvent,
+    PackageDirectory, UninstallSuccess,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// The version of the event schema written by this version of hasp.
+///
+/// This is bumped whenever the payload of an event changes incompatibly. Events recorded with
+/// older versions are upgraded when they're read, in [`EventEnvelope::from_journal`].
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// An event recorded in the journal, along with the version of the schema it was recorded with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EventEnvelope {
+    /// The version of the schema the event was recorded with. Events recorded before the schema
+    /// was versioned have version 0.
+    pub schema_version: u32,
+
+    /// The time at which the event was recorded.
+    pub event_time: DateTime<Local>,
+
+    /// The ID of the run of hasp that recorded the event, shared by every event it recorded.
+    /// Events recorded before runs had IDs don't have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+
+    /// The event, upgraded to the current schema.
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl EventEnvelope {
+    /// Parses an event from the columns of the journal, upgrading its payload to the current
+    /// schema.
+    pub fn from_journal(
+        schema_version: u32,
+        event_name: &str,
+        event_time: DateTime<Local>,
+        run_id: Option<String>,
+        data: &str,
+    ) -> Result<Self, serde_json::Error> {
+        let data: Value = serde_json::from_str(data)?;
+        let data = upgrade_payload(schema_version, event_name, data);
+        let event = serde_json::from_value(serde_json::json!({
+            "event": event_name,
+            "data": data,
+        }))?;
+        Ok(Self {
+            schema_version,
+            event_time,
+            run_id,
+            event,
+        })
+    }
+}
+
+/// Upgrades the payload of an event recorded with an older schema to the current schema.
+///
+/// Version 1 only added versioning, so no payloads need upgrading yet. Events from newer versions
+/// of hasp are parsed as they are, which works as long as their payloads only gained fields.
+fn upgrade_payload(_schema_version: u32, _event_name: &str, data: Value) -> Value {
+    data
+}
+
+/// An event recorded in the journal.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "data")]
+pub enum Event {
+    /// An installation was started.
+    InstallStarted(InstallStarted),
+
+    /// An installation succeeded.
+    InstallSuccess(InstallSuccess),
+
+    /// An installation failed.
+    InstallFailed(InstallFailed),
+
+    /// A package was uninstalled.
+    UninstallSuccess(UninstallSuccess),
+
+    /// Binaries were removed because a new install of a package doesn't have them.
+    BinariesRemoved(BinariesRemoved),
+
+    /// Binaries of a package being installed were already installed by other packages.
+    BinaryConflict(BinaryConflict),
+
+    /// A database migration was started.
+    MigrationStarted(MigrationEvent),
+
+    /// A database migration was applied.
+    MigrationFinished(MigrationEvent),
+
+    /// A database migration failed, and the migrations applied before it were rolled back.
+    MigrationRollback(MigrationRollback),
+
+    /// A database migration was rolled back through `hasp db migrate`.
+    MigrationReverted(MigrationEvent),
+
+    /// Events were removed from the journal.
+    EventsPruned(EventsPruned),
+
+    /// A source was approved.
+    SourceTrusted(SourceTrusted),
+
+    /// The approval for a source was removed.
+    SourceUntrusted(SourceUntrusted),
+
+    /// A hook configured in `[hooks]` was run.
+    HookFinished(HookFinished),
+
+    /// hasp replaced its own binary with a newer version.
+    SelfUpdated(SelfUpdated),
+
+    /// A run of hasp that installs packages started.
+    RunStarted(RunStarted),
+
+    /// A run of hasp that installs packages finished.
+    RunFinished(RunFinished),
+}
+
+impl Event {
+    /// Returns the name this event is recorded under in the journal.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::InstallStarted(_) => "install_started",
+            Event::InstallSuccess(_) => "install_success",
+            Event::InstallFailed(_) => "install_failed",
+            Event::UninstallSuccess(_) => "uninstall_success",
+            Event::BinariesRemoved(_) => "binaries_removed",
+            Event::BinaryConflict(_) => "binary_conflict",
+            Event::MigrationStarted(_) => "migration_started",
+            Event::MigrationFinished(_) => "migration_finished",
+            Event::MigrationRollback(_) => "migration_rollback",
+            Event::MigrationReverted(_) => "migration_reverted",
+            Event::EventsPruned(_) => "events_pruned",
+            Event::SourceTrusted(_) => "source_trusted",
+            Event::SourceUntrusted(_) => "source_untrusted",
+            Event::HookFinished(_) => "hook_finished",
+            Event::SelfUpdated(_) => "self_updated",
+            Event::RunStarted(_) => "run_started",
+            Event::RunFinished(_) => "run_finished",
+        }
+    }
+
+    /// Returns the payload of this event, as it's recorded in the journal.
+    pub fn payload(&self) -> Result<Value, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        Ok(value
+            .get_mut("data")
+            .map(Value::take)
+            .unwrap_or(Value::Null))
+    }
+}
+
+impl From<LifecycleEvent> for Event {
+    fn from(event: LifecycleEvent) -> Self {
+        match event {
+            LifecycleEvent::InstallStarted(event) => Event::InstallStarted(event),
+            LifecycleEvent::InstallSuccess(event) => Event::InstallSuccess(event),
+            LifecycleEvent::InstallFailed(event) => Event::InstallFailed(event),
+            LifecycleEvent::UninstallSuccess(event) => Event::UninstallSuccess(event),
+        }
+    }
+}
+
+/// Binaries that an earlier install of a package had, but a new install of it doesn't.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BinariesRemoved {
+    /// The package that was installed.
+    pub package: PackageDirectory,
+
+    /// The names of the binaries whose records and shims were removed.
+    pub binaries: Vec<String>,
+}
+
+/// Binaries of a package being installed that other installed packages already have.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BinaryConflict {
+    /// The package being installed.
+    pub package: PackageDirectory,
+
+    /// The conflicting binaries, along with the packages that have them, like `npm:prettier`.
+    pub conflicts: BTreeMap<String, Vec<String>>,
+
+    /// Whether the binaries were overwritten, through `--force-bin-overwrite`. If they weren't,
+    /// the install failed.
+    pub overwritten: bool,
+}
+
+/// A database migration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MigrationEvent {
+    /// The name of the migration.
+    pub name: String,
+}
+
+/// Migrations that were rolled back because a later migration failed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MigrationRollback {
+    /// The names of the migrations that were rolled back, in the order they were applied.
+    pub rolled_back: Vec<String>,
+}
+
+/// Events that were removed from the journal.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EventsPruned {
+    /// The number of events removed for being older than the maximum age.
+    pub pruned_by_age: usize,
+
+    /// The number of events removed for being over the maximum number of rows.
+    pub pruned_by_rows: usize,
+
+    /// The number of events left in the journal.
+    pub remaining: u64,
+
+    /// Whether the events database was vacuumed to reclaim space.
+    pub vacuumed: bool,
+}
+
+/// A source that was approved.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SourceTrusted {
+    /// The source, e.g. `cargo:crates.io`.
+    pub source: String,
+
+    /// How the source was approved: `prompt` or `command`.
+    pub approval: String,
+}
+
+/// A source whose approval was removed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SourceUntrusted {
+    /// The source, e.g. `cargo:crates.io`.
+    pub source: String,
+}
+
+/// A hook that was run.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HookFinished {
+    /// The point at which the hook was run, e.g. `post-install`.
+    pub point: String,
+
+    /// The package the hook was run for, e.g. `ripgrep` or `npm:prettier`.
+    pub package: String,
+
+    /// A description of the hook: its command line, or the built-in action it performed.
+    pub hook: String,
+
+    /// Whether the hook succeeded.
+    pub success: bool,
+
+    /// The exit code of the hook's command, if it ran and exited normally.
+    pub exit_code: Option<i32>,
+
+    /// The error the hook failed with, if it failed.
+    pub error: Option<String>,
+
+    /// Whether the failure of this hook aborted the install.
+    pub aborted: bool,
+}
+
+/// An update of hasp itself, through `hasp self update`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SelfUpdated {
+    /// The version that was running.
+    pub from: String,
+
+    /// The version hasp was updated to.
+    pub to: String,
+
+    /// The path to the binary that was replaced.
+    pub path: String,
+}
+
+/// The start of a run of hasp that installs packages. The events the run records share its run ID.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RunStarted {
+    /// The version of hasp.
+    pub hasp_version: String,
+
+    /// The command-line arguments hasp was run with, not including the program name. Values of
+    /// environment variables set with `--env` are replaced by hashes of them.
+    pub args: Vec<String>,
+}
+
+/// The end of a run of hasp that installs packages.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RunFinished {
+    /// The command-line arguments hasp was run with, not including the program name. Values of
+    /// environment variables set with `--env` are replaced by hashes of them.
+    pub args: Vec<String>,
+
+    /// The exit code, or `None` if hasp exited with an error.
+    pub exit_code: Option<i32>,
+
+    /// The error hasp exited with, if any.
+    pub error: Option<String>,
+
+    /// The result for each package the run installed or tried to install.
+    pub packages: Vec<PackageResult>,
+}
+
+/// The result of installing a single package, as recorded in [`RunFinished`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PackageResult {
+    /// The package, e.g. `ripgrep` or `npm:prettier`.
+    pub name: String,
+
+    /// The version that was installed or attempted, if one was resolved.
+    pub version: Option<DirectoryVersion>,
+
+    /// What happened to the package.
+    pub outcome: PackageOutcome,
+
+    /// The error the install failed with, or why the package was skipped.
+    pub error: Option<String>,
+}
+
+/// What happened to a package in a run of hasp.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageOutcome {
+    Installed,
+    AlreadyInstalled,
+    Failed,
+    Skipped,
+}
+
+impl PackageOutcome {
+    /// Returns the name of this outcome, as it's serialized.
+    pub fn name(self) -> &'static str {
+        match self {
+            PackageOutcome::Installed => "installed",
+            PackageOutcome::AlreadyInstalled => "already-installed",
+            PackageOutcome::Failed => "failed",
+            PackageOutcome::Skipped => "skipped",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_roundtrip() {
+        let event = Event::SourceTrusted(SourceTrusted {
+            source: "oci:ghcr.io".to_owned(),
+            approval: "prompt".to_owned(),
+        });
+        let payload = event.payload().expect("payload serialized");
+        assert_eq!(
+            payload,
+            serde_json::json!({ "source": "oci:ghcr.io", "approval": "prompt" })
+        );
+
+        // Events recorded before versioning have the same payloads.
+        let envelope =
+            EventEnvelope::from_journal(0, event.name(), Local::now(), None, &payload.to_string())
+                .expect("event parsed");
+        assert!(matches!(
+            &envelope.event,
+            Event::SourceTrusted(trusted) if trusted.source == "oci:ghcr.io"
+        ));
+
+        let json = serde_json::to_value(&envelope).expect("envelope serialized");
+        assert_eq!(json["schema-version"], 0);
+        assert_eq!(json["event"], "source_trusted");
+        assert_eq!(json["data"]["approval"], "prompt");
+        assert!(json.get("run-id").is_none());
+        let parsed: EventEnvelope = serde_json::from_value(json).expect("envelope parsed");
+        assert_eq!(parsed.event.name(), "source_trusted");
+
+        assert!(
+            EventEnvelope::from_journal(1, "no_such_event", Local::now(), None, "{}").is_err(),
+            "unknown events are rejected"
+        );
+    }
+}
@@ -4,7 +4,12 @@
 use crate::PackageDirectory;
 use camino::Utf8PathBuf;
 use chrono::{DateTime, Local};
+use semver::Version;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
 
 /// A message generated by hasp when an installation is started.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -25,6 +30,9 @@ pub struct InstallStarted {
     /// If this is an update to the same directory, the full path to the temporary directory to
     /// which the previous install will been moved.
     pub old_dir: Utf8PathBuf,
+
+    /// The full path to the log that the output of build processes is written to.
+    pub build_log: Utf8PathBuf,
     // TODO: namespace-specific metadata
 }
 
@@ -43,9 +51,77 @@ pub struct InstallSuccess {
 
     /// The time at which the installation process ended.
     pub end_time: DateTime<Local>,
+
+    /// How long each phase of the install took. `None` for installs recorded before this was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<InstallTimings>,
     // TODO: more specific metadata
 }
 
+/// How long each phase of an install took, in milliseconds.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct InstallTimings {
+    /// Looking up the version to install. Zero if the version was already known, e.g. for
+    /// `--crate-file`.
+    pub resolve_ms: u64,
+
+    /// Fetching the package, and verifying its checksum and signature.
+    pub fetch_ms: u64,
+
+    /// Building the package, or copying in a build of it.
+    pub build_ms: u64,
+
+    /// Recording the install and moving it into place.
+    pub finish_ms: u64,
+
+    /// Where the build of the package came from.
+    pub build_source: BuildSource,
+}
+
+impl InstallTimings {
+    /// Returns the total time across all phases, in milliseconds.
+    pub fn total_ms(&self) -> u64 {
+        self.resolve_ms + self.fetch_ms + self.build_ms + self.finish_ms
+    }
+}
+
+/// Where the build of an installed package came from.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildSource {
+    /// The package was built by this install.
+    #[default]
+    Built,
+
+    /// The build from an earlier, interrupted attempt was reused.
+    Resumed,
+
+    /// A build was copied from the shared store.
+    SharedStore,
+
+    /// A build was downloaded from the remote cache.
+    RemoteCache,
+}
+
+impl BuildSource {
+    /// Returns true if an existing build was reused rather than building the package.
+    pub fn is_reused(self) -> bool {
+        self != BuildSource::Built
+    }
+}
+
+impl fmt::Display for BuildSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildSource::Built => write!(f, "built"),
+            BuildSource::Resumed => write!(f, "resumed"),
+            BuildSource::SharedStore => write!(f, "shared-store"),
+            BuildSource::RemoteCache => write!(f, "remote-cache"),
+        }
+    }
+}
+
 /// An installation process failed.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -64,6 +140,59 @@ pub struct InstallFailed {
 
     /// The reason the install failed.
     pub reason: FailureReason,
+
+    /// The full path to the log that the output of build processes was written to.
+    pub build_log: Utf8PathBuf,
+}
+
+/// A package was uninstalled.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UninstallSuccess {
+    /// Information about the package that was uninstalled.
+    pub package: PackageDirectory,
+
+    /// The time at which the package was uninstalled.
+    pub end_time: DateTime<Local>,
+}
+
+/// A lifecycle event for a package, as recorded in the events journal.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "data")]
+pub enum LifecycleEvent {
+    /// An installation was started.
+    InstallStarted(InstallStarted),
+
+    /// An installation succeeded.
+    InstallSuccess(InstallSuccess),
+
+    /// An installation failed.
+    InstallFailed(InstallFailed),
+
+    /// A package was uninstalled.
+    UninstallSuccess(UninstallSuccess),
+}
+
+impl LifecycleEvent {
+    /// Returns the name this event is recorded under in the journal.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LifecycleEvent::InstallStarted(_) => "install_started",
+            LifecycleEvent::InstallSuccess(_) => "install_success",
+            LifecycleEvent::InstallFailed(_) => "install_failed",
+            LifecycleEvent::UninstallSuccess(_) => "uninstall_success",
+        }
+    }
+
+    /// Returns information about the package this event is for.
+    pub fn package(&self) -> &PackageDirectory {
+        match self {
+            LifecycleEvent::InstallStarted(event) => &event.package,
+            LifecycleEvent::InstallSuccess(event) => &event.package,
+            LifecycleEvent::InstallFailed(event) => &event.package,
+            LifecycleEvent::UninstallSuccess(event) => &event.package,
+        }
+    }
 }
 
 /// The reason a package installation failed.
@@ -72,8 +201,20 @@ pub struct InstallFailed {
 pub enum FailureReason {
     /// The external installation process failed.
     ProcessFailed {
-        /// Metadata associated with the failure.
-        metadata: serde_json::Value,
+        /// Details about the failure.
+        details: FailureDetails,
+    },
+
+    /// Binaries being installed are already installed by other packages.
+    BinaryConflict {
+        /// The conflicting binaries, along with the packages that have them.
+        conflicts: BTreeMap<String, Vec<String>>,
+    },
+
+    /// The installation was cancelled by a termination signal, such as Ctrl-C.
+    Cancelled {
+        /// The stage of the installation at which it was cancelled.
+        stage: FailureStage,
     },
 
     /// The installation process was aborted due to an internal error in hasp.
@@ -82,3 +223,107 @@ pub enum FailureReason {
         metadata: serde_json::Value,
     },
 }
+
+/// A structured representation of an installation failure.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FailureDetails {
+    /// The stage of the installation at which the failure occurred.
+    pub stage: FailureStage,
+
+    /// The chain of error messages, starting from the outermost context and ending at the root
+    /// cause.
+    pub errors: Vec<String>,
+
+    /// The exit code of the external process, if it exited with one.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+
+    /// The last few lines written to standard error by the external process, if any.
+    #[serde(default)]
+    pub stderr_tail: Vec<String>,
+}
+
+/// The stage of an installation at which a failure occurred.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureStage {
+    /// Resolving a version requirement to a version.
+    Resolve,
+
+    /// Fetching the package.
+    Fetch,
+
+    /// Building or installing the package into a temporary directory.
+    Build,
+
+    /// Moving the package into place and recording the installation.
+    Finish,
+}
+
+/// How a Cargo package was built, recorded in its install metadata.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CargoBuildMetadata {
+    /// The version of rustc the package was built with, e.g. `rustc 1.56.0 (09c42c458
+    /// 2021-10-18)`. `None` if it couldn't be determined.
+    #[serde(default)]
+    pub rustc_version: Option<String>,
+
+    /// The version of Cargo the package was built with, e.g. `cargo 1.56.0 (4ed5d137b
+    /// 2021-10-04)`. `None` if it couldn't be determined.
+    #[serde(default)]
+    pub cargo_version: Option<String>,
+
+    /// The target triple of the machine the package was built on, as reported by rustc.
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Whether default features were enabled.
+    #[serde(default)]
+    pub default_features: bool,
+
+    /// Features that were enabled, in addition to default features.
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+
+    /// The Cargo profile the package was built with. `None` means the release profile.
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// A hash of the value of `RUSTFLAGS` the package was built with, if it was set. Packages
+    /// installed by earlier versions of hasp have the value itself.
+    #[serde(default)]
+    pub rustflags: Option<String>,
+
+    /// A hash of the environment variables the build was run with.
+    pub env_hash: String,
+
+    /// Environment variables set explicitly for the build that don't affect the built artifacts,
+    /// with their values replaced by hashes of them.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// The full command line the build was run with.
+    #[serde(default)]
+    pub command: Vec<String>,
+}
+
+/// A dependency a package was built with, as resolved by its lockfile.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LockedDependency {
+    /// The name of the dependency.
+    pub name: String,
+
+    /// The version of the dependency.
+    pub version: Version,
+
+    /// Where the dependency came from, e.g. `registry+https://...`. `None` for path dependencies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// The checksum of the dependency's source, if its source records one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
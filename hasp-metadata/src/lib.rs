@@ -35,15 +35,23 @@ macro_rules! json_impls {
 #[macro_use]
 mod hash;
 
+mod checksum;
 mod directory;
 mod directory_hash;
 mod directory_version;
+mod events;
 mod install;
+mod output;
 mod package;
+mod progress;
 
+pub use checksum::*;
 pub use directory::*;
 pub use directory_hash::*;
 pub use directory_version::*;
+pub use events::*;
 pub use hash::ParseHashError;
 pub use install::*;
+pub use output::*;
 pub use package::*;
+pub use progress::*;
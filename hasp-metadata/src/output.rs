@@ -0,0 +1,208 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The output of hasp's read commands with `--format json`.
+//!
+//! These types are part of hasp's interface: fields may be added to them, but existing fields
+//! aren't renamed or removed.
+
+use crate::{
+    DirectoryVersion, InstallTimings, InstalledPackage, LockedDependency, PackageDirectory,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// The output of `hasp list`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PackageListOutput {
+    /// The installed packages, ordered by namespace and name.
+    pub packages: Vec<InstalledPackage>,
+
+    /// Directories that hasp knows about but that aren't installed, ordered by namespace and
+    /// name. Only listed with `--all`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_installed: Vec<NotInstalledDirectory>,
+}
+
+/// A directory that isn't installed, e.g. because installing it failed or it was rolled back
+/// from.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NotInstalledDirectory {
+    /// Information about the directory.
+    pub package: PackageDirectory,
+
+    /// Why the most recent attempt at installing the directory failed, if the journal records
+    /// it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<String>,
+
+    /// Whether the directory is kept as a previous install, to roll back to. These aren't
+    /// removed by `hasp gc --directories`.
+    #[serde(default)]
+    pub previous_install: bool,
+}
+
+/// The output of `hasp info`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PackageInfoOutput {
+    /// The installed package.
+    #[serde(flatten)]
+    pub installed: InstalledPackage,
+
+    /// The dependencies the package was built with, if requested with `--deps`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependencies: Option<Vec<LockedDependency>>,
+}
+
+/// The output of `hasp resolve`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResolveOutput {
+    /// The resolved packages, in the order they were given.
+    pub packages: Vec<ResolvedPackage>,
+}
+
+/// The version a package specifier resolves to, without anything being installed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResolvedPackage {
+    /// The namespace of the package, e.g. `cargo`.
+    pub namespace: String,
+
+    /// The name of the package.
+    pub name: String,
+
+    /// The version requirement that was resolved.
+    pub req: String,
+
+    /// The version that would be installed.
+    pub version: DirectoryVersion,
+
+    /// The URL the package would be downloaded from, if it's known before fetching.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+}
+
+/// The output of `hasp stats`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct InstallStatsOutput {
+    /// The number of successful installs with recorded timings.
+    pub installs: usize,
+
+    /// The number of those installs that reused an existing build rather than building the
+    /// package.
+    pub reused_builds: usize,
+
+    /// The total time spent resolving versions across all installs, in milliseconds.
+    pub resolve_ms: u64,
+
+    /// The total time spent fetching packages across all installs, in milliseconds.
+    pub fetch_ms: u64,
+
+    /// The total time spent building packages, or copying in builds, in milliseconds.
+    pub build_ms: u64,
+
+    /// The total time spent finishing installs across all installs, in milliseconds.
+    pub finish_ms: u64,
+
+    /// The slowest installs, slowest first.
+    pub slowest: Vec<TimedInstall>,
+}
+
+impl InstallStatsOutput {
+    /// Returns the fraction of installs that reused an existing build, or `None` if there were
+    /// no installs.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        (self.installs > 0).then(|| self.reused_builds as f64 / self.installs as f64)
+    }
+
+    /// Returns the total time spent across all phases of all installs, in milliseconds.
+    pub fn total_ms(&self) -> u64 {
+        self.resolve_ms + self.fetch_ms + self.build_ms + self.finish_ms
+    }
+}
+
+/// A successful install, and how long it took.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TimedInstall {
+    /// The package that was installed.
+    pub package: PackageDirectory,
+
+    /// The time at which the install finished.
+    pub end_time: DateTime<Local>,
+
+    /// How long each phase of the install took.
+    pub timings: InstallTimings,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DirectoryHash, InstallInfo, PackageDirectory};
+    use chrono::Local;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn package_info_output_shape() {
+        let installed = InstalledPackage {
+            package: PackageDirectory {
+                namespace: "cargo".to_owned(),
+                name: "foo".to_owned(),
+                version: "sem:1.0.0".parse().expect("valid version"),
+                hash: DirectoryHash::new(0x01234567),
+                metadata: serde_json::json!({}),
+            },
+            info: InstallInfo {
+                install_path: "/hasp/installs/cargo/foo/0000000001234567".into(),
+                install_time: Local::now(),
+                installed_files: BTreeMap::new(),
+                metadata: serde_json::json!({ "env-hash": "0123456789abcdef" }),
+                held: false,
+            },
+        };
+        let mut output = PackageInfoOutput {
+            installed,
+            dependencies: None,
+        };
+
+        // Install info is flattened alongside the package, and dependencies are left out unless
+        // they were requested.
+        let json = serde_json::to_value(&output).expect("output serialized");
+        assert_eq!(json["package"]["name"], "foo");
+        assert_eq!(json["metadata"]["env-hash"], "0123456789abcdef");
+        assert!(json["install-path"].is_string());
+        assert_eq!(json["held"], false);
+        assert!(json.get("dependencies").is_none());
+
+        output.dependencies = Some(vec![LockedDependency {
+            name: "bar".to_owned(),
+            version: semver::Version::new(0, 2, 1),
+            source: None,
+            checksum: None,
+        }]);
+        let json = serde_json::to_value(&output).expect("output serialized");
+        assert_eq!(json["dependencies"][0]["version"], "0.2.1");
+        let parsed: PackageInfoOutput = serde_json::from_value(json).expect("output parsed");
+        assert_eq!(parsed.installed.package.name, "foo");
+
+        // Download URLs are left out if they aren't known.
+        let resolved = ResolvedPackage {
+            namespace: "cargo".to_owned(),
+            name: "foo".to_owned(),
+            req: "^1".to_owned(),
+            version: "sem:1.2.0".parse().expect("valid version"),
+            download_url: None,
+        };
+        let json = serde_json::to_value(&ResolveOutput {
+            packages: vec![resolved],
+        })
+        .expect("output serialized");
+        assert_eq!(json["packages"][0]["req"], "^1");
+        assert!(json["packages"][0].get("download-url").is_none());
+    }
+}
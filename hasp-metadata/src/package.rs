@@ -1,7 +1,7 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use crate::{DirectoryVersion, PackageDirectory, ParseHashError};
+use crate::{DirectoryVersion, PackageDirectory, ParseHashError, Sha256Hash};
 use camino::Utf8PathBuf;
 use chrono::{DateTime, Local};
 use once_cell::sync::OnceCell;
@@ -20,26 +20,69 @@ pub struct DirectoryVersionReq {
 }
 
 impl DirectoryVersionReq {
+    /// Creates a new version requirement from a string.
+    ///
+    /// The string is matched exactly against literal versions, and parsed lazily as a semver
+    /// requirement for semantic versions. A string starting with `lit:` is a literal requirement:
+    /// see [`Self::new_literal`].
+    pub fn new(req: impl Into<String>) -> Self {
+        Self {
+            req: req.into(),
+            parsed: OnceCell::new(),
+        }
+    }
+
+    /// Creates a requirement for exactly this version, compared as a string against every kind of
+    /// version rather than parsed as semver. This is for packages whose versions aren't semver,
+    /// or to pin a version exactly, including any build metadata.
+    pub fn new_literal(version: impl AsRef<str>) -> Self {
+        Self::new(format!(
+            "{}{}",
+            DirectoryVersion::LIT_PREFIX,
+            version.as_ref()
+        ))
+    }
+
+    /// Returns the version this requirement is for, if it's a literal requirement.
+    #[inline]
+    pub fn as_literal(&self) -> Option<&str> {
+        self.req.strip_prefix(DirectoryVersion::LIT_PREFIX)
+    }
+
     /// Returns the version requirement string.
     #[inline]
     pub fn as_str(&self) -> &str {
         &self.req
     }
 
-    /// Returns the version requirement parsed as semver, if successful.
+    /// Returns the version requirement parsed as semver, if successful. Literal requirements are
+    /// never parsed as semver.
     pub fn as_semver(&self) -> Option<&VersionReq> {
         self.parsed
-            .get_or_init(|| self.req.parse::<VersionReq>().ok())
+            .get_or_init(|| match self.as_literal() {
+                Some(_) => None,
+                None => self.req.parse::<VersionReq>().ok(),
+            })
             .as_ref()
     }
 
     /// Returns true if self matches the version.
     pub fn matches(&self, version: &DirectoryVersion) -> bool {
+        if let Some(literal) = self.as_literal() {
+            return match version {
+                DirectoryVersion::Semantic(version) => version.to_string() == literal,
+                DirectoryVersion::Literal(version) => version == literal,
+                DirectoryVersion::Floating(version) => {
+                    version.spec() == literal || version.resolved() == literal
+                }
+            };
+        }
         match version {
             DirectoryVersion::Semantic(version) => {
                 self.as_semver().map_or(false, |req| req.matches(version))
             }
             DirectoryVersion::Literal(version) => &self.req == version,
+            DirectoryVersion::Floating(version) => self.req == version.spec(),
         }
     }
 }
@@ -87,6 +130,10 @@ pub struct InstallInfo {
 
     /// Metadata associated with the installation.
     pub metadata: serde_json::Value,
+
+    /// Whether the package is held at this version through `hasp hold`.
+    #[serde(default)]
+    pub held: bool,
 }
 
 /// Represents a binary that is currently installed. Returned as part of [`InstallInfo`].
@@ -111,12 +158,26 @@ pub struct InstalledFile {
 #[non_exhaustive]
 pub enum FileHash {
     Blake3(Blake3Hash),
+    /// A SHA-256 hash, which matches the checksums that registries publish.
+    Sha256(Sha256Hash),
+}
+
+impl FileHash {
+    /// Returns the algorithm this hash was computed with, which files are verified against it
+    /// with.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            FileHash::Blake3(_) => HashAlgorithm::Blake3,
+            FileHash::Sha256(_) => HashAlgorithm::Sha256,
+        }
+    }
 }
 
 impl fmt::Display for FileHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             FileHash::Blake3(hash) => write!(f, "{}{}", Blake3Hash::PREFIX, hash),
+            FileHash::Sha256(hash) => write!(f, "{}{}", Sha256Hash::PREFIX, hash),
         }
     }
 }
@@ -124,8 +185,11 @@ impl fmt::Display for FileHash {
 impl FromStr for FileHash {
     type Err = ParseHashError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.strip_prefix(Blake3Hash::PREFIX) {
-            Some(hash) => hash.parse().map(FileHash::Blake3),
+        if let Some(hash) = s.strip_prefix(Blake3Hash::PREFIX) {
+            return hash.parse().map(FileHash::Blake3);
+        }
+        match s.strip_prefix(Sha256Hash::PREFIX) {
+            Some(hash) => hash.parse().map(FileHash::Sha256),
             None => Err(ParseHashError {
                 description: "binary hash",
                 input: s.into(),
@@ -135,6 +199,15 @@ impl FromStr for FileHash {
     }
 }
 
+/// An algorithm that files can be hashed with.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
 impl Serialize for FileHash {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -171,6 +244,11 @@ mod binary_hash_rusqlite_impls {
                         ValueRef::Blob(bytes),
                     )?));
                 }
+                if let Some(bytes) = input.strip_prefix(&Sha256Hash::DB_PREFIX) {
+                    return Ok(FileHash::Sha256(Sha256Hash::column_result(
+                        ValueRef::Blob(bytes),
+                    )?));
+                }
                 let err =
                     ParseHashError::from_blob("binary hash", input, "hash prefix not recognized");
                 Err(FromSqlError::Other(Box::new(err)))
@@ -188,6 +266,12 @@ mod binary_hash_rusqlite_impls {
                     output.extend_from_slice(&hash.to_be_bytes());
                     output
                 }
+                FileHash::Sha256(hash) => {
+                    let mut output = Vec::with_capacity(2 + Sha256Hash::BYTES);
+                    output.extend_from_slice(&Sha256Hash::DB_PREFIX);
+                    output.extend_from_slice(&hash.to_be_bytes());
+                    output
+                }
             };
             Ok(output.into())
         }
@@ -232,3 +316,69 @@ impl From<blake3::Hash> for Blake3Hash {
 }
 
 hash_impls!(Blake3Hash, blake3_hash);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_hash_algorithms() {
+        for (s, algorithm) in [
+            (
+                "blake3:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+                HashAlgorithm::Blake3,
+            ),
+            (
+                "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+                HashAlgorithm::Sha256,
+            ),
+        ] {
+            let hash: FileHash = s.parse().expect("hash parsed");
+            assert_eq!(hash.algorithm(), algorithm, "{}", s);
+            assert_eq!(hash.to_string(), s);
+        }
+        assert!("md5:0123456789abcdef0123456789abcdef"
+            .parse::<FileHash>()
+            .is_err());
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn file_hash_rusqlite_roundtrip() {
+        use rusqlite::Connection;
+
+        let conn = Connection::open_in_memory().expect("in-memory DB succeeded");
+        conn.execute("CREATE TABLE file_hash (hash BLOB)", [])
+            .expect("creating table succeeded");
+
+        for (s, prefix) in [
+            (
+                "blake3:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+                *b"01",
+            ),
+            (
+                "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+                *b"02",
+            ),
+        ] {
+            let hash: FileHash = s.parse().expect("hash parsed");
+            conn.execute("DELETE FROM file_hash", [])
+                .expect("delete succeeded");
+            conn.execute("INSERT INTO file_hash (hash) VALUES (?1)", [&hash])
+                .expect("insert succeeded");
+
+            let bytes: Vec<u8> = conn
+                .query_row("SELECT (hash) from file_hash", [], |row| row.get("hash"))
+                .expect("select succeeded");
+            assert_eq!(bytes[..2], prefix, "{} is stored with its prefix", s);
+            let roundtrip: FileHash = conn
+                .query_row("SELECT (hash) from file_hash", [], |row| row.get("hash"))
+                .expect("select succeeded");
+            assert_eq!(
+                roundtrip.to_string(),
+                s,
+                "hash through SQL roundtrip matches"
+            );
+        }
+    }
+}
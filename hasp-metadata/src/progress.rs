@@ -0,0 +1,63 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Progress events for frontends that render the progress of installs themselves.
+//!
+//! These types are part of hasp's interface: fields may be added to them, but existing fields
+//! aren't renamed or removed.
+
+use serde::{Deserialize, Serialize};
+
+/// A package moved to another phase of being installed.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProgressEvent {
+    /// The package, keyed as in the config file, e.g. `ripgrep` or `npm:prettier`.
+    pub package: String,
+
+    /// The version being installed, once it's been resolved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    /// The phase the package is now in.
+    pub phase: ProgressPhase,
+
+    /// The percentage of the packages being installed that have finished, from 0 to 100.
+    ///
+    /// How far along a package is within a phase isn't known, so this only advances as packages
+    /// finish.
+    pub percent: u8,
+}
+
+/// The phases a package goes through while being installed.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgressPhase {
+    /// A version is being resolved.
+    Resolving,
+
+    /// The package is being fetched.
+    Fetching,
+
+    /// The package is being built, or a build of it is being reused.
+    Building,
+
+    /// The package was installed. This is a final phase.
+    Installed,
+
+    /// The package was already installed. This is a final phase.
+    AlreadyInstalled,
+
+    /// The package failed to install. This is a final phase.
+    Failed,
+}
+
+impl ProgressPhase {
+    /// Returns true if the package won't move to another phase.
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            ProgressPhase::Installed | ProgressPhase::AlreadyInstalled | ProgressPhase::Failed
+        )
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn main() {
+    // Manifest entries can be filtered by target triple, which is only known to build scripts.
+    let target = std::env::var("TARGET").expect("TARGET is set for build scripts");
+    println!("cargo:rustc-env=HASP_TARGET={}", target);
+    generate_messages();
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+}
+
+/// The prefix of output targets, which message IDs don't include.
+const OUTPUT_PREFIX: &str = "target: \"hasp::output::";
+
+/// The comment at the top of the generated catalog, which is printed by `hasp --print messages`.
+const CATALOG_HEADER: &str = "\
+# Messages printed by hasp, keyed by message ID.
+#
+# A message ID is the target of an output event without the `hasp::output::` prefix. `{name}`
+# is replaced with the `name` field of the event, and `{{` and `}}` print literal braces. The
+# first word of a message is its header, which is aligned and colored separately.
+#
+# To localize hasp, save this catalog as `<locale>.toml` (e.g. `de.toml`) in the locale directory
+# and translate the messages. Messages that are missing from a catalog are printed in English.
+";
+
+/// Generates the English message catalog from the messages of output events in the source, so
+/// that each message is only written once.
+///
+/// Every message must be renderable from the fields of its event, so the build fails on messages
+/// with positional arguments like `{}` or format specs like `{:#}`, which couldn't be translated.
+fn generate_messages() {
+    let mut paths = vec![];
+    collect_sources(Path::new("src"), &mut paths);
+    paths.sort();
+
+    let mut messages = BTreeMap::new();
+    for path in paths {
+        let source = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err));
+        for (id, template) in
+            output_messages(&source).unwrap_or_else(|err| panic!("in {}: {}", path.display(), err))
+        {
+            match messages.entry(id) {
+                Entry::Vacant(entry) => {
+                    entry.insert(template);
+                }
+                Entry::Occupied(entry) if *entry.get() != template => panic!(
+                    "in {}: events with the target {} have different messages (hint: give them \
+                     their own targets, so that they can be translated separately)",
+                    path.display(),
+                    entry.key(),
+                ),
+                Entry::Occupied(_) => {}
+            }
+        }
+    }
+
+    let mut catalog = format!("{}\n[messages]\n", CATALOG_HEADER);
+    for (id, template) in &messages {
+        writeln!(catalog, "{} = {}", toml_string(id), toml_string(template))
+            .expect("writing to a string never fails");
+    }
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is set for build scripts"));
+    fs::write(out_dir.join("messages.toml"), catalog).expect("failed to write message catalog");
+}
+
+fn collect_sources(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let entries =
+        fs::read_dir(dir).unwrap_or_else(|err| panic!("failed to read {}: {}", dir.display(), err));
+    for entry in entries {
+        let path = entry.expect("failed to read source directory").path();
+        if path.is_dir() {
+            collect_sources(&path, paths);
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            paths.push(path);
+        }
+    }
+}
+
+/// Returns the ID and message of each output event in a source file, checking that the message can
+/// be rendered from the fields of the event.
+fn output_messages(source: &str) -> Result<Vec<(String, String)>, String> {
+    let mut messages = vec![];
+    let mut rest = source;
+    while let Some(start) = rest.find(OUTPUT_PREFIX) {
+        rest = &rest[start + OUTPUT_PREFIX.len()..];
+        let end = rest.find('"').ok_or("unterminated target")?;
+        let id = &rest[..end];
+        rest = rest[end + 1..].trim_start();
+        rest = match rest.strip_prefix(',') {
+            Some(rest) => rest,
+            // Targets compared against rather than passed to an event.
+            None => continue,
+        };
+
+        // Fields come before the message, as `name = value`, `%name` or `name`.
+        let mut fields = vec![];
+        let message = loop {
+            rest = rest.trim_start();
+            if rest.starts_with('"') {
+                let (message, after) = parse_literal(rest)?;
+                rest = after;
+                break Some(message);
+            }
+            if rest.is_empty() || rest.starts_with(')') {
+                break None;
+            }
+            let (field, after) = split_field(rest)?;
+            let name = field.trim_start_matches(['%', '?']);
+            let name = name.split('=').next().unwrap_or(name).trim();
+            fields.push(name.to_owned());
+            rest = after;
+        };
+        let message = match message {
+            Some(message) => message,
+            None => continue,
+        };
+
+        for name in placeholders(&message)? {
+            if !is_ident(&name) {
+                return Err(format!(
+                    "the message for {} uses {{{}}}, which can't be rendered from the fields of \
+                     the event (hint: record the value as a named field, formatted as it should \
+                     be printed, and refer to it by name)",
+                    id, name,
+                ));
+            }
+            if !fields.contains(&name) {
+                return Err(format!(
+                    "the message for {} uses {{{}}}, which isn't a field of the event (hint: \
+                     record it as a field, so that the message can be translated)",
+                    id, name,
+                ));
+            }
+        }
+        messages.push((id.to_owned(), message));
+    }
+    Ok(messages)
+}
+
+/// Splits off a field up to the next comma that isn't nested in brackets or a string.
+fn split_field(s: &str) -> Result<(&str, &str), String> {
+    let mut depth = 0;
+    let mut pos = 0;
+    while let Some(c) = s[pos..].chars().next() {
+        match c {
+            '"' => {
+                let (_, rest) = parse_literal(&s[pos..])?;
+                pos = s.len() - rest.len();
+                continue;
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' if depth == 0 => return Ok((&s[..pos], &s[pos..])),
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => return Ok((&s[..pos], &s[pos + 1..])),
+            _ => {}
+        }
+        pos += c.len_utf8();
+    }
+    Err("unterminated event".to_owned())
+}
+
+/// Parses a string literal at the start of `s`, returning its value and the rest of `s`.
+fn parse_literal(s: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[idx + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some('0') => value.push('\0'),
+                Some(c @ ('\\' | '"' | '\'')) => value.push(c),
+                // A backslash at the end of a line skips the line break and the indentation after
+                // it.
+                Some('\n') => {
+                    while chars.clone().next().is_some_and(|(_, c)| c.is_whitespace()) {
+                        chars.next();
+                    }
+                }
+                other => return Err(format!("unsupported escape in message: \\{:?}", other)),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string literal".to_owned())
+}
+
+/// Returns the contents of the placeholders in a message, e.g. `name` for `{name}` and an empty
+/// string for `{}`.
+fn placeholders(message: &str) -> Result<Vec<String>, String> {
+    let mut placeholders = vec![];
+    let mut rest = message.replace("{{", "").replace("}}", "");
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated placeholder in {:?}", message))?;
+        placeholders.push(rest[start + 1..start + end].to_owned());
+        rest = rest[start + end + 1..].to_owned();
+    }
+    Ok(placeholders)
+}
+
+fn is_ident(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn toml_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                write!(quoted, "\\u{:04X}", c as u32).expect("writing to a string never fails")
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
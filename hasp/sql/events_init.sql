@@ -6,7 +6,12 @@ CREATE TABLE IF NOT EXISTS journal (
   -- Timestamp associated with the event (RFC3339).
   event_time DATETIME NOT NULL,
   -- Data associated with this event as a JSON blob.
-  data TEXT
+  data TEXT,
+  -- The version of the event schema the data was recorded with (0 for events recorded before
+  -- the schema was versioned).
+  schema_version INTEGER NOT NULL DEFAULT 0,
+  -- The ID of the run of hasp that recorded the event.
+  run_id TEXT
 );
 
 CREATE INDEX IF NOT EXISTS idx_event_time ON journal (event_time);
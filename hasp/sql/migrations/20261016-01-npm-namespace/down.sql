@@ -0,0 +1,2 @@
+-- Fails if any npm packages are recorded, since directories reference their namespace.
+DELETE FROM packages.namespaces WHERE namespace = "npm";
@@ -0,0 +1,2 @@
+-- Allow packages installed through npm.
+INSERT INTO packages.namespaces VALUES ("npm");
@@ -0,0 +1,2 @@
+-- Fails if any container images are recorded, since directories reference their namespace.
+DELETE FROM packages.namespaces WHERE namespace = "oci";
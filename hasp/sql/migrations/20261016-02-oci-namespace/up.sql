@@ -0,0 +1,2 @@
+-- Allow container images, run through docker or podman.
+INSERT INTO packages.namespaces VALUES ("oci");
@@ -0,0 +1,7 @@
+-- The last time each registry index was updated, so that recent updates can be skipped.
+CREATE TABLE index_updates (
+  -- The registry URL, or "crates.io" for the default registry.
+  registry TEXT PRIMARY KEY NOT NULL,
+  -- The time at which the index was last updated.
+  update_time DATETIME NOT NULL
+);
@@ -0,0 +1 @@
+DROP TABLE packages.install_progress;
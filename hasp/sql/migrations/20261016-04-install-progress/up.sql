@@ -0,0 +1,18 @@
+-- The progress of installs, so that an interrupted install can resume from its last completed
+-- phase.
+CREATE TABLE packages.install_progress (
+  -- The working directory for the install, within the cache directory.
+  work_dir TEXT PRIMARY KEY NOT NULL,
+  -- The namespace for this package.
+  namespace TEXT NOT NULL REFERENCES namespaces(namespace),
+  -- The name of the package.
+  name TEXT NOT NULL,
+  -- The package version.
+  version TEXT NOT NULL,
+  -- The last completed phase: resolved, fetched, built or finalized.
+  phase TEXT NOT NULL,
+  -- Data needed to resume from this phase, as a JSON blob.
+  data TEXT NOT NULL,
+  -- The time at which the phase was completed.
+  update_time DATETIME NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Previous installs are left on disk, but can no longer be rolled back to.
+DROP TABLE packages.previous_installs;
@@ -0,0 +1,13 @@
+-- Installs that were superseded by a newer install of the same package, kept around so that
+-- `hasp rollback` can restore them.
+CREATE TABLE packages.previous_installs (
+  previous_id INTEGER PRIMARY KEY,
+  -- The id in packages.directories. A directory has at most one previous install.
+  directory_id INTEGER UNIQUE NOT NULL REFERENCES directories(directory_id),
+  -- The id in packages.installed of the install that was superseded.
+  install_id INTEGER NOT NULL REFERENCES installed(install_id),
+  -- The path the install was moved to, within the previous installs directory.
+  path TEXT NOT NULL,
+  -- The time at which the install was superseded.
+  retire_time DATETIME NOT NULL
+);
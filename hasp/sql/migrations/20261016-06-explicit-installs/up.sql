@@ -0,0 +1,7 @@
+-- Whether a directory was requested directly, through the command line or a manifest, rather
+-- than being installed for another package. Directories that aren't explicit can be removed once
+-- nothing explicit references them.
+ALTER TABLE packages.directories ADD COLUMN explicit BOOLEAN NOT NULL DEFAULT 0;
+
+-- Until now, every install was requested directly.
+UPDATE packages.directories SET explicit = 1;
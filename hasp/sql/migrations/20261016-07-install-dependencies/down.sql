@@ -0,0 +1,2 @@
+DROP INDEX packages.install_dependencies_install_id;
+DROP TABLE packages.install_dependencies;
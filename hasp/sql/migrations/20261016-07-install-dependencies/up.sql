@@ -0,0 +1,16 @@
+-- The dependencies that installs were built with, as resolved by their lockfiles.
+CREATE TABLE packages.install_dependencies (
+  dependency_id INTEGER PRIMARY KEY,
+  -- The id in packages.installed.
+  install_id INTEGER NOT NULL REFERENCES installed(install_id),
+  -- The name of the dependency.
+  name TEXT NOT NULL,
+  -- The resolved version of the dependency.
+  version TEXT NOT NULL,
+  -- Where the dependency came from, e.g. a registry or git URL. NULL for path dependencies.
+  source TEXT,
+  -- The checksum of the dependency's source, if its source records one.
+  checksum TEXT
+);
+
+CREATE INDEX packages.install_dependencies_install_id ON install_dependencies (install_id);
@@ -0,0 +1,10 @@
+-- Sources that packages may be fetched from, approved when they were first used or through
+-- `hasp trust add`. Only consulted if approval of new sources is required.
+CREATE TABLE trusted_sources (
+  -- The source, e.g. "cargo:crates.io" or "oci:ghcr.io".
+  source TEXT PRIMARY KEY NOT NULL,
+  -- How the source was approved: "prompt" or "command".
+  approval TEXT NOT NULL,
+  -- The time at which the source was approved.
+  approve_time DATETIME NOT NULL
+);
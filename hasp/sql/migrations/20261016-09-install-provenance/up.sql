@@ -0,0 +1,4 @@
+-- Who or what requested a directory, as a JSON blob: the command line, manifest or project it
+-- was requested through, along with the user and time. NULL for directories installed before
+-- this was recorded, and for directories that weren't requested directly.
+ALTER TABLE packages.directories ADD COLUMN provenance TEXT;
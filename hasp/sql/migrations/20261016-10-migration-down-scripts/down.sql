@@ -0,0 +1 @@
+ALTER TABLE migration_status DROP COLUMN down_sql;
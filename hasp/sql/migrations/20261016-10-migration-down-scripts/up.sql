@@ -0,0 +1,4 @@
+-- The SQL that rolls back each applied migration, so that a database can be rolled back by an
+-- older version of hasp that doesn't know about newer migrations. NULL for migrations that can't
+-- be rolled back.
+ALTER TABLE migration_status ADD COLUMN down_sql TEXT;
@@ -0,0 +1 @@
+ALTER TABLE packages.directories DROP COLUMN held;
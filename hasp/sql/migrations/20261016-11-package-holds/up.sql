@@ -0,0 +1,4 @@
+-- Whether a directory is held at its version through `hasp hold`, so that `hasp sync` and
+-- `hasp install --update` leave its package alone. Installing another version of the package
+-- drops the hold, since the new directory isn't held.
+ALTER TABLE packages.directories ADD COLUMN held BOOLEAN NOT NULL DEFAULT 0;
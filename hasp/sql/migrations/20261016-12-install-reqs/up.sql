@@ -0,0 +1,4 @@
+-- The version requirement a directory was resolved from when it was installed, e.g. "^1.2", for
+-- checking whether newer versions matching it are available. NULL for directories installed
+-- before this was recorded, and for directories installed without resolving a version.
+ALTER TABLE packages.directories ADD COLUMN req TEXT;
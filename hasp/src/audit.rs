@@ -0,0 +1,457 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Checking installed packages against the RustSec advisory database.
+//!
+//! The advisory database is downloaded as an archive, and the advisories in it are cached in the
+//! cache directory. Both installed Cargo packages and the dependencies recorded from their
+//! lockfiles are checked.
+//!
+//! Installed Cargo packages are also checked against their registry's index, to find versions
+//! that have been yanked since they were installed.
+
+use crate::{
+    home::HaspHome,
+    http_client,
+    lockfile::LockedDependency,
+    network::RetryPolicy,
+    ops::{is_yanked, CargoBuildOpts},
+    state::HaspState,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use flate2::read::GzDecoder;
+use hasp_metadata::{CargoDirectory, DirectoryVersion};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Read,
+    time::{Duration, SystemTime},
+};
+use tar::Archive;
+
+/// The URL of an archive of the RustSec advisory database.
+const ADVISORY_DB_URL: &str =
+    "https://github.com/rustsec/advisory-db/archive/refs/heads/main.tar.gz";
+
+/// Sources for crates.io in lockfiles, which are the only sources the advisory database covers.
+const CRATES_IO_SOURCES: &[&str] = &[
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// A security advisory for a crate.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Advisory {
+    /// The advisory ID, e.g. `RUSTSEC-2021-0001`.
+    pub(crate) id: String,
+    /// The affected crate.
+    pub(crate) package: String,
+    pub(crate) title: String,
+    /// Versions with fixes for the vulnerability.
+    pub(crate) patched: Vec<VersionReq>,
+    /// Versions that were never affected.
+    pub(crate) unaffected: Vec<VersionReq>,
+}
+
+impl Advisory {
+    /// Returns true if this version of the crate is affected.
+    pub(crate) fn affects(&self, version: &Version) -> bool {
+        !self
+            .patched
+            .iter()
+            .chain(&self.unaffected)
+            .any(|req| req.matches(version))
+    }
+
+    /// Returns a description of the patched versions, e.g. `>=1.2.3`.
+    pub(crate) fn patched_display(&self) -> String {
+        if self.patched.is_empty() {
+            "no patched versions".to_owned()
+        } else {
+            let patched = self
+                .patched
+                .iter()
+                .map(|req| req.to_string())
+                .collect::<Vec<_>>();
+            format!("patched in {}", patched.join(" or "))
+        }
+    }
+}
+
+/// The advisories in the RustSec advisory database, keyed by crate.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct AdvisoryDb {
+    advisories: BTreeMap<String, Vec<Advisory>>,
+}
+
+impl AdvisoryDb {
+    /// The name of the file that advisories are cached in, within the cache directory.
+    const FILE_NAME: &'static str = "advisory-db.json";
+
+    /// How long the cached advisories are used for before being fetched again.
+    const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Loads the advisory database, fetching it if the cached copy is missing or stale. If
+    /// `refresh` is true, it's always fetched.
+    pub(crate) async fn load(
+        hasp_home: &HaspHome,
+        network: &RetryPolicy,
+        refresh: bool,
+    ) -> Result<Self> {
+        let path = Self::path(hasp_home);
+        if !refresh && Self::is_fresh(&path) {
+            let contents =
+                fs::read_to_string(&path).wrap_err_with(|| format!("failed to read {}", path))?;
+            // A cache that can't be parsed (e.g. one written by a different version of hasp) is
+            // fetched again.
+            if let Ok(db) = serde_json::from_str(&contents) {
+                return Ok(db);
+            }
+        }
+
+        tracing::info!(
+            target: "hasp::output::working::fetching_advisories",
+            url = ADVISORY_DB_URL,
+            "Fetching advisories from {url}",
+            url = ADVISORY_DB_URL,
+        );
+        let client = http_client::client()?;
+        let bytes = network
+            .run("fetch the advisory database", || async {
+                let resp = client
+                    .get(ADVISORY_DB_URL)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(resp.bytes().await?)
+            })
+            .await?;
+        let db = Self::parse_archive(&bytes)
+            .wrap_err_with(|| format!("failed to parse advisories from {}", ADVISORY_DB_URL))?;
+
+        let contents = serde_json::to_string(&db).expect("serializing advisories never fails");
+        fs::write(&path, contents).wrap_err_with(|| format!("failed to write {}", path))?;
+        Ok(db)
+    }
+
+    /// Returns the advisories that affect this version of a crate.
+    pub(crate) fn affecting<'a>(
+        &'a self,
+        name: &str,
+        version: &'a Version,
+    ) -> impl Iterator<Item = &'a Advisory> + 'a {
+        self.advisories
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |advisory| advisory.affects(version))
+    }
+
+    /// Parses advisories out of a `.tar.gz` archive of the advisory database.
+    fn parse_archive(bytes: &[u8]) -> Result<Self> {
+        let mut db = Self::default();
+        let mut archive = Archive::new(GzDecoder::new(bytes));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            // Advisories for crates are at <root>/crates/<name>/<id>.md.
+            let path = entry.path()?.into_owned();
+            let components = path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            let is_advisory = matches!(
+                components.as_slice(),
+                [_, dir, _, file] if dir == "crates" && file.ends_with(".md")
+            );
+            if !is_advisory {
+                continue;
+            }
+
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            match parse_advisory(&contents) {
+                Ok(Some(advisory)) => db.insert(advisory),
+                Ok(None) => {}
+                Err(err) => {
+                    let path = path.display();
+                    let error = format!("{:#}", err);
+                    tracing::debug!(
+                        target: "hasp::output::working::advisory_skipped",
+                        %path,
+                        %error,
+                        "Skipped advisory {path}: {error}",
+                    );
+                }
+            }
+        }
+        Ok(db)
+    }
+
+    fn insert(&mut self, advisory: Advisory) {
+        self.advisories
+            .entry(advisory.package.clone())
+            .or_default()
+            .push(advisory);
+    }
+
+    fn path(hasp_home: &HaspHome) -> Utf8PathBuf {
+        hasp_home.cache_dir().join(Self::FILE_NAME)
+    }
+
+    fn is_fresh(path: &Utf8Path) -> bool {
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return false,
+        };
+        match SystemTime::now().duration_since(modified) {
+            Ok(age) => age < Self::TTL,
+            // The file was modified in the future, so the clock must have changed.
+            Err(_) => false,
+        }
+    }
+}
+
+/// Parses an advisory in the advisory database's Markdown format, which starts with a TOML block
+/// and is followed by a title.
+///
+/// Returns `None` for advisories that were withdrawn or are only informational, e.g. for crates
+/// that are unmaintained.
+fn parse_advisory(contents: &str) -> Result<Option<Advisory>> {
+    let rest = contents
+        .trim_start()
+        .strip_prefix("```toml")
+        .ok_or_else(|| eyre!("advisory doesn't start with a TOML block"))?;
+    let (front_matter, rest) = rest
+        .split_once("\n```")
+        .ok_or_else(|| eyre!("advisory's TOML block isn't closed"))?;
+    let raw: RawAdvisory = toml::from_str(front_matter)?;
+    if raw.advisory.withdrawn.is_some() || raw.advisory.informational.is_some() {
+        return Ok(None);
+    }
+
+    let title = rest
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .unwrap_or("(no title)")
+        .trim()
+        .to_owned();
+    Ok(Some(Advisory {
+        id: raw.advisory.id,
+        package: raw.advisory.package,
+        title,
+        patched: raw.versions.patched,
+        unaffected: raw.versions.unaffected,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RawAdvisory {
+    advisory: RawAdvisoryInfo,
+    #[serde(default)]
+    versions: RawVersions,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawAdvisoryInfo {
+    id: String,
+    package: String,
+    informational: Option<String>,
+    withdrawn: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RawVersions {
+    #[serde(default)]
+    patched: Vec<VersionReq>,
+    #[serde(default)]
+    unaffected: Vec<VersionReq>,
+}
+
+/// A vulnerability found in an installed package.
+#[derive(Clone, Debug)]
+pub(crate) struct AuditFinding {
+    /// The name of the installed package.
+    pub(crate) name: String,
+    /// The installed version.
+    pub(crate) version: DirectoryVersion,
+    /// The dependency that's affected, or `None` if the package itself is.
+    pub(crate) dependency: Option<LockedDependency>,
+    pub(crate) advisory: Advisory,
+}
+
+/// The results of auditing installed packages.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AuditSummary {
+    /// The number of installed packages checked.
+    pub(crate) checked: usize,
+    pub(crate) findings: Vec<AuditFinding>,
+    /// Installed Cargo packages whose versions have been yanked.
+    pub(crate) yanked: Vec<(String, DirectoryVersion)>,
+}
+
+/// Checks installed Cargo packages from crates.io, and the dependencies they were built with,
+/// against the advisory database. Installed Cargo packages from any registry are checked for
+/// yanked versions.
+pub(crate) async fn audit_installed(
+    state: &HaspState,
+    db: &AdvisoryDb,
+    build_opts: &CargoBuildOpts,
+) -> Result<AuditSummary> {
+    let mut summary = AuditSummary::default();
+    for installed in state.installed_packages()? {
+        let package = &installed.directory_row.package;
+        if package.namespace != "cargo" {
+            continue;
+        }
+        let metadata: CargoDirectory = serde_json::from_value(package.metadata.clone())
+            .wrap_err_with(|| {
+                format!(
+                    "failed to parse metadata for {}",
+                    installed.directory_row.to_friendly()
+                )
+            })?;
+        summary.checked += 1;
+        if let Some(version) = package.version.as_semantic() {
+            match is_yanked(
+                &package.name,
+                version,
+                &metadata,
+                build_opts,
+                state.db_creator(),
+            )
+            .await
+            {
+                Ok(true) => summary
+                    .yanked
+                    .push((package.name.clone(), package.version.clone())),
+                Ok(false) => {}
+                Err(err) => {
+                    let package = installed.directory_row.to_friendly();
+                    let error = format!("{:#}", err);
+                    tracing::warn!(
+                        target: "hasp::output::audit::yank_check_failed",
+                        %package,
+                        %error,
+                        "Warning failed to check whether {package} has been yanked: {error}",
+                    );
+                }
+            }
+        }
+
+        // Crates from other registries may share names with unrelated crates on crates.io.
+        if metadata.registry.is_some() {
+            continue;
+        }
+
+        let finding = |dependency: Option<&LockedDependency>, advisory: &Advisory| AuditFinding {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            dependency: dependency.cloned(),
+            advisory: advisory.clone(),
+        };
+        if let Some(version) = package.version.as_semantic() {
+            for advisory in db.affecting(&package.name, version) {
+                summary.findings.push(finding(None, advisory));
+            }
+        }
+        for dependency in state.install_dependencies(&installed)? {
+            let from_crates_io = matches!(
+                dependency.source.as_deref(),
+                Some(source) if CRATES_IO_SOURCES.contains(&source)
+            );
+            if !from_crates_io {
+                continue;
+            }
+            for advisory in db.affecting(&dependency.name, &dependency.version) {
+                summary.findings.push(finding(Some(&dependency), advisory));
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+
+    static ADVISORY: &str = r#"```toml
+[advisory]
+id = "RUSTSEC-2021-0001"
+package = "foo"
+date = "2021-01-01"
+url = "https://example.com/foo/issues/1"
+categories = ["memory-corruption"]
+
+[versions]
+patched = [">= 1.2.3"]
+unaffected = ["< 1.0.0"]
+```
+
+# Buffer overflow in foo
+
+A description of the vulnerability.
+"#;
+
+    #[test]
+    fn parse_advisory_basic() {
+        let advisory = parse_advisory(ADVISORY)
+            .expect("advisory parsed")
+            .expect("advisory applies");
+        assert_eq!(advisory.id, "RUSTSEC-2021-0001");
+        assert_eq!(advisory.package, "foo");
+        assert_eq!(advisory.title, "Buffer overflow in foo");
+        assert_eq!(advisory.patched_display(), "patched in >=1.2.3");
+
+        assert!(!advisory.affects(&Version::new(0, 9, 0)), "unaffected");
+        assert!(advisory.affects(&Version::new(1, 2, 2)), "affected");
+        assert!(!advisory.affects(&Version::new(1, 2, 3)), "patched");
+
+        let informational = ADVISORY.replace(
+            "package = \"foo\"",
+            "package = \"foo\"\ninformational = \"unmaintained\"",
+        );
+        assert!(
+            parse_advisory(&informational)
+                .expect("advisory parsed")
+                .is_none(),
+            "informational advisories are skipped"
+        );
+    }
+
+    #[test]
+    fn parse_archive_basic() {
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::fast()));
+        for (path, contents) in [
+            ("advisory-db-main/crates/foo/RUSTSEC-2021-0001.md", ADVISORY),
+            ("advisory-db-main/README.md", "# RustSec Advisory Database"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .expect("entry appended");
+        }
+        let bytes = builder
+            .into_inner()
+            .expect("archive finished")
+            .finish()
+            .expect("compression finished");
+
+        let db = AdvisoryDb::parse_archive(&bytes).expect("archive parsed");
+        let version = Version::new(1, 2, 0);
+        let affecting = db.affecting("foo", &version).collect::<Vec<_>>();
+        assert_eq!(affecting.len(), 1);
+        assert_eq!(affecting[0].id, "RUSTSEC-2021-0001");
+        assert_eq!(db.affecting("bar", &version).count(), 0);
+    }
+}
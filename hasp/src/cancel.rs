@@ -0,0 +1,138 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Cancelling in-flight installs on Ctrl-C and other termination signals.
+//!
+//! On the first signal, child processes are killed and installs in progress fail, so that they're
+//! rolled back through the usual failure path. On the second signal, hasp exits immediately.
+
+use crate::platform::termination_signal;
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    error, fmt, io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The exit code used when hasp is cancelled, following the shell convention of 128 + SIGINT.
+pub(crate) const CANCELLED_EXIT_CODE: i32 = 130;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+static NEXT_CHILD_ID: AtomicU64 = AtomicU64::new(0);
+static CHILDREN: Lazy<Mutex<BTreeMap<u64, Arc<dyn KillChild>>>> = Lazy::new(Default::default);
+
+/// Starts listening for termination signals in the background.
+///
+/// Must be called from within a Tokio runtime.
+pub(crate) fn listen() {
+    tokio::spawn(async {
+        let mut signals_received = 0;
+        loop {
+            if let Err(err) = termination_signal().await {
+                let error = err.to_string();
+                tracing::debug!(
+                    target: "hasp::output::cancel::listen_failed",
+                    %error,
+                    "Failed to listen for termination signals: {error}",
+                );
+                return;
+            }
+            signals_received += 1;
+            if signals_received > 1 {
+                std::process::exit(CANCELLED_EXIT_CODE);
+            }
+
+            tracing::warn!(
+                target: "hasp::output::cancel::cancelling",
+                "Cancelling installs in progress (interrupt again to exit immediately)",
+            );
+            cancel();
+        }
+    });
+}
+
+/// Marks hasp as cancelled, and kills any registered child processes.
+fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+    let children = CHILDREN.lock().expect("lock is never poisoned");
+    for child in children.values() {
+        // The child may have already exited.
+        let _ = child.kill();
+    }
+}
+
+/// Returns true if hasp has been cancelled.
+pub(crate) fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Returns an error if hasp has been cancelled.
+pub(crate) fn check() -> Result<(), Cancelled> {
+    if is_cancelled() {
+        Err(Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Registers a child process to be killed if hasp is cancelled, until the returned guard is
+/// dropped.
+///
+/// If hasp has already been cancelled, the child is killed immediately.
+pub(crate) fn register_child(child: Arc<dyn KillChild>) -> ChildGuard {
+    let id = NEXT_CHILD_ID.fetch_add(1, Ordering::SeqCst);
+    let mut children = CHILDREN.lock().expect("lock is never poisoned");
+    // Check while holding the lock, so that this can't race with cancel().
+    if is_cancelled() {
+        let _ = child.kill();
+    }
+    children.insert(id, child);
+    ChildGuard { id }
+}
+
+/// Unregisters a child process when dropped.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct ChildGuard {
+    id: u64,
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Ok(mut children) = CHILDREN.lock() {
+            children.remove(&self.id);
+        }
+    }
+}
+
+/// A child process that can be killed.
+pub(crate) trait KillChild: Send + Sync {
+    fn kill(&self) -> io::Result<()>;
+}
+
+impl KillChild for duct::Handle {
+    fn kill(&self) -> io::Result<()> {
+        duct::Handle::kill(self)
+    }
+}
+
+impl KillChild for duct::ReaderHandle {
+    fn kill(&self) -> io::Result<()> {
+        duct::ReaderHandle::kill(self)
+    }
+}
+
+/// Hasp was cancelled by a termination signal.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled by signal")
+    }
+}
+
+impl error::Error for Cancelled {}
@@ -6,73 +6,115 @@
 //! Cargo CLI support.
 
 use crate::output::OutputOpts;
-use camino::Utf8PathBuf;
-use std::{convert::TryInto, env, path::PathBuf};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::{collections::BTreeMap, convert::TryInto, env, ffi::OsString, path::PathBuf};
+
+static HASP_CARGO_ENV: &str = "HASP_CARGO";
 
 #[derive(Clone, Debug)]
-pub(crate) struct CargoCli<'a> {
+pub(crate) struct CargoCli {
     cargo_path: Utf8PathBuf,
     output_opts: OutputOpts,
-    command: &'a str,
-    args: Vec<&'a str>,
+    command: String,
+    config: Vec<String>,
+    args: Vec<String>,
+    env: Option<BTreeMap<String, OsString>>,
 }
 
-impl<'a> CargoCli<'a> {
-    pub(crate) fn new(command: &'a str, output_opts: OutputOpts) -> Self {
-        let cargo_path = cargo_path();
+impl CargoCli {
+    /// Creates a new invocation of `cargo <command>`, using the cargo executable at `configured`
+    /// unless it's overridden through `HASP_CARGO`.
+    pub(crate) fn new(
+        command: impl Into<String>,
+        configured: Option<&Utf8Path>,
+        output_opts: OutputOpts,
+    ) -> Self {
+        let cargo_path = cargo_path(configured);
         Self {
             cargo_path,
             output_opts,
-            command,
+            command: command.into(),
+            config: vec![],
             args: vec![],
+            env: None,
         }
     }
 
-    pub(crate) fn add_arg(&mut self, arg: &'a str) -> &mut Self {
-        self.args.push(arg);
+    pub(crate) fn add_arg(&mut self, arg: impl Into<String>) -> &mut Self {
+        self.args.push(arg.into());
         self
     }
 
-    pub(crate) fn add_args(&mut self, args: impl IntoIterator<Item = &'a str>) -> &mut Self {
-        self.args.extend(args);
+    pub(crate) fn add_args(
+        &mut self,
+        args: impl IntoIterator<Item = impl Into<String>>,
+    ) -> &mut Self {
+        self.args.extend(args.into_iter().map(Into::into));
         self
     }
 
-    pub(crate) fn all_args(&self) -> Vec<&str> {
-        let mut all_args = vec![self.cargo_path.as_str(), self.command];
-        all_args.extend_from_slice(&self.args);
-        all_args
+    /// Adds configuration values, passed to Cargo as `--config <value>`.
+    pub(crate) fn add_config(
+        &mut self,
+        values: impl IntoIterator<Item = impl Into<String>>,
+    ) -> &mut Self {
+        self.config.extend(values.into_iter().map(Into::into));
+        self
     }
 
-    pub(crate) fn to_expression(&self) -> duct::Expression {
-        let mut initial_args = vec![];
+    /// Runs Cargo with exactly these environment variables, rather than those of hasp.
+    pub(crate) fn full_env(&mut self, env: BTreeMap<String, OsString>) -> &mut Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Returns the full command line, starting with the path to Cargo.
+    pub(crate) fn all_args(&self) -> Vec<&str> {
+        let mut all_args = vec![self.cargo_path.as_str()];
         if self.output_opts.quiet {
-            initial_args.push("--quiet");
+            all_args.push("--quiet");
         }
         // Don't push verbose down because it produces a ton of output.
         // TODO: push verbose 2 and above down?
 
-        initial_args.push(self.output_opts.color.to_arg());
+        all_args.push(self.output_opts.color().to_arg());
+        for value in &self.config {
+            all_args.extend(["--config", value.as_str()]);
+        }
 
-        initial_args.push(self.command);
+        all_args.push(&self.command);
+        all_args.extend(self.args.iter().map(|arg| arg.as_str()));
+        all_args
+    }
 
-        let args: Vec<_> = initial_args
-            .into_iter()
-            .chain(self.args.iter().copied())
-            .collect();
+    pub(crate) fn to_expression(&self) -> duct::Expression {
+        let all_args = self.all_args();
+        let command = all_args.join(" ");
         tracing::debug!(
             target: "hasp::output::working::running_cargo",
-            "Running {} {}", self.cargo_path, args.join(" "),
+            %command,
+            "Running {command}",
         );
-        duct::cmd(self.cargo_path.as_str(), args)
+        let expression = duct::cmd(all_args[0], &all_args[1..]);
+        match &self.env {
+            Some(env) => expression.full_env(env),
+            None => expression,
+        }
     }
 }
 
-fn cargo_path() -> Utf8PathBuf {
-    match env::var_os("CARGO") {
-        Some(cargo_path) => PathBuf::from(cargo_path)
-            .try_into()
-            .expect("CARGO env var is not valid UTF-8"),
-        None => Utf8PathBuf::from("cargo"),
-    }
+/// Returns the path to the cargo executable: `HASP_CARGO` if set, then the path in the config,
+/// then `CARGO` as set when running under Cargo, and finally `cargo` in `PATH`.
+fn cargo_path(configured: Option<&Utf8Path>) -> Utf8PathBuf {
+    let from_env = |env_var: &str| {
+        env::var_os(env_var).map(|cargo_path| {
+            PathBuf::from(cargo_path)
+                .try_into()
+                .unwrap_or_else(|_| panic!("{} env var is not valid UTF-8", env_var))
+        })
+    };
+    from_env(HASP_CARGO_ENV)
+        .or_else(|| configured.map(|cargo_path| cargo_path.to_path_buf()))
+        .or_else(|| from_env("CARGO"))
+        .unwrap_or_else(|| Utf8PathBuf::from("cargo"))
 }
@@ -0,0 +1,695 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Configuration for hasp, read from `config.toml` in the hasp home directory.
+//!
+//! Values in the config file act as defaults: command-line flags always take precedence.
+
+use crate::{
+    events::RetentionPolicy,
+    helpers::find_in_path,
+    hints::PackageHint,
+    hooks::HooksConfig,
+    network::RetryPolicy,
+    ops::{
+        BuildCache, BuildCacheMode, BuildEnv, CargoBuildOpts, IndexProtocol, LockWait,
+        SignatureMethod,
+    },
+    output::{otlp::OtlpSettings, Color},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::{Checksum, HashAlgorithm};
+use semver::VersionReq;
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, io, time::Duration};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct HaspConfig {
+    /// Defaults for installs.
+    #[serde(default)]
+    pub(crate) install: InstallConfig,
+
+    /// Defaults for output.
+    #[serde(default)]
+    pub(crate) output: OutputConfig,
+
+    /// Settings for the hasp database.
+    #[serde(default)]
+    pub(crate) database: DatabaseConfig,
+
+    /// Timeouts and retries for network operations.
+    #[serde(default)]
+    pub(crate) network: NetworkConfig,
+
+    /// How long to keep events in the journal.
+    #[serde(default)]
+    pub(crate) events: EventsConfig,
+
+    /// Settings for hints about deprecated packages.
+    #[serde(default)]
+    pub(crate) hints: HintsConfig,
+
+    /// Which sources packages may be fetched from.
+    #[serde(default)]
+    pub(crate) trust: TrustConfig,
+
+    /// Keys for verifying signatures, and the namespaces that require them.
+    #[serde(default)]
+    pub(crate) signatures: SignaturesConfig,
+
+    /// Hooks run during installs and uninstalls.
+    #[serde(default)]
+    pub(crate) hooks: HooksConfig,
+
+    /// Exporting logs and traces to an OpenTelemetry collector.
+    #[serde(default)]
+    pub(crate) telemetry: TelemetryConfig,
+
+    /// External programs implementing additional namespaces, keyed by namespace.
+    #[serde(default)]
+    pub(crate) plugins: BTreeMap<String, PluginConfig>,
+
+    /// Per-package overrides, keyed by package name.
+    #[serde(default)]
+    pub(crate) packages: BTreeMap<String, PackageConfig>,
+}
+
+impl HaspConfig {
+    /// The name of the config file within the hasp home directory.
+    pub(crate) const FILE_NAME: &'static str = "config.toml";
+
+    /// Reads the config file from the given path, returning the default config if it doesn't
+    /// exist.
+    pub(crate) fn from_path(path: &Utf8Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read config at {}", path))
+            }
+        };
+        Self::parse(&contents).wrap_err_with(|| format!("failed to parse config at {}", path))
+    }
+
+    pub(crate) fn parse(contents: &str) -> Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        config.hooks.validate()?;
+        Ok(config)
+    }
+
+    /// Returns overrides for this package, if any.
+    ///
+    /// Cargo packages are keyed by name, and packages in other namespaces by `namespace:name`.
+    pub(crate) fn package(&self, namespace: &str, name: &str) -> Option<&PackageConfig> {
+        self.packages.get(&package_key(namespace, name))
+    }
+}
+
+/// Returns the key for a package in the config file or a manifest: the name for Cargo
+/// packages, and `namespace:name` otherwise.
+pub(crate) fn package_key(namespace: &str, name: &str) -> String {
+    if namespace == "cargo" {
+        name.to_owned()
+    } else {
+        format!("{}:{}", namespace, name)
+    }
+}
+
+/// Splits a package key or specifier into a namespace and the rest, defaulting to Cargo.
+pub(crate) fn split_package_key(key: &str) -> (&str, &str) {
+    key.split_once(':').unwrap_or(("cargo", key))
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct InstallConfig {
+    /// The registry index URL to install crates from. Defaults to crates.io.
+    pub(crate) registry: Option<String>,
+
+    /// The number of parallel jobs to build with.
+    pub(crate) jobs: Option<usize>,
+
+    /// The Cargo profile to build with. Defaults to `release`.
+    pub(crate) profile: Option<String>,
+
+    /// Whether to copy packages that were already built elsewhere from the shared store, then
+    /// the remote cache, before building them from source. On by default. `--prefer-prebuilt`
+    /// and `--no-prefer-prebuilt` override it.
+    pub(crate) prefer_prebuilt: Option<bool>,
+
+    /// Whether to skip versions of crates whose `rust-version` is newer than the rustc that
+    /// builds use, when resolving versions. Off by default. `--ignore-rust-version` overrides it.
+    #[serde(default)]
+    pub(crate) respect_rust_version: bool,
+
+    /// Whether to install a yanked version of a crate if it's the only one that matches, like
+    /// `--allow-yanked`. Off by default.
+    #[serde(default)]
+    pub(crate) allow_yanked: bool,
+
+    /// Whether to install npm packages without running their install scripts, like
+    /// `--ignore-scripts`. Off by default. This is recorded with each install, so changing it
+    /// installs npm packages again.
+    #[serde(default)]
+    pub(crate) ignore_scripts: bool,
+
+    /// How to access the crates.io index: `git` (the default) or `sparse`.
+    pub(crate) index_protocol: Option<IndexProtocol>,
+
+    /// The number of seconds after an update during which the git index isn't updated again.
+    /// Defaults to 15 minutes.
+    pub(crate) index_ttl: Option<u64>,
+
+    /// Environment variables to pass through to builds, in addition to a default set such as
+    /// `PATH` and `HOME`. All other variables are removed.
+    #[serde(default)]
+    pub(crate) build_env: Vec<String>,
+
+    /// The cargo executable to build with. `HASP_CARGO` takes precedence over this, and
+    /// `CARGO` or `cargo` in `PATH` are used if neither is set.
+    pub(crate) cargo: Option<Utf8PathBuf>,
+
+    /// Values to pass to Cargo with `--config`, e.g. `profile.release.lto=true`. These are
+    /// recorded with each install, so changing them installs packages again.
+    #[serde(default)]
+    pub(crate) cargo_config: Vec<String>,
+
+    /// A read-only directory of installs, laid out like the installs directory of a hasp home,
+    /// that's checked before building Cargo packages. Matching installs are copied in instead.
+    pub(crate) shared_store: Option<Utf8PathBuf>,
+
+    /// A directory of `.crate` files named `<name>-<version>.crate`, such as a local registry or
+    /// vendored crates, that Cargo packages are installed from before the registry is checked.
+    /// With `--offline`, packages that aren't in it fail to install.
+    pub(crate) mirror: Option<Utf8PathBuf>,
+
+    /// A cache of built packages on a server, shared between machines. It's checked before
+    /// building Cargo packages, after the shared store, and packages built on this machine are
+    /// uploaded to it. Off by default.
+    pub(crate) remote_cache: Option<RemoteCacheConfig>,
+
+    /// Whether to hard-link identical files across installs from a content-addressed store in the
+    /// hasp home, to save space when many versions are kept. Off by default. Run `hasp gc` to
+    /// remove files no installs use any more.
+    #[serde(default)]
+    pub(crate) content_store: bool,
+
+    /// The algorithm that the files of new installs are hashed with in their receipts: `blake3`
+    /// (the default) or `sha256`, which matches the checksums registries publish. Files are
+    /// verified with whichever algorithm they were hashed with, so changing this doesn't cause
+    /// packages to be installed again.
+    #[serde(default)]
+    pub(crate) hash_algorithm: HashAlgorithm,
+
+    /// How builds reuse work from earlier builds: `target-dir` keeps a target directory for each
+    /// crate in the cache directory, and `sccache` runs rustc through sccache. Off by default.
+    /// Changing this doesn't cause packages to be installed again.
+    pub(crate) build_cache: Option<BuildCacheMode>,
+
+    /// Environment variables to set for builds, e.g. `RUSTFLAGS` or `CC`. Variables that affect
+    /// the built artifacts are recorded with each install, so changing them installs packages
+    /// again.
+    #[serde(default)]
+    pub(crate) env: BTreeMap<String, String>,
+
+    /// The number of superseded installs of each package to keep around for `hasp rollback`.
+    /// Defaults to 1.
+    pub(crate) keep_previous: Option<usize>,
+
+    /// The number of seconds to wait for another process to release an install lock. By default,
+    /// hasp waits for as long as it takes.
+    pub(crate) lock_timeout: Option<u64>,
+}
+
+impl InstallConfig {
+    const DEFAULT_INDEX_TTL_SECS: u64 = 15 * 60;
+    const DEFAULT_KEEP_PREVIOUS: usize = 1;
+
+    /// Returns the Cargo build options specified by this config. If `refresh` is true, indexes
+    /// are updated even if they were updated recently, and if `offline` is true, crates are only
+    /// installed from the mirror.
+    pub(crate) fn build_opts(
+        &self,
+        cache_dir: &Utf8Path,
+        network: RetryPolicy,
+        refresh: bool,
+        offline: bool,
+    ) -> CargoBuildOpts {
+        CargoBuildOpts {
+            jobs: self.jobs,
+            prefer_prebuilt: self.prefer_prebuilt(),
+            index_protocol: self.index_protocol.unwrap_or_default(),
+            index_ttl: self.index_ttl(refresh),
+            network,
+            build_env: self.build_env(&BTreeMap::new()),
+            cargo: self.cargo.clone(),
+            build_cache: self.build_cache(cache_dir),
+            allow_yanked: self.allow_yanked,
+            respect_rust_version: self.respect_rust_version,
+            mirror: self.mirror.clone(),
+            offline,
+        }
+    }
+
+    /// Returns the build cache to use, if one is enabled.
+    pub(crate) fn build_cache(&self, cache_dir: &Utf8Path) -> Option<BuildCache> {
+        match self.build_cache? {
+            BuildCacheMode::TargetDir => {
+                Some(BuildCache::TargetDir(BuildCache::target_dirs(cache_dir)))
+            }
+            BuildCacheMode::Sccache => match find_in_path("sccache") {
+                Some(path) => Some(BuildCache::Sccache(path)),
+                None => {
+                    tracing::warn!(
+                        target: "hasp::output::sccache_missing",
+                        "Warning sccache not found in PATH, building without a build cache",
+                    );
+                    None
+                }
+            },
+        }
+    }
+
+    /// Returns the environment builds run in, with variables set on the command line taking
+    /// precedence over those in the config.
+    pub(crate) fn build_env(&self, cli_env: &BTreeMap<String, String>) -> BuildEnv {
+        let vars = self
+            .env
+            .iter()
+            .chain(cli_env)
+            .filter(|(name, _)| !BuildEnv::affects_artifacts(name))
+            .map(|(name, value)| (name.clone(), value.clone()));
+        BuildEnv::new(self.build_env.iter().cloned()).with_vars(vars)
+    }
+
+    /// Returns the variables that affect the built artifacts, with variables set on the command
+    /// line taking precedence over those in the config.
+    pub(crate) fn artifact_env(
+        &self,
+        cli_env: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        self.env
+            .iter()
+            .chain(cli_env)
+            .filter(|(name, _)| BuildEnv::affects_artifacts(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Returns how long an index update stays fresh for.
+    pub(crate) fn index_ttl(&self, refresh: bool) -> Duration {
+        if refresh {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(self.index_ttl.unwrap_or(Self::DEFAULT_INDEX_TTL_SECS))
+        }
+    }
+
+    /// Returns how long to wait for another process to release an install lock.
+    pub(crate) fn lock_wait(&self) -> LockWait {
+        match self.lock_timeout {
+            Some(secs) => LockWait::Timeout(Duration::from_secs(secs)),
+            None => LockWait::Forever,
+        }
+    }
+
+    /// Returns the number of superseded installs of each package to keep.
+    pub(crate) fn keep_previous(&self) -> usize {
+        self.keep_previous.unwrap_or(Self::DEFAULT_KEEP_PREVIOUS)
+    }
+
+    /// Returns whether prebuilt binaries are copied in before building from source.
+    pub(crate) fn prefer_prebuilt(&self) -> bool {
+        self.prefer_prebuilt.unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct RemoteCacheConfig {
+    /// The base URL of the cache. Builds are downloaded with GET and uploaded with PUT, so any
+    /// HTTP server or S3-compatible bucket that allows those requests can be used.
+    pub(crate) url: String,
+
+    /// Whether to upload packages built on this machine. Defaults to true.
+    pub(crate) upload: Option<bool>,
+
+    /// The environment variable holding a token to send as `Authorization: Bearer <token>`.
+    pub(crate) token_env: Option<String>,
+}
+
+impl RemoteCacheConfig {
+    /// Returns true if packages built on this machine are uploaded.
+    pub(crate) fn upload(&self) -> bool {
+        self.upload.unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct OutputConfig {
+    /// Whether to produce color output.
+    pub(crate) color: Option<Color>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct TelemetryConfig {
+    /// The base URL of an OpenTelemetry collector to send logs and traces to with OTLP over HTTP,
+    /// e.g. `http://localhost:4318`. `OTEL_EXPORTER_OTLP_ENDPOINT` takes precedence. Off by
+    /// default.
+    pub(crate) otlp_endpoint: Option<String>,
+
+    /// Headers to send to the collector, e.g. for authentication. Headers in
+    /// `OTEL_EXPORTER_OTLP_HEADERS` are added to these.
+    #[serde(default)]
+    pub(crate) otlp_headers: BTreeMap<String, String>,
+
+    /// The service name to report data under. `OTEL_SERVICE_NAME` takes precedence. Defaults to
+    /// `hasp`.
+    pub(crate) service_name: Option<String>,
+}
+
+impl TelemetryConfig {
+    /// Returns where to export tracing data to, or `None` if exporting is off.
+    pub(crate) fn otlp_settings(&self) -> Option<OtlpSettings> {
+        OtlpSettings::new(
+            self.otlp_endpoint.as_deref(),
+            &self.otlp_headers,
+            self.service_name.as_deref(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct PluginConfig {
+    /// The command to run, as a program followed by its arguments.
+    pub(crate) command: Vec<String>,
+
+    /// Where packages are fetched from, for approving sources, e.g. `pypi.org`. Defaults to the
+    /// file name of the program.
+    pub(crate) source: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct DatabaseConfig {
+    /// The number of times to retry a write if another hasp process holds the database lock.
+    pub(crate) busy_retries: Option<u32>,
+
+    /// The size in bytes that write-ahead logs are truncated to after checkpoints, or -1 for no
+    /// limit.
+    pub(crate) journal_size_limit: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct NetworkConfig {
+    /// The number of times to retry a failed network operation.
+    pub(crate) retries: Option<u32>,
+
+    /// The time limit in seconds for each attempt at a network operation.
+    pub(crate) timeout: Option<u64>,
+
+    /// The time limit in seconds across all attempts at a network operation.
+    pub(crate) deadline: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct EventsConfig {
+    /// The number of days to keep events for. If set along with `max-rows`, both apply.
+    pub(crate) max_age_days: Option<u64>,
+
+    /// The maximum number of events to keep.
+    pub(crate) max_rows: Option<u64>,
+}
+
+impl EventsConfig {
+    /// Returns the retention policy for the journal. If it's limited, it's enforced whenever hasp
+    /// starts up.
+    pub(crate) fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age: self.max_age_days.map(days_to_duration),
+            max_rows: self.max_rows,
+        }
+    }
+}
+
+/// Converts a number of days from the config or the command line into a duration.
+pub(crate) fn days_to_duration(days: u64) -> chrono::Duration {
+    chrono::Duration::days(days.min(i64::MAX as u64 / 86_400) as i64)
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct HintsConfig {
+    /// Whether to print hints when installing deprecated packages.
+    pub(crate) enabled: Option<bool>,
+
+    /// The URL that `hasp hints update` fetches hints from.
+    pub(crate) url: Option<String>,
+
+    /// Extra hints, keyed by package name. These take precedence over fetched hints.
+    #[serde(default)]
+    pub(crate) packages: BTreeMap<String, PackageHint>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct TrustConfig {
+    /// Whether packages may only be fetched from trusted sources. Sources that aren't listed
+    /// below must be approved the first time they're used.
+    #[serde(default)]
+    pub(crate) require_approval: bool,
+
+    /// Sources that are always trusted, e.g. `cargo:crates.io` or `oci:ghcr.io`.
+    #[serde(default)]
+    pub(crate) sources: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct SignaturesConfig {
+    /// Namespaces whose packages must have a valid signature, e.g. `cargo` or `oci`.
+    #[serde(default)]
+    pub(crate) require: Vec<String>,
+
+    /// Public keys for minisign signatures, as paths to `.pub` files.
+    #[serde(default)]
+    pub(crate) minisign_keys: Vec<Utf8PathBuf>,
+
+    /// Public keys for sigstore signatures, as paths to files that cosign accepts with `--key`.
+    #[serde(default)]
+    pub(crate) cosign_keys: Vec<Utf8PathBuf>,
+}
+
+impl SignaturesConfig {
+    /// Returns true if packages in this namespace must have a valid signature.
+    pub(crate) fn is_required(&self, namespace: &str) -> bool {
+        self.require.iter().any(|required| required == namespace)
+    }
+
+    /// Returns true if any keys are configured, in which case signatures are verified wherever
+    /// they're available.
+    pub(crate) fn has_keys(&self) -> bool {
+        !self.minisign_keys.is_empty() || !self.cosign_keys.is_empty()
+    }
+
+    /// Returns the keys configured for this kind of signature.
+    pub(crate) fn keys(&self, method: SignatureMethod) -> &[Utf8PathBuf] {
+        match method {
+            SignatureMethod::Minisign => &self.minisign_keys,
+            SignatureMethod::Sigstore => &self.cosign_keys,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct PackageConfig {
+    /// The version requirement to use if one isn't specified on the command line.
+    pub(crate) version: Option<VersionReq>,
+
+    /// Features to activate if none are specified on the command line.
+    pub(crate) features: Option<Vec<String>>,
+
+    /// Whether to activate default features.
+    pub(crate) default_features: Option<bool>,
+
+    /// The expected checksum of the fetched artifact, e.g. the `.crate` file for Cargo packages.
+    ///
+    /// Installs fail if the fetched artifact doesn't match.
+    pub(crate) checksum: Option<Checksum>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_parse() {
+        let config = HaspConfig::parse(
+            r#"
+            [install]
+            jobs = 4
+            profile = "dev"
+            prefer-prebuilt = false
+            index-protocol = "sparse"
+            index-ttl = 60
+            allow-yanked = true
+            ignore-scripts = true
+            build-env = ["RUSTC_WRAPPER"]
+            cargo = "/opt/rust/bin/cargo"
+            cargo-config = ["profile.release.lto=true"]
+            shared-store = "/mnt/team/hasp/installs"
+            remote-cache = { url = "https://cache.example.com/hasp", upload = false }
+            build-cache = "target-dir"
+            env = { RUSTFLAGS = "-C target-cpu=native", CARGO_TARGET_DIR = "/tmp/target" }
+            keep-previous = 3
+            lock-timeout = 300
+
+            [output]
+            color = "never"
+
+            [database]
+            busy-retries = 5
+            journal-size-limit = 1048576
+
+            [network]
+            retries = 5
+            deadline = 120
+
+            [events]
+            max-age-days = 30
+
+            [hints]
+            url = "https://example.com/hints.toml"
+
+            [hints.packages.foo]
+            reason = "is unmaintained"
+            replacement = "bar"
+
+            [trust]
+            require-approval = true
+            sources = ["cargo:crates.io"]
+
+            [signatures]
+            require = ["oci"]
+            minisign-keys = ["/etc/hasp/minisign.pub"]
+
+            [[hooks.post-install]]
+            action = "smoke-test"
+
+            [packages.ripgrep]
+            version = "13"
+            features = ["pcre2"]
+            default-features = false
+
+            [packages."npm:prettier"]
+            version = "=2.4.1"
+            checksum = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
+            "#,
+        )
+        .expect("config parsed");
+
+        assert_eq!(config.install.registry, None);
+        assert_eq!(config.install.jobs, Some(4));
+        assert_eq!(config.install.profile.as_deref(), Some("dev"));
+        assert!(!config.install.prefer_prebuilt());
+        assert!(InstallConfig::default().prefer_prebuilt());
+        assert_eq!(config.install.index_protocol, Some(IndexProtocol::Sparse));
+        assert_eq!(config.install.index_ttl(false), Duration::from_secs(60));
+        assert_eq!(config.install.index_ttl(true), Duration::ZERO);
+        assert!(config.install.allow_yanked);
+        assert!(config.install.ignore_scripts);
+        assert_eq!(config.install.build_env, vec!["RUSTC_WRAPPER"]);
+        assert!(matches!(
+            config.install.build_cache(Utf8Path::new("/cache")),
+            Some(BuildCache::TargetDir(dir)) if dir == "/cache/build-cache"
+        ));
+        let cli_env = BTreeMap::from([("CC".to_owned(), "clang".to_owned())]);
+        assert_eq!(
+            config
+                .install
+                .artifact_env(&cli_env)
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["CC", "RUSTFLAGS"]
+        );
+        let remote_cache = config
+            .install
+            .remote_cache
+            .as_ref()
+            .expect("remote cache set");
+        assert_eq!(remote_cache.url, "https://cache.example.com/hasp");
+        assert!(!remote_cache.upload());
+        assert_eq!(remote_cache.token_env, None);
+        assert_eq!(config.install.keep_previous(), 3);
+        assert_eq!(
+            config.install.lock_wait(),
+            LockWait::Timeout(Duration::from_secs(300))
+        );
+        assert_eq!(config.output.color, Some(Color::Never));
+        assert_eq!(config.database.busy_retries, Some(5));
+        assert_eq!(config.database.journal_size_limit, Some(1048576));
+        assert_eq!(config.network.retries, Some(5));
+        assert_eq!(config.network.timeout, None);
+        assert_eq!(config.network.deadline, Some(120));
+        let retention = config.events.retention();
+        assert_eq!(retention.max_age, Some(chrono::Duration::days(30)));
+        assert_eq!(retention.max_rows, None);
+        assert!(config.hints.enabled());
+        assert_eq!(config.hints.url(), "https://example.com/hints.toml");
+        assert_eq!(
+            config.hints.packages["foo"].replacement.as_deref(),
+            Some("bar")
+        );
+
+        assert!(config.trust.require_approval);
+        assert_eq!(config.trust.sources, vec!["cargo:crates.io"]);
+        assert!(config.signatures.is_required("oci"));
+        assert!(!config.signatures.is_required("cargo"));
+        assert!(config.signatures.has_keys());
+        assert!(config.signatures.keys(SignatureMethod::Sigstore).is_empty());
+        assert_eq!(config.hooks.post_install.len(), 1);
+
+        let ripgrep = config
+            .package("cargo", "ripgrep")
+            .expect("ripgrep config present");
+        assert_eq!(ripgrep.version, Some("^13".parse().unwrap()));
+        assert_eq!(ripgrep.features, Some(vec!["pcre2".to_owned()]));
+        assert_eq!(ripgrep.default_features, Some(false));
+        assert!(config.package("cargo", "fd-find").is_none());
+
+        let prettier = config
+            .package("npm", "prettier")
+            .expect("prettier config present");
+        assert_eq!(prettier.version, Some("=2.4.1".parse().unwrap()));
+        assert_eq!(
+            prettier.checksum,
+            Some(
+                "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert_eq!(ripgrep.checksum, None);
+        assert!(config.package("cargo", "prettier").is_none());
+
+        let empty = HaspConfig::parse("").expect("empty config parsed");
+        assert!(empty.packages.is_empty());
+
+        assert!(
+            HaspConfig::parse("[install]\njbos = 4").is_err(),
+            "unknown fields are rejected"
+        );
+        assert!(
+            HaspConfig::parse("[[hooks.pre-resolve]]\naction = \"smoke-test\"").is_err(),
+            "invalid hooks are rejected"
+        );
+    }
+}
@@ -1,18 +1,29 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use crate::events::EventLogger;
+use crate::{
+    config::HaspConfig,
+    events::{upgrade_journal, EventLogger},
+    home::HaspHome,
+    ops::{find_lock_holders, LockHolder},
+};
 use camino::{Utf8Path, Utf8PathBuf};
-use chrono::Local;
+use chrono::{DateTime, Local};
 use color_eyre::{
-    eyre::{bail, WrapErr},
+    eyre::{bail, eyre, WrapErr},
     Report, Result,
 };
+use hasp_metadata::{Event, MigrationEvent, MigrationRollback};
 use include_dir::{include_dir, Dir};
 use once_cell::sync::OnceCell;
-use rusqlite::{params, Connection, DatabaseName, Transaction};
-use serde::Serialize;
-use std::{collections::BTreeMap, fmt, sync::Arc};
+use rusqlite::{params, Connection, DatabaseName, ErrorCode, Transaction, TransactionBehavior};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
 
 const SQL_DIR: Dir = include_dir!("sql");
 
@@ -26,6 +37,9 @@ pub(crate) struct DbContext {
 pub(crate) struct ConnectionCreator {
     inner: Arc<dyn CreateConnectionImpl>,
     initialized: Arc<OnceCell<()>>,
+    pool: Arc<ConnectionPool>,
+    busy_retries: u32,
+    journal_size_limit: i64,
 }
 
 impl ConnectionCreator {
@@ -36,15 +50,41 @@ impl ConnectionCreator {
     const APPLICATION_ID_PRAGMA: &'static str = "application_id";
     const APPLICATION_ID: i32 = 0x68617370;
 
+    // The version of the database format, stored as the SQLite user version. Schema changes are
+    // handled through migrations, so this is only bumped for changes that older versions of hasp
+    // can't cope with even through `hasp db migrate`. Databases created before it was recorded
+    // have version 0.
+    const USER_VERSION_PRAGMA: &'static str = "user_version";
+    const SCHEMA_VERSION: i32 = 1;
+
     // Busy timeout.
     const BUSY_TIMEOUT_MS: u32 = 5000;
 
-    pub(crate) fn new(hasp_home: impl Into<Utf8PathBuf>) -> Self {
+    // The number of times to retry obtaining the write lock after the busy timeout, by default.
+    const DEFAULT_BUSY_RETRIES: u32 = 2;
+
+    // The size that write-ahead logs are truncated to after checkpoints, by default (32 MiB).
+    const DEFAULT_JOURNAL_SIZE_LIMIT: i64 = 32 * 1024 * 1024;
+
+    // The number of prepared statements cached by each connection. Pooled connections keep their
+    // caches, so this is enough to hold the statements an install uses.
+    const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+    pub(crate) fn new(hasp_home: &HaspHome) -> Self {
+        let database_config = &hasp_home.config().database;
         Self {
             inner: Arc::new(DiskDb {
-                hasp_home: hasp_home.into(),
+                hasp_home: hasp_home.home_dir().to_path_buf(),
+                installs_dir: hasp_home.installs_dir().to_path_buf(),
             }),
             initialized: Arc::new(OnceCell::new()),
+            pool: Arc::new(ConnectionPool::default()),
+            busy_retries: database_config
+                .busy_retries
+                .unwrap_or(Self::DEFAULT_BUSY_RETRIES),
+            journal_size_limit: database_config
+                .journal_size_limit
+                .unwrap_or(Self::DEFAULT_JOURNAL_SIZE_LIMIT),
         }
     }
 
@@ -53,11 +93,56 @@ impl ConnectionCreator {
         Self {
             inner: Arc::new(InMemoryDb),
             initialized: Arc::new(OnceCell::new()),
+            pool: Arc::new(ConnectionPool::default()),
+            busy_retries: 0,
+            journal_size_limit: Self::DEFAULT_JOURNAL_SIZE_LIMIT,
         }
     }
 
-    pub(crate) fn create(&self) -> Result<Connection> {
+    /// Returns a connection to the main and packages databases, reusing one that was returned to
+    /// the pool if there is one.
+    pub(crate) fn create(&self) -> Result<PooledConnection> {
+        let conn = match self.pool.take() {
+            Some(conn) => conn,
+            None => self.open()?,
+        };
+        Ok(PooledConnection {
+            conn: Some(conn),
+            pool: self.pool.clone(),
+        })
+    }
+
+    /// Runs `f` with a connection from the pool on a thread where blocking is allowed, so that
+    /// waiting for the database doesn't hold up other work on the async runtime, such as
+    /// concurrent installs.
+    pub(crate) async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let creator = self.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = creator.create()?;
+            f(&mut conn)
+        })
+        .await;
+        match result {
+            Ok(result) => result,
+            Err(err) => match err.try_into_panic() {
+                Ok(payload) => std::panic::resume_unwind(payload),
+                Err(err) => Err(err).wrap_err_with(|| {
+                    format!("database task for {} failed", self.inner.description())
+                }),
+            },
+        }
+    }
+
+    /// Opens a new connection to the main and packages databases, bypassing the pool.
+    fn open(&self) -> Result<Connection> {
         let conn = self.inner.create_impl()?;
+        for (db, name) in Self::DATABASES.into_iter().zip(["main", "packages"]) {
+            self.validate(&conn, db, name)?;
+        }
 
         // Turn on foreign key support and a busy timeout.
         conn.pragma_update(None, "foreign_keys", "ON")
@@ -75,12 +160,17 @@ impl ConnectionCreator {
                     self.inner.description()
                 )
             })?;
+        for db in Self::DATABASES {
+            self.set_journal_size_limit(&conn, db)?;
+        }
+        conn.set_prepared_statement_cache_capacity(Self::STATEMENT_CACHE_CAPACITY);
 
         Ok(conn)
     }
 
     pub(crate) fn create_events(&self) -> Result<Connection> {
         let conn = self.inner.create_events()?;
+        self.validate(&conn, DatabaseName::Main, "events")?;
 
         // Turn on the busy timeout (foreign key support isn't required).
         conn.pragma_update(None, "busy_timeout", Self::BUSY_TIMEOUT_MS)
@@ -91,14 +181,166 @@ impl ConnectionCreator {
                     self.inner.description()
                 )
             })?;
+        self.set_journal_size_limit(&conn, DatabaseName::Main)?;
 
         Ok(conn)
     }
 
+    /// Returns the sizes of database files and their write-ahead logs.
+    pub(crate) fn file_statuses(&self) -> Vec<DatabaseFileStatus> {
+        self.inner
+            .files()
+            .into_iter()
+            .map(|(name, path)| {
+                let wal_path = Utf8PathBuf::from(format!("{}-wal", path));
+                DatabaseFileStatus {
+                    name,
+                    size: file_size(&path),
+                    wal_size: file_size(&wal_path),
+                    path,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the path to the database file with this name (`main`, `packages` or `events`), or
+    /// `None` for in-memory databases.
+    pub(crate) fn file_path(&self, name: &str) -> Option<Utf8PathBuf> {
+        self.inner
+            .files()
+            .into_iter()
+            .find_map(|(file_name, path)| (file_name == name).then_some(path))
+    }
+
+    #[inline]
+    pub(crate) fn journal_size_limit(&self) -> i64 {
+        self.journal_size_limit
+    }
+
+    /// Checkpoints the write-ahead logs for all databases into the databases, truncating them.
+    ///
+    /// If `wait` is false, databases that are in use by other connections are skipped rather than
+    /// waited on.
+    pub(crate) fn checkpoint(&self, wait: bool) -> Result<Vec<CheckpointStatus>> {
+        // The busy timeout may be changed below, so this connection isn't returned to the pool.
+        let conn = self.open()?;
+        let events_conn = self.create_events()?;
+        if !wait {
+            for conn in [&conn, &events_conn] {
+                conn.busy_timeout(Duration::from_millis(0))
+                    .wrap_err("setting busy timeout failed")?;
+            }
+        }
+
+        let mut statuses = Vec::with_capacity(Self::DATABASES.len() + 1);
+        for (db, name) in Self::DATABASES.into_iter().zip(["main", "packages"]) {
+            statuses.push(checkpoint_one(&conn, db, name)?);
+        }
+        statuses.push(checkpoint_one(&events_conn, DatabaseName::Main, "events")?);
+        Ok(statuses)
+    }
+
+    /// Runs `PRAGMA optimize` on the pooled connections, closing them. This analyzes the tables
+    /// that queries on those connections used if their statistics are out of date, so that the
+    /// query planner keeps picking good indexes as the databases grow. SQLite recommends doing
+    /// this before connections are closed, and it usually does nothing.
+    ///
+    /// If `wait` is false, databases that are in use by other connections are skipped rather than
+    /// waited on.
+    pub(crate) fn optimize(&self, wait: bool) -> Result<()> {
+        for conn in self.pool.drain() {
+            if !wait {
+                conn.busy_timeout(Duration::from_millis(0))
+                    .wrap_err("setting busy timeout failed")?;
+            }
+            for db in Self::DATABASES {
+                let schema = schema_name(db);
+                match conn.execute_batch(&format!("PRAGMA {}.optimize", schema)) {
+                    Ok(()) => {}
+                    Err(err) if !wait && is_busy(&err) => {}
+                    Err(err) => {
+                        return Err(err)
+                            .wrap_err_with(|| format!("optimizing database {} failed", schema));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Begins a transaction that writes to the database.
+    ///
+    /// The write lock is obtained up front, so a transaction can't fail partway through because
+    /// another process is writing to the database. If the lock can't be obtained within the busy
+    /// timeout, this is retried a configurable number of times.
+    pub(crate) fn write_transaction<'conn>(
+        &self,
+        conn: &'conn mut Connection,
+    ) -> Result<Transaction<'conn>> {
+        // A shared reference allows retries within the loop below.
+        let conn: &'conn Connection = conn;
+        let mut retries = 0;
+        loop {
+            match Transaction::new_unchecked(conn, TransactionBehavior::Immediate) {
+                Ok(txn) => return Ok(txn),
+                Err(err) if is_busy(&err) && retries < self.busy_retries => {
+                    retries += 1;
+                    let database = self.inner.description();
+                    let holders = self.describe_lock_holders();
+                    tracing::warn!(
+                        target: "hasp::output::database_busy",
+                        %database,
+                        %holders,
+                        retry = retries,
+                        retries = self.busy_retries,
+                        "Waiting for another hasp process to unlock the database at \
+                         {database}{holders} (retry {retry} of {retries})",
+                        retry = retries,
+                        retries = self.busy_retries,
+                    );
+                }
+                Err(err) if is_busy(&err) => {
+                    return Err(err).wrap_err_with(|| {
+                        format!(
+                            "database at {} is locked by another hasp process{} \
+                            (tried {} times, waiting {} ms each; hint: wait for the other process to finish, \
+                            or increase database.busy-retries in {})",
+                            self.inner.description(),
+                            self.describe_lock_holders(),
+                            retries + 1,
+                            Self::BUSY_TIMEOUT_MS,
+                            HaspConfig::FILE_NAME,
+                        )
+                    });
+                }
+                Err(err) => {
+                    return Err(err).wrap_err_with(|| {
+                        format!(
+                            "starting write transaction failed for {}",
+                            self.inner.description()
+                        )
+                    })
+                }
+            }
+        }
+    }
+
     /// Create a connection and initialize it.
     pub(crate) fn initialize(&self, event_logger: &EventLogger) -> Result<()> {
+        self.initialize_to(event_logger, None)?;
+        Ok(())
+    }
+
+    /// Initializes the databases, applying migrations up to and including `target`, or all
+    /// migrations if it's `None`. Returns the names of the migrations that were applied.
+    fn initialize_to(
+        &self,
+        event_logger: &EventLogger,
+        target: Option<&str>,
+    ) -> Result<Vec<&'static str>> {
         let mut conn = self.create()?;
         let events_conn = self.create_events()?;
+        let mut performed = vec![];
 
         // Initialize and run migrations the first time this creator opens a connection.
         // TODO: should we open the db in read-only mode sometimes?
@@ -110,26 +352,21 @@ impl ConnectionCreator {
             }
             self.enable_wal(&events_conn, DatabaseName::Main)?;
 
-            let txn = conn.transaction()?;
+            let txn = self.write_transaction(&mut conn)?;
 
             for db in Self::DATABASES {
-                // Write out the application ID -- this is persistent.
-                // TODO: read it to check its value and fail if it doesn't match?
-                self.set_application_id(&txn, db)
+                // Write out the application ID and schema version -- these are persistent, and
+                // are checked whenever a connection is created.
+                self.mark_as_hasp(&txn, db)
                     .wrap_err("setting application ID failed")?;
             }
 
-            self.set_application_id(&events_conn, DatabaseName::Main)
+            self.mark_as_hasp(&events_conn, DatabaseName::Main)
                 .wrap_err("setting application ID failed for events DB")?;
 
             // Initialize tables that stay the same.
 
-            let init_sql = SQL_DIR
-                .get_file("init.sql")
-                .expect("sql/init.sql exists")
-                .contents_utf8()
-                .expect("sql/init.sql is valid UTF-8");
-            txn.execute_batch(init_sql).wrap_err_with(|| {
+            txn.execute_batch(init_sql()).wrap_err_with(|| {
                 format!(
                     "creating initial tables failed for {}",
                     self.inner.description()
@@ -149,9 +386,12 @@ impl ConnectionCreator {
                         self.inner.description()
                     )
                 })?;
+            upgrade_journal(&events_conn).wrap_err_with(|| {
+                format!("upgrading events db failed at {}", self.inner.description())
+            })?;
 
             // Run migrations.
-            run_migrations(&txn, event_logger).wrap_err_with(|| {
+            performed = run_migrations(&txn, event_logger, target).wrap_err_with(|| {
                 format!("running migrations failed for {}", self.inner.description())
             })?;
 
@@ -163,13 +403,424 @@ impl ConnectionCreator {
             })
         })?;
 
-        Ok(())
+        Ok(performed)
+    }
+
+    /// Checks the health of the databases, for `hasp doctor`. Returns the name of each check and
+    /// its result.
+    ///
+    /// Unlike other methods, this doesn't initialize or migrate the databases, so that problems
+    /// with them are reported rather than papered over.
+    pub(crate) fn check_health(&self) -> Vec<(String, Result<()>)> {
+        let statuses = self.file_statuses();
+        if statuses.iter().all(|status| status.size.is_none()) {
+            // The databases are created the first time they're used.
+            return vec![];
+        }
+
+        // Opening a connection would create any missing files, so check for them first.
+        let missing: Vec<_> = statuses
+            .iter()
+            .filter(|status| status.size.is_none())
+            .map(|status| {
+                (
+                    format!("database {} at {}", status.name, status.path),
+                    Err(eyre!(
+                        "database file is missing (hint: {})",
+                        restore_hint(status.name)
+                    )),
+                )
+            })
+            .collect();
+        if !missing.is_empty() {
+            return missing;
+        }
+
+        // A new connection is opened so that problems opening one are reported.
+        let (conn, events_conn) = match self.open().and_then(|conn| {
+            let events_conn = self.create_events()?;
+            Ok((conn, events_conn))
+        }) {
+            Ok(conns) => conns,
+            Err(err) => return vec![("opening databases".to_owned(), Err(err))],
+        };
+
+        let mut checks = vec![];
+        let dbs = [
+            (&conn, DatabaseName::Main),
+            (&conn, DatabaseName::Attached("packages")),
+            (&events_conn, DatabaseName::Main),
+        ];
+        for ((conn, db), status) in dbs.into_iter().zip(&statuses) {
+            checks.push((
+                format!("database {} at {}", status.name, status.path),
+                self.check_one(conn, db, status),
+            ));
+        }
+        checks.push((
+            "database schema version".to_owned(),
+            check_schema_version(&conn),
+        ));
+        checks
+    }
+
+    /// Replaces the packages database with an empty one, for `hasp db rebuild-from-disk`. The old
+    /// database files are moved aside rather than deleted, and the path they were moved to is
+    /// returned if they existed.
+    ///
+    /// Migration state is tracked in the main database, so the new packages database is brought up
+    /// to the same migration as the main database.
+    pub(crate) fn recreate_packages(&self) -> Result<Option<Utf8PathBuf>> {
+        let files = self.inner.files();
+        let (main_path, packages_path) = match files.as_slice() {
+            [(_, main_path), (_, packages_path), ..] => (main_path, packages_path),
+            _ => bail!(
+                "{} can't be rebuilt since it isn't stored on disk",
+                self.inner.description()
+            ),
+        };
+        let holders = self.describe_lock_holders();
+        if !holders.is_empty() {
+            bail!(
+                "can't rebuild the packages database while installs are in progress{} (hint: wait \
+                 for them to finish)",
+                holders,
+            );
+        }
+
+        // Find the migration the main database is at, without running any migrations.
+        let last_applied = match file_size(main_path) {
+            Some(_) => {
+                let conn = Connection::open(main_path)
+                    .wrap_err_with(|| format!("opening DB at {} failed", main_path))?;
+                match last_applied_migration(&conn) {
+                    Ok(last_applied) => last_applied,
+                    Err(err) if is_missing_table_report(&err) => None,
+                    Err(err) => return Err(err),
+                }
+            }
+            None => None,
+        };
+
+        // Pooled connections would keep using the old files.
+        self.pool.clear();
+        let backup_path = move_file_aside(packages_path)?;
+
+        // Run migrations against a scratch main database, so that only the packages schema is
+        // created.
+        let mut conn = Connection::open_in_memory().wrap_err("opening in memory db failed")?;
+        conn.execute("ATTACH DATABASE ?1 as packages", [packages_path.as_str()])
+            .wrap_err_with(|| format!("attaching packages DB at {} failed", packages_path))?;
+        let packages = DatabaseName::Attached("packages");
+        self.enable_wal(&conn, packages)?;
+        let txn = conn.transaction()?;
+        self.mark_as_hasp(&txn, packages)
+            .wrap_err("setting application ID failed")?;
+        txn.execute_batch(init_sql())
+            .wrap_err("creating initial tables failed for scratch database")?;
+        if let Some(last_applied) = &last_applied {
+            for (name, migration) in all_migrations()
+                .into_iter()
+                .filter(|(name, _)| *name <= last_applied.as_str())
+            {
+                run_one_migration(&txn, name, migration.up)?;
+            }
+        }
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "failed to commit new packages database at {}",
+                packages_path
+            )
+        })?;
+
+        Ok(backup_path)
+    }
+
+    /// Returns the database files that are corrupted, along with what's wrong with them, for
+    /// `hasp db repair`.
+    ///
+    /// Like `check_health`, this doesn't initialize or migrate the databases, and each file is
+    /// opened on its own so that one corrupted file doesn't hide the state of the others.
+    pub(crate) fn find_corrupted(&self) -> Result<Vec<CorruptedFile>> {
+        let mut corrupted = vec![];
+        for (name, path) in self.inner.files() {
+            if file_size(&path).is_none() {
+                continue;
+            }
+            let quick_check = Connection::open(&path).and_then(|conn| {
+                conn.pragma_query_value(None, "quick_check", |row| row.get::<_, String>(0))
+            });
+            let problem = match quick_check {
+                Ok(result) if result == "ok" => continue,
+                Ok(result) => result,
+                Err(err) if is_corrupt(&err) => err.to_string(),
+                Err(err) => {
+                    return Err(err)
+                        .wrap_err_with(|| format!("checking database file {} failed", path))
+                }
+            };
+            corrupted.push(CorruptedFile {
+                name,
+                path,
+                problem,
+            });
+        }
+        Ok(corrupted)
+    }
+
+    /// Moves the database file with this name (`main`, `packages` or `events`) aside, along with
+    /// its write-ahead log, so that it's created afresh the next time it's used. Returns the path
+    /// it was moved to, if it existed.
+    pub(crate) fn move_aside(&self, name: &str) -> Result<Option<Utf8PathBuf>> {
+        let path = match self.file_path(name) {
+            Some(path) => path,
+            None => bail!(
+                "{} can't be moved aside since it isn't stored on disk",
+                self.inner.description()
+            ),
+        };
+        let holders = self.describe_lock_holders();
+        if !holders.is_empty() {
+            bail!(
+                "can't move the {} database aside while installs are in progress{} (hint: wait \
+                 for them to finish)",
+                name,
+                holders,
+            );
+        }
+        // Pooled connections would keep using the old file.
+        self.pool.clear();
+        move_file_aside(&path)
+    }
+
+    /// Returns the state of every migration that's known to this version of hasp or recorded in
+    /// the database, in order, for `hasp db status`.
+    ///
+    /// Like `check_health`, this doesn't initialize or migrate the databases.
+    pub(crate) fn migration_statuses(&self) -> Result<Vec<MigrationStatus>> {
+        // Opening a connection would create the databases, so only look in existing ones.
+        let exists = self
+            .inner
+            .files()
+            .first()
+            .is_none_or(|(_, path)| file_size(path).is_some());
+        let mut recorded = if exists {
+            let conn = self.create()?;
+            recorded_migrations(&conn)?
+        } else {
+            BTreeMap::new()
+        };
+
+        let all_migrations = all_migrations();
+        let names: BTreeSet<String> = all_migrations
+            .keys()
+            .map(|name| (*name).to_owned())
+            .chain(recorded.keys().cloned())
+            .collect();
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let state = recorded
+                    .remove(&name)
+                    .map_or(MigrationState::Pending, |recorded| recorded.state);
+                MigrationStatus {
+                    known: all_migrations.contains_key(name.as_str()),
+                    name,
+                    state,
+                }
+            })
+            .collect())
+    }
+
+    /// Migrates the databases to `target`, or to the latest migration known to this version of
+    /// hasp if it's `None`, for `hasp db migrate`. Migrations after the target are rolled back,
+    /// newest first, and migrations up to it are applied.
+    ///
+    /// Migrations that this version of hasp doesn't know about are rolled back through the SQL
+    /// recorded when they were applied, so that a database can be rolled back after downgrading
+    /// hasp.
+    pub(crate) fn migrate(
+        &self,
+        target: Option<&str>,
+        event_logger: &EventLogger,
+    ) -> Result<MigrateSummary> {
+        let holders = self.describe_lock_holders();
+        if !holders.is_empty() {
+            bail!(
+                "can't migrate the database while installs are in progress{} (hint: wait for \
+                 them to finish)",
+                holders,
+            );
+        }
+
+        let all_migrations = all_migrations();
+        let mut conn = self.create()?;
+        let recorded = recorded_migrations(&conn)?;
+        let target = match target {
+            Some(target) => {
+                if !all_migrations.contains_key(target) && !recorded.contains_key(target) {
+                    bail!(
+                        "unknown migration {} (hint: run `hasp db status` to list migrations)",
+                        target
+                    );
+                }
+                target.to_owned()
+            }
+            None => all_migrations
+                .keys()
+                .next_back()
+                .expect("at least one migration known to hasp")
+                .to_string(),
+        };
+
+        let applied: Vec<_> = recorded
+            .iter()
+            .filter(|(_, recorded)| matches!(recorded.state, MigrationState::Applied(_)))
+            .collect();
+        if applied
+            .iter()
+            .all(|(name, _)| name.as_str() <= target.as_str())
+        {
+            if !all_migrations.contains_key(target.as_str()) {
+                bail!(
+                    "migration {} isn't known to this version of hasp, so it can't be applied \
+                     (hint: upgrade hasp)",
+                    target
+                );
+            }
+            // Applying migrations is part of initializing the databases.
+            let applied = self.initialize_to(event_logger, Some(&target))?;
+            return Ok(MigrateSummary {
+                target,
+                applied: applied.into_iter().map(|name| name.to_owned()).collect(),
+                rolled_back: vec![],
+            });
+        }
+
+        let txn = self.write_transaction(&mut conn)?;
+        let mut rolled_back = vec![];
+        for (name, recorded) in applied
+            .into_iter()
+            .rev()
+            .filter(|(name, _)| name.as_str() > target.as_str())
+        {
+            // Prefer the SQL known to this version of hasp, in case the recorded SQL is outdated.
+            let down_sql = match all_migrations.get(name.as_str()) {
+                Some(migration) => migration
+                    .down
+                    .ok_or_else(|| eyre!("migration {} can't be rolled back", name))?,
+                None => recorded.down_sql.as_deref().ok_or_else(|| {
+                    eyre!(
+                        "migration {} can't be rolled back, since the version of hasp that \
+                         applied it didn't record how (hint: upgrade hasp)",
+                        name
+                    )
+                })?,
+            };
+            tracing::debug!("rolling back migration {}", name);
+            txn.execute_batch(down_sql)
+                .wrap_err_with(|| format!("failed to roll back migration {}", name))?;
+            txn.execute(
+                "UPDATE migration_status SET state = ?2, rollback_time = ?3 WHERE name = ?1",
+                params![name, "rolled-back", Local::now()],
+            )
+            .wrap_err_with(|| format!("failed to mark migration {} as rolled back", name))?;
+            event_logger.log(Event::MigrationReverted(MigrationEvent {
+                name: name.clone(),
+            }));
+            rolled_back.push(name.clone());
+        }
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "committing rollback failed for {}",
+                self.inner.description()
+            )
+        })?;
+
+        Ok(MigrateSummary {
+            target,
+            applied: vec![],
+            rolled_back,
+        })
     }
 
     // ---
     // Helper methods
     // ---
 
+    fn check_one(
+        &self,
+        conn: &Connection,
+        db: DatabaseName,
+        status: &DatabaseFileStatus,
+    ) -> Result<()> {
+        let application_id: i32 =
+            conn.pragma_query_value(Some(db), Self::APPLICATION_ID_PRAGMA, |row| row.get(0))?;
+        if application_id != Self::APPLICATION_ID {
+            bail!(
+                "application ID is {:#x} rather than {:#x} (hint: this file may not have been \
+                 created by hasp)",
+                application_id,
+                Self::APPLICATION_ID,
+            );
+        }
+
+        let journal_mode: String =
+            conn.pragma_query_value(Some(db), "journal_mode", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            bail!(
+                "journal mode is {} rather than WAL (hint: the next hasp command to use the \
+                 database turns write-ahead logging back on)",
+                journal_mode,
+            );
+        }
+
+        let quick_check: String =
+            conn.pragma_query_value(Some(db), "quick_check", |row| row.get(0))?;
+        if quick_check != "ok" {
+            bail!(
+                "integrity check failed: {} (hint: {})",
+                quick_check,
+                restore_hint(status.name),
+            );
+        }
+
+        if let Some(wal_size) = status.wal_size {
+            if wal_size > self.journal_size_limit as u64 {
+                bail!(
+                    "write-ahead log is {} bytes, over the journal size limit of {} bytes \
+                     (hint: run `hasp db checkpoint` to truncate it)",
+                    wal_size,
+                    self.journal_size_limit,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a description of the processes that likely hold the write lock, for error messages.
+    fn describe_lock_holders(&self) -> String {
+        let holders = self.inner.lock_holders();
+        if holders.is_empty() {
+            String::new()
+        } else {
+            let holders: Vec<_> = holders.iter().map(|holder| holder.to_string()).collect();
+            format!(" (likely held by {})", holders.join(", "))
+        }
+    }
+
+    fn set_journal_size_limit(&self, conn: &Connection, db: DatabaseName) -> Result<()> {
+        conn.pragma_update(Some(db), "journal_size_limit", self.journal_size_limit)
+            .wrap_err_with(|| {
+                format!(
+                    "setting journal size limit to {} failed for {} (database {:?})",
+                    self.journal_size_limit,
+                    self.inner.description(),
+                    db
+                )
+            })
+    }
+
     fn enable_wal(&self, conn: &Connection, db: DatabaseName) -> Result<()> {
         conn.pragma_update(Some(db), "journal_mode", "WAL")
             .wrap_err_with(|| {
@@ -181,7 +832,17 @@ impl ConnectionCreator {
             })
     }
 
-    fn set_application_id(&self, conn: &Connection, db: DatabaseName) -> Result<()> {
+    /// Describes a database file for error messages: its path, or the name of the database if it
+    /// isn't stored on disk.
+    fn describe_file(&self, name: &str) -> String {
+        match self.file_path(name) {
+            Some(path) => format!("database file {}", path),
+            None => format!("{} ({})", name, self.inner.description()),
+        }
+    }
+
+    /// Sets the application ID and schema version of a database, marking it as a hasp database.
+    fn mark_as_hasp(&self, conn: &Connection, db: DatabaseName) -> Result<()> {
         let mut application_id = 0;
 
         conn.pragma_query(Some(db), Self::APPLICATION_ID_PRAGMA, |row| {
@@ -205,12 +866,369 @@ impl ConnectionCreator {
                     )
                 })?;
         }
+
+        let user_version: i32 = conn
+            .pragma_query_value(Some(db), Self::USER_VERSION_PRAGMA, |row| row.get(0))
+            .wrap_err_with(|| {
+                format!(
+                    "query schema version failed for {} (database {:?})",
+                    self.inner.description(),
+                    db
+                )
+            })?;
+        if user_version < Self::SCHEMA_VERSION {
+            conn.pragma_update(Some(db), Self::USER_VERSION_PRAGMA, Self::SCHEMA_VERSION)
+                .wrap_err_with(|| {
+                    format!(
+                        "setting schema version failed for {} (database {:?})",
+                        self.inner.description(),
+                        db
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a database was created by hasp, with a schema version this version of hasp
+    /// understands, before anything is read from or written to it. Empty databases are fine,
+    /// since they're set up when they're initialized.
+    fn validate(&self, conn: &Connection, db: DatabaseName, name: &str) -> Result<()> {
+        let (application_id, user_version): (i32, i32) = conn
+            .pragma_query_value(Some(db), Self::APPLICATION_ID_PRAGMA, |row| row.get(0))
+            .and_then(|application_id| {
+                let user_version =
+                    conn.pragma_query_value(Some(db), Self::USER_VERSION_PRAGMA, |row| row.get(0))?;
+                Ok((application_id, user_version))
+            })
+            .map_err(|err| {
+                open_error(
+                    err,
+                    name,
+                    &self.describe_file(name),
+                    "reading the header of",
+                )
+            })?;
+
+        let schema = match db {
+            DatabaseName::Main => "main",
+            DatabaseName::Temp => "temp",
+            DatabaseName::Attached(schema) => schema,
+        };
+        // Reading the schema catches corruption that the header doesn't show.
+        let table_count: i64 = conn
+            .query_row(
+                &format!("SELECT count(*) FROM {}.sqlite_master", schema),
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|err| {
+                open_error(
+                    err,
+                    name,
+                    &self.describe_file(name),
+                    "reading the schema of",
+                )
+            })?;
+
+        if application_id == Self::APPLICATION_ID {
+            if user_version > Self::SCHEMA_VERSION {
+                bail!(
+                    "{} was created by a newer version of hasp (schema version {}, but this \
+                     version of hasp supports up to {}; hint: upgrade hasp)",
+                    self.describe_file(name),
+                    user_version,
+                    Self::SCHEMA_VERSION,
+                );
+            }
+            return Ok(());
+        }
+
+        if application_id != 0 || table_count > 0 {
+            bail!(
+                "{} is not a hasp database (application ID {:#x}; hint: move it aside, or set \
+                 HASP_HOME to a different directory)",
+                self.describe_file(name),
+                application_id,
+            );
+        }
         Ok(())
     }
 }
 
-fn run_migrations(txn: &Transaction, event_logger: &EventLogger) -> Result<()> {
-    let all_migrations: BTreeMap<&'static str, &'static str> = SQL_DIR
+/// A database file that failed SQLite's integrity check, as found by `hasp db repair`.
+#[derive(Clone, Debug)]
+pub(crate) struct CorruptedFile {
+    pub(crate) name: &'static str,
+    pub(crate) path: Utf8PathBuf,
+    /// What's wrong with the file, as reported by SQLite.
+    pub(crate) problem: String,
+}
+
+/// Context marking an error caused by a corrupted database, with a hint for recovering it.
+#[derive(Clone, Debug)]
+pub(crate) struct DatabaseCorrupted(pub(crate) String);
+
+impl fmt::Display for DatabaseCorrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The size of a database file and its write-ahead log, as reported by `hasp db status`.
+#[derive(Clone, Debug)]
+pub(crate) struct DatabaseFileStatus {
+    pub(crate) name: &'static str,
+    pub(crate) path: Utf8PathBuf,
+    /// The size of the database file in bytes, or `None` if it doesn't exist.
+    pub(crate) size: Option<u64>,
+    /// The size of the write-ahead log in bytes, or `None` if it doesn't exist.
+    pub(crate) wal_size: Option<u64>,
+}
+
+/// The state of a migration, as reported by `hasp db status`.
+#[derive(Clone, Debug)]
+pub(crate) struct MigrationStatus {
+    pub(crate) name: String,
+    /// Whether this version of hasp knows about the migration. Unknown migrations were applied
+    /// by a newer version.
+    pub(crate) known: bool,
+    pub(crate) state: MigrationState,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum MigrationState {
+    /// The migration hasn't been applied yet.
+    Pending,
+    /// The migration was applied at this time.
+    Applied(DateTime<Local>),
+    /// The migration was rolled back at this time.
+    RolledBack(DateTime<Local>),
+}
+
+/// What `hasp db migrate` did.
+#[derive(Clone, Debug)]
+pub(crate) struct MigrateSummary {
+    /// The migration the database was migrated to.
+    pub(crate) target: String,
+    /// The migrations that were applied, in order.
+    pub(crate) applied: Vec<String>,
+    /// The migrations that were rolled back, in order.
+    pub(crate) rolled_back: Vec<String>,
+}
+
+/// The result of checkpointing a database.
+#[derive(Clone, Debug)]
+pub(crate) struct CheckpointStatus {
+    pub(crate) name: &'static str,
+    /// True if the checkpoint couldn't complete because the database was in use.
+    pub(crate) busy: bool,
+    /// The number of pages in the write-ahead log, or -1 if the database isn't in WAL mode.
+    pub(crate) wal_pages: i64,
+    /// The number of pages that were checkpointed into the database.
+    pub(crate) checkpointed_pages: i64,
+}
+
+fn checkpoint_one(
+    conn: &Connection,
+    db: DatabaseName,
+    name: &'static str,
+) -> Result<CheckpointStatus> {
+    conn.query_row(
+        &format!("PRAGMA {}.wal_checkpoint(TRUNCATE)", schema_name(db)),
+        [],
+        |row| {
+            Ok(CheckpointStatus {
+                name,
+                busy: row.get::<_, i64>(0)? != 0,
+                wal_pages: row.get(1)?,
+                checkpointed_pages: row.get(2)?,
+            })
+        },
+    )
+    .wrap_err_with(|| format!("checkpointing database {} failed", name))
+}
+
+/// Returns the name that SQL uses for a database, e.g. in pragmas.
+fn schema_name<'a>(db: DatabaseName<'a>) -> &'a str {
+    match db {
+        DatabaseName::Main => "main",
+        DatabaseName::Temp => "temp",
+        DatabaseName::Attached(schema) => schema,
+    }
+}
+
+/// Checks that the database schema isn't newer than this version of hasp knows about. Older
+/// schemas are fine, since migrations are run automatically.
+fn check_schema_version(conn: &Connection) -> Result<()> {
+    let last_applied = match last_applied_migration(conn) {
+        Ok(last_applied) => last_applied,
+        Err(err) if is_missing_table_report(&err) => {
+            bail!("migration status table is missing (hint: this database may not have been created by hasp)")
+        }
+        Err(err) => return Err(err),
+    };
+    let last_known = all_migrations()
+        .into_keys()
+        .next_back()
+        .expect("at least one migration known to hasp");
+    match last_applied {
+        Some(last_applied) if last_applied.as_str() > last_known => bail!(
+            "database is at migration {}, newer than the latest known migration {} (hint: \
+             upgrade hasp, or run `hasp db migrate` to roll it back)",
+            last_applied,
+            last_known,
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Returns a hint for how to recover a database that's missing or corrupted.
+fn restore_hint(name: &str) -> &'static str {
+    match name {
+        "packages" => {
+            "run `hasp db repair` to rebuild it from install receipts and the events journal"
+        }
+        "main" => {
+            "restore it from a backup, or run `hasp db repair` to recreate it from install \
+             receipts and the events journal"
+        }
+        _ => "restore it from a backup, or run `hasp db repair` to start a new journal",
+    }
+}
+
+/// Wraps an error from opening the database file `name`, described as `file`. If the file is
+/// corrupted, the error says so and points to `hasp db repair`, rather than being a bare SQLite
+/// error.
+fn open_error(err: rusqlite::Error, name: &str, file: &str, action: &str) -> Report {
+    if is_corrupt(&err) {
+        Report::new(err).wrap_err(DatabaseCorrupted(format!(
+            "{} is corrupted (hint: {})",
+            file,
+            restore_hint(name)
+        )))
+    } else {
+        Report::new(err).wrap_err(format!("{} {} failed", action, file))
+    }
+}
+
+fn file_size(path: &Utf8Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+/// Returns true if this error indicates that a database file is corrupted, or isn't a database
+/// at all.
+pub(crate) fn is_corrupt(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase,
+                ..
+            },
+            _
+        )
+    )
+}
+
+/// Adds a hint to run `hasp db repair` to errors caused by a corrupted database. Corruption that
+/// isn't caught when a database is opened only shows up once the damaged part is read.
+pub(crate) fn hint_if_corrupt(err: Report) -> Report {
+    let corrupt = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<rusqlite::Error>()
+            .is_some_and(is_corrupt)
+    });
+    let hinted = err.downcast_ref::<DatabaseCorrupted>().is_some();
+    if corrupt && !hinted {
+        err.wrap_err(DatabaseCorrupted(
+            "a hasp database is corrupted (hint: run `hasp db repair` to recover it)".to_owned(),
+        ))
+    } else {
+        err
+    }
+}
+
+/// Moves a database file aside, along with its write-ahead log and shared memory file, returning
+/// the path it was moved to if it existed.
+fn move_file_aside(path: &Utf8Path) -> Result<Option<Utf8PathBuf>> {
+    if file_size(path).is_none() {
+        return Ok(None);
+    }
+    let backup_path = Utf8PathBuf::from(format!(
+        "{}.{}.bak",
+        path,
+        Local::now().format("%Y%m%d%H%M%S"),
+    ));
+    for suffix in ["", "-wal", "-shm"] {
+        let from = format!("{}{}", path, suffix);
+        let to = format!("{}{}", backup_path, suffix);
+        match fs::rename(&from, &to) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to move {} to {}", from, to))
+            }
+        }
+    }
+    Ok(Some(backup_path))
+}
+
+/// Returns true if this error indicates that another connection holds a conflicting lock.
+pub(crate) fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: ErrorCode::DatabaseBusy,
+                ..
+            },
+            _
+        )
+    )
+}
+
+/// Returns true if this error indicates that a statement refers to a table that doesn't exist.
+///
+/// SQLite reports this with the generic `SQLITE_ERROR` code, so the message SQLite attached to the
+/// failure tells it apart from other errors like syntax errors.
+fn is_missing_table(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: ErrorCode::Unknown,
+                ..
+            },
+            Some(message),
+        ) if message.starts_with("no such table")
+    )
+}
+
+/// Returns true if this error was caused by a statement that refers to a missing table.
+fn is_missing_table_report(err: &Report) -> bool {
+    err.downcast_ref::<rusqlite::Error>()
+        .is_some_and(is_missing_table)
+}
+
+fn init_sql() -> &'static str {
+    SQL_DIR
+        .get_file("init.sql")
+        .expect("sql/init.sql exists")
+        .contents_utf8()
+        .expect("sql/init.sql is valid UTF-8")
+}
+
+/// A migration known to this version of hasp.
+#[derive(Clone, Copy, Debug)]
+struct Migration {
+    up: &'static str,
+    /// The SQL that undoes `up`, or `None` if the migration can't be rolled back.
+    down: Option<&'static str>,
+}
+
+/// Returns all migrations known to this version of hasp, by name.
+fn all_migrations() -> BTreeMap<&'static str, Migration> {
+    SQL_DIR
         .get_dir("migrations")
         .expect("migrations should exist")
         .dirs()
@@ -221,26 +1239,81 @@ fn run_migrations(txn: &Transaction, event_logger: &EventLogger) -> Result<()> {
                 .expect("migrations are UTF-8")
                 .file_name()
                 .expect("directory names present");
-            let file_path = dir.path().join("up.sql");
-            let sql = dir
-                .get_file(&file_path)
-                .unwrap_or_else(|| panic!("{} does not exist", file_path.display()))
-                .contents_utf8()
-                .expect("up.sql is valid UTF-8");
-            (migration_name, sql)
+            let sql = |file_name: &str| {
+                let file_path = dir.path().join(file_name);
+                dir.get_file(&file_path).map(|file| {
+                    file.contents_utf8()
+                        .unwrap_or_else(|| panic!("{} is valid UTF-8", file_path.display()))
+                })
+            };
+            let up = sql("up.sql")
+                .unwrap_or_else(|| panic!("{}/up.sql does not exist", dir.path().display()));
+            let down = sql("down.sql");
+            (migration_name, Migration { up, down })
         })
-        .collect();
+        .collect()
+}
 
-    // Look for all migrations that haven't been run yet.
-    let mut stmt = txn.prepare(
+/// Returns the name of the last migration applied to the database, if any.
+fn last_applied_migration(conn: &Connection) -> Result<Option<String>> {
+    let mut stmt = conn.prepare(
         r#"SELECT name, state, apply_time FROM migration_status
         WHERE state == "applied"
         ORDER BY name DESC"#,
     )?;
     let mut rows = stmt.query([])?;
-    let last_applied: Option<String> = rows
+    Ok(rows
         .next()?
-        .map(|row| row.get("name").expect("name field is text"));
+        .map(|row| row.get("name").expect("name field is text")))
+}
+
+/// A migration recorded in the migration status table.
+struct RecordedMigration {
+    state: MigrationState,
+    /// The SQL that rolls the migration back, if it was recorded.
+    down_sql: Option<String>,
+}
+
+/// Returns the migrations recorded in the database, by name. Databases without a migration status
+/// table have no migrations recorded.
+fn recorded_migrations(conn: &Connection) -> Result<BTreeMap<String, RecordedMigration>> {
+    let mut stmt = match conn.prepare("SELECT * FROM migration_status") {
+        Ok(stmt) => stmt,
+        Err(err) if is_missing_table(&err) => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err).wrap_err("failed to read migration status"),
+    };
+    // Down scripts are recorded by a later migration, so the column may not exist.
+    let has_down_sql = stmt.column_index("down_sql").is_ok();
+    let mut rows = stmt.query([])?;
+    let mut recorded = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let name: String = row.get("name")?;
+        let state = match row.get::<_, String>("state")?.as_str() {
+            "applied" => MigrationState::Applied(row.get("apply_time")?),
+            "rolled-back" => MigrationState::RolledBack(row.get("rollback_time")?),
+            other => bail!("migration {} has unknown state {}", name, other),
+        };
+        let down_sql = if has_down_sql {
+            row.get("down_sql")?
+        } else {
+            None
+        };
+        recorded.insert(name, RecordedMigration { state, down_sql });
+    }
+    Ok(recorded)
+}
+
+/// Applies migrations that haven't been applied yet, up to and including `target` if it's
+/// specified. Returns the names of the migrations that were applied.
+fn run_migrations(
+    txn: &Transaction,
+    event_logger: &EventLogger,
+    target: Option<&str>,
+) -> Result<Vec<&'static str>> {
+    let all_migrations = all_migrations();
+
+    // Look for all migrations that haven't been run yet.
+    let last_applied = last_applied_migration(txn)?;
 
     let migrations_to_perform = match &last_applied {
         Some(last_applied) => {
@@ -260,8 +1333,8 @@ fn run_migrations(txn: &Transaction, event_logger: &EventLogger) -> Result<()> {
                         .last()
                         .expect("at least one migration known to hasp");
                     bail!(
-                        "latest applied migration {} is newer than latest known migration {}\
-                        (hint: upgrade hasp version)",
+                        "latest applied migration {} is newer than latest known migration {} \
+                        (hint: upgrade hasp, or run `hasp db migrate` to roll it back)",
                         last_applied,
                         last_known,
                     );
@@ -276,51 +1349,154 @@ fn run_migrations(txn: &Transaction, event_logger: &EventLogger) -> Result<()> {
 
     // Perform all the migrations one by one.
     let mut migrations_performed = vec![];
-    if migrations_performed.is_empty() {}
 
-    for (&name, sql) in migrations_to_perform {
-        let data = MigrationData { name };
+    for (&name, migration) in migrations_to_perform {
+        if target.is_some_and(|target| name > target) {
+            break;
+        }
+        let event = MigrationEvent {
+            name: name.to_owned(),
+        };
 
         tracing::debug!("running migration {}", name);
-        event_logger.log("migration_started", &data);
-        match run_one_migration(txn, name, sql) {
+        event_logger.log(Event::MigrationStarted(event.clone()));
+        match run_one_migration(txn, name, migration.up) {
             Ok(()) => {
                 migrations_performed.push(name);
-                event_logger.log("migration_finished", &data);
+                event_logger.log(Event::MigrationFinished(event));
             }
             Err(err) => {
-                let rollback_data = RollbackData {
-                    rolled_back: migrations_performed,
-                };
-                event_logger.log("migration_rollback", &rollback_data);
+                event_logger.log(Event::MigrationRollback(MigrationRollback {
+                    rolled_back: migrations_performed
+                        .into_iter()
+                        .map(|name| name.to_owned())
+                        .collect(),
+                }));
                 return Err(err);
             }
         }
     }
 
-    Ok(())
+    if !migrations_performed.is_empty() {
+        record_down_sql(txn, &all_migrations)?;
+    }
+    Ok(migrations_performed)
 }
 
 fn run_one_migration(txn: &Transaction, name: &'static str, sql: &str) -> Result<()> {
     txn.execute_batch(sql)
         .wrap_err_with(|| format!("failed to perform migration {}", name))?;
+    // Migrations that were rolled back are already in the table.
     txn.execute(
-        "INSERT INTO migration_status (name, state, apply_time) VALUES (?1, ?2, ?3)",
+        "INSERT INTO migration_status (name, state, apply_time) VALUES (?1, ?2, ?3)
+        ON CONFLICT (name) DO UPDATE SET state = excluded.state, apply_time = excluded.apply_time",
         params![name, "applied", Local::now()],
     )
     .wrap_err_with(|| format!("failed to insert migration {} into table", name))?;
     Ok(())
 }
 
-#[derive(Debug, Serialize)]
-struct MigrationData {
-    name: &'static str,
+/// Records the SQL that rolls back each applied migration, so that versions of hasp that don't
+/// know about a migration can still roll it back.
+fn record_down_sql(txn: &Transaction, all_migrations: &BTreeMap<&str, Migration>) -> Result<()> {
+    // The column is added by a migration, which may not have been applied yet.
+    if txn
+        .prepare("SELECT down_sql FROM migration_status LIMIT 0")
+        .is_err()
+    {
+        return Ok(());
+    }
+    for (name, migration) in all_migrations {
+        txn.execute(
+            "UPDATE migration_status SET down_sql = ?2 WHERE name = ?1",
+            params![name, migration.down],
+        )
+        .wrap_err_with(|| format!("failed to record down SQL for migration {}", name))?;
+    }
+    Ok(())
+}
+
+/// A connection to the main and packages databases, which is returned to the pool it came from
+/// when dropped.
+#[derive(Debug)]
+pub(crate) struct PooledConnection {
+    // Always Some until dropped.
+    conn: Option<Connection>,
+    pool: Arc<ConnectionPool>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn
+            .as_mut()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put(conn);
+        }
+    }
+}
+
+/// Connections that aren't in use, kept around so that they don't have to be opened, validated
+/// and configured again. Connections are shared between clones of a `ConnectionCreator`.
+#[derive(Default)]
+struct ConnectionPool {
+    idle: Mutex<Vec<Connection>>,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "kebab-case")]
-struct RollbackData {
-    rolled_back: Vec<&'static str>,
+impl ConnectionPool {
+    // The number of idle connections to keep. Installs run concurrently, but each only holds a
+    // connection briefly.
+    const MAX_IDLE: usize = 4;
+
+    fn take(&self) -> Option<Connection> {
+        self.idle().pop()
+    }
+
+    fn put(&self, conn: Connection) {
+        // A connection left in the middle of a transaction can't be reused safely.
+        if !conn.is_autocommit() {
+            return;
+        }
+        let mut idle = self.idle();
+        if idle.len() < Self::MAX_IDLE {
+            idle.push(conn);
+        }
+    }
+
+    fn clear(&self) {
+        self.idle().clear();
+    }
+
+    fn drain(&self) -> Vec<Connection> {
+        std::mem::take(&mut *self.idle())
+    }
+
+    fn idle(&self) -> MutexGuard<'_, Vec<Connection>> {
+        self.idle.lock().expect("pool lock is never poisoned")
+    }
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("idle", &self.idle().len())
+            .finish()
+    }
 }
 
 // ---
@@ -333,6 +1509,13 @@ trait CreateConnectionImpl: Sync + Send + fmt::Debug {
     fn create_impl(&self) -> Result<Connection>;
     fn create_events(&self) -> Result<Connection>;
     fn description(&self) -> &str;
+
+    /// Returns the processes that hold install locks, and are therefore likely to be writing to
+    /// the database.
+    fn lock_holders(&self) -> Vec<LockHolder>;
+
+    /// Returns the names and paths of database files.
+    fn files(&self) -> Vec<(&'static str, Utf8PathBuf)>;
 }
 
 // ---
@@ -342,25 +1525,43 @@ trait CreateConnectionImpl: Sync + Send + fmt::Debug {
 #[derive(Clone, Debug)]
 pub(crate) struct DiskDb {
     hasp_home: Utf8PathBuf,
+    installs_dir: Utf8PathBuf,
+}
+
+impl DiskDb {
+    const MAIN_FILE_NAME: &'static str = "db.sqlite";
+    const PACKAGES_FILE_NAME: &'static str = "packages.sqlite";
+    const EVENTS_FILE_NAME: &'static str = "events.sqlite";
 }
 
 impl CreateConnectionImpl for DiskDb {
     fn create_impl(&self) -> Result<Connection> {
-        let db = self.hasp_home.join("db.sqlite");
-        let packages = self.hasp_home.join("packages.sqlite");
+        let db = self.hasp_home.join(Self::MAIN_FILE_NAME);
+        let packages = self.hasp_home.join(Self::PACKAGES_FILE_NAME);
 
         let conn =
             Connection::open(&db).wrap_err_with(|| format!("opening DB at {} failed", db))?;
+        // Connections are opened lazily, so read the schema before attaching the packages DB to
+        // tell whether errors are about this file or that one.
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|err| open_error(err, "main", &format!("DB at {}", db), "opening"))?;
 
         // Attach the packages DB.
         conn.execute("ATTACH DATABASE ?1 as packages", [packages.as_str()])
-            .wrap_err_with(|| format!("attaching packages DB at {} failed", packages))?;
+            .map_err(|err| {
+                open_error(
+                    err,
+                    "packages",
+                    &format!("packages DB at {}", packages),
+                    "attaching",
+                )
+            })?;
 
         Ok(conn)
     }
 
     fn create_events(&self) -> Result<Connection> {
-        let events = self.hasp_home.join("events.sqlite");
+        let events = self.hasp_home.join(Self::EVENTS_FILE_NAME);
         Connection::open(&events)
             .wrap_err_with(|| format!("opening events DB at {} failed", events))
     }
@@ -368,6 +1569,18 @@ impl CreateConnectionImpl for DiskDb {
     fn description(&self) -> &str {
         self.hasp_home.as_str()
     }
+
+    fn lock_holders(&self) -> Vec<LockHolder> {
+        find_lock_holders(&self.installs_dir)
+    }
+
+    fn files(&self) -> Vec<(&'static str, Utf8PathBuf)> {
+        vec![
+            ("main", self.hasp_home.join(Self::MAIN_FILE_NAME)),
+            ("packages", self.hasp_home.join(Self::PACKAGES_FILE_NAME)),
+            ("events", self.hasp_home.join(Self::EVENTS_FILE_NAME)),
+        ]
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -389,4 +1602,257 @@ impl CreateConnectionImpl for InMemoryDb {
     fn description(&self) -> &str {
         "in-memory database"
     }
+
+    fn lock_holders(&self) -> Vec<LockHolder> {
+        // In-memory databases aren't shared with other processes.
+        vec![]
+    }
+
+    fn files(&self) -> Vec<(&'static str, Utf8PathBuf)> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{state::HaspState, test_helpers::TestHome};
+
+    #[test]
+    fn migrate_down_and_up() {
+        let test_home = TestHome::new();
+        let (hasp_home, creator) = (&test_home.hasp_home, test_home.creator());
+        let event_logger = &test_home.db_ctx.event_logger;
+
+        let all_migrations = all_migrations();
+        let (&first, _) = all_migrations.iter().next().expect("migrations exist");
+        let summary = creator
+            .migrate(Some(first), event_logger)
+            .expect("migrated down");
+        assert_eq!(summary.rolled_back.len(), all_migrations.len() - 1);
+        assert!(
+            creator
+                .migrate(Some(first), event_logger)
+                .expect("migrated down again")
+                .rolled_back
+                .is_empty(),
+            "nothing left to roll back"
+        );
+        let err = creator
+            .migrate(Some("20000101-01-unknown"), event_logger)
+            .expect_err("unknown migration");
+        assert!(err.to_string().starts_with("unknown migration"), "{}", err);
+
+        // Every down script must undo its up script for the migrations to apply again.
+        let creator = ConnectionCreator::new(hasp_home);
+        let summary = creator.migrate(None, event_logger).expect("migrated up");
+        assert_eq!(summary.applied.len(), all_migrations.len() - 1);
+        let statuses = creator.migration_statuses().expect("statuses read");
+        assert!(statuses
+            .iter()
+            .all(|status| status.known && matches!(status.state, MigrationState::Applied(_))));
+
+        // Migrations unknown to this version of hasp are rolled back with the recorded SQL.
+        let conn = creator.create().expect("connection created");
+        conn.execute_batch(
+            r#"CREATE TABLE future (id INTEGER);
+            INSERT INTO migration_status (name, state, apply_time, down_sql)
+            VALUES ("99991231-01-future", "applied", "2026-10-16T00:00:00+00:00",
+                    "DROP TABLE future;");"#,
+        )
+        .expect("future migration recorded");
+        let summary = ConnectionCreator::new(hasp_home)
+            .migrate(None, event_logger)
+            .expect("migrated down");
+        assert_eq!(summary.rolled_back, vec!["99991231-01-future".to_owned()]);
+    }
+
+    #[test]
+    fn repair_corrupted_databases() {
+        let test_home = TestHome::new();
+        let hasp_home = test_home.hasp_home.clone();
+        let state = HaspState::load_or_init(hasp_home.clone()).expect("state loaded");
+        state
+            .trust_source("https://example.com/index")
+            .expect("source trusted");
+        state.flush_events(Duration::from_secs(10));
+        drop(state);
+        drop(test_home.db_ctx);
+
+        // Overwrite the headers, which SQLite checks as soon as a database is read.
+        for file in ["db.sqlite", "packages.sqlite"] {
+            let path = hasp_home.home_dir().join(file);
+            let mut contents = fs::read(&path).expect("database read");
+            contents[..100].fill(b'x');
+            fs::write(&path, contents).expect("database garbled");
+        }
+
+        let err = HaspState::load_or_init(hasp_home.clone())
+            .map_err(hint_if_corrupt)
+            .expect_err("corrupted database detected");
+        assert!(
+            err.downcast_ref::<DatabaseCorrupted>().is_some(),
+            "corruption is marked: {:#}",
+            err
+        );
+        assert!(
+            format!("{:#}", err).contains("hasp db repair"),
+            "hint given: {:#}",
+            err
+        );
+
+        let summary = HaspState::repair(hasp_home.clone()).expect("databases repaired");
+        assert!(summary.is_corrupted("main"));
+        assert!(summary.is_corrupted("packages"));
+        assert!(!summary.is_corrupted("events"));
+        assert_eq!(summary.backup_paths.len(), 2, "both databases moved aside");
+        assert_eq!(
+            summary.restored_sources,
+            vec!["https://example.com/index".to_owned()]
+        );
+
+        let state = HaspState::load_or_init(hasp_home).expect("databases recreated");
+        let sources: Vec<_> = state
+            .trusted_sources()
+            .expect("trusted sources read")
+            .into_iter()
+            .map(|row| row.source)
+            .collect();
+        assert_eq!(sources, vec!["https://example.com/index".to_owned()]);
+    }
+
+    #[test]
+    fn hint_corruption_once() {
+        let corrupt = || {
+            Report::new(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CORRUPT),
+                None,
+            ))
+            .wrap_err("reading packages failed")
+        };
+        let hinted = hint_if_corrupt(corrupt());
+        assert!(hinted.downcast_ref::<DatabaseCorrupted>().is_some());
+        assert_eq!(
+            hint_if_corrupt(hinted).chain().count(),
+            corrupt().chain().count() + 1,
+            "the hint is only added once"
+        );
+
+        let other = hint_if_corrupt(eyre!("no such package"));
+        assert!(other.downcast_ref::<DatabaseCorrupted>().is_none());
+    }
+
+    #[test]
+    fn detect_missing_tables() {
+        let conn = Connection::open_in_memory().expect("in-memory DB opened");
+        let err = conn
+            .prepare("SELECT * FROM migration_status")
+            .expect_err("table is missing");
+        assert!(is_missing_table(&err), "missing table detected: {:?}", err);
+        let err = last_applied_migration(&conn).expect_err("table is missing");
+        assert!(
+            is_missing_table_report(&err),
+            "missing table detected: {:?}",
+            err
+        );
+        assert!(recorded_migrations(&conn)
+            .expect("missing table means no migrations")
+            .is_empty());
+
+        let err = conn
+            .prepare("SELECT * FROM")
+            .expect_err("statement is invalid");
+        assert!(
+            !is_missing_table(&err),
+            "syntax error isn't a missing table"
+        );
+        assert!(!is_missing_table_report(&eyre!(
+            "no such table: migration_status"
+        )));
+    }
+
+    #[test]
+    fn validate_on_open() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+
+        let conn = creator.create().expect("hasp databases accepted");
+        conn.pragma_update(
+            Some(DatabaseName::Attached("packages")),
+            ConnectionCreator::USER_VERSION_PRAGMA,
+            ConnectionCreator::SCHEMA_VERSION + 1,
+        )
+        .expect("user version set");
+        let err = creator.create().expect_err("newer schema rejected");
+        assert!(
+            err.to_string()
+                .contains("created by a newer version of hasp"),
+            "{}",
+            err
+        );
+
+        let other_home = test_home.hasp_home.home_dir().join("other");
+        let other_home = HaspHome::new(&other_home).expect("hasp home created");
+        let conn =
+            Connection::open(other_home.home_dir().join("db.sqlite")).expect("database created");
+        conn.execute_batch("CREATE TABLE foreign_data (id INTEGER);")
+            .expect("table created");
+        let err = ConnectionCreator::new(&other_home)
+            .create()
+            .expect_err("foreign database rejected");
+        assert!(
+            err.to_string().contains("is not a hasp database"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn pool_connections() {
+        // Each in-memory connection has a database of its own, so a table created through one
+        // shows whether it's reused.
+        fn has_table(conn: &Connection) -> bool {
+            conn.query_row(
+                "SELECT count(*) FROM sqlite_master WHERE name = 'pooled'",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .expect("schema queried")
+                == 1
+        }
+
+        let creator = ConnectionCreator::new_in_memory();
+        creator
+            .create()
+            .expect("connection created")
+            .execute_batch("CREATE TABLE pooled (id INTEGER)")
+            .expect("table created");
+        assert!(
+            creator
+                .run(|conn| Ok(has_table(conn)))
+                .await
+                .expect("task succeeded"),
+            "the connection is reused"
+        );
+
+        let conn = creator.create().expect("connection created");
+        conn.execute_batch("BEGIN").expect("transaction started");
+        drop(conn);
+        assert!(
+            !has_table(&creator.create().expect("connection created")),
+            "connections left in a transaction aren't reused"
+        );
+
+        // Optimizing closes the pooled connections.
+        creator
+            .create()
+            .expect("connection created")
+            .execute_batch("CREATE TABLE pooled (id INTEGER)")
+            .expect("table created");
+        creator.optimize(true).expect("databases optimized");
+        assert!(
+            !has_table(&creator.create().expect("connection created")),
+            "optimized connections aren't reused"
+        );
+    }
 }
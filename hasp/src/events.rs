@@ -1,65 +1,674 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use crate::database::ConnectionCreator;
-use chrono::Local;
+use crate::{
+    database::{is_busy, ConnectionCreator},
+    report::RunReport,
+};
+use chrono::{Duration, Local};
 use color_eyre::{eyre::WrapErr, Result};
 use colored::Colorize;
+use futures::prelude::*;
+use hasp_metadata::{
+    Event, EventEnvelope, EventsPruned, LifecycleEvent, RunStarted, EVENT_SCHEMA_VERSION,
+};
 use jod_thread::JoinHandle;
-use rusqlite::params;
-use serde::Serialize;
-use std::sync::{mpsc, Arc};
+use once_cell::sync::{Lazy, OnceCell};
+use rusqlite::{params, Connection};
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+static RUN_ID: OnceCell<String> = OnceCell::new();
+static RUN: OnceCell<Mutex<RunJournal>> = OnceCell::new();
+
+/// The number of lifecycle events buffered for each subscriber. Subscribers that fall further
+/// behind than this miss events.
+const LIFECYCLE_CAPACITY: usize = 256;
+
+/// Lifecycle events logged by any event logger are broadcast to in-process subscribers. Each
+/// command loads its own state, so subscribers are shared across loggers.
+static LIFECYCLE: Lazy<broadcast::Sender<LifecycleEvent>> =
+    Lazy::new(|| broadcast::channel(LIFECYCLE_CAPACITY).0);
+
+/// Returns a stream of lifecycle events logged after this call, by any event logger.
+///
+/// Subscribers that fall too far behind skip the oldest events.
+pub(crate) fn subscribe_lifecycle() -> impl Stream<Item = LifecycleEvent> {
+    let receiver = LIFECYCLE.subscribe();
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::debug!(
+                        target: "hasp::output::working::lifecycle_lagged",
+                        skipped,
+                        "Skipped {skipped} lifecycle events for a slow subscriber",
+                    );
+                }
+                // The sender is static, so this doesn't happen.
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Returns the ID of this run of hasp, which is recorded with every event it logs. It's also the
+/// trace ID of exported telemetry, so it's 32 hex digits.
+pub(crate) fn run_id() -> &'static str {
+    RUN_ID.get_or_init(|| {
+        // A random ID, without depending on a random number generator.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos());
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&nanos.to_le_bytes());
+        hasher.update(&process::id().to_le_bytes());
+        hasher.finalize().to_hex()[..32].to_owned()
+    })
+}
+
+/// A run of hasp that installs packages, recorded in the journal as `run_started` and
+/// `run_finished` events.
+#[derive(Debug)]
+struct RunJournal {
+    args: Vec<String>,
+    /// The logger for the journal `run_started` was recorded in, once one is opened.
+    logger: Option<EventLogger>,
+}
+
+/// Starts recording this run. `run_started` is recorded once the journal is opened, and
+/// `run_finished` by [`finish_run`]. Runs that never open the journal aren't recorded.
+pub(crate) fn start_run(args: Vec<String>) {
+    let _ = RUN.set(Mutex::new(RunJournal { args, logger: None }));
+}
+
+/// Records `run_finished` with the results in the report, if `run_started` was recorded, and waits
+/// for the journal to be written.
+pub(crate) fn finish_run(report: &RunReport) {
+    let logger = RUN.get().and_then(|run| {
+        run.lock()
+            .expect("run lock is never poisoned")
+            .logger
+            .take()
+    });
+    if let Some(logger) = logger {
+        logger.log(report.to_event());
+    }
+}
 
 #[derive(Clone, Debug)]
 pub(crate) struct EventLogger {
-    // Send and receive pairs of (event name, event data).
-    sender: mpsc::Sender<(&'static str, String)>,
-    join_handle: Arc<JoinHandle<()>>,
+    sender: mpsc::SyncSender<Message>,
+    stats: Arc<LoggerStats>,
+    // Waits for pending events to be recorded once the last logger is dropped.
+    _shutdown: Arc<Shutdown>,
 }
 
 impl EventLogger {
+    /// The number of events to record between checkpoints of the events database.
+    const CHECKPOINT_INTERVAL: usize = 256;
+
+    /// The number of events buffered for recording. Events logged while the buffer is full are
+    /// dropped, so that a slow or locked events database doesn't hold up hasp.
+    const BUFFER_CAPACITY: usize = 1024;
+
+    /// The number of times to try recording an event if another process holds the write lock for
+    /// longer than the busy timeout.
+    const INSERT_ATTEMPTS: u32 = 3;
+
+    /// How long to wait for pending events to be recorded when shutting down.
+    const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
     pub(crate) fn new(creator: &ConnectionCreator) -> Result<Self> {
         let events_conn = creator.create_events()?;
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(Self::BUFFER_CAPACITY);
+        let stats = Arc::new(LoggerStats::default());
+        let thread_stats = stats.clone();
         // Create a new thread to serialize event logging.
         let join_handle = jod_thread::Builder::new()
             .name("hasp-event-logger".to_owned())
             .spawn(move || {
+                let mut events_since_checkpoint = 0;
                 loop {
-                    let (event_name, data): (&str, String) = match receiver.recv() {
-                        Ok(event) => event,
+                    let (event_name, data) = match receiver.recv() {
+                        Ok(Message::Event(event_name, data)) => (event_name, data),
+                        Ok(Message::Flush(ack)) => {
+                            // Events are recorded in order, so everything before this is done.
+                            let _ = ack.send(());
+                            continue;
+                        }
                         Err(_) => {
-                            // All senders were dropped -- shut this thread down.
+                            // All senders were dropped -- checkpoint and shut this thread down.
+                            checkpoint(&events_conn);
                             return;
                         }
                     };
                     // TODO: begin concurrent if/when that's available?
-                    // TODO: error handling for this? ignore errors for now.
+                    let event = event_name.bold();
                     tracing::debug!(
                         target: "hasp::output::recording::recording_event",
-                        "Recording event {}", event_name.bold(),
+                        %event,
+                        "Recording event {event}",
                     );
-                    events_conn.execute(
-                    "INSERT INTO journal (event_name, event_time, data) VALUES (?1, ?2, ?3)",
-                    params![event_name, Local::now(), data],
-                    ).expect("input is always valid");
+                    if let Err(err) = record(&events_conn, event_name, &data) {
+                        // Only warn once, since later events are likely to fail the same way.
+                        if thread_stats.failed.fetch_add(1, Ordering::Relaxed) == 0 {
+                            let error = err.to_string();
+                            tracing::warn!(
+                                target: "hasp::output::recording::record_failed",
+                                event = event_name,
+                                %error,
+                                "Warning failed to record event {event} in the journal: {error}",
+                                event = event_name,
+                            );
+                        }
+                    }
+
+                    // Checkpoint periodically so that the write-ahead log doesn't grow without
+                    // bound.
+                    events_since_checkpoint += 1;
+                    if events_since_checkpoint == Self::CHECKPOINT_INTERVAL {
+                        checkpoint(&events_conn);
+                        events_since_checkpoint = 0;
+                    }
                 }
             })
             .wrap_err("creating event logger thread failed")?;
         Ok(EventLogger {
-            sender,
-            join_handle: Arc::new(join_handle),
+            sender: sender.clone(),
+            stats: stats.clone(),
+            _shutdown: Arc::new(Shutdown {
+                sender: Some(sender),
+                stats,
+                join_handle: Some(join_handle),
+            }),
         })
     }
 
-    pub(crate) fn log(&self, event_name: &'static str, data: &impl Serialize) {
+    /// Records `run_started` in this journal, if this run is being recorded and it hasn't been
+    /// recorded in another journal already.
+    pub(crate) fn start_run(&self) {
+        let mut run = match RUN.get() {
+            Some(run) => run.lock().expect("run lock is never poisoned"),
+            None => return,
+        };
+        if run.logger.is_some() {
+            return;
+        }
+        self.log(Event::RunStarted(RunStarted {
+            hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+            args: run.args.clone(),
+        }));
+        run.logger = Some(self.clone());
+    }
+
+    pub(crate) fn log(&self, event: impl Into<Event>) {
+        let event = event.into();
+        let event_name = event.name();
         // This should basically never fail, but if it does, ignore the error.
-        let data = match serde_json::to_string(data) {
-            Ok(data) => data,
+        let data = match event.payload() {
+            Ok(data) => data.to_string(),
             Err(_) => return,
         };
 
-        // Assume writing to events is lossy so ignore send errors.
-        let _ = self.sender.send((event_name, data));
+        // Writing to events is lossy: if recording has fallen behind or stopped, drop the event
+        // and account for it.
+        if self
+            .sender
+            .try_send(Message::Event(event_name, data))
+            .is_err()
+        {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits up to `timeout` for every event logged before this call to be recorded. Returns
+    /// false if that took too long.
+    pub(crate) fn flush(&self, timeout: std::time::Duration) -> bool {
+        flush(&self.sender, timeout)
+    }
+
+    /// Records a lifecycle event in the journal, and broadcasts it to subscribers.
+    pub(crate) fn log_lifecycle(&self, event: LifecycleEvent) {
+        self.log(event.clone());
+
+        // Sending fails if there are no subscribers, which is fine.
+        let _ = LIFECYCLE.send(event);
+    }
+}
+
+enum Message {
+    /// An event name and its data.
+    Event(&'static str, String),
+    /// Acknowledged once every event sent before it has been recorded.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Events that weren't recorded in the journal.
+#[derive(Debug, Default)]
+struct LoggerStats {
+    /// Events dropped because the buffer was full.
+    dropped: AtomicU64,
+    /// Events that couldn't be written to the events database.
+    failed: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Shutdown {
+    sender: Option<mpsc::SyncSender<Message>>,
+    stats: Arc<LoggerStats>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        let flushed = match &self.sender {
+            Some(sender) => flush(sender, EventLogger::SHUTDOWN_TIMEOUT),
+            None => true,
+        };
+        // The thread exits once every sender is dropped. If it's stuck, don't wait for it.
+        self.sender = None;
+        if let Some(join_handle) = self.join_handle.take() {
+            if flushed {
+                join_handle.join();
+            } else {
+                join_handle.detach();
+            }
+        }
+
+        let dropped = self.stats.dropped.load(Ordering::Relaxed);
+        let failed = self.stats.failed.load(Ordering::Relaxed);
+        if !flushed {
+            tracing::warn!(
+                target: "hasp::output::recording::events_unflushed",
+                dropped,
+                failed,
+                "Warning some events weren't recorded in the journal ({dropped} dropped because \
+                 recording fell behind, {failed} failed, and gave up waiting for the rest)",
+            );
+        } else if dropped > 0 || failed > 0 {
+            tracing::warn!(
+                target: "hasp::output::recording::events_lost",
+                dropped,
+                failed,
+                "Warning some events weren't recorded in the journal ({dropped} dropped because \
+                 recording fell behind, {failed} failed)",
+            );
+        }
+    }
+}
+
+/// Sends a flush message and waits for it to be acknowledged, for up to `timeout` in total.
+fn flush(sender: &mpsc::SyncSender<Message>, timeout: std::time::Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let (ack_sender, ack_receiver) = mpsc::channel();
+    let mut message = Message::Flush(ack_sender);
+    loop {
+        match sender.try_send(message) {
+            Ok(()) => break,
+            Err(mpsc::TrySendError::Full(returned)) if Instant::now() < deadline => {
+                message = returned;
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+            Err(mpsc::TrySendError::Full(_)) => return false,
+            // The thread has exited, so there's nothing left to wait for.
+            Err(mpsc::TrySendError::Disconnected(_)) => return true,
+        }
+    }
+    ack_receiver
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .is_ok()
+}
+
+/// Inserts an event into the journal, retrying if another process holds the write lock.
+fn record(conn: &Connection, event_name: &str, data: &str) -> rusqlite::Result<()> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match conn.execute(
+            "INSERT INTO journal (event_name, event_time, data, schema_version, run_id)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                event_name,
+                Local::now(),
+                data,
+                EVENT_SCHEMA_VERSION,
+                run_id()
+            ],
+        ) {
+            Ok(_) => return Ok(()),
+            Err(err) if is_busy(&err) && attempts < EventLogger::INSERT_ATTEMPTS => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How many events to keep in the journal. Events are kept forever by default.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct RetentionPolicy {
+    /// Events older than this are removed.
+    pub(crate) max_age: Option<Duration>,
+    /// Only this many of the most recent events are kept.
+    pub(crate) max_rows: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Returns true if events are kept forever.
+    pub(crate) fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_rows.is_none()
+    }
+}
+
+/// What was removed from the journal by [`prune_events`]. This is also recorded in the journal as
+/// an `events_pruned` event.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PruneSummary {
+    /// The number of events removed for being older than the maximum age.
+    pub(crate) pruned_by_age: usize,
+    /// The number of events removed for being over the maximum number of rows.
+    pub(crate) pruned_by_rows: usize,
+    /// The number of events left in the journal.
+    pub(crate) remaining: u64,
+    /// Whether the events database was vacuumed to reclaim space.
+    pub(crate) vacuumed: bool,
+}
+
+impl PruneSummary {
+    /// Returns the event this is recorded in the journal as.
+    pub(crate) fn to_event(&self) -> Event {
+        Event::EventsPruned(EventsPruned {
+            pruned_by_age: self.pruned_by_age,
+            pruned_by_rows: self.pruned_by_rows,
+            remaining: self.remaining,
+            vacuumed: self.vacuumed,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn pruned(&self) -> usize {
+        self.pruned_by_age + self.pruned_by_rows
+    }
+}
+
+/// Adds columns that were added to the journal after it was created. The events database doesn't
+/// have migrations, so this is checked each time it's initialized.
+pub(crate) fn upgrade_journal(conn: &Connection) -> Result<()> {
+    const ADDED_COLUMNS: &[(&str, &str)] = &[
+        ("schema_version", "INTEGER NOT NULL DEFAULT 0"),
+        ("run_id", "TEXT"),
+    ];
+
+    for (column, definition) in ADDED_COLUMNS {
+        let has_column = |conn: &Connection| {
+            conn.prepare(&format!("SELECT {} FROM journal LIMIT 0", column))
+                .is_ok()
+        };
+        if has_column(conn) {
+            continue;
+        }
+        match conn.execute_batch(&format!(
+            "ALTER TABLE journal ADD COLUMN {} {}",
+            column, definition
+        )) {
+            Ok(()) => {}
+            // Another process may have added the column in the meantime.
+            Err(_) if has_column(conn) => {}
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("failed to add {} to the journal", column))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An event read from the journal.
+#[derive(Debug)]
+pub(crate) struct JournalEntry {
+    pub(crate) event_id: i64,
+    pub(crate) event_name: String,
+    /// The parsed event, or the error from parsing it.
+    pub(crate) event: Result<EventEnvelope>,
+}
+
+/// Returns the most recent `limit` events in the journal, or all of them, oldest first.
+pub(crate) fn read_events(
+    creator: &ConnectionCreator,
+    limit: Option<u64>,
+) -> Result<Vec<JournalEntry>> {
+    let conn = creator.create_events()?;
+    let mut stmt = conn.prepare(
+        "SELECT event_id, event_name, event_time, data, schema_version, run_id FROM journal
+        WHERE event_name != 'sentinel'
+        -- julianday() only has millisecond precision: order events recorded within the same
+        -- millisecond by the full timestamp.
+        ORDER BY julianday(event_time) DESC, event_time DESC LIMIT ?1",
+    )?;
+    // A negative limit means no limit.
+    let limit = limit.map_or(-1, |limit| limit.min(i64::MAX as u64) as i64);
+    let mut entries = stmt
+        .query_map([limit], |row| {
+            let event_name: String = row.get("event_name")?;
+            let data: Option<String> = row.get("data")?;
+            let event = EventEnvelope::from_journal(
+                row.get("schema_version")?,
+                &event_name,
+                row.get("event_time")?,
+                row.get("run_id")?,
+                data.as_deref().unwrap_or("null"),
+            )
+            .wrap_err_with(|| format!("failed to parse {} event", event_name));
+            Ok(JournalEntry {
+                event_id: row.get("event_id")?,
+                event_name,
+                event,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err("failed to read events from the journal")?;
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Removes events from the journal according to the policy, and vacuums the events database if
+/// `vacuum` is true.
+pub(crate) fn prune_events(
+    creator: &ConnectionCreator,
+    policy: RetentionPolicy,
+    vacuum: bool,
+) -> Result<PruneSummary> {
+    let mut conn = creator.create_events()?;
+    let txn = conn.transaction()?;
+    let mut summary = PruneSummary::default();
+
+    // The sentinel row makes event IDs random, so order events by time instead. Event times are
+    // compared through julianday, since they're recorded with the local time zone's offset.
+    if let Some(max_age) = policy.max_age {
+        summary.pruned_by_age = txn
+            .execute(
+                "DELETE FROM journal WHERE event_name != 'sentinel' \
+                AND julianday(event_time) < julianday(?1)",
+                [Local::now() - max_age],
+            )
+            .wrap_err("failed to prune events by age")?;
+    }
+    if let Some(max_rows) = policy.max_rows {
+        summary.pruned_by_rows = txn
+            .execute(
+                "DELETE FROM journal WHERE event_id IN \
+                    (SELECT event_id FROM journal WHERE event_name != 'sentinel' \
+                    ORDER BY julianday(event_time) DESC, event_time DESC LIMIT -1 OFFSET ?1)",
+                [max_rows],
+            )
+            .wrap_err("failed to prune events by count")?;
+    }
+    summary.remaining = txn
+        .query_row(
+            "SELECT COUNT(*) FROM journal WHERE event_name != 'sentinel'",
+            [],
+            |row| row.get(0),
+        )
+        .wrap_err("failed to count remaining events")?;
+    txn.commit().wrap_err("failed to commit pruned events")?;
+
+    if vacuum {
+        conn.execute_batch("VACUUM")
+            .wrap_err("failed to vacuum events database")?;
+        summary.vacuumed = true;
+    }
+    Ok(summary)
+}
+
+/// Checkpoints the events database, ignoring errors: the database may be in use by another
+/// process, in which case it'll be checkpointed later.
+fn checkpoint(events_conn: &Connection) {
+    let _ = events_conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TestHome;
+    use chrono::DateTime;
+    use hasp_metadata::{DirectoryHash, PackageDirectory, UninstallSuccess};
+
+    #[test]
+    fn prune_by_retention_policy() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+        // Initializing the databases logs events, which mustn't be recorded after the journal is
+        // cleared.
+        assert!(test_home
+            .db_ctx
+            .event_logger
+            .flush(std::time::Duration::from_secs(10)));
+        let conn = creator.create_events().expect("events connection created");
+        conn.execute("DELETE FROM journal WHERE event_name != 'sentinel'", [])
+            .expect("journal cleared");
+        let now = Local::now();
+        for days in [0, 1, 2, 10, 20] {
+            conn.execute(
+                "INSERT INTO journal (event_name, event_time) VALUES ('test', ?1)",
+                [now - Duration::days(days)],
+            )
+            .expect("event inserted");
+        }
+
+        let summary = prune_events(
+            creator,
+            RetentionPolicy {
+                max_age: Some(Duration::days(5)),
+                max_rows: None,
+            },
+            false,
+        )
+        .expect("events pruned by age");
+        assert_eq!(
+            (
+                summary.pruned_by_age,
+                summary.pruned_by_rows,
+                summary.remaining
+            ),
+            (2, 0, 3)
+        );
+
+        // The oldest events are pruned to stay under the maximum number of rows.
+        let summary = prune_events(
+            creator,
+            RetentionPolicy {
+                max_age: Some(Duration::days(5)),
+                max_rows: Some(2),
+            },
+            true,
+        )
+        .expect("events pruned by count");
+        assert_eq!(
+            (
+                summary.pruned_by_age,
+                summary.pruned_by_rows,
+                summary.remaining
+            ),
+            (0, 1, 2)
+        );
+        assert!(summary.vacuumed);
+        let oldest: DateTime<Local> = conn
+            .query_row(
+                "SELECT event_time FROM journal WHERE event_name != 'sentinel' \
+                ORDER BY julianday(event_time) LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .expect("oldest event read");
+        assert_eq!(oldest, now - Duration::days(1));
+    }
+
+    #[test]
+    fn full_buffer_counts_dropped() {
+        // Nothing reads from this receiver, so the buffer fills up.
+        let (sender, _receiver) = mpsc::sync_channel(EventLogger::BUFFER_CAPACITY);
+        let stats = Arc::new(LoggerStats::default());
+        let logger = EventLogger {
+            sender,
+            stats: stats.clone(),
+            _shutdown: Arc::new(Shutdown {
+                sender: None,
+                stats: stats.clone(),
+                join_handle: None,
+            }),
+        };
+        for _ in 0..EventLogger::BUFFER_CAPACITY + 5 {
+            logger.log(Event::RunStarted(RunStarted {
+                hasp_version: "0.0.0".to_owned(),
+                args: vec![],
+            }));
+        }
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.failed.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events_journaled_and_broadcast() {
+        let test_home = TestHome::new();
+        let logger = &test_home.db_ctx.event_logger;
+        let mut lifecycle = Box::pin(subscribe_lifecycle());
+
+        // Other tests broadcast events too, so this one's package has its own name.
+        let package = PackageDirectory {
+            namespace: "cargo".to_owned(),
+            name: "lifecycle-test".to_owned(),
+            version: "sem:1.0.0".parse().expect("valid version"),
+            hash: DirectoryHash::new(0x01234567),
+            metadata: serde_json::json!({}),
+        };
+        logger.log_lifecycle(LifecycleEvent::UninstallSuccess(UninstallSuccess {
+            package: package.clone(),
+            end_time: Local::now(),
+        }));
+
+        let event = loop {
+            let event = lifecycle.next().await.expect("stream doesn't end");
+            if event.package().name == package.name {
+                break event;
+            }
+        };
+        assert_eq!(event.name(), "uninstall_success");
+
+        assert!(logger.flush(std::time::Duration::from_secs(10)));
+        let entries = read_events(test_home.creator(), None).expect("events read");
+        assert!(
+            entries
+                .iter()
+                .any(|entry| entry.event_name == "uninstall_success"),
+            "lifecycle event recorded in the journal"
+        );
     }
 }
@@ -0,0 +1,284 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Classifying install failures by cause, to summarize them and to pick an exit code.
+//!
+//! Commands that install packages exit with:
+//!
+//! * 0 if every package was installed, was already installed or was skipped.
+//! * 1 if `--strict` is passed, nothing failed and some packages were already installed.
+//! * [`FAILURE_EXIT_CODE`] plus a bit for each class of failure, if any package failed to
+//!   install, whether or not others were installed.
+//! * [`CANCELLED_EXIT_CODE`](crate::cancel::CANCELLED_EXIT_CODE) if hasp was cancelled.
+
+use crate::{cancel::Cancelled, process::ProcessFailed};
+use color_eyre::Report;
+use colored::Colorize;
+use hasp_metadata::{FailureReason, FailureStage};
+use std::{collections::BTreeMap, error, fmt, io};
+
+/// The exit code used with `--strict` when packages were already installed and none failed.
+pub(crate) const ALREADY_INSTALLED_EXIT_CODE: i32 = 1;
+
+/// The exit code used when any package fails to install. The bit for each class of failure that
+/// occurred is added to it (see [`FailureClass::exit_bit`]).
+pub(crate) const FAILURE_EXIT_CODE: i32 = 2;
+
+/// Returns true if the exit code indicates that at least one package failed to install.
+pub(crate) fn is_failure_exit_code(exit_code: i32) -> bool {
+    exit_code != crate::cancel::CANCELLED_EXIT_CODE && exit_code & FAILURE_EXIT_CODE != 0
+}
+
+/// Summarizes the reason recorded for a failed install in a line, e.g. for `hasp list --all`.
+pub(crate) fn describe_reason(reason: &FailureReason) -> String {
+    let stage = |stage: &FailureStage| match stage {
+        FailureStage::Resolve => "resolving",
+        FailureStage::Fetch => "fetching",
+        FailureStage::Build => "building",
+        FailureStage::Finish => "finishing",
+    };
+    match reason {
+        FailureReason::ProcessFailed { details } => format!(
+            "failed while {}: {}",
+            stage(&details.stage),
+            details
+                .errors
+                .first()
+                .map_or("unknown error", String::as_str)
+        ),
+        FailureReason::BinaryConflict { conflicts } => format!(
+            "binaries {} are already installed by other packages",
+            conflicts.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+        FailureReason::Cancelled { stage: cancelled } => {
+            format!("cancelled while {}", stage(cancelled))
+        }
+        FailureReason::Aborted { .. } => "aborted by an internal error".to_owned(),
+    }
+}
+
+/// The broad cause of a failure.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) enum FailureClass {
+    /// A network operation failed, even after retries.
+    Network,
+    /// The package or a matching version couldn't be found.
+    Resolution,
+    /// Building the package failed.
+    Compile,
+    /// A configured policy, such as a pinned checksum, was violated.
+    Policy,
+    /// Binaries being installed are already installed by other packages.
+    Conflict,
+    /// Reading from or writing to disk failed.
+    Disk,
+    /// Any other failure.
+    Other,
+}
+
+impl FailureClass {
+    /// Classifies a failure, looking through its chain of causes.
+    ///
+    /// A failure may have several causes that match, e.g. a network failure while resolving a
+    /// package. The more specific cause is preferred.
+    pub(crate) fn classify(report: &Report) -> Self {
+        // Context markers are only visible through downcast_ref, not through the chain.
+        if report.downcast_ref::<NetworkFailed>().is_some()
+            || report
+                .chain()
+                .any(|cause| cause.downcast_ref::<reqwest::Error>().is_some())
+        {
+            FailureClass::Network
+        } else if report.downcast_ref::<PolicyViolation>().is_some() {
+            FailureClass::Policy
+        } else if report.downcast_ref::<BinaryConflicts>().is_some() {
+            FailureClass::Conflict
+        } else if report
+            .chain()
+            .any(|cause| cause.downcast_ref::<ProcessFailed>().is_some())
+        {
+            FailureClass::Compile
+        } else if report.downcast_ref::<ResolveFailed>().is_some() {
+            FailureClass::Resolution
+        } else if report
+            .chain()
+            .any(|cause| cause.downcast_ref::<io::Error>().is_some())
+        {
+            FailureClass::Disk
+        } else {
+            FailureClass::Other
+        }
+    }
+
+    /// Returns true if a failure might not happen again, so that the install is worth attempting
+    /// again: a network failure, or a build process killed with `SIGKILL` (e.g. by the
+    /// out-of-memory killer). Other failures, like compile errors, are returned as is.
+    pub(crate) fn is_retryable(report: &Report) -> bool {
+        /// The signal that terminates a process without letting it clean up.
+        const SIGKILL: i32 = 9;
+
+        if report
+            .chain()
+            .any(|cause| cause.downcast_ref::<Cancelled>().is_some())
+        {
+            return false;
+        }
+        let killed = report
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<ProcessFailed>())
+            .any(|process_failed| process_failed.signal == Some(SIGKILL));
+        killed || Self::classify(report) == FailureClass::Network
+    }
+
+    /// Returns the bit added to [`FAILURE_EXIT_CODE`] for this class. Exit codes stay below 128,
+    /// so that they aren't confused with termination by a signal, so conflicts share the bit for
+    /// policy violations: hasp declined to replace binaries, rather than failing to install.
+    pub(crate) fn exit_bit(self) -> i32 {
+        match self {
+            FailureClass::Network => 4,
+            FailureClass::Resolution => 8,
+            FailureClass::Compile => 16,
+            FailureClass::Policy | FailureClass::Conflict => 32,
+            FailureClass::Disk => 64,
+            FailureClass::Other => 0,
+        }
+    }
+
+    /// Returns a short, human-readable name for this class.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            FailureClass::Network => "network",
+            FailureClass::Resolution => "resolution",
+            FailureClass::Compile => "compile",
+            FailureClass::Policy => "policy",
+            FailureClass::Conflict => "conflict",
+            FailureClass::Disk => "disk",
+            FailureClass::Other => "other",
+        }
+    }
+}
+
+/// Context marking a network operation that failed after all retries.
+#[derive(Clone, Debug)]
+pub(crate) struct NetworkFailed(pub(crate) String);
+
+impl fmt::Display for NetworkFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Context marking a failure to resolve a package to a version.
+#[derive(Clone, Debug)]
+pub(crate) struct ResolveFailed(pub(crate) String);
+
+impl fmt::Display for ResolveFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A configured policy was violated.
+#[derive(Clone, Debug)]
+pub(crate) struct PolicyViolation(pub(crate) String);
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for PolicyViolation {}
+
+/// Binaries being installed that other installed packages already have, along with those
+/// packages.
+#[derive(Clone, Debug)]
+pub(crate) struct BinaryConflicts(pub(crate) BTreeMap<String, Vec<String>>);
+
+impl BinaryConflicts {
+    /// Lists the binaries along with the packages that have them, e.g. `rg (from ripgrep)`.
+    pub(crate) fn describe(&self) -> String {
+        self.0
+            .iter()
+            .map(|(binary, owners)| format!("{} (from {})", binary.bold(), owners.join(", ")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for BinaryConflicts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "binaries {} are already installed by other packages (hint: uninstall them, or pass \
+             --force-bin-overwrite to replace them)",
+            self.describe(),
+        )
+    }
+}
+
+impl error::Error for BinaryConflicts {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::{eyre, WrapErr};
+
+    #[test]
+    fn classify_basic() {
+        let network = Err::<(), _>(eyre!("connection reset"))
+            .wrap_err(NetworkFailed(
+                "failed to fetch index after 4 attempts".to_owned(),
+            ))
+            .wrap_err(ResolveFailed("failed to resolve foo".to_owned()))
+            .unwrap_err();
+        assert_eq!(FailureClass::classify(&network), FailureClass::Network);
+        assert!(FailureClass::is_retryable(&network));
+
+        let resolution = Err::<(), _>(eyre!("no matching version"))
+            .wrap_err(ResolveFailed("failed to resolve foo".to_owned()))
+            .unwrap_err();
+        assert_eq!(
+            FailureClass::classify(&resolution),
+            FailureClass::Resolution
+        );
+
+        let policy = Report::new(PolicyViolation("checksum mismatch".to_owned()))
+            .wrap_err("failed to fetch foo");
+        assert_eq!(FailureClass::classify(&policy), FailureClass::Policy);
+
+        let conflict = Report::new(BinaryConflicts(
+            [("rg".to_owned(), vec!["ripgrep".to_owned()])].into(),
+        ));
+        assert_eq!(FailureClass::classify(&conflict), FailureClass::Conflict);
+        assert!(!FailureClass::is_retryable(&conflict));
+
+        let disk = Report::new(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "permission denied",
+        ))
+        .wrap_err("failed to write foo");
+        assert_eq!(FailureClass::classify(&disk), FailureClass::Disk);
+
+        assert_eq!(FailureClass::classify(&eyre!("???")), FailureClass::Other);
+
+        let process_failed = |exit_code, signal| ProcessFailed {
+            program: "cargo build".to_owned(),
+            exit_code,
+            signal,
+            stderr_tail: vec![],
+        };
+        let compile = Report::new(process_failed(Some(101), None)).wrap_err("failed to build foo");
+        assert_eq!(FailureClass::classify(&compile), FailureClass::Compile);
+        assert!(!FailureClass::is_retryable(&compile));
+        let killed = Report::new(process_failed(None, Some(9))).wrap_err("failed to build foo");
+        assert!(FailureClass::is_retryable(&killed));
+        assert!(!FailureClass::is_retryable(&disk));
+
+        assert!(is_failure_exit_code(
+            FAILURE_EXIT_CODE | FailureClass::Disk.exit_bit()
+        ));
+        assert!(!is_failure_exit_code(1));
+        assert!(!is_failure_exit_code(crate::cancel::CANCELLED_EXIT_CODE));
+    }
+}
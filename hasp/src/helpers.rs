@@ -1,24 +1,351 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use color_eyre::{eyre::WrapErr, Result};
+use crate::{
+    config::{package_key, split_package_key},
+    ops::split_image_ref,
+    platform::{set_executable, EXECUTABLE_EXTENSIONS},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
 use colored::Colorize;
-use semver::VersionReq;
-
-/// Split a specifier into name and version.
-pub(crate) fn split_version(spec: &str) -> Result<(String, VersionReq)> {
-    match spec.split_once('@') {
-        Some((name, version)) => {
-            let version = version.parse::<VersionReq>().wrap_err_with(|| {
-                format!("failed to parse version req for crate {}", name.bold())
-            })?;
-            Ok((name.to_owned(), version))
+use hasp_metadata::{DirectoryVersion, DirectoryVersionReq};
+use semver::{BuildMetadata, Version, VersionReq};
+use std::{
+    env, fs, io,
+    path::{self, Path},
+    str::FromStr,
+};
+
+/// A package requested on the command line, like `ripgrep@13` or `npm:@scope/name@1.0`.
+#[derive(Clone, Debug)]
+pub(crate) struct PackageSpec {
+    /// The namespace of the package, defaulting to `cargo`.
+    pub(crate) namespace: String,
+    pub(crate) name: String,
+    /// The requested version, if one was given.
+    pub(crate) req: Option<DirectoryVersionReq>,
+}
+
+impl FromStr for PackageSpec {
+    type Err = color_eyre::Report;
+
+    /// Parses a specifier of the form `[namespace:]name[@version]`, as accepted by `cargo install`
+    /// for crates.
+    ///
+    /// A leading `@` is treated as part of the name, to support scoped npm packages like
+    /// `@scope/name@1.0`. A version is a semver requirement such as `1.2`, `^1` or `=1.2.3`,
+    /// optionally prefixed with `v`, or a literal version starting with `lit:`, matched exactly
+    /// rather than parsed as semver. Images use their own syntax: see [`split_image_ref`].
+    fn from_str(s: &str) -> Result<Self> {
+        let (namespace, spec) = match s.split_once(':') {
+            // The colon is part of a version like `tool@lit:nightly`, not after a namespace.
+            Some((before, _)) if before.contains('@') => ("cargo", s),
+            _ => split_package_key(s),
+        };
+        if namespace == "oci" {
+            let (name, reference) = split_image_ref(spec)?;
+            return Ok(Self {
+                namespace: namespace.to_owned(),
+                name,
+                req: Some(DirectoryVersionReq::new(reference)),
+            });
+        }
+
+        let (name, version) = match spec.char_indices().skip(1).find(|&(_, c)| c == '@') {
+            Some((idx, _)) => (&spec[..idx], Some(&spec[idx + 1..])),
+            None => (spec, None),
+        };
+        if namespace.is_empty() || name.is_empty() || version == Some("") {
+            bail!(
+                "invalid package '{}' (hint: packages look like ripgrep@13 or npm:prettier)",
+                s
+            );
         }
-        None => Ok((spec.to_owned(), VersionReq::default())),
+        let req = version
+            .map(
+                |version| match version.strip_prefix(DirectoryVersion::LIT_PREFIX) {
+                    Some("") => bail!("empty literal version for {}", name.bold()),
+                    Some(literal) => Ok(DirectoryVersionReq::new_literal(literal)),
+                    None => {
+                        // Allow tags like v1.0, which is how versions are often written.
+                        let semver = match version.strip_prefix('v') {
+                            Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => rest,
+                            _ => version,
+                        };
+                        let req = semver.parse::<VersionReq>().wrap_err_with(|| {
+                            format!(
+                            "failed to parse version req for {} (hint: use lit:{} for a version \
+                             that isn't semver)",
+                            name.bold(),
+                            version,
+                        )
+                        })?;
+                        Ok(req.into())
+                    }
+                },
+            )
+            .transpose()?;
+        Ok(Self {
+            namespace: namespace.to_owned(),
+            name: name.to_owned(),
+            req,
+        })
     }
 }
 
+/// Merges specifiers for the same package, so that each package is installed once. Specifiers
+/// are returned in the order their packages were first requested.
+///
+/// Semver requirements are combined, so that `foo foo@1.2` installs a version matching `^1.2`. It's
+/// an error to request versions of a package that no single version could satisfy.
+pub(crate) fn merge_specs(specs: Vec<PackageSpec>) -> Result<Vec<PackageSpec>> {
+    let mut merged: Vec<PackageSpec> = vec![];
+    for spec in specs {
+        let existing = match merged
+            .iter_mut()
+            .find(|existing| existing.namespace == spec.namespace && existing.name == spec.name)
+        {
+            Some(existing) => existing,
+            None => {
+                merged.push(spec);
+                continue;
+            }
+        };
+        existing.req = match (existing.req.take(), spec.req) {
+            (None, req) | (req, None) => req,
+            (Some(a), Some(b)) => Some(merge_reqs(&spec.name, a, b)?),
+        };
+        let package = package_key(&spec.namespace, &spec.name);
+        tracing::debug!(
+            target: "hasp::output::working::coalesced",
+            %package,
+            "Merged duplicate request for {package}",
+        );
+    }
+    Ok(merged)
+}
+
+fn merge_reqs(
+    name: &str,
+    a: DirectoryVersionReq,
+    b: DirectoryVersionReq,
+) -> Result<DirectoryVersionReq> {
+    if a.as_str() == b.as_str() {
+        return Ok(a);
+    }
+    let conflict = || {
+        eyre!(
+            "conflicting versions requested for {}: {} and {} (hint: request it once, with the \
+             version to install)",
+            name.bold(),
+            a,
+            b,
+        )
+    };
+    let (a_semver, b_semver) = match (a.as_semver(), b.as_semver()) {
+        (Some(a_semver), Some(b_semver)) => (a_semver, b_semver),
+        _ => return Err(conflict()),
+    };
+    let mut comparators = a_semver.comparators.clone();
+    for comparator in &b_semver.comparators {
+        if !comparators.contains(comparator) {
+            comparators.push(comparator.clone());
+        }
+    }
+    let req = VersionReq { comparators };
+    // The lowest version matching every comparator, if any, is the version of one of them or just
+    // above it.
+    let satisfiable = req.comparators.iter().any(|comparator| {
+        let version = Version {
+            major: comparator.major,
+            minor: comparator.minor.unwrap_or(0),
+            patch: comparator.patch.unwrap_or(0),
+            pre: comparator.pre.clone(),
+            build: BuildMetadata::EMPTY,
+        };
+        [
+            version.clone(),
+            Version::new(version.major, version.minor, version.patch + 1),
+            Version::new(version.major, version.minor + 1, 0),
+            Version::new(version.major + 1, 0, 0),
+        ]
+        .iter()
+        .any(|candidate| req.matches(candidate))
+    });
+    if !satisfiable {
+        return Err(conflict());
+    }
+    Ok(req.into())
+}
+
+/// Makes a path absolute, relative to the current directory, without resolving symlinks.
+pub(crate) fn absolute(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let absolute =
+        path::absolute(path).wrap_err_with(|| format!("failed to make {} absolute", path))?;
+    Utf8PathBuf::from_path_buf(absolute)
+        .map_err(|absolute| eyre!("{} is not valid UTF-8", absolute.display()))
+}
+
+/// Looks up an executable by name in `PATH`, returning the first match.
+pub(crate) fn find_in_path(name: &str) -> Option<Utf8PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .filter_map(|dir| Utf8PathBuf::from_path_buf(dir).ok())
+        .flat_map(|dir| {
+            EXECUTABLE_EXTENSIONS
+                .iter()
+                .map(move |ext| dir.join(format!("{}{}", name, ext)))
+        })
+        .find(|candidate| candidate.is_file())
+}
+
+/// Returns the total size of the files within a directory, without following symlinks.
+pub(crate) fn dir_size(dir: &Utf8Path) -> Result<u64> {
+    fn dir_size_impl(dir: &Path) -> io::Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            size += if metadata.is_dir() {
+                dir_size_impl(&entry.path())?
+            } else {
+                metadata.len()
+            };
+        }
+        Ok(size)
+    }
+
+    dir_size_impl(dir.as_std_path()).wrap_err_with(|| format!("failed to get size of {}", dir))
+}
+
+/// Writes out a script to the given path, marking it executable where that's necessary.
+pub(crate) fn write_script(path: &Utf8Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).wrap_err_with(|| format!("failed to write script to {}", path))?;
+    set_executable(path)
+}
+
 #[cfg(test)]
 mod tests {
-    // TODO: tests for split_version
+    use super::*;
+
+    #[test]
+    fn package_spec_parse() {
+        let parse = |s: &str| s.parse::<PackageSpec>().expect("spec parsed");
+        let spec = parse("ripgrep");
+        assert_eq!(
+            (spec.namespace.as_str(), spec.name.as_str()),
+            ("cargo", "ripgrep")
+        );
+        assert!(spec.req.is_none(), "no version given");
+
+        for (s, req) in [
+            ("ripgrep@13", "^13"),
+            ("ripgrep@^1", "^1"),
+            ("ripgrep@1.2", "^1.2"),
+            ("ripgrep@=13.0.0", "=13.0.0"),
+            ("ripgrep@v13.0", "^13.0"),
+        ] {
+            let spec = parse(s);
+            assert_eq!(spec.name, "ripgrep", "{}", s);
+            assert_eq!(
+                spec.req.as_ref().and_then(|req| req.as_semver()),
+                Some(&req.parse().expect("valid req")),
+                "{}",
+                s
+            );
+        }
+
+        let spec = parse("npm:@scope/tool@=1.2.3");
+        assert_eq!(
+            (spec.namespace.as_str(), spec.name.as_str()),
+            ("npm", "@scope/tool")
+        );
+        assert_eq!(
+            spec.req.as_ref().and_then(|req| req.as_semver()),
+            Some(&"=1.2.3".parse().unwrap())
+        );
+        let spec = parse("npm:@scope/tool");
+        assert_eq!(spec.name, "@scope/tool");
+        assert!(spec.req.is_none(), "no version given");
+
+        let spec = parse("github:owner/repo@v1.0");
+        assert_eq!(spec.namespace, "github");
+        assert_eq!(spec.name, "owner/repo");
+        assert_eq!(spec.req.expect("version given").as_str(), "^1.0");
+
+        let spec = parse("oci:hadolint/hadolint:v2.8.0");
+        assert_eq!(spec.name, "hadolint/hadolint");
+        assert_eq!(spec.req.expect("tag given").as_str(), "v2.8.0");
+
+        for invalid in ["ripgrep@not-a-version", "ripgrep@", ":ripgrep", "cargo:"] {
+            assert!(
+                invalid.parse::<PackageSpec>().is_err(),
+                "{} is invalid",
+                invalid
+            );
+        }
+
+        let req = parse("tool@lit:nightly-2021").req.expect("version given");
+        assert_eq!(req.as_literal(), Some("nightly-2021"));
+        assert_eq!(req.as_semver(), None);
+        assert_eq!(req.to_string(), "lit:nightly-2021");
+        assert!(req.matches(&DirectoryVersion::new_literal("nightly-2021")));
+        assert!(!req.matches(&DirectoryVersion::new_literal("nightly-2022")));
+
+        // Literal versions are compared exactly, even against semantic versions.
+        let req = parse("tool@lit:1.0.0+build.5").req.expect("version given");
+        assert!(req.matches(&"sem:1.0.0+build.5".parse().expect("valid version")));
+        assert!(!req.matches(&"sem:1.0.0".parse().expect("valid version")));
+        assert!(
+            "tool@lit:".parse::<PackageSpec>().is_err(),
+            "empty literal version"
+        );
+    }
+
+    #[test]
+    fn merge_package_specs() {
+        let merge = |specs: &[&str]| {
+            merge_specs(
+                specs
+                    .iter()
+                    .map(|s| s.parse::<PackageSpec>().expect("spec parsed"))
+                    .collect(),
+            )
+            .map(|merged| {
+                merged
+                    .into_iter()
+                    .map(|spec| {
+                        let req = spec.req.map(|req| req.to_string());
+                        (spec.name, req)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+        let merged = merge(&["foo", "bar@1", "foo@1.2", "foo@1.2"]).expect("specs merged");
+        assert_eq!(
+            merged,
+            [
+                ("foo".to_owned(), Some("^1.2".to_owned())),
+                ("bar".to_owned(), Some("^1".to_owned())),
+            ]
+        );
+        let merged = merge(&["foo@>=1.2", "foo@<1.5", "foo@>1.4.0"]).expect("specs merged");
+        assert_eq!(
+            merged,
+            [("foo".to_owned(), Some(">=1.2, <1.5, >1.4.0".to_owned()))]
+        );
+
+        for conflicting in [
+            &["foo@1", "foo@2"][..],
+            &["foo@>1.2", "foo@<1.3"],
+            &["foo@lit:nightly", "foo@1"],
+            &["oci:foo:latest", "oci:foo:stable"],
+        ] {
+            assert!(merge(conflicting).is_err(), "{:?} conflict", conflicting);
+        }
+    }
 }
@@ -0,0 +1,186 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Hints about packages that are deprecated, unmaintained or superseded by another package.
+//!
+//! Hints are printed when a package is resolved for installation. A small list is built into hasp,
+//! and can be replaced with a newer list from a remote URL through `hasp hints update`. Hints in
+//! the config file take precedence over both.
+
+use crate::{
+    config::{package_key, HintsConfig},
+    home::HaspHome,
+    http_client,
+    network::RetryPolicy,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt, fs, io};
+
+/// The URL that `hasp hints update` fetches hints from, unless overridden in the config.
+pub(crate) const DEFAULT_HINTS_URL: &str =
+    "https://raw.githubusercontent.com/hasp-rs/hasp/main/hasp/hints.toml";
+
+/// The hints built into hasp, used until a list is fetched with `hasp hints update`.
+static BUILTIN_HINTS: &str = include_str!("../hints.toml");
+
+/// A hint about a single package.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct PackageHint {
+    /// Why the package shouldn't be used, e.g. "is unmaintained".
+    pub(crate) reason: Option<String>,
+
+    /// A package or tool to use instead.
+    pub(crate) replacement: Option<String>,
+}
+
+impl PackageHint {
+    /// Returns a note about this package, e.g. "foo is unmaintained, consider bar".
+    pub(crate) fn note<'a>(&'a self, name: &'a str) -> impl fmt::Display + 'a {
+        HintNote { name, hint: self }
+    }
+}
+
+struct HintNote<'a> {
+    name: &'a str,
+    hint: &'a PackageHint,
+}
+
+impl<'a> fmt::Display for HintNote<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.name,
+            self.hint.reason.as_deref().unwrap_or("is deprecated")
+        )?;
+        if let Some(replacement) = &self.hint.replacement {
+            write!(f, ", consider {}", replacement)?;
+        }
+        Ok(())
+    }
+}
+
+/// A list of hints, keyed by package: the name for Cargo packages, and `namespace:name`
+/// otherwise.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HintList {
+    #[serde(default)]
+    pub(crate) packages: BTreeMap<String, PackageHint>,
+}
+
+impl HintList {
+    /// The name of the file that fetched hints are stored in, within the cache directory.
+    const FILE_NAME: &'static str = "hints.toml";
+
+    /// Loads the hints in effect: the fetched list if there is one or the built-in list
+    /// otherwise, with hints from the config file taking precedence.
+    pub(crate) fn load(hasp_home: &HaspHome) -> Result<Self> {
+        let config = &hasp_home.config().hints;
+        if !config.enabled() {
+            return Ok(Self::default());
+        }
+
+        let path = Self::path(hasp_home);
+        let mut list = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                Self::parse(&contents).wrap_err_with(|| format!("failed to parse {}", path))?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Self::parse(BUILTIN_HINTS).expect("built-in hints are valid")
+            }
+            Err(err) => return Err(err).wrap_err_with(|| format!("failed to read {}", path)),
+        };
+        list.packages.extend(config.packages.clone());
+        Ok(list)
+    }
+
+    /// Fetches the latest hints and stores them for future installs, returning the number of
+    /// packages with hints.
+    pub(crate) async fn update(hasp_home: &HaspHome, network: &RetryPolicy) -> Result<usize> {
+        let url = hasp_home.config().hints.url();
+        let client = http_client::client()?;
+        let contents = network
+            .run(&format!("fetch hints from {}", url), || async move {
+                let resp = client.get(url).send().await?.error_for_status()?;
+                Ok(resp.text().await?)
+            })
+            .await?;
+        let list = Self::parse(&contents)
+            .wrap_err_with(|| format!("failed to parse hints fetched from {}", url))?;
+
+        let path = Self::path(hasp_home);
+        write_atomic(&path, &contents)?;
+        Ok(list.packages.len())
+    }
+
+    /// Returns the hint for a package, if there is one.
+    pub(crate) fn get(&self, namespace: &str, name: &str) -> Option<&PackageHint> {
+        self.packages.get(&package_key(namespace, name))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    fn path(hasp_home: &HaspHome) -> Utf8PathBuf {
+        hasp_home.cache_dir().join(Self::FILE_NAME)
+    }
+}
+
+/// Writes out a file through a temporary file, so that readers never see a partial list.
+fn write_atomic(path: &Utf8Path, contents: &str) -> Result<()> {
+    let temp_path = path.with_extension("toml.tmp");
+    fs::write(&temp_path, contents).wrap_err_with(|| format!("failed to write {}", temp_path))?;
+    fs::rename(&temp_path, path)
+        .wrap_err_with(|| format!("failed to rename {} to {}", temp_path, path))
+}
+
+impl HintsConfig {
+    /// Returns whether hints are printed. Defaults to true.
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// Returns the URL to fetch hints from.
+    pub(crate) fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or(DEFAULT_HINTS_URL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_hints_parse() {
+        let list = HintList::parse(BUILTIN_HINTS).expect("built-in hints are valid");
+        assert!(!list.packages.is_empty(), "built-in hints aren't empty");
+    }
+
+    #[test]
+    fn hint_note() {
+        let list = HintList::parse(
+            r#"
+            [packages.foo]
+            reason = "is unmaintained"
+            replacement = "bar"
+
+            [packages."npm:baz"]
+            "#,
+        )
+        .expect("hints parsed");
+
+        let hint = list.get("cargo", "foo").expect("hint for foo");
+        assert_eq!(
+            hint.note("foo").to_string(),
+            "foo is unmaintained, consider bar"
+        );
+        let hint = list.get("npm", "baz").expect("hint for npm:baz");
+        assert_eq!(hint.note("baz").to_string(), "baz is deprecated");
+        assert!(list.get("npm", "foo").is_none(), "hints are per namespace");
+    }
+}
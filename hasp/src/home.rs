@@ -1,6 +1,7 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+use crate::{config::HaspConfig, ops::LockWait};
 use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::{
     eyre::{bail, WrapErr},
@@ -15,6 +16,10 @@ pub(crate) struct HaspHome {
     home_dir: Utf8PathBuf,
     cache_dir: Utf8PathBuf,
     installs_dir: Utf8PathBuf,
+    bin_dir: Utf8PathBuf,
+    config: HaspConfig,
+    lock_wait: LockWait,
+    interactive: bool,
 }
 
 impl HaspHome {
@@ -22,16 +27,25 @@ impl HaspHome {
         let home_dir = home_dir.into();
         let cache_dir = home_dir.join("cache");
         let installs_dir = home_dir.join("installs");
+        let bin_dir = home_dir.join("bin");
 
         // The home directory will automatically be created.
         fs::create_dir_all(&cache_dir)
             .wrap_err_with(|| format!("failed to create {}", cache_dir))?;
         fs::create_dir_all(&installs_dir)
             .wrap_err_with(|| format!("failed to create {}", installs_dir))?;
+        fs::create_dir_all(&bin_dir).wrap_err_with(|| format!("failed to create {}", bin_dir))?;
+
+        let config = HaspConfig::from_path(&home_dir.join(HaspConfig::FILE_NAME))?;
+        let lock_wait = config.install.lock_wait();
         Ok(Self {
             home_dir,
             cache_dir,
             installs_dir,
+            bin_dir,
+            config,
+            lock_wait,
+            interactive: true,
         })
     }
 
@@ -73,16 +87,91 @@ impl HaspHome {
         &self.installs_dir
     }
 
-    pub(crate) fn make_install_path(
+    #[inline]
+    pub(crate) fn bin_dir(&self) -> &Utf8Path {
+        &self.bin_dir
+    }
+
+    #[inline]
+    pub(crate) fn config(&self) -> &HaspConfig {
+        &self.config
+    }
+
+    /// Returns how long to wait for other processes to release install locks.
+    #[inline]
+    pub(crate) fn lock_wait(&self) -> LockWait {
+        self.lock_wait
+    }
+
+    /// Overrides how long to wait for other processes to release install locks.
+    pub(crate) fn set_lock_wait(&mut self, lock_wait: LockWait) {
+        self.lock_wait = lock_wait;
+    }
+
+    /// Returns whether hasp may ask questions in the terminal, such as whether to trust a source.
+    #[inline]
+    pub(crate) fn interactive(&self) -> bool {
+        self.interactive
+    }
+
+    /// Overrides whether hasp may ask questions in the terminal.
+    pub(crate) fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// Returns the install path for a package, without creating it.
+    pub(crate) fn install_path(
         &self,
-        namespace: &'static str,
+        namespace: &str,
         name: &str,
         hash: DirectoryHash,
-    ) -> Result<Utf8PathBuf> {
+    ) -> Utf8PathBuf {
         let mut install_path = self.installs_dir().join(namespace);
         install_path.push(name);
         install_path.push(&format!("{}", hash));
+        install_path
+    }
+
+    /// Returns the directory that superseded installs are kept in, for `hasp rollback`.
+    pub(crate) fn previous_dir(&self) -> Utf8PathBuf {
+        self.home_dir().join("previous")
+    }
+
+    /// Returns the path that a superseded install of a package is kept at, for `hasp rollback`.
+    pub(crate) fn previous_path(
+        &self,
+        namespace: &str,
+        name: &str,
+        hash: DirectoryHash,
+    ) -> Utf8PathBuf {
+        let mut previous_path = self.previous_dir();
+        previous_path.push(namespace);
+        previous_path.push(name);
+        previous_path.push(&format!("{}", hash));
+        previous_path
+    }
 
+    /// Returns the content-addressed store that identical installed files are hard-linked from,
+    /// if `install.content-store` is set.
+    pub(crate) fn content_store_dir(&self) -> Utf8PathBuf {
+        self.home_dir().join("content-store")
+    }
+
+    /// Returns the directory that build logs for a package are written to.
+    pub(crate) fn build_log_dir(&self, namespace: &str, name: &str) -> Utf8PathBuf {
+        let mut build_log_dir = self.cache_dir().join("build-logs");
+        build_log_dir.push(namespace);
+        build_log_dir.push(name);
+        build_log_dir
+    }
+
+    pub(crate) fn make_install_path(
+        &self,
+        namespace: &'static str,
+        name: &str,
+        hash: DirectoryHash,
+    ) -> Result<Utf8PathBuf> {
+        let install_path = self.install_path(namespace, name, hash);
         fs::create_dir_all(&install_path)
             .wrap_err_with(|| format!("failed to create directory at {}", install_path))?;
         Ok(install_path)
@@ -0,0 +1,444 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Hooks that run at points during installs and uninstalls, configured in `[hooks]`.
+//!
+//! Each hook either runs a command or performs a built-in action, and may be limited to some
+//! packages:
+//!
+//! ```toml
+//! [[hooks.post-install]]
+//! action = "smoke-test"
+//!
+//! [[hooks.post-fetch]]
+//! command = ["scan-sources", "--strict"]
+//! packages = ["ripgrep", "npm:prettier"]
+//! abort-on-failure = true
+//! ```
+//!
+//! Commands are passed details about the package through `HASP_*` environment variables. Every
+//! hook that runs is recorded in the journal. A hook that fails aborts the install if
+//! `abort-on-failure` is set, and is otherwise reported as a warning.
+
+use crate::{
+    cancel, config::package_key, events::EventLogger, failure::PolicyViolation, home::HaspHome,
+    process::ProcessFailed,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use hasp_metadata::{Event, HookFinished};
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The number of lines of standard error kept from a failed smoke test.
+const SMOKE_TEST_STDERR_LINES: usize = 20;
+
+/// How long a binary may take to run with `--help` before the smoke test kills it.
+const SMOKE_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether a smoke-tested binary has exited.
+const SMOKE_TEST_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct HooksConfig {
+    /// Hooks run before a version requirement is resolved.
+    #[serde(default)]
+    pub(crate) pre_resolve: Vec<HookConfig>,
+
+    /// Hooks run after a package is fetched, with `HASP_FETCH_DIR` set to the fetched files.
+    #[serde(default)]
+    pub(crate) post_fetch: Vec<HookConfig>,
+
+    /// Hooks run after a package is built, but before it's moved into place, with
+    /// `HASP_INSTALL_DIR` set to the built files.
+    #[serde(default)]
+    pub(crate) post_install: Vec<HookConfig>,
+
+    /// Hooks run after a package is uninstalled. These can't abort anything.
+    #[serde(default)]
+    pub(crate) post_uninstall: Vec<HookConfig>,
+}
+
+impl HooksConfig {
+    /// Returns the hooks configured for this point.
+    pub(crate) fn hooks(&self, point: HookPoint) -> &[HookConfig] {
+        match point {
+            HookPoint::PreResolve => &self.pre_resolve,
+            HookPoint::PostFetch => &self.post_fetch,
+            HookPoint::PostInstall => &self.post_install,
+            HookPoint::PostUninstall => &self.post_uninstall,
+        }
+    }
+
+    /// Checks that each hook runs exactly one command or action, and that actions are only used
+    /// where they make sense.
+    pub(crate) fn validate(&self) -> Result<()> {
+        for point in HookPoint::ALL {
+            for hook in self.hooks(point) {
+                match (&hook.command, hook.action) {
+                    (Some(command), None) if command.is_empty() => {
+                        bail!("{} hook has an empty command", point)
+                    }
+                    (Some(_), None) => {}
+                    (None, Some(HookAction::SmokeTest)) if point != HookPoint::PostInstall => {
+                        bail!(
+                            "{} hook uses the smoke-test action, which only applies to \
+                             post-install hooks",
+                            point
+                        )
+                    }
+                    (None, Some(_)) => {}
+                    _ => bail!(
+                        "{} hook must set exactly one of `command` or `action`",
+                        point
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct HookConfig {
+    /// The command to run, as a program followed by its arguments.
+    pub(crate) command: Option<Vec<String>>,
+
+    /// The built-in action to perform instead of a command.
+    pub(crate) action: Option<HookAction>,
+
+    /// The packages to run this hook for, keyed as in `[packages]`. Defaults to every package.
+    #[serde(default)]
+    pub(crate) packages: Vec<String>,
+
+    /// Whether a failure aborts the install, rather than being reported as a warning.
+    #[serde(default)]
+    pub(crate) abort_on_failure: bool,
+}
+
+impl HookConfig {
+    fn applies_to(&self, package: &str) -> bool {
+        self.packages.is_empty() || self.packages.iter().any(|key| key == package)
+    }
+}
+
+impl fmt::Display for HookConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.command, self.action) {
+            (Some(command), _) => write!(f, "{}", command.join(" ")),
+            (None, Some(action)) => write!(f, "{}", action),
+            (None, None) => write!(f, "(nothing)"),
+        }
+    }
+}
+
+/// A built-in action a hook can perform.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum HookAction {
+    /// Run each installed binary with `--help`, failing if any of them exits unsuccessfully.
+    SmokeTest,
+}
+
+impl fmt::Display for HookAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookAction::SmokeTest => write!(f, "smoke-test"),
+        }
+    }
+}
+
+/// A point during an install or uninstall at which hooks run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum HookPoint {
+    PreResolve,
+    PostFetch,
+    PostInstall,
+    PostUninstall,
+}
+
+impl HookPoint {
+    const ALL: [HookPoint; 4] = [
+        HookPoint::PreResolve,
+        HookPoint::PostFetch,
+        HookPoint::PostInstall,
+        HookPoint::PostUninstall,
+    ];
+
+    /// Returns true if a failing hook at this point can abort what's being done.
+    fn can_abort(self) -> bool {
+        self != HookPoint::PostUninstall
+    }
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookPoint::PreResolve => write!(f, "pre-resolve"),
+            HookPoint::PostFetch => write!(f, "post-fetch"),
+            HookPoint::PostInstall => write!(f, "post-install"),
+            HookPoint::PostUninstall => write!(f, "post-uninstall"),
+        }
+    }
+}
+
+/// The package hooks are run for, and what's known about it at that point.
+#[derive(Clone, Debug)]
+pub(crate) struct HookContext<'a> {
+    pub(crate) point: HookPoint,
+    pub(crate) namespace: &'a str,
+    pub(crate) name: &'a str,
+    /// Extra environment variables passed to commands, such as `HASP_VERSION`.
+    pub(crate) env: Vec<(&'static str, String)>,
+    /// The binaries a smoke test runs.
+    pub(crate) binaries: Vec<Utf8PathBuf>,
+}
+
+impl<'a> HookContext<'a> {
+    pub(crate) fn new(point: HookPoint, namespace: &'a str, name: &'a str) -> Self {
+        Self {
+            point,
+            namespace,
+            name,
+            env: vec![],
+            binaries: vec![],
+        }
+    }
+
+    pub(crate) fn env(mut self, key: &'static str, value: impl fmt::Display) -> Self {
+        self.env.push((key, value.to_string()));
+        self
+    }
+
+    pub(crate) fn binaries(mut self, binaries: impl IntoIterator<Item = Utf8PathBuf>) -> Self {
+        self.binaries.extend(binaries);
+        self
+    }
+}
+
+/// Runs the hooks configured for this point that apply to the package, recording each one in the
+/// journal.
+///
+/// Returns an error if a hook with `abort-on-failure` fails. Other failures are reported as
+/// warnings.
+pub(crate) fn run_hooks(
+    hasp_home: &HaspHome,
+    event_logger: &EventLogger,
+    ctx: &HookContext<'_>,
+) -> Result<()> {
+    let package = package_key(ctx.namespace, ctx.name);
+    for hook in hasp_home.config().hooks.hooks(ctx.point) {
+        if !hook.applies_to(&package) {
+            continue;
+        }
+        tracing::debug!(
+            target: "hasp::output::working::hook",
+            point = %ctx.point,
+            %hook,
+            %package,
+            "Running {point} hook `{hook}` for {package}",
+            point = ctx.point,
+        );
+        let res = run_hook(hook, ctx);
+        let aborted = res.is_err() && hook.abort_on_failure && ctx.point.can_abort();
+        event_logger.log(Event::HookFinished(HookFinished {
+            point: ctx.point.to_string(),
+            package: package.clone(),
+            hook: hook.to_string(),
+            success: res.is_ok(),
+            exit_code: res.as_ref().err().and_then(|err| {
+                err.chain()
+                    .find_map(|cause| cause.downcast_ref::<ProcessFailed>())
+                    .and_then(|process_failed| process_failed.exit_code)
+            }),
+            error: res.as_ref().err().map(|err| format!("{:#}", err)),
+            aborted,
+        }));
+
+        match res {
+            Ok(()) => {}
+            Err(err) if aborted => {
+                return Err(err).wrap_err_with(|| {
+                    PolicyViolation(format!(
+                        "{} hook `{}` failed for {}",
+                        ctx.point, hook, package
+                    ))
+                });
+            }
+            Err(err) => {
+                let error = format!("{:#}", err);
+                tracing::warn!(
+                    target: "hasp::output::hook_failed",
+                    point = %ctx.point,
+                    %hook,
+                    %package,
+                    %error,
+                    "Warning {point} hook `{hook}` failed for {package}: {error}",
+                    point = ctx.point,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_hook(hook: &HookConfig, ctx: &HookContext<'_>) -> Result<()> {
+    match (&hook.command, hook.action) {
+        (Some(command), _) => {
+            let (program, args) = command.split_first().expect("commands are validated");
+            // Hooks write their output to standard error, so that it doesn't mix with hasp's
+            // machine-readable output.
+            let mut expression = duct::cmd(program, args)
+                .env("HASP_HOOK", ctx.point.to_string())
+                .env("HASP_NAMESPACE", ctx.namespace)
+                .env("HASP_NAME", ctx.name)
+                .stdin_null()
+                .stdout_to_stderr()
+                .unchecked();
+            for (key, value) in &ctx.env {
+                expression = expression.env(key, value);
+            }
+            let output = expression
+                .run()
+                .wrap_err_with(|| format!("failed to run {}", program))?;
+            ProcessFailed::check(program, &output.status, vec![])?;
+            Ok(())
+        }
+        (None, Some(HookAction::SmokeTest)) => {
+            for binary in &ctx.binaries {
+                smoke_test(binary, SMOKE_TEST_TIMEOUT)?;
+            }
+            Ok(())
+        }
+        (None, None) => Ok(()),
+    }
+}
+
+/// Runs a binary with `--help`, killing it if it doesn't exit within `timeout`.
+fn smoke_test(binary: &Utf8Path, timeout: Duration) -> Result<()> {
+    let handle = Arc::new(
+        duct::cmd(binary.as_std_path(), ["--help"])
+            .stdin_null()
+            .stdout_null()
+            .stderr_capture()
+            .unchecked()
+            .start()
+            .wrap_err_with(|| format!("failed to run {}", binary))?,
+    );
+    let _child_guard = cancel::register_child(handle.clone());
+
+    let start = Instant::now();
+    let output = loop {
+        if let Some(output) = handle
+            .try_wait()
+            .wrap_err_with(|| format!("failed to wait for {}", binary))?
+        {
+            break output;
+        }
+        if start.elapsed() >= timeout {
+            // The binary may have exited in the meantime, in which case there's nothing to kill.
+            let _ = handle.kill();
+            bail!(
+                "{} --help didn't exit within {}s, so it was killed (hint: binaries that don't \
+                 support --help can't be smoke tested)",
+                binary,
+                timeout.as_secs(),
+            );
+        }
+        thread::sleep(SMOKE_TEST_POLL_INTERVAL);
+    };
+    // If the binary was killed because hasp was cancelled, report that rather than the exit
+    // status.
+    cancel::check()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    let stderr_tail = lines[lines.len().saturating_sub(SMOKE_TEST_STDERR_LINES)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+    ProcessFailed::check(format!("{} --help", binary), &output.status, stderr_tail)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_validate_and_run() {
+        let config: HooksConfig = toml::from_str(
+            r#"
+            [[pre-resolve]]
+            command = ["sh", "-c", "[ \"$HASP_NAME\" != foo ] || exit 3"]
+            packages = ["foo"]
+
+            [[post-install]]
+            action = "smoke-test"
+            abort-on-failure = true
+            "#,
+        )
+        .expect("hooks parsed");
+        config.validate().expect("hooks are valid");
+        let hook = &config.pre_resolve[0];
+        assert!(hook.applies_to("foo"));
+        assert!(!hook.applies_to("npm:foo"));
+        assert!(config.post_install[0].applies_to("npm:foo"));
+
+        for invalid in [
+            "[[post-fetch]]\naction = \"smoke-test\"",
+            "[[post-install]]\ncommand = []",
+            "[[post-install]]\ncommand = [\"true\"]\naction = \"smoke-test\"",
+            "[[post-install]]\npackages = [\"foo\"]",
+        ] {
+            let config: HooksConfig = toml::from_str(invalid).expect("hooks parsed");
+            assert!(config.validate().is_err(), "{} is rejected", invalid);
+        }
+
+        if cfg!(unix) {
+            let ctx = HookContext::new(HookPoint::PreResolve, "cargo", "foo");
+            let err = run_hook(hook, &ctx).expect_err("hook fails for foo");
+            let process_failed = err
+                .downcast_ref::<ProcessFailed>()
+                .expect("failure is a process failure");
+            assert_eq!(process_failed.exit_code, Some(3));
+            let ctx = HookContext::new(HookPoint::PreResolve, "cargo", "bar");
+            run_hook(hook, &ctx).expect("hook succeeds for bar");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn smoke_test_timeout() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let binary = dir.join("hangs");
+        fs::write(&binary, "#!/bin/sh\nexec sleep 60\n").expect("binary written");
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755))
+            .expect("binary made executable");
+
+        let start = Instant::now();
+        let err =
+            smoke_test(&binary, Duration::from_millis(200)).expect_err("smoke test timed out");
+        assert!(
+            err.to_string().contains("didn't exit within"),
+            "unexpected error: {}",
+            err
+        );
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "binary was killed rather than waited for"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The HTTP client used for downloads, set up the way Cargo's is.
+//!
+//! Settings are read from `config.toml` and `credentials.toml` in the Cargo home directory, and
+//! Cargo's environment variables take precedence over them:
+//!
+//! * `http.proxy` (`CARGO_HTTP_PROXY`) is used for every request if it's set. Otherwise, the
+//!   `https_proxy` and `http_proxy` environment variables are used, except for hosts in `no_proxy`.
+//! * `http.cainfo` (`CARGO_HTTP_CAINFO`) is a PEM bundle of additional certificate authorities to
+//!   trust, for registries behind a TLS-intercepting proxy.
+//! * `http.user-agent` (`CARGO_HTTP_USER_AGENT`) replaces the default user agent.
+//! * `registries.<name>.token` (`CARGO_REGISTRIES_<NAME>_TOKEN`) is sent as the `Authorization`
+//!   header with requests to the host of the registry's sparse index, so that crates can be
+//!   installed from private registries.
+
+use crate::import::cargo_home;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use once_cell::sync::OnceCell;
+use reqwest::{header::AUTHORIZATION, Certificate, Method, Proxy, RequestBuilder, Url};
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, fs, io};
+
+static CLIENT: OnceCell<HttpClient> = OnceCell::new();
+
+/// Returns the shared HTTP client, setting it up from Cargo's configuration the first time.
+pub(crate) fn client() -> Result<&'static HttpClient> {
+    CLIENT.get_or_try_init(|| {
+        HttpSettings::from_cargo_home(&cargo_home()?)?
+            .client()
+            .wrap_err("failed to set up the HTTP client from Cargo's configuration")
+    })
+}
+
+#[derive(Debug)]
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    /// Tokens for private registries, along with the URLs of their indexes.
+    tokens: Vec<(Url, String)>,
+}
+
+impl HttpClient {
+    /// Starts a GET request, sending a token if the URL is on the same host as the sparse index of
+    /// a private registry.
+    pub(crate) fn get(&self, url: &str) -> RequestBuilder {
+        let request = self.client.get(url);
+        match self.token_for(url) {
+            Some(token) => request.header(AUTHORIZATION, token),
+            None => request,
+        }
+    }
+
+    /// Starts a request without any registry token, for services with their own authentication.
+    pub(crate) fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    fn token_for(&self, url: &str) -> Option<&str> {
+        let url = Url::parse(url).ok()?;
+        self.tokens
+            .iter()
+            .find(|(index, _)| index.origin() == url.origin())
+            .map(|(_, token)| token.as_str())
+    }
+}
+
+/// The settings in Cargo's configuration that affect HTTP requests.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct HttpSettings {
+    proxy: Option<String>,
+    cainfo: Option<Utf8PathBuf>,
+    user_agent: Option<String>,
+    /// Tokens for private registries, keyed by the URL of their index.
+    tokens: BTreeMap<String, String>,
+}
+
+impl HttpSettings {
+    fn from_cargo_home(cargo_home: &Utf8Path) -> Result<Self> {
+        let config = CargoConfigFile::read(cargo_home, "config")?;
+        let credentials = CargoConfigFile::read(cargo_home, "credentials")?;
+        // As in Cargo, relative paths in the config file are relative to the directory the Cargo
+        // home directory is in.
+        let base_dir = cargo_home.parent().unwrap_or(cargo_home);
+        Ok(Self::new(&config, &credentials, base_dir, |name| {
+            env::var(name).ok()
+        }))
+    }
+
+    fn new(
+        config: &CargoConfigFile,
+        credentials: &CargoConfigFile,
+        base_dir: &Utf8Path,
+        env_var: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let mut tokens = BTreeMap::new();
+        let names = config
+            .registries
+            .keys()
+            .chain(credentials.registries.keys());
+        for name in names {
+            let env_prefix = format!(
+                "CARGO_REGISTRIES_{}_",
+                name.to_uppercase().replace('-', "_")
+            );
+            let index = env_var(&format!("{}INDEX", env_prefix)).or_else(|| {
+                config
+                    .registries
+                    .get(name)
+                    .and_then(|registry| registry.index.clone())
+            });
+            let token = env_var(&format!("{}TOKEN", env_prefix)).or_else(|| {
+                [credentials, config]
+                    .iter()
+                    .find_map(|file| file.registries.get(name)?.token.clone())
+            });
+            if let (Some(index), Some(token)) = (index, token) {
+                tokens.insert(index, token);
+            }
+        }
+
+        Self {
+            proxy: env_var("CARGO_HTTP_PROXY").or_else(|| config.http.proxy.clone()),
+            cainfo: env_var("CARGO_HTTP_CAINFO")
+                .map(Utf8PathBuf::from)
+                .or_else(|| config.http.cainfo.as_ref().map(|path| base_dir.join(path))),
+            user_agent: env_var("CARGO_HTTP_USER_AGENT").or_else(|| config.http.user_agent.clone()),
+            tokens,
+        }
+    }
+
+    fn client(&self) -> Result<HttpClient> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            // Like Cargo, accept proxies without a scheme.
+            let url = if proxy.contains("://") {
+                proxy.clone()
+            } else {
+                format!("http://{}", proxy)
+            };
+            let proxy = Proxy::all(&url)
+                .wrap_err_with(|| format!("invalid proxy {} in http.proxy", url))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(cainfo) = &self.cainfo {
+            let bundle = fs::read_to_string(cainfo)
+                .wrap_err_with(|| format!("failed to read CA bundle at {}", cainfo))?;
+            let certificates = pem_certificates(&bundle);
+            if certificates.is_empty() {
+                bail!(
+                    "no certificates found in {} (hint: http.cainfo should be a PEM bundle)",
+                    cainfo
+                );
+            }
+            for certificate in certificates {
+                let certificate = Certificate::from_pem(certificate.as_bytes())
+                    .wrap_err_with(|| format!("invalid certificate in {}", cainfo))?;
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        // Git indexes are often hosted alongside unrelated repositories, so tokens are only sent
+        // to the hosts of sparse indexes.
+        let mut tokens = vec![];
+        for (index, token) in &self.tokens {
+            let url = match index.strip_prefix("sparse+").map(Url::parse) {
+                Some(Ok(url)) => url,
+                _ => {
+                    tracing::debug!(
+                        target: "hasp::output::working::registry_token_skipped",
+                        %index,
+                        "Not sending a token to registry {index}, as it isn't a sparse index",
+                    );
+                    continue;
+                }
+            };
+            tokens.push((url, token.clone()));
+        }
+
+        Ok(HttpClient {
+            client: builder
+                .build()
+                .wrap_err("failed to build the HTTP client")?,
+            tokens,
+        })
+    }
+}
+
+/// Returns each certificate in a PEM bundle, including its delimiters.
+fn pem_certificates(bundle: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let mut certificates = vec![];
+    let mut rest = bundle;
+    while let Some(start) = rest.find(BEGIN) {
+        let end = match rest[start..].find(END) {
+            Some(end) => start + end + END.len(),
+            None => break,
+        };
+        certificates.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certificates
+}
+
+/// The parts of a Cargo config or credentials file that hasp uses.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CargoConfigFile {
+    #[serde(default)]
+    http: CargoHttpConfig,
+    #[serde(default)]
+    registries: BTreeMap<String, CargoRegistryConfig>,
+}
+
+impl CargoConfigFile {
+    /// Reads `<name>.toml`, or `<name>` as in older versions of Cargo, from the Cargo home
+    /// directory. Returns an empty file if neither exists.
+    fn read(cargo_home: &Utf8Path, name: &str) -> Result<Self> {
+        for path in [
+            cargo_home.join(format!("{}.toml", name)),
+            cargo_home.join(name),
+        ] {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).wrap_err_with(|| format!("failed to read {}", path)),
+            };
+            return toml::from_str(&contents).wrap_err_with(|| format!("failed to parse {}", path));
+        }
+        Ok(Self::default())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CargoHttpConfig {
+    proxy: Option<String>,
+    cainfo: Option<Utf8PathBuf>,
+    user_agent: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CargoRegistryConfig {
+    index: Option<String>,
+    token: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_from_cargo_config() {
+        let config: CargoConfigFile = toml::from_str(
+            r#"
+            [http]
+            proxy = "proxy.internal:3128"
+            cainfo = "certs/ca.pem"
+            check-revoke = false
+
+            [registries.my-registry]
+            index = "sparse+https://crates.internal/index/"
+
+            [registries.other]
+            index = "https://github.com/example/index"
+            "#,
+        )
+        .expect("config parsed");
+        let credentials: CargoConfigFile = toml::from_str(
+            r#"
+            [registries.my-registry]
+            token = "secret"
+            "#,
+        )
+        .expect("credentials parsed");
+
+        let settings =
+            HttpSettings::new(&config, &credentials, Utf8Path::new("/home/me"), |_| None);
+        assert_eq!(settings.proxy.as_deref(), Some("proxy.internal:3128"));
+        assert_eq!(
+            settings.cainfo.as_deref(),
+            Some(Utf8Path::new("/home/me/certs/ca.pem"))
+        );
+        assert_eq!(
+            settings.tokens,
+            [(
+                "sparse+https://crates.internal/index/".to_owned(),
+                "secret".to_owned()
+            )]
+            .into_iter()
+            .collect()
+        );
+
+        // Environment variables take precedence, and can supply tokens for registries without
+        // one in the credentials file.
+        let settings = HttpSettings::new(
+            &config,
+            &credentials,
+            Utf8Path::new("/home/me"),
+            |name| match name {
+                "CARGO_HTTP_PROXY" => Some("http://other:8080".to_owned()),
+                "CARGO_REGISTRIES_OTHER_TOKEN" => Some("other-secret".to_owned()),
+                _ => None,
+            },
+        );
+        assert_eq!(settings.proxy.as_deref(), Some("http://other:8080"));
+        assert_eq!(settings.tokens.len(), 2);
+
+        let client = HttpSettings {
+            cainfo: None,
+            ..settings
+        }
+        .client()
+        .expect("client built");
+        assert_eq!(
+            client.token_for("https://crates.internal/api/v1/crates/foo/1.0.0/download"),
+            Some("secret")
+        );
+        assert_eq!(client.token_for("https://crates.io/foo"), None);
+        assert_eq!(client.token_for("http://crates.internal/index/"), None);
+        assert_eq!(client.token_for("https://github.com/example/index"), None);
+
+        let bundle = "junk\n-----BEGIN CERTIFICATE-----\nAAA\n-----END CERTIFICATE-----\n\
+                      -----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----\n";
+        let certificates = pem_certificates(bundle);
+        assert_eq!(certificates.len(), 2);
+        assert!(certificates[1].contains("BBB"));
+    }
+}
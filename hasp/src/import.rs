@@ -0,0 +1,206 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Importing packages installed through `cargo install`.
+//!
+//! Cargo records installed packages in `$CARGO_HOME/.crates2.json`.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use hasp_metadata::CargoDirectory;
+use semver::Version;
+use serde::Deserialize;
+use std::{collections::BTreeMap, convert::TryInto, fs};
+
+/// The index URL for crates.io, as recorded by Cargo.
+static CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+
+/// A package installed through `cargo install`.
+#[derive(Clone, Debug)]
+pub(crate) struct CargoImport {
+    pub(crate) name: String,
+    pub(crate) version: Version,
+
+    /// The names of the binaries installed into `$CARGO_HOME/bin`.
+    pub(crate) binaries: Vec<String>,
+
+    /// The directory metadata for this package, or the reason it can't be imported.
+    pub(crate) directory: Result<CargoDirectory, &'static str>,
+}
+
+impl CargoImport {
+    /// Returns the paths to the binaries for this package.
+    pub(crate) fn binary_paths(&self, cargo_home: &Utf8Path) -> Vec<Utf8PathBuf> {
+        let bin_dir = cargo_home.join("bin");
+        self.binaries
+            .iter()
+            .map(|binary| bin_dir.join(binary))
+            .collect()
+    }
+}
+
+/// Returns the Cargo home directory, usually `~/.cargo`.
+pub(crate) fn cargo_home() -> Result<Utf8PathBuf> {
+    home::cargo_home()
+        .wrap_err("Cargo home directory could not be determined")?
+        .try_into()
+        .wrap_err("Cargo home directory is not valid UTF-8")
+}
+
+/// Reads the list of packages installed through `cargo install`.
+///
+/// Returns an empty list if nothing has been installed.
+pub(crate) fn read_cargo_installs(cargo_home: &Utf8Path) -> Result<Vec<CargoImport>> {
+    let path = cargo_home.join(".crates2.json");
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let contents =
+        fs::read_to_string(&path).wrap_err_with(|| format!("failed to read {}", path))?;
+    parse_cargo_installs(&contents).wrap_err_with(|| format!("failed to parse {}", path))
+}
+
+fn parse_cargo_installs(contents: &str) -> Result<Vec<CargoImport>> {
+    let crates2: Crates2 = serde_json::from_str(contents)?;
+    crates2
+        .installs
+        .into_iter()
+        .map(|(key, info)| {
+            // The key is of the form "name version (source)".
+            let mut parts = key.splitn(3, ' ');
+            let (name, version, source) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(version), Some(source)) => (name, version, source),
+                _ => return Err(eyre!("invalid package ID '{}'", key)),
+            };
+            let version = version
+                .parse::<Version>()
+                .wrap_err_with(|| format!("invalid version in package ID '{}'", key))?;
+            let source = source.trim_start_matches('(').trim_end_matches(')');
+
+            let directory = info.to_directory(source);
+            Ok(CargoImport {
+                name: name.to_owned(),
+                version,
+                binaries: info.bins,
+                directory,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct Crates2 {
+    installs: BTreeMap<String, Crates2Info>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Crates2Info {
+    #[serde(default)]
+    bins: Vec<String>,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    all_features: bool,
+    #[serde(default)]
+    no_default_features: bool,
+    #[serde(default)]
+    profile: Option<String>,
+}
+
+impl Crates2Info {
+    fn to_directory(&self, source: &str) -> Result<CargoDirectory, &'static str> {
+        let registry = match source.strip_prefix("registry+") {
+            Some(url) if url == CRATES_IO_INDEX => None,
+            Some(url) => Some(url.to_owned()),
+            None => return Err("only packages installed from registries can be imported"),
+        };
+        if self.all_features {
+            return Err("packages installed with --all-features can't be imported");
+        }
+
+        Ok(CargoDirectory {
+            default_features: !self.no_default_features,
+            features: self.features.iter().cloned().collect(),
+            profile: self.profile.clone().filter(|profile| profile != "release"),
+            registry,
+            // cargo install doesn't record whether binaries were selected, so install all of them.
+            bins: Default::default(),
+            examples: false,
+            env: Default::default(),
+            config: vec![],
+            args: vec![],
+            path: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cargo_installs_basic() {
+        let imports = parse_cargo_installs(
+            r#"{
+                "installs": {
+                    "cargo-hakari 0.9.6 (registry+https://github.com/rust-lang/crates.io-index)": {
+                        "version_req": null,
+                        "bins": ["cargo-hakari"],
+                        "features": [],
+                        "all_features": false,
+                        "no_default_features": false,
+                        "profile": "release",
+                        "target": "x86_64-unknown-linux-gnu",
+                        "rustc": "rustc 1.56.0"
+                    },
+                    "ripgrep 13.0.0 (registry+https://github.com/rust-lang/crates.io-index)": {
+                        "bins": ["rg"],
+                        "features": ["pcre2"],
+                        "no_default_features": true,
+                        "profile": "dev"
+                    },
+                    "tool 0.1.0 (git+https://github.com/example/tool#0123abcd)": {
+                        "bins": ["tool"]
+                    }
+                }
+            }"#,
+        )
+        .expect("installs parsed");
+
+        assert_eq!(imports.len(), 3);
+
+        let hakari = &imports[0];
+        assert_eq!(hakari.name, "cargo-hakari");
+        assert_eq!(hakari.version, Version::new(0, 9, 6));
+        assert_eq!(hakari.binaries, vec!["cargo-hakari".to_owned()]);
+        assert_eq!(
+            hakari.directory,
+            Ok(CargoDirectory {
+                default_features: true,
+                features: Default::default(),
+                profile: None,
+                registry: None,
+                bins: Default::default(),
+                examples: false,
+                env: Default::default(),
+                config: vec![],
+                args: vec![],
+                path: None,
+            })
+        );
+
+        let ripgrep = imports[1].directory.as_ref().expect("ripgrep importable");
+        assert!(!ripgrep.default_features);
+        assert_eq!(ripgrep.profile.as_deref(), Some("dev"));
+        assert!(ripgrep.features.contains("pcre2"));
+
+        assert!(
+            imports[2].directory.is_err(),
+            "git packages can't be imported"
+        );
+    }
+}
@@ -2,39 +2,182 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{
-    helpers::split_version,
-    ops::InstallStatus,
-    output::{NameVersionDisplay, OutputOpts},
-    state::HaspState,
+    audit::{audit_installed, AdvisoryDb, AuditSummary},
+    cancel::CANCELLED_EXIT_CODE,
+    config::{days_to_duration, package_key, split_package_key, HaspConfig},
+    database::{hint_if_corrupt, ConnectionCreator, MigrationState},
+    events::{EventLogger, RetentionPolicy},
+    failure::{is_failure_exit_code, FailureClass, ALREADY_INSTALLED_EXIT_CODE, FAILURE_EXIT_CODE},
+    helpers::{absolute, dir_size, merge_specs, PackageSpec},
+    hints::HintList,
+    home::HaspHome,
+    import::{cargo_home, read_cargo_installs},
+    manifest::{
+        find_project_manifest, Manifest, ManifestPlatform, PROJECT_MANIFEST_FILE_NAME,
+        PROJECT_ROOT_DIR_NAME,
+    },
+    network::{NetworkOpts, RetryPolicy},
+    ops::{
+        default_binary_name, find_lock_holders, init_registry, latest_crate_version,
+        local_packages, local_version, parse_crate_file_name, registry, Backend, BackendRegistry,
+        BuildCache, BuildCacheMode, CargoBuildOpts, CargoFileFetcher, CargoPathFetcher,
+        ContentStore, FsckSummary, InstallStatus, LockWait, Utf8TempDir,
+    },
+    output::{otlp, progress, Color, MessageFormat, NameVersionDisplay, OutputOpts, PackageList},
+    platform::{self_test, sh_quote, PlatformInfo},
+    print::PrintKey,
+    process::{latest_build_log, ProcessFailed},
+    provenance::{Provenance, Requester},
+    report::{OutcomeCounts, RunReport},
+    run::{find_binary, PackageFilter},
+    self_update::SELF_CRATE,
+    state::{HaspState, InstallRequest, PackageMetadata},
+    stats::{install_stats, summary_lines},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report, Result,
 };
-use color_eyre::Result;
 use futures::prelude::*;
-use hasp_metadata::CargoDirectory;
+use hasp_metadata::{
+    is_redacted_env_value, CargoDirectory, Event, EventEnvelope, NpmDirectory, OciDirectory,
+    PackageInfoOutput, PackageListOutput, PackageOutcome, ResolveOutput,
+};
+use semver::VersionReq;
+use std::{collections::BTreeMap, env, ffi::OsString, fs, io, time::Duration};
 use structopt::StructOpt;
 
+mod audit;
+mod cancel;
 mod cargo_cli;
+mod config;
 mod database;
 mod events;
+mod failure;
 mod helpers;
+mod hints;
 mod home;
+mod hooks;
+mod http_client;
+mod import;
+mod lockfile;
+mod manifest;
 mod models;
+mod network;
+mod npm_cli;
+mod oci_cli;
 mod ops;
 mod output;
+mod platform;
+mod print;
+mod process;
+mod provenance;
+mod receipt;
+mod report;
+mod run;
+mod self_update;
+mod shims;
 mod state;
+mod stats;
+#[cfg(test)]
+mod test_helpers;
+mod trust;
+mod tui;
+
+/// Returns a channel that receives structured progress events for packages installed from now on,
+/// for frontends like TUIs and editor integrations that render progress themselves.
+pub fn progress_events() -> std::sync::mpsc::Receiver<hasp_metadata::ProgressEvent> {
+    output::progress::subscribe()
+}
+
+/// Returns a stream of typed install and uninstall events from now on, sent as they're recorded
+/// in the events journal, so that frontends can react to them without polling the database.
+pub fn lifecycle_events() -> impl futures::Stream<Item = hasp_metadata::LifecycleEvent> {
+    events::subscribe_lifecycle()
+}
 
 #[derive(Debug, StructOpt)]
 pub struct App {
     #[structopt(flatten)]
     global_opts: GlobalOpts,
 
+    /// Print a single value for use in scripts, e.g. `home`, `bin-dir` or `version-of:ripgrep`
+    /// (run `hasp --print keys` for a list)
+    #[structopt(long, value_name = "KEY")]
+    print: Option<PrintKey>,
+
     #[structopt(subcommand)]
-    command: Command,
+    command: Option<Command>,
 }
 
 impl App {
-    pub async fn exec(self) -> Result<i32> {
-        self.global_opts.output.init_logger();
-        self.command.exec(&self.global_opts).await
+    pub async fn exec(mut self) -> Result<i32> {
+        let mut home = HaspHome::discover()?;
+        self.global_opts.override_home(&mut home);
+
+        // Command-line flags take precedence over the config file.
+        let ci = self.global_opts.ci;
+        let output = &mut self.global_opts.output;
+        output.color = output
+            .color
+            .or(home.config().output.color)
+            .or_else(|| ci.then_some(Color::Never));
+        if ci {
+            output.message_format = output.message_format.or(Some(MessageFormat::Json));
+        }
+        output.init_logger(home.config().telemetry.otlp_settings());
+        cancel::listen();
+        init_registry(
+            BackendRegistry::with_plugins(&home.config().plugins)
+                .wrap_err("failed to register plugins from the config file")?,
+        );
+
+        let command = match (&self.print, self.command) {
+            (Some(key), None) => {
+                println!("{}", key.value(home)?);
+                return Ok(0);
+            }
+            (Some(_), Some(_)) => bail!("--print can't be combined with a command"),
+            (None, Some(command)) => command,
+            (None, None) => bail!("no command given (hint: run `hasp --help` for a list)"),
+        };
+
+        // env::args() panics on arguments that aren't valid Unicode, which paths may not be.
+        let mut report = RunReport::new(
+            env::args_os()
+                .skip(1)
+                .map(|arg| arg.to_string_lossy().into_owned()),
+        );
+        if command.installs_packages() {
+            events::start_run(report.args.clone());
+        }
+        let result = command
+            .exec(home, &self.global_opts, &mut report)
+            .await
+            .map_err(hint_if_corrupt);
+        report.finish(&result);
+        events::finish_run(&report);
+        // Export telemetry before the report is written, so that failing to write it doesn't skip
+        // this.
+        otlp::flush().await;
+        if let Some(path) = self.global_opts.report_path() {
+            if let Err(err) = report.write(&path) {
+                // Don't hide the error hasp is exiting with.
+                match result {
+                    Ok(_) => return Err(err),
+                    Err(_) => {
+                        let error = format!("{:#}", err);
+                        tracing::error!(
+                            target: "hasp::output::report_failed",
+                            %error,
+                            "Failed to write report: {error}",
+                        )
+                    }
+                }
+            }
+        }
+        result
     }
 }
 
@@ -44,110 +187,2836 @@ struct GlobalOpts {
     frozen: bool,
     #[structopt(long, global = true)]
     locked: bool,
+    /// Only install Cargo packages from the mirror in install.mirror, without accessing the network
     #[structopt(long, global = true)]
     offline: bool,
+    /// Update package indexes even if they were updated recently
+    #[structopt(long, global = true)]
+    refresh: bool,
+    /// Wait for other processes to release install locks for as long as it takes
+    #[structopt(long, global = true, conflicts_with = "no-wait")]
+    wait: bool,
+    /// Fail immediately if another process holds an install lock
+    #[structopt(long, global = true)]
+    no_wait: bool,
+    /// Run non-interactively with settings suited to CI: JSON output without color, bounded waits
+    /// for locks and network operations, failures instead of prompts, and a report written to
+    /// hasp-report.json
+    #[structopt(long, global = true)]
+    ci: bool,
+    /// Exit with 1 if any package was already installed and none failed, rather than 0
+    #[structopt(long, global = true)]
+    strict: bool,
+    /// Write a JSON report of the results to this path when hasp finishes
+    #[structopt(long, global = true)]
+    report: Option<Utf8PathBuf>,
     #[structopt(flatten)]
     output: OutputOpts,
+    #[structopt(flatten)]
+    network: NetworkOpts,
+}
+
+impl GlobalOpts {
+    /// How long to wait for install locks in CI mode, unless set in the config.
+    const CI_LOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+    /// The time limit across all attempts at a network operation in CI mode, unless set in the
+    /// config.
+    const CI_NETWORK_DEADLINE: Duration = Duration::from_secs(10 * 60);
+
+    /// The path the report is written to in CI mode, unless overridden with `--report`.
+    const CI_REPORT_PATH: &'static str = "hasp-report.json";
+
+    fn retry_policy(&self, config: &HaspConfig) -> RetryPolicy {
+        let mut policy = self.network.retry_policy(&config.network);
+        if self.ci && policy.deadline.is_none() {
+            policy.deadline = Some(Self::CI_NETWORK_DEADLINE);
+        }
+        policy
+    }
+
+    /// Returns how long to wait for install locks, if overridden on the command line.
+    fn lock_wait(&self, config: &HaspConfig) -> Option<LockWait> {
+        if self.wait {
+            Some(LockWait::Forever)
+        } else if self.no_wait {
+            Some(LockWait::Timeout(Duration::ZERO))
+        } else if self.ci && config.install.lock_timeout.is_none() {
+            Some(LockWait::Timeout(Self::CI_LOCK_TIMEOUT))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the hasp home a command operates on: `root` if it was passed with `--root`, or the
+    /// discovered home otherwise.
+    fn root_home(&self, home: HaspHome, root: Option<Utf8PathBuf>) -> Result<HaspHome> {
+        let root = match root {
+            Some(root) => absolute(&root)?,
+            None => return Ok(home),
+        };
+        let mut home = HaspHome::new(root)?;
+        self.override_home(&mut home);
+        Ok(home)
+    }
+
+    /// Applies the options that override settings of the home, which take precedence over its
+    /// config file.
+    fn override_home(&self, home: &mut HaspHome) {
+        if let Some(lock_wait) = self.lock_wait(home.config()) {
+            home.set_lock_wait(lock_wait);
+        }
+        if self.ci {
+            home.set_interactive(false);
+        }
+    }
+
+    /// Returns the path to write a report to, if any.
+    fn report_path(&self) -> Option<Utf8PathBuf> {
+        self.report
+            .clone()
+            .or_else(|| self.ci.then(|| Self::CI_REPORT_PATH.into()))
+    }
 }
 
 #[derive(Debug, StructOpt)]
 enum Command {
     Install {
-        #[structopt(visible_alias = "crate", required = true, min_values = 1)]
-        crates: Vec<String>,
+        /// Packages to install, optionally prefixed with a namespace (e.g. `npm:prettier@2` or
+        /// `oci:hadolint/hadolint:v2.8.0`)
+        #[structopt(
+            visible_alias = "crate",
+            required_unless_one = &["crate-file", "path"],
+            min_values = 1
+        )]
+        crates: Vec<PackageSpec>,
+
+        /// Install a crate from a .crate file named like ripgrep-13.0.0.crate, rather than
+        /// fetching it from a registry, e.g. on a machine without network access
+        #[structopt(long, value_name = "PATH", conflicts_with = "crates")]
+        crate_file: Option<Utf8PathBuf>,
+
+        /// Build and install the package in this local directory, rather than fetching it
+        #[structopt(long, value_name = "DIR", conflicts_with_all = &["crates", "crate-file"])]
+        path: Option<Utf8PathBuf>,
+
+        /// With --path, install every member of the workspace that has binaries, each as its own
+        /// package, all recorded with the same version from `git describe` (or the current time
+        /// outside a git repository)
+        #[structopt(long, requires = "path")]
+        workspace: bool,
+
+        /// Continue to install packages on encountering a failure
+        #[structopt(long)]
+        keep_going: bool,
+
+        /// Re-resolve floating versions like image tags, even if the package is installed
+        #[structopt(long)]
+        update: bool,
+
+        /// Replace binaries that other installed packages have, rather than failing
+        #[structopt(long)]
+        force_bin_overwrite: bool,
+
+        /// Attempt installs again up to this many times if they fail for a reason that might not
+        /// happen again, like a network failure or the build being killed (compile errors aren't
+        /// retried)
+        #[structopt(long, value_name = "N", conflicts_with_all = &["crate-file", "path"])]
+        retries: Option<u32>,
+
+        /// Operate on this hasp home rather than HASP_HOME or ~/.hasp, e.g. to keep
+        /// project-local tools
+        #[structopt(long, value_name = "DIR")]
+        root: Option<Utf8PathBuf>,
+
+        #[structopt(flatten)]
+        cargo_opts: CargoInstallOpts,
+        // TODO: git etc
+    },
+
+    /// Print a manifest of installed packages to standard output, for use with `hasp sync`
+    Export {
+        /// Operate on this hasp home rather than HASP_HOME or ~/.hasp, e.g. to keep
+        /// project-local tools
+        #[structopt(long, value_name = "DIR")]
+        root: Option<Utf8PathBuf>,
+    },
+
+    /// Install the packages listed in a manifest, as written by `hasp export`
+    Sync {
+        /// Path to the manifest
+        #[structopt(default_value = "hasp.toml")]
+        manifest: Utf8PathBuf,
+
+        /// Uninstall packages that aren't listed in the manifest
+        #[structopt(long)]
+        prune: bool,
 
         /// Continue to install packages on encountering a failure
         #[structopt(long)]
         keep_going: bool,
-        // TODO: git, registry etc
-        // TODO: version req
-        // TODO: features/all-features/no-default-features
-        // TODO: profile
+
+        /// Replace binaries that other installed packages have, rather than failing
+        #[structopt(long)]
+        force_bin_overwrite: bool,
+
+        /// Operate on this hasp home rather than HASP_HOME or ~/.hasp, e.g. to keep
+        /// project-local tools
+        #[structopt(long, value_name = "DIR")]
+        root: Option<Utf8PathBuf>,
+    },
+
+    /// Install the tools a project declares, in `tools.toml` or in the `[workspace.metadata.tools]`
+    /// table of `Cargo.toml`
+    ImportCargoLock {
+        /// Path to the project
+        #[structopt(long, default_value = ".")]
+        path: Utf8PathBuf,
+
+        /// Continue to install tools on encountering a failure
+        #[structopt(long)]
+        keep_going: bool,
+    },
+
+    /// Install the tools declared in a project's `.hasp.toml` into a root next to it, and print
+    /// shell commands that put them on PATH, e.g. for `eval "$(hasp env)"`
+    Env {
+        /// Directory to search for `.hasp.toml`, along with its parents
+        #[structopt(long, default_value = ".")]
+        path: Utf8PathBuf,
+
+        /// Continue to install tools on encountering a failure
+        #[structopt(long)]
+        keep_going: bool,
+    },
+
+    /// Restore the version of a package that was installed before the current one
+    Rollback {
+        /// The package, as `name` for Cargo packages or `namespace:name` otherwise
+        package: String,
+    },
+
+    /// Hold a package at its installed version, so that `hasp sync` and `hasp install --update`
+    /// skip it
+    Hold {
+        /// The package, as `name` for Cargo packages or `namespace:name` otherwise
+        package: String,
+    },
+
+    /// Release a package held with `hasp hold`
+    Unhold {
+        /// The package, as `name` for Cargo packages or `namespace:name` otherwise
+        package: String,
+    },
+
+    /// Print the most recent build log for a package
+    Logs {
+        /// The package, as `name` for Cargo packages or `namespace:name` otherwise
+        package: String,
+    },
+
+    /// Print the version each package would be installed at, without installing anything
+    Resolve {
+        /// Packages to resolve, as for `hasp install`
+        #[structopt(required = true, min_values = 1)]
+        specs: Vec<PackageSpec>,
+
+        /// Print a table of versions, or a JSON object for use in scripts
+        #[structopt(long, possible_values = &["human", "json"])]
+        format: Option<MessageFormat>,
+
+        #[structopt(flatten)]
+        cargo_opts: CargoInstallOpts,
+    },
+
+    /// List installed packages
+    List {
+        /// Print a table of packages, or a JSON object for use in scripts
+        #[structopt(long, possible_values = &["human", "json"])]
+        format: Option<MessageFormat>,
+
+        /// Also list directories that aren't installed, e.g. because installing them failed, with
+        /// the reason for the last failure
+        #[structopt(long)]
+        all: bool,
+
+        /// Operate on this hasp home rather than HASP_HOME or ~/.hasp, e.g. to keep
+        /// project-local tools
+        #[structopt(long, value_name = "DIR")]
+        root: Option<Utf8PathBuf>,
+    },
+
+    /// Print information about an installed package
+    Info {
+        /// The package, as `name` for Cargo packages or `namespace:name` otherwise
+        package: String,
+
+        /// Also print the dependencies the package was built with
+        #[structopt(long)]
+        deps: bool,
+
+        /// Print information as human-readable lines, or as a JSON object for use in scripts
+        #[structopt(long, possible_values = &["human", "json"])]
+        format: Option<MessageFormat>,
+    },
+
+    /// Print how long installs took and how often builds were reused, from the events journal
+    Stats {
+        /// Print this many of the slowest installs
+        #[structopt(long, default_value = "5")]
+        top: usize,
+
+        /// Print a summary for people to read, or a JSON object for use in scripts
+        #[structopt(long, possible_values = &["human", "json"])]
+        format: Option<MessageFormat>,
+    },
+
+    /// Run an installed binary, without needing the bin directory on PATH
+    Run {
+        /// The binary to run
+        binary: String,
+
+        /// The package providing the binary, as `[namespace:]name[@version]`, if several do
+        #[structopt(long)]
+        package: Option<PackageFilter>,
+
+        /// Arguments to pass to the binary
+        #[structopt(last = true, parse(from_os_str))]
+        args: Vec<OsString>,
+    },
+
+    /// Start a shell with installed binaries on PATH, without changing which versions shims run
+    Shell {
+        /// Packages whose binaries to put first on PATH, as `[namespace:]name[@version]`; the whole
+        /// bin directory is used if none are given
+        packages: Vec<PackageFilter>,
+    },
+
+    /// Explain why a package is installed: who or what requested it, and when
+    Why {
+        /// The package, as `name` for Cargo packages or `namespace:name` otherwise
+        package: String,
+    },
+
+    /// Inspect and maintain hasp's databases
+    Db {
+        #[structopt(subcommand)]
+        command: DbCommand,
+    },
+
+    /// Check installed packages and the dependencies they were built with against the RustSec
+    /// advisory database, and check for installed versions that have been yanked
+    Audit,
+
+    /// Inspect and clear the build cache
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand,
+    },
+
+    /// Remove files from the content store that no installs use any more
+    Gc {
+        /// Print what would be removed without removing anything
+        #[structopt(long)]
+        dry_run: bool,
+
+        /// Also remove the records of directories that aren't installed, e.g. because installing
+        /// them failed, other than previous installs kept to roll back to
+        #[structopt(long)]
+        directories: bool,
+    },
+
+    /// Remove the cached downloads, build directories and previous installs kept for a package,
+    /// leaving its current install alone
+    Clean {
+        /// The package, as `name` for Cargo packages or `namespace:name` otherwise
+        #[structopt(long)]
+        package: String,
+
+        /// Print what would be removed without removing anything
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Manage hints about deprecated and superseded packages
+    Hints {
+        #[structopt(subcommand)]
+        command: HintsCommand,
+    },
+
+    /// Manage the sources packages may be fetched from, if approval of new sources is required
+    Trust {
+        #[structopt(subcommand)]
+        command: TrustCommand,
+    },
+
+    /// Maintain the journal of events
+    Events {
+        #[structopt(subcommand)]
+        command: EventsCommand,
+    },
+
+    /// Manage hasp itself
+    #[structopt(name = "self")]
+    Itself {
+        #[structopt(subcommand)]
+        command: SelfCommand,
+    },
+
+    /// Check that hasp is set up correctly
+    Doctor {
+        /// Also check that platform-specific behavior like file locking and shims works on this
+        /// system
+        #[structopt(long)]
+        platform: bool,
+    },
+
+    /// Import packages installed through `cargo install`
+    Import {
+        /// Re-install packages through hasp, rather than adopting existing binaries
+        #[structopt(long)]
+        reinstall: bool,
+
+        /// Continue to import packages on encountering a failure
+        #[structopt(long)]
+        keep_going: bool,
     },
+
+    /// Show a dashboard of installed packages and recent events, and run commands from it
+    Tui,
+}
+
+/// Options for installing packages through Cargo. Defaults are read from the config file.
+#[derive(Debug, StructOpt)]
+struct CargoInstallOpts {
+    /// Comma-separated list of features to activate
+    #[structopt(long, use_delimiter = true)]
+    features: Vec<String>,
+
+    /// Do not activate the `default` feature
+    #[structopt(long)]
+    no_default_features: bool,
+
+    /// Install only the specified binary (may be repeated)
+    #[structopt(long, number_of_values = 1)]
+    bin: Vec<String>,
+
+    /// Install all binaries (the default)
+    #[structopt(long, conflicts_with = "bin")]
+    bins: bool,
+
+    /// Install all examples, as well as binaries
+    #[structopt(long)]
+    examples: bool,
+
+    /// Build with the given profile [default: release]
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// Number of parallel jobs to build with
+    #[structopt(long, short = "j")]
+    jobs: Option<usize>,
+
+    /// Registry index URL to install crates from [default: crates.io]
+    #[structopt(long)]
+    registry: Option<String>,
+
+    /// Install a yanked version if it's the only one that matches
+    #[structopt(long)]
+    allow_yanked: bool,
+
+    /// Resolve versions without checking their rust-version against rustc, even if
+    /// install.respect-rust-version is set in the config file
+    #[structopt(long)]
+    ignore_rust_version: bool,
+
+    /// Copy packages that were already built elsewhere from the shared store or remote cache
+    /// before building them from source [default]
+    #[structopt(long, overrides_with = "no-prefer-prebuilt")]
+    prefer_prebuilt: bool,
+
+    /// Always build from source, even if install.prefer-prebuilt is set in the config file
+    #[structopt(long, overrides_with = "prefer-prebuilt")]
+    no_prefer_prebuilt: bool,
+
+    /// Install npm packages without running their install scripts
+    #[structopt(long)]
+    ignore_scripts: bool,
+
+    /// Set an environment variable for builds, as NAME=VALUE (may be repeated)
+    #[structopt(
+        long,
+        value_name = "NAME=VALUE",
+        number_of_values = 1,
+        parse(try_from_str = parse_env_var)
+    )]
+    env: Vec<(String, String)>,
+
+    /// Pass a configuration value to Cargo, as with `cargo --config` (may be repeated)
+    #[structopt(long, value_name = "KEY=VALUE", number_of_values = 1)]
+    cargo_config: Vec<String>,
+
+    /// Pass an argument through to `cargo build`, e.g. `--cargo-arg=--locked` (may be repeated)
+    #[structopt(
+        long,
+        visible_alias = "build-arg",
+        value_name = "ARG",
+        number_of_values = 1,
+        allow_hyphen_values = true,
+        parse(try_from_str = parse_cargo_arg)
+    )]
+    cargo_arg: Vec<String>,
+}
+
+impl CargoInstallOpts {
+    fn directory(&self, config: &HaspConfig, name: &str) -> CargoDirectory {
+        let package_config = config.package("cargo", name);
+
+        let features = if self.features.is_empty() {
+            package_config
+                .and_then(|package_config| package_config.features.clone())
+                .unwrap_or_default()
+        } else {
+            self.features.clone()
+        };
+        let default_features = !self.no_default_features
+            && package_config
+                .and_then(|package_config| package_config.default_features)
+                .unwrap_or(true);
+
+        CargoDirectory {
+            default_features,
+            features: features.into_iter().collect(),
+            profile: self
+                .profile
+                .clone()
+                .or_else(|| config.install.profile.clone()),
+            registry: self
+                .registry
+                .clone()
+                .or_else(|| config.install.registry.clone()),
+            // --bins selects all binaries, which is the same as not selecting any.
+            bins: if self.bins {
+                Default::default()
+            } else {
+                self.bin.iter().cloned().collect()
+            },
+            examples: self.examples,
+            env: config.install.artifact_env(&self.cli_env()),
+            // Later values take precedence, so values from the command line go last.
+            config: config
+                .install
+                .cargo_config
+                .iter()
+                .chain(&self.cargo_config)
+                .cloned()
+                .collect(),
+            args: self.cargo_arg.clone(),
+            path: None,
+        }
+    }
+
+    fn cli_env(&self) -> BTreeMap<String, String> {
+        self.env.iter().cloned().collect()
+    }
+
+    fn build_opts(
+        &self,
+        config: &HaspConfig,
+        cache_dir: &Utf8Path,
+        network: RetryPolicy,
+        refresh: bool,
+        offline: bool,
+    ) -> CargoBuildOpts {
+        let prefer_prebuilt = if self.prefer_prebuilt {
+            true
+        } else if self.no_prefer_prebuilt {
+            false
+        } else {
+            config.install.prefer_prebuilt()
+        };
+
+        CargoBuildOpts {
+            jobs: self.jobs.or(config.install.jobs),
+            prefer_prebuilt,
+            index_protocol: config.install.index_protocol.unwrap_or_default(),
+            index_ttl: config.install.index_ttl(refresh),
+            network,
+            build_env: config.install.build_env(&self.cli_env()),
+            cargo: config.install.cargo.clone(),
+            build_cache: config.install.build_cache(cache_dir),
+            allow_yanked: self.allow_yanked || config.install.allow_yanked,
+            respect_rust_version: config.install.respect_rust_version && !self.ignore_rust_version,
+            mirror: config.install.mirror.clone(),
+            offline,
+        }
+    }
 }
 
 impl Command {
-    async fn exec(self, global_opts: &GlobalOpts) -> Result<i32> {
+    /// Returns true for commands that install packages, whose runs are recorded in the journal
+    /// as `run_started` and `run_finished` events.
+    fn installs_packages(&self) -> bool {
+        matches!(
+            self,
+            Command::Install { .. }
+                | Command::Sync { .. }
+                | Command::ImportCargoLock { .. }
+                | Command::Env { .. }
+                | Command::Import { .. }
+                | Command::Itself { .. }
+                | Command::Tui
+        )
+    }
+
+    async fn exec(
+        self,
+        home: HaspHome,
+        global_opts: &GlobalOpts,
+        report: &mut RunReport,
+    ) -> Result<i32> {
         match self {
-            Command::Install { crates, keep_going } => {
-                let state = HaspState::load_or_init()?;
+            Command::Install {
+                crates,
+                crate_file,
+                path,
+                workspace,
+                keep_going,
+                update,
+                force_bin_overwrite,
+                retries,
+                root,
+                cargo_opts,
+            } => {
+                let state = HaspState::load_or_init(global_opts.root_home(home, root)?)?;
+                let config = state.home().config();
+
+                if let Some(path) = path {
+                    let path = absolute(&path)?;
+                    let build_opts = cargo_opts.build_opts(
+                        config,
+                        state.home().cache_dir(),
+                        global_opts.retry_policy(config),
+                        global_opts.refresh,
+                        global_opts.offline,
+                    );
+                    let packages = local_packages(
+                        &path,
+                        workspace,
+                        build_opts.cargo.as_deref(),
+                        global_opts.output,
+                    )?;
+                    let version = local_version(&path);
+                    let provenance = Provenance::command_line(&report.args);
+                    // Members of a workspace share its target directory, so they're built one at a
+                    // time, stopping at the first failure unless --keep-going is passed.
+                    let mut statuses = vec![];
+                    for package in packages {
+                        let name = package.name.clone();
+                        let fetcher = CargoPathFetcher::new(
+                            package,
+                            version.clone(),
+                            cargo_opts.directory(config, &name),
+                            build_opts.clone(),
+                            global_opts.output,
+                        );
+                        let status = state
+                            .cargo_install_path(
+                                fetcher,
+                                force_bin_overwrite,
+                                &provenance,
+                                global_opts.output,
+                            )
+                            .await;
+                        let failed = matches!(status, Err(_) | Ok(InstallStatus::Failure { .. }));
+                        statuses.push((name, status));
+                        if failed && !keep_going || cancel::is_cancelled() {
+                            break;
+                        }
+                    }
+                    return Ok(finish_installs(
+                        statuses,
+                        keep_going,
+                        global_opts.strict,
+                        global_opts.output.quiet,
+                        report,
+                    )
+                    .await);
+                }
 
-                let mut install_futures = Vec::with_capacity(crates.len());
-                for spec in crates {
-                    let (name, version_req) = split_version(&spec)?;
-                    let install_fut = state.cargo_install(
+                if let Some(crate_file) = crate_file {
+                    let (name, version) = parse_crate_file_name(&crate_file)?;
+                    let fetcher = CargoFileFetcher::new(
                         name.clone(),
-                        version_req.into(),
-                        CargoDirectory {
-                            default_features: true,
-                        },
+                        version,
+                        absolute(&crate_file)?,
+                        cargo_opts.directory(config, &name),
+                        cargo_opts.build_opts(
+                            config,
+                            state.home().cache_dir(),
+                            global_opts.retry_policy(config),
+                            global_opts.refresh,
+                            global_opts.offline,
+                        ),
                         global_opts.output,
                     );
-                    install_futures.push(
-                        install_fut.map(move |status| status.map(move |status| (name, status))),
-                    );
+                    let status = state
+                        .cargo_install_file(
+                            fetcher,
+                            force_bin_overwrite,
+                            &Provenance::command_line(&report.args),
+                            global_opts.output,
+                        )
+                        .await;
+                    return Ok(finish_installs(
+                        vec![(name, status)],
+                        keep_going,
+                        global_opts.strict,
+                        global_opts.output.quiet,
+                        report,
+                    )
+                    .await);
                 }
 
-                let mut already_installed = vec![];
-                let mut any_failed = false;
+                let requests = merge_specs(crates)?
+                    .into_iter()
+                    .map(|spec| {
+                        let request = parse_install_request(spec, &state, &cargo_opts)?;
+                        Ok(InstallRequest {
+                            update,
+                            force_bin_overwrite,
+                            retries: retries.unwrap_or_default(),
+                            ..request
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let requests = if update {
+                    skip_held(&state, requests, report)?
+                } else {
+                    requests
+                };
+                let statuses = install_all(
+                    &state,
+                    requests,
+                    &Provenance::command_line(&report.args),
+                    cargo_opts.build_opts(
+                        config,
+                        state.home().cache_dir(),
+                        global_opts.retry_policy(config),
+                        global_opts.refresh,
+                        global_opts.offline,
+                    ),
+                    global_opts.output,
+                )
+                .await;
+                Ok(finish_installs(
+                    statuses,
+                    keep_going,
+                    global_opts.strict,
+                    global_opts.output.quiet,
+                    report,
+                )
+                .await)
+            }
+            Command::Import {
+                reinstall,
+                keep_going,
+            } => {
+                let state = HaspState::load_or_init(home)?;
+                let config = state.home().config();
+                let cargo_home = cargo_home()?;
+                let provenance = Provenance::cargo_import(&cargo_home);
 
-                for (name, status) in futures::future::try_join_all(install_futures).await? {
-                    match status {
-                        InstallStatus::Success { version, binaries } => {
-                            let binaries_str = binaries.join(", ");
-                            tracing::info!(
-                                target: "hasp::output::install_success",
-                                "Success {} installed with binaries {}",
-                                NameVersionDisplay::dir_version(&name, &version),
-                                binaries_str,
+                let mut import_futures = vec![];
+                for import in read_cargo_installs(&cargo_home)? {
+                    let directory = match &import.directory {
+                        Ok(directory) => directory.clone(),
+                        Err(reason) => {
+                            let package = NameVersionDisplay::semver(&import.name, &import.version);
+                            tracing::warn!(
+                                target: "hasp::output::import_skipped",
+                                %package,
+                                %reason,
+                                "Skipping {package}: {reason}",
                             );
+                            continue;
                         }
-                        InstallStatus::Failure { version, report } => {
-                            tracing::error!(
-                                target: "hasp::output::install_failed",
-                                "Failed to install {}: {:#}",
-                                NameVersionDisplay::dir_version(&name, &version), report,
-                            );
-                            any_failed = true;
-                            if !keep_going {
-                                return Ok(2);
+                    };
+
+                    let name = import.name.clone();
+                    let import_fut = if reinstall {
+                        let req = VersionReq::parse(&format!("={}", import.version))?;
+                        let request = InstallRequest {
+                            name: import.name,
+                            req: req.into(),
+                            metadata: PackageMetadata::Cargo(directory),
+                            checksum: None,
+                            update: false,
+                            explicit: true,
+                            force_bin_overwrite: false,
+                            retries: 0,
+                        };
+                        state
+                            .install_package(
+                                request,
+                                &provenance,
+                                config.install.build_opts(
+                                    state.home().cache_dir(),
+                                    global_opts.retry_policy(config),
+                                    global_opts.refresh,
+                                    global_opts.offline,
+                                ),
+                                global_opts.output,
+                            )
+                            .boxed_local()
+                    } else {
+                        let binary_paths = import.binary_paths(&cargo_home);
+                        state
+                            .cargo_adopt(
+                                import.name,
+                                import.version,
+                                directory,
+                                binary_paths,
+                                &provenance,
+                                global_opts.output,
+                            )
+                            .boxed_local()
+                    };
+                    import_futures.push(import_fut.map(move |status| (name, status)));
+                }
+
+                if import_futures.is_empty() {
+                    tracing::info!(
+                        target: "hasp::output::informational::nothing_to_import",
+                        %cargo_home,
+                        "Info no packages to import from {cargo_home}",
+                    );
+                    return Ok(0);
+                }
+
+                let statuses = futures::future::join_all(import_futures).await;
+                Ok(finish_installs(
+                    statuses,
+                    keep_going,
+                    global_opts.strict,
+                    global_opts.output.quiet,
+                    report,
+                )
+                .await)
+            }
+            Command::ImportCargoLock { path, keep_going } => {
+                let state = HaspState::load_or_init(home)?;
+                let config = state.home().config();
+                let manifest = Manifest::from_project(&path)?;
+                let requests =
+                    manifest.install_requests(&ManifestPlatform::current(global_opts.ci))?;
+                report_skipped(&requests.skipped, report);
+
+                let statuses = install_all(
+                    &state,
+                    requests.requests,
+                    &Provenance::project(&path)?,
+                    config.install.build_opts(
+                        state.home().cache_dir(),
+                        global_opts.retry_policy(config),
+                        global_opts.refresh,
+                        global_opts.offline,
+                    ),
+                    global_opts.output,
+                )
+                .await;
+                Ok(finish_installs(
+                    statuses,
+                    keep_going,
+                    global_opts.strict,
+                    global_opts.output.quiet,
+                    report,
+                )
+                .await)
+            }
+            Command::Env { path, keep_going } => {
+                let manifest_path = match find_project_manifest(&absolute(&path)?) {
+                    Some(manifest_path) => manifest_path,
+                    None => bail!(
+                        "no {} found in {} or its parents (hint: list the tools the project needs \
+                         in a [tools] table there)",
+                        PROJECT_MANIFEST_FILE_NAME,
+                        path,
+                    ),
+                };
+                let project_dir = manifest_path
+                    .parent()
+                    .expect("manifest path is in a directory");
+                let root = project_dir.join(PROJECT_ROOT_DIR_NAME);
+                let state = HaspState::load_or_init(global_opts.root_home(home, Some(root))?)?;
+                let config = state.home().config();
+                let manifest = Manifest::from_tools_path(&manifest_path)?;
+                let requests =
+                    manifest.install_requests(&ManifestPlatform::current(global_opts.ci))?;
+                report_skipped(&requests.skipped, report);
+
+                let statuses = install_all(
+                    &state,
+                    requests.requests,
+                    &Provenance::project(project_dir)?,
+                    config.install.build_opts(
+                        state.home().cache_dir(),
+                        global_opts.retry_policy(config),
+                        global_opts.refresh,
+                        global_opts.offline,
+                    ),
+                    global_opts.output,
+                )
+                .await;
+                // Standard output is evaluated by the shell, so it only gets the exports.
+                let exit_code =
+                    finish_installs(statuses, keep_going, global_opts.strict, false, report).await;
+                // Tools that did install are still usable if others failed.
+                if exit_code != CANCELLED_EXIT_CODE {
+                    print!("{}", shell_exports(state.home().bin_dir()));
+                }
+                Ok(exit_code)
+            }
+            Command::Rollback { package } => {
+                let state = HaspState::load_or_init(home)?;
+                let (namespace, name) = split_package_key(&package);
+                let rolled_back = state.rollback(namespace, name)?;
+                let from = NameVersionDisplay::dir_version(name, &rolled_back.from);
+                let to = NameVersionDisplay::dir_version(name, &rolled_back.to);
+                let binaries = rolled_back.binaries.join(", ");
+                tracing::info!(
+                    target: "hasp::output::rollback_success",
+                    %from,
+                    %to,
+                    %binaries,
+                    "Success rolled back {from} to {to} with binaries {binaries}",
+                );
+                Ok(0)
+            }
+            Command::Hold { package } => {
+                let state = HaspState::load_or_init(home)?;
+                let (namespace, name) = split_package_key(&package);
+                let installed = match state.installed_package(namespace, name)? {
+                    Some(installed) => installed,
+                    None => bail!("{} is not installed", package),
+                };
+                state.set_held(&installed, true)?;
+                let package =
+                    NameVersionDisplay::dir_version(name, &installed.directory_row.package.version);
+                tracing::info!(
+                    target: "hasp::output::hold_success",
+                    %package,
+                    "Held {package}, which sync and install --update will skip",
+                );
+                Ok(0)
+            }
+            Command::Unhold { package } => {
+                let state = HaspState::load_or_init(home)?;
+                let (namespace, name) = split_package_key(&package);
+                let installed = match state.installed_package(namespace, name)? {
+                    Some(installed) => installed,
+                    None => bail!("{} is not installed", package),
+                };
+                if !installed.held {
+                    tracing::warn!(
+                        target: "hasp::output::not_held",
+                        %package,
+                        "Warning {package} is not held",
+                    );
+                    return Ok(0);
+                }
+                state.set_held(&installed, false)?;
+                let package =
+                    NameVersionDisplay::dir_version(name, &installed.directory_row.package.version);
+                tracing::info!(
+                    target: "hasp::output::unhold_success",
+                    %package,
+                    "Released the hold on {package}",
+                );
+                Ok(0)
+            }
+            Command::Logs { package } => {
+                let (namespace, name) = split_package_key(&package);
+                let build_log_dir = home.build_log_dir(namespace, name);
+                let build_log = match latest_build_log(&build_log_dir)? {
+                    Some(build_log) => build_log,
+                    None => bail!("no build logs found for {}", package),
+                };
+
+                let mut file = fs::File::open(&build_log)
+                    .wrap_err_with(|| format!("failed to open build log at {}", build_log))?;
+                io::copy(&mut file, &mut io::stdout().lock())
+                    .wrap_err_with(|| format!("failed to print build log at {}", build_log))?;
+                Ok(0)
+            }
+            Command::Run {
+                binary,
+                package,
+                args,
+            } => {
+                let state = HaspState::load_or_init(home)?;
+                let program = run::find_binary(&state, &binary, package.as_ref())?;
+                // On Unix, hasp is replaced by the binary without dropping state, so record
+                // pending events first.
+                state.flush_events(Duration::from_secs(1));
+                run::exec(&program, &args)
+            }
+            Command::Shell { packages } => {
+                let state = HaspState::load_or_init(home)?;
+                let (dirs, on_path) = if packages.is_empty() {
+                    let bin_dir = state.home().bin_dir();
+                    (vec![bin_dir.to_owned()], bin_dir.to_string())
+                } else {
+                    let on_path: Vec<_> =
+                        packages.iter().map(|filter| filter.to_string()).collect();
+                    (run::package_dirs(&state, &packages)?, on_path.join(", "))
+                };
+                tracing::info!(
+                    target: "hasp::output::shell_started",
+                    %on_path,
+                    "Starting a shell with {on_path} on PATH (exit it to return)",
+                );
+                state.flush_events(Duration::from_secs(1));
+                run::shell(&dirs, &packages)
+            }
+            Command::Resolve {
+                specs,
+                format,
+                cargo_opts,
+            } => {
+                let state = HaspState::load_or_init(home)?;
+                let config = state.home().config();
+                let build_opts = cargo_opts.build_opts(
+                    config,
+                    state.home().cache_dir(),
+                    global_opts.retry_policy(config),
+                    global_opts.refresh,
+                    global_opts.offline,
+                );
+                let mut packages = vec![];
+                for spec in specs {
+                    let request = parse_install_request(spec, &state, &cargo_opts)?;
+                    packages.push(
+                        state
+                            .resolve(request, build_opts.clone(), global_opts.output)
+                            .await?,
+                    );
+                }
+
+                match format.unwrap_or_else(|| global_opts.output.message_format()) {
+                    MessageFormat::Human => {
+                        let rows: Vec<_> = packages
+                            .iter()
+                            .map(|package| {
+                                (
+                                    package_key(&package.namespace, &package.name),
+                                    package.version.short_display().to_string(),
+                                )
+                            })
+                            .collect();
+                        let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+                        for ((key, version), package) in rows.into_iter().zip(&packages) {
+                            match &package.download_url {
+                                Some(url) => {
+                                    println!("{:<width$}  {}  {}", key, version, url, width = width)
+                                }
+                                None => println!("{:<width$}  {}", key, version, width = width),
                             }
                         }
-                        InstallStatus::AlreadyInstalled { version } => {
-                            already_installed.push((name, version));
-                        }
                     }
+                    MessageFormat::Json => println!(
+                        "{}",
+                        serde_json::to_string(&ResolveOutput { packages })
+                            .wrap_err("failed to serialize resolved packages")?
+                    ),
                 }
-
-                if !already_installed.is_empty() {
-                    let mut s = String::with_capacity(512);
-                    let len = already_installed.len();
-                    for (idx, (name, version)) in already_installed.iter().enumerate() {
-                        s.push_str("* ");
-                        s.push_str(
-                            format!("{}", NameVersionDisplay::dir_version(name, version)).as_str(),
+                Ok(0)
+            }
+            Command::List { format, all, root } => {
+                let state = HaspState::load_or_init(global_opts.root_home(home, root)?)?;
+                // Wait for any installs in progress to finish, so they're listed as they end up,
+                // and keep them from swapping directories until the list is printed.
+                let (installed, _locks) = state.installed_packages_locked()?;
+                let not_installed = if all {
+                    state.not_installed_directories()?
+                } else {
+                    vec![]
+                };
+                match format.unwrap_or_else(|| global_opts.output.message_format()) {
+                    MessageFormat::Human => {
+                        let mut rows = installed
+                            .iter()
+                            .map(|installed| {
+                                let directory = &installed.directory_row.package;
+                                let held = if installed.held {
+                                    "  (held)".to_owned()
+                                } else {
+                                    String::new()
+                                };
+                                (
+                                    package_key(&directory.namespace, &directory.name),
+                                    directory.version.short_display().to_string(),
+                                    held,
+                                )
+                            })
+                            .collect::<Vec<_>>();
+                        rows.extend(not_installed.iter().map(|directory| {
+                            let package = &directory.package;
+                            let note = match (&directory.last_failure, directory.previous_install) {
+                                (_, true) => "  (not installed, kept to roll back to)".to_owned(),
+                                (Some(failure), false) => {
+                                    format!("  (not installed, last install {})", failure)
+                                }
+                                (None, false) => "  (not installed)".to_owned(),
+                            };
+                            (
+                                package_key(&package.namespace, &package.name),
+                                package.version.short_display().to_string(),
+                                note,
+                            )
+                        }));
+                        let width = rows.iter().map(|(key, ..)| key.len()).max().unwrap_or(0);
+                        for (key, version, note) in rows {
+                            println!("{:<width$}  {}{}", key, version, note, width = width);
+                        }
+                    }
+                    MessageFormat::Json => {
+                        let packages = installed
+                            .iter()
+                            .map(|installed| {
+                                let directory = &installed.directory_row.package;
+                                installed.to_installed_package(state.home().install_path(
+                                    &directory.namespace,
+                                    &directory.name,
+                                    directory.hash,
+                                ))
+                            })
+                            .collect();
+                        println!(
+                            "{}",
+                            serde_json::to_string(&PackageListOutput {
+                                packages,
+                                not_installed
+                            })
+                            .wrap_err("failed to serialize installed packages")?
                         );
-                        if idx < (len - 1) {
-                            s.push('\n');
+                    }
+                }
+                Ok(0)
+            }
+            Command::Stats { top, format } => {
+                let state = HaspState::load_or_init(home)?;
+                let events = state.events(None)?;
+                let installs = events.iter().filter_map(|entry| match &entry.event {
+                    Ok(EventEnvelope {
+                        event: Event::InstallSuccess(install),
+                        ..
+                    }) => Some(install),
+                    _ => None,
+                });
+                let stats = install_stats(installs, top);
+                match format.unwrap_or_else(|| global_opts.output.message_format()) {
+                    MessageFormat::Human => {
+                        for line in summary_lines(&stats) {
+                            println!("{}", line);
                         }
                     }
+                    MessageFormat::Json => println!(
+                        "{}",
+                        serde_json::to_string(&stats).wrap_err("failed to serialize stats")?
+                    ),
+                }
+                Ok(0)
+            }
+            Command::Info {
+                package,
+                deps,
+                format,
+            } => {
+                let state = HaspState::load_or_init(home)?;
+                let (namespace, name) = split_package_key(&package);
+                let (installed, _lock) = match state.installed_package_locked(namespace, name)? {
+                    Some(installed) => installed,
+                    None => bail!("{} is not installed", package),
+                };
 
-                    // TODO: pass in more structured metadata once Valuable is implemented
-                    tracing::info!(
-                        target: "hasp::output::informational::already_installed",
-                        "Info the following packages are already installed:\n{}",
-                        s
+                let directory = &installed.directory_row.package;
+                let format = format.unwrap_or_else(|| global_opts.output.message_format());
+                if format == MessageFormat::Json {
+                    let install_path = state.home().install_path(namespace, name, directory.hash);
+                    let dependencies = if deps {
+                        Some(state.install_dependencies(&installed)?)
+                    } else {
+                        None
+                    };
+                    let output = PackageInfoOutput {
+                        installed: installed.to_installed_package(install_path),
+                        dependencies,
+                    };
+                    println!(
+                        "{}",
+                        serde_json::to_string(&output)
+                            .wrap_err_with(|| format!("failed to serialize {}", package))?
                     );
+                    return Ok(0);
+                }
+                println!("package: {}:{}", namespace, name);
+                println!("version: {}", directory.version.short_display());
+                if installed.held {
+                    println!("held: yes");
+                }
+                println!(
+                    "path: {}",
+                    state.home().install_path(namespace, name, directory.hash)
+                );
+                println!(
+                    "binaries: {}",
+                    installed.binary_names().collect::<Vec<_>>().join(", ")
+                );
+                if let Some(signature) = installed.signature() {
+                    println!("signature: {}", signature);
+                }
+                if let Some(build) = installed.build_metadata() {
+                    let unknown = || "unknown".to_owned();
+                    println!("rustc: {}", build.rustc_version.unwrap_or_else(unknown));
+                    println!("cargo: {}", build.cargo_version.unwrap_or_else(unknown));
+                    println!("host: {}", build.host.unwrap_or_else(unknown));
+                    let mut features: Vec<_> = build.features.into_iter().collect();
+                    if build.default_features {
+                        features.insert(0, "default".to_owned());
+                    }
+                    if features.is_empty() {
+                        println!("features: none");
+                    } else {
+                        println!("features: {}", features.join(", "));
+                    }
+                    println!("profile: {}", build.profile.as_deref().unwrap_or("release"));
+                    match build.rustflags {
+                        Some(rustflags) if is_redacted_env_value(&rustflags) => {
+                            println!("rustflags: set (the value isn't recorded)");
+                        }
+                        Some(rustflags) => println!("rustflags: {}", rustflags),
+                        None => {}
+                    }
+                }
+                if deps {
+                    let dependencies = state.install_dependencies(&installed)?;
+                    if dependencies.is_empty() {
+                        println!("dependencies: none recorded");
+                    } else {
+                        println!("dependencies:");
+                    }
+                    for dependency in dependencies {
+                        print!("  {} {}", dependency.name, dependency.version);
+                        if let Some(source) = &dependency.source {
+                            print!(" ({})", source);
+                        }
+                        if let Some(checksum) = &dependency.checksum {
+                            print!(" checksum {}", checksum);
+                        }
+                        println!();
+                    }
                 }
+                Ok(0)
+            }
+            Command::Why { package } => {
+                let state = HaspState::load_or_init(home)?;
+                let (namespace, name) = split_package_key(&package);
+                let installed = match state.installed_package(namespace, name)? {
+                    Some(installed) => installed,
+                    None => bail!("{} is not installed", package),
+                };
 
-                if any_failed {
-                    Ok(2)
-                } else if !already_installed.is_empty() {
-                    Ok(1)
-                } else {
-                    Ok(0)
+                let directory = &installed.directory_row.package;
+                println!("package: {}:{}", namespace, name);
+                println!("version: {}", directory.version.short_display());
+                match state.requested_by(&installed)? {
+                    (false, _) => println!("reason: installed for another package"),
+                    (true, None) => println!(
+                        "reason: requested directly (installed before hasp recorded who requested \
+                         packages)"
+                    ),
+                    (true, Some(provenance)) => {
+                        println!("reason: {}", provenance.requester);
+                        if let Some(user) = &provenance.user {
+                            println!("user: {}", user);
+                        }
+                        println!("time: {}", provenance.time.to_rfc3339());
+                        if let Requester::Manifest { path, .. } = &provenance.requester {
+                            // The manifest may have changed since.
+                            match Manifest::from_path(path) {
+                                Ok(manifest) if !manifest.contains(namespace, name) => {
+                                    println!("note: no longer listed in {}", path)
+                                }
+                                Ok(_) => {}
+                                Err(_) => println!("note: {} can no longer be read", path),
+                            }
+                        }
+                    }
                 }
+                Ok(0)
             }
-        }
+            Command::Tui => {
+                if !home.interactive() {
+                    bail!(
+                        "the dashboard reads commands from the terminal, so it can't be shown with \
+                         --ci (hint: run the commands directly instead)"
+                    );
+                }
+                tui::run(home, global_opts, report).await
+            }
+            Command::Doctor { platform } => {
+                let bin_dir = home.bin_dir();
+                println!("hasp {}", env!("CARGO_PKG_VERSION"));
+                println!("platform: {}", PlatformInfo::current());
+                println!("home: {}", home.home_dir());
+
+                let mut checks: Vec<(String, Result<()>)> = vec![];
+                let bin_dir_on_path = env::var_os("PATH")
+                    .map(|path| env::split_paths(&path).any(|dir| dir == bin_dir))
+                    .unwrap_or(false);
+                checks.push((
+                    "bin directory on PATH".to_owned(),
+                    if bin_dir_on_path {
+                        Ok(())
+                    } else {
+                        Err(eyre!(
+                            "{} is not on PATH, so installed binaries can't be run by name (hint: \
+                             add it to PATH in your shell profile)",
+                            bin_dir,
+                        ))
+                    },
+                ));
+
+                // Only look at installs if the databases are healthy, since opening them runs
+                // migrations.
+                let db_checks = ConnectionCreator::new(&home).check_health();
+                let dbs_healthy = db_checks.iter().all(|(_, result)| result.is_ok());
+                checks.extend(db_checks);
+                if dbs_healthy {
+                    let state = HaspState::load_or_init(home.clone())?;
+                    let summary = state.fsck(false)?;
+                    checks.push((
+                        format!(
+                            "install directories and shims ({} checked)",
+                            summary.checked
+                        ),
+                        if summary.problems.is_empty() {
+                            Ok(())
+                        } else {
+                            Err(eyre!(
+                                "{} problems found:\n{}",
+                                summary.problems.len(),
+                                summary
+                                    .problems
+                                    .iter()
+                                    .map(|problem| format!("  - {}", problem))
+                                    .collect::<Vec<_>>()
+                                    .join("\n"),
+                            ))
+                        },
+                    ));
+                }
+
+                if platform {
+                    let temp_dir = Utf8TempDir::new(home.cache_dir(), "doctor-", "")?;
+                    checks.extend(
+                        self_test(temp_dir.path())
+                            .into_iter()
+                            .map(|(check, result)| (check.to_owned(), result)),
+                    );
+                }
+
+                let mut failed = false;
+                for (check, result) in checks {
+                    match result {
+                        Ok(()) => tracing::info!(
+                            target: "hasp::output::doctor::passed",
+                            %check,
+                            "Passed {check}",
+                        ),
+                        Err(err) => {
+                            failed = true;
+                            let error = format!("{:#}", err);
+                            tracing::error!(
+                                target: "hasp::output::doctor::failed",
+                                %check,
+                                %error,
+                                "Failed {check}: {error}",
+                            );
+                        }
+                    }
+                }
+
+                Ok(if failed { 2 } else { 0 })
+            }
+            Command::Db { command } => command.exec(home),
+            Command::Audit => {
+                let state = HaspState::load_or_init(home)?;
+                let db = AdvisoryDb::load(
+                    state.home(),
+                    &global_opts.retry_policy(state.home().config()),
+                    global_opts.refresh,
+                )
+                .await?;
+                let config = state.home().config();
+                let build_opts = config.install.build_opts(
+                    state.home().cache_dir(),
+                    global_opts.retry_policy(config),
+                    global_opts.refresh,
+                    global_opts.offline,
+                );
+                let summary = audit_installed(&state, &db, &build_opts).await?;
+                Ok(log_audit_summary(&summary))
+            }
+            Command::Hints {
+                command: HintsCommand::Update,
+            } => {
+                let count =
+                    HintList::update(&home, &global_opts.retry_policy(home.config())).await?;
+                tracing::info!(
+                    target: "hasp::output::hints::updated",
+                    count,
+                    "Success updated hints for {count} packages",
+                );
+                Ok(0)
+            }
+            Command::Hints {
+                command: HintsCommand::List,
+            } => {
+                for (key, hint) in HintList::load(&home)?.packages {
+                    println!("{}", hint.note(&key));
+                }
+                Ok(0)
+            }
+            Command::Cache { command } => command.exec(&home),
+            Command::Gc {
+                dry_run,
+                directories,
+            } => {
+                // Installs in progress may be adding files that their receipts don't list yet.
+                if !dry_run && !find_lock_holders(home.installs_dir()).is_empty() {
+                    bail!(
+                        "can't remove files from the content store while installs are in \
+                         progress (hint: wait for them to finish)"
+                    );
+                }
+                let summary = ContentStore::new(home.content_store_dir()).gc(&home, dry_run)?;
+                let (removed, checked, bytes) =
+                    (summary.removed, summary.checked, summary.removed_bytes);
+                if dry_run {
+                    tracing::info!(
+                        target: "hasp::output::gc::unused",
+                        removed,
+                        checked,
+                        bytes,
+                        "Unused {removed} of {checked} files in the content store ({bytes} bytes)",
+                    );
+                } else {
+                    tracing::info!(
+                        target: "hasp::output::gc::summary",
+                        removed,
+                        checked,
+                        bytes,
+                        "Removed {removed} of {checked} files in the content store ({bytes} bytes)",
+                    );
+                }
+                if directories {
+                    let state = HaspState::load_or_init(home)?;
+                    let purged = state.purge_directories(dry_run)?;
+                    for row in &purged {
+                        let key = package_key(&row.package.namespace, &row.package.name);
+                        let package = NameVersionDisplay::dir_version(&key, &row.package.version);
+                        if dry_run {
+                            tracing::info!(
+                                target: "hasp::output::gc::directory_found",
+                                %package,
+                                "Found record of {package}, which isn't installed",
+                            );
+                        } else {
+                            tracing::info!(
+                                target: "hasp::output::gc::directory",
+                                %package,
+                                "Removed record of {package}, which isn't installed",
+                            );
+                        }
+                    }
+                    let count = purged.len();
+                    if dry_run {
+                        tracing::info!(
+                            target: "hasp::output::gc::directories_found",
+                            count,
+                            "Found {count} records of directories that aren't installed",
+                        );
+                    } else {
+                        tracing::info!(
+                            target: "hasp::output::gc::directories",
+                            count,
+                            "Removed {count} records of directories that aren't installed",
+                        );
+                    }
+                }
+                Ok(0)
+            }
+            Command::Clean { package, dry_run } => {
+                // Cargo may be building in the package's target directory.
+                if !dry_run && !find_lock_holders(home.installs_dir()).is_empty() {
+                    bail!(
+                        "can't clean {} while installs are in progress (hint: wait for them to \
+                         finish)",
+                        package
+                    );
+                }
+                let state = HaspState::load_or_init(home)?;
+                let (namespace, name) = split_package_key(&package);
+                let cleaned = state.clean(namespace, name, dry_run)?;
+                for dir in &cleaned {
+                    let (description, path, bytes) = (&dir.description, &dir.path, dir.size);
+                    if dry_run {
+                        tracing::info!(
+                            target: "hasp::output::clean::found",
+                            %description,
+                            %path,
+                            bytes,
+                            "Found {description} at {path} ({bytes} bytes)",
+                        );
+                    } else {
+                        tracing::info!(
+                            target: "hasp::output::clean::removed",
+                            %description,
+                            %path,
+                            bytes,
+                            "Removed {description} at {path} ({bytes} bytes)",
+                        );
+                    }
+                }
+                if cleaned.is_empty() {
+                    tracing::info!(
+                        target: "hasp::output::clean::nothing",
+                        %package,
+                        "Info nothing is kept for {package} other than its current install",
+                    );
+                }
+                Ok(0)
+            }
+            Command::Trust { command } => {
+                let state = HaspState::load_or_init(home)?;
+                command.exec(&state)
+            }
+            Command::Events {
+                command: EventsCommand::List { limit, format },
+            } => {
+                let state = HaspState::load_or_init(home)?;
+                let format = format.unwrap_or_else(|| global_opts.output.message_format());
+                for entry in state.events(limit)? {
+                    let envelope = match entry.event {
+                        Ok(envelope) => envelope,
+                        Err(err) => {
+                            let error = format!("{:#}", err);
+                            tracing::warn!(
+                                target: "hasp::output::events::unparseable",
+                                event_id = entry.event_id,
+                                event_name = %entry.event_name,
+                                %error,
+                                "Skipping event {event_id} ({event_name}): {error}",
+                                event_id = entry.event_id,
+                                event_name = entry.event_name,
+                            );
+                            continue;
+                        }
+                    };
+                    match format {
+                        MessageFormat::Human => println!("{}", event_line(&envelope)),
+                        MessageFormat::Json => println!(
+                            "{}",
+                            serde_json::to_string(&envelope)
+                                .wrap_err("failed to serialize event")?
+                        ),
+                    }
+                }
+                Ok(0)
+            }
+            Command::Events {
+                command:
+                    EventsCommand::Prune {
+                        max_age_days,
+                        max_rows,
+                        vacuum,
+                    },
+            } => {
+                let state = HaspState::load_or_init(home)?;
+                let config = state.home().config().events.retention();
+                let policy = RetentionPolicy {
+                    max_age: max_age_days.map(days_to_duration).or(config.max_age),
+                    max_rows: max_rows.or(config.max_rows),
+                };
+                if policy.is_unlimited() && !vacuum {
+                    bail!(
+                        "no retention policy for events (hint: pass --max-age-days or --max-rows, \
+                         or set events.max-age-days or events.max-rows in the config)"
+                    );
+                }
+
+                let summary = state.prune_events(policy, vacuum)?;
+                let (pruned, by_age, by_count, remaining) = (
+                    summary.pruned(),
+                    summary.pruned_by_age,
+                    summary.pruned_by_rows,
+                    summary.remaining,
+                );
+                if summary.vacuumed {
+                    tracing::info!(
+                        target: "hasp::output::events::pruned_vacuumed",
+                        pruned,
+                        by_age,
+                        by_count,
+                        remaining,
+                        "Pruned {pruned} events ({by_age} by age, {by_count} by count), \
+                         {remaining} remaining, database vacuumed",
+                    );
+                } else {
+                    tracing::info!(
+                        target: "hasp::output::events::pruned",
+                        pruned,
+                        by_age,
+                        by_count,
+                        remaining,
+                        "Pruned {pruned} events ({by_age} by age, {by_count} by count), \
+                         {remaining} remaining",
+                    );
+                }
+                Ok(0)
+            }
+            Command::Itself {
+                command: SelfCommand::Update { cargo_opts },
+            } => {
+                let state = HaspState::load_or_init(home)?;
+                let config = state.home().config();
+                let build_opts = cargo_opts.build_opts(
+                    config,
+                    state.home().cache_dir(),
+                    global_opts.retry_policy(config),
+                    global_opts.refresh,
+                    global_opts.offline,
+                );
+                let directory = cargo_opts.directory(config, SELF_CRATE);
+
+                let current = self_update::current_version();
+                let latest = latest_crate_version(
+                    directory.registry.as_deref(),
+                    SELF_CRATE,
+                    &build_opts,
+                    state.db_creator(),
+                )
+                .await?;
+                let latest = match latest {
+                    Some(latest) if latest > current => latest,
+                    _ => {
+                        let package = NameVersionDisplay::semver(SELF_CRATE, &current);
+                        tracing::info!(
+                            target: "hasp::output::self_update::up_to_date",
+                            %package,
+                            "Up-to-date {package} is the latest version",
+                        );
+                        return Ok(0);
+                    }
+                };
+
+                let request = InstallRequest {
+                    name: SELF_CRATE.to_owned(),
+                    req: VersionReq::parse(&format!("={}", latest))
+                        .expect("exact version is a valid requirement")
+                        .into(),
+                    metadata: PackageMetadata::Cargo(directory),
+                    checksum: None,
+                    update: false,
+                    explicit: true,
+                    force_bin_overwrite: false,
+                    retries: 0,
+                };
+                let statuses = install_all(
+                    &state,
+                    vec![request],
+                    &Provenance::command_line(&report.args),
+                    build_opts,
+                    global_opts.output,
+                )
+                .await;
+                let exit_code =
+                    finish_installs(statuses, false, false, global_opts.output.quiet, report).await;
+                if exit_code != 0 {
+                    return Ok(exit_code);
+                }
+
+                let exe = self_update::current_exe()?;
+                if exe.starts_with(state.home().installs_dir()) {
+                    // hasp is run through its own shim, which now runs the new version.
+                    tracing::info!(
+                        target: "hasp::output::self_update::shim_updated",
+                        %exe,
+                        "Info hasp is installed through hasp, so its shim was updated instead of {exe}",
+                    );
+                } else {
+                    let filter: PackageFilter = format!("{}@={}", SELF_CRATE, latest).parse()?;
+                    let binary = find_binary(&state, SELF_CRATE, Some(&filter))?;
+                    self_update::replace_binary(&binary, &exe)?;
+                }
+                state.record_self_update(&current, &latest, &exe);
+                let from = NameVersionDisplay::semver(SELF_CRATE, &current);
+                let to = NameVersionDisplay::semver(SELF_CRATE, &latest);
+                tracing::info!(
+                    target: "hasp::output::self_update::success",
+                    %from,
+                    %to,
+                    %exe,
+                    "Success updated {from} to {to} at {exe}",
+                );
+                Ok(0)
+            }
+            Command::Export { root } => {
+                let state = HaspState::load_or_init(global_opts.root_home(home, root)?)?;
+                let manifest = Manifest::from_installed(&state.installed_packages()?)?;
+                print!("{}", manifest.to_toml()?);
+                Ok(0)
+            }
+            Command::Sync {
+                manifest,
+                prune,
+                keep_going,
+                force_bin_overwrite,
+                root,
+            } => {
+                let state = HaspState::load_or_init(global_opts.root_home(home, root)?)?;
+                let config = state.home().config();
+                let provenance = Provenance::manifest(&manifest, &report.args)?;
+                let manifest = Manifest::from_path(&manifest)?;
+                let requests =
+                    manifest.install_requests(&ManifestPlatform::current(global_opts.ci))?;
+                report_skipped(&requests.skipped, report);
+                let to_install = skip_held(&state, requests.requests, report)?
+                    .into_iter()
+                    .map(|request| InstallRequest {
+                        force_bin_overwrite,
+                        ..request
+                    })
+                    .collect();
+
+                let statuses = install_all(
+                    &state,
+                    to_install,
+                    &provenance,
+                    config.install.build_opts(
+                        state.home().cache_dir(),
+                        global_opts.retry_policy(config),
+                        global_opts.refresh,
+                        global_opts.offline,
+                    ),
+                    global_opts.output,
+                )
+                .await;
+                let exit_code = finish_installs(
+                    statuses,
+                    keep_going,
+                    global_opts.strict,
+                    global_opts.output.quiet,
+                    report,
+                )
+                .await;
+                if exit_code == CANCELLED_EXIT_CODE
+                    || (is_failure_exit_code(exit_code) && !keep_going)
+                {
+                    return Ok(exit_code);
+                }
+
+                if prune {
+                    prune_unlisted(&state, &manifest)?;
+                }
+
+                Ok(exit_code)
+            }
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum DbCommand {
+    /// Print the sizes of databases and their write-ahead logs, and which migrations have been
+    /// applied
+    Status,
+
+    /// Apply or roll back migrations, e.g. to roll the database back before or after downgrading
+    /// hasp
+    Migrate {
+        /// The migration to migrate to [default: the latest migration known to this version of
+        /// hasp]
+        #[structopt(long, value_name = "NAME")]
+        to: Option<String>,
+    },
+
+    /// Checkpoint write-ahead logs into databases, truncating them, after updating the statistics
+    /// the query planner uses
+    Checkpoint,
+
+    /// Check install directories against the databases, restoring missing records from the
+    /// receipts in install directories
+    Fsck,
+
+    /// Rebuild the packages database from the receipts in install directories, moving the old
+    /// database aside
+    RebuildFromDisk,
+
+    /// Check the databases for corruption, moving corrupted ones aside and recovering what they
+    /// held from the receipts in install directories and the events journal
+    Repair,
+}
+
+/// Formats an event as a line for `hasp events list`.
+fn event_line(envelope: &EventEnvelope) -> String {
+    format!(
+        "{} {}{}",
+        envelope.event_time.to_rfc3339(),
+        envelope.event.name(),
+        event_subject(&envelope.event)
+            .map(|subject| format!(" {}", subject))
+            .unwrap_or_default()
+    )
+}
+
+/// Returns what an event is about, for `hasp events list`: a package, a migration or a source.
+fn event_subject(event: &Event) -> Option<String> {
+    let package = match event {
+        Event::InstallStarted(event) => &event.package,
+        Event::InstallSuccess(event) => &event.package,
+        Event::InstallFailed(event) => &event.package,
+        Event::UninstallSuccess(event) => &event.package,
+        Event::BinariesRemoved(event) => &event.package,
+        Event::BinaryConflict(event) => &event.package,
+        Event::MigrationStarted(event)
+        | Event::MigrationFinished(event)
+        | Event::MigrationReverted(event) => return Some(event.name.clone()),
+        Event::MigrationRollback(event) => return Some(event.rolled_back.join(", ")),
+        Event::EventsPruned(_) => return None,
+        Event::SourceTrusted(event) => return Some(event.source.clone()),
+        Event::SourceUntrusted(event) => return Some(event.source.clone()),
+        Event::HookFinished(event) => return Some(format!("{} ({})", event.package, event.point)),
+        Event::SelfUpdated(event) => return Some(format!("{} -> {}", event.from, event.to)),
+        Event::RunStarted(event) => return Some(event.args.join(" ")),
+        Event::RunFinished(event) => {
+            return Some(match event.exit_code {
+                Some(exit_code) => format!("exit code {}", exit_code),
+                None => "error".to_owned(),
+            })
+        }
+    };
+    Some(format!(
+        "{}@{}",
+        package_key(&package.namespace, &package.name),
+        package.version.short_display()
+    ))
+}
+
+#[derive(Debug, StructOpt)]
+enum EventsCommand {
+    /// Print events recorded in the journal, oldest first
+    List {
+        /// Only print this many of the most recent events
+        #[structopt(long)]
+        limit: Option<u64>,
+
+        /// Print events as human-readable lines or as JSON, one object per line
+        #[structopt(long, possible_values = &["human", "json"])]
+        format: Option<MessageFormat>,
+    },
+
+    /// Remove old events from the journal, according to the retention policy in the config
+    Prune {
+        /// Remove events older than this many days
+        #[structopt(long)]
+        max_age_days: Option<u64>,
+
+        /// Keep at most this many of the most recent events
+        #[structopt(long)]
+        max_rows: Option<u64>,
+
+        /// Also vacuum the events database to reclaim space
+        #[structopt(long)]
+        vacuum: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum SelfCommand {
+    /// Update hasp to the latest version on crates.io, building it like any other package
+    Update {
+        #[structopt(flatten)]
+        cargo_opts: CargoInstallOpts,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum TrustCommand {
+    /// Print trusted sources, including those from the config file
+    List,
+
+    /// Approve a source, e.g. cargo:crates.io or oci:ghcr.io
+    Add { source: String },
+
+    /// Remove the approval for a source
+    Remove { source: String },
+}
+
+#[derive(Debug, StructOpt)]
+enum CacheCommand {
+    /// Print the build cache in use, and the target directories kept for crates
+    Status,
+
+    /// Remove the target directories kept for crates, or for all crates if none are given
+    Clear { names: Vec<String> },
+}
+
+#[derive(Debug, StructOpt)]
+enum HintsCommand {
+    /// Fetch the latest hints, replacing the ones built into hasp
+    Update,
+
+    /// Print the hints in effect, including those from the config file
+    List,
+}
+
+impl CacheCommand {
+    fn exec(self, home: &HaspHome) -> Result<i32> {
+        let target_dirs = BuildCache::target_dirs(home.cache_dir());
+        let mut entries = match target_dirs.read_dir() {
+            Ok(entries) => entries
+                .map(|entry| {
+                    let entry = entry
+                        .wrap_err_with(|| format!("failed to read entry in {}", target_dirs))?;
+                    entry.file_name().into_string().map_err(|name| {
+                        eyre!("file name {:?} in {} is not valid UTF-8", name, target_dirs)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read {}", target_dirs))
+            }
+        };
+        entries.sort_unstable();
+
+        match self {
+            CacheCommand::Status => {
+                match home.config().install.build_cache {
+                    Some(BuildCacheMode::TargetDir) => {
+                        println!("build cache: target directories in {}", target_dirs)
+                    }
+                    Some(BuildCacheMode::Sccache) => {
+                        println!("build cache: sccache (run `sccache --show-stats` for details)")
+                    }
+                    None => println!("build cache: off"),
+                }
+                let mut total = 0;
+                for name in &entries {
+                    let size = dir_size(&target_dirs.join(name))?;
+                    total += size;
+                    println!("{}: {} bytes", name, size);
+                }
+                if !entries.is_empty() {
+                    println!("total: {} bytes", total);
+                }
+            }
+            CacheCommand::Clear { names } => {
+                // Cargo may be building in these directories.
+                if !find_lock_holders(home.installs_dir()).is_empty() {
+                    bail!(
+                        "can't clear the build cache while installs are in progress (hint: wait \
+                         for them to finish)"
+                    );
+                }
+                let names = if names.is_empty() { entries } else { names };
+                for name in names {
+                    let dir = target_dirs.join(&name);
+                    if !dir.exists() {
+                        tracing::warn!(
+                            target: "hasp::output::cache_missing",
+                            %name,
+                            "Warning no target directory is kept for {name}",
+                        );
+                        continue;
+                    }
+                    let size = dir_size(&dir)?;
+                    fs::remove_dir_all(&dir)
+                        .wrap_err_with(|| format!("failed to remove directory {}", dir))?;
+                    tracing::info!(
+                        target: "hasp::output::cache::cleared",
+                        %name,
+                        %size,
+                        "Removed target directory for {name} ({size} bytes)",
+                    );
+                }
+            }
+        }
+        Ok(0)
+    }
+}
+
+impl TrustCommand {
+    fn exec(self, state: &HaspState) -> Result<i32> {
+        let config = &state.home().config().trust;
+        match self {
+            TrustCommand::List => {
+                for source in &config.sources {
+                    println!("{} (config)", source);
+                }
+                for row in state.trusted_sources()? {
+                    println!(
+                        "{} (approved through {} on {})",
+                        row.source,
+                        row.approval,
+                        row.approve_time.format("%Y-%m-%d"),
+                    );
+                }
+                if !config.require_approval {
+                    tracing::info!(
+                        target: "hasp::output::informational::trust_not_required",
+                        "Note approval of new sources isn't required (hint: set \
+                         trust.require-approval in the config file)",
+                    );
+                }
+            }
+            TrustCommand::Add { source } => {
+                trust::validate_source(&source)?;
+                if state.trust_source(&source)? {
+                    tracing::info!(
+                        target: "hasp::output::trust::added",
+                        %source,
+                        "Success trusted {source}",
+                    );
+                } else {
+                    tracing::info!(
+                        target: "hasp::output::informational::trust_exists",
+                        %source,
+                        "Info {source} is already trusted",
+                    );
+                }
+            }
+            TrustCommand::Remove { source } => {
+                if state.untrust_source(&source)? {
+                    tracing::info!(
+                        target: "hasp::output::trust::removed",
+                        %source,
+                        "Removed {source} from trusted sources",
+                    );
+                } else if !config.sources.contains(&source) {
+                    bail!("{} is not trusted", source);
+                }
+                if config.sources.contains(&source) {
+                    tracing::warn!(
+                        target: "hasp::output::trust_in_config",
+                        %source,
+                        "Warning {source} is still trusted through trust.sources in the config file",
+                    );
+                }
+            }
+        }
+        Ok(0)
+    }
+}
+
+impl DbCommand {
+    fn exec(self, home: HaspHome) -> Result<i32> {
+        match self {
+            // Loading state would apply pending migrations, and fails for databases that are newer
+            // than this version of hasp, so these commands don't load it.
+            DbCommand::Status => {
+                let creator = ConnectionCreator::new(&home);
+                let display_size = |size: Option<u64>| match size {
+                    Some(size) => format!("{} bytes", size),
+                    None => "absent".to_owned(),
+                };
+                for status in creator.file_statuses() {
+                    println!(
+                        "{}: {} ({}, write-ahead log {})",
+                        status.name,
+                        status.path,
+                        display_size(status.size),
+                        display_size(status.wal_size),
+                    );
+                }
+                println!("journal size limit: {} bytes", creator.journal_size_limit());
+                println!("migrations:");
+                for status in creator.migration_statuses()? {
+                    let state = match status.state {
+                        MigrationState::Pending => "pending".to_owned(),
+                        MigrationState::Applied(time) => format!("applied at {}", time),
+                        MigrationState::RolledBack(time) => format!("rolled back at {}", time),
+                    };
+                    let unknown = if status.known {
+                        ""
+                    } else {
+                        " (unknown to this version of hasp)"
+                    };
+                    println!("  {}: {}{}", status.name, state, unknown);
+                }
+                Ok(0)
+            }
+            DbCommand::Migrate { to } => {
+                let creator = ConnectionCreator::new(&home);
+                let event_logger = EventLogger::new(&creator)?;
+                let summary = creator.migrate(to.as_deref(), &event_logger)?;
+                for name in &summary.rolled_back {
+                    tracing::info!(
+                        target: "hasp::output::migrate::rolled_back",
+                        %name,
+                        "Success rolled back migration {name}",
+                    );
+                }
+                for name in &summary.applied {
+                    tracing::info!(
+                        target: "hasp::output::migrate::applied",
+                        %name,
+                        "Success applied migration {name}",
+                    );
+                }
+                if summary.applied.is_empty() && summary.rolled_back.is_empty() {
+                    tracing::info!(
+                        target: "hasp::output::migrate::up_to_date",
+                        migration = %summary.target,
+                        "Info database is already at migration {migration}",
+                        migration = summary.target,
+                    );
+                }
+                Ok(0)
+            }
+            DbCommand::RebuildFromDisk => {
+                // The packages database may be too damaged to load, so don't load state first.
+                let summary = HaspState::rebuild_from_disk(home)?;
+                if let Some(backup_path) = &summary.backup_path {
+                    tracing::info!(
+                        target: "hasp::output::rebuild::backup",
+                        %backup_path,
+                        "Moved old packages database to {backup_path}",
+                    );
+                }
+                Ok(log_fsck_summary(&summary.fsck))
+            }
+            DbCommand::Repair => {
+                // The databases may be too damaged to load, so don't load state first.
+                let summary = HaspState::repair(home)?;
+                if summary.corrupted.is_empty() {
+                    tracing::info!(
+                        target: "hasp::output::repair::not_corrupted",
+                        "Info no corrupted databases found (hint: run `hasp db fsck` to check \
+                         install directories against them)",
+                    );
+                    return Ok(0);
+                }
+                for file in &summary.corrupted {
+                    tracing::warn!(
+                        target: "hasp::output::repair::corrupted",
+                        database = %file.name,
+                        path = %file.path,
+                        problem = %file.problem,
+                        "Warning database {database} at {path} is corrupted: {problem}",
+                        database = file.name,
+                        path = file.path,
+                        problem = file.problem,
+                    );
+                }
+                for backup_path in &summary.backup_paths {
+                    tracing::info!(
+                        target: "hasp::output::repair::backup",
+                        %backup_path,
+                        "Moved old database to {backup_path}",
+                    );
+                }
+                if summary.is_corrupted("events") {
+                    tracing::warn!(
+                        target: "hasp::output::repair::journal_lost",
+                        "Warning the events journal was started afresh, so install history was \
+                         lost and couldn't be used for recovery",
+                    );
+                }
+                for source in &summary.restored_sources {
+                    tracing::info!(
+                        target: "hasp::output::repair::restored_source",
+                        %source,
+                        "Restored approval of source {source} from the events journal",
+                    );
+                }
+                for package in &summary.missing {
+                    let package = NameVersionDisplay::dir_version(&package.name, &package.version);
+                    tracing::warn!(
+                        target: "hasp::output::repair::missing",
+                        %package,
+                        "Warning {package} was installed according to the events journal, but \
+                         couldn't be restored from its install directory (hint: install it again)",
+                    );
+                }
+                let code = summary.fsck.as_ref().map_or(0, log_fsck_summary);
+                Ok(if summary.missing.is_empty() { code } else { 2 })
+            }
+            DbCommand::Checkpoint => {
+                let state = HaspState::load_or_init(home)?;
+                state.db_creator().optimize(true)?;
+                let mut any_busy = false;
+                for status in state.db_creator().checkpoint(true)? {
+                    if status.busy {
+                        any_busy = true;
+                        tracing::warn!(
+                            target: "hasp::output::checkpoint_busy",
+                            database = %status.name,
+                            checkpointed = status.checkpointed_pages,
+                            pages = status.wal_pages,
+                            "Warning database {database} is in use by another process, \
+                             checkpointed {checkpointed} of {pages} pages",
+                            database = status.name,
+                            checkpointed = status.checkpointed_pages,
+                            pages = status.wal_pages,
+                        );
+                    } else {
+                        tracing::info!(
+                            target: "hasp::output::checkpoint_success",
+                            database = %status.name,
+                            pages = status.checkpointed_pages,
+                            "Checkpointed database {database} ({pages} pages)",
+                            database = status.name,
+                            pages = status.checkpointed_pages,
+                        );
+                    }
+                }
+                Ok(if any_busy { 1 } else { 0 })
+            }
+            DbCommand::Fsck => {
+                let state = HaspState::load_or_init(home)?;
+                let summary = state.fsck(true)?;
+                Ok(log_fsck_summary(&summary))
+            }
+        }
+    }
+}
+
+/// Logs the vulnerabilities and yanked versions found by `hasp audit`, returning the exit code.
+fn log_audit_summary(summary: &AuditSummary) -> i32 {
+    for finding in &summary.findings {
+        let advisory = &finding.advisory;
+        let package = NameVersionDisplay::dir_version(&finding.name, &finding.version);
+        let (id, title, patched) = (&advisory.id, &advisory.title, advisory.patched_display());
+        match &finding.dependency {
+            Some(dependency) => {
+                let dependency = NameVersionDisplay::semver(&dependency.name, &dependency.version);
+                let name = &finding.name;
+                tracing::warn!(
+                    target: "hasp::output::audit::vulnerable_dependency",
+                    %package,
+                    %dependency,
+                    %id,
+                    %title,
+                    %patched,
+                    %name,
+                    "Warning {package} was built with {dependency}, which is affected by {id}: \
+                     {title} ({patched}; hint: install a newer version of {name} to rebuild it \
+                     with patched dependencies)",
+                )
+            }
+            None => tracing::warn!(
+                target: "hasp::output::audit::vulnerable",
+                %package,
+                %id,
+                %title,
+                %patched,
+                "Warning {package} is affected by {id}: {title} ({patched}; hint: install a \
+                 patched version)",
+            ),
+        }
+    }
+    for (name, version) in &summary.yanked {
+        let package = NameVersionDisplay::dir_version(name, version);
+        tracing::warn!(
+            target: "hasp::output::audit::yanked",
+            %package,
+            "Warning {package} has been yanked (hint: install a different version)",
+        );
+    }
+    let (checked, vulnerabilities, yanked) = (
+        summary.checked,
+        summary.findings.len(),
+        summary.yanked.len(),
+    );
+    tracing::info!(
+        target: "hasp::output::audit::summary",
+        checked,
+        vulnerabilities,
+        yanked,
+        "Checked {checked} packages: {vulnerabilities} vulnerabilities found, {yanked} yanked",
+    );
+    if summary.findings.is_empty() && summary.yanked.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Logs what `hasp db fsck` restored and the problems it found, returning the exit code.
+fn log_fsck_summary(summary: &FsckSummary) -> i32 {
+    for row in &summary.restored {
+        let package = NameVersionDisplay::dir_version(&row.package.name, &row.package.version);
+        tracing::info!(
+            target: "hasp::output::fsck::restored",
+            %package,
+            "Restored {package} from its install receipt",
+        );
+    }
+    for shim_path in &summary.restored_shims {
+        tracing::info!(
+            target: "hasp::output::fsck::restored_shim",
+            %shim_path,
+            "Restored missing shim {shim_path}",
+        );
+    }
+    for shim_path in &summary.removed_shims {
+        tracing::info!(
+            target: "hasp::output::fsck::removed_shim",
+            %shim_path,
+            "Removed shim {shim_path} for a binary that no longer exists",
+        );
+    }
+    for problem in &summary.problems {
+        tracing::warn!(
+            target: "hasp::output::fsck::problem",
+            %problem,
+            "Warning {problem}",
+        );
+    }
+    let (checked, restored, problems) = (
+        summary.checked,
+        summary.restored.len(),
+        summary.problems.len(),
+    );
+    tracing::info!(
+        target: "hasp::output::fsck::summary",
+        checked,
+        restored,
+        problems,
+        "Checked {checked} install directories: {restored} restored, {problems} problems found",
+    );
+    if summary.problems.is_empty() {
+        0
+    } else {
+        2
+    }
+}
+
+/// Parses an environment variable from the command line, as `NAME=VALUE`.
+fn parse_env_var(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => bail!("expected NAME=VALUE, found '{}'", s),
+    }
+}
+
+fn parse_cargo_arg(s: &str) -> Result<String> {
+    // hasp reads Cargo's JSON messages to find the built binaries.
+    if s.starts_with("--message-format") {
+        bail!("--message-format is set by hasp and can't be passed through");
+    }
+    Ok(s.to_owned())
+}
+
+/// Turns a package specifier from the command line into an install request.
+fn parse_install_request(
+    spec: PackageSpec,
+    state: &HaspState,
+    cargo_opts: &CargoInstallOpts,
+) -> Result<InstallRequest> {
+    let config = state.home().config();
+    let PackageSpec {
+        namespace,
+        name,
+        req,
+    } = spec;
+    // Use the version from the config file if one wasn't specified, then the requirement the
+    // installed version was resolved from, so that e.g. an install of foo@^1 stays on 1.x.
+    let req = match req.or_else(|| {
+        config
+            .package(&namespace, &name)
+            .and_then(|package_config| package_config.version.clone())
+            .map(Into::into)
+    }) {
+        Some(req) => req,
+        None => state
+            .installed_req(&namespace, &name)?
+            .unwrap_or_else(|| VersionReq::STAR.clone().into()),
+    };
+
+    let metadata = match registry().get(&namespace) {
+        Some(Backend::Cargo) => PackageMetadata::Cargo(cargo_opts.directory(config, &name)),
+        Some(Backend::Npm) => PackageMetadata::Npm(NpmDirectory {
+            ignore_scripts: cargo_opts.ignore_scripts || config.install.ignore_scripts,
+        }),
+        Some(Backend::Oci) => PackageMetadata::Oci(OciDirectory {
+            binary: default_binary_name(&name)?,
+        }),
+        Some(Backend::Plugin(backend)) => PackageMetadata::Plugin(backend),
+        None => return Err(registry().unknown_namespace(&namespace)),
+    };
+    let checksum = config
+        .package(&namespace, &name)
+        .and_then(|package_config| package_config.checksum.clone());
+
+    Ok(InstallRequest {
+        name,
+        req,
+        metadata,
+        checksum,
+        update: false,
+        explicit: true,
+        force_bin_overwrite: false,
+        retries: 0,
+    })
+}
+
+/// Installs packages concurrently, returning their names and install statuses. Each package
+/// should only be requested once, as `merge_specs` ensures for packages on the command line.
+///
+/// A failure to install one package doesn't stop the others, so that all failures can be
+/// reported together.
+async fn install_all(
+    state: &HaspState,
+    requests: Vec<InstallRequest>,
+    provenance: &Provenance,
+    build_opts: CargoBuildOpts,
+    output_opts: OutputOpts,
+) -> Vec<(String, Result<InstallStatus>)> {
+    let _progress = progress::start(
+        output_opts,
+        requests
+            .iter()
+            .map(|request| package_key(request.namespace(), &request.name))
+            .collect(),
+    );
+    let install_futures = requests.into_iter().map(|request| {
+        let name = package_key(request.namespace(), &request.name);
+        state
+            .install_package(request, provenance, build_opts.clone(), output_opts)
+            .map(move |status| {
+                let outcome = match &status {
+                    Ok(InstallStatus::Success { .. }) => progress::Outcome::Installed,
+                    Ok(InstallStatus::AlreadyInstalled { .. }) => {
+                        progress::Outcome::AlreadyInstalled
+                    }
+                    Ok(InstallStatus::Failure { .. }) | Err(_) => progress::Outcome::Failed,
+                };
+                progress::finish(&name, outcome);
+                (name, status)
+            })
+    });
+    futures::future::join_all(install_futures).await
+}
+
+/// Logs the results of installing packages followed by a summary, returning the exit code. If
+/// hasp was cancelled while installing packages, this returns [`CANCELLED_EXIT_CODE`].
+///
+/// If `print_results` is true, which it is with `--quiet`, a line is printed to standard output
+/// for each package as well.
+async fn finish_installs(
+    mut statuses: Vec<(String, Result<InstallStatus>)>,
+    keep_going: bool,
+    strict: bool,
+    print_results: bool,
+    report: &mut RunReport,
+) -> i32 {
+    if !cancel::is_cancelled() {
+        // Checks for newer versions of packages that were already installed run in the
+        // background while other packages install, so they've usually finished by now.
+        for (_, status) in &mut statuses {
+            if let Ok(InstallStatus::AlreadyInstalled { available, .. }) = status {
+                available.wait().await;
+            }
+        }
+        let exit_code = report_statuses(statuses, keep_going, strict, report);
+        report_summary(report, print_results);
+        return exit_code;
+    }
+
+    // Report what finished before hasp was cancelled. Installs that were cancelled are only
+    // recorded as failures, without logging an error for each of them.
+    let (finished, cancelled): (Vec<_>, Vec<_>) =
+        statuses.into_iter().partition(|(_, status)| status.is_ok());
+    report_statuses(finished, true, strict, report);
+    for (name, status) in cancelled {
+        if let Err(err) = status {
+            let class = FailureClass::classify(&err);
+            report.push_failure(&name, None, class, format!("{:#}", err), None);
+        }
+    }
+    report_summary(report, print_results);
+    tracing::warn!(
+        target: "hasp::output::cancel::cancelled",
+        "Cancelled installs in progress were rolled back",
+    );
+    CANCELLED_EXIT_CODE
+}
+
+/// Returns POSIX shell commands that put a bin directory at the front of `PATH`.
+fn shell_exports(bin_dir: &Utf8Path) -> String {
+    format!("export PATH={}:\"$PATH\"\n", sh_quote(bin_dir.as_str()))
+}
+
+/// Logs manifest entries that were skipped because they don't apply to this platform.
+fn report_skipped(skipped: &[(String, String)], report: &mut RunReport) {
+    for (package, reason) in skipped {
+        tracing::info!(
+            target: "hasp::output::informational::manifest_skipped",
+            %package,
+            %reason,
+            "Skipped {package}: {reason}",
+        );
+        report.push_skipped(package, reason);
+    }
+}
+
+/// Filters out requests for packages held with `hasp hold`, reporting them as skipped.
+fn skip_held(
+    state: &HaspState,
+    requests: Vec<InstallRequest>,
+    report: &mut RunReport,
+) -> Result<Vec<InstallRequest>> {
+    let mut to_install = vec![];
+    let mut held = vec![];
+    for request in requests {
+        let key = package_key(request.namespace(), &request.name);
+        match state.installed_package(request.namespace(), &request.name)? {
+            Some(installed) if installed.held => {
+                let reason = format!(
+                    "held at {} (hint: run `hasp unhold {}` to release it)",
+                    installed.directory_row.package.version.short_display(),
+                    key,
+                );
+                held.push((key, reason));
+            }
+            _ => to_install.push(request),
+        }
+    }
+    report_skipped(&held, report);
+    Ok(to_install)
+}
+
+/// Uninstalls packages that aren't listed in the manifest, for `hasp sync --prune`.
+fn prune_unlisted(state: &HaspState, manifest: &Manifest) -> Result<()> {
+    for installed in state.installed_packages()? {
+        let package = &installed.directory_row.package;
+        // Entries skipped on this platform still count as listed, and held packages are left
+        // alone.
+        if manifest.contains(&package.namespace, &package.name) || installed.held {
+            continue;
+        }
+
+        let package = NameVersionDisplay::dir_version(&package.name, &package.version).to_string();
+        let binaries = state.uninstall(installed)?.join(", ");
+        tracing::info!(
+            target: "hasp::output::uninstall_success",
+            %package,
+            %binaries,
+            "Uninstalled {package} with binaries {binaries}",
+        );
+    }
+    Ok(())
+}
+
+/// Logs the results of installing packages and records them in the report, returning the exit
+/// code. Every package is recorded, even if `keep_going` is false, so that each one gets a result
+/// line with `--quiet`.
+///
+/// Packages that were already installed exit with 1 if `strict` is true.
+fn report_statuses(
+    statuses: Vec<(String, Result<InstallStatus>)>,
+    keep_going: bool,
+    strict: bool,
+    run_report: &mut RunReport,
+) -> i32 {
+    let mut already_installed = PackageList::default();
+    let mut failures: Vec<(FailureClass, String, Report)> = vec![];
+
+    for (name, status) in statuses {
+        match status {
+            Ok(InstallStatus::Success {
+                version, binaries, ..
+            }) => {
+                let package = NameVersionDisplay::dir_version(&name, &version);
+                let binaries_str = binaries.join(", ");
+                tracing::info!(
+                    target: "hasp::output::install_success",
+                    %package,
+                    binaries = %binaries_str,
+                    "Success {package} installed with binaries {binaries}",
+                    binaries = binaries_str,
+                );
+                run_report.push_success(&name, &version, PackageOutcome::Installed, &binaries);
+            }
+            Ok(InstallStatus::Failure {
+                version,
+                report,
+                build_log,
+                ..
+            }) => {
+                // Output from concurrent builds is interleaved, so repeat the end of this one.
+                let stderr_tail = report
+                    .chain()
+                    .find_map(|cause| cause.downcast_ref::<ProcessFailed>())
+                    .map(|process_failed| process_failed.stderr_tail.join("\n"))
+                    .filter(|stderr_tail| !stderr_tail.is_empty());
+                let name_version = NameVersionDisplay::dir_version(&name, &version).to_string();
+                let error = format!("{:#}", report);
+                let stderr_tail = stderr_tail
+                    .map_or_else(String::new, |stderr_tail| format!("{}\n", stderr_tail));
+                tracing::error!(
+                    target: "hasp::output::build_failed",
+                    package = %name_version,
+                    %error,
+                    %stderr_tail,
+                    %build_log,
+                    "Failed to install {package}: {error}\n{stderr_tail}(full build log at \
+                     {build_log})",
+                    package = name_version,
+                );
+                let class = FailureClass::classify(&report);
+                run_report.push_failure(
+                    &name,
+                    Some(&version),
+                    class,
+                    format!("{:#}", report),
+                    Some(&build_log),
+                );
+                failures.push((class, name_version, report));
+            }
+            Err(report) => {
+                let error = format!("{:#}", report);
+                tracing::error!(
+                    target: "hasp::output::install_failed",
+                    package = %name,
+                    %error,
+                    "Failed to install {package}: {error}",
+                    package = name,
+                );
+                let class = FailureClass::classify(&report);
+                run_report.push_failure(&name, None, class, format!("{:#}", report), None);
+                failures.push((class, name, report));
+            }
+            Ok(InstallStatus::AlreadyInstalled { version, available }) => {
+                run_report.push_success(&name, &version, PackageOutcome::AlreadyInstalled, &[]);
+                already_installed.push(name, version, available.found());
+            }
+        }
+    }
+
+    if !already_installed.is_empty() {
+        tracing::info!(
+            target: "hasp::output::informational::already_installed",
+            packages = %already_installed,
+            "Info the following packages are already installed:",
+        );
+    }
+
+    match failures.first() {
+        // Without --keep-going, the exit code is for the first failure.
+        Some((class, _, _)) if !keep_going => FAILURE_EXIT_CODE | class.exit_bit(),
+        Some(_) => report_failure_summary(&failures),
+        None if !already_installed.is_empty() && strict => ALREADY_INSTALLED_EXIT_CODE,
+        None => 0,
+    }
+}
+
+/// Logs the number of packages with each outcome, along with the results for each package in JSON
+/// output. If `print_results` is true, the result for each package is also printed to standard
+/// output, one line each, for scripts.
+fn report_summary(report: &RunReport, print_results: bool) {
+    if print_results {
+        for package in &report.packages {
+            println!("{}", package.result_line());
+        }
+    }
+    let counts = report.counts();
+    let total = counts.total();
+    if total == 0 {
+        return;
+    }
+    let status = counts.status();
+    let OutcomeCounts {
+        installed,
+        already_installed,
+        failed,
+        skipped,
+    } = counts;
+    let results = serde_json::to_string(&report.packages).expect("serializing results succeeds");
+    tracing::info!(
+        target: "hasp::output::summary",
+        %status,
+        total,
+        installed,
+        already_installed,
+        failed,
+        skipped,
+        results = %results,
+        "Finished {total} packages ({status}): {installed} installed, {already_installed} already \
+         installed, {failed} failed, {skipped} skipped",
+    );
+}
+
+/// Logs failures grouped by their cause, with one example of each, and returns the exit code.
+fn report_failure_summary(failures: &[(FailureClass, String, Report)]) -> i32 {
+    let mut by_class: BTreeMap<FailureClass, (usize, &str, &Report)> = BTreeMap::new();
+    for (class, name, report) in failures {
+        by_class.entry(*class).or_insert((0, name, report)).0 += 1;
+    }
+
+    let mut s = String::with_capacity(512);
+    let mut exit_code = FAILURE_EXIT_CODE;
+    for (idx, (class, (count, name, report))) in by_class.iter().enumerate() {
+        if idx > 0 {
+            s.push('\n');
+        }
+        s.push_str(&format!(
+            "* {} ({}), e.g. {}: {:#}",
+            class.name(),
+            count,
+            name,
+            report
+        ));
+        exit_code |= class.exit_bit();
+    }
+
+    let (count, causes) = (failures.len(), s);
+    if count == 1 {
+        tracing::error!(
+            target: "hasp::output::failure_summary_one",
+            %causes,
+            "Failed to install 1 package, by cause:\n{causes}",
+        );
+    } else {
+        tracing::error!(
+            target: "hasp::output::failure_summary",
+            count,
+            %causes,
+            "Failed to install {count} packages, by cause:\n{causes}",
+        );
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::directory::InstalledRow, test_helpers::TestHome};
+
+    #[test]
+    fn every_status_reported() {
+        let version: hasp_metadata::DirectoryVersion = "sem:1.0.0".parse().expect("valid version");
+        let statuses = || {
+            vec![
+                ("foo".to_owned(), Err(eyre!("foo failed"))),
+                (
+                    "bar".to_owned(),
+                    Ok(InstallStatus::Success {
+                        version: version.clone(),
+                        binaries: vec!["bar".to_owned()],
+                        replaced: None,
+                    }),
+                ),
+            ]
+        };
+
+        for keep_going in [false, true] {
+            let mut report = RunReport::new(vec![]);
+            let exit_code = report_statuses(statuses(), keep_going, false, &mut report);
+            assert!(
+                is_failure_exit_code(exit_code),
+                "{} is a failure",
+                exit_code
+            );
+            let lines: Vec<_> = report
+                .packages
+                .iter()
+                .map(|package| package.result_line())
+                .collect();
+            assert_eq!(
+                lines,
+                vec!["foo - failed", "bar 1.0.0 installed"],
+                "with keep_going = {}",
+                keep_going
+            );
+        }
+    }
+
+    #[test]
+    fn held_packages_left_alone() {
+        let test_home = TestHome::new();
+        let state = HaspState::load_or_init(test_home.hasp_home.clone()).expect("state loaded");
+        for (name, hash) in [("foo", 0x10), ("bar", 0x20), ("baz", 0x30)] {
+            let row = test_home.insert_install(name, "sem:1.0.0", hash, &[name]);
+            let install_path = test_home
+                .hasp_home
+                .install_path("cargo", name, row.package.hash);
+            fs::create_dir_all(install_path).expect("install directory created");
+        }
+        let foo = installed(&state, "foo").expect("foo is installed");
+        assert!(!foo.held);
+        state.set_held(&foo, true).expect("foo held");
+        assert!(installed(&state, "foo").expect("foo is installed").held);
+
+        // Syncing foo and bar skips foo, and pruning removes baz but not foo.
+        let manifest = Manifest::parse(
+            r#"
+            [packages.bar]
+            version = "1"
+            "#,
+        )
+        .expect("manifest parsed");
+        let requests = Manifest::parse(
+            r#"
+            [packages.foo]
+            version = "1"
+
+            [packages.bar]
+            version = "1"
+            "#,
+        )
+        .expect("manifest parsed")
+        .install_requests(&ManifestPlatform::current(false))
+        .expect("requests are valid")
+        .requests;
+        let mut report = RunReport::new(vec![]);
+        let to_install = skip_held(&state, requests, &mut report).expect("held packages skipped");
+        assert_eq!(
+            to_install
+                .iter()
+                .map(|request| request.name.as_str())
+                .collect::<Vec<_>>(),
+            ["bar"]
+        );
+        assert_eq!(
+            report
+                .packages
+                .iter()
+                .map(|package| package.result_line())
+                .collect::<Vec<_>>(),
+            ["foo - skipped"]
+        );
+
+        prune_unlisted(&state, &manifest).expect("unlisted packages pruned");
+        assert!(installed(&state, "baz").is_none());
+        assert!(installed(&state, "bar").is_some());
+
+        // Releasing the hold lets foo be pruned.
+        let foo = installed(&state, "foo").expect("foo is installed");
+        state.set_held(&foo, false).expect("foo released");
+        assert!(!installed(&state, "foo").expect("foo is installed").held);
+        prune_unlisted(&state, &manifest).expect("unlisted packages pruned");
+        assert!(installed(&state, "foo").is_none());
+    }
+
+    fn installed(state: &HaspState, name: &str) -> Option<InstalledRow> {
+        state
+            .installed_package("cargo", name)
+            .expect("install queried")
     }
 }
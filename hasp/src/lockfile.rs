@@ -0,0 +1,81 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Parsing the dependencies that packages were built with out of their lockfiles.
+
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+pub(crate) use hasp_metadata::LockedDependency;
+use serde::Deserialize;
+use std::fs;
+
+/// The name of Cargo's lockfile, which is kept in install directories.
+pub(crate) const CARGO_LOCK: &str = "Cargo.lock";
+
+/// Reads the dependencies out of a `Cargo.lock` file.
+///
+/// The package being built is listed in its own lockfile, so `root` is left out.
+pub(crate) fn read_cargo_lock(path: &Utf8Path, root: &str) -> Result<Vec<LockedDependency>> {
+    let contents = fs::read_to_string(path).wrap_err_with(|| format!("failed to read {}", path))?;
+    parse_cargo_lock(&contents, root).wrap_err_with(|| format!("failed to parse {}", path))
+}
+
+fn parse_cargo_lock(contents: &str, root: &str) -> Result<Vec<LockedDependency>> {
+    let lockfile: CargoLock = toml::from_str(contents)?;
+    Ok(lockfile
+        .package
+        .into_iter()
+        .filter(|package| !(package.name == root && package.source.is_none()))
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<LockedDependency>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use semver::Version;
+
+    #[test]
+    fn parse_cargo_lock_basic() {
+        let contents = r#"
+# This file is automatically @generated by Cargo.
+# It is not intended for manual editing.
+version = 3
+
+[[package]]
+name = "foo"
+version = "1.0.0"
+dependencies = [
+ "bar",
+ "baz",
+]
+
+[[package]]
+name = "bar"
+version = "0.2.1"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
+
+[[package]]
+name = "baz"
+version = "0.3.0"
+source = "git+https://github.com/example/baz#0123456789abcdef"
+"#;
+
+        let dependencies = parse_cargo_lock(contents, "foo").expect("valid lockfile");
+        assert_eq!(dependencies.len(), 2, "root package is left out");
+        assert_eq!(dependencies[0].name, "bar");
+        assert_eq!(dependencies[0].version, Version::new(0, 2, 1));
+        assert_eq!(
+            dependencies[0].checksum.as_deref(),
+            Some("0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(dependencies[1].name, "baz");
+        assert_eq!(dependencies[1].checksum, None);
+    }
+}
@@ -0,0 +1,724 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Manifests: declarative lists of packages, written by `hasp export` and installed by
+//! `hasp sync`.
+//!
+//! Projects can also declare the tools they need, which `hasp import-cargo-lock` reads into a
+//! manifest. Tools declared in a `.hasp.toml` are installed by `hasp env` into a root that belongs
+//! to the project, found by searching upwards from the current directory.
+//!
+//! Entries can be limited to some platforms with `os`, `target` and `when`, so that one manifest
+//! can be shared between different machines. Entries that don't apply are skipped.
+
+use crate::{
+    config::{package_key, split_package_key},
+    models::directory::InstalledRow,
+    ops::{default_binary_name, registry, Backend, BuildEnv},
+    platform::TARGET,
+    state::{InstallRequest, PackageMetadata},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use hasp_metadata::{
+    is_redacted_env_value, CargoDirectory, Checksum, DirectoryVersion, DirectoryVersionReq,
+    NpmDirectory, OciDirectory,
+};
+use semver::VersionReq;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs, io,
+};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Manifest {
+    /// Packages, keyed by name for Cargo packages and `namespace:name` otherwise.
+    #[serde(default)]
+    pub(crate) packages: BTreeMap<String, ManifestPackage>,
+}
+
+impl Manifest {
+    pub(crate) fn from_path(path: &Utf8Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read manifest at {}", path))?;
+        Self::parse(&contents).wrap_err_with(|| format!("failed to parse manifest at {}", path))
+    }
+
+    pub(crate) fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Reads the tools declared by a project, from `tools.toml` or the `[workspace.metadata.tools]`
+    /// or `[package.metadata.tools]` tables in `Cargo.toml`.
+    pub(crate) fn from_project(project_dir: &Utf8Path) -> Result<Self> {
+        let tools_path = project_dir.join(TOOLS_FILE_NAME);
+        if tools_path.exists() {
+            return Self::from_tools_path(&tools_path);
+        }
+
+        let cargo_toml_path = project_dir.join("Cargo.toml");
+        let contents = match fs::read_to_string(&cargo_toml_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => bail!(
+                "no tools declared in {} (expected {} or Cargo.toml)",
+                project_dir,
+                TOOLS_FILE_NAME,
+            ),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read {}", cargo_toml_path))
+            }
+        };
+        Self::parse_cargo_toml(&contents)
+            .wrap_err_with(|| format!("failed to parse tools in {}", cargo_toml_path))
+    }
+
+    /// Reads a file that lists tools in a `[tools]` table, like `tools.toml` or `.hasp.toml`.
+    pub(crate) fn from_tools_path(path: &Utf8Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).wrap_err_with(|| format!("failed to read {}", path))?;
+        Self::parse_tools_file(&contents)
+            .wrap_err_with(|| format!("failed to parse tools in {}", path))
+    }
+
+    /// Parses a `tools.toml` file, which lists tools in a `[tools]` table.
+    pub(crate) fn parse_tools_file(contents: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct ToolsFile {
+            tools: BTreeMap<String, ToolSpec>,
+        }
+
+        let tools_file: ToolsFile = toml::from_str(contents)?;
+        Ok(Self::from_tools(tools_file.tools))
+    }
+
+    /// Parses the tools listed in the `[workspace.metadata.tools]` or `[package.metadata.tools]`
+    /// table of a `Cargo.toml` file.
+    pub(crate) fn parse_cargo_toml(contents: &str) -> Result<Self> {
+        let cargo_toml: toml::Value = toml::from_str(contents)?;
+        let tools = ["workspace", "package"].iter().find_map(|section| {
+            cargo_toml
+                .get(section)
+                .and_then(|section| section.get("metadata"))
+                .and_then(|metadata| metadata.get("tools"))
+        });
+        let tools = match tools {
+            Some(tools) => tools.clone(),
+            None => bail!("no [workspace.metadata.tools] or [package.metadata.tools] table found"),
+        };
+
+        let tools: BTreeMap<String, ToolSpec> = tools.try_into()?;
+        Ok(Self::from_tools(tools))
+    }
+
+    fn from_tools(tools: BTreeMap<String, ToolSpec>) -> Self {
+        let packages = tools
+            .into_iter()
+            .map(|(key, spec)| {
+                let package = match spec {
+                    ToolSpec::Version(version) => ManifestPackage {
+                        version,
+                        ..Default::default()
+                    },
+                    ToolSpec::Detailed(package) => *package,
+                };
+                (key, package)
+            })
+            .collect();
+        Self { packages }
+    }
+
+    pub(crate) fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).wrap_err("failed to serialize manifest")
+    }
+
+    /// Creates a manifest from installed packages.
+    ///
+    /// Versions are pinned exactly, other than floating versions like image tags. If several
+    /// versions of a package are installed, the most recently installed one is used.
+    pub(crate) fn from_installed(installed: &[InstalledRow]) -> Result<Self> {
+        let mut packages = BTreeMap::new();
+        for row in installed {
+            let package = &row.directory_row.package;
+            let version = match &package.version {
+                DirectoryVersion::Semantic(version) => format!("={}", version),
+                DirectoryVersion::Literal(version) => version.clone(),
+                // Keep following the floating version, rather than pinning what it resolved to.
+                DirectoryVersion::Floating(version) => version.spec().to_owned(),
+            };
+            let mut manifest_package = ManifestPackage {
+                version,
+                ..Default::default()
+            };
+
+            let metadata = package.metadata.clone();
+            let parsed = match package.namespace.as_str() {
+                "cargo" => serde_json::from_value::<CargoDirectory>(metadata).map(|metadata| {
+                    if !metadata.default_features {
+                        manifest_package.default_features = Some(false);
+                    }
+                    manifest_package.features = metadata.features;
+                    manifest_package.profile = metadata.profile;
+                    manifest_package.registry = metadata.registry;
+                    manifest_package.bins = metadata.bins;
+                    if metadata.examples {
+                        manifest_package.examples = Some(true);
+                    }
+                    // Values of environment variables aren't recorded, so they can't be exported.
+                    let (redacted, env): (BTreeMap<_, _>, BTreeMap<_, _>) = metadata
+                        .env
+                        .into_iter()
+                        .partition(|(_, value)| is_redacted_env_value(value));
+                    if !redacted.is_empty() {
+                        let package = package_key(&package.namespace, &package.name);
+                        let vars = redacted.into_keys().collect::<Vec<_>>().join(", ");
+                        tracing::warn!(
+                            target: "hasp::output::export_env_redacted",
+                            %package,
+                            %vars,
+                            "Warning {package} was built with {vars} set, but their values aren't \
+                             recorded (hint: add them to its env table in the manifest)",
+                        );
+                    }
+                    manifest_package.env = env;
+                    manifest_package.cargo_config = metadata.config;
+                    manifest_package.cargo_args = metadata.args;
+                }),
+                "npm" => serde_json::from_value::<NpmDirectory>(metadata).map(|metadata| {
+                    if metadata.ignore_scripts {
+                        manifest_package.ignore_scripts = Some(true);
+                    }
+                }),
+                _ => Ok(()),
+            };
+            parsed.wrap_err_with(|| {
+                format!(
+                    "failed to parse metadata for {}",
+                    row.directory_row.to_friendly()
+                )
+            })?;
+
+            packages.insert(
+                package_key(&package.namespace, &package.name),
+                manifest_package,
+            );
+        }
+
+        Ok(Self { packages })
+    }
+
+    /// Returns true if the manifest has an entry for this package.
+    pub(crate) fn contains(&self, namespace: &str, name: &str) -> bool {
+        self.packages.contains_key(&package_key(namespace, name))
+    }
+
+    /// Returns install requests for the packages in the manifest that apply to `platform`, along
+    /// with the entries that were skipped.
+    pub(crate) fn install_requests(&self, platform: &ManifestPlatform) -> Result<ManifestRequests> {
+        let mut requests = ManifestRequests::default();
+        for (key, package) in &self.packages {
+            let request = package
+                .to_request(key)
+                .wrap_err_with(|| format!("invalid manifest entry for {}", key))?;
+            match package.skip_reason(platform) {
+                Some(reason) => requests.skipped.push((key.clone(), reason)),
+                None => requests.requests.push(request),
+            }
+        }
+        Ok(requests)
+    }
+}
+
+/// The install requests for a manifest.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ManifestRequests {
+    pub(crate) requests: Vec<InstallRequest>,
+    /// Entries that don't apply to this platform, along with the reason why.
+    pub(crate) skipped: Vec<(String, String)>,
+}
+
+/// The platform that manifest entries are filtered against.
+#[derive(Clone, Debug)]
+pub(crate) struct ManifestPlatform {
+    /// The operating system, as in [`std::env::consts::OS`].
+    pub(crate) os: String,
+    /// The target triple.
+    pub(crate) target: String,
+    /// Whether hasp is running in CI.
+    pub(crate) ci: bool,
+}
+
+impl ManifestPlatform {
+    /// Returns the platform hasp is running on. hasp is running in CI if `ci` is true, or if the
+    /// `CI` environment variable is set as it is by most CI providers.
+    pub(crate) fn current(ci: bool) -> Self {
+        let ci_env = env::var("CI").unwrap_or_default();
+        Self {
+            os: env::consts::OS.to_owned(),
+            target: TARGET.to_owned(),
+            ci: ci || !matches!(ci_env.as_str(), "" | "0" | "false"),
+        }
+    }
+}
+
+/// When a manifest entry applies.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ManifestCondition {
+    /// Only in CI.
+    Ci,
+    /// Only outside of CI.
+    Local,
+}
+
+/// The name of the file that declares a project's tools, if they aren't declared in `Cargo.toml`.
+const TOOLS_FILE_NAME: &str = "tools.toml";
+
+/// The name of the file that declares the tools `hasp env` installs for a project.
+pub(crate) const PROJECT_MANIFEST_FILE_NAME: &str = ".hasp.toml";
+
+/// The name of the directory next to `.hasp.toml` that a project's tools are installed into.
+pub(crate) const PROJECT_ROOT_DIR_NAME: &str = ".hasp";
+
+/// Finds the nearest `.hasp.toml` in a directory or its ancestors.
+pub(crate) fn find_project_manifest(dir: &Utf8Path) -> Option<Utf8PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_MANIFEST_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// A tool declared by a project: either a version, or a full manifest entry.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum ToolSpec {
+    Version(String),
+    Detailed(Box<ManifestPackage>),
+}
+
+/// A single package in a manifest.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ManifestPackage {
+    /// The version requirement, or the tag or digest for container images.
+    pub(crate) version: String,
+
+    /// The expected checksum of the fetched artifact.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) checksum: Option<Checksum>,
+
+    /// Whether to activate default features (Cargo only). Defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) default_features: Option<bool>,
+
+    /// Features to activate (Cargo only).
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) features: BTreeSet<String>,
+
+    /// The Cargo profile to build with (Cargo only). Defaults to `release`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) profile: Option<String>,
+
+    /// The registry index URL to install from (Cargo only). Defaults to crates.io.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) registry: Option<String>,
+
+    /// The binaries to install (Cargo only). Defaults to all binaries in the package.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) bins: BTreeSet<String>,
+
+    /// Whether to install examples as well as binaries (Cargo only). Defaults to false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) examples: Option<bool>,
+
+    /// Environment variables to set for the build that affect the built artifacts, e.g.
+    /// `RUSTFLAGS` (Cargo only).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) env: BTreeMap<String, String>,
+
+    /// Values to pass to Cargo with `--config` (Cargo only).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) cargo_config: Vec<String>,
+
+    /// Extra arguments to pass to `cargo build` (Cargo only).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) cargo_args: Vec<String>,
+
+    /// Whether to skip package lifecycle scripts (npm only). Defaults to false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ignore_scripts: Option<bool>,
+
+    /// The operating systems to install on, e.g. `linux` or `macos`. Defaults to all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) os: Vec<String>,
+
+    /// The target triples to install on, with `*` matching any characters, e.g. `aarch64-*`.
+    /// Defaults to all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) target: Option<String>,
+
+    /// Whether to install only in CI (`ci`) or only outside of it (`local`). Defaults to both.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) when: Option<ManifestCondition>,
+}
+
+impl ManifestPackage {
+    fn to_request(&self, key: &str) -> Result<InstallRequest> {
+        let (namespace, name) = split_package_key(key);
+        if namespace != "cargo"
+            && (self.default_features.is_some()
+                || !self.features.is_empty()
+                || self.profile.is_some()
+                || self.registry.is_some()
+                || !self.bins.is_empty()
+                || self.examples.is_some()
+                || !self.env.is_empty()
+                || !self.cargo_config.is_empty()
+                || !self.cargo_args.is_empty())
+        {
+            bail!("default-features, features, profile, registry, bins, examples, env, cargo-config and cargo-args are only supported for Cargo packages");
+        }
+        if let Some(name) = self
+            .env
+            .keys()
+            .find(|name| !BuildEnv::affects_artifacts(name))
+        {
+            bail!(
+                "{} doesn't affect the built artifacts, so it can't be set for a single package \
+                 (hint: set it in [install.env] in the config file)",
+                name
+            );
+        }
+        if namespace != "npm" && self.ignore_scripts.is_some() {
+            bail!("ignore-scripts is only supported for npm packages");
+        }
+
+        let (req, metadata) = match registry().get(namespace) {
+            Some(Backend::Cargo) => (
+                self.semver_req()?,
+                PackageMetadata::Cargo(CargoDirectory {
+                    default_features: self.default_features.unwrap_or(true),
+                    features: self.features.clone(),
+                    profile: self.profile.clone(),
+                    registry: self.registry.clone(),
+                    bins: self.bins.clone(),
+                    examples: self.examples.unwrap_or(false),
+                    env: self.env.clone(),
+                    config: self.cargo_config.clone(),
+                    args: self.cargo_args.clone(),
+                    path: None,
+                }),
+            ),
+            Some(Backend::Npm) => (
+                self.semver_req()?,
+                PackageMetadata::Npm(NpmDirectory {
+                    ignore_scripts: self.ignore_scripts.unwrap_or(false),
+                }),
+            ),
+            Some(Backend::Oci) => (
+                DirectoryVersionReq::new(self.version.clone()),
+                PackageMetadata::Oci(OciDirectory {
+                    binary: default_binary_name(name)?,
+                }),
+            ),
+            // Plugins may not use semantic versions, so their requirements are passed on as is.
+            Some(Backend::Plugin(backend)) => (
+                DirectoryVersionReq::new(self.version.clone()),
+                PackageMetadata::Plugin(backend),
+            ),
+            None => return Err(registry().unknown_namespace(namespace)),
+        };
+
+        Ok(InstallRequest {
+            name: name.to_owned(),
+            req,
+            metadata,
+            checksum: self.checksum.clone(),
+            update: false,
+            explicit: true,
+            force_bin_overwrite: false,
+            retries: 0,
+        })
+    }
+
+    /// Returns why this entry doesn't apply to `platform`, or `None` if it does.
+    fn skip_reason(&self, platform: &ManifestPlatform) -> Option<String> {
+        if !self.os.is_empty() && !self.os.contains(&platform.os) {
+            return Some(format!(
+                "os {} is not one of {}",
+                platform.os,
+                self.os.join(", ")
+            ));
+        }
+        if let Some(target) = &self.target {
+            if !wildcard_match(target, &platform.target) {
+                return Some(format!(
+                    "target {} doesn't match {}",
+                    platform.target, target
+                ));
+            }
+        }
+        match (self.when, platform.ci) {
+            (Some(ManifestCondition::Ci), false) => Some("only installed in CI".to_owned()),
+            (Some(ManifestCondition::Local), true) => {
+                Some("only installed outside of CI".to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Parses the version as a semver requirement, unless it's a literal version like
+    /// `lit:1.0.0+build.5`.
+    fn semver_req(&self) -> Result<DirectoryVersionReq> {
+        if self.version.starts_with(DirectoryVersion::LIT_PREFIX) {
+            return Ok(DirectoryVersionReq::new(self.version.clone()));
+        }
+        let req = self
+            .version
+            .parse::<VersionReq>()
+            .wrap_err_with(|| format!("failed to parse version req {}", self.version))?;
+        Ok(req.into())
+    }
+}
+
+/// Returns true if `text` matches `pattern`, where `*` in the pattern matches any characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always returns at least one part.
+    let first = parts.next().expect("at least one part");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No wildcards.
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_roundtrip() {
+        let manifest = Manifest::parse(
+            r#"
+            [packages.ripgrep]
+            version = "=13.0.0"
+            default-features = false
+            features = ["pcre2"]
+            cargo-args = ["--locked"]
+
+            [packages.cargo-hakari]
+            version = "0.9"
+            env = { RUSTFLAGS = "-C target-cpu=native" }
+
+            [packages."npm:prettier"]
+            version = "=2.4.1"
+            ignore-scripts = true
+
+            [packages."oci:hadolint/hadolint"]
+            version = "v2.8.0"
+            "#,
+        )
+        .expect("manifest parsed");
+
+        let requests = manifest
+            .install_requests(&test_platform())
+            .expect("requests are valid")
+            .requests;
+        let namespaces: Vec<_> = requests
+            .iter()
+            .map(|request| (request.namespace(), request.name.as_str()))
+            .collect();
+        assert_eq!(
+            namespaces,
+            vec![
+                ("cargo", "cargo-hakari"),
+                ("npm", "prettier"),
+                ("oci", "hadolint/hadolint"),
+                ("cargo", "ripgrep"),
+            ]
+        );
+        assert_eq!(requests[2].req.as_str(), "v2.8.0");
+        assert!(manifest.contains("oci", "hadolint/hadolint"));
+        assert!(!manifest.contains("cargo", "prettier"));
+
+        let roundtrip = Manifest::parse(&manifest.to_toml().expect("manifest serialized"))
+            .expect("serialized manifest parsed");
+        assert_eq!(roundtrip.packages, manifest.packages);
+
+        let invalid = Manifest::parse(
+            r#"
+            [packages."npm:prettier"]
+            version = "2"
+            features = ["foo"]
+            "#,
+        )
+        .expect("manifest parsed");
+        assert!(
+            invalid.install_requests(&test_platform()).is_err(),
+            "features are only valid for Cargo packages"
+        );
+    }
+
+    #[test]
+    fn project_tools() {
+        let cargo_toml = Manifest::parse_cargo_toml(
+            r#"
+            [workspace]
+            members = ["foo"]
+
+            [workspace.metadata.tools]
+            cargo-hakari = "=0.9.6"
+            cargo-nextest = { version = "0.9", default-features = false }
+            "npm:prettier" = "=2.4.1"
+            "#,
+        )
+        .expect("tools parsed");
+        let hakari = &cargo_toml.packages["cargo-hakari"];
+        assert_eq!(hakari.version, "=0.9.6");
+        assert_eq!(hakari.default_features, None);
+        assert_eq!(
+            cargo_toml.packages["cargo-nextest"].default_features,
+            Some(false)
+        );
+        assert_eq!(
+            cargo_toml
+                .install_requests(&test_platform())
+                .expect("requests are valid")
+                .requests
+                .len(),
+            3
+        );
+
+        let tools_file = Manifest::parse_tools_file(
+            r#"
+            [tools]
+            ripgrep = "13"
+            "#,
+        )
+        .expect("tools parsed");
+        assert_eq!(tools_file.packages["ripgrep"].version, "13");
+
+        assert!(
+            Manifest::parse_cargo_toml("[package]\nname = \"foo\"").is_err(),
+            "Cargo.toml without tools is an error"
+        );
+
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let nested = dir.join("src/bin");
+        fs::create_dir_all(&nested).expect("nested dir created");
+        assert_eq!(find_project_manifest(&nested), None);
+        let manifest_path = dir.join(PROJECT_MANIFEST_FILE_NAME);
+        fs::write(&manifest_path, "[tools]\nripgrep = \"13\"\n").expect("manifest written");
+        assert_eq!(find_project_manifest(&nested), Some(manifest_path.clone()));
+        let project = Manifest::from_tools_path(&manifest_path).expect("tools parsed");
+        assert_eq!(project.packages["ripgrep"].version, "13");
+    }
+
+    #[test]
+    fn platform_filters() {
+        let manifest = Manifest::parse(
+            r#"
+            [packages.everywhere]
+            version = "1"
+
+            [packages.linux-only]
+            version = "1"
+            os = ["linux"]
+
+            [packages.mac-or-windows]
+            version = "1"
+            os = ["macos", "windows"]
+
+            [packages.arm]
+            version = "1"
+            target = "aarch64-*"
+
+            [packages.gnu]
+            version = "1"
+            target = "*-linux-gnu"
+
+            [packages.ci-only]
+            version = "1"
+            when = "ci"
+
+            [packages.local-only]
+            version = "1"
+            when = "local"
+            "#,
+        )
+        .expect("manifest parsed");
+
+        let requests = manifest
+            .install_requests(&test_platform())
+            .expect("requests are valid");
+        let names: Vec<_> = requests
+            .requests
+            .iter()
+            .map(|request| request.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["everywhere", "gnu", "linux-only", "local-only"]);
+        let skipped: Vec<_> = requests
+            .skipped
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect();
+        assert_eq!(skipped, vec!["arm", "ci-only", "mac-or-windows"]);
+
+        let ci_platform = ManifestPlatform {
+            ci: true,
+            ..test_platform()
+        };
+        let requests = manifest
+            .install_requests(&ci_platform)
+            .expect("requests are valid");
+        assert!(requests
+            .requests
+            .iter()
+            .any(|request| request.name == "ci-only"));
+        assert!(requests
+            .skipped
+            .iter()
+            .any(|(key, reason)| key == "local-only" && reason.contains("outside of CI")));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("aarch64-*", "aarch64-apple-darwin"));
+        assert!(wildcard_match("*-linux-*", "x86_64-unknown-linux-musl"));
+        assert!(wildcard_match(
+            "x86_64-pc-windows-msvc",
+            "x86_64-pc-windows-msvc"
+        ));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("x86_64-*", "aarch64-unknown-linux-gnu"));
+        assert!(!wildcard_match("*-gnu", "x86_64-unknown-linux-musl"));
+        assert!(!wildcard_match("a*b*b", "ab"));
+        assert!(!wildcard_match("x86_64", "x86_64-unknown-linux-gnu"));
+    }
+
+    fn test_platform() -> ManifestPlatform {
+        ManifestPlatform {
+            os: "linux".to_owned(),
+            target: "x86_64-unknown-linux-gnu".to_owned(),
+            ci: false,
+        }
+    }
+}
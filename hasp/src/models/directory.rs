@@ -1,9 +1,22 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+use crate::{
+    models::install_dependency::InstallDependencyRow,
+    ops::SignatureVerification,
+    provenance::Provenance,
+    receipt::{InstallReceipt, ReceiptFile},
+};
+use camino::Utf8PathBuf;
+use chrono::{DateTime, Local};
 use color_eyre::{eyre::WrapErr, Result};
-use hasp_metadata::{DirectoryVersion, FileHash, PackageDirectory};
-use rusqlite::{named_params, params, Connection, Row, Transaction};
+use hasp_metadata::{
+    CargoBuildMetadata, DirectoryHash, DirectoryVersion, DirectoryVersionReq, FileHash,
+    InstallInfo, InstalledFile, InstalledPackage, PackageDirectory,
+};
+use rusqlite::{
+    named_params, params, types::ToSql, Connection, OptionalExtension, Row, Transaction,
+};
 use std::collections::BTreeMap;
 
 /// Per-directory information stored in the database.
@@ -14,6 +27,10 @@ pub(crate) struct DirectoryRow {
 }
 
 impl DirectoryRow {
+    // The number of installed files inserted per statement, which keeps the number of parameters
+    // well under the limit of 999 in older versions of SQLite.
+    const FILES_PER_INSERT: usize = 100;
+
     pub(crate) fn all_matches_for(
         namespace: &str,
         name: &str,
@@ -75,6 +92,78 @@ impl DirectoryRow {
             .wrap_err("failed to collect matches")
     }
 
+    /// Returns every directory that isn't installed, e.g. because installing it failed, ordered by
+    /// namespace and name.
+    pub(crate) fn all_not_installed(conn: &Connection) -> Result<Vec<Self>> {
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT directory_id, namespace, name, hash, version, metadata \
+                FROM packages.directories WHERE NOT installed \
+                ORDER BY namespace, name, directory_id",
+            )
+            .wrap_err("failed to prepare statement for directories that aren't installed")?;
+        let rows = stmt
+            .query_and_then([], Self::from_row)
+            .wrap_err("failed to query directories that aren't installed")?;
+        rows.collect::<rusqlite::Result<Vec<Self>>>()
+            .wrap_err("failed to collect directories that aren't installed")
+    }
+
+    /// Gets the row for the directory with this hash, if there is one.
+    pub(crate) fn get(
+        namespace: &str,
+        name: &str,
+        hash: DirectoryHash,
+        conn: &Connection,
+    ) -> Result<Option<Self>> {
+        conn.query_row(
+            "SELECT directory_id, namespace, name, hash, version, metadata \
+            FROM packages.directories \
+            WHERE namespace = :namespace AND name == :name AND hash == :hash",
+            named_params! {
+                ":namespace": namespace,
+                ":name": name,
+                ":hash": hash,
+            },
+            Self::from_row,
+        )
+        .optional()
+        .wrap_err_with(|| {
+            format!(
+                "failed to get row for {}:{} (hash {})",
+                namespace, name, hash
+            )
+        })
+    }
+
+    /// Inserts a new row for this directory, and returns it.
+    pub(crate) fn insert(
+        package: &PackageDirectory,
+        installed: bool,
+        txn: &Transaction,
+    ) -> Result<Self> {
+        txn.query_row_and_then(
+            "INSERT INTO packages.directories (namespace, name, hash, version, metadata, installed) \
+                VALUES (:namespace, :name, :hash, :version, :metadata, :installed)\
+                RETURNING directory_id, namespace, name, hash, version, metadata",
+            named_params! {
+                ":namespace": package.namespace,
+                ":name": package.name,
+                ":hash": package.hash,
+                ":version": package.version,
+                ":metadata": package.metadata,
+                ":installed": installed,
+            },
+            Self::from_row,
+        )
+        .wrap_err_with(|| {
+            format!(
+                "failed to insert row for {}:{} (version {}, hash {})",
+                package.namespace, package.name, package.version, package.hash,
+            )
+        })
+    }
+
     pub(crate) fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
         let directory_id = row.get("directory_id")?;
         let namespace = row.get("namespace")?;
@@ -112,6 +201,284 @@ impl DirectoryRow {
         .wrap_err_with(|| format!("failed to get installed state for {}", self.to_friendly()))
     }
 
+    /// Returns true if this directory was requested directly, rather than being installed for
+    /// another package.
+    pub(crate) fn get_explicit(&self, conn: &Connection) -> Result<bool> {
+        conn.query_row(
+            "SELECT explicit FROM packages.directories WHERE directory_id = ?1",
+            [self.directory_id],
+            |row| row.get("explicit"),
+        )
+        .wrap_err_with(|| format!("failed to get explicit state for {}", self.to_friendly()))
+    }
+
+    /// Sets whether this directory was requested directly.
+    pub(crate) fn set_explicit(&self, txn: &Transaction, explicit: bool) -> Result<()> {
+        txn.execute(
+            "UPDATE packages.directories SET explicit = ?1 WHERE directory_id = ?2",
+            params![explicit, self.directory_id],
+        )
+        .wrap_err_with(|| format!("failed to set explicit state for {}", self.to_friendly()))?;
+        Ok(())
+    }
+
+    /// Sets whether this directory is held at its version.
+    pub(crate) fn set_held(&self, txn: &Transaction, held: bool) -> Result<()> {
+        txn.execute(
+            "UPDATE packages.directories SET held = ?1 WHERE directory_id = ?2",
+            params![held, self.directory_id],
+        )
+        .wrap_err_with(|| format!("failed to set held state for {}", self.to_friendly()))?;
+        Ok(())
+    }
+
+    /// Returns the version requirement this directory was resolved from, if it was recorded.
+    pub(crate) fn get_req(&self, conn: &Connection) -> Result<Option<DirectoryVersionReq>> {
+        let req: Option<String> = conn
+            .query_row(
+                "SELECT req FROM packages.directories WHERE directory_id = ?1",
+                [self.directory_id],
+                |row| row.get("req"),
+            )
+            .wrap_err_with(|| format!("failed to get requirement for {}", self.to_friendly()))?;
+        Ok(req.map(DirectoryVersionReq::new))
+    }
+
+    /// Records the version requirement this directory was resolved from.
+    pub(crate) fn set_req(&self, txn: &Transaction, req: &DirectoryVersionReq) -> Result<()> {
+        txn.execute(
+            "UPDATE packages.directories SET req = ?1 WHERE directory_id = ?2",
+            params![req.as_str(), self.directory_id],
+        )
+        .wrap_err_with(|| format!("failed to set requirement for {}", self.to_friendly()))?;
+        Ok(())
+    }
+
+    /// Returns who or what requested this directory, if it was recorded.
+    pub(crate) fn get_provenance(&self, conn: &Connection) -> Result<Option<Provenance>> {
+        let provenance: Option<String> = conn
+            .query_row(
+                "SELECT provenance FROM packages.directories WHERE directory_id = ?1",
+                [self.directory_id],
+                |row| row.get("provenance"),
+            )
+            .wrap_err_with(|| format!("failed to get provenance for {}", self.to_friendly()))?;
+        provenance
+            .map(|provenance| Provenance::from_json(&provenance))
+            .transpose()
+    }
+
+    /// Records who or what requested this directory.
+    pub(crate) fn set_provenance(&self, txn: &Transaction, provenance: &Provenance) -> Result<()> {
+        txn.execute(
+            "UPDATE packages.directories SET provenance = ?1 WHERE directory_id = ?2",
+            params![provenance.to_json(), self.directory_id],
+        )
+        .wrap_err_with(|| format!("failed to set provenance for {}", self.to_friendly()))?;
+        Ok(())
+    }
+
+    /// Deletes all installs for this row, along with their installed files and dependencies.
+    pub(crate) fn delete_installs(&self, txn: &Transaction) -> Result<()> {
+        InstallDependencyRow::delete_for_directory(self.directory_id, txn)?;
+        txn.execute(
+            "DELETE FROM packages.installed_files WHERE install_id IN \
+                (SELECT install_id FROM packages.installed WHERE directory_id = ?1)",
+            [self.directory_id],
+        )
+        .wrap_err_with(|| {
+            format!(
+                "failed to delete installed files for {}",
+                self.to_friendly()
+            )
+        })?;
+        txn.execute(
+            "DELETE FROM packages.installed WHERE directory_id = ?1",
+            [self.directory_id],
+        )
+        .wrap_err_with(|| format!("failed to delete installs for {}", self.to_friendly()))?;
+        Ok(())
+    }
+
+    /// Deletes the installed files recorded for earlier installs of this directory that aren't in
+    /// `files`, returning the names of the binaries among them.
+    pub(crate) fn delete_stale_files(
+        &self,
+        files: &BTreeMap<String, ReceiptFile>,
+        txn: &Transaction,
+    ) -> Result<Vec<String>> {
+        let mut stmt = txn
+            .prepare_cached(
+                "SELECT name, MAX(is_binary) as is_binary FROM packages.installed_files \
+                WHERE install_id IN \
+                    (SELECT install_id FROM packages.installed WHERE directory_id = ?1) \
+                GROUP BY name ORDER BY name",
+            )
+            .wrap_err("failed to prepare statement for installed files")?;
+        let recorded = stmt
+            .query_map([self.directory_id], |row| {
+                Ok((
+                    row.get::<_, String>("name")?,
+                    row.get::<_, bool>("is_binary")?,
+                ))
+            })
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .wrap_err_with(|| {
+                format!("failed to get installed files for {}", self.to_friendly())
+            })?;
+
+        let mut delete_stmt = txn
+            .prepare_cached(
+                "DELETE FROM packages.installed_files WHERE name = ?1 AND install_id IN \
+                    (SELECT install_id FROM packages.installed WHERE directory_id = ?2)",
+            )
+            .wrap_err("failed to prepare statement for deleting installed files")?;
+        let mut stale_binaries = vec![];
+        for (name, is_binary) in recorded {
+            if files.contains_key(&name) {
+                continue;
+            }
+            delete_stmt
+                .execute(params![name, self.directory_id])
+                .wrap_err_with(|| {
+                    format!(
+                        "for {}, failed to delete {} from packages.installed_files",
+                        self.to_friendly(),
+                        name,
+                    )
+                })?;
+            if is_binary {
+                stale_binaries.push(name);
+            }
+        }
+        Ok(stale_binaries)
+    }
+
+    /// Returns the namespaces and names of the other installed packages that have a binary named
+    /// `binary`. Other versions of this package aren't included, since they share its binaries.
+    pub(crate) fn binary_owners(
+        &self,
+        binary: &str,
+        txn: &Transaction,
+    ) -> Result<Vec<(String, String)>> {
+        let mut stmt = txn
+            .prepare_cached(
+                "SELECT DISTINCT namespace, packages.directories.name as name \
+                FROM packages.installed_files \
+                INNER JOIN packages.installed USING (install_id) \
+                INNER JOIN packages.directories USING (directory_id) \
+                WHERE installed AND is_binary AND packages.installed_files.name = :binary \
+                    AND NOT (namespace = :namespace AND packages.directories.name = :name) \
+                ORDER BY namespace, name",
+            )
+            .wrap_err("failed to prepare statement for binary owners")?;
+        let rows = stmt
+            .query_map(
+                named_params! {
+                    ":binary": binary,
+                    ":namespace": self.package.namespace,
+                    ":name": self.package.name,
+                },
+                |row| Ok((row.get("namespace")?, row.get("name")?)),
+            )
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .wrap_err_with(|| format!("failed to get packages with binary {}", binary))?;
+        Ok(rows)
+    }
+
+    /// Records a new install of this directory, with the metadata, files and dependencies in the
+    /// receipt.
+    ///
+    /// This makes it the most recent install of the package.
+    pub(crate) fn insert_install(
+        &self,
+        receipt: &InstallReceipt,
+        txn: &Transaction,
+    ) -> Result<i64> {
+        let install_id: i64 = txn
+            .query_row(
+                "INSERT INTO packages.installed (directory_id, install_time, metadata) \
+                VALUES (:directory_id, :install_time, :metadata) \
+                RETURNING install_id",
+                named_params! {
+                    ":directory_id": self.directory_id,
+                    ":install_time": receipt.install_time,
+                    ":metadata": &receipt.metadata,
+                },
+                |row| row.get("install_id"),
+            )
+            .wrap_err_with(|| {
+                format!("failed to add {} to packages.installed", self.to_friendly())
+            })?;
+
+        // Packages can have thousands of files, so they're inserted several rows at a time.
+        let files: Vec<_> = receipt.files.iter().collect();
+        for chunk in files.chunks(Self::FILES_PER_INSERT) {
+            let sql = format!(
+                "INSERT INTO packages.installed_files (install_id, name, hash, metadata, is_binary) \
+                VALUES {}",
+                vec!["(?, ?, ?, ?, ?)"; chunk.len()].join(", "),
+            );
+            let mut values: Vec<&dyn ToSql> = Vec::with_capacity(chunk.len() * 5);
+            for (name, file) in chunk {
+                values.extend([
+                    &install_id as &dyn ToSql,
+                    name,
+                    &file.hash,
+                    &file.metadata,
+                    &file.is_binary,
+                ]);
+            }
+            // All but the last chunk have the same number of rows, so the statement is reused.
+            let mut stmt = txn
+                .prepare_cached(&sql)
+                .wrap_err("failed to prepare statement for installed files")?;
+            stmt.execute(values.as_slice()).wrap_err_with(|| {
+                format!(
+                    "for {}, failed to insert {} to packages.installed_files",
+                    self.to_friendly(),
+                    chunk
+                        .iter()
+                        .map(|(name, _)| name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            })?;
+        }
+        InstallDependencyRow::insert_all(install_id, &receipt.dependencies, txn)?;
+        Ok(install_id)
+    }
+
+    /// Returns true if this directory has any installs recorded.
+    pub(crate) fn has_installs(&self, conn: &Connection) -> Result<bool> {
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM packages.installed WHERE directory_id = ?1)",
+            [self.directory_id],
+            |row| row.get(0),
+        )
+        .wrap_err_with(|| format!("failed to get installs for {}", self.to_friendly()))
+    }
+
+    /// Returns true if this directory is kept as a previous install, to roll back to.
+    pub(crate) fn is_previous_install(&self, conn: &Connection) -> Result<bool> {
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM packages.previous_installs WHERE directory_id = ?1)",
+            [self.directory_id],
+            |row| row.get(0),
+        )
+        .wrap_err_with(|| format!("failed to get previous install of {}", self.to_friendly()))
+    }
+
+    /// Deletes this row. It must have no installs, and mustn't be kept as a previous install.
+    pub(crate) fn delete(&self, txn: &Transaction) -> Result<()> {
+        txn.execute(
+            "DELETE FROM packages.directories WHERE directory_id = ?1",
+            [self.directory_id],
+        )
+        .wrap_err_with(|| format!("failed to delete row for {}", self.to_friendly()))?;
+        Ok(())
+    }
+
     /// Sets a new installed state for this row.
     pub(crate) fn set_installed(&self, txn: &Transaction, installed: bool) -> Result<()> {
         txn.execute(
@@ -128,6 +495,9 @@ impl DirectoryRow {
 pub(crate) struct InstalledRow {
     pub(crate) directory_row: DirectoryRow,
     pub(crate) install_id: i64,
+    /// Whether the directory is held at its version through `hasp hold`, as of when this was read.
+    pub(crate) held: bool,
+    install_time: DateTime<Local>,
     install_metadata: serde_json::Value,
     binaries: BTreeMap<String, InstalledFileRow>,
 }
@@ -142,18 +512,163 @@ impl InstalledRow {
             .wrap_err_with(|| format!("failed to get install data for {}:{}", namespace, name))
     }
 
+    /// Returns the most recent install for every installed directory, across all namespaces.
+    pub(crate) fn all_installed(conn: &Connection) -> Result<Vec<Self>> {
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT \
+                    packages.directories.directory_id as directory_id, \
+                    namespace, name, hash, version, \
+                    packages.directories.metadata as metadata, held, \
+                    MAX(install_id) as install_id, install_time, \
+                    packages.installed.metadata as install_metadata \
+                FROM packages.directories \
+                INNER JOIN packages.installed USING (directory_id) \
+                WHERE installed \
+                GROUP BY directory_id \
+                ORDER BY namespace, name, install_id",
+            )
+            .wrap_err("failed to prepare statement for installed packages")?;
+        let rows = stmt
+            .query_and_then([], |row| Self::from_row(conn, row))
+            .wrap_err("failed to query installed packages")?;
+        rows.collect::<rusqlite::Result<Vec<Self>>>()
+            .wrap_err("failed to collect installed packages")
+    }
+
+    /// Returns the most recent install for every installed directory of a package, oldest first.
+    pub(crate) fn all_installed_for(
+        namespace: &str,
+        name: &str,
+        conn: &Connection,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT \
+                    packages.directories.directory_id as directory_id, \
+                    namespace, name, hash, version, \
+                    packages.directories.metadata as metadata, held, \
+                    MAX(install_id) as install_id, install_time, \
+                    packages.installed.metadata as install_metadata \
+                FROM packages.directories \
+                INNER JOIN packages.installed USING (directory_id) \
+                WHERE installed AND namespace == :namespace AND name == :name \
+                GROUP BY directory_id \
+                ORDER BY install_id",
+            )
+            .wrap_err("failed to prepare statement for installed packages")?;
+        let rows = stmt
+            .query_and_then(
+                named_params! {
+                    ":namespace": namespace,
+                    ":name": name,
+                },
+                |row| Self::from_row(conn, row),
+            )
+            .wrap_err_with(|| format!("failed to query installs of {}:{}", namespace, name))?;
+        rows.collect::<rusqlite::Result<Vec<Self>>>()
+            .wrap_err_with(|| format!("failed to collect installs of {}:{}", namespace, name))
+    }
+
+    /// Records a new install of this directory, with the same metadata, files and dependencies as
+    /// this one.
+    ///
+    /// This makes this the most recent install of the package.
+    pub(crate) fn copy_install(&self, txn: &Transaction) -> Result<i64> {
+        let install_id: i64 = txn
+            .query_row(
+                "INSERT INTO packages.installed (directory_id, install_time, metadata) \
+                SELECT directory_id, :install_time, metadata FROM packages.installed \
+                WHERE install_id = :install_id \
+                RETURNING install_id",
+                named_params! {
+                    ":install_time": Local::now(),
+                    ":install_id": self.install_id,
+                },
+                |row| row.get("install_id"),
+            )
+            .wrap_err_with(|| {
+                format!(
+                    "failed to add {} to packages.installed",
+                    self.directory_row.to_friendly()
+                )
+            })?;
+        txn.execute(
+            "INSERT INTO packages.installed_files (install_id, name, hash, metadata, is_binary) \
+            SELECT ?1, name, hash, metadata, is_binary FROM packages.installed_files \
+            WHERE install_id = ?2",
+            params![install_id, self.install_id],
+        )
+        .wrap_err_with(|| {
+            format!(
+                "failed to copy installed files for {}",
+                self.directory_row.to_friendly()
+            )
+        })?;
+        InstallDependencyRow::copy(self.install_id, install_id, txn)?;
+        Ok(install_id)
+    }
+
+    /// Returns how the signature on the package was verified when it was installed, if it was.
+    pub(crate) fn signature(&self) -> Option<SignatureVerification> {
+        serde_json::from_value(self.install_metadata.get("signature")?.clone()).ok()
+    }
+
+    /// Returns how this package was built, if it was built by Cargo.
+    pub(crate) fn build_metadata(&self) -> Option<CargoBuildMetadata> {
+        if self.directory_row.package.namespace != "cargo" {
+            return None;
+        }
+        serde_json::from_value(self.install_metadata.clone()).ok()
+    }
+
+    /// Returns this install as reported by `hasp list` and `hasp info`, given its install path.
+    pub(crate) fn to_installed_package(&self, install_path: Utf8PathBuf) -> InstalledPackage {
+        let installed_files = self
+            .binaries
+            .iter()
+            .map(|(name, file)| {
+                let installed_file = InstalledFile {
+                    full_path: install_path.join(name),
+                    hash: file.hash.clone(),
+                    metadata: file.file_metadata.clone(),
+                    is_binary: file.is_binary,
+                };
+                (name.clone(), installed_file)
+            })
+            .collect();
+        InstalledPackage {
+            package: self.directory_row.package.clone(),
+            info: InstallInfo {
+                install_path,
+                install_time: self.install_time,
+                installed_files,
+                metadata: self.install_metadata.clone(),
+                held: self.held,
+            },
+        }
+    }
+
+    /// Returns the names of the binaries in this install.
+    pub(crate) fn binary_names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.binaries
+            .iter()
+            .filter(|(_, file)| file.is_binary)
+            .map(|(name, _)| name.as_str())
+    }
+
     fn all_matches_for_impl(namespace: &str, name: &str, conn: &Connection) -> Result<Vec<Self>> {
         let mut stmt = conn
             .prepare_cached(
                 "SELECT \
                     packages.directories.directory_id as directory_id, \
                     namespace, name, hash, version, \
-                    packages.directories.metadata as metadata, \
+                    packages.directories.metadata as metadata, held, \
                     install_id, install_time, \
                     packages.installed.metadata as install_metadata \
                 FROM packages.directories \
                 INNER JOIN packages.installed USING (directory_id) \
-                WHERE namespace == :namespace AND name == :name",
+                WHERE namespace == :namespace AND name == :name AND installed",
             )
             .wrap_err("failed to prepare statement")?;
         let rows = stmt
@@ -172,6 +687,8 @@ impl InstalledRow {
     pub(crate) fn from_row(conn: &Connection, row: &Row<'_>) -> rusqlite::Result<Self> {
         let directory_row = DirectoryRow::from_row(row)?;
         let install_id = row.get("install_id")?;
+        let held = row.get("held")?;
+        let install_time = row.get("install_time")?;
         let install_metadata = row.get("install_metadata")?;
 
         // Find all the binaries for this install id.
@@ -180,6 +697,8 @@ impl InstalledRow {
         Ok(Self {
             directory_row,
             install_id,
+            held,
+            install_time,
             install_metadata,
             binaries,
         })
@@ -225,3 +744,191 @@ impl InstalledFileRow {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TestHome;
+
+    #[test]
+    fn delete_stale_files_basic() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+
+        let package = foo_dir("sem:1.0.0", 0x01234567);
+        let row = DirectoryRow::insert(&package, true, &txn).expect("row inserted");
+        let old = receipt(
+            &package,
+            &[("foo", true), ("foo-helper", true), ("README.md", false)],
+        );
+        row.insert_install(&old, &txn).expect("install recorded");
+
+        // The new install doesn't have foo-helper or README.md, but only binaries are returned.
+        let new = receipt(&package, &[("foo", true)]);
+        let removed = row
+            .delete_stale_files(&new.files, &txn)
+            .expect("stale files deleted");
+        assert_eq!(removed, vec!["foo-helper".to_owned()]);
+        row.insert_install(&new, &txn).expect("install recorded");
+        assert!(row
+            .delete_stale_files(&new.files, &txn)
+            .expect("stale files deleted")
+            .is_empty());
+
+        let names: Vec<String> = txn
+            .prepare("SELECT DISTINCT name FROM packages.installed_files ORDER BY name")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()
+            })
+            .expect("installed files read");
+        assert_eq!(names, vec!["foo".to_owned()]);
+    }
+
+    #[test]
+    fn binary_owners_other_packages() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+
+        let package = foo_dir("sem:1.0.0", 0x01234567);
+        let row = DirectoryRow::insert(&package, true, &txn).expect("row inserted");
+        row.insert_install(&receipt(&package, &[("foo", true)]), &txn)
+            .expect("install recorded");
+
+        // Another package with a binary named foo owns it, but other versions of foo don't.
+        let other_version_row = DirectoryRow::insert(&foo_dir("sem:2.0.0", 0x89abcdef), true, &txn)
+            .expect("row inserted");
+        assert!(other_version_row
+            .binary_owners("foo", &txn)
+            .expect("owners queried")
+            .is_empty());
+        let other_package = PackageDirectory {
+            namespace: "npm".to_owned(),
+            name: "bar".to_owned(),
+            hash: DirectoryHash::new(0x76543210),
+            ..package.clone()
+        };
+        let other_row = DirectoryRow::insert(&other_package, true, &txn).expect("row inserted");
+        assert_eq!(
+            other_row
+                .binary_owners("foo", &txn)
+                .expect("owners queried"),
+            vec![("cargo".to_owned(), "foo".to_owned())]
+        );
+        assert!(row
+            .binary_owners("foo", &txn)
+            .expect("owners queried")
+            .is_empty());
+    }
+
+    #[test]
+    fn delete_not_installed() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+
+        let package = foo_dir("sem:1.0.0", 0x01234567);
+        let row = DirectoryRow::insert(&package, true, &txn).expect("row inserted");
+        row.insert_install(&receipt(&package, &[("foo", true)]), &txn)
+            .expect("install recorded");
+
+        // Directories that aren't installed can be removed once their installs are.
+        let failed_row = DirectoryRow::insert(&foo_dir("sem:3.0.0", 0x13579bdf), false, &txn)
+            .expect("row inserted");
+        row.set_installed(&txn, false).expect("installed state set");
+        let not_installed = |txn: &Transaction| {
+            DirectoryRow::all_not_installed(txn)
+                .expect("directories queried")
+                .into_iter()
+                .map(|row| row.directory_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            not_installed(&txn),
+            vec![row.directory_id, failed_row.directory_id]
+        );
+        assert!(!failed_row
+            .is_previous_install(&txn)
+            .expect("previous install queried"));
+        failed_row.delete(&txn).expect("row deleted");
+        row.delete_installs(&txn).expect("installs deleted");
+        row.delete(&txn).expect("row deleted");
+        assert!(not_installed(&txn).is_empty());
+    }
+
+    #[test]
+    fn insert_many_files() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+
+        // Packages with more files than fit in one statement are inserted over several.
+        let package = foo_dir("sem:4.0.0", 0x2468ace0);
+        let row = DirectoryRow::insert(&package, true, &txn).expect("row inserted");
+        let names: Vec<_> = (0..DirectoryRow::FILES_PER_INSERT * 2 + 1)
+            .map(|i| format!("file-{:03}", i))
+            .collect();
+        let files: Vec<_> = names.iter().map(|name| (name.as_str(), false)).collect();
+        let install_id = row
+            .insert_install(&receipt(&package, &files), &txn)
+            .expect("install recorded");
+        let count: usize = txn
+            .query_row(
+                "SELECT count(*) FROM packages.installed_files WHERE install_id = ?1",
+                [install_id],
+                |row| row.get(0),
+            )
+            .expect("installed files counted");
+        assert_eq!(count, names.len());
+    }
+
+    /// Returns a directory of the cargo package foo.
+    fn foo_dir(version: &str, hash: u64) -> PackageDirectory {
+        PackageDirectory {
+            namespace: "cargo".to_owned(),
+            name: "foo".to_owned(),
+            version: version.parse().expect("valid version"),
+            hash: DirectoryHash::new(hash),
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    /// Returns a receipt for an install of `package` with these files, and whether each is a
+    /// binary.
+    fn receipt(package: &PackageDirectory, files: &[(&str, bool)]) -> InstallReceipt {
+        InstallReceipt {
+            hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+            package: package.clone(),
+            target: None,
+            metadata: serde_json::json!({}),
+            files: files
+                .iter()
+                .map(|&(name, is_binary)| {
+                    let file = ReceiptFile {
+                        hash: FileHash::Blake3(blake3::hash(b"binary").into()),
+                        metadata: serde_json::Value::Null,
+                        is_binary,
+                    };
+                    (name.to_owned(), file)
+                })
+                .collect(),
+            dependencies: vec![],
+            start_time: Local::now(),
+            install_time: Local::now(),
+        }
+    }
+}
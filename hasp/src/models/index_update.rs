@@ -0,0 +1,82 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use chrono::{DateTime, Local};
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::{named_params, Connection, OptionalExtension, Transaction};
+
+/// The last time a registry index was updated, stored in the main database.
+#[derive(Clone, Debug)]
+pub(crate) struct IndexUpdateRow {
+    pub(crate) registry: String,
+    pub(crate) update_time: DateTime<Local>,
+}
+
+impl IndexUpdateRow {
+    /// Gets the last update for a registry, if it was ever updated.
+    pub(crate) fn get(registry: &str, conn: &Connection) -> Result<Option<Self>> {
+        conn.query_row(
+            "SELECT registry, update_time FROM index_updates WHERE registry = ?1",
+            [registry],
+            |row| {
+                Ok(Self {
+                    registry: row.get("registry")?,
+                    update_time: row.get("update_time")?,
+                })
+            },
+        )
+        .optional()
+        .wrap_err_with(|| format!("failed to get last index update for {}", registry))
+    }
+
+    /// Records this update, replacing any earlier one for the registry.
+    pub(crate) fn upsert(&self, txn: &Transaction) -> Result<()> {
+        txn.execute(
+            "INSERT INTO index_updates (registry, update_time) \
+            VALUES (:registry, :update_time) \
+            ON CONFLICT (registry) DO UPDATE SET update_time = excluded.update_time",
+            named_params! {
+                ":registry": self.registry,
+                ":update_time": self.update_time,
+            },
+        )
+        .wrap_err_with(|| format!("failed to record index update for {}", self.registry))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn upsert_basic() {
+        let mut conn = Connection::open_in_memory().expect("in-memory database opened");
+        conn.execute_batch(include_str!(
+            "../../sql/migrations/20261016-03-index-updates/up.sql"
+        ))
+        .expect("migration ran");
+        assert!(IndexUpdateRow::get("crates.io", &conn)
+            .expect("query succeeded")
+            .is_none());
+
+        let earlier = Local::now() - Duration::hours(1);
+        let later = Local::now();
+        for update_time in [earlier, later] {
+            let txn = conn.transaction().expect("transaction started");
+            IndexUpdateRow {
+                registry: "crates.io".to_owned(),
+                update_time,
+            }
+            .upsert(&txn)
+            .expect("upsert succeeded");
+            txn.commit().expect("transaction committed");
+        }
+
+        let row = IndexUpdateRow::get("crates.io", &conn)
+            .expect("query succeeded")
+            .expect("row exists");
+        assert_eq!(row.update_time, later, "later update replaced earlier one");
+    }
+}
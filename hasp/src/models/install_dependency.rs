@@ -0,0 +1,102 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::lockfile::LockedDependency;
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::{named_params, params, Connection, Row, Transaction};
+
+/// The dependencies an install was built with, stored in the database.
+#[derive(Clone, Debug)]
+pub(crate) struct InstallDependencyRow;
+
+impl InstallDependencyRow {
+    /// Returns the dependencies of an install, ordered by name and version.
+    pub(crate) fn all_for(install_id: i64, conn: &Connection) -> Result<Vec<LockedDependency>> {
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT name, version, source, checksum FROM packages.install_dependencies \
+                WHERE install_id = ?1 \
+                ORDER BY name, version",
+            )
+            .wrap_err("failed to prepare statement for install dependencies")?;
+        let rows = stmt
+            .query_and_then([install_id], Self::from_row)
+            .wrap_err_with(|| format!("failed to query dependencies of install {}", install_id))?;
+        rows.collect::<Result<Vec<_>>>()
+            .wrap_err_with(|| format!("failed to collect dependencies of install {}", install_id))
+    }
+
+    /// Records the dependencies of an install.
+    pub(crate) fn insert_all(
+        install_id: i64,
+        dependencies: &[LockedDependency],
+        txn: &Transaction,
+    ) -> Result<()> {
+        let mut stmt = txn
+            .prepare_cached(
+                "INSERT INTO packages.install_dependencies \
+                    (install_id, name, version, source, checksum) \
+                VALUES (:install_id, :name, :version, :source, :checksum)",
+            )
+            .wrap_err("failed to prepare statement for install dependencies")?;
+        for dependency in dependencies {
+            stmt.execute(named_params! {
+                    ":install_id": install_id,
+                    ":name": dependency.name,
+                    ":version": dependency.version.to_string(),
+                    ":source": dependency.source,
+                    ":checksum": dependency.checksum,
+            })
+            .wrap_err_with(|| {
+                format!(
+                    "failed to insert dependency {} v{} of install {}",
+                    dependency.name, dependency.version, install_id
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Copies the dependencies of one install to another.
+    pub(crate) fn copy(from_install_id: i64, to_install_id: i64, txn: &Transaction) -> Result<()> {
+        txn.execute(
+            "INSERT INTO packages.install_dependencies \
+                (install_id, name, version, source, checksum) \
+            SELECT ?1, name, version, source, checksum FROM packages.install_dependencies \
+            WHERE install_id = ?2",
+            params![to_install_id, from_install_id],
+        )
+        .wrap_err_with(|| format!("failed to copy dependencies of install {}", from_install_id))?;
+        Ok(())
+    }
+
+    /// Deletes the dependencies of all installs of a directory.
+    pub(crate) fn delete_for_directory(directory_id: i64, txn: &Transaction) -> Result<()> {
+        txn.execute(
+            "DELETE FROM packages.install_dependencies WHERE install_id IN \
+                (SELECT install_id FROM packages.installed WHERE directory_id = ?1)",
+            [directory_id],
+        )
+        .wrap_err_with(|| {
+            format!(
+                "failed to delete install dependencies for directory {}",
+                directory_id
+            )
+        })?;
+        Ok(())
+    }
+
+    fn from_row(row: &Row<'_>) -> Result<LockedDependency> {
+        let name: String = row.get("name")?;
+        let version: String = row.get("version")?;
+        let version = version
+            .parse()
+            .wrap_err_with(|| format!("invalid version {} for dependency {}", version, name))?;
+        Ok(LockedDependency {
+            name,
+            version,
+            source: row.get("source")?,
+            checksum: row.get("checksum")?,
+        })
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use camino::Utf8PathBuf;
+use chrono::Local;
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::DirectoryVersion;
+use rusqlite::{
+    named_params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, Row, ToSql, Transaction,
+};
+use std::fmt;
+
+/// The phases of an install, in the order they're completed.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) enum InstallPhase {
+    /// A version was resolved, and fetching it has started.
+    Resolved,
+    /// The package was fetched into the work directory.
+    Fetched,
+    /// The package was built, and its files were moved into the work directory.
+    Built,
+    /// The new install is being moved into place, but hasn't been recorded in the database. If
+    /// this is interrupted, the next attempt puts the previous install back.
+    Swapping,
+    /// The install was recorded in the database. Only cleanup remains.
+    Finalized,
+}
+
+impl InstallPhase {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            InstallPhase::Resolved => "resolved",
+            InstallPhase::Fetched => "fetched",
+            InstallPhase::Built => "built",
+            InstallPhase::Swapping => "swapping",
+            InstallPhase::Finalized => "finalized",
+        }
+    }
+}
+
+impl fmt::Display for InstallPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ToSql for InstallPhase {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for InstallPhase {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "resolved" => Ok(InstallPhase::Resolved),
+            "fetched" => Ok(InstallPhase::Fetched),
+            "built" => Ok(InstallPhase::Built),
+            "swapping" => Ok(InstallPhase::Swapping),
+            "finalized" => Ok(InstallPhase::Finalized),
+            other => Err(FromSqlError::Other(
+                format!("unknown install phase '{}'", other).into(),
+            )),
+        }
+    }
+}
+
+/// The progress of an install, stored in the database.
+#[derive(Clone, Debug)]
+pub(crate) struct InstallProgressRow {
+    pub(crate) work_dir: Utf8PathBuf,
+    pub(crate) namespace: String,
+    pub(crate) name: String,
+    pub(crate) version: DirectoryVersion,
+    pub(crate) phase: InstallPhase,
+    pub(crate) data: serde_json::Value,
+}
+
+impl InstallProgressRow {
+    /// Gets the progress of the install in this work directory, if any.
+    pub(crate) fn get(work_dir: &str, conn: &Connection) -> Result<Option<Self>> {
+        conn.query_row(
+            "SELECT work_dir, namespace, name, version, phase, data \
+            FROM packages.install_progress WHERE work_dir = ?1",
+            [work_dir],
+            Self::from_row,
+        )
+        .optional()
+        .wrap_err_with(|| format!("failed to get install progress for {}", work_dir))
+    }
+
+    /// Returns the progress of all installs of a package, in any version.
+    pub(crate) fn all_for(namespace: &str, name: &str, conn: &Connection) -> Result<Vec<Self>> {
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT work_dir, namespace, name, version, phase, data \
+                FROM packages.install_progress \
+                WHERE namespace = :namespace AND name = :name \
+                ORDER BY work_dir",
+            )
+            .wrap_err("failed to prepare statement for install progress")?;
+        let rows = stmt
+            .query_and_then(
+                named_params! {
+                    ":namespace": namespace,
+                    ":name": name,
+                },
+                Self::from_row,
+            )
+            .wrap_err_with(|| {
+                format!("failed to query install progress of {}:{}", namespace, name)
+            })?;
+        rows.collect::<rusqlite::Result<Vec<Self>>>()
+            .wrap_err_with(|| {
+                format!(
+                    "failed to collect install progress of {}:{}",
+                    namespace, name
+                )
+            })
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            work_dir: Utf8PathBuf::from(row.get::<_, String>("work_dir")?),
+            namespace: row.get("namespace")?,
+            name: row.get("name")?,
+            version: row.get("version")?,
+            phase: row.get("phase")?,
+            data: row.get("data")?,
+        })
+    }
+
+    /// Records this progress, replacing any earlier progress for the work directory.
+    pub(crate) fn upsert(&self, txn: &Transaction) -> Result<()> {
+        txn.execute(
+            "INSERT INTO packages.install_progress \
+                (work_dir, namespace, name, version, phase, data, update_time) \
+            VALUES (:work_dir, :namespace, :name, :version, :phase, :data, :update_time) \
+            ON CONFLICT (work_dir) DO UPDATE SET \
+                phase = excluded.phase, data = excluded.data, update_time = excluded.update_time",
+            named_params! {
+                ":work_dir": self.work_dir.as_str(),
+                ":namespace": self.namespace,
+                ":name": self.name,
+                ":version": self.version,
+                ":phase": self.phase,
+                ":data": self.data,
+                ":update_time": Local::now(),
+            },
+        )
+        .wrap_err_with(|| {
+            format!(
+                "failed to record {} phase for {}:{} (version {})",
+                self.phase, self.namespace, self.name, self.version
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Deletes the progress of the install in this work directory.
+    pub(crate) fn delete(work_dir: &str, txn: &Transaction) -> Result<()> {
+        txn.execute(
+            "DELETE FROM packages.install_progress WHERE work_dir = ?1",
+            [work_dir],
+        )
+        .wrap_err_with(|| format!("failed to delete install progress for {}", work_dir))?;
+        Ok(())
+    }
+}
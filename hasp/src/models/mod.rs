@@ -4,3 +4,9 @@
 //! Data models for information stored in the database.
 
 pub(crate) mod directory;
+pub(crate) mod index_update;
+pub(crate) mod install_dependency;
+pub(crate) mod install_progress;
+pub(crate) mod namespace;
+pub(crate) mod previous_install;
+pub(crate) mod trusted_source;
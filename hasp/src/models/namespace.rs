@@ -0,0 +1,28 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::Transaction;
+
+/// A namespace that packages can be stored under, stored in the packages database.
+///
+/// The built-in namespaces are added by migrations, and namespaces implemented by plugins the
+/// first time a package in them is installed.
+#[derive(Clone, Debug)]
+pub(crate) struct NamespaceRow {
+    pub(crate) namespace: String,
+}
+
+impl NamespaceRow {
+    /// Adds the namespace if it isn't already there. Returns false if it was.
+    pub(crate) fn insert(&self, txn: &Transaction) -> Result<bool> {
+        let inserted = txn
+            .execute(
+                "INSERT INTO packages.namespaces (namespace) VALUES (?1) \
+                ON CONFLICT (namespace) DO NOTHING",
+                [&self.namespace],
+            )
+            .wrap_err_with(|| format!("failed to record namespace {}", self.namespace))?;
+        Ok(inserted > 0)
+    }
+}
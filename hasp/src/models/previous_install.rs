@@ -0,0 +1,181 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::models::directory::InstalledRow;
+use camino::Utf8PathBuf;
+use chrono::Local;
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::{named_params, Connection, Transaction};
+
+/// An install that was superseded by a newer install of the same package, stored in the database.
+#[derive(Clone, Debug)]
+pub(crate) struct PreviousInstallRow {
+    pub(crate) previous_id: i64,
+    pub(crate) installed: InstalledRow,
+    /// The path the install was moved to.
+    pub(crate) path: Utf8PathBuf,
+}
+
+impl PreviousInstallRow {
+    /// Returns the previous installs of a package, most recently superseded first.
+    pub(crate) fn all_for(namespace: &str, name: &str, conn: &Connection) -> Result<Vec<Self>> {
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT \
+                    previous_id, path, \
+                    packages.directories.directory_id as directory_id, \
+                    namespace, name, hash, version, \
+                    packages.directories.metadata as metadata, held, \
+                    packages.installed.install_id as install_id, install_time, \
+                    packages.installed.metadata as install_metadata \
+                FROM packages.previous_installs \
+                INNER JOIN packages.directories \
+                    ON packages.directories.directory_id = packages.previous_installs.directory_id \
+                INNER JOIN packages.installed \
+                    ON packages.installed.install_id = packages.previous_installs.install_id \
+                WHERE namespace == :namespace AND name == :name \
+                ORDER BY retire_time DESC, previous_id DESC",
+            )
+            .wrap_err("failed to prepare statement for previous installs")?;
+        let rows = stmt
+            .query_and_then(
+                named_params! {
+                    ":namespace": namespace,
+                    ":name": name,
+                },
+                |row| {
+                    Ok(Self {
+                        previous_id: row.get("previous_id")?,
+                        installed: InstalledRow::from_row(conn, row)?,
+                        path: Utf8PathBuf::from(row.get::<_, String>("path")?),
+                    })
+                },
+            )
+            .wrap_err_with(|| {
+                format!(
+                    "failed to query previous installs of {}:{}",
+                    namespace, name
+                )
+            })?;
+        rows.collect::<rusqlite::Result<Vec<Self>>>()
+            .wrap_err_with(|| {
+                format!(
+                    "failed to collect previous installs of {}:{}",
+                    namespace, name
+                )
+            })
+    }
+
+    /// Records that an install was superseded and moved to `path`.
+    pub(crate) fn insert(
+        installed: &InstalledRow,
+        path: &Utf8PathBuf,
+        txn: &Transaction,
+    ) -> Result<()> {
+        txn.execute(
+            "INSERT INTO packages.previous_installs (directory_id, install_id, path, retire_time) \
+            VALUES (:directory_id, :install_id, :path, :retire_time)",
+            named_params! {
+                ":directory_id": installed.directory_row.directory_id,
+                ":install_id": installed.install_id,
+                ":path": path.as_str(),
+                ":retire_time": Local::now(),
+            },
+        )
+        .wrap_err_with(|| {
+            format!(
+                "failed to add {} to packages.previous_installs",
+                installed.directory_row.to_friendly()
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Deletes this row.
+    pub(crate) fn delete(&self, txn: &Transaction) -> Result<()> {
+        txn.execute(
+            "DELETE FROM packages.previous_installs WHERE previous_id = ?1",
+            [self.previous_id],
+        )
+        .wrap_err_with(|| {
+            format!(
+                "failed to delete previous install of {}",
+                self.installed.directory_row.to_friendly()
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::directory::DirectoryRow, receipt::InstallReceipt, test_helpers::TestHome};
+    use hasp_metadata::{DirectoryHash, PackageDirectory};
+
+    #[test]
+    fn insert_and_delete() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+
+        for (version, hash) in [("sem:1.0.0", 0x10), ("sem:2.0.0", 0x20)] {
+            let package = PackageDirectory {
+                namespace: "cargo".to_owned(),
+                name: "foo".to_owned(),
+                version: version.parse().expect("valid version"),
+                hash: DirectoryHash::new(hash),
+                metadata: serde_json::json!({}),
+            };
+            let receipt = InstallReceipt {
+                hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+                package: package.clone(),
+                target: None,
+                metadata: serde_json::json!({}),
+                files: Default::default(),
+                dependencies: vec![],
+                start_time: Local::now(),
+                install_time: Local::now(),
+            };
+            let row = DirectoryRow::insert(&package, true, &txn).expect("row inserted");
+            row.insert_install(&receipt, &txn)
+                .expect("install recorded");
+        }
+        let installed =
+            InstalledRow::all_installed_for("cargo", "foo", &txn).expect("installs queried");
+        assert_eq!(installed.len(), 2);
+        for installed in &installed {
+            let path = Utf8PathBuf::from(format!("/previous/{}", installed.install_id));
+            PreviousInstallRow::insert(installed, &path, &txn).expect("previous install inserted");
+        }
+
+        // The most recently superseded install comes first.
+        let previous =
+            PreviousInstallRow::all_for("cargo", "foo", &txn).expect("previous installs queried");
+        let ids = |previous: &[PreviousInstallRow]| {
+            previous
+                .iter()
+                .map(|previous| previous.installed.install_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(&previous),
+            vec![installed[1].install_id, installed[0].install_id]
+        );
+        assert_eq!(
+            previous[0].path,
+            format!("/previous/{}", installed[1].install_id)
+        );
+        assert!(PreviousInstallRow::all_for("cargo", "bar", &txn)
+            .expect("previous installs queried")
+            .is_empty());
+
+        previous[0].delete(&txn).expect("previous install deleted");
+        let previous =
+            PreviousInstallRow::all_for("cargo", "foo", &txn).expect("previous installs queried");
+        assert_eq!(ids(&previous), vec![installed[0].install_id]);
+    }
+}
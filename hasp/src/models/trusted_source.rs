@@ -0,0 +1,118 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use chrono::{DateTime, Local};
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::{named_params, Connection, OptionalExtension, Row, Transaction};
+
+/// A source that was approved for fetching packages from, stored in the main database.
+#[derive(Clone, Debug)]
+pub(crate) struct TrustedSourceRow {
+    pub(crate) source: String,
+    /// How the source was approved: `prompt` or `command`.
+    pub(crate) approval: String,
+    pub(crate) approve_time: DateTime<Local>,
+}
+
+impl TrustedSourceRow {
+    /// Gets the approval for a source, if it was approved.
+    pub(crate) fn get(source: &str, conn: &Connection) -> Result<Option<Self>> {
+        conn.query_row(
+            "SELECT source, approval, approve_time FROM trusted_sources WHERE source = ?1",
+            [source],
+            Self::from_row,
+        )
+        .optional()
+        .wrap_err_with(|| format!("failed to get approval for source {}", source))
+    }
+
+    /// Returns all approved sources, ordered by source.
+    pub(crate) fn all(conn: &Connection) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(
+            "SELECT source, approval, approve_time FROM trusted_sources ORDER BY source",
+        )?;
+        let rows = stmt
+            .query_map([], Self::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .wrap_err("failed to get trusted sources")?;
+        Ok(rows)
+    }
+
+    /// Records this approval. Returns false if the source was already approved, in which case
+    /// the earlier approval is kept.
+    pub(crate) fn insert(&self, txn: &Transaction) -> Result<bool> {
+        let inserted = txn
+            .execute(
+                "INSERT INTO trusted_sources (source, approval, approve_time) \
+                VALUES (:source, :approval, :approve_time) \
+                ON CONFLICT (source) DO NOTHING",
+                named_params! {
+                    ":source": self.source,
+                    ":approval": self.approval,
+                    ":approve_time": self.approve_time,
+                },
+            )
+            .wrap_err_with(|| format!("failed to record approval for source {}", self.source))?;
+        Ok(inserted > 0)
+    }
+
+    /// Removes the approval for a source. Returns false if it wasn't approved.
+    pub(crate) fn delete(source: &str, txn: &Transaction) -> Result<bool> {
+        let deleted = txn
+            .execute("DELETE FROM trusted_sources WHERE source = ?1", [source])
+            .wrap_err_with(|| format!("failed to remove approval for source {}", source))?;
+        Ok(deleted > 0)
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            source: row.get("source")?,
+            approval: row.get("approval")?,
+            approve_time: row.get("approve_time")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_delete() {
+        let mut conn = Connection::open_in_memory().expect("in-memory database opened");
+        conn.execute_batch(include_str!(
+            "../../sql/migrations/20261016-08-trusted-sources/up.sql"
+        ))
+        .expect("migration ran");
+
+        let row = |approval: &str| TrustedSourceRow {
+            source: "oci:ghcr.io".to_owned(),
+            approval: approval.to_owned(),
+            approve_time: Local::now(),
+        };
+        let txn = conn.transaction().expect("transaction started");
+        assert!(row("prompt").insert(&txn).expect("insert succeeded"));
+        assert!(
+            !row("command").insert(&txn).expect("insert succeeded"),
+            "second approval is ignored"
+        );
+        txn.commit().expect("transaction committed");
+
+        let approved = TrustedSourceRow::get("oci:ghcr.io", &conn)
+            .expect("query succeeded")
+            .expect("source approved");
+        assert_eq!(approved.approval, "prompt", "earlier approval kept");
+        assert_eq!(
+            TrustedSourceRow::all(&conn).expect("query succeeded").len(),
+            1
+        );
+
+        let txn = conn.transaction().expect("transaction started");
+        assert!(TrustedSourceRow::delete("oci:ghcr.io", &txn).expect("delete succeeded"));
+        assert!(!TrustedSourceRow::delete("oci:ghcr.io", &txn).expect("delete succeeded"));
+        txn.commit().expect("transaction committed");
+        assert!(TrustedSourceRow::get("oci:ghcr.io", &conn)
+            .expect("query succeeded")
+            .is_none());
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Timeouts and retries for network operations.
+
+use crate::{cancel, config::NetworkConfig, failure::NetworkFailed};
+use color_eyre::{eyre::eyre, Report, Result};
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+// Command-line overrides for network settings in the config file. (Not a doc comment, since
+// structopt would use it as the about text for hasp.)
+#[derive(Copy, Clone, Debug, Default, StructOpt)]
+pub(crate) struct NetworkOpts {
+    /// Time limit in seconds for each attempt at a network operation [default: 30]
+    #[structopt(long, global = true)]
+    network_timeout: Option<u64>,
+
+    /// Number of times to retry failed network operations [default: 3]
+    #[structopt(long, global = true)]
+    network_retries: Option<u32>,
+}
+
+impl NetworkOpts {
+    /// Returns the retry policy for these options, falling back to the config file.
+    pub(crate) fn retry_policy(&self, config: &NetworkConfig) -> RetryPolicy {
+        RetryPolicy {
+            attempts: self
+                .network_retries
+                .or(config.retries)
+                .unwrap_or(RetryPolicy::DEFAULT_RETRIES)
+                + 1,
+            timeout: Duration::from_secs(
+                self.network_timeout
+                    .or(config.timeout)
+                    .unwrap_or(RetryPolicy::DEFAULT_TIMEOUT_SECS),
+            ),
+            deadline: config.deadline.map(Duration::from_secs),
+            initial_backoff: RetryPolicy::DEFAULT_INITIAL_BACKOFF,
+        }
+    }
+}
+
+/// How network operations are retried.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+    /// The number of attempts to make, including the first one.
+    pub(crate) attempts: u32,
+
+    /// The time limit for each attempt.
+    pub(crate) timeout: Duration,
+
+    /// The time limit across all attempts, if any.
+    pub(crate) deadline: Option<Duration>,
+
+    /// The delay before the first retry. The delay doubles for each subsequent retry.
+    pub(crate) initial_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) const DEFAULT_RETRIES: u32 = 3;
+    pub(crate) const DEFAULT_TIMEOUT_SECS: u64 = 30;
+    const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+    /// Runs an asynchronous operation, retrying it on failure or timeout.
+    ///
+    /// `description` is used for error context, e.g. "download foo".
+    pub(crate) async fn run<T, F, Fut>(&self, description: &str, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let timeout = match self.remaining(start) {
+                Some(remaining) => self.timeout.min(remaining),
+                None => self.timeout,
+            };
+            let res = match tokio::time::timeout(timeout, operation()).await {
+                Ok(res) => res,
+                Err(_) => Err(eyre!("timed out after {:.1}s", timeout.as_secs_f64())),
+            };
+            let err = match res {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+
+            let backoff = self.next_backoff(start, attempt, description, err)?;
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// Runs a blocking operation, retrying it on failure.
+    ///
+    /// Blocking operations can't be interrupted, so the per-attempt timeout doesn't apply to
+    /// them.
+    pub(crate) fn run_blocking<T>(
+        &self,
+        description: &str,
+        mut operation: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let err = match operation() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+
+            let backoff = self.next_backoff(start, attempt, description, err)?;
+            std::thread::sleep(backoff);
+            attempt += 1;
+        }
+    }
+
+    /// Returns the time remaining before the deadline, if there is one.
+    fn remaining(&self, start: Instant) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_sub(start.elapsed()))
+    }
+
+    /// Decides whether to retry after a failed attempt, returning the delay before the next
+    /// attempt or the error to fail with.
+    fn next_backoff(
+        &self,
+        start: Instant,
+        attempt: u32,
+        description: &str,
+        err: Report,
+    ) -> Result<Duration> {
+        let attempt_failed = format!(
+            "attempt {} of {} to {} failed",
+            attempt, self.attempts, description
+        );
+
+        if attempt >= self.attempts {
+            return Err(err.wrap_err(attempt_failed).wrap_err(NetworkFailed(format!(
+                "failed to {} after {} attempts",
+                description, attempt
+            ))));
+        }
+        cancel::check()?;
+
+        let backoff = self.initial_backoff * 2u32.saturating_pow(attempt - 1);
+        if let Some(remaining) = self.remaining(start) {
+            if backoff >= remaining {
+                return Err(err.wrap_err(attempt_failed).wrap_err(NetworkFailed(format!(
+                    "failed to {} within the deadline of {}s",
+                    description,
+                    self.deadline.unwrap_or_default().as_secs()
+                ))));
+            }
+        }
+
+        let seconds = format!("{:.1}", backoff.as_secs_f64());
+        let error = format!("{:#}", err);
+        tracing::warn!(
+            target: "hasp::output::network::retrying",
+            %description,
+            %seconds,
+            %attempt_failed,
+            %error,
+            "Retrying {description} in {seconds}s ({attempt_failed}: {error})",
+        );
+        Ok(backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        NetworkOpts::default().retry_policy(&NetworkConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            timeout: Duration::from_secs(5),
+            deadline: None,
+            initial_backoff: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn run_blocking_retries() {
+        let mut calls = 0;
+        let value = policy(3)
+            .run_blocking("do thing", || {
+                calls += 1;
+                if calls < 3 {
+                    Err(eyre!("flaky"))
+                } else {
+                    Ok(calls)
+                }
+            })
+            .expect("third attempt succeeded");
+        assert_eq!(value, 3);
+
+        let err = policy(2)
+            .run_blocking("do thing", || -> Result<()> { Err(eyre!("broken")) })
+            .expect_err("all attempts failed");
+        let chain: Vec<_> = err.chain().map(|cause| cause.to_string()).collect();
+        assert_eq!(
+            chain,
+            vec![
+                "failed to do thing after 2 attempts",
+                "attempt 2 of 2 to do thing failed",
+                "broken"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_times_out() {
+        let mut policy = policy(2);
+        policy.timeout = Duration::from_millis(10);
+        let err = policy
+            .run("wait", || async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(())
+            })
+            .await
+            .expect_err("all attempts timed out");
+        assert_eq!(
+            err.root_cause().to_string(),
+            "timed out after 0.0s",
+            "root cause is the timeout"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! npm CLI support.
+
+use crate::{
+    helpers::find_in_path,
+    output::{Color, OutputOpts},
+};
+use camino::Utf8PathBuf;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use std::{convert::TryInto, env, path::PathBuf};
+
+static HASP_NPM_ENV: &str = "HASP_NPM";
+static HASP_NODE_ENV: &str = "HASP_NODE";
+
+#[derive(Clone, Debug)]
+pub(crate) struct NpmCli<'a> {
+    npm_path: Utf8PathBuf,
+    output_opts: OutputOpts,
+    command: &'a str,
+    args: Vec<&'a str>,
+}
+
+impl<'a> NpmCli<'a> {
+    pub(crate) fn new(command: &'a str, output_opts: OutputOpts) -> Result<Self> {
+        let npm_path = npm_path()?;
+        Ok(Self {
+            npm_path,
+            output_opts,
+            command,
+            args: vec![],
+        })
+    }
+
+    pub(crate) fn add_arg(&mut self, arg: &'a str) -> &mut Self {
+        self.args.push(arg);
+        self
+    }
+
+    pub(crate) fn add_args(&mut self, args: impl IntoIterator<Item = &'a str>) -> &mut Self {
+        self.args.extend(args);
+        self
+    }
+
+    pub(crate) fn to_expression(&self) -> duct::Expression {
+        let mut initial_args = vec![self.command];
+        if self.output_opts.quiet {
+            initial_args.push("--silent");
+        }
+        match self.output_opts.color() {
+            Color::Auto => {}
+            Color::Always => initial_args.push("--color=always"),
+            Color::Never => initial_args.push("--no-color"),
+        }
+
+        let args: Vec<_> = initial_args
+            .into_iter()
+            .chain(self.args.iter().copied())
+            .collect();
+        tracing::debug!(
+            target: "hasp::output::working::running_npm",
+            npm = %self.npm_path,
+            args = %args.join(" "),
+            "Running {npm} {args}",
+            npm = self.npm_path,
+            args = args.join(" "),
+        );
+        duct::cmd(self.npm_path.as_str(), args)
+    }
+}
+
+/// Returns the path to the npm executable, either from `HASP_NPM` or discovered through `PATH`.
+pub(crate) fn npm_path() -> Result<Utf8PathBuf> {
+    discover_tool(HASP_NPM_ENV, "npm")
+}
+
+/// Returns the path to the node executable, either from `HASP_NODE` or discovered through `PATH`.
+///
+/// The path is recorded into generated wrapper scripts, so it is always absolute.
+pub(crate) fn node_path() -> Result<Utf8PathBuf> {
+    discover_tool(HASP_NODE_ENV, "node")
+}
+
+/// Returns the registry npm fetches packages from, as set through `npm_config_registry`.
+/// Defaults to the public registry.
+///
+/// Registries set in `.npmrc` files aren't detected.
+pub(crate) fn registry() -> String {
+    ["npm_config_registry", "NPM_CONFIG_REGISTRY"]
+        .iter()
+        .find_map(|env_var| {
+            env::var(env_var)
+                .ok()
+                .filter(|registry| !registry.is_empty())
+        })
+        .unwrap_or_else(|| "registry.npmjs.org".to_owned())
+}
+
+fn discover_tool(env_var: &str, name: &str) -> Result<Utf8PathBuf> {
+    match env::var_os(env_var) {
+        Some(path) => PathBuf::from(path)
+            .try_into()
+            .wrap_err_with(|| format!("{} env var is not valid UTF-8", env_var)),
+        None => find_in_path(name).ok_or_else(|| {
+            eyre!(
+                "{} not found in PATH (use {} to set an explicit path)",
+                name,
+                env_var
+            )
+        }),
+    }
+}
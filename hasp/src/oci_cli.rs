@@ -0,0 +1,93 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Container runtime (docker or podman) CLI support.
+
+use crate::{helpers::find_in_path, output::OutputOpts};
+use camino::Utf8PathBuf;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use std::{convert::TryInto, env, path::PathBuf};
+
+static HASP_OCI_RUNTIME_ENV: &str = "HASP_OCI_RUNTIME";
+
+/// Runtimes to look for in `PATH`, in order of preference.
+static RUNTIMES: &[&str] = &["docker", "podman"];
+
+#[derive(Clone, Debug)]
+pub(crate) struct OciCli<'a> {
+    runtime_path: Utf8PathBuf,
+    output_opts: OutputOpts,
+    command: &'a str,
+    args: Vec<&'a str>,
+}
+
+impl<'a> OciCli<'a> {
+    pub(crate) fn new(
+        runtime_path: Utf8PathBuf,
+        command: &'a str,
+        output_opts: OutputOpts,
+    ) -> Self {
+        Self {
+            runtime_path,
+            output_opts,
+            command,
+            args: vec![],
+        }
+    }
+
+    pub(crate) fn add_arg(&mut self, arg: &'a str) -> &mut Self {
+        self.args.push(arg);
+        self
+    }
+
+    pub(crate) fn add_args(&mut self, args: impl IntoIterator<Item = &'a str>) -> &mut Self {
+        self.args.extend(args);
+        self
+    }
+
+    pub(crate) fn to_expression(&self) -> duct::Expression {
+        let mut initial_args = vec![self.command];
+        // Only `pull` understands --quiet.
+        if self.output_opts.quiet && self.command == "pull" {
+            initial_args.push("--quiet");
+        }
+
+        let args: Vec<_> = initial_args
+            .into_iter()
+            .chain(self.args.iter().copied())
+            .collect();
+        tracing::debug!(
+            target: "hasp::output::working::running_oci",
+            runtime = %self.runtime_path,
+            args = %args.join(" "),
+            "Running {runtime} {args}",
+            runtime = self.runtime_path,
+            args = args.join(" "),
+        );
+        duct::cmd(self.runtime_path.as_str(), args)
+    }
+}
+
+/// Returns the path to the container runtime, either from `HASP_OCI_RUNTIME` or by looking for
+/// docker, then podman, in `PATH`.
+///
+/// The path is recorded into generated wrapper scripts, so it is always absolute.
+pub(crate) fn runtime_path() -> Result<Utf8PathBuf> {
+    match env::var_os(HASP_OCI_RUNTIME_ENV) {
+        Some(path) => PathBuf::from(path)
+            .try_into()
+            .wrap_err_with(|| format!("{} env var is not valid UTF-8", HASP_OCI_RUNTIME_ENV)),
+        None => RUNTIMES
+            .iter()
+            .find_map(|runtime| find_in_path(runtime))
+            .ok_or_else(|| {
+                eyre!(
+                    "neither docker nor podman found in PATH (use {} to set an explicit path)",
+                    HASP_OCI_RUNTIME_ENV
+                )
+            }),
+    }
+}
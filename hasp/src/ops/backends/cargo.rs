@@ -4,17 +4,29 @@
 //! Cargo package fetcher and installer.
 
 use crate::{
+    cancel,
     cargo_cli::CargoCli,
-    models::directory::{DirectoryRow, InstalledRow},
+    database::ConnectionCreator,
+    http_client::{self, HttpClient},
+    lockfile::CARGO_LOCK,
+    models::{
+        directory::{DirectoryRow, InstalledRow},
+        index_update::IndexUpdateRow,
+    },
+    network::RetryPolicy,
     ops::{
-        PackageFetcherImpl, PackageInstallerImpl, PackageMatcherImpl, PackageResolverImpl,
-        TempInstalledFile, TempInstalledPackage,
+        checksum_file, hash_bytes, PackageFetcherImpl, PackageInstallerImpl, PackageMatcherImpl,
+        PackageResolverImpl, RemoteCacheKey, SignedArtifact, TempInstalledFile,
+        TempInstalledPackage,
     },
-    output::OutputOpts,
+    output::{NameVersionDisplay, OutputOpts},
+    platform::IS_WINDOWS,
+    process::{BuildLog, ProcessFailed, StderrTail},
 };
 use async_trait::async_trait;
 use camino::{Utf8Path, Utf8PathBuf};
-use cargo_metadata::Message;
+use cargo_metadata::{Message, MetadataCommand, Package, Target};
+use chrono::Local;
 use color_eyre::{
     eyre::{bail, eyre, WrapErr},
     Result,
@@ -22,23 +34,54 @@ use color_eyre::{
 use colored::Colorize;
 use crates_index::{Index, IndexConfig};
 use flate2::read::GzDecoder;
-use hasp_metadata::{CargoDirectory, DirectoryVersion, DirectoryVersionReq};
-use once_cell::sync::OnceCell;
-use semver::Version;
+use hasp_metadata::{
+    redact_env, redact_env_value, CargoBuildMetadata, CargoDirectory, Checksum, DirectoryVersion,
+    DirectoryVersionReq, Sha256Hash,
+};
+use once_cell::sync::Lazy;
+use semver::{Comparator, Op, Version, VersionReq};
+use serde::Deserialize;
 use serde_json::Value;
-use std::{collections::BTreeMap, fs, hash::Hasher, io::BufReader};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    ffi::OsString,
+    fs,
+    hash::Hasher,
+    io::BufReader,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tar::Archive;
 use twox_hash::XxHash64;
 
 #[derive(Debug)]
 pub(crate) struct CargoMatcher {
     metadata: CargoDirectory,
-    // TODO: features, git, registry etc
+    build_opts: CargoBuildOpts,
+    creator: ConnectionCreator,
+    // TODO: git etc
 }
 
 impl CargoMatcher {
-    pub(crate) fn new(metadata: CargoDirectory) -> Self {
-        Self { metadata }
+    pub(crate) fn new(
+        metadata: CargoDirectory,
+        build_opts: CargoBuildOpts,
+        creator: ConnectionCreator,
+    ) -> Self {
+        Self {
+            metadata,
+            build_opts,
+            creator,
+        }
+    }
+
+    fn matches_metadata(&self, metadata: &Value) -> bool {
+        // Directories recorded before environment variables were redacted have their values.
+        matches!(
+            serde_json::from_value::<CargoDirectory>(metadata.clone()),
+            Ok(metadata) if metadata.redacted() == self.metadata.redacted()
+        )
     }
 }
 
@@ -50,32 +93,296 @@ impl PackageMatcherImpl for CargoMatcher {
     }
 
     fn best_match(&self, rows: Vec<DirectoryRow>) -> Result<Option<DirectoryRow>> {
-        // TODO: actually match on features etc
-        Ok(rows.into_iter().next())
+        Ok(rows
+            .into_iter()
+            .find(|row| self.matches_metadata(&row.package.metadata)))
     }
 
     fn best_installed_match(
         &self,
         installed_rows: Vec<InstalledRow>,
     ) -> Result<Option<InstalledRow>> {
-        // TODO: actually match on features etc
-        Ok(installed_rows.into_iter().next())
+        Ok(installed_rows
+            .into_iter()
+            .find(|row| self.matches_metadata(&row.directory_row.package.metadata)))
     }
 
     fn metadata(&self) -> Value {
-        serde_json::to_value(&self.metadata).unwrap_or(Value::Null)
+        serde_json::to_value(self.metadata.redacted()).unwrap_or(Value::Null)
+    }
+
+    fn source(&self, _name: &str) -> String {
+        match &self.metadata.path {
+            Some(path) => format!("cargo:{}", path),
+            None => format!(
+                "cargo:{}",
+                self.metadata.registry.as_deref().unwrap_or("crates.io")
+            ),
+        }
     }
 
     fn make_resolver(&self) -> Box<dyn PackageResolverImpl> {
         Box::new(CargoResolver {
             metadata: self.metadata.clone(),
+            build_opts: self.build_opts.clone(),
+            creator: self.creator.clone(),
+        })
+    }
+}
+
+/// How a registry index is accessed.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum IndexProtocol {
+    /// Clone and update the full git repository for the index.
+    #[default]
+    Git,
+
+    /// Fetch the index entries for individual crates over HTTP.
+    Sparse,
+}
+
+/// How builds reuse work from earlier builds, as set in the config.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BuildCacheMode {
+    /// Build each crate in a persistent target directory within the cache directory.
+    TargetDir,
+
+    /// Run rustc through sccache.
+    Sccache,
+}
+
+/// A build cache in use.
+///
+/// Build caches don't change what a build produces, so they aren't part of the directory hash.
+#[derive(Clone, Debug)]
+pub(crate) enum BuildCache {
+    /// Each crate is built in a target directory named after it, within this directory.
+    TargetDir(Utf8PathBuf),
+
+    /// rustc is run through the sccache executable at this path.
+    Sccache(Utf8PathBuf),
+}
+
+impl BuildCache {
+    /// Returns the directory that target directories are kept in, within the cache directory.
+    pub(crate) fn target_dirs(cache_dir: &Utf8Path) -> Utf8PathBuf {
+        cache_dir.join("build-cache")
+    }
+
+    /// Returns the variable that enables this cache for a crate.
+    fn var(&self, name: &str) -> (String, OsString) {
+        match self {
+            BuildCache::TargetDir(dir) => ("CARGO_TARGET_DIR".to_owned(), dir.join(name).into()),
+            BuildCache::Sccache(path) => ("RUSTC_WRAPPER".to_owned(), path.into()),
+        }
+    }
+}
+
+/// Options that affect how a crate is fetched and built, but not the artifacts produced by the
+/// build.
+///
+/// Unlike [`CargoDirectory`], these aren't part of the directory hash.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CargoBuildOpts {
+    /// The number of parallel jobs to build with.
+    pub(crate) jobs: Option<usize>,
+
+    /// Whether to look for prebuilt binaries in the shared store and remote cache before building
+    /// from source.
+    pub(crate) prefer_prebuilt: bool,
+
+    /// How the crates.io index is accessed. Registries with `sparse+` URLs always use the sparse
+    /// protocol.
+    pub(crate) index_protocol: IndexProtocol,
+
+    /// How long after an update the git index is used without being updated again. Zero means
+    /// that the index is always updated.
+    pub(crate) index_ttl: Duration,
+
+    /// How index updates and downloads are retried.
+    pub(crate) network: RetryPolicy,
+
+    /// The environment variables builds are run with.
+    pub(crate) build_env: BuildEnv,
+
+    /// The cargo executable to build with, if configured.
+    pub(crate) cargo: Option<Utf8PathBuf>,
+
+    /// The build cache to use, if any.
+    pub(crate) build_cache: Option<BuildCache>,
+
+    /// Whether yanked versions may be installed if they're the only ones that match.
+    pub(crate) allow_yanked: bool,
+
+    /// Whether versions whose `rust-version` is newer than the rustc that builds use are skipped
+    /// when resolving.
+    pub(crate) respect_rust_version: bool,
+
+    /// A directory of `.crate` files that crates are installed from before the registry is
+    /// checked.
+    pub(crate) mirror: Option<Utf8PathBuf>,
+
+    /// Whether crates may only be installed from the mirror.
+    pub(crate) offline: bool,
+}
+
+/// The environment that builds run in.
+///
+/// Only allowlisted variables are passed through to builds, so that settings like `RUSTFLAGS` in
+/// the user's environment don't silently change what gets installed. A hash of the variables is
+/// recorded in the install metadata.
+#[derive(Clone, Debug)]
+pub(crate) struct BuildEnv {
+    allowlist: Vec<String>,
+    /// Variables set explicitly, which don't affect the built artifacts. Variables that do are
+    /// part of [`CargoDirectory`] instead.
+    set: BTreeMap<String, String>,
+}
+
+impl BuildEnv {
+    /// Variables that are always passed through: those needed to find and run the toolchain, and
+    /// to access the network.
+    const DEFAULT_ALLOWLIST: &'static [&'static str] = &[
+        // Toolchain.
+        "PATH",
+        "CARGO_HOME",
+        "RUSTUP_HOME",
+        "RUSTUP_TOOLCHAIN",
+        // User and temporary directories.
+        "HOME",
+        "USER",
+        "LOGNAME",
+        "TMPDIR",
+        "TEMP",
+        "TMP",
+        // Networking.
+        "HTTP_PROXY",
+        "HTTPS_PROXY",
+        "NO_PROXY",
+        "http_proxy",
+        "https_proxy",
+        "no_proxy",
+        "SSL_CERT_FILE",
+        "SSL_CERT_DIR",
+        "SSH_AUTH_SOCK",
+        // Windows.
+        "SYSTEMROOT",
+        "SYSTEMDRIVE",
+        "WINDIR",
+        "COMSPEC",
+        "PATHEXT",
+        "USERPROFILE",
+        "APPDATA",
+        "LOCALAPPDATA",
+        "PROGRAMDATA",
+        "PROGRAMFILES",
+        "PROGRAMFILES(X86)",
+    ];
+
+    /// Variables that change how a build runs, but not what it produces. Setting these doesn't
+    /// cause packages to be installed again.
+    const NON_ARTIFACT_VARS: &'static [&'static str] = &[
+        "CARGO_TARGET_DIR",
+        "CARGO_BUILD_TARGET_DIR",
+        "CARGO_BUILD_JOBS",
+        "CARGO_INCREMENTAL",
+        "CARGO_BUILD_INCREMENTAL",
+        "RUSTC_WRAPPER",
+        "CARGO_BUILD_RUSTC_WRAPPER",
+        "RUSTC_WORKSPACE_WRAPPER",
+        "CARGO_BUILD_RUSTC_WORKSPACE_WRAPPER",
+        "RUST_LOG",
+        "RUST_BACKTRACE",
+    ];
+
+    /// Prefixes of variables that don't affect what a build produces, like
+    /// [`Self::NON_ARTIFACT_VARS`].
+    const NON_ARTIFACT_PREFIXES: &'static [&'static str] =
+        &["CARGO_TERM_", "CARGO_NET_", "CARGO_HTTP_", "SCCACHE_"];
+
+    /// Creates a new build environment that passes through the default variables, along with
+    /// `extra`.
+    pub(crate) fn new(extra: impl IntoIterator<Item = String>) -> Self {
+        let mut allowlist: Vec<_> = Self::DEFAULT_ALLOWLIST
+            .iter()
+            .map(|name| (*name).to_owned())
+            .chain(extra)
+            .collect();
+        allowlist.sort_unstable();
+        allowlist.dedup();
+        Self {
+            allowlist,
+            set: BTreeMap::new(),
+        }
+    }
+
+    /// Sets variables for builds, with later values for the same variable taking precedence.
+    pub(crate) fn with_vars(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.set.extend(vars);
+        self
+    }
+
+    /// Returns true if setting this variable may change the artifacts a build produces, so that
+    /// it needs to be part of the directory hash.
+    pub(crate) fn affects_artifacts(name: &str) -> bool {
+        !(Self::NON_ARTIFACT_VARS.contains(&name)
+            || Self::NON_ARTIFACT_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix)))
+    }
+
+    /// Returns the allowlisted variables from hasp's environment, along with the variables set
+    /// explicitly.
+    fn vars(&self) -> BTreeMap<String, OsString> {
+        env::vars_os()
+            .filter_map(|(name, value)| {
+                let name = name.into_string().ok()?;
+                self.is_allowed(&name).then_some((name, value))
+            })
+            .chain(
+                self.set
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.into())),
+            )
+            .collect()
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        // Variable names are case-insensitive on Windows.
+        self.allowlist.iter().any(|allowed| {
+            if IS_WINDOWS {
+                allowed.eq_ignore_ascii_case(name)
+            } else {
+                allowed == name
+            }
         })
     }
+
+    /// Returns a hash of the variables builds are run with.
+    fn hash(vars: &BTreeMap<String, OsString>) -> String {
+        let mut hasher = XxHash64::default();
+        hasher.write_u64(vars.len() as u64);
+        for (name, value) in vars {
+            hash_bytes(name, &mut hasher);
+            hash_bytes(value.to_string_lossy().as_bytes(), &mut hasher);
+        }
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+impl Default for BuildEnv {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
 }
 
 #[derive(Debug)]
 struct CargoResolver {
     metadata: CargoDirectory,
+    build_opts: CargoBuildOpts,
+    creator: ConnectionCreator,
 }
 
 #[async_trait]
@@ -86,64 +393,115 @@ impl PackageResolverImpl for CargoResolver {
         req: DirectoryVersionReq,
         output_opts: OutputOpts,
     ) -> Result<Box<dyn PackageFetcherImpl>> {
-        let req = req
-            .as_semver()
-            .ok_or_else(|| eyre!("failed to parse requirement {} as semver", req.as_str()))?;
-
-        // TODO: make it configurable, use crates.io API directly
-
-        let (config, crate_) = {
-            let mut index = Index::new_cargo_default()?;
-            fetch_crates_io(&mut index)?;
-            let config = index
-                .index_config()
-                .wrap_err("failed to get crates.io index config")?;
-
-            let crate_ = index
-                .crate_(&name)
-                .ok_or_else(|| eyre!("crate '{}' not found on crates.io", name))?;
-            (config, crate_)
-        };
+        if req.as_literal().is_none() && req.as_semver().is_none() {
+            bail!("failed to parse requirement {} as semver", req);
+        }
 
-        // Look through all the versions and find the highest one that matches.
-        let matching_versions: BTreeMap<Version, &crates_index::Version> = crate_
-            .versions()
-            .iter()
-            .filter_map(|crate_info| {
-                // Skip yanked versions.
-                if crate_info.is_yanked() {
-                    return None;
-                }
+        if let Some(mirror) = &self.build_opts.mirror {
+            if let Some((version, crate_file)) = find_in_mirror(mirror, &name, &req)? {
+                let package = NameVersionDisplay::semver(&name, &version);
+                tracing::debug!(
+                    target: "hasp::output::working::mirror",
+                    %package,
+                    %crate_file,
+                    "Using {package} from mirror at {crate_file}",
+                );
+                return Ok(Box::new(CargoFileFetcher::new(
+                    name,
+                    version,
+                    crate_file,
+                    self.metadata.clone(),
+                    self.build_opts.clone(),
+                    output_opts,
+                )));
+            }
+        }
+        if self.build_opts.offline {
+            bail!(
+                "{} matching {} isn't in the mirror, and --offline was passed (hint: add its \
+                 .crate file to the directory in install.mirror)",
+                name,
+                req,
+            );
+        }
 
-                let version = match crate_info.version().parse::<Version>() {
-                    Ok(version) => version,
-                    Err(_) => {
-                        // TODO: what to do about versions that don't parse?
-                        return None;
-                    }
-                };
+        let entry = lookup_crate(
+            self.metadata.registry.as_deref(),
+            &name,
+            &self.build_opts,
+            &self.creator,
+        )
+        .await?;
 
-                req.matches(&version).then(|| (version, crate_info))
+        let rust_version_filter = if self.build_opts.respect_rust_version {
+            Some(RustVersionFilter {
+                rustc: self.rustc_release()?,
+                rust_versions: entry.rust_versions,
             })
-            .collect();
-
-        // This is the version that matches.
-        let (version, crate_info) = match matching_versions.into_iter().next_back() {
-            Some(x) => x,
-            None => bail!("no matching version found for crate {}, req {}", name, req,),
+        } else {
+            None
         };
+        let (version, crate_info) = select_version(
+            &name,
+            &req,
+            entry.crate_.versions(),
+            self.build_opts.allow_yanked,
+            rust_version_filter.as_ref(),
+        )?;
 
         Ok(Box::new(CargoFetcher {
             name,
             version,
-            config,
+            config: entry.config,
             crate_info: crate_info.clone(),
             metadata: self.metadata.clone(),
+            build_opts: self.build_opts.clone(),
             output_opts,
         }))
     }
 }
 
+impl CargoResolver {
+    /// Returns the release of rustc that builds use, e.g. 1.56.0. Pre-releases count as the
+    /// release they precede, as they do for Cargo's `rust-version` checks.
+    fn rustc_release(&self) -> Result<Version> {
+        let mut vars = self.build_opts.build_env.vars();
+        vars.extend(
+            self.metadata
+                .env
+                .iter()
+                .map(|(name, value)| (name.clone(), value.into())),
+        );
+        let rustc = vars
+            .get("RUSTC")
+            .cloned()
+            .unwrap_or_else(|| OsString::from("rustc"));
+        let output = duct::cmd(&rustc, ["--version"])
+            .full_env(vars)
+            .stdout_capture()
+            .stderr_null()
+            .read()
+            .wrap_err_with(|| {
+                format!(
+                    "failed to run {} --version (hint: pass --ignore-rust-version to resolve \
+                     versions without checking their rust-version)",
+                    rustc.to_string_lossy()
+                )
+            })?;
+        output
+            .split_whitespace()
+            .nth(1)
+            .and_then(parse_rust_version)
+            .ok_or_else(|| {
+                eyre!(
+                    "unexpected output from {} --version: {}",
+                    rustc.to_string_lossy(),
+                    output.trim()
+                )
+            })
+    }
+}
+
 #[derive(Debug)]
 struct CargoFetcher {
     name: String,
@@ -151,6 +509,7 @@ struct CargoFetcher {
     config: IndexConfig,
     crate_info: crates_index::Version,
     metadata: CargoDirectory,
+    build_opts: CargoBuildOpts,
     output_opts: OutputOpts,
 }
 
@@ -160,44 +519,96 @@ impl PackageFetcherImpl for CargoFetcher {
         DirectoryVersion::Semantic(self.version.clone())
     }
 
+    fn download_url(&self) -> Option<String> {
+        self.crate_info.download_url(&self.config)
+    }
+
     async fn fetch(&self, fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>> {
         // Fetch this version.
         let url = self
             .crate_info
             .download_url(&self.config)
             .ok_or_else(|| eyre!("failed to create download URL"))?;
-        let download_path = fetch_dir.join(format!("{}-{}.crate", self.name, self.version));
+        let download_path = self.download_path(fetch_dir);
 
-        fetch_url(&url, &download_path)
+        let client = http_client::client()?;
+        self.build_opts
+            .network
+            .run(&format!("download {}", url), || {
+                fetch_url(client, &url, &download_path)
+            })
             .await
             .wrap_err_with(|| format!("failed to download {} to {}", url, download_path))?;
 
-        // Extract the crate. (Can this be anything other than tar.gz?)
-        let tar_gz = fs::File::open(&download_path)
-            .wrap_err_with(|| format!("failed to open {}", download_path))?;
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        archive
-            .unpack(fetch_dir)
-            .wrap_err_with(|| format!("failed to extract {} as .tar.gz", download_path))?;
+        extract_crate(&download_path, fetch_dir)?;
+        let checksum = checksum_file(&download_path)?;
+        Ok(self.make_installer(fetch_dir, checksum))
+    }
 
-        let extracted_dir = fetch_dir.join(format!("{}-{}", self.name, self.version));
-        Ok(Box::new(CargoInstaller {
+    async fn resume(&self, fetch_dir: &Utf8Path) -> Result<Option<Box<dyn PackageInstallerImpl>>> {
+        // The extracted crate is reused as-is, along with the target directory from any earlier
+        // build, as long as the downloaded crate still matches the index.
+        if !self.extracted_dir(fetch_dir).exists() {
+            return Ok(None);
+        }
+        let checksum = checksum_file(&self.download_path(fetch_dir))?;
+        let expected = Checksum::Sha256(Sha256Hash::from_be_bytes(*self.crate_info.checksum()));
+        if checksum != expected {
+            return Ok(None);
+        }
+        Ok(Some(self.make_installer(fetch_dir, checksum)))
+    }
+}
+
+impl CargoFetcher {
+    fn download_path(&self, fetch_dir: &Utf8Path) -> Utf8PathBuf {
+        fetch_dir.join(format!("{}-{}.crate", self.name, self.version))
+    }
+
+    fn extracted_dir(&self, fetch_dir: &Utf8Path) -> Utf8PathBuf {
+        fetch_dir.join(format!("{}-{}", self.name, self.version))
+    }
+
+    fn make_installer(&self, fetch_dir: &Utf8Path, checksum: Checksum) -> Box<CargoInstaller> {
+        let extracted_dir = self.extracted_dir(fetch_dir);
+        Box::new(CargoInstaller {
             name: self.name.clone(),
             version: self.version.clone(),
+            artifact: Some(CrateArtifact {
+                checksum,
+                download_path: self.download_path(fetch_dir),
+                download_url: self.crate_info.download_url(&self.config),
+            }),
+            lock_file: extracted_dir.join(CARGO_LOCK),
             extracted_dir,
             metadata: self.metadata.clone(),
+            build_opts: self.build_opts.clone(),
             output_opts: self.output_opts,
-        }))
+        })
     }
 }
 
+/// A downloaded .crate file.
+#[derive(Debug)]
+struct CrateArtifact {
+    checksum: Checksum,
+    download_path: Utf8PathBuf,
+    download_url: Option<String>,
+}
+
 #[derive(Debug)]
 struct CargoInstaller {
     name: String,
     version: Version,
+    /// The .crate file the package was extracted from. `None` for packages built from a local
+    /// directory.
+    artifact: Option<CrateArtifact>,
+    /// The directory `cargo build` is run in.
     extracted_dir: Utf8PathBuf,
+    /// The lock file the build uses, which is in the workspace root for local directories.
+    lock_file: Utf8PathBuf,
     metadata: CargoDirectory,
+    build_opts: CargoBuildOpts,
     output_opts: OutputOpts,
     // TODO: --locked etc?
 }
@@ -205,43 +616,147 @@ struct CargoInstaller {
 #[async_trait]
 impl PackageInstallerImpl for CargoInstaller {
     fn installing_metadata(&self) -> Value {
-        // TODO: maybe other kinds of information here, like resolved features etc
-        Value::Null
+        let vars = self.build_vars();
+        let (rustc_version, host) = match self.rustc_version() {
+            Ok((version, host)) => (Some(version), host),
+            Err(err) => {
+                tracing::debug!("failed to determine the rustc version: {:#}", err);
+                (None, None)
+            }
+        };
+        let cargo_version = self
+            .cargo_version()
+            .map_err(|err| tracing::debug!("failed to determine the cargo version: {:#}", err))
+            .ok();
+        let metadata = CargoBuildMetadata {
+            rustc_version,
+            cargo_version,
+            host,
+            default_features: self.metadata.default_features,
+            features: self.metadata.features.clone(),
+            profile: self.metadata.profile.clone(),
+            rustflags: vars
+                .get("RUSTFLAGS")
+                .map(|rustflags| redact_env_value(&rustflags.to_string_lossy())),
+            env_hash: BuildEnv::hash(&vars),
+            env: redact_env(&self.build_opts.build_env.set),
+            command: self
+                .cargo_cli()
+                .all_args()
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+        };
+        serde_json::to_value(metadata).expect("build metadata is serializable")
     }
 
     fn add_to_hasher(&self, hasher: &mut XxHash64) {
-        hasher.write_u8(self.metadata.default_features as u8);
+        hash_directory(&self.metadata, hasher);
     }
 
-    async fn install(&self) -> Result<TempInstalledPackage> {
-        // TODO: fetch binaries if already available
-        let mut cargo_cli = CargoCli::new("build", self.output_opts);
+    fn shareable(&self) -> bool {
+        // Local directories can change without their version changing.
+        self.artifact.is_some()
+    }
 
-        // TODO: features etc
-        if !self.metadata.default_features {
-            cargo_cli.add_arg("--no-default-features");
+    fn prefer_prebuilt(&self) -> bool {
+        self.build_opts.prefer_prebuilt
+    }
+
+    fn remote_cache_key(&self) -> Option<RemoteCacheKey> {
+        self.artifact.as_ref()?;
+        if self.build_opts.offline {
+            tracing::debug!(
+                target: "hasp::output::working::remote_cache_offline",
+                name = %self.name,
+                "Not using the remote cache for {name}: --offline was passed",
+                name = self.name,
+            );
+            return None;
+        }
+        match self.rustc_version() {
+            Ok((toolchain, _)) => Some(RemoteCacheKey {
+                toolchain,
+                network: self.build_opts.network.clone(),
+            }),
+            Err(err) => {
+                let error = format!("{:#}", err);
+                tracing::debug!(
+                    target: "hasp::output::working::rustc_version_failed",
+                    name = %self.name,
+                    %error,
+                    "Not using the remote cache for {name}: {error}",
+                    name = self.name,
+                );
+                None
+            }
         }
+    }
 
+    fn artifact_checksum(&self) -> Option<Checksum> {
+        Some(self.artifact.as_ref()?.checksum.clone())
+    }
+
+    fn signed_artifact(&self) -> Option<SignedArtifact> {
+        let artifact = self.artifact.as_ref()?;
+        Some(SignedArtifact::File {
+            path: artifact.download_path.clone(),
+            // Offline, only signatures next to the artifact are used.
+            url: if self.build_opts.offline {
+                None
+            } else {
+                artifact.download_url.clone()
+            },
+            network: self.build_opts.network.clone(),
+        })
+    }
+
+    async fn install(&self, build_log: &BuildLog) -> Result<TempInstalledPackage> {
+        let package = NameVersionDisplay::semver(&self.name, &self.version);
         tracing::debug!(
             target: "hasp::output::working::building",
-            "Building with cargo in {}", self.extracted_dir,
+            %package,
+            dir = %self.extracted_dir,
+            "Building {package} with cargo in {dir}",
+            dir = self.extracted_dir,
         );
 
-        // Build the artifacts.
-        let reader = cargo_cli
-            .add_args(["--release", "--message-format", "json-render-diagnostics"])
-            .to_expression()
-            .dir(&self.extracted_dir)
-            .unchecked()
-            .reader()
-            .wrap_err("failed to start build process")?;
-        let messages = Message::parse_stream(BufReader::new(reader));
+        // Build the artifacts in an isolated environment. Only the names of variables are logged,
+        // since their values may contain credentials.
+        let vars = self.build_vars();
+        build_log.write_line(format_args!(
+            "Running cargo build in {} with environment variables {}",
+            self.extracted_dir,
+            vars.keys()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ));
+        let (expression, stderr_tail) = StderrTail::attach(
+            self.cargo_cli()
+                .full_env(vars)
+                .to_expression()
+                .dir(&self.extracted_dir)
+                .unchecked(),
+            build_log,
+        )?;
+        let reader = Arc::new(
+            expression
+                .reader()
+                .wrap_err("failed to start build process")?,
+        );
+        drop(expression);
+        let _child_guard = cancel::register_child(reader.clone());
+        let messages = Message::parse_stream(BufReader::new(&*reader));
 
         let mut installed_files = BTreeMap::new();
 
         for message in messages {
             let message = message.wrap_err("failed to parse Cargo message")?;
             if let Message::CompilerArtifact(artifact) = message {
+                if !self.is_selected(&artifact.target) {
+                    continue;
+                }
                 if let Some(temp_path) = artifact.executable {
                     let file_name = temp_path.file_name().expect("file name should exist");
                     // TODO: attach metadata?
@@ -257,15 +772,25 @@ impl PackageInstallerImpl for CargoInstaller {
             }
         }
 
+        // The process has exited now that its output has been fully read. If it was killed because
+        // hasp was cancelled, report that rather than the exit status.
+        cancel::check()?;
+        if let Some(output) = reader
+            .try_wait()
+            .wrap_err("failed to wait for build process")?
+        {
+            ProcessFailed::check("cargo build", &output.status, stderr_tail.finish())?;
+        }
+
         if installed_files.is_empty() {
             bail!("crate does not have any binaries");
         }
 
         // Also attach the Cargo.lock file.
         installed_files.insert(
-            "Cargo.lock".to_owned(),
+            CARGO_LOCK.to_owned(),
             TempInstalledFile {
-                temp_path: self.extracted_dir.join("Cargo.lock"),
+                temp_path: self.lock_file.clone(),
                 metadata: serde_json::Value::Null,
                 is_binary: false,
             },
@@ -280,37 +805,1782 @@ impl PackageInstallerImpl for CargoInstaller {
     }
 }
 
-async fn fetch_url(url: &str, download_path: &Utf8Path) -> Result<()> {
-    tracing::debug!(
-        target: "hasp::output::working::downloading",
-        "Downloading {} to {}", url.bold(), download_path.as_str().bold(),
-    );
-    let resp = reqwest::get(url).await?;
-    let bytes = resp.bytes().await?;
+impl CargoInstaller {
+    /// Returns the `cargo build` invocation for this package, other than its environment.
+    fn cargo_cli(&self) -> CargoCli {
+        let mut cargo_cli =
+            CargoCli::new("build", self.build_opts.cargo.as_deref(), self.output_opts);
+        cargo_cli.add_config(&self.metadata.config);
+        if self.metadata.path.is_some() {
+            // The package may be the root of a workspace with other default members.
+            cargo_cli.add_args(["--package", &self.name]);
+        }
+        if !self.metadata.default_features {
+            cargo_cli.add_arg("--no-default-features");
+        }
+        if !self.metadata.features.is_empty() {
+            let features = self
+                .metadata
+                .features
+                .iter()
+                .map(|feature| feature.as_str())
+                .collect::<Vec<_>>()
+                .join(",");
+            cargo_cli.add_args(["--features", &features]);
+        }
+        match &self.metadata.profile {
+            Some(profile) => cargo_cli.add_args(["--profile", profile]),
+            None => cargo_cli.add_arg("--release"),
+        };
+        if let Some(jobs) = self.build_opts.jobs {
+            cargo_cli.add_args(["--jobs".to_owned(), jobs.to_string()]);
+        }
+        if self.build_opts.offline {
+            cargo_cli.add_arg("--offline");
+        }
+        for bin in &self.metadata.bins {
+            cargo_cli.add_args(["--bin", bin]);
+        }
+        if self.metadata.examples {
+            // Selecting examples on their own would skip binaries.
+            if self.metadata.bins.is_empty() {
+                cargo_cli.add_arg("--bins");
+            }
+            cargo_cli.add_arg("--examples");
+        }
+        cargo_cli.add_args(["--message-format", "json-render-diagnostics"]);
+        // Arguments passed through go last, so that they can override the ones above.
+        cargo_cli.add_args(&self.metadata.args);
+        cargo_cli
+    }
 
-    tracing::trace!(
-        target: "hasp::output::working::writing_bytes",
-        "Writing {} bytes to {}", bytes.len(), download_path);
-    std::fs::write(download_path, &bytes)?;
-    tracing::debug!(
-        target: "hasp::output::downloaded",
-        "Downloaded {} to {}", url, download_path,
-    );
+    /// Returns the version of rustc that builds would use, e.g. `rustc 1.56.0 (09c42c458
+    /// 2021-10-18)`, and the host target it reports.
+    fn rustc_version(&self) -> Result<(String, Option<String>)> {
+        let vars = self.build_vars();
+        let rustc = vars
+            .get("RUSTC")
+            .cloned()
+            .unwrap_or_else(|| OsString::from("rustc"));
+        let output = duct::cmd(&rustc, ["-vV"])
+            .full_env(vars)
+            .dir(&self.extracted_dir)
+            .stdout_capture()
+            .stderr_null()
+            .read()
+            .wrap_err_with(|| format!("failed to run {} -vV", rustc.to_string_lossy()))?;
+        parse_rustc_version(&output)
+            .ok_or_else(|| eyre!("unexpected output from {} -vV", rustc.to_string_lossy()))
+    }
 
-    Ok(())
-}
+    /// Returns the version of Cargo that builds would use, e.g. `cargo 1.56.0 (4ed5d137b
+    /// 2021-10-04)`.
+    fn cargo_version(&self) -> Result<String> {
+        let output = CargoCli::new(
+            "version",
+            self.build_opts.cargo.as_deref(),
+            self.output_opts,
+        )
+        .full_env(self.build_vars())
+        .to_expression()
+        .dir(&self.extracted_dir)
+        .stdout_capture()
+        .stderr_null()
+        .read()
+        .wrap_err("failed to run cargo version")?;
+        Ok(output.trim().to_owned())
+    }
 
-// Fetch the crates.io index, once per process invocation.
-fn fetch_crates_io(index: &mut Index) -> Result<()> {
-    static FETCH_DONE: OnceCell<()> = OnceCell::new();
-    FETCH_DONE.get_or_try_init(|| {
-        tracing::info!(
-            target: "hasp::output::working::updating_index",
-            "Updating crates.io index",
+    /// Returns the variables the build is run with.
+    fn build_vars(&self) -> BTreeMap<String, OsString> {
+        // Variables set explicitly take precedence over the build cache.
+        let mut vars: BTreeMap<_, _> = self
+            .build_opts
+            .build_cache
+            .iter()
+            .map(|cache| cache.var(&self.name))
+            .collect();
+        vars.extend(self.build_opts.build_env.vars());
+        vars.extend(
+            self.metadata
+                .env
+                .iter()
+                .map(|(name, value)| (name.clone(), value.into())),
         );
-        index
-            .update()
-            .wrap_err("failed to retrieve crates.io index")
+        vars
+    }
+
+    /// Returns true if the artifacts of this build target should be installed.
+    fn is_selected(&self, target: &Target) -> bool {
+        let kind_selected = target.kind.iter().any(|kind| match kind.as_str() {
+            "bin" => true,
+            "example" => self.metadata.examples,
+            _ => false,
+        });
+        // Examples are selected as a group, so the list of binaries doesn't apply to them.
+        kind_selected
+            && (self.metadata.bins.is_empty()
+                || self.metadata.bins.contains(&target.name)
+                || !target.kind.iter().any(|kind| kind == "bin"))
+    }
+}
+
+/// Installs a crate from a `.crate` file on disk, e.g. one copied to an air-gapped machine,
+/// rather than fetching it from a registry.
+#[derive(Debug)]
+pub(crate) struct CargoFileFetcher {
+    name: String,
+    version: Version,
+    crate_file: Utf8PathBuf,
+    metadata: CargoDirectory,
+    build_opts: CargoBuildOpts,
+    output_opts: OutputOpts,
+}
+
+impl CargoFileFetcher {
+    pub(crate) fn new(
+        name: String,
+        version: Version,
+        crate_file: Utf8PathBuf,
+        metadata: CargoDirectory,
+        build_opts: CargoBuildOpts,
+        output_opts: OutputOpts,
+    ) -> Self {
+        Self {
+            name,
+            version,
+            crate_file,
+            metadata,
+            build_opts,
+            output_opts,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns a requirement for exactly the version in the file.
+    pub(crate) fn req(&self) -> VersionReq {
+        VersionReq::parse(&format!("={}", self.version))
+            .expect("exact version requirement is valid")
+    }
+
+    /// Returns a matcher for installs of the crate with the same metadata.
+    pub(crate) fn matcher(&self, creator: ConnectionCreator) -> CargoMatcher {
+        CargoMatcher::new(self.metadata.clone(), self.build_opts.clone(), creator)
+    }
+}
+
+#[async_trait]
+impl PackageFetcherImpl for CargoFileFetcher {
+    fn version(&self) -> DirectoryVersion {
+        DirectoryVersion::Semantic(self.version.clone())
+    }
+
+    async fn fetch(&self, fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>> {
+        let download_path = fetch_dir.join(format!("{}-{}.crate", self.name, self.version));
+        fs::copy(&self.crate_file, &download_path)
+            .wrap_err_with(|| format!("failed to copy {} to {}", self.crate_file, download_path))?;
+        // Detached minisign and sigstore signatures next to the file are verified like those
+        // downloaded from a registry.
+        for extension in ["minisig", "sig"] {
+            let signature = Utf8PathBuf::from(format!("{}.{}", self.crate_file, extension));
+            if signature.exists() {
+                let dest = format!("{}.{}", download_path, extension);
+                fs::copy(&signature, &dest)
+                    .wrap_err_with(|| format!("failed to copy {} to {}", signature, dest))?;
+            }
+        }
+
+        extract_crate(&download_path, fetch_dir)?;
+        let extracted_dir = fetch_dir.join(format!("{}-{}", self.name, self.version));
+        if !extracted_dir.join("Cargo.toml").is_file() {
+            bail!(
+                "{} doesn't contain {}-{}/Cargo.toml (hint: .crate files are created by `cargo \
+                 package`, or downloaded from a registry)",
+                self.crate_file,
+                self.name,
+                self.version,
+            );
+        }
+
+        let checksum = checksum_file(&download_path)?;
+        Ok(Box::new(CargoInstaller {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            artifact: Some(CrateArtifact {
+                checksum,
+                download_path,
+                download_url: None,
+            }),
+            lock_file: extracted_dir.join(CARGO_LOCK),
+            extracted_dir,
+            metadata: self.metadata.clone(),
+            build_opts: self.build_opts.clone(),
+            output_opts: self.output_opts,
+        }))
+    }
+}
+
+/// A Cargo package with binaries in a local directory, as found by [`local_packages`].
+#[derive(Clone, Debug)]
+pub(crate) struct LocalPackage {
+    pub(crate) name: String,
+    /// The version in the package's `Cargo.toml`.
+    pub(crate) version: Version,
+    /// The directory containing the package's `Cargo.toml`.
+    pub(crate) dir: Utf8PathBuf,
+    /// The root of the workspace the package is in, which holds its lock file.
+    pub(crate) workspace_root: Utf8PathBuf,
+}
+
+/// Returns the package in `dir`, or if `workspace` is true, every member of the workspace `dir`
+/// is in that has binaries, as reported by `cargo metadata`.
+pub(crate) fn local_packages(
+    dir: &Utf8Path,
+    workspace: bool,
+    cargo: Option<&Utf8Path>,
+    output_opts: OutputOpts,
+) -> Result<Vec<LocalPackage>> {
+    let manifest_path = dir.join("Cargo.toml");
+    let manifest_path = manifest_path.canonicalize_utf8().wrap_err_with(|| {
+        format!(
+            "failed to find {} (hint: --path takes the directory of a Cargo package or workspace)",
+            manifest_path
+        )
     })?;
-    Ok(())
+    let output = CargoCli::new("metadata", cargo, output_opts)
+        .add_args([
+            "--format-version",
+            "1",
+            "--no-deps",
+            "--manifest-path",
+            manifest_path.as_str(),
+        ])
+        .to_expression()
+        .stdout_capture()
+        .read()
+        .wrap_err_with(|| format!("failed to run cargo metadata for {}", manifest_path))?;
+    let metadata = MetadataCommand::parse(output)
+        .wrap_err_with(|| format!("failed to parse cargo metadata for {}", manifest_path))?;
+
+    let has_bins = |package: &&Package| {
+        package
+            .targets
+            .iter()
+            .any(|target| target.kind.iter().any(|kind| kind == "bin"))
+    };
+    let members = metadata
+        .packages
+        .iter()
+        .filter(|package| metadata.workspace_members.contains(&package.id));
+    let selected: Vec<_> = if workspace {
+        let selected: Vec<_> = members.filter(has_bins).collect();
+        if selected.is_empty() {
+            bail!(
+                "no members of the workspace at {} have binaries",
+                metadata.workspace_root
+            );
+        }
+        selected
+    } else {
+        let is_in_dir = |package: &&Package| {
+            package.manifest_path.canonicalize_utf8().ok().as_ref() == Some(&manifest_path)
+        };
+        let package = members.into_iter().find(is_in_dir).ok_or_else(|| {
+            eyre!(
+                "{} is the root of a workspace rather than a package (hint: pass --workspace \
+                 to install every member with binaries)",
+                dir
+            )
+        })?;
+        if !has_bins(&package) {
+            bail!("{} does not have any binaries", package.name);
+        }
+        vec![package]
+    };
+
+    Ok(selected
+        .into_iter()
+        .map(|package| LocalPackage {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            dir: package
+                .manifest_path
+                .parent()
+                .expect("manifest path is in a directory")
+                .to_path_buf(),
+            workspace_root: metadata.workspace_root.clone(),
+        })
+        .collect())
+}
+
+/// Returns the version that packages built from a local directory are recorded with: the output
+/// of `git describe` if the directory is in a git repository, or the current time otherwise.
+///
+/// Uncommitted changes don't change the output of `git describe`, so the current time is appended
+/// for them, so that a new build is installed each time.
+pub(crate) fn local_version(dir: &Utf8Path) -> String {
+    let now = Local::now().format("%Y%m%d%H%M%S");
+    let describe = duct::cmd("git", ["describe", "--tags", "--always", "--dirty"])
+        .dir(dir)
+        .stdout_capture()
+        .stderr_null()
+        .read();
+    match describe {
+        Ok(describe) if !describe.trim().is_empty() => {
+            let describe = describe.trim();
+            if describe.ends_with("-dirty") {
+                format!("{}.{}", describe, now)
+            } else {
+                describe.to_owned()
+            }
+        }
+        _ => now.to_string(),
+    }
+}
+
+/// Builds a package in a local directory, e.g. a member of the user's own workspace, rather than
+/// fetching it.
+#[derive(Debug)]
+pub(crate) struct CargoPathFetcher {
+    package: LocalPackage,
+    /// The literal version the package is recorded with, from [`local_version`].
+    version: String,
+    metadata: CargoDirectory,
+    build_opts: CargoBuildOpts,
+    output_opts: OutputOpts,
+}
+
+impl CargoPathFetcher {
+    pub(crate) fn new(
+        package: LocalPackage,
+        version: String,
+        metadata: CargoDirectory,
+        build_opts: CargoBuildOpts,
+        output_opts: OutputOpts,
+    ) -> Self {
+        let metadata = CargoDirectory {
+            path: Some(package.dir.clone()),
+            ..metadata
+        };
+        Self {
+            package,
+            version,
+            metadata,
+            build_opts,
+            output_opts,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.package.name
+    }
+
+    /// Returns a requirement for exactly the version being built.
+    pub(crate) fn req(&self) -> DirectoryVersionReq {
+        DirectoryVersionReq::new_literal(&self.version)
+    }
+
+    /// Returns a matcher for installs from the same directory with the same metadata.
+    pub(crate) fn matcher(&self, creator: ConnectionCreator) -> CargoMatcher {
+        CargoMatcher::new(self.metadata.clone(), self.build_opts.clone(), creator)
+    }
+}
+
+#[async_trait]
+impl PackageFetcherImpl for CargoPathFetcher {
+    fn version(&self) -> DirectoryVersion {
+        DirectoryVersion::new_literal(self.version.clone())
+    }
+
+    async fn fetch(&self, _fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>> {
+        // The package is built in place, with the workspace's lock file and target directory.
+        Ok(Box::new(CargoInstaller {
+            name: self.package.name.clone(),
+            version: self.package.version.clone(),
+            artifact: None,
+            extracted_dir: self.package.dir.clone(),
+            lock_file: self.package.workspace_root.join(CARGO_LOCK),
+            metadata: self.metadata.clone(),
+            build_opts: self.build_opts.clone(),
+            output_opts: self.output_opts,
+        }))
+    }
+}
+
+/// Returns the name and version of the crate in a `.crate` file, from its file name, e.g.
+/// `ripgrep-13.0.0.crate`.
+pub(crate) fn parse_crate_file_name(path: &Utf8Path) -> Result<(String, Version)> {
+    if let Some(stem) = path
+        .file_name()
+        .and_then(|file_name| file_name.strip_suffix(".crate"))
+    {
+        // Names can contain dashes too, so find the first dash followed by a valid version.
+        for (idx, _) in stem.match_indices('-').filter(|&(idx, _)| idx > 0) {
+            if let Ok(version) = stem[idx + 1..].parse::<Version>() {
+                return Ok((stem[..idx].to_owned(), version));
+            }
+        }
+    }
+    bail!(
+        "{} isn't named like a .crate file (hint: .crate files are named <name>-<version>.crate, \
+         e.g. ripgrep-13.0.0.crate)",
+        path
+    )
+}
+
+/// Returns the highest version of a crate in a mirror that matches a requirement, and the
+/// `.crate` file for it.
+fn find_in_mirror(
+    mirror: &Utf8Path,
+    name: &str,
+    req: &DirectoryVersionReq,
+) -> Result<Option<(Version, Utf8PathBuf)>> {
+    let entries = mirror.read_dir().wrap_err_with(|| {
+        format!(
+            "failed to read mirror at {} (hint: it's configured in install.mirror)",
+            mirror
+        )
+    })?;
+    let mut best: Option<(Version, Utf8PathBuf)> = None;
+    for entry in entries {
+        let entry = entry.wrap_err_with(|| format!("failed to read mirror at {}", mirror))?;
+        let path = match entry.file_name().into_string() {
+            Ok(file_name) => mirror.join(file_name),
+            Err(_) => continue,
+        };
+        // Other files, like the index of a local registry, are skipped.
+        let (crate_name, version) = match parse_crate_file_name(&path) {
+            Ok(parsed) => parsed,
+            Err(_) => continue,
+        };
+        if crate_name != name || !req.matches(&DirectoryVersion::new_semantic(version.clone())) {
+            continue;
+        }
+        let is_higher = match &best {
+            Some((best, _)) => version > *best,
+            None => true,
+        };
+        if is_higher {
+            best = Some((version, path));
+        }
+    }
+    Ok(best)
+}
+
+/// Extracts a downloaded `.crate` file, a gzipped tarball, into a directory.
+fn extract_crate(crate_file: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    let tar_gz =
+        fs::File::open(crate_file).wrap_err_with(|| format!("failed to open {}", crate_file))?;
+    Archive::new(GzDecoder::new(tar_gz))
+        .unpack(dest)
+        .wrap_err_with(|| format!("failed to extract {} as .tar.gz", crate_file))
+}
+
+/// Adopts binaries that were previously installed with `cargo install`, rather than building
+/// them from source.
+#[derive(Debug)]
+pub(crate) struct CargoAdoptFetcher {
+    version: Version,
+    metadata: CargoDirectory,
+    binaries: Vec<Utf8PathBuf>,
+}
+
+impl CargoAdoptFetcher {
+    pub(crate) fn new(
+        version: Version,
+        metadata: CargoDirectory,
+        binaries: Vec<Utf8PathBuf>,
+    ) -> Self {
+        Self {
+            version,
+            metadata,
+            binaries,
+        }
+    }
+}
+
+#[async_trait]
+impl PackageFetcherImpl for CargoAdoptFetcher {
+    fn version(&self) -> DirectoryVersion {
+        DirectoryVersion::Semantic(self.version.clone())
+    }
+
+    async fn fetch(&self, fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>> {
+        // Copy the binaries rather than moving them, so that the existing installation keeps
+        // working until it's removed with `cargo uninstall`.
+        let binaries = self
+            .binaries
+            .iter()
+            .map(|binary| {
+                let file_name = binary
+                    .file_name()
+                    .ok_or_else(|| eyre!("binary path {} has no file name", binary))?;
+                let dest = fetch_dir.join(file_name);
+                fs::copy(binary, &dest)
+                    .wrap_err_with(|| format!("failed to copy {} to {}", binary, dest))?;
+                Ok(dest)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Box::new(CargoAdoptInstaller {
+            metadata: self.metadata.clone(),
+            binaries,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct CargoAdoptInstaller {
+    metadata: CargoDirectory,
+    binaries: Vec<Utf8PathBuf>,
+}
+
+#[async_trait]
+impl PackageInstallerImpl for CargoAdoptInstaller {
+    fn installing_metadata(&self) -> Value {
+        serde_json::json!({ "adopted": true })
+    }
+
+    fn add_to_hasher(&self, hasher: &mut XxHash64) {
+        // Hash the same way as CargoInstaller, so that reinstalling this version later uses the
+        // same directory.
+        hash_directory(&self.metadata, hasher);
+    }
+
+    fn artifact_checksum(&self) -> Option<Checksum> {
+        // Adopted binaries weren't fetched from anywhere.
+        None
+    }
+
+    async fn install(&self, _build_log: &BuildLog) -> Result<TempInstalledPackage> {
+        if self.binaries.is_empty() {
+            bail!("crate does not have any binaries");
+        }
+
+        let installed_files = self
+            .binaries
+            .iter()
+            .map(|temp_path| {
+                let file_name = temp_path
+                    .file_name()
+                    .expect("file name checked while fetching");
+                (
+                    file_name.to_owned(),
+                    TempInstalledFile {
+                        temp_path: temp_path.clone(),
+                        metadata: Value::Null,
+                        is_binary: true,
+                    },
+                )
+            })
+            .collect();
+
+        Ok(TempInstalledPackage {
+            installed_files,
+            metadata: self.installing_metadata(),
+        })
+    }
+}
+
+/// Parses the output of `rustc -vV` into the version line and the host target.
+fn parse_rustc_version(output: &str) -> Option<(String, Option<String>)> {
+    let mut lines = output.lines();
+    let version = lines.next()?.trim();
+    if !version.starts_with("rustc ") {
+        return None;
+    }
+    let host = lines
+        .filter_map(|line| line.strip_prefix("host:"))
+        .map(|host| host.trim().to_owned())
+        .next();
+    Some((version.to_owned(), host))
+}
+
+fn hash_directory(metadata: &CargoDirectory, hasher: &mut XxHash64) {
+    hasher.write_u8(metadata.default_features as u8);
+    hasher.write_u64(metadata.features.len() as u64);
+    for feature in &metadata.features {
+        hash_bytes(feature, hasher);
+    }
+    for value in [&metadata.profile, &metadata.registry] {
+        match value {
+            Some(value) => {
+                hasher.write_u8(1);
+                hash_bytes(value, hasher);
+            }
+            None => hasher.write_u8(0),
+        }
+    }
+    // Only hash the selection if one was made, so that existing hashes stay the same.
+    if !metadata.bins.is_empty() || metadata.examples {
+        hasher.write_u64(metadata.bins.len() as u64);
+        for bin in &metadata.bins {
+            hash_bytes(bin, hasher);
+        }
+        hasher.write_u8(metadata.examples as u8);
+    }
+    // Likewise for environment variables and configuration.
+    if !metadata.env.is_empty() {
+        hasher.write_u64(metadata.env.len() as u64);
+        for (name, value) in &metadata.env {
+            hash_bytes(name, hasher);
+            hash_bytes(value, hasher);
+        }
+    }
+    if !metadata.config.is_empty() {
+        hasher.write_u64(metadata.config.len() as u64);
+        for value in &metadata.config {
+            hash_bytes(value, hasher);
+        }
+    }
+    if !metadata.args.is_empty() {
+        hasher.write_u64(metadata.args.len() as u64);
+        for arg in &metadata.args {
+            hash_bytes(arg, hasher);
+        }
+    }
+    if let Some(path) = &metadata.path {
+        hash_bytes(path.as_str(), hasher);
+    }
+}
+
+async fn fetch_url(client: &HttpClient, url: &str, download_path: &Utf8Path) -> Result<()> {
+    tracing::debug!(
+        target: "hasp::output::working::downloading",
+        url = %url.bold(),
+        path = %download_path.as_str().bold(),
+        "Downloading {url} to {path}",
+        url = url.bold(),
+        path = download_path.as_str().bold(),
+    );
+    let resp = client.get(url).send().await?.error_for_status()?;
+    let bytes = resp.bytes().await?;
+
+    tracing::trace!(
+        target: "hasp::output::working::writing_bytes",
+        bytes = bytes.len(),
+        %download_path,
+        "Writing {bytes} bytes to {download_path}",
+        bytes = bytes.len(),
+    );
+    std::fs::write(download_path, &bytes)?;
+    tracing::debug!(
+        target: "hasp::output::downloaded",
+        %url,
+        %download_path,
+        "Downloaded {url} to {download_path}",
+    );
+
+    Ok(())
+}
+
+/// The URL of the sparse index for crates.io.
+const CRATES_IO_SPARSE_INDEX: &str = "https://index.crates.io";
+
+/// The prefix for registry URLs that use the sparse protocol.
+const SPARSE_PREFIX: &str = "sparse+";
+
+/// Returns the URL of the sparse index to resolve crates with, or `None` if the git index should
+/// be used.
+fn sparse_index_url(registry: Option<&str>, protocol: IndexProtocol) -> Option<&str> {
+    match registry {
+        Some(url) => url.strip_prefix(SPARSE_PREFIX),
+        None => match protocol {
+            IndexProtocol::Git => None,
+            IndexProtocol::Sparse => Some(CRATES_IO_SPARSE_INDEX),
+        },
+    }
+}
+
+/// Returns the highest version of a crate that hasn't been yanked, from the index for a registry
+/// or crates.io if `registry` is `None`.
+pub(crate) async fn latest_crate_version(
+    registry: Option<&str>,
+    name: &str,
+    build_opts: &CargoBuildOpts,
+    creator: &ConnectionCreator,
+) -> Result<Option<Version>> {
+    let entry = lookup_crate(registry, name, build_opts, creator).await?;
+    Ok(entry
+        .crate_
+        .versions()
+        .iter()
+        .filter(|crate_info| !crate_info.is_yanked())
+        .filter_map(|crate_info| crate_info.version().parse::<Version>().ok())
+        .max())
+}
+
+/// Looks up a crate in the index for a registry, or crates.io if `registry` is `None`.
+async fn lookup_crate(
+    registry: Option<&str>,
+    name: &str,
+    build_opts: &CargoBuildOpts,
+    creator: &ConnectionCreator,
+) -> Result<IndexEntry> {
+    let registry_name = registry.unwrap_or("crates.io");
+    let network = &build_opts.network;
+    match sparse_index_url(registry, build_opts.index_protocol) {
+        Some(index_url) => match resolve_sparse(index_url, name, network).await {
+            Ok(Some(resolved)) => Ok(resolved),
+            Ok(None) => {
+                // Every lookup is a request, so only check for the most common mistake.
+                let mut suggestions = BTreeSet::new();
+                for candidate in name_suggestions(name, false) {
+                    if let Ok(Some(entry)) = resolve_sparse(index_url, &candidate, network).await {
+                        suggestions.insert(entry.crate_.name().to_owned());
+                    }
+                }
+                Err(crate_not_found(name, registry_name, &suggestions))
+            }
+            // Only crates.io has a git index to fall back to.
+            Err(err) if registry.is_none() => {
+                let error = format!("{:#}", err);
+                tracing::warn!(
+                    target: "hasp::output::sparse_index_failed",
+                    %registry_name,
+                    %error,
+                    "Warning failed to use sparse index for {registry_name}, falling back to git index: {error}",
+                );
+                resolve_git(registry, name, build_opts, creator)
+            }
+            Err(err) => Err(err),
+        },
+        None => resolve_git(registry, name, build_opts, creator),
+    }
+}
+
+/// Returns true if this version of a crate has been yanked from its registry since it was
+/// installed.
+pub(crate) async fn is_yanked(
+    name: &str,
+    version: &Version,
+    metadata: &CargoDirectory,
+    build_opts: &CargoBuildOpts,
+    creator: &ConnectionCreator,
+) -> Result<bool> {
+    let entry = lookup_crate(metadata.registry.as_deref(), name, build_opts, creator).await?;
+    Ok(entry.crate_.versions().iter().any(|crate_info| {
+        crate_info.is_yanked()
+            && crate_info.version().parse::<Version>().ok().as_ref() == Some(version)
+    }))
+}
+
+/// Looks up a crate in a git index, updating the index first if it wasn't updated recently.
+fn resolve_git(
+    registry: Option<&str>,
+    name: &str,
+    build_opts: &CargoBuildOpts,
+    creator: &ConnectionCreator,
+) -> Result<IndexEntry> {
+    let registry_name = registry.unwrap_or("crates.io");
+    let mut index = match registry {
+        Some(url) => Index::from_url(url)
+            .wrap_err_with(|| format!("failed to open registry index at {}", url))?,
+        None => Index::new_cargo_default()?,
+    };
+    fetch_index(&mut index, registry_name, build_opts, creator)?;
+    let config = index
+        .index_config()
+        .wrap_err_with(|| format!("failed to get {} index config", registry_name))?;
+
+    let crate_ = match index.crate_(name) {
+        Some(crate_) => crate_,
+        None => {
+            // Lookups in a local index are cheap, so look for typos as well.
+            let suggestions = name_suggestions(name, true)
+                .iter()
+                .filter_map(|candidate| index.crate_(candidate))
+                .map(|crate_| crate_.name().to_owned())
+                .collect();
+            return Err(crate_not_found(name, registry_name, &suggestions));
+        }
+    };
+    let rust_versions = if build_opts.respect_rust_version {
+        git_rust_versions(&index, name)
+    } else {
+        BTreeMap::new()
+    };
+    Ok(IndexEntry {
+        config,
+        crate_,
+        rust_versions,
+    })
+}
+
+/// Reads the `rust-version` of each version of a crate from a git index. The index library
+/// drops the field, so the entry is read through the git CLI instead.
+///
+/// Failures are logged rather than returned, and leave the versions unchecked.
+fn git_rust_versions(index: &Index, name: &str) -> BTreeMap<String, Version> {
+    let path = sparse_index_path(name);
+    // The index library reads the most recently fetched commit, as long as there is one.
+    for rev in ["FETCH_HEAD", "HEAD"] {
+        let entry = duct::cmd!("git", "cat-file", "blob", format!("{}:{}", rev, path))
+            .dir(index.path())
+            .stdout_capture()
+            .stderr_null()
+            .unchecked()
+            .run();
+        match entry {
+            Ok(output) if output.status.success() => return parse_rust_versions(&output.stdout),
+            Ok(_) => continue,
+            Err(err) => {
+                tracing::warn!(
+                    target: "hasp::output::rust_version_unavailable",
+                    %name,
+                    %err,
+                    "Warning failed to run git to read rust-version for {name}, so versions aren't \
+                     checked against rustc: {err}",
+                );
+                return BTreeMap::new();
+            }
+        }
+    }
+    let index_path = index.path().display();
+    tracing::warn!(
+        target: "hasp::output::rust_version_unread",
+        %name,
+        %index_path,
+        "Warning failed to read rust-version for {name} from the git index at {index_path}, so \
+         versions aren't checked against rustc",
+    );
+    BTreeMap::new()
+}
+
+/// Returns names that might have been meant instead of `name`: the name with `-` and `_`
+/// swapped, and if `typos` is true, the name with a character left out or two adjacent characters
+/// swapped.
+fn name_suggestions(name: &str, typos: bool) -> Vec<String> {
+    let mut suggestions = BTreeSet::new();
+    suggestions.insert(name.replace('-', "_"));
+    suggestions.insert(name.replace('_', "-"));
+    let chars: Vec<char> = name.chars().collect();
+    if typos && chars.len() > 1 {
+        for idx in 0..chars.len() {
+            let mut deleted = chars.clone();
+            deleted.remove(idx);
+            suggestions.insert(deleted.into_iter().collect());
+            if idx + 1 < chars.len() {
+                let mut swapped = chars.clone();
+                swapped.swap(idx, idx + 1);
+                suggestions.insert(swapped.into_iter().collect());
+            }
+        }
+    }
+    suggestions.remove(name);
+    suggestions.into_iter().collect()
+}
+
+/// Returns the error for a crate that isn't in the index, suggesting crates with similar names.
+fn crate_not_found(
+    name: &str,
+    registry_name: &str,
+    suggestions: &BTreeSet<String>,
+) -> color_eyre::Report {
+    let suggestions: Vec<_> = suggestions
+        .iter()
+        .filter(|suggestion| *suggestion != name)
+        .map(|suggestion| format!("'{}'", suggestion))
+        .collect();
+    match suggestions.as_slice() {
+        [] => eyre!("crate '{}' not found on {}", name, registry_name),
+        [suggestion] => eyre!(
+            "crate '{}' not found on {} (hint: did you mean {}?)",
+            name,
+            registry_name,
+            suggestion
+        ),
+        _ => eyre!(
+            "crate '{}' not found on {} (hint: did you mean one of {}?)",
+            name,
+            registry_name,
+            suggestions.join(", ")
+        ),
+    }
+}
+
+/// The number of available versions listed when none of them match.
+const LISTED_VERSIONS: usize = 8;
+
+/// Picks the version of a crate to install from the versions in its index entry.
+///
+/// The highest version that matches is picked. Yanked versions are only used if they're the only
+/// ones that match, and only if they're allowed. If `rust_version_filter` is passed, versions
+/// requiring a newer rustc are skipped, with a warning if newer versions than the one picked were.
+fn select_version<'a>(
+    name: &str,
+    req: &DirectoryVersionReq,
+    versions: &'a [crates_index::Version],
+    allow_yanked: bool,
+    rust_version_filter: Option<&RustVersionFilter>,
+) -> Result<(Version, &'a crates_index::Version)> {
+    let parsed = versions.iter().filter_map(|crate_info| {
+        match crate_info.version().parse::<Version>() {
+            Ok(version) => Some((version, crate_info)),
+            Err(err) => {
+                // Cargo can't install these either, so there's no point failing over them.
+                let version = crate_info.version();
+                tracing::debug!(
+                    target: "hasp::output::working::invalid_version",
+                    %version,
+                    %name,
+                    %err,
+                    "Skipping version {version} of {name}, which isn't valid semver: {err}",
+                );
+                None
+            }
+        }
+    });
+
+    // As with `cargo install --version =x.y.z`, exact requirements are looked up directly rather
+    // than matched against every version.
+    let exact = exact_version_req(req);
+    let matches = |version: &Version| match &exact {
+        Some((exact, compare_build)) => {
+            version.major == exact.major
+                && version.minor == exact.minor
+                && version.patch == exact.patch
+                && version.pre == exact.pre
+                && (!compare_build || version.build == exact.build)
+        }
+        None => req.matches(&DirectoryVersion::new_semantic(version.clone())),
+    };
+
+    let mut matching_versions = BTreeMap::new();
+    let mut matching_yanked = BTreeMap::new();
+    let mut too_new = BTreeMap::new();
+    for (version, crate_info) in parsed.filter(|(version, _)| matches(version)) {
+        let requires_newer =
+            rust_version_filter.and_then(|filter| filter.requires_newer(crate_info));
+        if let Some(rust_version) = requires_newer {
+            too_new.insert(version, rust_version.clone());
+        } else if crate_info.is_yanked() {
+            matching_yanked.insert(version, crate_info);
+        } else {
+            matching_versions.insert(version, crate_info);
+        }
+    }
+
+    let selected = match matching_versions.into_iter().next_back() {
+        Some(x) => x,
+        None => match matching_yanked.into_iter().next_back() {
+            Some((version, crate_info)) if allow_yanked => {
+                let package = NameVersionDisplay::semver(name, &version);
+                tracing::warn!(
+                    target: "hasp::output::installing_yanked",
+                    %package,
+                    "Warning {package} has been yanked, installing it because --allow-yanked was \
+                     passed",
+                );
+                (version, crate_info)
+            }
+            _ => {
+                return Err(match rust_version_filter {
+                    Some(filter) if !too_new.is_empty() => {
+                        eyre!(
+                            "every version of crate {} matching req {} requires a newer rustc \
+                             than {}: {} (hint: update rustc, or pass --ignore-rust-version to \
+                             install it anyway)",
+                            name,
+                            req,
+                            filter.rustc,
+                            describe_too_new(too_new.iter().rev()),
+                        )
+                    }
+                    _ => no_matching_version(name, req, versions),
+                })
+            }
+        },
+    };
+
+    if let Some(filter) = rust_version_filter {
+        let skipped: Vec<_> = too_new.range(&selected.0..).rev().collect();
+        if !skipped.is_empty() {
+            let package = NameVersionDisplay::semver(name, &selected.0);
+            let skipped = describe_too_new(skipped);
+            tracing::warn!(
+                target: "hasp::output::rust_version_skipped",
+                %package,
+                rustc = %filter.rustc,
+                %skipped,
+                "Warning installing {package} since newer versions require a newer rustc than \
+                 {rustc}: {skipped} (hint: pass --ignore-rust-version to install the newest \
+                 version anyway)",
+                rustc = filter.rustc,
+            );
+        }
+    }
+    Ok(selected)
+}
+
+/// Describes versions skipped for their `rust-version`, newest first, e.g. `1.2.0 (rust-version
+/// 1.60.0)`.
+fn describe_too_new<'a>(too_new: impl IntoIterator<Item = (&'a Version, &'a Version)>) -> String {
+    too_new
+        .into_iter()
+        .map(|(version, rust_version)| format!("{} (rust-version {})", version, rust_version))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the version an exact requirement is for, and whether build metadata has to match too.
+///
+/// `=1.2.3` is exact, but ignores build metadata as semver requirements do. Literal requirements
+/// like `lit:1.2.3+build` are compared exactly, build metadata included. Requirements that leave
+/// out the minor or patch version, like `=1.2`, match several versions, so they aren't exact.
+fn exact_version_req(req: &DirectoryVersionReq) -> Option<(Version, bool)> {
+    if let Some(literal) = req.as_literal() {
+        return literal.parse().ok().map(|version| (version, true));
+    }
+    match req.as_semver()?.comparators.as_slice() {
+        [Comparator {
+            op: Op::Exact,
+            major,
+            minor: Some(minor),
+            patch: Some(patch),
+            pre,
+        }] => {
+            let mut version = Version::new(*major, *minor, *patch);
+            version.pre = pre.clone();
+            Some((version, false))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the error for a requirement that no version matches, listing the most recent available
+/// versions. If yanked versions match, the error says so and points at `--allow-yanked`.
+fn no_matching_version(
+    name: &str,
+    req: &DirectoryVersionReq,
+    versions: &[crates_index::Version],
+) -> color_eyre::Report {
+    let mut available = vec![];
+    let mut yanked_matches = vec![];
+    for crate_info in versions {
+        let version = match crate_info.version().parse::<Version>() {
+            Ok(version) => version,
+            Err(_) => continue,
+        };
+        if !crate_info.is_yanked() {
+            available.push(version);
+        } else if req.matches(&DirectoryVersion::new_semantic(version.clone())) {
+            yanked_matches.push(version);
+        }
+    }
+    available.sort_unstable_by(|a, b| b.cmp(a));
+    let mut listed: Vec<_> = available
+        .iter()
+        .take(LISTED_VERSIONS)
+        .map(|version| version.to_string())
+        .collect();
+    if available.len() > LISTED_VERSIONS {
+        listed.push(format!("and {} older", available.len() - LISTED_VERSIONS));
+    }
+
+    if !yanked_matches.is_empty() {
+        yanked_matches.sort_unstable_by(|a, b| b.cmp(a));
+        let yanked: Vec<_> = yanked_matches.iter().map(|v| v.to_string()).collect();
+        let mut hint = "pass --allow-yanked to install it anyway".to_owned();
+        if !listed.is_empty() {
+            hint.push_str(&format!("; available versions: {}", listed.join(", ")));
+        }
+        return eyre!(
+            "every version of crate {} matching req {} has been yanked: {} (hint: {})",
+            name,
+            req,
+            yanked.join(", "),
+            hint,
+        );
+    }
+
+    let note = if listed.is_empty() {
+        "every version has been yanked".to_owned()
+    } else {
+        format!("available versions: {}", listed.join(", "))
+    };
+    eyre!(
+        "no matching version found for crate {}, req {} ({})",
+        name,
+        req,
+        note
+    )
+}
+
+/// Looks up a crate in a sparse index, fetching only the entry for that crate. Returns `None` if
+/// the crate wasn't found.
+async fn resolve_sparse(
+    index_url: &str,
+    name: &str,
+    network: &RetryPolicy,
+) -> Result<Option<IndexEntry>> {
+    let index_url = index_url.trim_end_matches('/');
+    tracing::debug!(
+        target: "hasp::output::working::fetching_index_entry",
+        %name,
+        %index_url,
+        "Fetching index entry for {name} from {index_url}",
+    );
+
+    let client = http_client::client()?;
+    let config_url = format!("{}/config.json", index_url);
+    let config = network
+        .run(&format!("fetch {}", config_url), || {
+            fetch_bytes(client, &config_url)
+        })
+        .await?
+        .ok_or_else(|| eyre!("index config not found at {}", config_url))?;
+    let config: IndexConfig = serde_json::from_slice(&config)
+        .wrap_err_with(|| format!("failed to parse index config at {}", config_url))?;
+
+    let entry_url = format!("{}/{}", index_url, sparse_index_path(name));
+    let entry = match network
+        .run(&format!("fetch {}", entry_url), || {
+            fetch_bytes(client, &entry_url)
+        })
+        .await?
+    {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let crate_ = crates_index::Crate::from_slice(&entry)
+        .wrap_err_with(|| format!("failed to parse index entry at {}", entry_url))?;
+    Ok(Some(IndexEntry {
+        config,
+        crate_,
+        rust_versions: parse_rust_versions(&entry),
+    }))
+}
+
+/// A crate's entry in a registry index.
+#[derive(Debug)]
+struct IndexEntry {
+    config: IndexConfig,
+    crate_: crates_index::Crate,
+    /// The `rust-version` of each version of the crate that declares one, keyed by version. Only
+    /// read from git indexes if it's going to be checked.
+    rust_versions: BTreeMap<String, Version>,
+}
+
+/// Reads the `rust-version` of each version from the lines of an index entry. Lines that can't
+/// be parsed are skipped, as they are by the index library.
+fn parse_rust_versions(entry: &[u8]) -> BTreeMap<String, Version> {
+    #[derive(Deserialize)]
+    struct VersionLine {
+        vers: String,
+        rust_version: Option<String>,
+    }
+
+    entry
+        .split(|&byte| byte == b'\n')
+        .filter_map(|line| serde_json::from_slice::<VersionLine>(line).ok())
+        .filter_map(|line| {
+            Some((
+                line.vers,
+                parse_rust_version(line.rust_version.as_deref()?)?,
+            ))
+        })
+        .collect()
+}
+
+/// Parses a Rust version like `1.56` or `1.56.1`. Missing components are zero, and pre-release
+/// and build suffixes like `-nightly` are ignored.
+fn parse_rust_version(version: &str) -> Option<Version> {
+    let release = version.split(['-', '+']).next()?;
+    let mut components = release.split('.').map(|component| component.parse::<u64>());
+    let major = components.next()?.ok()?;
+    let minor = components.next().unwrap_or(Ok(0)).ok()?;
+    let patch = components.next().unwrap_or(Ok(0)).ok()?;
+    if components.next().is_some() {
+        return None;
+    }
+    Some(Version::new(major, minor, patch))
+}
+
+/// Skips versions of a crate whose `rust-version` is newer than the rustc that builds use.
+#[derive(Clone, Debug)]
+struct RustVersionFilter {
+    rustc: Version,
+    rust_versions: BTreeMap<String, Version>,
+}
+
+impl RustVersionFilter {
+    /// Returns the `rust-version` of this version of the crate if rustc is too old for it.
+    fn requires_newer(&self, crate_info: &crates_index::Version) -> Option<&Version> {
+        self.rust_versions
+            .get(crate_info.version())
+            .filter(|rust_version| **rust_version > self.rustc)
+    }
+}
+
+/// Returns the path to a crate's entry within an index, relative to the root of the index.
+fn sparse_index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+/// Fetches the contents of a URL, returning `None` if it wasn't found.
+pub(crate) async fn fetch_bytes(client: &HttpClient, url: &str) -> Result<Option<Vec<u8>>> {
+    let resp = client.get(url).send().await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let bytes = resp.error_for_status()?.bytes().await?;
+    Ok(Some(bytes.to_vec()))
+}
+
+// Fetch a registry index, once per process invocation, and only if it wasn't updated within the
+// index TTL.
+fn fetch_index(
+    index: &mut Index,
+    registry_name: &str,
+    build_opts: &CargoBuildOpts,
+    creator: &ConnectionCreator,
+) -> Result<()> {
+    static FETCHED: Lazy<Mutex<BTreeSet<String>>> = Lazy::new(Default::default);
+    // Hold the lock while updating so that concurrent resolves wait for the update to finish.
+    let mut fetched = FETCHED.lock().expect("fetch lock is never poisoned");
+    if fetched.contains(registry_name) {
+        return Ok(());
+    }
+
+    let mut conn = creator.create()?;
+    if let Some(last_update) = IndexUpdateRow::get(registry_name, &conn)? {
+        let age = (Local::now() - last_update.update_time)
+            .to_std()
+            .unwrap_or_default();
+        if age < build_opts.index_ttl {
+            tracing::debug!(
+                target: "hasp::output::working::index_fresh",
+                %registry_name,
+                seconds = age.as_secs(),
+                "Skipping update of {registry_name} index, last updated {seconds}s ago (pass \
+                 --refresh to update)",
+                seconds = age.as_secs(),
+            );
+            fetched.insert(registry_name.to_owned());
+            return Ok(());
+        }
+    }
+
+    tracing::info!(
+        target: "hasp::output::working::updating_index",
+        registry = %registry_name,
+        "Updating {registry} index",
+        registry = registry_name,
+    );
+    build_opts
+        .network
+        .run_blocking(&format!("update {} index", registry_name), || {
+            Ok(index.update()?)
+        })
+        .wrap_err_with(|| format!("failed to retrieve {} index", registry_name))?;
+
+    let txn = creator.write_transaction(&mut conn)?;
+    IndexUpdateRow {
+        registry: registry_name.to_owned(),
+        update_time: Local::now(),
+    }
+    .upsert(&txn)?;
+    txn.commit()
+        .wrap_err_with(|| format!("failed to commit index update for {}", registry_name))?;
+
+    fetched.insert(registry_name.to_owned());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_crate_file_name_basic() {
+        let cases = [
+            ("ripgrep-13.0.0.crate", "ripgrep", "13.0.0"),
+            (
+                "/tmp/cargo-nextest-0.9.0-beta.1.crate",
+                "cargo-nextest",
+                "0.9.0-beta.1",
+            ),
+        ];
+        for (path, name, version) in cases {
+            let (parsed_name, parsed_version) =
+                parse_crate_file_name(Utf8Path::new(path)).expect("file name parsed");
+            assert_eq!(parsed_name, name, "{}", path);
+            assert_eq!(parsed_version.to_string(), version, "{}", path);
+        }
+        for path in ["ripgrep.crate", "ripgrep-13.0.0.tar.gz", "-13.0.0.crate"] {
+            assert!(
+                parse_crate_file_name(Utf8Path::new(path)).is_err(),
+                "{} is rejected",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn find_in_mirror_picks_highest() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        for file in [
+            "foo-1.0.0.crate",
+            "foo-1.2.0.crate",
+            "foo-2.0.0.crate",
+            "foo-bar-3.0.0.crate",
+            "config.json",
+        ] {
+            fs::write(dir.join(file), b"").expect("file written");
+        }
+
+        let req = DirectoryVersionReq::from("^1".parse::<VersionReq>().expect("valid req"));
+        let (version, path) = find_in_mirror(dir, "foo", &req)
+            .expect("mirror read")
+            .expect("version found");
+        assert_eq!(version.to_string(), "1.2.0");
+        assert_eq!(path, dir.join("foo-1.2.0.crate"));
+
+        let req = DirectoryVersionReq::from("*".parse::<VersionReq>().expect("valid req"));
+        let (version, _) = find_in_mirror(dir, "foo", &req)
+            .expect("mirror read")
+            .expect("version found");
+        assert_eq!(version.to_string(), "2.0.0");
+        assert!(find_in_mirror(dir, "bar", &req)
+            .expect("mirror read")
+            .is_none());
+    }
+
+    #[test]
+    fn parse_rustc_version_basic() {
+        let output = "rustc 1.56.0 (09c42c458 2021-10-18)\n\
+                      binary: rustc\n\
+                      commit-hash: 09c42c45858d5f3aedfa670698275303a3d19afa\n\
+                      host: x86_64-unknown-linux-gnu\n\
+                      release: 1.56.0\n";
+        assert_eq!(
+            parse_rustc_version(output),
+            Some((
+                "rustc 1.56.0 (09c42c458 2021-10-18)".to_owned(),
+                Some("x86_64-unknown-linux-gnu".to_owned())
+            ))
+        );
+        assert_eq!(parse_rustc_version("error: no such toolchain"), None);
+    }
+
+    #[test]
+    fn build_env_allowlist() {
+        let build_env = BuildEnv::new(vec!["MY_VAR".to_owned(), "PATH".to_owned()]);
+        assert!(build_env.is_allowed("PATH"));
+        assert!(build_env.is_allowed("MY_VAR"));
+        assert!(!build_env.is_allowed("RUSTFLAGS"));
+        assert_eq!(
+            build_env
+                .allowlist
+                .iter()
+                .filter(|name| *name == "PATH")
+                .count(),
+            1,
+            "allowlist is deduplicated"
+        );
+
+        let vars: BTreeMap<_, _> = [("PATH".to_owned(), OsString::from("/bin"))]
+            .into_iter()
+            .collect();
+        let mut changed = vars.clone();
+        changed.insert("HOME".to_owned(), OsString::from("/home/user"));
+        assert_eq!(BuildEnv::hash(&vars), BuildEnv::hash(&vars.clone()));
+        assert_ne!(BuildEnv::hash(&vars), BuildEnv::hash(&changed));
+
+        let build_env = build_env.with_vars([("CARGO_TARGET_DIR".to_owned(), "/tmp".to_owned())]);
+        assert_eq!(
+            build_env.vars().get("CARGO_TARGET_DIR"),
+            Some(&OsString::from("/tmp")),
+            "variables set explicitly don't need to be allowlisted"
+        );
+        assert!(BuildEnv::affects_artifacts("RUSTFLAGS"));
+        assert!(BuildEnv::affects_artifacts("CC"));
+        assert!(!BuildEnv::affects_artifacts("CARGO_TARGET_DIR"));
+        assert!(!BuildEnv::affects_artifacts("CARGO_TERM_COLOR"));
+    }
+
+    #[test]
+    fn matcher_redacts_env() {
+        let metadata = CargoDirectory {
+            default_features: true,
+            features: BTreeSet::new(),
+            profile: None,
+            registry: None,
+            bins: BTreeSet::new(),
+            examples: false,
+            env: BTreeMap::from([("RUSTFLAGS".to_owned(), "--cfg secret".to_owned())]),
+            config: vec![],
+            args: vec![],
+            path: None,
+        };
+        let matcher = CargoMatcher::new(
+            metadata.clone(),
+            CargoBuildOpts::default(),
+            ConnectionCreator::new_in_memory(),
+        );
+
+        // The recorded metadata has a hash of the value instead of the value.
+        let recorded = matcher.metadata();
+        assert!(!recorded.to_string().contains("secret"), "{}", recorded);
+        assert_eq!(
+            recorded["env"]["RUSTFLAGS"],
+            format!("blake3:{}", blake3::hash(b"--cfg secret").to_hex())
+        );
+        assert!(matcher.matches_metadata(&recorded));
+
+        // Directories recorded before values were redacted still match.
+        let unredacted = serde_json::to_value(&metadata).expect("serialized");
+        assert!(matcher.matches_metadata(&unredacted));
+
+        let other = CargoDirectory {
+            env: BTreeMap::from([("RUSTFLAGS".to_owned(), "--cfg other".to_owned())]),
+            ..metadata
+        };
+        let other = serde_json::to_value(other.redacted()).expect("serialized");
+        assert!(!matcher.matches_metadata(&other));
+    }
+
+    #[test]
+    fn offline_installer() {
+        let metadata = CargoDirectory {
+            default_features: true,
+            features: BTreeSet::new(),
+            profile: None,
+            registry: None,
+            bins: BTreeSet::new(),
+            examples: false,
+            env: BTreeMap::new(),
+            config: vec![],
+            args: vec![],
+            path: None,
+        };
+        let installer = |offline| CargoInstaller {
+            name: "foo".to_owned(),
+            version: Version::new(1, 0, 0),
+            artifact: Some(CrateArtifact {
+                checksum: Checksum::Sha256(Sha256Hash::from_be_bytes([0; Sha256Hash::BYTES])),
+                download_path: "/mirror/foo-1.0.0.crate".into(),
+                download_url: Some("https://example.com/foo-1.0.0.crate".to_owned()),
+            }),
+            extracted_dir: "/work/foo-1.0.0".into(),
+            lock_file: "/work/foo-1.0.0/Cargo.lock".into(),
+            metadata: metadata.clone(),
+            build_opts: CargoBuildOpts {
+                offline,
+                ..Default::default()
+            },
+            output_opts: output_opts(),
+        };
+        let signature_url = |installer: &CargoInstaller| match installer.signed_artifact() {
+            Some(SignedArtifact::File { url, .. }) => url,
+            other => panic!("unexpected signed artifact {:?}", other),
+        };
+
+        let online = installer(false);
+        assert!(!online.cargo_cli().all_args().contains(&"--offline"));
+        assert!(signature_url(&online).is_some());
+
+        let offline = installer(true);
+        assert!(offline.cargo_cli().all_args().contains(&"--offline"));
+        assert_eq!(
+            signature_url(&offline),
+            None,
+            "signatures aren't downloaded"
+        );
+        assert!(
+            offline.remote_cache_key().is_none(),
+            "the remote cache isn't used"
+        );
+    }
+
+    #[test]
+    fn local_packages_basic() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let root = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let write = |path: &str, contents: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().expect("path has a parent"))
+                .expect("directory created");
+            fs::write(&path, contents).expect("file written");
+        };
+        write("Cargo.toml", "[workspace]\nmembers = [\"tool\", \"lib\"]\n");
+        write(
+            "tool/Cargo.toml",
+            "[package]\nname = \"tool\"\nversion = \"0.3.0\"\nedition = \"2021\"\n",
+        );
+        write("tool/src/main.rs", "fn main() {}\n");
+        write(
+            "lib/Cargo.toml",
+            "[package]\nname = \"lib\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        );
+        write("lib/src/lib.rs", "");
+        let local_packages =
+            |dir: &Utf8Path, workspace| local_packages(dir, workspace, None, output_opts());
+
+        // Only members with binaries are installed from a workspace.
+        let packages = local_packages(root, true).expect("workspace members found");
+        assert_eq!(packages.len(), 1);
+        let tool = &packages[0];
+        assert_eq!(tool.name, "tool");
+        assert_eq!(tool.version, Version::new(0, 3, 0));
+        let root = root.canonicalize_utf8().expect("root canonicalized");
+        assert_eq!(tool.dir.canonicalize_utf8().ok(), Some(root.join("tool")));
+        assert_eq!(
+            tool.workspace_root.canonicalize_utf8().ok(),
+            Some(root.clone())
+        );
+
+        let packages = local_packages(&root.join("tool"), false).expect("package found");
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "tool");
+
+        let err = local_packages(&root, false).expect_err("workspace root isn't a package");
+        assert!(
+            err.to_string().contains("is the root of a workspace"),
+            "{}",
+            err
+        );
+        let err = local_packages(&root.join("lib"), false).expect_err("lib has no binaries");
+        assert_eq!(err.to_string(), "lib does not have any binaries");
+        let err = local_packages(&root.join("missing"), false).expect_err("no Cargo.toml");
+        assert!(err.to_string().starts_with("failed to find"), "{}", err);
+    }
+
+    #[test]
+    fn local_version_basic() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let root = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let is_time =
+            |version: &str| version.len() == 14 && version.bytes().all(|b| b.is_ascii_digit());
+        let version = local_version(root);
+        assert!(is_time(&version), "outside git, {} is the time", version);
+
+        let git = |args: &[&str]| {
+            duct::cmd("git", args)
+                .dir(root)
+                .env("GIT_AUTHOR_NAME", "hasp")
+                .env("GIT_AUTHOR_EMAIL", "hasp@example.com")
+                .env("GIT_COMMITTER_NAME", "hasp")
+                .env("GIT_COMMITTER_EMAIL", "hasp@example.com")
+                .stdout_null()
+                .stderr_null()
+                .run()
+                .expect("git succeeded");
+        };
+        fs::write(root.join("file"), "one").expect("file written");
+        git(&["init", "-q"]);
+        git(&["add", "file"]);
+        git(&["commit", "-q", "-m", "one"]);
+        git(&["tag", "v1.0.0"]);
+        assert_eq!(local_version(root), "v1.0.0");
+
+        // Uncommitted changes get a new version each time they're built.
+        fs::write(root.join("file"), "two").expect("file written");
+        let version = local_version(root);
+        let time = version
+            .strip_prefix("v1.0.0-dirty.")
+            .unwrap_or_else(|| panic!("{} is dirty", version));
+        assert!(is_time(time), "{} ends with the time", version);
+    }
+
+    #[test]
+    fn resolver_errors() {
+        let mut suggestions = name_suggestions("ripgrpe", true);
+        assert!(
+            suggestions.contains(&"ripgrep".to_owned()),
+            "swapped characters"
+        );
+        assert!(!suggestions.contains(&"ripgrpe".to_owned()), "name itself");
+        suggestions = name_suggestions("cargo_hakari", false);
+        assert_eq!(suggestions, ["cargo-hakari"]);
+        assert_eq!(
+            crate_not_found(
+                "cargo_hakari",
+                "crates.io",
+                &BTreeSet::from(["cargo-hakari".to_owned()])
+            )
+            .to_string(),
+            "crate 'cargo_hakari' not found on crates.io (hint: did you mean 'cargo-hakari'?)"
+        );
+
+        let entry: String = [("1.0.0", false), ("1.1.0", true), ("2.0.0", false)]
+            .iter()
+            .map(|(version, yanked)| {
+                format!(
+                    "{{\"name\":\"foo\",\"vers\":\"{}\",\"deps\":[],\"features\":{{}},\
+                     \"cksum\":\"{}\",\"yanked\":{}}}\n",
+                    version,
+                    "00".repeat(32),
+                    yanked
+                )
+            })
+            .collect();
+        let crate_ = crates_index::Crate::from_slice(entry.as_bytes()).expect("entry parsed");
+        let req = DirectoryVersionReq::from("^1.1".parse::<semver::VersionReq>().unwrap());
+        assert_eq!(
+            no_matching_version("foo", &req, crate_.versions()).to_string(),
+            "every version of crate foo matching req ^1.1 has been yanked: 1.1.0 (hint: pass \
+             --allow-yanked to install it anyway; available versions: 2.0.0, 1.0.0)"
+        );
+        let req = DirectoryVersionReq::from("^3".parse::<semver::VersionReq>().unwrap());
+        assert_eq!(
+            no_matching_version("foo", &req, crate_.versions()).to_string(),
+            "no matching version found for crate foo, req ^3 (available versions: 2.0.0, 1.0.0)"
+        );
+    }
+
+    #[test]
+    fn select_version_exact() {
+        let entry: String = [
+            ("1.0.0", false),
+            ("1.1.0", true),
+            ("1.2.0+build.1", false),
+            ("not-semver", false),
+        ]
+        .iter()
+        .map(|(version, yanked)| {
+            format!(
+                "{{\"name\":\"foo\",\"vers\":\"{}\",\"deps\":[],\"features\":{{}},\
+                 \"cksum\":\"{}\",\"yanked\":{}}}\n",
+                version,
+                "00".repeat(32),
+                yanked
+            )
+        })
+        .collect();
+        let crate_ = crates_index::Crate::from_slice(entry.as_bytes()).expect("entry parsed");
+        let select = |req: &str, allow_yanked: bool| {
+            select_version(
+                "foo",
+                &DirectoryVersionReq::new(req),
+                crate_.versions(),
+                allow_yanked,
+                None,
+            )
+            .map(|(version, _)| version.to_string())
+        };
+
+        assert_eq!(select("=1.0.0", false).expect("exact"), "1.0.0");
+        assert_eq!(select("^1", false).expect("highest"), "1.2.0+build.1");
+        assert!(
+            select("=1.1.0", false).is_err(),
+            "yanked needs --allow-yanked"
+        );
+        assert_eq!(select("=1.1.0", true).expect("yanked allowed"), "1.1.0");
+        // Build metadata is ignored by `=`, but not by literal requirements.
+        assert_eq!(select("=1.2.0", false).expect("exact"), "1.2.0+build.1");
+        assert_eq!(
+            select("lit:1.2.0+build.1", false).expect("literal"),
+            "1.2.0+build.1"
+        );
+        assert!(select("lit:1.2.0+build.2", false).is_err());
+        assert!(select("lit:1.2.0", false).is_err());
+
+        assert_eq!(
+            exact_version_req(&DirectoryVersionReq::new("=1.2.3-rc.1")),
+            Some(("1.2.3-rc.1".parse().expect("valid version"), false))
+        );
+        assert_eq!(exact_version_req(&DirectoryVersionReq::new("=1.2")), None);
+        assert_eq!(exact_version_req(&DirectoryVersionReq::new("1.2.3")), None);
+    }
+
+    #[test]
+    fn select_version_rust_version() {
+        let entry: String = [
+            ("1.0.0", None),
+            ("1.1.0", Some("1.56")),
+            ("1.2.0", Some("1.60.0")),
+            ("2.0.0", Some("1.70")),
+        ]
+        .iter()
+        .map(|(version, rust_version)| {
+            let rust_version = rust_version
+                .map(|rust_version| format!(",\"rust_version\":\"{}\"", rust_version))
+                .unwrap_or_default();
+            format!(
+                "{{\"name\":\"foo\",\"vers\":\"{}\",\"deps\":[],\"features\":{{}},\
+                 \"cksum\":\"{}\",\"yanked\":false{}}}\n",
+                version,
+                "00".repeat(32),
+                rust_version
+            )
+        })
+        .collect();
+        let crate_ = crates_index::Crate::from_slice(entry.as_bytes()).expect("entry parsed");
+        let filter = RustVersionFilter {
+            rustc: parse_rust_version("1.58.0-nightly").expect("valid rustc version"),
+            rust_versions: parse_rust_versions(entry.as_bytes()),
+        };
+        assert_eq!(filter.rustc, Version::new(1, 58, 0));
+        assert_eq!(
+            filter.rust_versions.get("1.2.0"),
+            Some(&Version::new(1, 60, 0))
+        );
+        assert!(!filter.rust_versions.contains_key("1.0.0"));
+
+        let select = |req: &str| {
+            select_version(
+                "foo",
+                &DirectoryVersionReq::new(req),
+                crate_.versions(),
+                false,
+                Some(&filter),
+            )
+            .map(|(version, _)| version.to_string())
+        };
+        assert_eq!(select("*").expect("compatible version"), "1.1.0");
+        assert_eq!(
+            select("^2")
+                .expect_err("every match requires a newer rustc")
+                .to_string(),
+            "every version of crate foo matching req ^2 requires a newer rustc than 1.58.0: 2.0.0 \
+             (rust-version 1.70.0) (hint: update rustc, or pass --ignore-rust-version to install \
+             it anyway)"
+        );
+    }
+
+    #[test]
+    fn sparse_index_path_basic() {
+        assert_eq!(sparse_index_path("a"), "1/a");
+        assert_eq!(sparse_index_path("fd"), "2/fd");
+        assert_eq!(sparse_index_path("Bat"), "3/b/bat");
+        assert_eq!(sparse_index_path("ripgrep"), "ri/pg/ripgrep");
+
+        assert_eq!(sparse_index_url(None, IndexProtocol::Git), None);
+        assert_eq!(
+            sparse_index_url(None, IndexProtocol::Sparse),
+            Some(CRATES_IO_SPARSE_INDEX)
+        );
+        assert_eq!(
+            sparse_index_url(
+                Some("sparse+https://example.com/index/"),
+                IndexProtocol::Git
+            ),
+            Some("https://example.com/index/")
+        );
+        assert_eq!(
+            sparse_index_url(Some("https://example.com/index"), IndexProtocol::Sparse),
+            None
+        );
+    }
+
+    fn output_opts() -> OutputOpts {
+        OutputOpts {
+            quiet: false,
+            verbose: 0,
+            color: None,
+            message_format: None,
+        }
+    }
 }
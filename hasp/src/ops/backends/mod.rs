@@ -2,5 +2,13 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 mod cargo;
+mod npm;
+mod oci;
+mod plugin;
+mod registry;
 
 pub(crate) use cargo::*;
+pub(crate) use npm::*;
+pub(crate) use oci::*;
+pub(crate) use plugin::*;
+pub(crate) use registry::*;
@@ -0,0 +1,354 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! npm package fetcher and installer.
+
+use crate::{
+    helpers::write_script,
+    models::directory::{DirectoryRow, InstalledRow},
+    npm_cli::{node_path, registry, NpmCli},
+    ops::{
+        checksum_file, PackageFetcherImpl, PackageInstallerImpl, PackageMatcherImpl,
+        PackageResolverImpl, TempInstalledFile, TempInstalledPackage,
+    },
+    output::OutputOpts,
+    platform::{sh_quote, ScriptFlavor, IS_WINDOWS},
+    process::{run_with_stderr_tail, BuildLog},
+};
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use hasp_metadata::{Checksum, DirectoryVersion, DirectoryVersionReq, NpmDirectory};
+use semver::Version;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, hash::Hasher};
+use twox_hash::XxHash64;
+
+#[derive(Debug)]
+pub(crate) struct NpmMatcher {
+    metadata: NpmDirectory,
+}
+
+impl NpmMatcher {
+    pub(crate) fn new(metadata: NpmDirectory) -> Self {
+        Self { metadata }
+    }
+}
+
+#[async_trait]
+impl PackageMatcherImpl for NpmMatcher {
+    #[inline]
+    fn namespace(&self) -> &'static str {
+        "npm"
+    }
+
+    fn best_match(&self, rows: Vec<DirectoryRow>) -> Result<Option<DirectoryRow>> {
+        Ok(rows.into_iter().next())
+    }
+
+    fn best_installed_match(
+        &self,
+        installed_rows: Vec<InstalledRow>,
+    ) -> Result<Option<InstalledRow>> {
+        Ok(installed_rows.into_iter().next())
+    }
+
+    fn metadata(&self) -> Value {
+        serde_json::to_value(&self.metadata).unwrap_or(Value::Null)
+    }
+
+    fn source(&self, _name: &str) -> String {
+        format!("npm:{}", registry())
+    }
+
+    fn make_resolver(&self) -> Box<dyn PackageResolverImpl> {
+        Box::new(NpmResolver {
+            metadata: self.metadata.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct NpmResolver {
+    metadata: NpmDirectory,
+}
+
+#[async_trait]
+impl PackageResolverImpl for NpmResolver {
+    async fn resolve(
+        &self,
+        name: String,
+        req: DirectoryVersionReq,
+        output_opts: OutputOpts,
+    ) -> Result<Box<dyn PackageFetcherImpl>> {
+        if req.as_literal().is_none() && req.as_semver().is_none() {
+            bail!("failed to parse requirement {} as semver", req);
+        }
+
+        let output = NpmCli::new("view", output_opts)?
+            .add_args([name.as_str(), "versions", "--json"])
+            .to_expression()
+            .read()
+            .wrap_err_with(|| format!("failed to look up versions for package '{}'", name))?;
+        let versions: NpmVersions = serde_json::from_str(&output)
+            .wrap_err_with(|| format!("failed to parse versions for package '{}'", name))?;
+
+        // npm versions are always semver, so pick the highest one that matches.
+        let version = versions
+            .into_vec()
+            .into_iter()
+            .filter_map(|version| version.parse::<Version>().ok())
+            .filter(|version| req.matches(&DirectoryVersion::new_semantic(version.clone())))
+            .max();
+        let version = match version {
+            Some(version) => version,
+            None => bail!(
+                "no matching version found for package {}, req {}",
+                name,
+                req
+            ),
+        };
+
+        Ok(Box::new(NpmFetcher {
+            name,
+            version,
+            metadata: self.metadata.clone(),
+            output_opts,
+        }))
+    }
+}
+
+/// The output of `npm view <package> versions --json`: a single string if only one version has
+/// been published, an array otherwise.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NpmVersions {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl NpmVersions {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            NpmVersions::One(version) => vec![version],
+            NpmVersions::Many(versions) => versions,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NpmFetcher {
+    name: String,
+    version: Version,
+    metadata: NpmDirectory,
+    output_opts: OutputOpts,
+}
+
+#[async_trait]
+impl PackageFetcherImpl for NpmFetcher {
+    fn version(&self) -> DirectoryVersion {
+        DirectoryVersion::Semantic(self.version.clone())
+    }
+
+    async fn fetch(&self, fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>> {
+        // Download the tarball into the fetch directory. npm prints out the name of the tarball
+        // as the last line of its output.
+        let spec = format!("{}@{}", self.name, self.version);
+        let output = NpmCli::new("pack", self.output_opts)?
+            .add_arg(&spec)
+            .to_expression()
+            .dir(fetch_dir)
+            .read()
+            .wrap_err_with(|| format!("failed to download {}", spec))?;
+        let tarball_name = output
+            .lines()
+            .map(|line| line.trim())
+            .rev()
+            .find(|line| !line.is_empty())
+            .ok_or_else(|| eyre!("npm pack did not report a tarball for {}", spec))?;
+
+        let tarball = fetch_dir.join(tarball_name);
+        Ok(Box::new(NpmInstaller {
+            name: self.name.clone(),
+            checksum: checksum_file(&tarball)?,
+            tarball,
+            prefix: fetch_dir.join("prefix"),
+            wrapper_dir: fetch_dir.join("wrappers"),
+            node_path: node_path()?,
+            metadata: self.metadata.clone(),
+            output_opts: self.output_opts,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct NpmInstaller {
+    name: String,
+    tarball: Utf8PathBuf,
+    checksum: Checksum,
+    prefix: Utf8PathBuf,
+    wrapper_dir: Utf8PathBuf,
+    node_path: Utf8PathBuf,
+    metadata: NpmDirectory,
+    output_opts: OutputOpts,
+}
+
+impl NpmInstaller {
+    /// The directory within the prefix that npm installs packages into, relative to the prefix.
+    ///
+    /// This is the top-level entry that's moved into the install directory.
+    const MODULES_ROOT: &'static str = if IS_WINDOWS { "node_modules" } else { "lib" };
+
+    fn package_dir(&self, root: &Utf8Path) -> Utf8PathBuf {
+        let mut package_dir = root.join(Self::MODULES_ROOT);
+        if !IS_WINDOWS {
+            package_dir.push("node_modules");
+        }
+        package_dir.push(&self.name);
+        package_dir
+    }
+
+    /// Returns the binaries declared in package.json, as a map of names to relative paths.
+    fn binaries(&self) -> Result<BTreeMap<String, String>> {
+        let package_json_path = self.package_dir(&self.prefix).join("package.json");
+        let package_json = fs::read_to_string(&package_json_path)
+            .wrap_err_with(|| format!("failed to read {}", package_json_path))?;
+        let package_json: PackageJson = serde_json::from_str(&package_json)
+            .wrap_err_with(|| format!("failed to parse {}", package_json_path))?;
+
+        let binaries = match package_json.bin {
+            Some(NpmBin::Single(path)) => {
+                // A single binary is named after the package, without the scope.
+                let bin_name = self
+                    .name
+                    .rsplit_once('/')
+                    .map_or(self.name.as_str(), |(_, name)| name);
+                std::iter::once((bin_name.to_owned(), path)).collect()
+            }
+            Some(NpmBin::Map(binaries)) => binaries,
+            None => BTreeMap::new(),
+        };
+        Ok(binaries)
+    }
+
+    /// Writes out a wrapper script that runs `bin_path` through node, relative to the install
+    /// directory.
+    fn write_wrapper(&self, bin_name: &str, bin_path: &str) -> Result<(String, Utf8PathBuf)> {
+        // The wrapper lives at the root of the install directory, next to MODULES_ROOT.
+        let relative_dir = self.package_dir(Utf8Path::new(""));
+        let script_path = relative_dir.join(bin_path);
+        let contents = match ScriptFlavor::CURRENT {
+            ScriptFlavor::Sh => format!(
+                "#!/bin/sh\nexec {} \"$(dirname \"$0\")\"/{} \"$@\"\n",
+                sh_quote(self.node_path.as_str()),
+                sh_quote(script_path.as_str())
+            ),
+            ScriptFlavor::Cmd => format!(
+                "@\"{}\" \"%~dp0{}\" %*\r\n",
+                self.node_path,
+                script_path.as_str().replace('/', "\\")
+            ),
+        };
+
+        let file_name = ScriptFlavor::CURRENT.file_name(bin_name);
+        let wrapper_path = self.wrapper_dir.join(&file_name);
+        write_script(&wrapper_path, &contents)?;
+        Ok((file_name, wrapper_path))
+    }
+}
+
+#[async_trait]
+impl PackageInstallerImpl for NpmInstaller {
+    fn installing_metadata(&self) -> Value {
+        serde_json::json!({ "node-path": self.node_path })
+    }
+
+    fn add_to_hasher(&self, hasher: &mut XxHash64) {
+        hasher.write_u8(self.metadata.ignore_scripts as u8);
+    }
+
+    fn artifact_checksum(&self) -> Option<Checksum> {
+        Some(self.checksum.clone())
+    }
+
+    async fn install(&self, build_log: &BuildLog) -> Result<TempInstalledPackage> {
+        for dir in [&self.prefix, &self.wrapper_dir] {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("failed to create directory at {}", dir))?;
+        }
+
+        tracing::debug!(
+            target: "hasp::output::working::npm_install",
+            prefix = %self.prefix,
+            "Installing with npm into {prefix}",
+            prefix = self.prefix,
+        );
+
+        let mut npm_cli = NpmCli::new("install", self.output_opts)?;
+        npm_cli.add_args([
+            "--global",
+            "--prefix",
+            self.prefix.as_str(),
+            "--no-audit",
+            "--no-fund",
+        ]);
+        if self.metadata.ignore_scripts {
+            npm_cli.add_arg("--ignore-scripts");
+        }
+        run_with_stderr_tail(
+            "npm install",
+            npm_cli.add_arg(self.tarball.as_str()).to_expression(),
+            build_log,
+        )
+        .wrap_err_with(|| format!("npm install failed for {}", self.tarball))?;
+
+        let binaries = self.binaries()?;
+        if binaries.is_empty() {
+            bail!("package does not have any binaries");
+        }
+
+        let mut installed_files = BTreeMap::new();
+        installed_files.insert(
+            NpmInstaller::MODULES_ROOT.to_owned(),
+            TempInstalledFile {
+                temp_path: self.prefix.join(NpmInstaller::MODULES_ROOT),
+                metadata: Value::Null,
+                is_binary: false,
+            },
+        );
+        for (bin_name, bin_path) in &binaries {
+            let (file_name, temp_path) = self.write_wrapper(bin_name, bin_path)?;
+            installed_files.insert(
+                file_name,
+                TempInstalledFile {
+                    temp_path,
+                    metadata: serde_json::json!({ "script": bin_path }),
+                    is_binary: true,
+                },
+            );
+        }
+
+        Ok(TempInstalledPackage {
+            installed_files,
+            metadata: self.installing_metadata(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageJson {
+    #[serde(default)]
+    bin: Option<NpmBin>,
+}
+
+/// The `bin` field in package.json: either a single path or a map of names to paths.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NpmBin {
+    Single(String),
+    Map(BTreeMap<String, String>),
+}
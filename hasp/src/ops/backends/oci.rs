@@ -0,0 +1,361 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Container image fetcher and installer.
+//!
+//! Images aren't installed in the usual sense: instead, a wrapper script is generated that runs
+//! the image, pinned to the digest that was pulled, through docker or podman.
+
+use crate::{
+    helpers::write_script,
+    models::directory::{DirectoryRow, InstalledRow},
+    oci_cli::{runtime_path, OciCli},
+    ops::{
+        hash_bytes, PackageFetcherImpl, PackageInstallerImpl, PackageMatcherImpl,
+        PackageResolverImpl, SignedArtifact, TempInstalledFile, TempInstalledPackage,
+    },
+    output::OutputOpts,
+    platform::{sh_quote, ScriptFlavor},
+    process::BuildLog,
+};
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use hasp_metadata::{Checksum, DirectoryVersion, DirectoryVersionReq, OciDirectory};
+use serde_json::Value;
+use std::{collections::BTreeMap, fs};
+use twox_hash::XxHash64;
+
+#[derive(Debug)]
+pub(crate) struct OciMatcher {
+    metadata: OciDirectory,
+}
+
+impl OciMatcher {
+    pub(crate) fn new(metadata: OciDirectory) -> Self {
+        Self { metadata }
+    }
+
+    fn matches_metadata(&self, metadata: &Value) -> bool {
+        matches!(
+            serde_json::from_value::<OciDirectory>(metadata.clone()),
+            Ok(metadata) if metadata == self.metadata
+        )
+    }
+}
+
+#[async_trait]
+impl PackageMatcherImpl for OciMatcher {
+    #[inline]
+    fn namespace(&self) -> &'static str {
+        "oci"
+    }
+
+    fn best_match(&self, rows: Vec<DirectoryRow>) -> Result<Option<DirectoryRow>> {
+        Ok(rows
+            .into_iter()
+            .find(|row| self.matches_metadata(&row.package.metadata)))
+    }
+
+    fn best_installed_match(
+        &self,
+        installed_rows: Vec<InstalledRow>,
+    ) -> Result<Option<InstalledRow>> {
+        Ok(installed_rows
+            .into_iter()
+            .find(|row| self.matches_metadata(&row.directory_row.package.metadata)))
+    }
+
+    fn metadata(&self) -> Value {
+        serde_json::to_value(&self.metadata).unwrap_or(Value::Null)
+    }
+
+    fn source(&self, name: &str) -> String {
+        format!("oci:{}", image_registry(name))
+    }
+
+    fn make_resolver(&self) -> Box<dyn PackageResolverImpl> {
+        Box::new(OciResolver {
+            metadata: self.metadata.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct OciResolver {
+    metadata: OciDirectory,
+}
+
+#[async_trait]
+impl PackageResolverImpl for OciResolver {
+    async fn resolve(
+        &self,
+        name: String,
+        req: DirectoryVersionReq,
+        output_opts: OutputOpts,
+    ) -> Result<Box<dyn PackageFetcherImpl>> {
+        let reference = req.as_literal().unwrap_or_else(|| req.as_str());
+        if reference.is_empty() || reference == "*" {
+            bail!("image {} requires a tag or digest", name);
+        }
+
+        // Digests are fixed, but tags can be moved to point at a different image. Tags are
+        // resolved to the digest they currently point to, and both are recorded.
+        let version = if is_digest(reference) {
+            DirectoryVersion::Literal(reference.to_owned())
+        } else {
+            let image_ref = format!("{}:{}", name, reference);
+            let digest = pull_digest(&runtime_path()?, &image_ref, output_opts)?;
+            DirectoryVersion::new_floating(reference, digest)
+        };
+
+        Ok(Box::new(OciFetcher {
+            name,
+            version,
+            metadata: self.metadata.clone(),
+            output_opts,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct OciFetcher {
+    name: String,
+    /// A digest like `sha256:...`, or a tag like `1.2` along with the digest it resolved to.
+    version: DirectoryVersion,
+    metadata: OciDirectory,
+    output_opts: OutputOpts,
+}
+
+impl OciFetcher {
+    fn image_ref(&self) -> String {
+        match &self.version {
+            DirectoryVersion::Floating(version) => format!("{}@{}", self.name, version.resolved()),
+            version => format!("{}@{}", self.name, version.short_display()),
+        }
+    }
+}
+
+#[async_trait]
+impl PackageFetcherImpl for OciFetcher {
+    fn version(&self) -> DirectoryVersion {
+        self.version.clone()
+    }
+
+    async fn fetch(&self, fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>> {
+        let runtime_path = runtime_path()?;
+        // Images are always pulled by digest, so that the wrapper keeps running the same image
+        // even if a tag moves.
+        let digest = pull_digest(&runtime_path, &self.image_ref(), self.output_opts)?;
+
+        Ok(Box::new(OciInstaller {
+            name: self.name.clone(),
+            digest,
+            wrapper_dir: fetch_dir.join("wrappers"),
+            runtime_path,
+            metadata: self.metadata.clone(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct OciInstaller {
+    name: String,
+    digest: String,
+    wrapper_dir: Utf8PathBuf,
+    runtime_path: Utf8PathBuf,
+    metadata: OciDirectory,
+}
+
+impl OciInstaller {
+    fn pinned_ref(&self) -> String {
+        format!("{}@{}", self.name, self.digest)
+    }
+
+    /// Writes out a wrapper script that runs the pinned image with the current directory mounted.
+    fn write_wrapper(&self) -> Result<(String, Utf8PathBuf)> {
+        let contents = match ScriptFlavor::CURRENT {
+            ScriptFlavor::Sh => format!(
+                "#!/bin/sh\n\
+                tty_flag=\n\
+                if [ -t 0 ] && [ -t 1 ]; then tty_flag=-t; fi\n\
+                exec {} run --rm -i $tty_flag -v \"$PWD:$PWD\" -w \"$PWD\" {} \"$@\"\n",
+                sh_quote(self.runtime_path.as_str()),
+                sh_quote(&self.pinned_ref())
+            ),
+            ScriptFlavor::Cmd => format!(
+                "@\"{}\" run --rm -i -v \"%CD%:/work\" -w /work \"{}\" %*\r\n",
+                self.runtime_path,
+                self.pinned_ref()
+            ),
+        };
+
+        let file_name = ScriptFlavor::CURRENT.file_name(&self.metadata.binary);
+        let wrapper_path = self.wrapper_dir.join(&file_name);
+        write_script(&wrapper_path, &contents)?;
+        Ok((file_name, wrapper_path))
+    }
+}
+
+#[async_trait]
+impl PackageInstallerImpl for OciInstaller {
+    fn installing_metadata(&self) -> Value {
+        serde_json::json!({
+            "runtime-path": self.runtime_path,
+            "digest": self.digest,
+        })
+    }
+
+    fn signed_artifact(&self) -> Option<SignedArtifact> {
+        Some(SignedArtifact::Image {
+            reference: self.pinned_ref(),
+        })
+    }
+
+    fn add_to_hasher(&self, hasher: &mut XxHash64) {
+        hash_bytes(&self.metadata.binary, hasher);
+        // The same tag may point to different digests over time.
+        hash_bytes(&self.digest, hasher);
+    }
+
+    fn artifact_checksum(&self) -> Option<Checksum> {
+        // The digest is the checksum of the image manifest.
+        self.digest.parse().ok()
+    }
+
+    async fn install(&self, _build_log: &BuildLog) -> Result<TempInstalledPackage> {
+        fs::create_dir_all(&self.wrapper_dir)
+            .wrap_err_with(|| format!("failed to create directory at {}", self.wrapper_dir))?;
+
+        let (file_name, temp_path) = self.write_wrapper()?;
+        let mut installed_files = BTreeMap::new();
+        installed_files.insert(
+            file_name,
+            TempInstalledFile {
+                temp_path,
+                metadata: serde_json::json!({ "image": self.pinned_ref() }),
+                is_binary: true,
+            },
+        );
+
+        Ok(TempInstalledPackage {
+            installed_files,
+            metadata: self.installing_metadata(),
+        })
+    }
+}
+
+/// Returns true if a reference is a digest like `sha256:...` rather than a tag.
+fn is_digest(reference: &str) -> bool {
+    reference.contains(':')
+}
+
+/// Pulls an image, returning the digest that the reference currently points to.
+fn pull_digest(
+    runtime_path: &Utf8Path,
+    image_ref: &str,
+    output_opts: OutputOpts,
+) -> Result<String> {
+    OciCli::new(runtime_path.to_owned(), "pull", output_opts)
+        .add_arg(image_ref)
+        .to_expression()
+        .run()
+        .wrap_err_with(|| format!("failed to pull image {}", image_ref))?;
+
+    let repo_digest = OciCli::new(runtime_path.to_owned(), "image", output_opts)
+        .add_args(["inspect", "--format", "{{index .RepoDigests 0}}", image_ref])
+        .to_expression()
+        .read()
+        .wrap_err_with(|| format!("failed to inspect image {}", image_ref))?;
+    match repo_digest.trim().split_once('@') {
+        Some((_, digest)) if is_digest(digest) => Ok(digest.to_owned()),
+        _ => bail!(
+            "image {} has no repository digest (output: {})",
+            image_ref,
+            repo_digest.trim()
+        ),
+    }
+}
+
+/// Splits an image reference like `ghcr.io/owner/tool:1.2` or `tool@sha256:...` into the image
+/// name and the tag or digest. The tag defaults to `latest`.
+pub(crate) fn split_image_ref(spec: &str) -> Result<(String, String)> {
+    let (name, reference) = if let Some((name, digest)) = spec.split_once('@') {
+        (name, digest)
+    } else {
+        // A colon before the last slash is a registry port, not a tag.
+        let last_component = spec.rfind('/').map_or(0, |idx| idx + 1);
+        match spec[last_component..].rfind(':') {
+            Some(idx) => (
+                &spec[..last_component + idx],
+                &spec[last_component + idx + 1..],
+            ),
+            None => (spec, "latest"),
+        }
+    };
+
+    if name.is_empty() || reference.is_empty() {
+        bail!("invalid image reference '{}'", spec);
+    }
+    Ok((name.to_owned(), reference.to_owned()))
+}
+
+/// Returns the registry an image is pulled from: the first component of its name if it looks like
+/// a host, and Docker Hub otherwise.
+pub(crate) fn image_registry(image_name: &str) -> &str {
+    match image_name.split_once('/') {
+        Some((first, _)) if first.contains(['.', ':']) || first == "localhost" => first,
+        _ => "docker.io",
+    }
+}
+
+/// Returns the default name of the wrapper script for an image: the last component of its name.
+pub(crate) fn default_binary_name(image_name: &str) -> Result<String> {
+    image_name
+        .rsplit('/')
+        .next()
+        .filter(|binary| !binary.is_empty())
+        .map(|binary| binary.to_owned())
+        .ok_or_else(|| eyre!("unable to determine binary name for image {}", image_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_image_ref_basic() {
+        let cases = [
+            ("hadolint/hadolint", "hadolint/hadolint", "latest"),
+            ("hadolint/hadolint:v2.8.0", "hadolint/hadolint", "v2.8.0"),
+            (
+                "localhost:5000/tools/jq:1.6",
+                "localhost:5000/tools/jq",
+                "1.6",
+            ),
+            (
+                "localhost:5000/tools/jq",
+                "localhost:5000/tools/jq",
+                "latest",
+            ),
+            ("alpine@sha256:abcd", "alpine", "sha256:abcd"),
+        ];
+        for (spec, name, reference) in cases {
+            let split = split_image_ref(spec).expect("image ref parsed");
+            assert_eq!(split, (name.to_owned(), reference.to_owned()), "{}", spec);
+        }
+
+        assert!(split_image_ref("alpine:").is_err(), "empty tag is rejected");
+        assert_eq!(image_registry("ghcr.io/owner/tool"), "ghcr.io");
+        assert_eq!(image_registry("localhost:5000/tools/jq"), "localhost:5000");
+        assert_eq!(image_registry("hadolint/hadolint"), "docker.io");
+        assert_eq!(image_registry("alpine"), "docker.io");
+        assert_eq!(
+            default_binary_name("ghcr.io/owner/tool").expect("binary name found"),
+            "tool"
+        );
+    }
+}
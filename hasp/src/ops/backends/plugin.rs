@@ -0,0 +1,517 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Backends implemented by external programs, configured in `[plugins]`.
+//!
+//! ```toml
+//! [plugins.pip]
+//! command = ["hasp-pip", "--quiet"]
+//! source = "pypi.org"
+//! ```
+//!
+//! Packages in the namespace, like `pip:black`, are installed by running the command once for
+//! each step, with the step as an extra argument: `resolve`, `fetch` or `install`. The command is
+//! passed a JSON object on standard input and prints one to standard output. Standard error is
+//! shown if a step fails, and is written to the build log during installs.
+//!
+//! Every request has `protocol` (currently 1), `name` and `data`:
+//!
+//! * `resolve` is passed `req`, the version requirement, and returns `version`: a semantic
+//!   version, or any other string to be recorded as is.
+//! * `fetch` is passed `version` and `fetch-dir`, an empty directory to download the package into.
+//!   It may return `artifact`, the path of the downloaded file within `fetch-dir`, so that it can
+//!   be checked against a pinned checksum.
+//! * `install` is passed `version`, `fetch-dir` and `install-dir`, an empty directory to put the
+//!   installed files in, and returns `binaries`: the names of the binaries among them. Everything
+//!   in `install-dir` is moved into the install directory.
+//!
+//! Each step may also return `data`, which is passed to the steps after it in place of what was
+//! passed before.
+
+use crate::{
+    config::PluginConfig,
+    models::directory::{DirectoryRow, InstalledRow},
+    ops::{
+        checksum_file, PackageFetcherImpl, PackageInstallerImpl, PackageMatcherImpl,
+        PackageResolverImpl, TempInstalledFile, TempInstalledPackage,
+    },
+    output::OutputOpts,
+    process::{read_with_stderr_tail, stderr_tail, BuildLog, ProcessFailed},
+};
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use hasp_metadata::{Checksum, DirectoryVersion, DirectoryVersionReq};
+use semver::Version;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fmt, fs};
+use twox_hash::XxHash64;
+
+/// The version of the protocol spoken with plugins.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// A namespace implemented by an external program.
+#[derive(Clone, Debug)]
+pub(crate) struct PluginBackend {
+    namespace: String,
+    command: Vec<String>,
+    source: String,
+}
+
+impl PluginBackend {
+    pub(crate) fn new(namespace: &str, config: &PluginConfig) -> Result<Self> {
+        let program = match config.command.first() {
+            Some(program) => program,
+            None => bail!("plugin command is empty"),
+        };
+        let source = match &config.source {
+            Some(source) => source.clone(),
+            None => Utf8Path::new(program)
+                .file_stem()
+                .unwrap_or(program)
+                .to_owned(),
+        };
+        Ok(Self {
+            namespace: namespace.to_owned(),
+            command: config.command.clone(),
+            source,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Runs a step, passing it this request and parsing its response.
+    ///
+    /// Standard error is written to the build log if there is one, and otherwise kept for the
+    /// error if the step fails.
+    fn run<T: DeserializeOwned>(
+        &self,
+        step: PluginStep,
+        mut request: Value,
+        build_log: Option<&BuildLog>,
+    ) -> Result<T> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .expect("plugin commands are validated");
+        request["protocol"] = json!(PROTOCOL_VERSION);
+        let expression = duct::cmd(
+            program,
+            args.iter().map(String::as_str).chain([step.as_str()]),
+        )
+        .env("HASP_NAMESPACE", &self.namespace)
+        .stdin_bytes(request.to_string());
+        let description = format!("{} {}", program, step);
+
+        let stdout = match build_log {
+            Some(build_log) => read_with_stderr_tail(&description, expression, build_log)?,
+            None => {
+                let output = expression
+                    .stdout_capture()
+                    .stderr_capture()
+                    .unchecked()
+                    .run()
+                    .wrap_err_with(|| format!("failed to run {}", description))?;
+                ProcessFailed::check(&description, &output.status, stderr_tail(&output.stderr))?;
+                String::from_utf8(output.stdout)
+                    .wrap_err_with(|| format!("output of {} is not valid UTF-8", description))?
+            }
+        };
+        serde_json::from_str(&stdout)
+            .wrap_err_with(|| format!("failed to parse the response of {}", description))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum PluginStep {
+    Resolve,
+    Fetch,
+    Install,
+}
+
+impl PluginStep {
+    fn as_str(self) -> &'static str {
+        match self {
+            PluginStep::Resolve => "resolve",
+            PluginStep::Fetch => "fetch",
+            PluginStep::Install => "install",
+        }
+    }
+}
+
+impl fmt::Display for PluginStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ResolveResponse {
+    version: String,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct FetchResponse {
+    #[serde(default)]
+    artifact: Option<Utf8PathBuf>,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct InstallResponse {
+    binaries: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct PluginMatcher {
+    backend: &'static PluginBackend,
+}
+
+impl PluginMatcher {
+    pub(crate) fn new(backend: &'static PluginBackend) -> Self {
+        Self { backend }
+    }
+}
+
+#[async_trait]
+impl PackageMatcherImpl for PluginMatcher {
+    #[inline]
+    fn namespace(&self) -> &'static str {
+        &self.backend.namespace
+    }
+
+    fn best_match(&self, rows: Vec<DirectoryRow>) -> Result<Option<DirectoryRow>> {
+        Ok(rows.into_iter().next())
+    }
+
+    fn best_installed_match(
+        &self,
+        installed_rows: Vec<InstalledRow>,
+    ) -> Result<Option<InstalledRow>> {
+        Ok(installed_rows.into_iter().next())
+    }
+
+    fn metadata(&self) -> Value {
+        Value::Null
+    }
+
+    fn source(&self, _name: &str) -> String {
+        format!("{}:{}", self.backend.namespace, self.backend.source)
+    }
+
+    fn make_resolver(&self) -> Box<dyn PackageResolverImpl> {
+        Box::new(PluginResolver {
+            backend: self.backend,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct PluginResolver {
+    backend: &'static PluginBackend,
+}
+
+#[async_trait]
+impl PackageResolverImpl for PluginResolver {
+    async fn resolve(
+        &self,
+        name: String,
+        req: DirectoryVersionReq,
+        _output_opts: OutputOpts,
+    ) -> Result<Box<dyn PackageFetcherImpl>> {
+        let response: ResolveResponse = self.backend.run(
+            PluginStep::Resolve,
+            json!({ "name": name, "req": req.as_str(), "data": Value::Null }),
+            None,
+        )?;
+        let version = match response.version.parse::<Version>() {
+            Ok(version) => DirectoryVersion::new_semantic(version),
+            Err(_) => DirectoryVersion::new_literal(response.version),
+        };
+        // Other versions are recorded as is, since there's no telling how they compare.
+        if version.as_semantic().is_some() && !req.matches(&version) {
+            bail!(
+                "plugin for {} resolved {} to version {}, which doesn't match it",
+                self.backend.namespace,
+                req,
+                version.short_display()
+            );
+        }
+
+        Ok(Box::new(PluginFetcher {
+            backend: self.backend,
+            name,
+            version,
+            data: response.data,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct PluginFetcher {
+    backend: &'static PluginBackend,
+    name: String,
+    version: DirectoryVersion,
+    data: Value,
+}
+
+#[async_trait]
+impl PackageFetcherImpl for PluginFetcher {
+    fn version(&self) -> DirectoryVersion {
+        self.version.clone()
+    }
+
+    async fn fetch(&self, fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>> {
+        let download_dir = fetch_dir.join("download");
+        fs::create_dir_all(&download_dir)
+            .wrap_err_with(|| format!("failed to create directory at {}", download_dir))?;
+        let response: FetchResponse = self.backend.run(
+            PluginStep::Fetch,
+            json!({
+                "name": self.name,
+                "version": self.version.short_display().to_string(),
+                "data": self.data,
+                "fetch-dir": download_dir,
+            }),
+            None,
+        )?;
+
+        let checksum = match &response.artifact {
+            Some(artifact) => {
+                if !artifact
+                    .components()
+                    .all(|component| matches!(component, Utf8Component::Normal(_)))
+                {
+                    bail!(
+                        "plugin for {} returned artifact {}, which isn't within the fetch \
+                         directory",
+                        self.backend.namespace,
+                        artifact
+                    );
+                }
+                Some(checksum_file(&download_dir.join(artifact))?)
+            }
+            None => None,
+        };
+        let data = match response.data {
+            Value::Null => self.data.clone(),
+            data => data,
+        };
+
+        Ok(Box::new(PluginInstaller {
+            backend: self.backend,
+            name: self.name.clone(),
+            version: self.version.clone(),
+            data,
+            checksum,
+            download_dir,
+            install_dir: fetch_dir.join("install"),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct PluginInstaller {
+    backend: &'static PluginBackend,
+    name: String,
+    version: DirectoryVersion,
+    data: Value,
+    checksum: Option<Checksum>,
+    download_dir: Utf8PathBuf,
+    install_dir: Utf8PathBuf,
+}
+
+#[async_trait]
+impl PackageInstallerImpl for PluginInstaller {
+    fn installing_metadata(&self) -> Value {
+        json!({ "plugin": self.backend.command })
+    }
+
+    fn add_to_hasher(&self, _hasher: &mut XxHash64) {
+        // Nothing other than the name and version is known to affect what plugins install.
+    }
+
+    fn artifact_checksum(&self) -> Option<Checksum> {
+        self.checksum.clone()
+    }
+
+    async fn install(&self, build_log: &BuildLog) -> Result<TempInstalledPackage> {
+        fs::create_dir_all(&self.install_dir)
+            .wrap_err_with(|| format!("failed to create directory at {}", self.install_dir))?;
+        tracing::debug!(
+            target: "hasp::output::working::plugin_install",
+            namespace = %self.backend.namespace,
+            dir = %self.install_dir,
+            "Installing with plugin for {namespace} into {dir}",
+            namespace = self.backend.namespace,
+            dir = self.install_dir,
+        );
+
+        let response: InstallResponse = self.backend.run(
+            PluginStep::Install,
+            json!({
+                "name": self.name,
+                "version": self.version.short_display().to_string(),
+                "data": self.data,
+                "fetch-dir": self.download_dir,
+                "install-dir": self.install_dir,
+            }),
+            Some(build_log),
+        )?;
+        if response.binaries.is_empty() {
+            bail!("package does not have any binaries");
+        }
+
+        let mut installed_files = BTreeMap::new();
+        let entries = fs::read_dir(&self.install_dir)
+            .wrap_err_with(|| format!("failed to read directory {}", self.install_dir))?;
+        for entry in entries {
+            let entry =
+                entry.wrap_err_with(|| format!("failed to read entry in {}", self.install_dir))?;
+            let file_name = match entry.file_name().into_string() {
+                Ok(file_name) => file_name,
+                Err(file_name) => bail!(
+                    "plugin for {} installed {:?}, which is not valid UTF-8",
+                    self.backend.namespace,
+                    file_name
+                ),
+            };
+            let is_binary = response.binaries.contains(&file_name);
+            installed_files.insert(
+                file_name.clone(),
+                TempInstalledFile {
+                    temp_path: self.install_dir.join(&file_name),
+                    metadata: Value::Null,
+                    is_binary,
+                },
+            );
+        }
+        if let Some(missing) = response
+            .binaries
+            .iter()
+            .find(|binary| !installed_files.contains_key(*binary))
+        {
+            bail!(
+                "plugin for {} reported binary {}, which isn't in {}",
+                self.backend.namespace,
+                missing,
+                self.install_dir
+            );
+        }
+
+        Ok(TempInstalledPackage {
+            installed_files,
+            metadata: self.installing_metadata(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::fake_plugin;
+
+    async fn fetch(
+        backend: &'static PluginBackend,
+        fetch_dir: &Utf8Path,
+    ) -> Result<Box<dyn PackageInstallerImpl>> {
+        let resolver = PluginMatcher::new(backend).make_resolver();
+        let fetcher = resolver
+            .resolve(
+                "tool".to_owned(),
+                DirectoryVersionReq::from("^1".parse::<semver::VersionReq>().expect("valid req")),
+                OutputOpts {
+                    quiet: false,
+                    verbose: 0,
+                    color: None,
+                    message_format: None,
+                },
+            )
+            .await?;
+        assert_eq!(
+            fetcher.version(),
+            DirectoryVersion::new_semantic(Version::new(1, 2, 3))
+        );
+        fetcher.fetch(fetch_dir).await
+    }
+
+    #[tokio::test]
+    async fn plugin_steps() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let backend = fake_plugin(dir, "ok");
+        let fetch_dir = dir.join("fetch");
+
+        let installer = fetch(backend, &fetch_dir).await.expect("package fetched");
+        assert_eq!(
+            installer.artifact_checksum(),
+            Some(checksum_file(&fetch_dir.join("download/tool.tar")).expect("checksum computed")),
+            "the artifact is checksummed"
+        );
+        let build_log = BuildLog::create(dir.join("build.log")).expect("build log created");
+        let installed = installer
+            .install(&build_log)
+            .await
+            .expect("package installed");
+        let files: Vec<_> = installed
+            .installed_files
+            .iter()
+            .map(|(name, file)| (name.as_str(), file.is_binary))
+            .collect();
+        assert_eq!(files, [("tool", true)]);
+    }
+
+    #[tokio::test]
+    async fn plugin_misbehaving() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+
+        let err = fetch(fake_plugin(dir, "malformed"), &dir.join("malformed"))
+            .await
+            .expect_err("malformed response rejected");
+        assert!(
+            err.to_string()
+                .starts_with("failed to parse the response of sh resolve"),
+            "{:#}",
+            err
+        );
+
+        let err = fetch(fake_plugin(dir, "escape"), &dir.join("escape"))
+            .await
+            .expect_err("artifact outside the fetch directory rejected");
+        assert!(
+            err.to_string()
+                .contains("which isn't within the fetch directory"),
+            "{:#}",
+            err
+        );
+
+        let installer = fetch(fake_plugin(dir, "missing"), &dir.join("missing"))
+            .await
+            .expect("package fetched");
+        let build_log = BuildLog::create(dir.join("build.log")).expect("build log created");
+        let err = installer
+            .install(&build_log)
+            .await
+            .expect_err("missing binary rejected");
+        assert!(
+            err.to_string()
+                .starts_with("plugin for fake reported binary other, which isn't in"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The namespaces packages can be installed from, and the backends implementing them.
+//!
+//! The built-in namespaces are always registered. Plugins configured in `[plugins]` are
+//! registered at startup, after which the registry doesn't change for the rest of the run.
+
+use crate::{config::PluginConfig, ops::PluginBackend};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report, Result,
+};
+use once_cell::sync::OnceCell;
+use std::collections::BTreeMap;
+
+static REGISTRY: OnceCell<BackendRegistry> = OnceCell::new();
+
+/// Sets the registry used for the rest of this run. This is done once at startup, before any
+/// packages are looked up: if the registry was already set, it's left as is.
+pub(crate) fn init_registry(registry: BackendRegistry) {
+    let _ = REGISTRY.set(registry);
+}
+
+/// Returns the registry set at startup, or just the built-in backends if none was set.
+pub(crate) fn registry() -> &'static BackendRegistry {
+    REGISTRY.get_or_init(BackendRegistry::builtin)
+}
+
+/// The backend implementing a namespace.
+#[derive(Debug)]
+pub(crate) enum Backend {
+    Cargo,
+    Npm,
+    Oci,
+    Plugin(PluginBackend),
+}
+
+#[derive(Debug)]
+pub(crate) struct BackendRegistry {
+    backends: BTreeMap<String, Backend>,
+}
+
+impl BackendRegistry {
+    /// Returns a registry with only the built-in backends.
+    pub(crate) fn builtin() -> Self {
+        let backends = [
+            ("cargo", Backend::Cargo),
+            ("npm", Backend::Npm),
+            ("oci", Backend::Oci),
+        ]
+        .into_iter()
+        .map(|(namespace, backend)| (namespace.to_owned(), backend))
+        .collect();
+        Self { backends }
+    }
+
+    /// Returns a registry with the built-in backends and these plugins, keyed by namespace.
+    pub(crate) fn with_plugins(plugins: &BTreeMap<String, PluginConfig>) -> Result<Self> {
+        let mut registry = Self::builtin();
+        for (namespace, config) in plugins {
+            let plugin = PluginBackend::new(namespace, config)
+                .wrap_err_with(|| format!("invalid plugin for namespace '{}'", namespace))?;
+            registry.register(namespace, Backend::Plugin(plugin))?;
+        }
+        Ok(registry)
+    }
+
+    /// Registers a backend for a new namespace.
+    pub(crate) fn register(&mut self, namespace: &str, backend: Backend) -> Result<()> {
+        if !is_valid_namespace(namespace) {
+            bail!(
+                "invalid namespace '{}' (hint: namespaces are made of lowercase letters, digits \
+                 and dashes)",
+                namespace
+            );
+        }
+        if self.backends.contains_key(namespace) {
+            bail!("namespace '{}' is already registered", namespace);
+        }
+        self.backends.insert(namespace.to_owned(), backend);
+        Ok(())
+    }
+
+    /// Returns the backend for a namespace, if one is registered.
+    pub(crate) fn get(&self, namespace: &str) -> Option<&Backend> {
+        self.backends.get(namespace)
+    }
+
+    /// Returns an error for a namespace that isn't registered, listing the ones that are.
+    pub(crate) fn unknown_namespace(&self, namespace: &str) -> Report {
+        let namespaces: Vec<_> = self.backends.keys().map(String::as_str).collect();
+        let expected = match namespaces.split_last() {
+            Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+            _ => namespaces.join(""),
+        };
+        eyre!("unknown namespace '{}' (expected {})", namespace, expected)
+    }
+}
+
+fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_with_plugins() {
+        let config: BTreeMap<String, PluginConfig> = toml::from_str(
+            r#"
+            [pip]
+            command = ["hasp-pip", "--quiet"]
+            "#,
+        )
+        .expect("plugins parsed");
+        let registry = BackendRegistry::with_plugins(&config).expect("plugins registered");
+        assert!(matches!(registry.get("cargo"), Some(Backend::Cargo)));
+        match registry.get("pip") {
+            Some(Backend::Plugin(plugin)) => assert_eq!(plugin.namespace(), "pip"),
+            other => panic!("expected plugin for pip, found {:?}", other),
+        }
+        assert_eq!(
+            registry.unknown_namespace("gem").to_string(),
+            "unknown namespace 'gem' (expected cargo, npm, oci or pip)"
+        );
+
+        for invalid in [
+            "[npm]\ncommand = [\"hasp-npm\"]",
+            "[Pip]\ncommand = [\"hasp-pip\"]",
+            "[pip]\ncommand = []",
+        ] {
+            let config: BTreeMap<String, PluginConfig> =
+                toml::from_str(invalid).expect("plugins parsed");
+            assert!(
+                BackendRegistry::with_plugins(&config).is_err(),
+                "{} is rejected",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    database::DbContext,
+    helpers::dir_size,
+    home::HaspHome,
+    models::{
+        install_progress::{InstallPhase, InstallProgressRow},
+        previous_install::PreviousInstallRow,
+    },
+    ops::{
+        states::{helpers::UnlockedRoot, work_dir::remove_dir_all},
+        BuildCache, PreviousInstalls,
+    },
+    output::NameVersionDisplay,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+
+/// Removes what's kept around for a package other than its current install.
+///
+/// That's the work directories of unfinished installs, which hold fetched downloads and partial
+/// builds, the target directory kept for it by the build cache, and its previous installs. This
+/// is useful if any of them is suspected to be corrupt: the next install starts from scratch.
+#[derive(Debug)]
+pub(crate) struct PackageCleaner {
+    hasp_home: HaspHome,
+    db_ctx: DbContext,
+    namespace: String,
+    name: String,
+}
+
+impl PackageCleaner {
+    pub(crate) fn new(
+        hasp_home: HaspHome,
+        db_ctx: DbContext,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Self {
+        Self {
+            hasp_home,
+            db_ctx,
+            namespace: namespace.into(),
+            name: name.into(),
+        }
+    }
+
+    /// Removes everything kept for the package, returning what was removed. If `dry_run` is true,
+    /// nothing is removed, and what would have been removed is returned.
+    pub(crate) fn clean(&self, dry_run: bool) -> Result<Vec<CleanedDir>> {
+        let mut cleaned = vec![];
+        let conn = self.db_ctx.creator.create()?;
+
+        for progress in InstallProgressRow::all_for(&self.namespace, &self.name, &conn)? {
+            let version = NameVersionDisplay::dir_version(&self.name, &progress.version);
+            let description = format!("work directory for {}", version);
+            // Wait for any other process installing this version.
+            let _lock = if dry_run {
+                None
+            } else {
+                Some(
+                    UnlockedRoot::new(&progress.work_dir)?
+                        .lock_exclusive(self.hasp_home.lock_wait())?,
+                )
+            };
+            let phase = match InstallProgressRow::get(progress.work_dir.as_str(), &conn)? {
+                Some(row) => row.phase,
+                None if progress.work_dir.exists() => progress.phase,
+                None => continue,
+            };
+            if phase == InstallPhase::Swapping {
+                // The work directory may hold the only copy of the previous install.
+                tracing::warn!(
+                    target: "hasp::output::clean_skipped",
+                    %description,
+                    name = %self.name,
+                    "Warning skipping {description}, which was interrupted while being moved into \
+                     place (hint: install {name} again to recover it)",
+                    name = self.name,
+                );
+                continue;
+            }
+
+            let size = size_if_exists(&progress.work_dir)?;
+            if !dry_run {
+                let mut conn = self.db_ctx.creator.create()?;
+                let txn = self.db_ctx.creator.write_transaction(&mut conn)?;
+                InstallProgressRow::delete(progress.work_dir.as_str(), &txn)?;
+                txn.commit().wrap_err_with(|| {
+                    format!(
+                        "failed to commit removal of install progress for {}",
+                        progress.work_dir
+                    )
+                })?;
+                remove_dir_all(&progress.work_dir)?;
+            }
+            cleaned.push(CleanedDir {
+                description,
+                path: progress.work_dir,
+                size,
+            });
+        }
+
+        if self.namespace == "cargo" {
+            let target_dir = BuildCache::target_dirs(self.hasp_home.cache_dir()).join(&self.name);
+            if target_dir.exists() {
+                let size = dir_size(&target_dir)?;
+                if !dry_run {
+                    remove_dir_all(&target_dir)?;
+                }
+                cleaned.push(CleanedDir {
+                    description: format!("target directory for {}", self.name),
+                    path: target_dir,
+                    size,
+                });
+            }
+        }
+
+        let previous_installs = PreviousInstalls::new(
+            self.hasp_home.clone(),
+            self.db_ctx.clone(),
+            &self.namespace,
+            &self.name,
+        );
+        for previous in PreviousInstallRow::all_for(&self.namespace, &self.name, &conn)? {
+            let package = &previous.installed.directory_row.package;
+            let size = size_if_exists(&previous.path)?;
+            if !dry_run {
+                previous_installs.remove_previous(&previous)?;
+            }
+            cleaned.push(CleanedDir {
+                description: format!(
+                    "previous install of {}",
+                    NameVersionDisplay::dir_version(&self.name, &package.version)
+                ),
+                path: previous.path,
+                size,
+            });
+        }
+
+        Ok(cleaned)
+    }
+}
+
+/// A directory removed by cleaning a package.
+#[derive(Clone, Debug)]
+pub(crate) struct CleanedDir {
+    /// What the directory was for, e.g. "target directory for foo".
+    pub(crate) description: String,
+    pub(crate) path: Utf8PathBuf,
+    /// The total size of the files in the directory, in bytes.
+    pub(crate) size: u64,
+}
+
+fn size_if_exists(dir: &Utf8Path) -> Result<u64> {
+    if dir.exists() {
+        dir_size(dir)
+    } else {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TestHome;
+    use std::fs;
+
+    #[test]
+    fn clean_work_and_target_dirs() {
+        let test_home = TestHome::new();
+        let (hasp_home, creator) = (&test_home.hasp_home, test_home.creator());
+
+        let cache_dir = hasp_home.cache_dir().to_path_buf();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+        for (dir, name, version, phase) in [
+            ("install-1", "foo", "sem:1.0.0", InstallPhase::Fetched),
+            ("install-2", "foo", "sem:2.0.0", InstallPhase::Swapping),
+            ("install-3", "bar", "sem:1.0.0", InstallPhase::Built),
+        ] {
+            let work_dir = cache_dir.join(dir);
+            fs::create_dir_all(&work_dir).expect("work dir created");
+            fs::write(work_dir.join("download.crate"), "12345").expect("download written");
+            InstallProgressRow {
+                work_dir,
+                namespace: "cargo".to_owned(),
+                name: name.to_owned(),
+                version: version.parse().expect("valid version"),
+                phase,
+                data: serde_json::Value::Null,
+            }
+            .upsert(&txn)
+            .expect("progress recorded");
+        }
+        txn.commit().expect("transaction committed");
+        let target_dir = BuildCache::target_dirs(&cache_dir).join("foo");
+        fs::create_dir_all(&target_dir).expect("target dir created");
+        fs::write(target_dir.join("build"), "123").expect("build output written");
+
+        let cleaner =
+            PackageCleaner::new(hasp_home.clone(), test_home.db_ctx.clone(), "cargo", "foo");
+        let dry_run = cleaner.clean(true).expect("dry run succeeded");
+        let summary = |cleaned: &[CleanedDir]| {
+            cleaned
+                .iter()
+                .map(|dir| (dir.description.clone(), dir.size))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            summary(&dry_run),
+            [
+                (
+                    format!(
+                        "work directory for {}",
+                        NameVersionDisplay::dir_version(
+                            "foo",
+                            &"sem:1.0.0".parse().expect("valid version")
+                        )
+                    ),
+                    5
+                ),
+                ("target directory for foo".to_owned(), 3),
+            ]
+        );
+        assert!(target_dir.exists(), "dry run doesn't remove anything");
+
+        let cleaned = cleaner.clean(false).expect("clean succeeded");
+        assert_eq!(summary(&cleaned), summary(&dry_run));
+        assert!(!cache_dir.join("install-1").exists());
+        assert!(!target_dir.exists());
+        // Interrupted swaps and other packages are left alone.
+        assert!(cache_dir.join("install-2").exists());
+        assert!(cache_dir.join("install-3").exists());
+        assert_eq!(
+            InstallProgressRow::all_for("cargo", "foo", &conn)
+                .expect("progress queried")
+                .len(),
+            1
+        );
+        assert!(cleaner.clean(false).expect("clean succeeded").is_empty());
+    }
+}
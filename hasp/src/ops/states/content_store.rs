@@ -0,0 +1,252 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A content-addressed store of installed files, used to deduplicate them across installs.
+//!
+//! If `install.content-store` is set, each file in a new install is hard-linked with the file in
+//! the store that has the same hash, or added to the store if there isn't one. Identical binaries
+//! across versions and packages then only take up space once.
+//!
+//! A file in the store is referenced by every install whose receipt lists its hash, including
+//! superseded installs kept for `hasp rollback`. `hasp gc` removes files that aren't referenced
+//! any more.
+
+use crate::{
+    home::HaspHome,
+    ops::states::{fsck::subdirs, helpers::hash_file},
+    platform,
+    receipt::InstallReceipt,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::FileHash;
+use std::{collections::BTreeMap, fs, io};
+
+#[derive(Clone, Debug)]
+pub(crate) struct ContentStore {
+    root: Utf8PathBuf,
+}
+
+impl ContentStore {
+    pub(crate) fn new(root: impl Into<Utf8PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Returns the path a file with this hash is stored at: `<algorithm>/<hex>` within the store.
+    fn object_path(&self, hash: &FileHash) -> Utf8PathBuf {
+        let hash = hash.to_string();
+        match hash.split_once(':') {
+            Some((algorithm, hex)) => self.root.join(algorithm).join(hex),
+            None => self.root.join(hash),
+        }
+    }
+
+    /// Replaces the file at `path` with a hard link to the stored file with the same hash, adding
+    /// it to the store if there isn't one. Returns true if the file was replaced, saving space.
+    ///
+    /// Directories and symlinks are left alone.
+    pub(super) fn link(&self, path: &Utf8Path, hash: &FileHash) -> Result<bool> {
+        let metadata =
+            fs::symlink_metadata(path).wrap_err_with(|| format!("failed to stat {}", path))?;
+        if !metadata.is_file() {
+            return Ok(false);
+        }
+
+        let object = self.object_path(hash);
+        match fs::symlink_metadata(&object) {
+            // A stored file may have been changed through one of its links. Don't spread the
+            // change to this install, and store this file instead.
+            Ok(_) if hash_file(&object, hash.algorithm())?.to_string() != hash.to_string() => {
+                tracing::debug!("replacing {}, which doesn't match its hash", object);
+                fs::remove_file(&object)
+                    .wrap_err_with(|| format!("failed to remove {}", object))?;
+            }
+            Ok(_) => {
+                // Link through a temporary name, so that the file is never missing.
+                let file_name = path.file_name().expect("installed files have names");
+                let temp_path = path.with_file_name(format!(".{}.hasp-link", file_name));
+                fs::hard_link(&object, &temp_path)
+                    .wrap_err_with(|| format!("failed to link {} to {}", temp_path, object))?;
+                platform::rename(&temp_path, path)
+                    .wrap_err_with(|| format!("failed to rename {} to {}", temp_path, path))?;
+                // If the file was already a link to the object, renaming does nothing.
+                let _ = fs::remove_file(&temp_path);
+                return Ok(true);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).wrap_err_with(|| format!("failed to stat {}", object)),
+        }
+
+        let parent = object.parent().expect("objects are within the store");
+        fs::create_dir_all(parent).wrap_err_with(|| format!("failed to create {}", parent))?;
+        match fs::hard_link(path, &object) {
+            Ok(()) => Ok(false),
+            // Another install stored the same file in the meantime. Leave this one as it is.
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err).wrap_err_with(|| format!("failed to link {} to {}", object, path)),
+        }
+    }
+
+    /// Removes stored files that no installs reference. If `dry_run` is true, nothing is removed.
+    pub(crate) fn gc(&self, hasp_home: &HaspHome, dry_run: bool) -> Result<GcSummary> {
+        let refcounts = refcounts(hasp_home)?;
+        let mut summary = GcSummary::default();
+        for (algorithm, algorithm_dir) in subdirs(&self.root)? {
+            let entries = algorithm_dir
+                .read_dir()
+                .wrap_err_with(|| format!("failed to read directory {}", algorithm_dir))?;
+            for entry in entries {
+                let entry =
+                    entry.wrap_err_with(|| format!("failed to read entry in {}", algorithm_dir))?;
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                summary.checked += 1;
+                let key = format!("{}:{}", algorithm, name);
+                if refcounts.get(&key).copied().unwrap_or(0) > 0 {
+                    continue;
+                }
+
+                let path = algorithm_dir.join(&name);
+                let size = entry
+                    .metadata()
+                    .wrap_err_with(|| format!("failed to stat {}", path))?
+                    .len();
+                if !dry_run {
+                    fs::remove_file(&path)
+                        .wrap_err_with(|| format!("failed to remove {}", path))?;
+                }
+                summary.removed += 1;
+                summary.removed_bytes += size;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// What `hasp gc` removed from the content store.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GcSummary {
+    /// The number of files in the store.
+    pub(crate) checked: usize,
+    /// The number of files that no installs referenced, which were removed.
+    pub(crate) removed: usize,
+    /// The total size of the removed files.
+    pub(crate) removed_bytes: u64,
+}
+
+/// Counts how many files in installs and superseded installs have each hash, keyed by the hash.
+fn refcounts(hasp_home: &HaspHome) -> Result<BTreeMap<String, usize>> {
+    let mut refcounts = BTreeMap::new();
+    // Both directories are laid out as <namespace>/<name>/<hash>.
+    for dir in [
+        hasp_home.installs_dir().to_path_buf(),
+        hasp_home.previous_dir(),
+    ] {
+        for (_, namespace_dir) in subdirs(&dir)? {
+            for (_, name_dir) in subdirs(&namespace_dir)? {
+                for (_, install_path) in subdirs(&name_dir)? {
+                    if let Some(receipt) = InstallReceipt::read(&install_path)? {
+                        for file in receipt.files.values() {
+                            *refcounts.entry(file.hash.to_string()).or_default() += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(refcounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receipt::ReceiptFile;
+    use chrono::Local;
+    use hasp_metadata::{DirectoryHash, PackageDirectory};
+
+    #[test]
+    fn link_and_gc() {
+        let home = tempfile::tempdir().expect("tempdir created");
+        let home = Utf8Path::from_path(home.path()).expect("tempdir is UTF-8");
+        let hasp_home = HaspHome::new(home).expect("hasp home created");
+        let store = ContentStore::new(hasp_home.content_store_dir());
+
+        let hash = FileHash::Blake3(blake3::hash(b"binary").into());
+        let mut install_paths = vec![];
+        for (version, hash_value) in [("sem:1.0.0", 1), ("sem:1.1.0", 2)] {
+            let package = PackageDirectory {
+                namespace: "cargo".to_owned(),
+                name: "foo".to_owned(),
+                version: version.parse().expect("valid version"),
+                hash: DirectoryHash::new(hash_value),
+                metadata: serde_json::json!({}),
+            };
+            let install_path =
+                hasp_home.install_path(&package.namespace, &package.name, package.hash);
+            fs::create_dir_all(&install_path).expect("install dir created");
+            fs::write(install_path.join("foo"), b"binary").expect("file written");
+            InstallReceipt {
+                hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+                package,
+                target: None,
+                metadata: serde_json::Value::Null,
+                files: BTreeMap::from([(
+                    "foo".to_owned(),
+                    ReceiptFile {
+                        hash: hash.clone(),
+                        metadata: serde_json::Value::Null,
+                        is_binary: true,
+                    },
+                )]),
+                dependencies: vec![],
+                start_time: Local::now(),
+                install_time: Local::now(),
+            }
+            .write(&install_path)
+            .expect("receipt written");
+            install_paths.push(install_path);
+        }
+
+        assert!(
+            !store
+                .link(&install_paths[0].join("foo"), &hash)
+                .expect("file stored"),
+            "the first file is added to the store"
+        );
+        assert!(
+            store
+                .link(&install_paths[1].join("foo"), &hash)
+                .expect("file linked"),
+            "the second file is replaced with a link"
+        );
+        assert_eq!(
+            fs::read(install_paths[1].join("foo")).expect("file read"),
+            b"binary".to_vec()
+        );
+        assert!(
+            !install_paths[1].join(".foo.hasp-link").exists(),
+            "temporary link is removed"
+        );
+        assert!(
+            store
+                .link(&install_paths[1].join("foo"), &hash)
+                .expect("file linked again"),
+            "linking again is harmless"
+        );
+        assert!(!install_paths[1].join(".foo.hasp-link").exists());
+
+        let summary = store.gc(&hasp_home, false).expect("gc succeeded");
+        assert_eq!((summary.checked, summary.removed), (1, 0));
+
+        for install_path in &install_paths {
+            fs::remove_dir_all(install_path).expect("install removed");
+        }
+        let summary = store.gc(&hasp_home, true).expect("gc succeeded");
+        assert_eq!((summary.removed, summary.removed_bytes), (1, 6));
+        assert!(store.object_path(&hash).exists(), "dry runs remove nothing");
+        store.gc(&hasp_home, false).expect("gc succeeded");
+        assert!(!store.object_path(&hash).exists());
+    }
+}
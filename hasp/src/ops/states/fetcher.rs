@@ -2,14 +2,20 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{
-    ops::{states::helpers::Utf8TempDir, PackageInstaller, PackageInstallerImpl, PackageMatcher},
+    failure::PolicyViolation,
+    hooks::{run_hooks, HookContext, HookPoint},
+    models::install_progress::InstallPhase,
+    ops::{
+        states::helpers::elapsed_ms, verify_signature, PackageInstaller, PackageInstallerImpl,
+        PackageMatcher, WorkDir,
+    },
     output::NameVersionDisplay,
 };
 use async_trait::async_trait;
 use camino::Utf8Path;
 use color_eyre::{eyre::WrapErr, Result};
-use hasp_metadata::DirectoryVersion;
-use std::{fmt, fs};
+use hasp_metadata::{DirectoryVersion, InstallTimings};
+use std::{fmt, fs, time::Instant};
 
 /// Fetches a new package.
 #[derive(Debug)]
@@ -17,6 +23,8 @@ pub(crate) struct PackageFetcher {
     matcher: PackageMatcher,
     fetcher: Box<dyn PackageFetcherImpl>,
     version: DirectoryVersion,
+    /// How long resolving the version took in milliseconds, if it was resolved.
+    resolve_ms: u64,
 }
 
 impl PackageFetcher {
@@ -27,30 +35,152 @@ impl PackageFetcher {
             matcher,
             fetcher,
             version,
+            resolve_ms: 0,
         }
     }
 
+    /// Returns the URL the package will be downloaded from, if it's known before fetching.
+    #[inline]
+    pub(crate) fn download_url(&self) -> Option<String> {
+        self.fetcher.download_url()
+    }
+
+    /// Records how long resolving the version took.
+    pub(super) fn with_resolve_ms(mut self, resolve_ms: u64) -> Self {
+        self.resolve_ms = resolve_ms;
+        self
+    }
+
     pub(crate) async fn fetch(self) -> Result<PackageInstaller> {
         // TODO: consider sharing the fetch dir across installs?
+        let start = Instant::now();
+
+        let mut work_dir = WorkDir::open(&self.matcher, &self.version)?;
+        let fetch_dir = work_dir.path().join("fetch");
+
+        // Reuse the package fetched by an earlier attempt, if possible.
+        let resumed = match work_dir.resume_phase() {
+            Some(phase) if phase >= InstallPhase::Fetched => {
+                match self.fetcher.resume(&fetch_dir).await {
+                    Ok(Some(installer)) => {
+                        let package =
+                            NameVersionDisplay::dir_version(self.matcher.name(), &self.version);
+                        tracing::info!(
+                            target: "hasp::output::working::resuming",
+                            key = %self.matcher.key(),
+                            %package,
+                            %phase,
+                            "Resuming {package} from the {phase} phase",
+                        );
+                        Some(installer)
+                    }
+                    Ok(None) => None,
+                    Err(err) => {
+                        let package = self.to_friendly();
+                        let error = format!("{:#}", err);
+                        tracing::debug!(
+                            target: "hasp::output::working::resume_failed",
+                            %package,
+                            %error,
+                            "Failed to resume {package}, fetching again: {error}",
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let installer = match resumed {
+            Some(installer) => installer,
+            None => {
+                work_dir.restart()?;
+                fs::create_dir_all(&fetch_dir)
+                    .wrap_err_with(|| format!("failed to create directory at {}", fetch_dir))?;
+
+                let package = NameVersionDisplay::dir_version(self.matcher.name(), &self.version);
+                tracing::info!(
+                    target: "hasp::output::working::fetching",
+                    key = %self.matcher.key(),
+                    %package,
+                    "Fetching {package}",
+                );
+
+                self.fetcher.fetch(&fetch_dir).await.wrap_err_with(|| {
+                    format!("failed to fetch package for {}", self.to_friendly())
+                })?
+            }
+        };
+
+        if let Some(expected) = self.matcher.expected_checksum() {
+            match installer.artifact_checksum() {
+                Some(actual) if &actual == expected => {
+                    let package = self.to_friendly();
+                    tracing::debug!(
+                        target: "hasp::output::working::verified_checksum",
+                        %actual,
+                        %package,
+                        "Verified checksum {actual} for {package}",
+                    );
+                }
+                Some(actual) => {
+                    return Err(PolicyViolation(format!(
+                        "checksum mismatch for {}: expected {}, found {}",
+                        self.to_friendly(),
+                        expected,
+                        actual,
+                    ))
+                    .into())
+                }
+                None => {
+                    return Err(PolicyViolation(format!(
+                        "checksum {} is pinned for {}, but it doesn't have an artifact to verify",
+                        expected,
+                        self.to_friendly(),
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        let signature = verify_signature(
+            &self.matcher.hasp_home().config().signatures,
+            self.matcher.namespace(),
+            &self.to_friendly(),
+            installer.signed_artifact(),
+        )
+        .await?;
+
+        let hook_ctx = HookContext::new(
+            HookPoint::PostFetch,
+            self.matcher.namespace(),
+            self.matcher.name(),
+        )
+        .env("HASP_VERSION", self.version.short_display())
+        .env("HASP_FETCH_DIR", &fetch_dir);
+        run_hooks(
+            self.matcher.hasp_home(),
+            &self.matcher.db_ctx().event_logger,
+            &hook_ctx,
+        )?;
 
-        let cache_dir = self.matcher.hasp_home().cache_dir();
-        let temp_dir = Utf8TempDir::new(cache_dir, "install-", "")?;
-        let fetch_dir = temp_dir.path().join("fetch");
-        fs::create_dir_all(&fetch_dir)
-            .wrap_err_with(|| format!("failed to create directory at {}", fetch_dir))?;
-
-        tracing::info!(
-            target: "hasp::output::working::fetching",
-            "Fetching {}",
-            NameVersionDisplay::dir_version(self.matcher.name(), &self.version),
-        );
-
-        let installer = self
-            .fetcher
-            .fetch(&fetch_dir)
-            .await
-            .wrap_err_with(|| format!("failed to fetch package for {}", self.to_friendly()))?;
-        PackageInstaller::new(self.matcher, installer, self.version, temp_dir)
+        // An interrupted swap stays recorded until the installer has restored the previous install.
+        if work_dir.resume_phase().is_none() && !work_dir.interrupted_swap() {
+            work_dir.record(InstallPhase::Fetched, serde_json::Value::Null)?;
+        }
+        let timings = InstallTimings {
+            resolve_ms: self.resolve_ms,
+            fetch_ms: elapsed_ms(start),
+            ..InstallTimings::default()
+        };
+        PackageInstaller::new(
+            self.matcher,
+            installer,
+            self.version,
+            work_dir,
+            signature,
+            timings,
+        )
     }
 
     #[allow(dead_code)]
@@ -70,10 +200,23 @@ impl PackageFetcher {
 
 /// Represents a way to fetch a specific package.
 #[async_trait]
-pub(crate) trait PackageFetcherImpl: fmt::Debug {
+pub(crate) trait PackageFetcherImpl: fmt::Debug + Send + Sync {
     /// Returns the version of the package that will be fetched.
     fn version(&self) -> DirectoryVersion;
 
+    /// Returns the URL the package will be downloaded from, if it's known before fetching.
+    /// Defaults to none.
+    fn download_url(&self) -> Option<String> {
+        None
+    }
+
     /// Fetches the package into the provided directory.
     async fn fetch(&self, fetch_dir: &Utf8Path) -> Result<Box<dyn PackageInstallerImpl>>;
+
+    /// Reuses the package fetched into the provided directory by an earlier, interrupted attempt.
+    ///
+    /// Returns `None` if the package has to be fetched again.
+    async fn resume(&self, _fetch_dir: &Utf8Path) -> Result<Option<Box<dyn PackageInstallerImpl>>> {
+        Ok(None)
+    }
 }
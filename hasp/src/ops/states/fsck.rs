@@ -0,0 +1,245 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    database::DbContext,
+    home::HaspHome,
+    models::directory::{DirectoryRow, InstalledRow},
+    ops::states::helpers::{hash_file, UnlockedRoot},
+    receipt::InstallReceipt,
+    shims::{find_broken_shims, shim_path, write_shims},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::DirectoryHash;
+use std::{fs, io};
+
+/// Checks install directories against the database and shims, restoring rows that were lost from
+/// the receipts in install directories.
+#[derive(Debug)]
+pub(crate) struct InstallChecker {
+    hasp_home: HaspHome,
+    db_ctx: DbContext,
+}
+
+impl InstallChecker {
+    pub(crate) fn new(hasp_home: HaspHome, db_ctx: DbContext) -> Self {
+        Self { hasp_home, db_ctx }
+    }
+
+    /// Checks install directories and shims. If `repair` is true, problems that can be repaired
+    /// are repaired. Otherwise, nothing is changed and all problems are reported.
+    pub(crate) fn check(&self, repair: bool) -> Result<FsckSummary> {
+        let mut summary = FsckSummary::default();
+
+        // Install directories are laid out as <namespace>/<name>/<hash>.
+        let installs_dir = self.hasp_home.installs_dir();
+        for (namespace, namespace_dir) in subdirs(installs_dir)? {
+            for (name, name_dir) in subdirs(&namespace_dir)? {
+                for (hash, install_path) in subdirs(&name_dir)? {
+                    let hash = match hash.parse::<DirectoryHash>() {
+                        Ok(hash) => hash,
+                        Err(_) => continue,
+                    };
+                    self.check_dir(&namespace, &name, hash, &install_path, repair, &mut summary)?;
+                }
+            }
+        }
+
+        let bin_dir = self.hasp_home.bin_dir();
+        let conn = self.db_ctx.creator.create()?;
+        for installed in InstalledRow::all_installed(&conn)? {
+            let package = &installed.directory_row.package;
+            let install_path =
+                self.hasp_home
+                    .install_path(&package.namespace, &package.name, package.hash);
+            if !install_path.exists() {
+                summary.problems.push(format!(
+                    "{} is recorded as installed, but {} is missing (hint: reinstall it)",
+                    installed.directory_row.to_friendly(),
+                    install_path,
+                ));
+                continue;
+            }
+
+            for binary in installed.binary_names() {
+                let shim_path = shim_path(bin_dir, binary);
+                if shim_path.exists() {
+                    continue;
+                }
+                if repair {
+                    write_shims(bin_dir, &install_path, [binary])?;
+                    summary.restored_shims.push(shim_path);
+                } else {
+                    summary.problems.push(format!(
+                        "shim {} for {} is missing (hint: run `hasp db fsck` to restore it)",
+                        shim_path,
+                        installed.directory_row.to_friendly(),
+                    ));
+                }
+            }
+        }
+
+        for (shim_path, target) in find_broken_shims(bin_dir, self.hasp_home.installs_dir())? {
+            if repair {
+                fs::remove_file(&shim_path)
+                    .wrap_err_with(|| format!("failed to remove shim at {}", shim_path))?;
+                summary.removed_shims.push(shim_path);
+            } else {
+                summary.problems.push(format!(
+                    "shim {} runs {}, which is missing (hint: run `hasp db fsck` to remove it)",
+                    shim_path, target,
+                ));
+            }
+        }
+
+        Ok(summary)
+    }
+
+    fn check_dir(
+        &self,
+        namespace: &str,
+        name: &str,
+        hash: DirectoryHash,
+        install_path: &Utf8Path,
+        repair: bool,
+        summary: &mut FsckSummary,
+    ) -> Result<()> {
+        summary.checked += 1;
+        let mut conn = self.db_ctx.creator.create()?;
+        let (_lock, txn) = if repair {
+            // Wait for any install into this directory to finish.
+            let lock =
+                UnlockedRoot::new(install_path)?.lock_exclusive(self.hasp_home.lock_wait())?;
+            (
+                Some(lock),
+                self.db_ctx.creator.write_transaction(&mut conn)?,
+            )
+        } else {
+            (None, conn.transaction()?)
+        };
+        let row = DirectoryRow::get(namespace, name, hash, &txn)?;
+        if let Some(row) = &row {
+            if row.get_installed(&txn)? && row.has_installs(&txn)? {
+                return Ok(());
+            }
+        }
+
+        let receipt = match InstallReceipt::read(install_path)? {
+            Some(receipt) => receipt,
+            None => {
+                // Failed installs leave behind empty directories.
+                if !is_empty_dir(install_path)? {
+                    summary.problems.push(format!(
+                        "{} isn't recorded as installed, and has no receipt to restore it from",
+                        install_path,
+                    ));
+                }
+                return Ok(());
+            }
+        };
+        let package = &receipt.package;
+        if package.namespace != namespace || package.name != name || package.hash != hash {
+            summary.problems.push(format!(
+                "receipt in {} is for a different package: {}:{} (hash {})",
+                install_path, package.namespace, package.name, package.hash,
+            ));
+            return Ok(());
+        }
+        if let Some(mismatch) = verify_files(&receipt, install_path)? {
+            summary.problems.push(format!(
+                "{} can't be restored from its receipt: {} (hint: reinstall it)",
+                install_path, mismatch,
+            ));
+            return Ok(());
+        }
+        if !repair {
+            summary.problems.push(format!(
+                "{} isn't recorded as installed (hint: run `hasp db fsck` to restore it from its \
+                 receipt)",
+                install_path,
+            ));
+            return Ok(());
+        }
+
+        let row = match row {
+            Some(row) => row,
+            None => {
+                let row = DirectoryRow::insert(package, true, &txn)?;
+                // Receipts don't record whether the package was requested directly, so err on the
+                // side of keeping it around.
+                row.set_explicit(&txn, true)?;
+                row
+            }
+        };
+        if !row.has_installs(&txn)? {
+            row.insert_install(&receipt, &txn)?;
+        }
+        row.set_installed(&txn, true)?;
+        txn.commit()
+            .wrap_err_with(|| format!("failed to commit restore of {}", row.to_friendly()))?;
+
+        summary.restored.push(row);
+        Ok(())
+    }
+}
+
+/// The results of checking install directories.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FsckSummary {
+    /// The number of install directories checked.
+    pub(crate) checked: usize,
+    /// Installs that were restored from their receipts.
+    pub(crate) restored: Vec<DirectoryRow>,
+    /// Shims that were missing and have been written out again.
+    pub(crate) restored_shims: Vec<Utf8PathBuf>,
+    /// Shims for binaries that no longer exist, which have been removed.
+    pub(crate) removed_shims: Vec<Utf8PathBuf>,
+    /// Problems that weren't repaired.
+    pub(crate) problems: Vec<String>,
+}
+
+/// Checks that the files listed in a receipt are present and unchanged, returning a description
+/// of the first mismatch found.
+fn verify_files(receipt: &InstallReceipt, install_path: &Utf8Path) -> Result<Option<String>> {
+    for (name, file) in &receipt.files {
+        let path = install_path.join(name);
+        if fs::symlink_metadata(&path).is_err() {
+            return Ok(Some(format!("{} is missing", name)));
+        }
+        if hash_file(&path, file.hash.algorithm())?.to_string() != file.hash.to_string() {
+            return Ok(Some(format!("{} has changed since it was installed", name)));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the names and paths of the subdirectories of `dir`, or nothing if it doesn't exist.
+pub(super) fn subdirs(dir: &Utf8Path) -> Result<Vec<(String, Utf8PathBuf)>> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("failed to read directory {}", dir));
+        }
+    };
+
+    let mut subdirs = vec![];
+    for entry in entries {
+        let entry = entry.wrap_err_with(|| format!("failed to read directory {}", dir))?;
+        let is_dir = matches!(entry.file_type(), Ok(file_type) if file_type.is_dir());
+        if let (true, Ok(name)) = (is_dir, entry.file_name().into_string()) {
+            let path = dir.join(&name);
+            subdirs.push((name, path));
+        }
+    }
+    subdirs.sort();
+    Ok(subdirs)
+}
+
+fn is_empty_dir(dir: &Utf8Path) -> Result<bool> {
+    let mut entries = dir
+        .read_dir()
+        .wrap_err_with(|| format!("failed to read directory {}", dir))?;
+    Ok(entries.next().is_none())
+}
@@ -1,36 +1,180 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+use crate::{cancel, platform};
 use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local, SecondsFormat};
 use color_eyre::{
     eyre::{bail, WrapErr},
     Result,
 };
-use fs2::FileExt;
-use hasp_metadata::FileHash;
-use std::{fs, hash::Hasher, io, io::Read};
+use hasp_metadata::{Checksum, FileHash, HashAlgorithm, Sha256Hash};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, fs,
+    hash::Hasher,
+    io::{self, Write},
+    num::NonZeroUsize,
+    process, thread,
+    time::{Duration, Instant},
+};
 use tempfile::TempDir;
 use twox_hash::XxHash64;
 
-pub(super) fn hash_file(path: &Utf8Path) -> Result<FileHash> {
-    let mut hasher = blake3::Hasher::new();
+/// Returns the time since `start`, in milliseconds, as recorded in install timings.
+pub(super) fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Hashes the file or directory at this path with this algorithm. To verify a recorded hash, pass
+/// in its algorithm.
+///
+/// Directories are hashed recursively, in sorted order, including relative paths and symlink
+/// targets.
+pub(super) fn hash_file(path: &Utf8Path, algorithm: HashAlgorithm) -> Result<FileHash> {
+    let mut hasher = FileHasher::new(algorithm);
+    let metadata =
+        fs::symlink_metadata(path).wrap_err_with(|| format!("failed to stat {}", path))?;
+    if metadata.is_dir() {
+        hash_dir_contents(path, Utf8Path::new(""), &mut hasher)?;
+    } else {
+        hash_file_contents(path, &mut hasher)?;
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Hashes the files or directories with these names in `dir` with this algorithm, returning their
+/// hashes in the same order. The files are hashed on as many threads as there are CPUs.
+pub(super) fn hash_files(
+    dir: &Utf8Path,
+    names: &[&str],
+    algorithm: HashAlgorithm,
+) -> Result<Vec<FileHash>> {
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunk_size = names.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = names
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|name| hash_file(&dir.join(name), algorithm))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect();
+        let mut hashes = Vec::with_capacity(names.len());
+        for handle in handles {
+            let chunk_hashes = handle
+                .join()
+                .unwrap_or_else(|payload| std::panic::resume_unwind(payload))?;
+            hashes.extend(chunk_hashes);
+        }
+        Ok(hashes)
+    })
+}
+
+/// A hasher for any of the algorithms files can be hashed with.
+enum FileHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl FileHasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => FileHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            FileHasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            FileHasher::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    fn finalize(self) -> FileHash {
+        match self {
+            FileHasher::Blake3(hasher) => FileHash::Blake3(hasher.finalize().into()),
+            FileHasher::Sha256(hasher) => {
+                FileHash::Sha256(Sha256Hash::from_be_bytes(hasher.finalize().into()))
+            }
+        }
+    }
+}
+
+impl Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn hash_file_contents(path: &Utf8Path, hasher: &mut FileHasher) -> Result<()> {
     let mut file = fs::File::open(path).wrap_err_with(|| format!("failed to open {}", path))?;
+    io::copy(&mut file, hasher).wrap_err_with(|| format!("failed to read from {}", path))?;
+    Ok(())
+}
 
-    let mut buf = Vec::with_capacity(64 * 1024 * 1024);
-    loop {
-        let read_len = file
-            .read(&mut buf)
-            .wrap_err_with(|| format!("failed to read from {}", path))?;
-        if read_len == 0 {
-            break;
+fn hash_dir_contents(root: &Utf8Path, rel_path: &Utf8Path, hasher: &mut FileHasher) -> Result<()> {
+    let dir = root.join(rel_path);
+    let mut entries = vec![];
+    for entry in dir
+        .read_dir()
+        .wrap_err_with(|| format!("failed to read directory {}", dir))?
+    {
+        let entry = entry.wrap_err_with(|| format!("failed to read entry in {}", dir))?;
+        match entry.file_name().into_string() {
+            Ok(name) => entries.push(name),
+            Err(name) => bail!("file name {:?} in {} is not valid UTF-8", name, dir),
         }
-        hasher.update(&buf[..read_len]);
     }
+    entries.sort_unstable();
 
-    Ok(FileHash::Blake3(hasher.finalize().into()))
+    for name in entries {
+        let entry_rel_path = rel_path.join(&name);
+        let entry_path = root.join(&entry_rel_path);
+        let file_type = fs::symlink_metadata(&entry_path)
+            .wrap_err_with(|| format!("failed to stat {}", entry_path))?
+            .file_type();
+
+        hasher.update(entry_rel_path.as_str().as_bytes());
+        hasher.update(&[0]);
+        if file_type.is_dir() {
+            hash_dir_contents(root, &entry_rel_path, hasher)?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&entry_path)
+                .wrap_err_with(|| format!("failed to read symlink {}", entry_path))?;
+            hasher.update(target.to_string_lossy().as_bytes());
+        } else {
+            hash_file_contents(&entry_path, hasher)?;
+        }
+    }
+
+    Ok(())
 }
 
-pub(super) fn hash_bytes(bytes: impl AsRef<[u8]>, hasher: &mut XxHash64) {
+/// Computes the checksum of a fetched artifact, for comparison against a pinned checksum.
+pub(crate) fn checksum_file(path: &Utf8Path) -> Result<Checksum> {
+    let mut file = fs::File::open(path).wrap_err_with(|| format!("failed to open {}", path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).wrap_err_with(|| format!("failed to read from {}", path))?;
+    Ok(Checksum::Sha256(Sha256Hash::from_be_bytes(
+        hasher.finalize().into(),
+    )))
+}
+
+pub(crate) fn hash_bytes(bytes: impl AsRef<[u8]>, hasher: &mut XxHash64) {
     let bytes = bytes.as_ref();
     // This is similar to https://doc.rust-lang.org/beta/nightly-rustc/rustc_data_structures/stable_hasher/trait.HashStable.html.
     hasher.write_u64(bytes.len() as u64);
@@ -38,13 +182,13 @@ pub(super) fn hash_bytes(bytes: impl AsRef<[u8]>, hasher: &mut XxHash64) {
 }
 
 #[derive(Debug)]
-pub(super) struct Utf8TempDir {
+pub(crate) struct Utf8TempDir {
     temp_dir: TempDir,
     path: Utf8PathBuf,
 }
 
 impl Utf8TempDir {
-    pub(super) fn new(parent: &Utf8Path, prefix: &str, suffix: &str) -> Result<Self> {
+    pub(crate) fn new(parent: &Utf8Path, prefix: &str, suffix: &str) -> Result<Self> {
         let mut builder = tempfile::Builder::new();
         let temp_dir = builder
             .prefix(prefix)
@@ -58,22 +202,34 @@ impl Utf8TempDir {
         Ok(Self { temp_dir, path })
     }
 
-    pub(super) fn path(&self) -> &Utf8Path {
+    pub(crate) fn path(&self) -> &Utf8Path {
         &self.path
     }
 }
 
+/// Flushes a directory's entries to disk, so that renames within it survive a crash.
+pub(super) fn sync_dir(dir: &Utf8Path) -> Result<()> {
+    platform::sync_dir(dir).wrap_err_with(|| format!("failed to sync directory {}", dir))
+}
+
 /// Rename a directory to another, ignoring file not found issues.
 pub(super) fn rename_non_racy(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
-    match fs::rename(src, dest) {
+    match platform::rename(src, dest) {
         Ok(()) => Ok(()),
+        // Skip this -- means src doesn't exist.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
         Err(err) => {
-            if err.kind() == io::ErrorKind::NotFound {
-                // Skip this -- means src doesn't exist.
-                Ok(())
+            let hint = if platform::is_file_in_use(&err) {
+                " (hint: exit any programs running from it)"
             } else {
-                bail!("failed to rename existing directory {} to {}", src, dest)
-            }
+                ""
+            };
+            Err(err).wrap_err_with(|| {
+                format!(
+                    "failed to rename existing directory {} to {}{}",
+                    src, dest, hint
+                )
+            })
         }
     }
 }
@@ -103,32 +259,251 @@ impl<T: AsRef<Utf8Path>> UnlockedRoot<T> {
         })
     }
 
-    #[inline]
-    pub(super) fn lock_exclusive(self) -> Result<ExclusiveRoot<T>> {
-        self.file
-            .lock_exclusive()
-            .wrap_err_with(|| format!("failed to obtain exclusive lock at {}", self.lock_path))?;
+    /// Opens the lockfile for a root that has been locked before, without creating it. Returns
+    /// `None` if the lockfile doesn't exist.
+    pub(super) fn open_existing(ctx: T) -> Result<Option<Self>> {
+        let mut lock_path = ctx.as_ref().to_path_buf();
+        lock_path.set_extension(LOCKFILE_EXT);
+        let file = match fs::File::open(&lock_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("failed to open install lock at {}", lock_path))
+            }
+        };
+        Ok(Some(Self {
+            file,
+            lock_path,
+            ctx,
+        }))
+    }
+
+    /// Obtains an exclusive lock, waiting for other processes holding the lock as configured.
+    pub(super) fn lock_exclusive(self, wait: LockWait) -> Result<ExclusiveRoot<T>> {
+        let waited = self.wait_for_lock(wait, "exclusive", platform::try_lock_exclusive)?;
+
+        // Read what the process that was waited for recorded before it's overwritten below.
+        let waited_for = waited.then(|| read_lock_holder(&self.lock_path));
+
+        // Record the process holding the lock and when it obtained it, for diagnostics. This is
+        // best-effort: the lock is what matters, not the contents of the file.
+        let _ = self.file.set_len(0).and_then(|()| {
+            write!(
+                &self.file,
+                "{}\n{}",
+                process::id(),
+                Local::now().to_rfc3339_opts(SecondsFormat::Secs, false)
+            )
+        });
         Ok(ExclusiveRoot {
             file: self.file,
             ctx: self.ctx,
+            waited_for,
         })
     }
 
-    #[inline]
-    #[allow(dead_code)]
-    pub(super) fn lock_shared(self) -> Result<SharedRoot<T>> {
-        self.file
-            .lock_shared()
-            .wrap_err_with(|| format!("failed to obtain shared lock at {}", self.lock_path))?;
+    /// Obtains a shared lock, waiting for any process holding the exclusive lock as configured.
+    pub(super) fn lock_shared(self, wait: LockWait) -> Result<SharedRoot<T>> {
+        self.wait_for_lock(wait, "shared", platform::try_lock_shared)?;
         Ok(SharedRoot {
             file: self.file,
             ctx: self.ctx,
         })
     }
+
+    /// Polls `try_lock` until it obtains the lock, returning true if it had to wait.
+    fn wait_for_lock(
+        &self,
+        wait: LockWait,
+        kind: &str,
+        try_lock: fn(&fs::File) -> io::Result<bool>,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let mut next_message = start;
+        let mut waited = false;
+        while !try_lock(&self.file)
+            .wrap_err_with(|| format!("failed to obtain {} lock at {}", kind, self.lock_path))?
+        {
+            waited = true;
+            let holder = read_lock_holder(&self.lock_path);
+            let waited = start.elapsed();
+            if let LockWait::Timeout(timeout) = wait {
+                if waited >= timeout {
+                    bail!(
+                        "timed out after {}s waiting for {} (hint: pass --wait to wait for as long \
+                         as it takes)",
+                        timeout.as_secs(),
+                        holder,
+                    );
+                }
+            }
+            if Instant::now() >= next_message {
+                tracing::info!(
+                    target: "hasp::output::working::waiting_for_lock",
+                    %holder,
+                    "Waiting for {holder}",
+                );
+                next_message += LOCK_MESSAGE_INTERVAL;
+            }
+
+            cancel::check()?;
+            let sleep_for = match wait {
+                LockWait::Timeout(timeout) => LOCK_POLL_INTERVAL.min(timeout - waited),
+                LockWait::Forever => LOCK_POLL_INTERVAL,
+            };
+            thread::sleep(sleep_for);
+        }
+        Ok(waited)
+    }
 }
 
 static LOCKFILE_EXT: &str = "lock";
 
+/// How often to check whether a contended lock has been released.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often to say that hasp is still waiting for a lock.
+const LOCK_MESSAGE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait for another process to release an install lock.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) enum LockWait {
+    /// Wait for as long as it takes.
+    #[default]
+    Forever,
+    /// Fail if the lock isn't released within this duration. A zero duration fails immediately.
+    Timeout(Duration),
+}
+
+/// Another process holding an exclusive lock on an install directory.
+#[derive(Clone, Debug)]
+pub(crate) struct LockHolder {
+    /// The install directory that's locked.
+    pub(crate) path: Utf8PathBuf,
+    /// The process ID recorded in the lockfile, if any.
+    pub(crate) pid: Option<u32>,
+    /// When the lock was obtained, if recorded in the lockfile.
+    pub(crate) since: Option<DateTime<Local>>,
+    /// The outcome of the install, if the process recorded one before releasing the lock.
+    pub(crate) outcome: Option<LockOutcome>,
+}
+
+/// The outcome of an install, recorded in the lockfile so that processes waiting to install the
+/// same directory don't redo the work.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum LockOutcome {
+    /// The package was installed.
+    Installed,
+    /// Building the package failed, with the output in this build log.
+    Failed { build_log: Utf8PathBuf },
+}
+
+impl LockOutcome {
+    fn parse(line: &str) -> Option<Self> {
+        match line.split_once(' ') {
+            Some(("failed", build_log)) => Some(LockOutcome::Failed {
+                build_log: build_log.into(),
+            }),
+            None if line == "installed" => Some(LockOutcome::Installed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LockOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockOutcome::Installed => write!(f, "installed"),
+            LockOutcome::Failed { build_log } => write!(f, "failed {}", build_log),
+        }
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "process {} (installing to {}", pid, self.path)?,
+            None => write!(f, "unknown process (installing to {}", self.path)?,
+        }
+        match self.since {
+            Some(since) => write!(f, " since {})", since.format("%Y-%m-%d %H:%M:%S")),
+            None => write!(f, ")"),
+        }
+    }
+}
+
+/// Finds other processes holding exclusive locks on install directories within `installs_dir`.
+///
+/// Errors are ignored, since this is only used for diagnostics.
+pub(crate) fn find_lock_holders(installs_dir: &Utf8Path) -> Vec<LockHolder> {
+    let mut holders = vec![];
+    find_lock_holders_impl(installs_dir, &mut holders);
+    holders
+}
+
+fn find_lock_holders_impl(dir: &Utf8Path, holders: &mut Vec<LockHolder>) {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = match entry.file_name().into_string() {
+            Ok(name) => dir.join(name),
+            Err(_) => continue,
+        };
+        let is_dir = matches!(entry.file_type(), Ok(file_type) if file_type.is_dir());
+        if path.extension() == Some(LOCKFILE_EXT) {
+            holders.extend(lock_holder(&path));
+        } else if is_dir && !path.with_extension(LOCKFILE_EXT).exists() {
+            // Don't descend into install directories, which have lockfiles next to them.
+            find_lock_holders_impl(&path, holders);
+        }
+    }
+}
+
+fn lock_holder(lock_path: &Utf8Path) -> Option<LockHolder> {
+    let file = fs::File::open(lock_path).ok()?;
+    match platform::try_lock_shared(&file) {
+        Ok(true) => {
+            // Nothing holds an exclusive lock. (Closing the file releases the shared lock.)
+            None
+        }
+        Ok(false) => {
+            let holder = read_lock_holder(lock_path);
+            if holder.pid == Some(process::id()) {
+                // This process holds the lock.
+                return None;
+            }
+            Some(holder)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Reads the process holding a lock, as recorded in the lockfile: its process ID on the first
+/// line, when it obtained the lock on the second, and the outcome of its install on the third.
+fn read_lock_holder(lock_path: &Utf8Path) -> LockHolder {
+    // On Windows, the lockfile can't be read while it's exclusively locked, so the holder is
+    // unknown.
+    let contents = fs::read_to_string(lock_path).unwrap_or_default();
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|line| line.trim().parse().ok());
+    let since = lines
+        .next()
+        .and_then(|line| DateTime::parse_from_rfc3339(line.trim()).ok())
+        .map(|since| since.with_timezone(&Local));
+    let outcome = lines
+        .next()
+        .and_then(|line| LockOutcome::parse(line.trim()));
+    LockHolder {
+        path: lock_path.with_extension(""),
+        pid,
+        since,
+        outcome,
+    }
+}
+
 /// Operations that can only be performed on a root where the shared lock has been acquired.
 #[derive(Debug)]
 #[must_use]
@@ -137,6 +512,27 @@ pub(super) struct SharedRoot<T> {
     pub(super) ctx: T,
 }
 
+/// A shared lock on an install directory, held while reading it so that an install doesn't
+/// replace it halfway through. Installs wait for shared locks to be released.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct InstallReadLock {
+    _root: SharedRoot<Utf8PathBuf>,
+}
+
+impl InstallReadLock {
+    /// Obtains a shared lock on an install directory, waiting for any install to it as
+    /// configured. Returns `None` if nothing has been installed there, so there's nothing to lock.
+    pub(crate) fn acquire(install_path: &Utf8Path, wait: LockWait) -> Result<Option<Self>> {
+        match UnlockedRoot::open_existing(install_path.to_path_buf())? {
+            Some(root) => Ok(Some(Self {
+                _root: root.lock_shared(wait)?,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
 /// Operations that can only be performed on a root where the exclusive lock has been acquired.
 /// This forms a superset of the operations on the shared root.
 #[derive(Debug)]
@@ -144,4 +540,131 @@ pub(super) struct SharedRoot<T> {
 pub(super) struct ExclusiveRoot<T> {
     file: fs::File,
     pub(super) ctx: T,
+    waited_for: Option<LockHolder>,
+}
+
+impl<T> ExclusiveRoot<T> {
+    /// Returns the process that held the lock before it was obtained, as it was when it released
+    /// the lock, or `None` if the lock wasn't contended.
+    pub(super) fn waited_for(&self) -> Option<&LockHolder> {
+        self.waited_for.as_ref()
+    }
+
+    /// Records the outcome of an install in the lockfile, for any processes waiting for the lock.
+    ///
+    /// This is best-effort, like the rest of the contents of the lockfile.
+    pub(super) fn record_outcome(&self, outcome: &LockOutcome) {
+        let _ = write!(&self.file, "\n{}", outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_file_sha256() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let path = dir.join("abc");
+        fs::write(&path, "abc").expect("file written");
+
+        // The SHA-256 test vector for "abc", from FIPS 180-2.
+        let hash = hash_file(&path, HashAlgorithm::Sha256).expect("file hashed");
+        assert_eq!(
+            hash.to_string(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hash.algorithm(), HashAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn lock_wait_timeout() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let install_path = dir.join("foo");
+        assert!(
+            InstallReadLock::acquire(&install_path, LockWait::Forever)
+                .expect("missing lockfile is fine")
+                .is_none(),
+            "nothing to lock before the first install"
+        );
+
+        let lock = UnlockedRoot::new(&install_path)
+            .expect("lockfile opened")
+            .lock_exclusive(LockWait::Forever)
+            .expect("uncontended lock obtained");
+        let holder = read_lock_holder(&install_path.with_extension(LOCKFILE_EXT));
+        assert_eq!(holder.pid, Some(process::id()));
+        assert!(holder.since.is_some(), "lock time recorded");
+
+        let err = UnlockedRoot::new(&install_path)
+            .expect("lockfile opened")
+            .lock_exclusive(LockWait::Timeout(Duration::ZERO))
+            .expect_err("contended lock not obtained");
+        assert!(
+            err.to_string()
+                .starts_with("timed out after 0s waiting for process"),
+            "unexpected error: {}",
+            err
+        );
+
+        // Readers wait for installs, and installs wait for readers.
+        let no_wait = LockWait::Timeout(Duration::ZERO);
+        assert!(
+            InstallReadLock::acquire(&install_path, no_wait).is_err(),
+            "read lock waits for install"
+        );
+        drop(lock);
+        let _read_lock = InstallReadLock::acquire(&install_path, no_wait)
+            .expect("read lock obtained")
+            .expect("lockfile exists");
+        let _other_read_lock = InstallReadLock::acquire(&install_path, no_wait)
+            .expect("read locks are shared")
+            .expect("lockfile exists");
+        assert!(
+            UnlockedRoot::new(&install_path)
+                .expect("lockfile opened")
+                .lock_exclusive(no_wait)
+                .is_err(),
+            "install waits for readers"
+        );
+    }
+
+    #[test]
+    fn lock_outcome() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let install_path = dir.join("foo");
+
+        let lock = UnlockedRoot::new(install_path.clone())
+            .expect("lockfile opened")
+            .lock_exclusive(LockWait::Forever)
+            .expect("uncontended lock obtained");
+        assert!(
+            lock.waited_for().is_none(),
+            "uncontended lock wasn't waited for"
+        );
+
+        let waiter = thread::spawn(move || {
+            let lock = UnlockedRoot::new(install_path)
+                .expect("lockfile opened")
+                .lock_exclusive(LockWait::Forever)
+                .expect("lock obtained after waiting");
+            lock.waited_for().cloned()
+        });
+        thread::sleep(LOCK_POLL_INTERVAL * 2);
+        let outcome = LockOutcome::Failed {
+            build_log: dir.join("build.log"),
+        };
+        lock.record_outcome(&outcome);
+        drop(lock);
+
+        let holder = waiter
+            .join()
+            .expect("waiter thread didn't panic")
+            .expect("contended lock was waited for");
+        assert_eq!(holder.pid, Some(process::id()));
+        assert_eq!(holder.outcome, Some(outcome));
+    }
 }
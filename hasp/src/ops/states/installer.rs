@@ -2,26 +2,52 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{
+    cancel::{self, Cancelled},
+    config::package_key,
     database::DbContext,
-    models::directory::DirectoryRow,
+    failure::{BinaryConflicts, FailureClass},
+    hooks::{run_hooks, HookContext, HookPoint},
+    lockfile::{read_cargo_lock, LockedDependency, CARGO_LOCK},
+    models::{
+        directory::{DirectoryRow, InstalledRow},
+        install_progress::InstallPhase,
+    },
     ops::{
-        states::helpers::{
-            hash_bytes, hash_file, rename_non_racy, ExclusiveRoot, UnlockedRoot, Utf8TempDir,
+        states::{
+            content_store::ContentStore,
+            helpers::{
+                elapsed_ms, hash_bytes, hash_files, rename_non_racy, sync_dir, ExclusiveRoot,
+                LockHolder, LockOutcome, UnlockedRoot, Utf8TempDir,
+            },
+            remote_cache::{RemoteCache, RemoteCacheKey, ARTIFACT_CHECKSUM_KEY},
+            shared_store::SharedStore,
+            verifier::{SignatureVerification, SignedArtifact},
+            work_dir::remove_dir_all,
         },
-        PackageMatcher,
+        BuiltPackage, PackageMatcher, WorkDir,
     },
     output::NameVersionDisplay,
+    platform::{self, TARGET},
+    process::{failure_details, BuildLog},
+    receipt::{InstallReceipt, ReceiptFile},
+    shims::{remove_shims, write_shims},
 };
 use async_trait::async_trait;
 use camino::{Utf8Path, Utf8PathBuf};
 use chrono::{DateTime, Local};
-use color_eyre::{eyre::WrapErr, Report, Result};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result,
+};
 use colored::Colorize;
 use hasp_metadata::{
-    DirectoryHash, DirectoryVersion, FailureReason, InstallFailed, InstallStarted, InstallSuccess,
+    BinariesRemoved, BinaryConflict, BuildSource, Checksum, DirectoryHash, DirectoryVersion, Event,
+    FailureReason, FailureStage, InstallFailed, InstallStarted, InstallSuccess, InstallTimings,
+    LifecycleEvent, PackageDirectory,
 };
-use rusqlite::{named_params, Transaction};
-use std::{collections::BTreeMap, fmt, fs, hash::Hasher};
+use rusqlite::Transaction;
+use std::{collections::BTreeMap, fmt, fs, future::Future, hash::Hasher, time::Instant};
+use tokio::task::JoinHandle;
 use twox_hash::XxHash64;
 
 #[derive(Debug)]
@@ -29,9 +55,13 @@ pub(crate) struct PackageInstaller {
     matcher: PackageMatcher,
     installer: Box<dyn PackageInstallerImpl>,
     version: DirectoryVersion,
-    temp_dir: Utf8TempDir,
+    work_dir: WorkDir,
     install_path: Utf8PathBuf,
     row: DirectoryRow,
+    /// How the signature on the fetched artifact was verified, if it was.
+    signature: Option<SignatureVerification>,
+    /// How long resolving and fetching the package took.
+    timings: InstallTimings,
 }
 
 impl PackageInstaller {
@@ -39,12 +69,15 @@ impl PackageInstaller {
         matcher: PackageMatcher,
         installer: Box<dyn PackageInstallerImpl>,
         version: DirectoryVersion,
-        temp_dir: Utf8TempDir,
+        work_dir: WorkDir,
+        signature: Option<SignatureVerification>,
+        timings: InstallTimings,
     ) -> Result<Self> {
-        let mut conn = matcher.db_ctx().creator.create()?;
+        let creator = &matcher.db_ctx().creator;
+        let mut conn = creator.create()?;
 
         // Create a new transaction for the initial lookup since we may end up writing to it.
-        let txn = conn.transaction()?;
+        let txn = creator.write_transaction(&mut conn)?;
 
         let (namespace, name) = (matcher.namespace(), matcher.name());
         // Check if a row exists, and insert it if it doesn't.
@@ -75,7 +108,9 @@ impl PackageInstaller {
                     path: &install_path,
                 };
 
-                let lock = init_context.open_lockfile()?.lock_exclusive()?;
+                let lock = init_context
+                    .open_lockfile()?
+                    .lock_exclusive(matcher.hasp_home().lock_wait())?;
                 let row = lock.insert_new(&txn)?;
                 // Complete the transaction before releasing the lock to avoid A-B-B-A issues.
                 txn.commit()?;
@@ -87,38 +122,81 @@ impl PackageInstaller {
             matcher,
             installer,
             version,
-            temp_dir,
+            work_dir,
             install_path,
             row,
+            signature,
+            timings,
         })
     }
 
-    pub(crate) async fn install(&self, force: bool) -> Result<InstallStatus> {
-        let conn = self.matcher.db_ctx().creator.create()?;
-
+    /// Installs the package, unless it's already installed and `force` is false.
+    ///
+    /// If `force_bin_overwrite` is true, binaries that other installed packages have are replaced
+    /// rather than causing the install to fail.
+    pub(crate) async fn install(
+        &self,
+        force: bool,
+        force_bin_overwrite: bool,
+    ) -> Result<InstallStatus> {
         // Obtain an exclusive lock.
-        let lock = self.open_lockfile()?.lock_exclusive()?;
-        // What is the current state of the package?
-        if self.row.get_installed(&conn)? && !force {
-            // The package is already installed.
+        let lock = self
+            .open_lockfile()?
+            .lock_exclusive(self.matcher.hasp_home().lock_wait())?;
+        if let (Some(holder), false) = (lock.waited_for(), force) {
+            if let Some(LockOutcome::Failed { build_log }) = &holder.outcome {
+                // Another process just failed to build the same directory. Report its failure
+                // rather than building it again.
+                self.work_dir.remove()?;
+                return Ok(InstallStatus::Failure {
+                    version: self.version.clone(),
+                    report: concurrent_install_failed(holder),
+                    build_log: build_log.clone(),
+                    retryable: false,
+                });
+            }
+        }
+
+        // What is the current state of the package? If another process was installing it, this
+        // picks up its install.
+        let row = self.row.clone();
+        let installed = self
+            .matcher
+            .db_ctx()
+            .creator
+            .run(move |conn| row.get_installed(conn))
+            .await?;
+        if installed && !force {
+            // The package is already installed, so there's nothing to resume.
+            self.work_dir.remove()?;
             Ok(InstallStatus::AlreadyInstalled {
                 version: self.version.clone(),
+                available: AvailableUpdate::default(),
             })
         } else {
             // Start the installation. (The locking means that nothing else would have come
             // along to start the installation.)
-            let guard = lock.start_install(true)?;
+            let guard = lock.start_install(true, force_bin_overwrite)?;
 
             match self.install_and_finish(guard).await {
-                Ok(binaries) => Ok(InstallStatus::Success {
+                Ok(Finished { binaries, replaced }) => Ok(InstallStatus::Success {
                     version: self.version.clone(),
                     binaries,
+                    replaced,
+                }),
+                Err((InstallError::Fail(err), build_log)) => Ok(InstallStatus::Failure {
+                    version: self.version.clone(),
+                    report: err,
+                    build_log,
+                    retryable: false,
                 }),
-                Err(InstallError::Fail(err)) => Ok(InstallStatus::Failure {
+                Err((InstallError::Retryable(err), build_log)) => Ok(InstallStatus::Failure {
                     version: self.version.clone(),
                     report: err,
+                    build_log,
+                    retryable: true,
                 }),
-                Err(InstallError::Abort(err)) => Err(err),
+                Err((InstallError::Abort(err), _)) => Err(err),
             }
         }
     }
@@ -132,21 +210,42 @@ impl PackageInstaller {
         UnlockedRoot::new(self)
     }
 
+    /// Performs the install, returning the path to the build log on failure.
     #[inline]
     async fn install_and_finish(
         &self,
         mut guard: InstallGuard<'_>,
-    ) -> Result<Vec<String>, InstallError> {
-        let temp_package = guard.install().await.map_err(|err| {
-            err.log_and_rollback(&mut guard);
-            err
-        })?;
+    ) -> Result<Finished, (InstallError, Utf8PathBuf)> {
+        let build_log_path = guard.build_log.path().to_path_buf();
+        let temp_package = match guard.install().await {
+            Ok(temp_package) => temp_package,
+            Err(err) => {
+                err.log_and_rollback(FailureStage::Build, &mut guard);
+                return Err((err, build_log_path));
+            }
+        };
 
-        guard.finish(temp_package).map_err(|err| {
-            let err = InstallError::Abort(err);
-            err.log_and_rollback(&mut guard);
-            err
-        })
+        if let Err(err) = guard.run_post_install_hooks(&temp_package) {
+            let err = InstallError::fail(err);
+            err.log_and_rollback(FailureStage::Finish, &mut guard);
+            return Err((err, build_log_path));
+        }
+
+        // Check for cancellation one last time before committing the install.
+        if let Err(err) = cancel::check() {
+            let err = InstallError::Fail(err.into());
+            err.log_and_rollback(FailureStage::Finish, &mut guard);
+            return Err((err, build_log_path));
+        }
+
+        let finished = guard.finish(temp_package).map_err(|err| {
+            err.log_and_rollback(FailureStage::Finish, &mut guard);
+            (err, build_log_path)
+        })?;
+        if guard.timings.build_source == BuildSource::Built {
+            guard.upload_to_remote_cache().await;
+        }
+        Ok(finished)
     }
 }
 
@@ -162,16 +261,75 @@ pub(crate) enum InstallStatus {
     Success {
         version: DirectoryVersion,
         binaries: Vec<String>,
+        /// The other installed directory of the package whose shims this install took over, if
+        /// any.
+        replaced: Option<DirectoryRow>,
     },
     Failure {
         version: DirectoryVersion,
         report: Report,
+        /// The log containing the output of any build processes.
+        build_log: Utf8PathBuf,
+        /// Whether the failure might not happen again if the install is attempted again.
+        retryable: bool,
     },
     AlreadyInstalled {
         version: DirectoryVersion,
+        /// The check for a newer version matching the requirement the package was installed
+        /// with.
+        available: AvailableUpdate,
     },
 }
 
+/// A check for a newer version of an installed package, which runs in the background so that it
+/// doesn't hold up other installs.
+#[derive(Debug, Default)]
+pub(crate) enum AvailableUpdate {
+    /// The check is still running.
+    Checking(JoinHandle<Option<DirectoryVersion>>),
+    /// The check finished, or there was nothing to check.
+    #[default]
+    Unknown,
+    /// A newer version was found.
+    Found(DirectoryVersion),
+}
+
+impl AvailableUpdate {
+    /// Runs the check in the background.
+    pub(crate) fn spawn(
+        check: impl Future<Output = Option<DirectoryVersion>> + Send + 'static,
+    ) -> Self {
+        Self::Checking(tokio::spawn(check))
+    }
+
+    /// Waits for the check to finish, if it's still running.
+    pub(crate) async fn wait(&mut self) {
+        if let Self::Checking(handle) = self {
+            *self = match handle.await {
+                Ok(Some(version)) => Self::Found(version),
+                Ok(None) | Err(_) => Self::Unknown,
+            };
+        }
+    }
+
+    /// Returns the newer version, if the check has finished and found one.
+    pub(crate) fn found(self) -> Option<DirectoryVersion> {
+        match self {
+            Self::Found(version) => Some(version),
+            Self::Checking(_) | Self::Unknown => None,
+        }
+    }
+}
+
+/// A finished install.
+#[derive(Debug)]
+struct Finished {
+    /// The binaries in the install, formatted for output.
+    binaries: Vec<String>,
+    /// The other installed directory of the package whose shims were overwritten, if any.
+    replaced: Option<DirectoryRow>,
+}
+
 #[derive(Debug)]
 #[must_use]
 pub(crate) struct TempInstalledPackage {
@@ -193,8 +351,38 @@ pub(crate) trait PackageInstallerImpl: fmt::Debug {
     /// Information to add to the directory hash, other than the name and version.
     fn add_to_hasher(&self, hasher: &mut XxHash64);
 
-    /// Installs a package as necessary.
-    async fn install(&self) -> Result<TempInstalledPackage>;
+    /// Returns the checksum of the fetched artifact, if there is one.
+    ///
+    /// This is compared against the checksum pinned for the package, if any.
+    fn artifact_checksum(&self) -> Option<Checksum>;
+
+    /// Returns the fetched artifact, for verifying its signature. Defaults to none, for packages
+    /// that don't have a signed artifact.
+    fn signed_artifact(&self) -> Option<SignedArtifact> {
+        None
+    }
+
+    /// Installs a package as necessary, writing the output of any build processes to the build
+    /// log.
+    async fn install(&self, build_log: &BuildLog) -> Result<TempInstalledPackage>;
+
+    /// Returns true if installs don't depend on the machine they were made on, other than its
+    /// target, so that they can be copied in from a shared store instead.
+    fn shareable(&self) -> bool {
+        false
+    }
+
+    /// Returns true if installs should be copied in from the shared store or the remote cache,
+    /// when they have one, rather than built from source. Defaults to true.
+    fn prefer_prebuilt(&self) -> bool {
+        true
+    }
+
+    /// Returns what builds depend on other than the directory hash and target, if they can be
+    /// shared through a remote cache. Defaults to none.
+    fn remote_cache_key(&self) -> Option<RemoteCacheKey> {
+        None
+    }
 }
 
 // ---
@@ -228,37 +416,23 @@ impl<'a> ExclusiveRoot<InitContext<'a>> {
         // TODO: may actually want to store fetch metadata
         let metadata = self.ctx.matcher.metadata();
 
-        let (namespace, name) = (self.ctx.matcher.namespace(), self.ctx.matcher.name());
-        txn.query_row_and_then(
-            // Since this can only be inserted while the exclusive lock is held,
-            // concurrent connections should never be able to insert the same package.
-            // Fail if it happens.
-            "INSERT INTO packages.directories (namespace, name, hash, version, metadata, installed) \
-                VALUES (:namespace, :name, :hash, :version, :metadata, :installed)\
-                RETURNING directory_id, namespace, name, hash, version, metadata",
-            named_params! {
-                ":namespace": namespace,
-                ":name": name,
-                ":hash": &self.ctx.hash,
-                ":version": &self.ctx.version,
-                ":metadata": metadata,
-                ":installed": false,
-            },
-            DirectoryRow::from_row,
-        )
-        .wrap_err_with(|| {
-            format!(
-                "failed to insert row for {}:{} (version {}, hash {})",
-                namespace, name, self.ctx.version, self.ctx.hash,
-            )
-        })
+        let package = PackageDirectory {
+            namespace: self.ctx.matcher.namespace().to_owned(),
+            name: self.ctx.matcher.name().to_owned(),
+            version: self.ctx.version.clone(),
+            hash: self.ctx.hash,
+            metadata,
+        };
+        // Since this can only be inserted while the exclusive lock is held, concurrent
+        // connections should never be able to insert the same package. Fail if it happens.
+        DirectoryRow::insert(&package, false, txn)
     }
 }
 
 impl<'inst> ExclusiveRoot<&'inst PackageInstaller> {
     /// Start an installation. Returns an `InstallTransaction`, which is an RAII guard.
-    fn start_install(self, force: bool) -> Result<InstallGuard<'inst>> {
-        InstallGuard::new(self, force)
+    fn start_install(self, force: bool, force_bin_overwrite: bool) -> Result<InstallGuard<'inst>> {
+        InstallGuard::new(self, force, force_bin_overwrite)
     }
 
     /// Returns the database context (convenience method).
@@ -274,23 +448,79 @@ impl<'inst> ExclusiveRoot<&'inst PackageInstaller> {
 struct InstallGuard<'inst> {
     lock: ExclusiveRoot<&'inst PackageInstaller>,
     force: bool,
+    /// Whether to replace binaries that other installed packages have.
+    force_bin_overwrite: bool,
     start_time: DateTime<Local>,
     new_dir: Utf8PathBuf,
     old_dir: Utf8PathBuf,
+    build_log: BuildLog,
+    /// Files built by an earlier attempt, which don't need to be built again.
+    resumed_build: Option<TempInstalledPackage>,
+    /// How long each phase took, and where the build came from.
+    timings: InstallTimings,
     finished: bool,
 }
 
 impl<'inst> InstallGuard<'inst> {
     /// Creates a new install transaction, setting the status in the database to `"installing"`.
-    fn new(lock: ExclusiveRoot<&'inst PackageInstaller>, force: bool) -> Result<Self> {
-        // Create the old and new directories.
-        let new_dir = lock.ctx.temp_dir.path().join("install-new");
+    fn new(
+        lock: ExclusiveRoot<&'inst PackageInstaller>,
+        force: bool,
+        force_bin_overwrite: bool,
+    ) -> Result<Self> {
+        // Create the old and new directories, reusing the files built by an earlier attempt if
+        // they're all still around.
+        let work_dir = &lock.ctx.work_dir;
+        let new_dir = work_dir.path().join("install-new");
+        let resumed_build = work_dir.resume_build(&new_dir);
+        if resumed_build.is_none() {
+            remove_dir_all(&new_dir)?;
+        }
         fs::create_dir_all(&new_dir)
             .wrap_err_with(|| format!("failed to create directory at {}", new_dir))?;
-        let old_dir = lock.ctx.temp_dir.path().join("install-old");
+
+        // If an earlier attempt was interrupted while finishing, the previous install may have
+        // been moved aside, and the new one moved into place without being recorded in the
+        // database. Restore the previous install if so.
+        let old_dir = work_dir.old_dir();
+        let install_path = lock.ctx.as_ref();
+        if old_dir.exists() {
+            if install_path.exists() && !work_dir.interrupted_swap() {
+                remove_dir_all(&old_dir)?;
+            } else {
+                remove_dir_all(install_path)?;
+                platform::rename(&old_dir, install_path).wrap_err_with(|| {
+                    format!(
+                        "failed to restore previous install from {} to {}",
+                        old_dir, install_path
+                    )
+                })?;
+            }
+        }
 
         let start_time = Local::now();
 
+        let ctx = lock.ctx;
+        let (namespace, name) = (ctx.matcher.namespace(), ctx.matcher.name());
+        let build_log_path = ctx
+            .matcher
+            .hasp_home()
+            .build_log_dir(namespace, name)
+            .join(format!(
+                "{}-{}.{}",
+                start_time.format("%Y%m%d-%H%M%S"),
+                ctx.row.package.hash,
+                BuildLog::EXTENSION,
+            ));
+        let build_log = BuildLog::create(build_log_path)?;
+        build_log.write_line(format_args!(
+            "Installing {}:{} version {} at {}",
+            namespace,
+            name,
+            ctx.version,
+            start_time.to_rfc3339(),
+        ));
+
         // This shouldn't mark the directory as not-installed, because a force-install failure
         // should keep the old version around.
 
@@ -302,148 +532,509 @@ impl<'inst> InstallGuard<'inst> {
                 start_time,
                 new_dir: new_dir.clone(),
                 old_dir: old_dir.clone(),
+                build_log: build_log.path().to_path_buf(),
             };
-            lock.db_ctx().event_logger.log("install_started", &event);
+            lock.db_ctx()
+                .event_logger
+                .log_lifecycle(LifecycleEvent::InstallStarted(event));
         }
 
+        let timings = lock.ctx.timings.clone();
         Ok(Self {
             lock,
             force,
+            force_bin_overwrite,
             start_time,
             new_dir,
             old_dir,
+            build_log,
+            resumed_build,
+            timings,
             finished: false,
         })
     }
 
-    /// Installs the package into the temp directory.
-    async fn install(&self) -> Result<TempInstalledPackage, InstallError> {
-        tracing::info!(
-            target: "hasp::output::working::installing",
-            "Installing {}",
-            NameVersionDisplay::dir_version(self.lock.ctx.matcher.name(), &self.lock.ctx.version),
-        );
+    /// Installs the package into the new directory.
+    async fn install(&mut self) -> Result<TempInstalledPackage, InstallError> {
+        let start = Instant::now();
+        let temp_package = self.install_impl().await?;
+        self.timings.build_ms = elapsed_ms(start);
+        Ok(temp_package)
+    }
 
-        let mut temp_package = self
-            .lock
-            .ctx
-            .installer
-            .install()
-            .await
-            .map_err(InstallError::Fail)?;
+    async fn install_impl(&mut self) -> Result<TempInstalledPackage, InstallError> {
+        if let Some(temp_package) = self.resumed_build.take() {
+            let package = NameVersionDisplay::dir_version(
+                self.lock.ctx.matcher.name(),
+                &self.lock.ctx.version,
+            );
+            tracing::info!(
+                target: "hasp::output::working::reusing_build",
+                key = %self.lock.ctx.matcher.key(),
+                %package,
+                "Reusing build of {package} from an earlier attempt",
+            );
+            self.build_log
+                .write_line(format_args!("Reusing build from {}", self.new_dir));
+            self.timings.build_source = BuildSource::Resumed;
+            return Ok(temp_package);
+        }
 
-        // Move the installed files over to the new directory.
-        for (name, installed_file) in &mut temp_package.installed_files {
-            let new_path = self.new_dir.join(name);
-            std::fs::rename(&installed_file.temp_path, &new_path)
-                .wrap_err_with(|| {
-                    format!(
-                        "failed to rename {} to {}",
-                        installed_file.temp_path, new_path
-                    )
-                })
+        // Prebuilt binaries are looked for in the shared store, then the remote cache.
+        let prefer_prebuilt = self.lock.ctx.installer.prefer_prebuilt();
+        let mut copied = if prefer_prebuilt {
+            self.copy_from_shared_store().map_err(InstallError::Abort)?
+        } else {
+            None
+        };
+        if copied.is_some() {
+            self.timings.build_source = BuildSource::SharedStore;
+        } else if prefer_prebuilt {
+            copied = self
+                .fetch_from_remote_cache()
+                .await
                 .map_err(InstallError::Abort)?;
-            installed_file.temp_path = new_path;
+            if copied.is_some() {
+                self.timings.build_source = BuildSource::RemoteCache;
+            }
         }
+        let temp_package = match copied {
+            Some(temp_package) => temp_package,
+            None => {
+                let package = NameVersionDisplay::dir_version(
+                    self.lock.ctx.matcher.name(),
+                    &self.lock.ctx.version,
+                );
+                tracing::info!(
+                    target: "hasp::output::working::installing",
+                    key = %self.lock.ctx.matcher.key(),
+                    %package,
+                    "Installing {package}",
+                );
+
+                let mut temp_package = self
+                    .lock
+                    .ctx
+                    .installer
+                    .install(&self.build_log)
+                    .await
+                    .map_err(InstallError::fail)?;
+
+                // Move the installed files over to the new directory.
+                for (name, installed_file) in &mut temp_package.installed_files {
+                    let new_path = self.new_dir.join(name);
+                    std::fs::rename(&installed_file.temp_path, &new_path)
+                        .wrap_err_with(|| {
+                            format!(
+                                "failed to rename {} to {}",
+                                installed_file.temp_path, new_path
+                            )
+                        })
+                        .map_err(InstallError::Abort)?;
+                    installed_file.temp_path = new_path;
+                }
+                self.timings.build_source = BuildSource::Built;
+                temp_package
+            }
+        };
+
+        self.record_built(&temp_package)
+            .map_err(InstallError::Abort)?;
 
         Ok(temp_package)
     }
 
+    /// Records that the package was built into the new directory, so that a later attempt can
+    /// pick up from there.
+    fn record_built(&self, temp_package: &TempInstalledPackage) -> Result<()> {
+        let built = serde_json::to_value(BuiltPackage::new(temp_package))
+            .expect("serializing built package should never fail");
+        self.lock.ctx.work_dir.record(InstallPhase::Built, built)
+    }
+
+    /// Runs post-install hooks against the built files in the new directory, before they're moved
+    /// into place.
+    fn run_post_install_hooks(&self, temp_package: &TempInstalledPackage) -> Result<()> {
+        let ctx = self.lock.ctx;
+        let binaries = temp_package
+            .installed_files
+            .iter()
+            .filter(|(_, file)| file.is_binary)
+            .map(|(name, _)| self.new_dir.join(name));
+        let hook_ctx = HookContext::new(
+            HookPoint::PostInstall,
+            ctx.matcher.namespace(),
+            ctx.matcher.name(),
+        )
+        .env("HASP_VERSION", ctx.version.short_display())
+        .env("HASP_INSTALL_DIR", &self.new_dir)
+        .binaries(binaries);
+        run_hooks(
+            ctx.matcher.hasp_home(),
+            &self.lock.db_ctx().event_logger,
+            &hook_ctx,
+        )
+    }
+
     /// Commits the install transaction and mark it finished.
-    fn finish(&mut self, temp_package: TempInstalledPackage) -> Result<Vec<String>> {
+    fn finish(&mut self, temp_package: TempInstalledPackage) -> Result<Finished, InstallError> {
         assert!(!self.finished, "finish should never be called twice");
+        let start = Instant::now();
 
-        let mut conn = self.lock.db_ctx().creator.create()?;
-        let txn = conn.transaction()?;
+        // Record that directories are about to be moved around, so that if this is interrupted
+        // before the install is recorded, the next attempt puts the previous install back.
+        self.lock
+            .ctx
+            .work_dir
+            .record(InstallPhase::Swapping, serde_json::Value::Null)
+            .map_err(InstallError::Abort)?;
+
+        // Hash the files before obtaining the write lock, so that other installs aren't held up
+        // while packages with many files are hashed.
+        let files = self
+            .receipt_files(&temp_package)
+            .map_err(InstallError::Abort)?;
+
+        let creator = &self.lock.db_ctx().creator;
+        let mut conn = creator.create().map_err(InstallError::Abort)?;
+        // Obtain the write lock before moving directories around, so that the transaction doesn't
+        // fail partway through. Holding it also means that no other install can add binaries
+        // between checking for conflicts and recording this install.
+        let txn = creator
+            .write_transaction(&mut conn)
+            .map_err(InstallError::Abort)?;
+        let conflicts = self
+            .binary_conflicts(&temp_package, &txn)
+            .map_err(InstallError::Abort)?;
+        if !conflicts.0.is_empty() && !self.force_bin_overwrite {
+            // Nothing else can be recorded while the write lock is held.
+            drop(txn);
+            // Nothing has been moved yet, so the next attempt can pick up the build.
+            if let Err(err) = self.record_built(&temp_package) {
+                let package = self.row().to_friendly();
+                let error = format!("{:#}", err);
+                tracing::debug!(
+                    target: "hasp::output::working::record_failed",
+                    %package,
+                    %error,
+                    "Failed to record build of {package}: {error}",
+                );
+            }
+            self.record_binary_conflicts(&conflicts);
+            return Err(InstallError::Fail(Report::new(conflicts)));
+        }
+
+        let finished = self
+            .finish_in(temp_package, files, txn, start)
+            .map_err(InstallError::Abort)?;
+        if !conflicts.0.is_empty() {
+            self.record_binary_conflicts(&conflicts);
+            let binaries = conflicts.describe();
+            let package = NameVersionDisplay::dir_version(
+                self.lock.ctx.matcher.name(),
+                &self.lock.ctx.version,
+            );
+            tracing::warn!(
+                target: "hasp::output::binaries_overwritten",
+                %package,
+                %binaries,
+                "Warning {package} replaced binaries {binaries} installed by other packages",
+            );
+        }
+        Ok(finished)
+    }
+
+    /// Hashes the files in the new directory, returning them as they're listed in the receipt.
+    fn receipt_files(
+        &self,
+        temp_package: &TempInstalledPackage,
+    ) -> Result<BTreeMap<String, ReceiptFile>> {
+        let algorithm = self
+            .lock
+            .ctx
+            .matcher
+            .hasp_home()
+            .config()
+            .install
+            .hash_algorithm;
+        let names: Vec<_> = temp_package
+            .installed_files
+            .keys()
+            .map(String::as_str)
+            .collect();
+        let hashes = hash_files(&self.new_dir, &names, algorithm)?;
+        Ok(temp_package
+            .installed_files
+            .iter()
+            .zip(hashes)
+            .map(|((name, installed_file), hash)| {
+                let receipt_file = ReceiptFile {
+                    hash,
+                    metadata: installed_file.metadata.clone(),
+                    is_binary: installed_file.is_binary,
+                };
+                (name.clone(), receipt_file)
+            })
+            .collect())
+    }
+
+    /// Moves the new install into place and records it, committing the write transaction.
+    fn finish_in(
+        &mut self,
+        temp_package: TempInstalledPackage,
+        files: BTreeMap<String, ReceiptFile>,
+        txn: Transaction<'_>,
+        start: Instant,
+    ) -> Result<Finished> {
+        // Write out a receipt describing the install, so that it's in place as soon as the new
+        // directory is.
+        let install_time = Local::now();
+        self.link_into_content_store(&files);
+        let receipt = InstallReceipt {
+            hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+            package: self.row().package.clone(),
+            target: Some(TARGET.to_owned()),
+            metadata: self.install_metadata(&temp_package),
+            dependencies: self.read_dependencies(&files),
+            files,
+            start_time: self.start_time,
+            install_time,
+        };
+        receipt.write(&self.new_dir)?;
+        sync_dir(&self.new_dir)?;
 
         // Move the existing install into the old tempdir (if any), and the new install
         // into the new tempdir.
         let install_path = self.lock.ctx.as_ref();
         rename_non_racy(install_path, &self.old_dir)?;
-        fs::rename(&self.new_dir, install_path).wrap_err_with(|| {
-            format!(
-                "failed to rename new directory {} to install path {}",
-                &self.new_dir, install_path
-            )
-        })?;
-
-        // Add the install to packages.installed.
-        let install_time = Local::now();
-        let install_id: i64 = txn
-            .query_row(
-                "INSERT INTO packages.installed (directory_id, install_time, metadata)\
-        VALUES (:directory_id, :install_time, :metadata)\
-        RETURNING install_id",
-                named_params! {
-                    ":directory_id": self.row().directory_id,
-                    ":install_time": install_time,
-                    ":metadata": &temp_package.metadata,
-                },
-                |row| row.get("install_id"),
-            )
-            .wrap_err_with(|| {
+        if let Err(err) = platform::rename(&self.new_dir, install_path) {
+            self.revert_swap();
+            return Err(err).wrap_err_with(|| {
                 format!(
-                    "failed to add {} to packages.installed",
-                    self.row().to_friendly()
+                    "failed to rename new directory {} to install path {}",
+                    &self.new_dir, install_path
                 )
-            })?;
-
-        // Update the state to installed.
-        self.row().set_installed(&txn, true)?;
-
-        for (name, installed_file) in &temp_package.installed_files {
-            // Hash the file on disk.
-            let file_hash = hash_file(&install_path.join(name))?;
-
-            txn.execute(
-                "INSERT INTO packages.installed_files (install_id, name, hash, metadata, is_binary)\
-                VALUES (:install_id, :name, :hash, :metadata, :is_binary)",
-                named_params! {
-                    ":install_id": install_id,
-                    ":name": name,
-                    ":hash": file_hash,
-                    ":metadata": &installed_file.metadata,
-                    ":is_binary": installed_file.is_binary,
-                }
-            )
-            .wrap_err_with(|| {
-                format!(
-                    "for {}, failed to insert {} to packages.installed_files",
-                    self.row().to_friendly(),
-                    name,
-                )
-            })?;
+            });
         }
 
-        txn.commit().wrap_err_with(|| {
-            format!(
-                "failed to commit transaction for {}",
-                self.row().to_friendly(),
-            )
-        })?;
+        // Make sure the renames are on disk before the database says they happened.
+        let res = install_path
+            .parent()
+            .map_or(Ok(()), sync_dir)
+            .and_then(|()| sync_dir(self.lock.ctx.work_dir.path()))
+            .and_then(|()| {
+                // Files that earlier installs of this directory had, but this one doesn't, no
+                // longer exist.
+                let removed_binaries = self.row().delete_stale_files(&receipt.files, &txn)?;
+                let replaced = self.replaced_directory(&receipt.files, &txn)?;
+                // Add the install to packages.installed, and update the state to installed.
+                self.row().insert_install(&receipt, &txn)?;
+                self.row().set_installed(&txn, true)?;
+
+                self.lock.ctx.work_dir.record_in(
+                    InstallPhase::Finalized,
+                    serde_json::Value::Null,
+                    &txn,
+                )?;
+
+                txn.commit().wrap_err_with(|| {
+                    format!(
+                        "failed to commit transaction for {}",
+                        self.row().to_friendly(),
+                    )
+                })?;
+                Ok((removed_binaries, replaced))
+            });
+        let (removed_binaries, replaced) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                // The database still describes the previous install, so put it back.
+                self.revert_swap();
+                return Err(err);
+            }
+        };
 
         // Mark this install as finished at the end. If a rollback is initiated because of a failure
         // in this method, we want it to complete.
         self.finished = true;
+        self.lock.record_outcome(&LockOutcome::Installed);
+        self.timings.finish_ms = elapsed_ms(start);
         let install_success = InstallSuccess {
             package: self.row().package.clone(),
             force: self.force,
             start_time: self.start_time,
             end_time: Local::now(),
+            timings: Some(self.timings.clone()),
         };
 
         self.lock
             .db_ctx()
             .event_logger
-            .log("install_success", &install_success);
+            .log_lifecycle(LifecycleEvent::InstallSuccess(install_success));
+
+        // The work directory, including the previous install moved aside above, is no longer
+        // needed. Failing to remove it shouldn't cause the install to be rolled back.
+        if let Err(err) = self.lock.ctx.work_dir.remove() {
+            let package = self.row().to_friendly();
+            let error = format!("{:#}", err);
+            tracing::debug!(
+                target: "hasp::output::working::cleanup_failed",
+                %package,
+                %error,
+                "Failed to clean up work directory for {package}: {error}",
+            );
+        }
 
-        let installed_binaries: Vec<_> = temp_package
+        let binary_names = temp_package
             .installed_files
             .iter()
-            .filter_map(|(name, file)| file.is_binary.then(|| format!("{}", name.as_str().bold())))
+            .filter(|(_, file)| file.is_binary)
+            .map(|(name, _)| name.as_str());
+
+        // Shims are written out after the transaction is committed: failing to write them
+        // shouldn't cause the install to be rolled back.
+        let bin_dir = self.lock.ctx.matcher.hasp_home().bin_dir();
+        if let Err(err) = write_shims(bin_dir, install_path, binary_names.clone()) {
+            let package = self.row().to_friendly();
+            let error = format!("{:#}", err);
+            tracing::warn!(
+                target: "hasp::output::shims_failed",
+                %package,
+                %error,
+                "Warning failed to write shims for {package}: {error}",
+            );
+        }
+
+        if !removed_binaries.is_empty() {
+            self.remove_stale_shims(removed_binaries);
+        }
+
+        let installed_binaries: Vec<_> = binary_names
+            .map(|name| format!("{}", name.bold()))
             .collect();
 
-        Ok(installed_binaries)
+        Ok(Finished {
+            binaries: installed_binaries,
+            replaced,
+        })
+    }
+
+    /// Returns the most recently installed other directory of this package that has a binary this
+    /// install also has. Its shims for those binaries are overwritten by this install.
+    fn replaced_directory(
+        &self,
+        files: &BTreeMap<String, ReceiptFile>,
+        txn: &Transaction,
+    ) -> Result<Option<DirectoryRow>> {
+        let package = &self.row().package;
+        let installed = InstalledRow::all_installed_for(&package.namespace, &package.name, txn)?;
+        Ok(installed
+            .into_iter()
+            .rev()
+            .filter(|row| row.directory_row.directory_id != self.row().directory_id)
+            .find(|row| {
+                row.binary_names()
+                    .any(|name| matches!(files.get(name), Some(file) if file.is_binary))
+            })
+            .map(|row| row.directory_row))
+    }
+
+    /// Returns the binaries being installed that other installed packages have, along with those
+    /// packages. Installing them would replace the shims for those packages.
+    fn binary_conflicts(
+        &self,
+        temp_package: &TempInstalledPackage,
+        txn: &Transaction,
+    ) -> Result<BinaryConflicts> {
+        let mut conflicts = BTreeMap::new();
+        let binaries = temp_package
+            .installed_files
+            .iter()
+            .filter(|(_, file)| file.is_binary)
+            .map(|(name, _)| name);
+        for binary in binaries {
+            let owners = self.row().binary_owners(binary, txn)?;
+            if !owners.is_empty() {
+                let owners = owners
+                    .iter()
+                    .map(|(namespace, name)| package_key(namespace, name))
+                    .collect();
+                conflicts.insert(binary.clone(), owners);
+            }
+        }
+        Ok(BinaryConflicts(conflicts))
+    }
+
+    /// Records conflicting binaries as a `binary_conflict` event.
+    fn record_binary_conflicts(&self, conflicts: &BinaryConflicts) {
+        self.lock
+            .db_ctx()
+            .event_logger
+            .log(Event::BinaryConflict(BinaryConflict {
+                package: self.row().package.clone(),
+                conflicts: conflicts.0.clone(),
+                overwritten: self.force_bin_overwrite,
+            }));
+    }
+
+    /// Removes the shims for binaries that the previous install of this directory had, but this
+    /// one doesn't, and records that they were removed.
+    fn remove_stale_shims(&self, binaries: Vec<String>) {
+        let install_path = self.lock.ctx.as_ref();
+        let bin_dir = self.lock.ctx.matcher.hasp_home().bin_dir();
+        if let Err(err) = remove_shims(bin_dir, install_path, binaries.iter().map(String::as_str)) {
+            let package = self.row().to_friendly();
+            let error = format!("{:#}", err);
+            tracing::warn!(
+                target: "hasp::output::shims_remove_failed",
+                %package,
+                %error,
+                "Warning failed to remove shims for {package}: {error}",
+            );
+        }
+        let package =
+            NameVersionDisplay::dir_version(self.lock.ctx.matcher.name(), &self.lock.ctx.version);
+        let binaries_str = binaries
+            .iter()
+            .map(|name| format!("{}", name.bold()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        tracing::info!(
+            target: "hasp::output::binaries_removed",
+            %package,
+            binaries = %binaries_str,
+            "Removed binaries {binaries} that {package} no longer has",
+            binaries = binaries_str,
+        );
+        self.lock
+            .db_ctx()
+            .event_logger
+            .log(Event::BinariesRemoved(BinariesRemoved {
+                package: self.row().package.clone(),
+                binaries,
+            }));
+    }
+
+    /// Moves the new install back out of the install path, and the previous install (if any)
+    /// back into it, after a failure to record the new install.
+    ///
+    /// Errors are logged rather than returned: if this fails, the next attempt restores the
+    /// previous install, since the work directory is still in the swapping phase.
+    fn revert_swap(&self) {
+        let install_path = self.lock.ctx.as_ref();
+        let res = rename_non_racy(install_path, &self.new_dir)
+            .and_then(|()| rename_non_racy(&self.old_dir, install_path));
+        if let Err(err) = res {
+            let package = self.row().to_friendly();
+            let error = format!("{:#}", err);
+            tracing::warn!(
+                target: "hasp::output::revert_failed",
+                %package,
+                %error,
+                "Warning failed to restore the previous install of {package}, it will be restored the \
+                 next time it's installed: {error}",
+            );
+        }
     }
 
     /// Explicitly roll back the installation.
@@ -458,17 +1049,25 @@ impl<'inst> InstallGuard<'inst> {
         // then we don't want to try and rollback again -- instead, trust that a future process
         // will clean it up.
         self.finished = true;
+        // Processes waiting to install the same directory would fail the same way, but should
+        // try again if this install was cancelled or aborted.
+        if matches!(reason, FailureReason::ProcessFailed { .. }) {
+            self.lock.record_outcome(&LockOutcome::Failed {
+                build_log: self.build_log.path().to_path_buf(),
+            });
+        }
         let install_failed = InstallFailed {
             package: self.row().package.clone(),
             force: self.force,
             start_time: self.start_time,
             end_time: Local::now(),
             reason,
+            build_log: self.build_log.path().to_path_buf(),
         };
         self.lock
             .db_ctx()
             .event_logger
-            .log("install_failed", &install_failed);
+            .log_lifecycle(LifecycleEvent::InstallFailed(install_failed));
 
         Ok(())
     }
@@ -477,6 +1076,221 @@ impl<'inst> InstallGuard<'inst> {
     // Helper methods
     // ---
 
+    /// Copies a matching install from the shared store into the new directory, if one is
+    /// configured and has it. Forced installs are always built.
+    fn copy_from_shared_store(&self) -> Result<Option<TempInstalledPackage>> {
+        let ctx = self.lock.ctx;
+        let root = match &ctx.matcher.hasp_home().config().install.shared_store {
+            Some(root) if !self.force && ctx.installer.shareable() => root,
+            _ => return Ok(None),
+        };
+        let store = SharedStore::new(root);
+        match store.copy_into(&self.row().package, &self.new_dir) {
+            Ok(Some(temp_package)) => {
+                let package = NameVersionDisplay::dir_version(ctx.matcher.name(), &ctx.version);
+                tracing::info!(
+                    target: "hasp::output::working::shared_store",
+                    key = %ctx.matcher.key(),
+                    %package,
+                    store = %root,
+                    "Copying {package} from the shared store at {store}",
+                    store = root,
+                );
+                self.build_log
+                    .write_line(format_args!("Copied from the shared store at {}", root));
+                Ok(Some(temp_package))
+            }
+            Ok(None) => Ok(None),
+            Err(err) => {
+                // A broken store shouldn't stop the package from being built.
+                let name = ctx.matcher.name();
+                let error = format!("{:#}", err);
+                tracing::warn!(
+                    target: "hasp::output::shared_store_failed",
+                    %name,
+                    %error,
+                    "Warning failed to copy {name} from the shared store, building it instead: {error}",
+                );
+                remove_dir_all(&self.new_dir)?;
+                fs::create_dir_all(&self.new_dir)
+                    .wrap_err_with(|| format!("failed to create directory at {}", self.new_dir))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Downloads a matching build from the remote cache into the new directory, if one is
+    /// configured and has it. Forced installs are always built.
+    async fn fetch_from_remote_cache(&self) -> Result<Option<TempInstalledPackage>> {
+        let ctx = self.lock.ctx;
+        let hasp_home = ctx.matcher.hasp_home();
+        let config = match &hasp_home.config().install.remote_cache {
+            Some(config) if !self.force => config,
+            _ => return Ok(None),
+        };
+        let key = match ctx.installer.remote_cache_key() {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let cache = RemoteCache::new(config, key);
+        let staging = Utf8TempDir::new(hasp_home.cache_dir(), "remote-cache-", "")?;
+        match cache
+            .fetch_into(
+                &self.row().package,
+                staging.path(),
+                &self.new_dir,
+                &hasp_home.config().signatures,
+                ctx.installer.artifact_checksum().as_ref(),
+            )
+            .await
+        {
+            Ok(Some(temp_package)) => {
+                let package = NameVersionDisplay::dir_version(ctx.matcher.name(), &ctx.version);
+                let url = cache.url(&self.row().package);
+                tracing::info!(
+                    target: "hasp::output::working::remote_cache",
+                    key = %ctx.matcher.key(),
+                    %package,
+                    "Downloaded {package} from the remote cache",
+                );
+                self.build_log
+                    .write_line(format_args!("Downloaded from the remote cache at {}", url));
+                Ok(Some(temp_package))
+            }
+            Ok(None) => Ok(None),
+            Err(err) => {
+                // A broken cache shouldn't stop the package from being built.
+                let name = ctx.matcher.name();
+                let error = format!("{:#}", err);
+                tracing::warn!(
+                    target: "hasp::output::remote_cache_failed",
+                    %name,
+                    %error,
+                    "Warning failed to download {name} from the remote cache, building it instead: {error}",
+                );
+                remove_dir_all(&self.new_dir)?;
+                fs::create_dir_all(&self.new_dir)
+                    .wrap_err_with(|| format!("failed to create directory at {}", self.new_dir))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Uploads the finished install to the remote cache, if one is configured and uploads are
+    /// enabled. Failing to upload doesn't fail the install.
+    async fn upload_to_remote_cache(&self) {
+        let ctx = self.lock.ctx;
+        let config = match &ctx.matcher.hasp_home().config().install.remote_cache {
+            Some(config) if config.upload() => config,
+            _ => return,
+        };
+        let key = match ctx.installer.remote_cache_key() {
+            Some(key) => key,
+            None => return,
+        };
+        let cache = RemoteCache::new(config, key);
+        match cache.upload(&self.row().package, ctx.as_ref()).await {
+            Ok(url) => {
+                let package = self.row().to_friendly();
+                tracing::debug!(
+                    target: "hasp::output::working::remote_cache_uploaded",
+                    %package,
+                    %url,
+                    "Uploaded {package} to {url}",
+                )
+            }
+            Err(err) => {
+                let name = ctx.matcher.name();
+                let error = format!("{:#}", err);
+                tracing::warn!(
+                    target: "hasp::output::remote_cache_upload_failed",
+                    %name,
+                    %error,
+                    "Warning failed to upload {name} to the remote cache: {error}",
+                )
+            }
+        }
+    }
+
+    /// Hard-links the files in the new directory with identical files in other installs, if the
+    /// content store is enabled.
+    ///
+    /// Files that can't be linked are left as they are, so this never fails the install.
+    fn link_into_content_store(&self, files: &BTreeMap<String, ReceiptFile>) {
+        let hasp_home = self.lock.ctx.matcher.hasp_home();
+        if !hasp_home.config().install.content_store {
+            return;
+        }
+        let store = ContentStore::new(hasp_home.content_store_dir());
+        let mut linked = 0;
+        for (name, file) in files {
+            match store.link(&self.new_dir.join(name), &file.hash) {
+                Ok(true) => linked += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    let package = self.row().to_friendly();
+                    let error = format!("{:#}", err);
+                    tracing::warn!(
+                        target: "hasp::output::content_store_failed",
+                        %name,
+                        %package,
+                        %error,
+                        "Warning failed to add {name} from {package} to the content store: {error}",
+                    );
+                }
+            }
+        }
+        tracing::debug!(
+            "linked {} files of {} from the content store",
+            linked,
+            self.row().to_friendly()
+        );
+    }
+
+    /// Returns the metadata recorded for the install: the backend's metadata, along with the
+    /// checksum of the fetched artifact and how its signature was verified.
+    fn install_metadata(&self, temp_package: &TempInstalledPackage) -> serde_json::Value {
+        let mut metadata = temp_package.metadata.clone();
+        if let Some(object) = metadata.as_object_mut() {
+            if let Some(checksum) = self.lock.ctx.installer.artifact_checksum() {
+                object.insert(
+                    ARTIFACT_CHECKSUM_KEY.to_owned(),
+                    checksum.to_string().into(),
+                );
+            }
+            if let Some(signature) = &self.lock.ctx.signature {
+                object.insert(
+                    "signature".to_owned(),
+                    serde_json::to_value(signature)
+                        .expect("serializing a signature should never fail"),
+                );
+            }
+        }
+        metadata
+    }
+
+    /// Reads the dependencies the package was built with from its lockfile, if it has one.
+    ///
+    /// The dependencies are only informational, so failing to read them doesn't fail the install.
+    fn read_dependencies(&self, files: &BTreeMap<String, ReceiptFile>) -> Vec<LockedDependency> {
+        if !files.contains_key(CARGO_LOCK) {
+            return vec![];
+        }
+        read_cargo_lock(&self.new_dir.join(CARGO_LOCK), &self.row().package.name).unwrap_or_else(
+            |err| {
+                let package = self.row().to_friendly();
+                let error = format!("{:#}", err);
+                tracing::warn!(
+                    target: "hasp::output::dependencies_failed",
+                    %package,
+                    %error,
+                    "Warning failed to read dependencies for {package}: {error}",
+                );
+                vec![]
+            },
+        )
+    }
+
     #[inline]
     fn row(&self) -> &DirectoryRow {
         &self.lock.ctx.row
@@ -496,21 +1310,42 @@ impl<'inst> Drop for InstallGuard<'inst> {
 #[derive(Debug)]
 enum InstallError {
     Fail(Report),
+    /// A failure that might not happen again, like a network failure or the builder being
+    /// killed, so that the install can be attempted again.
+    Retryable(Report),
     Abort(Report),
 }
 
 impl InstallError {
-    fn log_and_rollback(&self, guard: &mut InstallGuard) {
-        // TODO: serialize the error properly
+    /// Returns the error for a failure to install the package, which may be retryable.
+    fn fail(err: Report) -> Self {
+        if FailureClass::is_retryable(&err) {
+            InstallError::Retryable(err)
+        } else {
+            InstallError::Fail(err)
+        }
+    }
+
+    fn log_and_rollback(&self, stage: FailureStage, guard: &mut InstallGuard) {
         let reason = match self {
-            InstallError::Fail(err) => {
-                let metadata = serde_json::to_value(format!("{}", err))
-                    .expect("serializing a string should never fail");
-                FailureReason::ProcessFailed { metadata }
+            InstallError::Fail(err) | InstallError::Abort(err)
+                if err.chain().any(|cause| cause.is::<Cancelled>()) =>
+            {
+                FailureReason::Cancelled { stage }
+            }
+            InstallError::Fail(err) | InstallError::Retryable(err) => {
+                match err.downcast_ref::<BinaryConflicts>() {
+                    Some(BinaryConflicts(conflicts)) => FailureReason::BinaryConflict {
+                        conflicts: conflicts.clone(),
+                    },
+                    None => FailureReason::ProcessFailed {
+                        details: failure_details(stage, err),
+                    },
+                }
             }
             InstallError::Abort(err) => {
-                let metadata = serde_json::to_value(format!("{}", err))
-                    .expect("serializing a string should never fail");
+                let metadata = serde_json::to_value(failure_details(stage, err))
+                    .expect("serializing failure details should never fail");
                 FailureReason::Aborted { metadata }
             }
         };
@@ -522,7 +1357,9 @@ impl InstallError {
 impl fmt::Display for InstallError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            InstallError::Fail(err) | InstallError::Abort(err) => write!(f, "{}", err),
+            InstallError::Fail(err) | InstallError::Retryable(err) | InstallError::Abort(err) => {
+                write!(f, "{}", err)
+            }
         }
     }
 }
@@ -530,7 +1367,19 @@ impl fmt::Display for InstallError {
 /// Metadata when an install transaction is dropped, likely due to a panic.
 ///
 /// This is returned as the metadata in [`InstallFailureReason::Aborted`].
-static TRANSACTION_DROPPED: &str = "transaction dropped, likely due to a panic or Ctrl-C";
+static TRANSACTION_DROPPED: &str = "transaction dropped, likely due to a panic";
+
+/// Returns the error for an install that failed in another process while this one waited for it.
+fn concurrent_install_failed(holder: &LockHolder) -> Report {
+    let process = match holder.pid {
+        Some(pid) => format!("process {}", pid),
+        None => "another process".to_owned(),
+    };
+    eyre!(
+        "install by {} failed while waiting for it (hint: run the command again to retry)",
+        process,
+    )
+}
 
 fn new_directory_hash(
     namespace: &'static str,
@@ -547,3 +1396,208 @@ fn new_directory_hash(
 
     DirectoryHash::new(hasher.finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::namespace::NamespaceRow,
+        ops::{PluginBackend, PluginMatcher},
+        output::OutputOpts,
+        test_helpers::{fake_plugin, TestHome},
+    };
+    use hasp_metadata::DirectoryVersionReq;
+    use semver::Version;
+
+    #[tokio::test]
+    async fn restore_interrupted_swap() {
+        let test_home = TestHome::new();
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        insert_fake_namespace(&test_home);
+        let conn = test_home.creator().create().expect("connection created");
+
+        let status = install(&test_home, fake_plugin(dir, "ok"), false, false).await;
+        assert!(
+            matches!(status, InstallStatus::Success { .. }),
+            "{:?}",
+            status
+        );
+
+        let installed = InstalledRow::all_installed_for("fake", "tool", &conn)
+            .expect("installs queried")
+            .pop()
+            .expect("tool is installed");
+        let install_path =
+            test_home
+                .hasp_home
+                .install_path("fake", "tool", installed.directory_row.package.hash);
+        let version = DirectoryVersion::new_semantic(Version::new(1, 2, 3));
+
+        // Interrupt a reinstall between moving the previous install aside and moving the new one
+        // into place, and after both.
+        for moved_in in [false, true] {
+            let work_dir = WorkDir::open(&matcher(&test_home, fake_plugin(dir, "ok")), &version)
+                .expect("work directory opened");
+            fs::create_dir_all(work_dir.path()).expect("work directory created");
+            work_dir
+                .record(InstallPhase::Swapping, serde_json::Value::Null)
+                .expect("swap recorded");
+            fs::rename(&install_path, work_dir.old_dir()).expect("previous install moved aside");
+            if moved_in {
+                fs::create_dir(&install_path).expect("new install created");
+                fs::write(install_path.join("tool"), "unrecorded").expect("binary written");
+            }
+            drop(work_dir);
+
+            // The fetcher can't resume, so the package is fetched again. The previous install is
+            // restored anyway, and kept when the reinstall fails.
+            let status = install(&test_home, fake_plugin(dir, "missing"), true, false).await;
+            assert!(
+                matches!(status, InstallStatus::Failure { .. }),
+                "{:?}",
+                status
+            );
+            assert_eq!(
+                fs::read_to_string(install_path.join("tool")).expect("binary read"),
+                "contents\n",
+                "previous install restored (moved in: {})",
+                moved_in
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_conflicts() {
+        let test_home = TestHome::new();
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        insert_fake_namespace(&test_home);
+        test_home.insert_install("other", "sem:1.0.0", 1, &["tool"]);
+        let expected: BTreeMap<_, _> = [("tool".to_owned(), vec!["other".to_owned()])].into();
+
+        // Without --force-bin-overwrite, the install fails with the conflict as its reason.
+        let status = install(&test_home, fake_plugin(dir, "ok"), false, false).await;
+        let report = match status {
+            InstallStatus::Failure {
+                report, retryable, ..
+            } => {
+                assert!(!retryable, "conflicts aren't retried");
+                report
+            }
+            other => panic!("install should fail, got {:?}", other),
+        };
+        assert_eq!(FailureClass::classify(&report), FailureClass::Conflict);
+        assert_eq!(
+            report
+                .downcast_ref::<BinaryConflicts>()
+                .expect("conflicts returned")
+                .0,
+            expected
+        );
+        let failed: Vec<InstallFailed> = events(&test_home, "install_failed");
+        assert!(
+            matches!(
+                &failed[..],
+                [InstallFailed {
+                    reason: FailureReason::BinaryConflict { conflicts },
+                    ..
+                }] if conflicts == &expected
+            ),
+            "{:?}",
+            failed
+        );
+
+        // With it, the install replaces the other package's binary.
+        let status = install(&test_home, fake_plugin(dir, "ok"), false, true).await;
+        assert!(
+            matches!(status, InstallStatus::Success { .. }),
+            "{:?}",
+            status
+        );
+        let conflicts: Vec<BinaryConflict> = events(&test_home, "binary_conflict");
+        assert_eq!(
+            conflicts
+                .iter()
+                .map(|conflict| (conflict.conflicts.clone(), conflict.overwritten))
+                .collect::<Vec<_>>(),
+            vec![(expected.clone(), false), (expected, true)]
+        );
+    }
+
+    fn insert_fake_namespace(test_home: &TestHome) {
+        let mut conn = test_home.creator().create().expect("connection created");
+        let txn = test_home
+            .creator()
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+        NamespaceRow {
+            namespace: "fake".to_owned(),
+        }
+        .insert(&txn)
+        .expect("namespace recorded");
+        txn.commit().expect("transaction committed");
+    }
+
+    /// Returns the events recorded with this name, oldest first.
+    fn events<T: serde::de::DeserializeOwned>(test_home: &TestHome, event_name: &str) -> Vec<T> {
+        assert!(test_home
+            .db_ctx
+            .event_logger
+            .flush(std::time::Duration::from_secs(10)));
+        let conn = test_home
+            .creator()
+            .create_events()
+            .expect("events connection created");
+        let mut stmt = conn
+            .prepare(
+                "SELECT data FROM journal WHERE event_name = ?1 \
+                ORDER BY julianday(event_time), event_time",
+            )
+            .expect("statement prepared");
+        let rows = stmt
+            .query_map([event_name], |row| row.get::<_, String>(0))
+            .expect("events queried");
+        rows.map(|data| {
+            serde_json::from_str(&data.expect("event read")).expect("event deserialized")
+        })
+        .collect()
+    }
+
+    fn matcher(test_home: &TestHome, backend: &'static PluginBackend) -> PackageMatcher {
+        PackageMatcher::new(
+            test_home.hasp_home.clone(),
+            Box::new(PluginMatcher::new(backend)),
+            "tool".to_owned(),
+            DirectoryVersionReq::from("^1".parse::<semver::VersionReq>().expect("valid req")),
+            None,
+            OutputOpts {
+                quiet: true,
+                verbose: 0,
+                color: None,
+                message_format: None,
+            },
+            test_home.db_ctx.clone(),
+        )
+    }
+
+    async fn install(
+        test_home: &TestHome,
+        backend: &'static PluginBackend,
+        force: bool,
+        force_bin_overwrite: bool,
+    ) -> InstallStatus {
+        let installer = matcher(test_home, backend)
+            .make_resolver()
+            .make_fetcher()
+            .await
+            .expect("version resolved")
+            .fetch()
+            .await
+            .expect("package fetched");
+        installer
+            .install(force, force_bin_overwrite)
+            .await
+            .expect("install finished")
+    }
+}
@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{
+    config::package_key,
     database::DbContext,
     home::HaspHome,
     models::directory::{DirectoryRow, InstalledRow},
@@ -10,13 +11,13 @@ use crate::{
 };
 use async_trait::async_trait;
 use color_eyre::{eyre::WrapErr, Result};
-use hasp_metadata::{DirectoryVersion, DirectoryVersionReq};
+use hasp_metadata::{Checksum, DirectoryVersion, DirectoryVersionReq};
 use rusqlite::Connection;
 use std::{fmt, sync::Arc};
 
 /// The initial state: a matcher and name has been provided, but a match still needs to
 /// be performed.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct PackageMatcher {
     inner: Arc<PackageMatcherInner>,
 }
@@ -28,6 +29,7 @@ struct PackageMatcherInner {
     namespace: &'static str,
     name: String,
     req: DirectoryVersionReq,
+    checksum: Option<Checksum>,
     output_opts: OutputOpts,
     db_ctx: DbContext,
 }
@@ -38,6 +40,7 @@ impl PackageMatcher {
         matcher: Box<dyn PackageMatcherImpl>,
         name: String,
         req: DirectoryVersionReq,
+        checksum: Option<Checksum>,
         output_opts: OutputOpts,
         db_ctx: DbContext,
     ) -> Self {
@@ -49,6 +52,7 @@ impl PackageMatcher {
                 namespace,
                 name,
                 req,
+                checksum,
                 output_opts,
                 db_ctx,
             }),
@@ -92,7 +96,17 @@ impl PackageMatcher {
             })
     }
 
-    pub(crate) fn best_installed_match(&self, conn: &Connection) -> Result<Option<InstalledRow>> {
+    /// Returns the best installed match, reading the database on a thread where blocking is
+    /// allowed.
+    pub(crate) async fn installed_match(&self) -> Result<Option<InstalledRow>> {
+        let matcher = self.clone();
+        self.db_ctx()
+            .creator
+            .run(move |conn| matcher.best_installed_match(conn))
+            .await
+    }
+
+    fn best_installed_match(&self, conn: &Connection) -> Result<Option<InstalledRow>> {
         let all_matches: Vec<_> =
             InstalledRow::all_matches_for(self.namespace(), self.name(), conn)?
                 .into_iter()
@@ -125,11 +139,22 @@ impl PackageMatcher {
         &self.inner.name
     }
 
+    /// Returns the package keyed as in the config file, e.g. `ripgrep` or `npm:prettier`.
+    pub(crate) fn key(&self) -> String {
+        package_key(self.namespace(), self.name())
+    }
+
     #[inline]
     pub(crate) fn req(&self) -> &DirectoryVersionReq {
         &self.inner.req
     }
 
+    /// Returns the checksum the fetched artifact is expected to have, if one was pinned.
+    #[inline]
+    pub(crate) fn expected_checksum(&self) -> Option<&Checksum> {
+        self.inner.checksum.as_ref()
+    }
+
     #[inline]
     pub(crate) fn output_opts(&self) -> OutputOpts {
         self.inner.output_opts
@@ -140,6 +165,11 @@ impl PackageMatcher {
         self.inner.matcher.metadata()
     }
 
+    #[inline]
+    pub(crate) fn source(&self) -> String {
+        self.inner.matcher.source(self.name())
+    }
+
     #[inline]
     pub(crate) fn db_ctx(&self) -> &DbContext {
         &self.inner.db_ctx
@@ -163,7 +193,7 @@ impl PackageMatcher {
 
 /// Represents a way to match a specific package.
 #[async_trait]
-pub(crate) trait PackageMatcherImpl: fmt::Debug {
+pub(crate) trait PackageMatcherImpl: fmt::Debug + Send + Sync {
     fn namespace(&self) -> &'static str;
 
     fn best_match(&self, all_matches: Vec<DirectoryRow>) -> Result<Option<DirectoryRow>>;
@@ -177,6 +207,10 @@ pub(crate) trait PackageMatcherImpl: fmt::Debug {
     /// Returns additional metadata to record and log as part of the package matcher.
     fn metadata(&self) -> serde_json::Value;
 
+    /// Returns the source the package is fetched from, e.g. `cargo:crates.io`, for checking
+    /// against trusted sources.
+    fn source(&self, name: &str) -> String;
+
     /// Creates a package resolver.
     fn make_resolver(&self) -> Box<dyn PackageResolverImpl>;
 }
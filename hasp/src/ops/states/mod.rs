@@ -1,13 +1,36 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+mod cleaner;
+mod content_store;
 mod fetcher;
+mod fsck;
 pub(self) mod helpers;
 mod installer;
 mod matcher;
+mod recovery;
+mod remote_cache;
 mod resolver;
+mod rollback;
+mod shared_store;
+mod uninstaller;
+mod verifier;
+mod work_dir;
 
+pub(crate) use cleaner::*;
+pub(crate) use content_store::*;
 pub(crate) use fetcher::*;
+pub(crate) use fsck::*;
+pub(crate) use helpers::{
+    checksum_file, find_lock_holders, hash_bytes, InstallReadLock, LockHolder, LockWait,
+    Utf8TempDir,
+};
 pub(crate) use installer::*;
 pub(crate) use matcher::*;
+pub(crate) use recovery::*;
+pub(crate) use remote_cache::RemoteCacheKey;
 pub(crate) use resolver::*;
+pub(crate) use rollback::*;
+pub(crate) use uninstaller::*;
+pub(crate) use verifier::*;
+pub(crate) use work_dir::*;
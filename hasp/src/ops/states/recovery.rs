@@ -0,0 +1,192 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    database::ConnectionCreator,
+    events::JournalEntry,
+    models::{directory::InstalledRow, trusted_source::TrustedSourceRow},
+};
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::{Event, PackageDirectory};
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+
+/// What the events journal says about installed packages and approved sources, for recovering
+/// what a corrupted database held beyond the receipts in install directories.
+///
+/// The journal may have been pruned, so this is only as complete as the events left in it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct JournalRecovery {
+    /// The package most recently installed for each namespace and name, unless it was uninstalled
+    /// afterwards.
+    installed: BTreeMap<(String, String), PackageDirectory>,
+    /// Approved sources, keyed by source.
+    trusted_sources: BTreeMap<String, TrustedSourceRow>,
+}
+
+impl JournalRecovery {
+    /// Replays the journal, oldest event first.
+    pub(crate) fn new(entries: &[JournalEntry]) -> Self {
+        let mut recovery = Self::default();
+        // Events that can't be parsed were damaged, or recorded by a newer version of hasp.
+        for envelope in entries.iter().filter_map(|entry| entry.event.as_ref().ok()) {
+            match &envelope.event {
+                Event::InstallSuccess(event) => {
+                    let package = &event.package;
+                    recovery.installed.insert(
+                        (package.namespace.clone(), package.name.clone()),
+                        package.clone(),
+                    );
+                }
+                Event::UninstallSuccess(event) => {
+                    let package = &event.package;
+                    recovery
+                        .installed
+                        .remove(&(package.namespace.clone(), package.name.clone()));
+                }
+                Event::SourceTrusted(event) => {
+                    // As when sources are approved, the earliest approval is kept.
+                    recovery
+                        .trusted_sources
+                        .entry(event.source.clone())
+                        .or_insert_with(|| TrustedSourceRow {
+                            source: event.source.clone(),
+                            approval: event.approval.clone(),
+                            approve_time: envelope.event_time,
+                        });
+                }
+                Event::SourceUntrusted(event) => {
+                    recovery.trusted_sources.remove(&event.source);
+                }
+                _ => {}
+            }
+        }
+        recovery
+    }
+
+    /// Records the approvals of sources that are missing from the database, returning the sources
+    /// that were restored.
+    pub(crate) fn restore_trusted_sources(
+        &self,
+        creator: &ConnectionCreator,
+    ) -> Result<Vec<String>> {
+        let mut conn = creator.create()?;
+        let txn = creator.write_transaction(&mut conn)?;
+        let mut restored = vec![];
+        for row in self.trusted_sources.values() {
+            if row.insert(&txn)? {
+                restored.push(row.source.clone());
+            }
+        }
+        txn.commit()
+            .wrap_err("failed to commit restored approvals of sources")?;
+        Ok(restored)
+    }
+
+    /// Returns the packages that are installed according to the journal, but have no installs
+    /// recorded in the database.
+    pub(crate) fn missing_installs(&self, conn: &Connection) -> Result<Vec<PackageDirectory>> {
+        let mut missing = vec![];
+        for ((namespace, name), package) in &self.installed {
+            if InstalledRow::all_installed_for(namespace, name, conn)?.is_empty() {
+                missing.push(package.clone());
+            }
+        }
+        Ok(missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TestHome;
+    use chrono::Local;
+    use hasp_metadata::{
+        DirectoryHash, EventEnvelope, InstallSuccess, SourceTrusted, SourceUntrusted,
+        UninstallSuccess, EVENT_SCHEMA_VERSION,
+    };
+
+    #[test]
+    fn recover_from_journal() {
+        let test_home = TestHome::new();
+        let creator = test_home.creator();
+
+        let package = |name: &str, version: &str| PackageDirectory {
+            namespace: "cargo".to_owned(),
+            name: name.to_owned(),
+            version: version.parse().expect("valid version"),
+            hash: DirectoryHash::new(0x01234567),
+            metadata: serde_json::json!({}),
+        };
+        let installed = |package| {
+            Event::InstallSuccess(InstallSuccess {
+                package,
+                force: false,
+                start_time: Local::now(),
+                end_time: Local::now(),
+                timings: None,
+            })
+        };
+        let trusted = |source: &str| {
+            Event::SourceTrusted(SourceTrusted {
+                source: source.to_owned(),
+                approval: "command".to_owned(),
+            })
+        };
+        let events = vec![
+            installed(package("foo", "sem:1.0.0")),
+            installed(package("foo", "sem:2.0.0")),
+            installed(package("bar", "sem:1.0.0")),
+            Event::UninstallSuccess(UninstallSuccess {
+                package: package("bar", "sem:1.0.0"),
+                end_time: Local::now(),
+            }),
+            trusted("cargo:crates.io"),
+            trusted("oci:ghcr.io"),
+            Event::SourceUntrusted(SourceUntrusted {
+                source: "oci:ghcr.io".to_owned(),
+            }),
+        ];
+        let entries: Vec<_> = events
+            .into_iter()
+            .enumerate()
+            .map(|(event_id, event)| JournalEntry {
+                event_id: event_id as i64,
+                event_name: event.name().to_owned(),
+                event: Ok(EventEnvelope {
+                    schema_version: EVENT_SCHEMA_VERSION,
+                    event_time: Local::now(),
+                    run_id: None,
+                    event,
+                }),
+            })
+            .collect();
+
+        let recovery = JournalRecovery::new(&entries);
+        let conn = creator.create().expect("connection created");
+        let missing = recovery
+            .missing_installs(&conn)
+            .expect("missing installs found");
+        assert_eq!(
+            missing
+                .iter()
+                .map(|package| (package.name.as_str(), package.version.to_string()))
+                .collect::<Vec<_>>(),
+            [("foo", package("foo", "sem:2.0.0").version.to_string())]
+        );
+
+        assert_eq!(
+            recovery
+                .restore_trusted_sources(creator)
+                .expect("sources restored"),
+            ["cargo:crates.io".to_owned()]
+        );
+        assert!(
+            recovery
+                .restore_trusted_sources(creator)
+                .expect("sources restored")
+                .is_empty(),
+            "sources already in the database aren't restored again"
+        );
+    }
+}
@@ -0,0 +1,421 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A cache of built packages on a server, shared between machines, configured in
+//! `install.remote-cache`.
+//!
+//! Each build is stored as a gzipped tarball of its install directory, receipt included, at
+//! `<url>/<namespace>/<name>/<version>/<hash>-<target>-<toolchain>.tar.gz`. The directory hash
+//! covers the features, profile and environment a package was built with, so a build is only
+//! reused where building it again would produce the same thing. Downloaded builds are checked
+//! against their receipts like installs in the shared store.
+//!
+//! Tarballs are held to the same `[signatures]` policy as fetched artifacts, with detached
+//! signatures published next to them, e.g. at `<tarball url>.minisig`. hasp doesn't sign the builds
+//! it uploads. A build is also only used if it was built from an artifact with the same checksum as
+//! the one fetched for the package, as recorded in its receipt.
+
+use crate::{
+    config::{RemoteCacheConfig, SignaturesConfig},
+    failure::PolicyViolation,
+    http_client,
+    network::RetryPolicy,
+    ops::{
+        states::{
+            shared_store::copy_install,
+            verifier::{verify_signature, SignedArtifact},
+        },
+        TempInstalledPackage,
+    },
+    platform::TARGET,
+};
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use hasp_metadata::{Checksum, PackageDirectory};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use std::{env, fs, fs::File, io};
+
+/// The key the checksum of the fetched artifact a package was built from is recorded under in its
+/// install metadata.
+pub(super) const ARTIFACT_CHECKSUM_KEY: &str = "artifact-checksum";
+
+/// What builds of a package depend on other than its directory hash and target, for packages
+/// whose builds can be shared through a remote cache.
+#[derive(Clone, Debug)]
+pub(crate) struct RemoteCacheKey {
+    /// The toolchain the package is built with, e.g. `rustc 1.56.0 (09c42c458 2021-10-18)`.
+    pub(crate) toolchain: String,
+    pub(crate) network: RetryPolicy,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct RemoteCache<'a> {
+    config: &'a RemoteCacheConfig,
+    key: RemoteCacheKey,
+}
+
+impl<'a> RemoteCache<'a> {
+    pub(super) fn new(config: &'a RemoteCacheConfig, key: RemoteCacheKey) -> Self {
+        Self { config, key }
+    }
+
+    /// Returns the URL a build of this package is stored at.
+    pub(super) fn url(&self, package: &PackageDirectory) -> String {
+        format!(
+            "{}/{}/{}/{}/{}-{}-{}.tar.gz",
+            self.config.url.trim_end_matches('/'),
+            package.namespace,
+            package.name,
+            package.version.short_display(),
+            package.hash,
+            TARGET,
+            toolchain_slug(&self.key.toolchain),
+        )
+    }
+
+    /// Downloads a build of this package and copies it into `dest`, if the cache has one. The
+    /// build is downloaded and unpacked into `staging`, an empty directory, first.
+    ///
+    /// Returns an error if the cache has a build that doesn't match its receipt, isn't signed as
+    /// `signatures` requires, or wasn't built from an artifact with this checksum.
+    pub(super) async fn fetch_into(
+        &self,
+        package: &PackageDirectory,
+        staging: &Utf8Path,
+        dest: &Utf8Path,
+        signatures: &SignaturesConfig,
+        artifact_checksum: Option<&Checksum>,
+    ) -> Result<Option<TempInstalledPackage>> {
+        let url = self.url(package);
+        let tarball = self
+            .key
+            .network
+            .run(&format!("download {}", url), || async {
+                let resp = self.request(Method::GET, &url)?.send().await?;
+                if resp.status() == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                Ok(Some(resp.error_for_status()?.bytes().await?))
+            })
+            .await?;
+        let tarball = match tarball {
+            Some(tarball) => tarball,
+            None => return Ok(None),
+        };
+        let tarball_path = staging.join("build.tar.gz");
+        fs::write(&tarball_path, tarball)
+            .wrap_err_with(|| format!("failed to write {}", tarball_path))?;
+        self.install_from(
+            &tarball_path,
+            url,
+            package,
+            dest,
+            signatures,
+            artifact_checksum,
+        )
+        .await
+    }
+
+    /// Verifies a downloaded build of this package and copies it into `dest`. The build is
+    /// unpacked next to the tarball first.
+    async fn install_from(
+        &self,
+        tarball_path: &Utf8Path,
+        url: String,
+        package: &PackageDirectory,
+        dest: &Utf8Path,
+        signatures: &SignaturesConfig,
+        artifact_checksum: Option<&Checksum>,
+    ) -> Result<Option<TempInstalledPackage>> {
+        let signature = verify_signature(
+            signatures,
+            &package.namespace,
+            &url,
+            Some(SignedArtifact::File {
+                path: tarball_path.to_owned(),
+                url: Some(url.clone()),
+                network: self.key.network.clone(),
+            }),
+        )
+        .await?;
+
+        let unpacked = tarball_path.with_file_name("build");
+        let tarball = File::open(tarball_path)
+            .wrap_err_with(|| format!("failed to open {}", tarball_path))?;
+        tar::Archive::new(GzDecoder::new(tarball))
+            .unpack(&unpacked)
+            .wrap_err_with(|| format!("failed to unpack {}", url))?;
+
+        let mut origin = serde_json::Map::new();
+        origin.insert("url".to_owned(), url.clone().into());
+        origin.insert("toolchain".to_owned(), self.key.toolchain.clone().into());
+        if let Some(signature) = signature {
+            origin.insert(
+                "signature".to_owned(),
+                serde_json::to_value(signature).expect("serializing a signature should never fail"),
+            );
+        }
+        let temp_package = match copy_install(&unpacked, package, dest, "remote-cache", origin)? {
+            Some(temp_package) => temp_package,
+            None => return Ok(None),
+        };
+
+        if let Some(expected) = artifact_checksum {
+            let built_from = temp_package.metadata.get(ARTIFACT_CHECKSUM_KEY);
+            if built_from.and_then(|checksum| checksum.as_str()) != Some(&expected.to_string()) {
+                return Err(PolicyViolation(format!(
+                    "the build at {} wasn't built from the fetched artifact: expected checksum \
+                     {}, found {}",
+                    url,
+                    expected,
+                    built_from.map_or_else(|| "none".to_owned(), |checksum| checksum.to_string()),
+                ))
+                .into());
+            }
+        }
+        Ok(Some(temp_package))
+    }
+
+    /// Uploads the install of a package, returning the URL it was uploaded to.
+    pub(super) async fn upload(
+        &self,
+        package: &PackageDirectory,
+        install_dir: &Utf8Path,
+    ) -> Result<String> {
+        let url = self.url(package);
+        let tarball =
+            archive(install_dir).wrap_err_with(|| format!("failed to archive {}", install_dir))?;
+        self.key
+            .network
+            .run(&format!("upload {}", url), || async {
+                self.request(Method::PUT, &url)?
+                    .body(tarball.clone())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(())
+            })
+            .await?;
+        Ok(url)
+    }
+
+    fn request(&self, method: Method, url: &str) -> Result<RequestBuilder> {
+        let mut request = http_client::client()?.request(method, url);
+        if let Some(token_env) = &self.config.token_env {
+            let token = env::var(token_env).wrap_err_with(|| {
+                format!(
+                    "failed to read the token for the remote cache from {} (hint: it's named in \
+                     install.remote-cache.token-env)",
+                    token_env
+                )
+            })?;
+            request = request.bearer_auth(token);
+        }
+        Ok(request)
+    }
+}
+
+/// Creates a gzipped tarball of the files in a directory.
+fn archive(dir: &Utf8Path) -> io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    builder.append_dir_all(".", dir)?;
+    builder.into_inner()?.finish()
+}
+
+/// Turns a toolchain version into something that can be part of a file name, e.g.
+/// `rustc-1.56.0-09c42c458-2021-10-18`.
+fn toolchain_slug(toolchain: &str) -> String {
+    toolchain
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        failure::FailureClass,
+        receipt::{InstallReceipt, ReceiptFile},
+    };
+    use chrono::Local;
+    use hasp_metadata::{DirectoryHash, FileHash, Sha256Hash};
+    use std::{collections::BTreeMap, time::Duration};
+
+    fn test_cache(config: &RemoteCacheConfig) -> RemoteCache<'_> {
+        let key = RemoteCacheKey {
+            toolchain: "rustc 1.56.0 (09c42c458 2021-10-18)".to_owned(),
+            network: RetryPolicy {
+                attempts: 1,
+                timeout: Duration::from_secs(1),
+                deadline: None,
+                initial_backoff: Duration::ZERO,
+            },
+        };
+        RemoteCache::new(config, key)
+    }
+
+    fn test_package() -> PackageDirectory {
+        PackageDirectory {
+            namespace: "cargo".to_owned(),
+            name: "foo".to_owned(),
+            version: "sem:1.0.0".parse().expect("valid version"),
+            hash: DirectoryHash::new(0x01234567),
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    /// Writes an install of `package` with a single binary, `foo`, into `install_dir`.
+    fn write_install(
+        install_dir: &Utf8Path,
+        package: &PackageDirectory,
+        metadata: serde_json::Value,
+    ) {
+        fs::create_dir_all(install_dir).expect("dir created");
+        fs::write(install_dir.join("foo"), b"binary").expect("file written");
+        let receipt = InstallReceipt {
+            hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+            package: package.clone(),
+            target: Some(TARGET.to_owned()),
+            metadata,
+            files: BTreeMap::from([(
+                "foo".to_owned(),
+                ReceiptFile {
+                    hash: FileHash::Blake3(blake3::hash(b"binary").into()),
+                    metadata: serde_json::Value::Null,
+                    is_binary: true,
+                },
+            )]),
+            dependencies: vec![],
+            start_time: Local::now(),
+            install_time: Local::now(),
+        };
+        receipt.write(install_dir).expect("receipt written");
+    }
+
+    #[test]
+    fn remote_cache_roundtrip() {
+        let config = RemoteCacheConfig {
+            url: "https://cache.example.com/hasp/".to_owned(),
+            upload: None,
+            token_env: None,
+        };
+        let cache = test_cache(&config);
+        let package = test_package();
+        assert_eq!(
+            cache.url(&package),
+            format!(
+                "https://cache.example.com/hasp/cargo/foo/1.0.0/{}-{}-rustc-1.56.0-09c42c458-2021-10-18.tar.gz",
+                package.hash, TARGET
+            )
+        );
+
+        // Builds are archived and unpacked again without any changes.
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let (install_dir, staging, dest) =
+            (dir.join("install"), dir.join("staging"), dir.join("dest"));
+        for path in [&staging, &dest] {
+            fs::create_dir_all(path).expect("dir created");
+        }
+        write_install(&install_dir, &package, serde_json::json!({}));
+
+        let tarball = archive(&install_dir).expect("install archived");
+        tar::Archive::new(GzDecoder::new(&tarball[..]))
+            .unpack(&staging)
+            .expect("archive unpacked");
+        let temp_package = copy_install(
+            &staging,
+            &package,
+            &dest,
+            "remote-cache",
+            Default::default(),
+        )
+        .expect("copy succeeded")
+        .expect("install found");
+        assert_eq!(
+            fs::read(dest.join("foo")).expect("file copied"),
+            b"binary".to_vec()
+        );
+        assert!(temp_package.installed_files["foo"].is_binary);
+        assert!(temp_package.metadata["remote-cache"]["install-time"].is_string());
+    }
+
+    #[tokio::test]
+    async fn remote_cache_verification() {
+        let config = RemoteCacheConfig {
+            url: "https://cache.example.com/hasp/".to_owned(),
+            upload: None,
+            token_env: None,
+        };
+        let cache = test_cache(&config);
+        let package = test_package();
+        let checksum = Checksum::Sha256(Sha256Hash::from_be_bytes([1; 32]));
+        let other_checksum = Checksum::Sha256(Sha256Hash::from_be_bytes([2; 32]));
+
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let install_dir = dir.join("install");
+        write_install(
+            &install_dir,
+            &package,
+            serde_json::json!({ ARTIFACT_CHECKSUM_KEY: checksum.to_string() }),
+        );
+        let tarball = archive(&install_dir).expect("install archived");
+        let install_from = |attempt: &str, signatures, artifact_checksum| {
+            let staging = dir.join(attempt);
+            let dest = staging.join("dest");
+            fs::create_dir_all(&dest).expect("dir created");
+            let tarball_path = staging.join("build.tar.gz");
+            fs::write(&tarball_path, &tarball).expect("tarball written");
+            let cache = cache.clone();
+            let package = package.clone();
+            async move {
+                cache
+                    .install_from(
+                        &tarball_path,
+                        cache.url(&package),
+                        &package,
+                        &dest,
+                        &signatures,
+                        artifact_checksum,
+                    )
+                    .await
+            }
+        };
+
+        let temp_package = install_from("matching", SignaturesConfig::default(), Some(&checksum))
+            .await
+            .expect("build from the same artifact used")
+            .expect("install found");
+        assert_eq!(
+            temp_package.metadata[ARTIFACT_CHECKSUM_KEY],
+            checksum.to_string()
+        );
+        let err = install_from(
+            "mismatched",
+            SignaturesConfig::default(),
+            Some(&other_checksum),
+        )
+        .await
+        .expect_err("build from another artifact rejected");
+        assert_eq!(FailureClass::classify(&err), FailureClass::Policy);
+        assert!(
+            err.to_string()
+                .contains("wasn't built from the fetched artifact"),
+            "{:#}",
+            err
+        );
+
+        let signatures = SignaturesConfig {
+            require: vec!["cargo".to_owned()],
+            ..SignaturesConfig::default()
+        };
+        let err = install_from("unsigned", signatures, Some(&checksum))
+            .await
+            .expect_err("unsigned build rejected");
+        assert_eq!(FailureClass::classify(&err), FailureClass::Policy);
+    }
+}
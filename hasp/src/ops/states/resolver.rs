@@ -2,14 +2,16 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{
-    ops::{PackageFetcher, PackageFetcherImpl, PackageMatcher},
+    failure::ResolveFailed,
+    hooks::{run_hooks, HookContext, HookPoint},
+    ops::{states::helpers::elapsed_ms, PackageFetcher, PackageFetcherImpl, PackageMatcher},
     output::OutputOpts,
 };
 use async_trait::async_trait;
 use color_eyre::{eyre::WrapErr, Result};
 use colored::Colorize;
 use hasp_metadata::DirectoryVersionReq;
-use std::fmt;
+use std::{fmt, time::Instant};
 
 /// Resolves a version requirement into a specific version.
 #[derive(Debug)]
@@ -25,6 +27,19 @@ impl PackageResolver {
 
     #[inline]
     pub(crate) async fn make_fetcher(self) -> Result<PackageFetcher> {
+        let start = Instant::now();
+        let hook_ctx = HookContext::new(
+            HookPoint::PreResolve,
+            self.matcher.namespace(),
+            self.matcher.name(),
+        )
+        .env("HASP_VERSION_REQ", self.matcher.req());
+        run_hooks(
+            self.matcher.hasp_home(),
+            &self.matcher.db_ctx().event_logger,
+            &hook_ctx,
+        )?;
+
         let fetcher = self
             .resolver
             .resolve(
@@ -34,27 +49,30 @@ impl PackageResolver {
             )
             .await
             .wrap_err_with(|| {
-                format!(
+                ResolveFailed(format!(
                     "failed to create fetcher for {}",
                     self.matcher.to_friendly()
-                )
+                ))
             })?;
 
+        let (name, req) = (self.matcher.name().blue(), self.matcher.req());
+        let version = fetcher.version();
+        let version = version.short_display();
         tracing::debug!(
             target: "hasp::output::resolved_version",
-            "Resolved {} @ {} to version {}",
-            self.matcher.name().blue(),
-            self.matcher.req(),
-            fetcher.version().short_display(),
+            %name,
+            %req,
+            %version,
+            "Resolved {name} @ {req} to version {version}",
         );
 
-        Ok(PackageFetcher::new(self.matcher, fetcher))
+        Ok(PackageFetcher::new(self.matcher, fetcher).with_resolve_ms(elapsed_ms(start)))
     }
 }
 
 /// Represents a way to match a specific package.
 #[async_trait]
-pub(crate) trait PackageResolverImpl: fmt::Debug {
+pub(crate) trait PackageResolverImpl: fmt::Debug + Send + Sync {
     /// Resolves this package into a specific version, and returns a fetcher.
     async fn resolve(
         &self,
@@ -0,0 +1,451 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    database::DbContext,
+    home::HaspHome,
+    models::{
+        directory::{DirectoryRow, InstalledRow},
+        previous_install::PreviousInstallRow,
+    },
+    ops::states::{
+        helpers::{rename_non_racy, UnlockedRoot},
+        work_dir::remove_dir_all,
+    },
+    output::NameVersionDisplay,
+    shims::{remove_shims, write_shims},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use hasp_metadata::DirectoryVersion;
+use std::fs;
+
+/// Keeps superseded installs of a package around, and swaps them back in.
+///
+/// When an install of a package overwrites the shims of another installed directory of it (e.g. an
+/// older version with the same binaries), that directory is moved into the previous installs area
+/// and marked as not installed. Directories installed side by side that don't share binaries are
+/// left alone. The most recently retired installs are kept, so that `hasp rollback` can restore
+/// them.
+#[derive(Debug)]
+pub(crate) struct PreviousInstalls {
+    hasp_home: HaspHome,
+    db_ctx: DbContext,
+    namespace: String,
+    name: String,
+}
+
+impl PreviousInstalls {
+    pub(crate) fn new(
+        hasp_home: HaspHome,
+        db_ctx: DbContext,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Self {
+        Self {
+            hasp_home,
+            db_ctx,
+            namespace: namespace.into(),
+            name: name.into(),
+        }
+    }
+
+    /// Retires `replaced`, the directory whose shims the most recent install of the package took
+    /// over, then removes all but the `keep` most recently retired installs.
+    pub(crate) fn retire_superseded(
+        &self,
+        replaced: Option<&DirectoryRow>,
+        keep: usize,
+    ) -> Result<()> {
+        let conn = self.db_ctx.creator.create()?;
+        let installed = InstalledRow::all_installed_for(&self.namespace, &self.name, &conn)?;
+        let current = match installed.last() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        // A previous install of the current directory is stale now that it's installed again.
+        for previous in PreviousInstallRow::all_for(&self.namespace, &self.name, &conn)? {
+            if previous.installed.directory_row.directory_id == current.directory_row.directory_id {
+                self.remove_previous(&previous)?;
+            }
+        }
+
+        let superseded = replaced.and_then(|replaced| {
+            installed
+                .iter()
+                .find(|row| row.directory_row.directory_id == replaced.directory_id)
+        });
+        if let Some(superseded) = superseded {
+            if superseded.directory_row.directory_id != current.directory_row.directory_id {
+                self.retire(superseded)?;
+            }
+        }
+
+        let previous = PreviousInstallRow::all_for(&self.namespace, &self.name, &conn)?;
+        for previous in previous.iter().skip(keep) {
+            self.remove_previous(previous)?;
+        }
+        Ok(())
+    }
+
+    /// Swaps the most recently superseded install back in, in place of the current install.
+    pub(crate) fn rollback(&self) -> Result<RolledBack> {
+        let conn = self.db_ctx.creator.create()?;
+        let current =
+            match InstalledRow::all_installed_for(&self.namespace, &self.name, &conn)?.pop() {
+                Some(current) => current,
+                None => bail!("{} is not installed", self.display_name()),
+            };
+        let previous = match PreviousInstallRow::all_for(&self.namespace, &self.name, &conn)?
+            .into_iter()
+            .next()
+        {
+            Some(previous) => previous,
+            None => bail!(
+                "no previous install of {} to roll back to (hint: set install.keep-previous in \
+                 the config to keep previous installs around)",
+                self.display_name(),
+            ),
+        };
+
+        if !previous.path.exists() {
+            bail!(
+                "previous install of {} is missing from {}",
+                previous.installed.directory_row.to_friendly(),
+                previous.path,
+            );
+        }
+
+        let current_path = self.install_path(&current);
+        let restored_path = self.install_path(&previous.installed);
+        let retired_path = self.previous_path(&current);
+
+        // Lock in a consistent order to avoid deadlocks with other processes.
+        let mut lock_paths = [&current_path, &restored_path];
+        lock_paths.sort();
+        let _locks = lock_paths
+            .iter()
+            .map(|path| UnlockedRoot::new(*path)?.lock_exclusive(self.hasp_home.lock_wait()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut conn = self.db_ctx.creator.create()?;
+        let txn = self.db_ctx.creator.write_transaction(&mut conn)?;
+
+        move_dir(&current_path, &retired_path)?;
+        if let Err(err) = move_dir(&previous.path, &restored_path) {
+            // Put the current install back.
+            let _ = move_dir(&retired_path, &current_path);
+            return Err(err);
+        }
+
+        current.directory_row.set_installed(&txn, false)?;
+        PreviousInstallRow::insert(&current, &retired_path, &txn)?;
+        previous.delete(&txn)?;
+        previous.installed.directory_row.set_installed(&txn, true)?;
+        previous.installed.copy_install(&txn)?;
+        if let Err(err) = txn.commit() {
+            let _ = move_dir(&restored_path, &previous.path);
+            let _ = move_dir(&retired_path, &current_path);
+            return Err(err)
+                .wrap_err_with(|| format!("failed to commit rollback of {}", self.display_name()));
+        }
+
+        let bin_dir = self.hasp_home.bin_dir();
+        remove_shims(bin_dir, &current_path, current.binary_names())?;
+        write_shims(bin_dir, &restored_path, previous.installed.binary_names())?;
+
+        Ok(RolledBack {
+            from: current.directory_row.package.version.clone(),
+            to: previous.installed.directory_row.package.version.clone(),
+            binaries: previous
+                .installed
+                .binary_names()
+                .map(|name| name.to_owned())
+                .collect(),
+        })
+    }
+
+    // ---
+    // Helper methods
+    // ---
+
+    /// Moves a superseded install into the previous installs area and marks it as not installed.
+    fn retire(&self, superseded: &InstalledRow) -> Result<()> {
+        let install_path = self.install_path(superseded);
+        let _lock = UnlockedRoot::new(&install_path)?.lock_exclusive(self.hasp_home.lock_wait())?;
+        let mut conn = self.db_ctx.creator.create()?;
+        let txn = self.db_ctx.creator.write_transaction(&mut conn)?;
+        // Another process may have reinstalled this directory while waiting for the lock.
+        let latest = InstalledRow::all_installed_for(&self.namespace, &self.name, &txn)?;
+        if latest.last().map(|row| row.directory_row.directory_id)
+            == Some(superseded.directory_row.directory_id)
+        {
+            return Ok(());
+        }
+
+        let previous_path = self.previous_path(superseded);
+        move_dir(&install_path, &previous_path)?;
+        superseded.directory_row.set_installed(&txn, false)?;
+        PreviousInstallRow::insert(superseded, &previous_path, &txn)?;
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "failed to commit retirement of {}",
+                superseded.directory_row.to_friendly()
+            )
+        })?;
+
+        // Shims for binaries that the current install also has were overwritten when it was
+        // installed. The rest still point at the superseded install.
+        remove_shims(
+            self.hasp_home.bin_dir(),
+            &install_path,
+            superseded.binary_names(),
+        )?;
+        let package =
+            NameVersionDisplay::dir_version(&self.name, &superseded.directory_row.package.version);
+        tracing::debug!(
+            target: "hasp::output::working::retired",
+            %package,
+            %previous_path,
+            "Moved superseded {package} to {previous_path}",
+        );
+        Ok(())
+    }
+
+    fn install_path(&self, installed: &InstalledRow) -> Utf8PathBuf {
+        let package = &installed.directory_row.package;
+        self.hasp_home
+            .install_path(&package.namespace, &package.name, package.hash)
+    }
+
+    fn previous_path(&self, installed: &InstalledRow) -> Utf8PathBuf {
+        let package = &installed.directory_row.package;
+        self.hasp_home
+            .previous_path(&package.namespace, &package.name, package.hash)
+    }
+
+    /// Removes a previous install, along with its rows in `packages.installed`.
+    pub(crate) fn remove_previous(&self, previous: &PreviousInstallRow) -> Result<()> {
+        let mut conn = self.db_ctx.creator.create()?;
+        let txn = self.db_ctx.creator.write_transaction(&mut conn)?;
+        previous.delete(&txn)?;
+        let directory_row = &previous.installed.directory_row;
+        // The directory may have been installed again, in which case its installs are current.
+        if !directory_row.get_installed(&txn)? {
+            directory_row.delete_installs(&txn)?;
+        }
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "failed to commit removal of previous install of {}",
+                directory_row.to_friendly()
+            )
+        })?;
+        remove_dir_all(&previous.path)
+    }
+
+    fn display_name(&self) -> String {
+        format!("{}:{}", self.namespace, self.name)
+    }
+}
+
+/// The result of rolling back a package.
+#[derive(Clone, Debug)]
+pub(crate) struct RolledBack {
+    /// The version that was installed before the rollback.
+    pub(crate) from: DirectoryVersion,
+    /// The version that's installed now.
+    pub(crate) to: DirectoryVersion,
+    /// The binaries in the restored install.
+    pub(crate) binaries: Vec<String>,
+}
+
+/// Moves a directory, creating the destination's parent if necessary.
+fn move_dir(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("failed to create directory at {}", parent))?;
+    }
+    // The destination may be an empty directory left behind by an earlier install.
+    remove_dir_all(dest)?;
+    rename_non_racy(src, dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::ScriptFlavor, shims::shim_path, test_helpers::TestHome};
+
+    #[test]
+    fn retire_replaced_install() {
+        let test_home = TestHome::new();
+        let side_by_side = install(&test_home, "sem:0.1.0", 0x01, &["foo-legacy"]);
+        let old = install(&test_home, "sem:1.0.0", 0x10, &["foo", "foo-old"]);
+        let new = install(&test_home, "sem:2.0.0", 0x20, &["foo"]);
+
+        previous_installs(&test_home)
+            .retire_superseded(Some(&old), 2)
+            .expect("superseded install retired");
+
+        // Only the replaced directory is retired: the one installed side by side is left alone.
+        assert_eq!(
+            installed_ids(&test_home),
+            vec![side_by_side.directory_id, new.directory_id]
+        );
+        let previous = previous_rows(&test_home);
+        assert_eq!(previous.len(), 1);
+        assert_eq!(
+            previous[0].installed.directory_row.directory_id,
+            old.directory_id
+        );
+        assert!(previous[0].path.join("foo-old").exists());
+        assert!(!install_path(&test_home, &old).exists());
+
+        // The shim the new install took over is kept, as is the side-by-side install's.
+        assert_eq!(
+            shim_target(&test_home, "foo"),
+            Some(install_path(&test_home, &new).join("foo"))
+        );
+        assert_eq!(
+            shim_target(&test_home, "foo-legacy"),
+            Some(install_path(&test_home, &side_by_side).join("foo-legacy"))
+        );
+        assert_eq!(shim_target(&test_home, "foo-old"), None);
+
+        // Nothing is retired without a replaced directory.
+        previous_installs(&test_home)
+            .retire_superseded(None, 2)
+            .expect("nothing retired");
+        assert_eq!(
+            installed_ids(&test_home),
+            vec![side_by_side.directory_id, new.directory_id]
+        );
+    }
+
+    #[test]
+    fn keep_previous_installs() {
+        let test_home = TestHome::new();
+        let previous_installs = previous_installs(&test_home);
+        let v1 = install(&test_home, "sem:1.0.0", 0x10, &["foo"]);
+        let v2 = install(&test_home, "sem:2.0.0", 0x20, &["foo"]);
+        previous_installs
+            .retire_superseded(Some(&v1), 1)
+            .expect("v1 retired");
+        let v1_previous = previous_rows(&test_home)[0].path.clone();
+        let v3 = install(&test_home, "sem:3.0.0", 0x30, &["foo"]);
+        previous_installs
+            .retire_superseded(Some(&v2), 1)
+            .expect("v2 retired");
+
+        assert_eq!(installed_ids(&test_home), vec![v3.directory_id]);
+        let previous = previous_rows(&test_home);
+        assert_eq!(
+            previous
+                .iter()
+                .map(|previous| previous.installed.directory_row.directory_id)
+                .collect::<Vec<_>>(),
+            vec![v2.directory_id],
+            "only the most recently retired install is kept"
+        );
+        assert!(!v1_previous.exists(), "v1 was removed from disk");
+        let conn = test_home.creator().create().expect("connection created");
+        assert!(!v1.has_installs(&conn).expect("installs queried"));
+    }
+
+    #[test]
+    fn rollback_restores_shims() {
+        let test_home = TestHome::new();
+        let previous_installs = previous_installs(&test_home);
+        let old = install(&test_home, "sem:1.0.0", 0x10, &["foo", "foo-old"]);
+        let new = install(&test_home, "sem:2.0.0", 0x20, &["foo", "foo-new"]);
+        previous_installs
+            .retire_superseded(Some(&old), 1)
+            .expect("old install retired");
+
+        let rolled_back = previous_installs.rollback().expect("rolled back");
+        assert_eq!(rolled_back.from, new.package.version);
+        assert_eq!(rolled_back.to, old.package.version);
+        assert_eq!(
+            rolled_back.binaries,
+            vec!["foo".to_owned(), "foo-old".to_owned()]
+        );
+
+        assert_eq!(installed_ids(&test_home), vec![old.directory_id]);
+        let old_path = install_path(&test_home, &old);
+        assert_eq!(shim_target(&test_home, "foo"), Some(old_path.join("foo")));
+        assert_eq!(
+            shim_target(&test_home, "foo-old"),
+            Some(old_path.join("foo-old"))
+        );
+        assert_eq!(shim_target(&test_home, "foo-new"), None);
+
+        // The install that was rolled back from can be rolled back to in turn.
+        let previous = previous_rows(&test_home);
+        assert_eq!(previous.len(), 1);
+        assert_eq!(
+            previous[0].installed.directory_row.directory_id,
+            new.directory_id
+        );
+        assert!(previous[0].path.join("foo-new").exists());
+    }
+
+    /// Records an install of foo with the given binaries, creating its install directory and
+    /// pointing shims at it.
+    fn install(test_home: &TestHome, version: &str, hash: u64, binaries: &[&str]) -> DirectoryRow {
+        let row = test_home.insert_install("foo", version, hash, binaries);
+        let install_path = install_path(test_home, &row);
+        fs::create_dir_all(&install_path).expect("install directory created");
+        for binary in binaries {
+            fs::write(install_path.join(binary), binary).expect("binary written");
+        }
+        fs::create_dir_all(test_home.hasp_home.bin_dir()).expect("bin directory created");
+        write_shims(
+            test_home.hasp_home.bin_dir(),
+            &install_path,
+            binaries.iter().copied(),
+        )
+        .expect("shims written");
+        row
+    }
+
+    fn previous_installs(test_home: &TestHome) -> PreviousInstalls {
+        PreviousInstalls::new(
+            test_home.hasp_home.clone(),
+            test_home.db_ctx.clone(),
+            "cargo",
+            "foo",
+        )
+    }
+
+    fn install_path(test_home: &TestHome, row: &DirectoryRow) -> Utf8PathBuf {
+        test_home
+            .hasp_home
+            .install_path("cargo", "foo", row.package.hash)
+    }
+
+    fn installed_ids(test_home: &TestHome) -> Vec<i64> {
+        let conn = test_home.creator().create().expect("connection created");
+        InstalledRow::all_installed_for("cargo", "foo", &conn)
+            .expect("installs queried")
+            .into_iter()
+            .map(|row| row.directory_row.directory_id)
+            .collect()
+    }
+
+    fn previous_rows(test_home: &TestHome) -> Vec<PreviousInstallRow> {
+        let conn = test_home.creator().create().expect("connection created");
+        PreviousInstallRow::all_for("cargo", "foo", &conn).expect("previous installs queried")
+    }
+
+    /// Returns the binary that the shim for `binary` runs, if there is a shim.
+    fn shim_target(test_home: &TestHome, binary: &str) -> Option<Utf8PathBuf> {
+        let contents = fs::read_to_string(shim_path(test_home.hasp_home.bin_dir(), binary)).ok()?;
+        let target = ScriptFlavor::CURRENT
+            .exec_script_program(&contents)
+            .expect("shim runs a binary");
+        Some(target)
+    }
+}
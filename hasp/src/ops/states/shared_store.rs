@@ -0,0 +1,228 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A shared, read-only store of installs that's consulted before building a package.
+//!
+//! The store is laid out like the installs directory of a hasp home, so that the installs
+//! directory of a build machine can be shared, e.g. over NFS, and used as is. An install is only
+//! copied in if its receipt is for the same directory hash and target, and every file matches the
+//! hash in the receipt.
+
+use crate::{
+    ops::{states::helpers::hash_file, TempInstalledFile, TempInstalledPackage},
+    platform::TARGET,
+    receipt::InstallReceipt,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use hasp_metadata::PackageDirectory;
+use std::{collections::BTreeMap, fs};
+
+#[derive(Clone, Debug)]
+pub(super) struct SharedStore<'a> {
+    root: &'a Utf8Path,
+}
+
+impl<'a> SharedStore<'a> {
+    pub(super) fn new(root: &'a Utf8Path) -> Self {
+        Self { root }
+    }
+
+    /// Returns the directory an install of this package would be at.
+    fn install_dir(&self, package: &PackageDirectory) -> Utf8PathBuf {
+        let mut install_dir = self.root.join(&package.namespace);
+        install_dir.push(&package.name);
+        install_dir.push(package.hash.to_string());
+        install_dir
+    }
+
+    /// Copies the install of a package into `dest`, if the store has a matching one.
+    ///
+    /// Returns an error if the store has an install that doesn't match its receipt.
+    pub(super) fn copy_into(
+        &self,
+        package: &PackageDirectory,
+        dest: &Utf8Path,
+    ) -> Result<Option<TempInstalledPackage>> {
+        let install_dir = self.install_dir(package);
+        let mut origin = serde_json::Map::new();
+        origin.insert("path".to_owned(), install_dir.as_str().into());
+        copy_install(&install_dir, package, dest, "shared-store", origin)
+    }
+}
+
+/// Copies an install laid out like those in a hasp home into `dest`, if there's one in
+/// `install_dir` for this package and target. Where it came from is recorded in its metadata under
+/// `origin_key`, along with when and by which version of hasp it was originally installed.
+///
+/// Returns an error if the install doesn't match its receipt.
+pub(super) fn copy_install(
+    install_dir: &Utf8Path,
+    package: &PackageDirectory,
+    dest: &Utf8Path,
+    origin_key: &str,
+    mut origin: serde_json::Map<String, serde_json::Value>,
+) -> Result<Option<TempInstalledPackage>> {
+    let receipt = match InstallReceipt::read(install_dir)? {
+        Some(receipt) => receipt,
+        None => return Ok(None),
+    };
+    let stored = &receipt.package;
+    if stored.namespace != package.namespace
+        || stored.name != package.name
+        || stored.hash != package.hash
+        || stored.version != package.version
+    {
+        bail!(
+            "receipt in {} is for a different package: {}:{} {} (hash {})",
+            install_dir,
+            stored.namespace,
+            stored.name,
+            stored.version,
+            stored.hash,
+        );
+    }
+    if receipt.target.as_deref() != Some(TARGET) {
+        let built_for = receipt.target.as_deref().unwrap_or("an unknown target");
+        tracing::debug!(
+            target: "hasp::output::working::shared_store_target",
+            %install_dir,
+            %built_for,
+            current = TARGET,
+            "Skipped install in {install_dir}: built for {built_for}, not {current}",
+            current = TARGET,
+        );
+        return Ok(None);
+    }
+
+    let mut installed_files = BTreeMap::new();
+    for (name, file) in &receipt.files {
+        // Installs can come from other machines, so don't trust names to stay within them.
+        if Utf8Path::new(name).file_name() != Some(name.as_str()) {
+            bail!(
+                "receipt in {} lists an invalid file name: {}",
+                install_dir,
+                name
+            );
+        }
+        let from = install_dir.join(name);
+        let to = dest.join(name);
+        fs::copy(&from, &to).wrap_err_with(|| format!("failed to copy {} to {}", from, to))?;
+        // Check the copy rather than the original, so that the original can't change in
+        // between.
+        if hash_file(&to, file.hash.algorithm())?.to_string() != file.hash.to_string() {
+            bail!("{} doesn't match the hash in its receipt", from);
+        }
+        installed_files.insert(
+            name.clone(),
+            TempInstalledFile {
+                temp_path: to,
+                metadata: file.metadata.clone(),
+                is_binary: file.is_binary,
+            },
+        );
+    }
+
+    // Record where the install came from, along with the metadata of the original build.
+    let mut metadata = match receipt.metadata {
+        serde_json::Value::Object(metadata) => metadata,
+        _ => serde_json::Map::new(),
+    };
+    origin.insert("hasp-version".to_owned(), receipt.hasp_version.into());
+    origin.insert(
+        "install-time".to_owned(),
+        serde_json::to_value(receipt.install_time)?,
+    );
+    metadata.insert(origin_key.to_owned(), origin.into());
+    Ok(Some(TempInstalledPackage {
+        installed_files,
+        metadata: metadata.into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receipt::ReceiptFile;
+    use chrono::Local;
+    use hasp_metadata::{DirectoryHash, FileHash};
+
+    #[test]
+    fn copy_into_basic() {
+        let root = tempfile::tempdir().expect("tempdir created");
+        let root = Utf8Path::from_path(root.path()).expect("tempdir is UTF-8");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).expect("dest created");
+        let store = SharedStore::new(root);
+
+        let package = PackageDirectory {
+            namespace: "cargo".to_owned(),
+            name: "foo".to_owned(),
+            version: "sem:1.0.0".parse().expect("valid version"),
+            hash: DirectoryHash::new(0x01234567),
+            metadata: serde_json::json!({}),
+        };
+        assert!(
+            store
+                .copy_into(&package, &dest)
+                .expect("copy succeeded")
+                .is_none(),
+            "store is empty"
+        );
+
+        let install_dir = store.install_dir(&package);
+        fs::create_dir_all(&install_dir).expect("install dir created");
+        fs::write(install_dir.join("foo"), b"binary").expect("file written");
+        let mut receipt = InstallReceipt {
+            hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+            package: package.clone(),
+            target: Some("wasm32-unknown-unknown".to_owned()),
+            metadata: serde_json::json!({ "env-hash": "0123456789abcdef" }),
+            files: BTreeMap::from([(
+                "foo".to_owned(),
+                ReceiptFile {
+                    hash: FileHash::Blake3(blake3::hash(b"binary").into()),
+                    metadata: serde_json::Value::Null,
+                    is_binary: true,
+                },
+            )]),
+            dependencies: vec![],
+            start_time: Local::now(),
+            install_time: Local::now(),
+        };
+        receipt.write(&install_dir).expect("receipt written");
+        assert!(
+            store
+                .copy_into(&package, &dest)
+                .expect("copy succeeded")
+                .is_none(),
+            "installs for other targets are skipped"
+        );
+
+        receipt.target = Some(TARGET.to_owned());
+        receipt.write(&install_dir).expect("receipt written");
+        let temp_package = store
+            .copy_into(&package, &dest)
+            .expect("copy succeeded")
+            .expect("install found");
+        assert_eq!(
+            fs::read(dest.join("foo")).expect("file copied"),
+            b"binary".to_vec()
+        );
+        assert!(temp_package.installed_files["foo"].is_binary);
+        assert_eq!(temp_package.metadata["env-hash"], "0123456789abcdef");
+        assert_eq!(
+            temp_package.metadata["shared-store"]["path"],
+            install_dir.as_str()
+        );
+
+        fs::write(install_dir.join("foo"), b"tampered").expect("file written");
+        assert!(
+            store.copy_into(&package, &dest).is_err(),
+            "files that don't match the receipt are rejected"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    database::DbContext,
+    home::HaspHome,
+    hooks::{run_hooks, HookContext, HookPoint},
+    models::directory::InstalledRow,
+    ops::states::helpers::{rename_non_racy, UnlockedRoot, Utf8TempDir},
+    output::NameVersionDisplay,
+    shims::remove_shims,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Local;
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::{LifecycleEvent, UninstallSuccess};
+
+/// Removes an installed package: its install directory, its shims and its rows in
+/// `packages.installed`.
+///
+/// The row in `packages.directories` is kept around, marked as not installed.
+#[derive(Debug)]
+pub(crate) struct PackageUninstaller {
+    hasp_home: HaspHome,
+    installed: InstalledRow,
+    install_path: Utf8PathBuf,
+    db_ctx: DbContext,
+}
+
+impl PackageUninstaller {
+    pub(crate) fn new(hasp_home: HaspHome, installed: InstalledRow, db_ctx: DbContext) -> Self {
+        let package = &installed.directory_row.package;
+        let install_path = hasp_home.install_path(&package.namespace, &package.name, package.hash);
+        Self {
+            hasp_home,
+            installed,
+            install_path,
+            db_ctx,
+        }
+    }
+
+    /// Uninstalls the package, returning the names of the binaries whose shims were removed.
+    pub(crate) fn uninstall(&self) -> Result<Vec<String>> {
+        let row = &self.installed.directory_row;
+        let _lock = UnlockedRoot::new(self)?.lock_exclusive(self.hasp_home.lock_wait())?;
+
+        let package = NameVersionDisplay::dir_version(&row.package.name, &row.package.version);
+        tracing::info!(
+            target: "hasp::output::working::uninstalling",
+            %package,
+            "Uninstalling {package}",
+        );
+
+        let mut conn = self.db_ctx.creator.create()?;
+        let txn = self.db_ctx.creator.write_transaction(&mut conn)?;
+        row.delete_installs(&txn)?;
+        row.set_installed(&txn, false)?;
+        txn.commit()
+            .wrap_err_with(|| format!("failed to commit uninstall of {}", row.to_friendly()))?;
+
+        let bin_dir = self.hasp_home.bin_dir();
+        let removed_shims =
+            remove_shims(bin_dir, &self.install_path, self.installed.binary_names())?;
+
+        // Move the install directory out of the way before removing it, so that a failure
+        // partway through doesn't leave a half-removed install behind.
+        let temp_dir = Utf8TempDir::new(self.hasp_home.cache_dir(), "uninstall-", "")?;
+        rename_non_racy(&self.install_path, &temp_dir.path().join("old"))?;
+
+        self.db_ctx
+            .event_logger
+            .log_lifecycle(LifecycleEvent::UninstallSuccess(UninstallSuccess {
+                package: row.package.clone(),
+                end_time: Local::now(),
+            }));
+
+        // The package is already gone, so failing hooks are only reported.
+        let hook_ctx = HookContext::new(
+            HookPoint::PostUninstall,
+            &row.package.namespace,
+            &row.package.name,
+        )
+        .env("HASP_VERSION", row.package.version.short_display());
+        let _ = run_hooks(&self.hasp_home, &self.db_ctx.event_logger, &hook_ctx);
+
+        Ok(removed_shims
+            .iter()
+            .filter_map(|shim| shim.file_stem().map(|stem| stem.to_owned()))
+            .collect())
+    }
+}
+
+impl AsRef<Utf8Path> for PackageUninstaller {
+    fn as_ref(&self) -> &Utf8Path {
+        &self.install_path
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Verifying detached signatures on fetched artifacts, configured in `[signatures]`.
+//!
+//! Signatures are checked with external tools: `minisign` for minisign keys, and `cosign` for
+//! sigstore keys. Files are verified against detached signatures published next to them, at
+//! `<url>.minisig` for minisign and `<url>.sig` for sigstore. Images are verified with
+//! `cosign verify`.
+//!
+//! A signature that doesn't match any configured key always fails the fetch. A missing signature
+//! only fails it if the package's namespace is listed in `signatures.require`.
+
+use crate::{
+    config::SignaturesConfig, failure::PolicyViolation, helpers::find_in_path, http_client,
+    network::RetryPolicy, ops::fetch_bytes,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs};
+
+/// A fetched artifact whose signature can be verified.
+#[derive(Clone, Debug)]
+pub(crate) enum SignedArtifact {
+    /// A downloaded file. Detached signatures are downloaded from next to `url`, unless they're
+    /// already next to `path`.
+    File {
+        path: Utf8PathBuf,
+        url: Option<String>,
+        network: RetryPolicy,
+    },
+    /// A container image, pinned by digest.
+    Image { reference: String },
+}
+
+/// How the signature on a package was verified, as recorded in its install metadata.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SignatureVerification {
+    pub(crate) method: SignatureMethod,
+    /// The public key the signature was verified with.
+    pub(crate) key: Utf8PathBuf,
+    pub(crate) verified_at: DateTime<Local>,
+}
+
+impl fmt::Display for SignatureVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "verified with {} key {} at {}",
+            self.method,
+            self.key,
+            self.verified_at.to_rfc3339()
+        )
+    }
+}
+
+/// A kind of signature hasp can verify.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SignatureMethod {
+    Minisign,
+    Sigstore,
+}
+
+impl SignatureMethod {
+    /// The tool signatures are verified with.
+    fn program(self) -> &'static str {
+        match self {
+            SignatureMethod::Minisign => "minisign",
+            SignatureMethod::Sigstore => "cosign",
+        }
+    }
+
+    /// The extension detached signatures have, appended to the name of the signed file.
+    fn extension(self) -> &'static str {
+        match self {
+            SignatureMethod::Minisign => "minisig",
+            SignatureMethod::Sigstore => "sig",
+        }
+    }
+
+    /// The config key the public keys for this method are listed under.
+    fn config_key(self) -> &'static str {
+        match self {
+            SignatureMethod::Minisign => "signatures.minisign-keys",
+            SignatureMethod::Sigstore => "signatures.cosign-keys",
+        }
+    }
+}
+
+impl fmt::Display for SignatureMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureMethod::Minisign => write!(f, "minisign"),
+            SignatureMethod::Sigstore => write!(f, "sigstore"),
+        }
+    }
+}
+
+/// Verifies the signature on the artifact fetched for a package, returning how it was verified,
+/// or `None` if it isn't signed and doesn't need to be.
+pub(crate) async fn verify_signature(
+    config: &SignaturesConfig,
+    namespace: &str,
+    package: &str,
+    artifact: Option<SignedArtifact>,
+) -> Result<Option<SignatureVerification>> {
+    let required = config.is_required(namespace);
+    if !required && !config.has_keys() {
+        return Ok(None);
+    }
+
+    let verification = match &artifact {
+        Some(SignedArtifact::File { path, url, network }) => {
+            verify_file(config, path, url.as_deref(), network).await?
+        }
+        Some(SignedArtifact::Image { reference }) => verify_image(config, reference)?,
+        None => None,
+    };
+    match verification {
+        Some(verification) => {
+            tracing::debug!(
+                target: "hasp::output::working::verified_signature",
+                method = %verification.method,
+                %package,
+                key = %verification.key,
+                "Verified {method} signature for {package} with {key}",
+                method = verification.method,
+                key = verification.key,
+            );
+            Ok(Some(verification))
+        }
+        None if required && artifact.is_none() => Err(PolicyViolation(format!(
+            "signatures are required for {} packages, but {} doesn't have an artifact to verify",
+            namespace, package,
+        ))
+        .into()),
+        None if required => Err(PolicyViolation(format!(
+            "signatures are required for {} packages, but no valid signature was found for {} \
+             (hint: keys are listed in signatures.minisign-keys and signatures.cosign-keys)",
+            namespace, package,
+        ))
+        .into()),
+        None => Ok(None),
+    }
+}
+
+/// Verifies a file against its detached signatures, trying minisign before sigstore.
+async fn verify_file(
+    config: &SignaturesConfig,
+    path: &Utf8Path,
+    url: Option<&str>,
+    network: &RetryPolicy,
+) -> Result<Option<SignatureVerification>> {
+    for method in [SignatureMethod::Minisign, SignatureMethod::Sigstore] {
+        let keys = config.keys(method);
+        if keys.is_empty() {
+            continue;
+        }
+
+        let signature_path = Utf8PathBuf::from(format!("{}.{}", path, method.extension()));
+        if !signature_path.exists() {
+            let signature_url = match url {
+                Some(url) => format!("{}.{}", url, method.extension()),
+                None => continue,
+            };
+            let client = http_client::client()?;
+            let signature = network
+                .run(&format!("download {}", signature_url), || {
+                    fetch_bytes(client, &signature_url)
+                })
+                .await
+                .wrap_err_with(|| format!("failed to download {}", signature_url))?;
+            match signature {
+                Some(signature) => fs::write(&signature_path, signature)
+                    .wrap_err_with(|| format!("failed to write {}", signature_path))?,
+                None => continue,
+            }
+        }
+
+        let program = verifier_path(method)?;
+        for key in keys {
+            let args = match method {
+                SignatureMethod::Minisign => vec![
+                    "-V",
+                    "-q",
+                    "-p",
+                    key.as_str(),
+                    "-m",
+                    path.as_str(),
+                    "-x",
+                    signature_path.as_str(),
+                ],
+                SignatureMethod::Sigstore => vec![
+                    "verify-blob",
+                    "--key",
+                    key.as_str(),
+                    "--signature",
+                    signature_path.as_str(),
+                    path.as_str(),
+                ],
+            };
+            if run_verifier(&program, &args)? {
+                return Ok(Some(SignatureVerification {
+                    method,
+                    key: key.clone(),
+                    verified_at: Local::now(),
+                }));
+            }
+        }
+        return Err(PolicyViolation(format!(
+            "the {} signature at {} doesn't match any key in {}",
+            method,
+            signature_path,
+            method.config_key(),
+        ))
+        .into());
+    }
+    Ok(None)
+}
+
+/// Verifies an image with `cosign verify`.
+///
+/// cosign doesn't distinguish images that aren't signed from images signed with other keys, so a
+/// failure is treated as a missing signature.
+fn verify_image(
+    config: &SignaturesConfig,
+    reference: &str,
+) -> Result<Option<SignatureVerification>> {
+    let method = SignatureMethod::Sigstore;
+    let keys = config.keys(method);
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    let program = verifier_path(method)?;
+    for key in keys {
+        if run_verifier(&program, &["verify", "--key", key.as_str(), reference])? {
+            return Ok(Some(SignatureVerification {
+                method,
+                key: key.clone(),
+                verified_at: Local::now(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+fn verifier_path(method: SignatureMethod) -> Result<Utf8PathBuf> {
+    find_in_path(method.program()).ok_or_else(|| {
+        eyre!(
+            "{} is needed to verify {} signatures, but wasn't found on PATH (hint: install it, or \
+             remove the keys in {})",
+            method.program(),
+            method,
+            method.config_key(),
+        )
+    })
+}
+
+/// Runs a verifier, returning true if the signature is valid.
+fn run_verifier(program: &Utf8Path, args: &[&str]) -> Result<bool> {
+    let output = duct::cmd(program.as_std_path(), args)
+        .stdin_null()
+        .stdout_null()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .wrap_err_with(|| format!("failed to run {}", program))?;
+    if !output.status.success() {
+        tracing::debug!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim(),
+        );
+    }
+    Ok(output.status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failure::FailureClass;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn verify_signature_policy() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let path = dir.join("foo-1.0.0.crate");
+        fs::write(&path, b"crate").expect("artifact written");
+        let artifact = || SignedArtifact::File {
+            path: path.clone(),
+            url: None,
+            network: RetryPolicy {
+                attempts: 1,
+                timeout: Duration::from_secs(1),
+                deadline: None,
+                initial_backoff: Duration::ZERO,
+            },
+        };
+
+        let mut config = SignaturesConfig::default();
+        assert_eq!(
+            verify_signature(&config, "cargo", "foo", Some(artifact()))
+                .await
+                .expect("nothing to verify"),
+            None
+        );
+
+        // Unsigned artifacts are fine unless signatures are required.
+        config.minisign_keys = vec![dir.join("minisign.pub")];
+        assert_eq!(
+            verify_signature(&config, "cargo", "foo", Some(artifact()))
+                .await
+                .expect("unsigned artifact allowed"),
+            None
+        );
+        config.require = vec!["cargo".to_owned()];
+        let err = verify_signature(&config, "cargo", "foo", Some(artifact()))
+            .await
+            .expect_err("unsigned artifact rejected");
+        assert_eq!(FailureClass::classify(&err), FailureClass::Policy);
+        let err = verify_signature(&config, "cargo", "foo", None)
+            .await
+            .expect_err("package without an artifact rejected");
+        assert!(
+            err.to_string().contains("doesn't have an artifact"),
+            "unexpected error: {}",
+            err
+        );
+    }
+}
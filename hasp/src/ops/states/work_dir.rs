@@ -0,0 +1,294 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{
+    database::ConnectionCreator,
+    models::install_progress::{InstallPhase, InstallProgressRow},
+    ops::{
+        states::helpers::{ExclusiveRoot, UnlockedRoot},
+        PackageMatcher, TempInstalledFile, TempInstalledPackage,
+    },
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::DirectoryVersion;
+use rusqlite::Transaction;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, hash::Hasher, io};
+use twox_hash::XxHash64;
+
+/// The working directory for installing a specific version of a package.
+///
+/// Unlike a temporary directory, this is kept around if an install fails or is interrupted, and
+/// its progress is recorded in the database. The next attempt to install the same version
+/// resumes from the last completed phase, reusing what was already fetched or built.
+///
+/// An exclusive lock is held on the directory, so only one process works in it at a time.
+#[derive(Debug)]
+pub(crate) struct WorkDir {
+    lock: ExclusiveRoot<Utf8PathBuf>,
+    creator: ConnectionCreator,
+    namespace: &'static str,
+    name: String,
+    version: DirectoryVersion,
+    resume_from: Option<(InstallPhase, serde_json::Value)>,
+    /// Whether an earlier attempt was interrupted while moving its install into place. This
+    /// outlives `resume_from`, since the previous install has to be restored even if nothing else
+    /// can be resumed.
+    interrupted_swap: bool,
+}
+
+impl WorkDir {
+    /// Opens the working directory for this version, waiting for any other process using it.
+    pub(crate) fn open(matcher: &PackageMatcher, version: &DirectoryVersion) -> Result<Self> {
+        // Installs of the same version with different metadata (e.g. features) get their own
+        // directories.
+        let mut hasher = XxHash64::default();
+        for part in [
+            matcher.namespace(),
+            matcher.name(),
+            &version.to_string(),
+            &matcher.metadata().to_string(),
+        ] {
+            hasher.write_u64(part.len() as u64);
+            hasher.write(part.as_bytes());
+        }
+        let path = matcher
+            .hasp_home()
+            .cache_dir()
+            .join(format!("install-{:016x}", hasher.finish()));
+        let lock = UnlockedRoot::new(path)?.lock_exclusive(matcher.hasp_home().lock_wait())?;
+
+        let creator = matcher.db_ctx().creator.clone();
+        let conn = creator.create()?;
+        let resume_from = match InstallProgressRow::get(lock.ctx.as_str(), &conn)? {
+            // A finalized install only has cleanup left, so there's nothing to resume.
+            Some(row) if row.phase < InstallPhase::Finalized && lock.ctx.exists() => {
+                Some((row.phase, row.data))
+            }
+            _ => None,
+        };
+        let interrupted_swap = matches!(resume_from, Some((InstallPhase::Swapping, _)));
+
+        Ok(Self {
+            lock,
+            creator,
+            namespace: matcher.namespace(),
+            name: matcher.name().to_owned(),
+            version: version.clone(),
+            resume_from,
+            interrupted_swap,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Utf8Path {
+        &self.lock.ctx
+    }
+
+    /// Returns the directory the previous install is moved aside to, while the new one is moved
+    /// into place.
+    pub(crate) fn old_dir(&self) -> Utf8PathBuf {
+        self.path().join("install-old")
+    }
+
+    /// Returns the phase completed by an earlier attempt, if it can be resumed from.
+    pub(crate) fn resume_phase(&self) -> Option<InstallPhase> {
+        self.resume_from.as_ref().map(|(phase, _)| *phase)
+    }
+
+    /// Returns true if an earlier attempt was interrupted while moving its install into place,
+    /// before it was recorded in the database.
+    pub(crate) fn interrupted_swap(&self) -> bool {
+        self.interrupted_swap
+    }
+
+    /// Returns the files built by an earlier attempt, if they're all still in `new_dir`.
+    pub(crate) fn resume_build(&self, new_dir: &Utf8Path) -> Option<TempInstalledPackage> {
+        let data = match &self.resume_from {
+            Some((InstallPhase::Built, data)) => data,
+            _ => return None,
+        };
+        let built: BuiltPackage = serde_json::from_value(data.clone()).ok()?;
+        built.into_temp(new_dir)
+    }
+
+    /// Clears out the directory to start a new attempt from scratch.
+    ///
+    /// After an interrupted swap, the previous install that was moved aside is kept, along with
+    /// the record of the swap, so that the installer can still put it back.
+    pub(crate) fn restart(&mut self) -> Result<()> {
+        self.resume_from = None;
+        if self.interrupted_swap {
+            let old_dir = self.old_dir();
+            let entries = fs::read_dir(self.path())
+                .wrap_err_with(|| format!("failed to read directory {}", self.path()))?;
+            for entry in entries {
+                let path = entry
+                    .wrap_err_with(|| format!("failed to read directory {}", self.path()))?
+                    .path();
+                if path == old_dir.as_std_path() {
+                    continue;
+                }
+                let res = if path.is_dir() {
+                    fs::remove_dir_all(&path)
+                } else {
+                    fs::remove_file(&path)
+                };
+                res.wrap_err_with(|| format!("failed to remove {}", path.display()))?;
+            }
+            return Ok(());
+        }
+        remove_dir_all(self.path())?;
+        fs::create_dir_all(self.path())
+            .wrap_err_with(|| format!("failed to create directory at {}", self.path()))?;
+        self.record(InstallPhase::Resolved, serde_json::Value::Null)
+    }
+
+    /// Records that a phase was completed, along with the data needed to resume from it.
+    pub(crate) fn record(&self, phase: InstallPhase, data: serde_json::Value) -> Result<()> {
+        let mut conn = self.creator.create()?;
+        let txn = self.creator.write_transaction(&mut conn)?;
+        self.record_in(phase, data, &txn)?;
+        txn.commit()
+            .wrap_err_with(|| format!("failed to commit {} phase for {}", phase, self.path()))
+    }
+
+    /// Records that a phase was completed, as part of a larger transaction.
+    pub(crate) fn record_in(
+        &self,
+        phase: InstallPhase,
+        data: serde_json::Value,
+        txn: &Transaction,
+    ) -> Result<()> {
+        InstallProgressRow {
+            work_dir: self.path().to_path_buf(),
+            namespace: self.namespace.to_owned(),
+            name: self.name.clone(),
+            version: self.version.clone(),
+            phase,
+            data,
+        }
+        .upsert(txn)
+    }
+
+    /// Removes the directory and its recorded progress, once there's nothing left to resume.
+    pub(crate) fn remove(&self) -> Result<()> {
+        let mut conn = self.creator.create()?;
+        let txn = self.creator.write_transaction(&mut conn)?;
+        InstallProgressRow::delete(self.path().as_str(), &txn)?;
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "failed to commit removal of install progress for {}",
+                self.path()
+            )
+        })?;
+        remove_dir_all(self.path())
+    }
+}
+
+/// The files produced by building a package, recorded so that the build can be reused.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuiltPackage {
+    files: BTreeMap<String, BuiltFile>,
+    metadata: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct BuiltFile {
+    metadata: serde_json::Value,
+    is_binary: bool,
+}
+
+impl BuiltPackage {
+    pub(crate) fn new(temp_package: &TempInstalledPackage) -> Self {
+        let files = temp_package
+            .installed_files
+            .iter()
+            .map(|(name, file)| {
+                let built_file = BuiltFile {
+                    metadata: file.metadata.clone(),
+                    is_binary: file.is_binary,
+                };
+                (name.clone(), built_file)
+            })
+            .collect();
+        Self {
+            files,
+            metadata: temp_package.metadata.clone(),
+        }
+    }
+
+    /// Converts this back into a temporary package with its files in `dir`, or returns `None` if
+    /// any of the files are missing.
+    fn into_temp(self, dir: &Utf8Path) -> Option<TempInstalledPackage> {
+        let installed_files = self
+            .files
+            .into_iter()
+            .map(|(name, file)| {
+                let temp_path = dir.join(&name);
+                temp_path.exists().then_some((
+                    name,
+                    TempInstalledFile {
+                        temp_path,
+                        metadata: file.metadata,
+                        is_binary: file.is_binary,
+                    },
+                ))
+            })
+            .collect::<Option<_>>()?;
+        Some(TempInstalledPackage {
+            installed_files,
+            metadata: self.metadata,
+        })
+    }
+}
+
+/// Removes a directory and its contents, ignoring it not existing.
+pub(super) fn remove_dir_all(dir: &Utf8Path) -> Result<()> {
+    match fs::remove_dir_all(dir) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).wrap_err_with(|| format!("failed to remove directory {}", dir)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_package_roundtrip() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        fs::write(dir.join("foo"), "binary").expect("file written");
+
+        let mut installed_files = BTreeMap::new();
+        installed_files.insert(
+            "foo".to_owned(),
+            TempInstalledFile {
+                temp_path: dir.join("foo"),
+                metadata: serde_json::Value::Null,
+                is_binary: true,
+            },
+        );
+        let temp_package = TempInstalledPackage {
+            installed_files,
+            metadata: serde_json::json!({ "env-hash": "0123456789abcdef" }),
+        };
+
+        let data = serde_json::to_value(BuiltPackage::new(&temp_package)).expect("serialized");
+        let built: BuiltPackage = serde_json::from_value(data).expect("deserialized");
+        let resumed = built.clone().into_temp(dir).expect("all files exist");
+        assert_eq!(resumed.metadata, temp_package.metadata);
+        assert_eq!(resumed.installed_files["foo"].temp_path, dir.join("foo"));
+        assert!(resumed.installed_files["foo"].is_binary);
+
+        fs::remove_file(dir.join("foo")).expect("file removed");
+        assert!(
+            built.into_temp(dir).is_none(),
+            "missing files aren't resumed"
+        );
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Message catalogs, used to localize output.
+//!
+//! Output events are identified by their target without the `hasp::output::` prefix, e.g.
+//! `working::installing`, and record the values they print as fields. The output formatters look
+//! the ID up in the catalog for the current locale and fill in the fields, falling back to the
+//! message in the event if the catalog doesn't have it.
+//!
+//! The English catalog is generated from the messages of output events by the build script, so
+//! that each message is only written once, and can be printed with `hasp --print messages` to
+//! start a translation from. Every message must only refer to fields of its event by name, so the
+//! build fails on messages with positional arguments like `{}`.
+//!
+//! Only English is built into hasp. Other catalogs are read from `<locale>.toml` in the directory
+//! set through `HASP_LOCALE_DIR`, either at runtime or when building hasp, so that distributions
+//! can ship their own translations. The locale is taken from `HASP_LOCALE`, then the usual
+//! `LC_ALL`, `LC_MESSAGES` and `LANG` variables.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, fs, io};
+
+static HASP_LOCALE_ENV: &str = "HASP_LOCALE";
+static HASP_LOCALE_DIR_ENV: &str = "HASP_LOCALE_DIR";
+
+/// The English catalog, which every other catalog falls back to.
+pub(crate) static BUILTIN_MESSAGES: &str = include_str!(concat!(env!("OUT_DIR"), "/messages.toml"));
+
+/// The prefix of output targets, which message IDs don't include.
+static OUTPUT_PREFIX: &str = "hasp::output::";
+
+static CATALOG: OnceCell<MessageCatalog> = OnceCell::new();
+
+/// Loads the catalog for the current locale, which is used from then on.
+///
+/// A catalog that can't be read is reported, and English is used instead.
+pub(super) fn init() {
+    let locale = locale_from_env();
+    let catalog = MessageCatalog::load(&locale, locale_dir().as_deref()).unwrap_or_else(|err| {
+        let error = format!("{:#}", err);
+        tracing::warn!(
+            target: "hasp::output::locale_failed",
+            %locale,
+            %error,
+            "Warning failed to load messages for locale {locale}, using en instead: {error}",
+        );
+        MessageCatalog::builtin()
+    });
+    tracing::debug!("using messages for locale {}", catalog.locale());
+    let _ = CATALOG.set(catalog);
+}
+
+/// Renders the message for an output event from the current catalog, if it has one.
+pub(super) fn render(target: &str, args: &BTreeMap<String, String>) -> Option<String> {
+    let id = target.strip_prefix(OUTPUT_PREFIX)?;
+    CATALOG.get()?.render(id, args)
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CatalogFile {
+    #[serde(default)]
+    messages: BTreeMap<String, String>,
+}
+
+/// The messages for a locale, keyed by message ID.
+#[derive(Clone, Debug)]
+pub(crate) struct MessageCatalog {
+    locale: String,
+    messages: BTreeMap<String, String>,
+}
+
+impl MessageCatalog {
+    /// Returns the English catalog built into hasp.
+    pub(crate) fn builtin() -> Self {
+        let file: CatalogFile =
+            toml::from_str(BUILTIN_MESSAGES).expect("built-in messages are valid");
+        Self {
+            locale: "en".to_owned(),
+            messages: file.messages,
+        }
+    }
+
+    /// Loads the catalog for a locale such as `de_DE`, from `<dir>/de_DE.toml` or `<dir>/de.toml`.
+    ///
+    /// Messages missing from the catalog are taken from the more general catalog, and then from
+    /// English. A locale without any catalog uses English.
+    pub(crate) fn load(locale: &str, dir: Option<&Utf8Path>) -> Result<Self> {
+        let mut catalog = Self::builtin();
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return Ok(catalog),
+        };
+        let language = locale.split('_').next().unwrap_or(locale);
+        let mut names = vec![language];
+        if language != locale {
+            names.push(locale);
+        }
+        for name in names {
+            if let Some(file) = read_catalog(&dir.join(format!("{}.toml", name)))? {
+                catalog.locale = name.to_owned();
+                catalog.messages.extend(file.messages);
+            }
+        }
+        Ok(catalog)
+    }
+
+    /// Returns the locale that was loaded, which is less specific than the requested one if only
+    /// a catalog for the language was found.
+    pub(crate) fn locale(&self) -> &str {
+        &self.locale
+    }
+
+    /// Renders the message with this ID, or returns `None` if there's no message or it refers to
+    /// a field that isn't in `args`.
+    pub(crate) fn render(&self, id: &str, args: &BTreeMap<String, String>) -> Option<String> {
+        render_template(self.messages.get(id)?, args)
+    }
+}
+
+fn read_catalog(path: &Utf8Path) -> Result<Option<CatalogFile>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).wrap_err_with(|| format!("failed to read {}", path)),
+    };
+    let file = toml::from_str(&contents).wrap_err_with(|| format!("failed to parse {}", path))?;
+    Ok(Some(file))
+}
+
+/// Fills in `{name}` placeholders in a template. `{{` and `}}` are literal braces.
+fn render_template(template: &str, args: &BTreeMap<String, String>) -> Option<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                rendered.push('{');
+            }
+            '{' => {
+                let (name, rest) = chars.as_str().split_once('}')?;
+                rendered.push_str(args.get(name)?);
+                chars = rest.chars();
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                rendered.push('}');
+            }
+            c => rendered.push(c),
+        }
+    }
+    Some(rendered)
+}
+
+/// Returns the locale from the environment, e.g. `de_DE` for `LANG=de_DE.UTF-8`.
+fn locale_from_env() -> String {
+    let locale = [HASP_LOCALE_ENV, "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+        .unwrap_or_default();
+    // Strip the encoding and modifier, as in de_DE.UTF-8@euro.
+    let locale = locale.split(['.', '@']).next().unwrap_or_default();
+    match locale {
+        "" | "C" | "POSIX" => "en".to_owned(),
+        locale => locale.to_owned(),
+    }
+}
+
+/// Returns the directory catalogs are read from: `HASP_LOCALE_DIR` if set, then the value it had
+/// when hasp was built.
+fn locale_dir() -> Option<Utf8PathBuf> {
+    env::var(HASP_LOCALE_DIR_ENV)
+        .ok()
+        .or_else(|| option_env!("HASP_LOCALE_DIR").map(|dir| dir.to_owned()))
+        .filter(|dir| !dir.is_empty())
+        .map(Utf8PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn render_templates() {
+        let catalog = MessageCatalog::builtin();
+        assert_eq!(
+            catalog
+                .render("working::installing", &args(&[("package", "foo v1.0.0")]))
+                .expect("message rendered"),
+            "Installing foo v1.0.0"
+        );
+        assert_eq!(
+            catalog.render("working::installing", &args(&[])),
+            None,
+            "missing fields fall back to the original message"
+        );
+        assert_eq!(catalog.render("nonexistent", &args(&[])), None);
+        assert_eq!(
+            render_template("{{{name}}} }}", &args(&[("name", "foo")])).expect("rendered"),
+            "{foo} }"
+        );
+        assert_eq!(render_template("{name", &args(&[("name", "foo")])), None);
+
+        // Every built-in message has its header followed by text, and only uses braces for
+        // placeholders.
+        for (id, template) in &catalog.messages {
+            assert!(template.contains(' '), "{} has a header", id);
+            let placeholders = template
+                .split('{')
+                .skip(1)
+                .filter_map(|part| part.split_once('}'))
+                .map(|(name, _)| (name, "value"))
+                .collect::<Vec<_>>();
+            render_template(template, &args(&placeholders))
+                .unwrap_or_else(|| panic!("{} renders", id));
+        }
+    }
+
+    #[test]
+    fn generated_from_call_sites() {
+        let catalog = MessageCatalog::builtin();
+        assert_eq!(
+            catalog
+                .render("cancel::cancelled", &args(&[]))
+                .expect("message without fields rendered"),
+            "Cancelled installs in progress were rolled back"
+        );
+        assert_eq!(
+            catalog
+                .render(
+                    "shims_failed",
+                    &args(&[("package", "foo v1.0.0"), ("error", "denied")])
+                )
+                .expect("message with fields rendered"),
+            "Warning failed to write shims for foo v1.0.0: denied"
+        );
+        assert!(BUILTIN_MESSAGES.starts_with("# Messages printed by hasp"));
+    }
+
+    #[test]
+    fn load_locale() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        fs::write(
+            dir.join("de.toml"),
+            "[messages]\n\"working::fetching\" = \"Lade {package}\"\n\
+             \"working::installing\" = \"Installiere {package}\"\n",
+        )
+        .expect("catalog written");
+        fs::write(
+            dir.join("de_AT.toml"),
+            "[messages]\n\"working::fetching\" = \"Hole {package}\"\n",
+        )
+        .expect("catalog written");
+        let package = args(&[("package", "foo")]);
+
+        let catalog = MessageCatalog::load("de_AT", Some(dir)).expect("catalog loaded");
+        assert_eq!(catalog.locale(), "de_AT");
+        assert_eq!(
+            catalog.render("working::fetching", &package).as_deref(),
+            Some("Hole foo")
+        );
+        assert_eq!(
+            catalog.render("working::installing", &package).as_deref(),
+            Some("Installiere foo"),
+            "messages fall back to the language"
+        );
+        assert_eq!(
+            catalog.render("working::uninstalling", &package).as_deref(),
+            Some("Uninstalling foo"),
+            "messages fall back to English"
+        );
+
+        let catalog = MessageCatalog::load("fr_FR", Some(dir)).expect("catalog loaded");
+        assert_eq!(catalog.locale(), "en");
+
+        fs::write(dir.join("es.toml"), "messages = 1").expect("catalog written");
+        assert!(MessageCatalog::load("es", Some(dir)).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Structured fields attached to output events.
+//!
+//! The version of tracing in use can't record arbitrary values (that needs Valuable support), so
+//! structured fields are recorded as JSON and decoded again by the output formatters.
+
+use crate::output::NameVersionDisplay;
+use hasp_metadata::DirectoryVersion;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The name of the field that [`PackageList`]s are recorded as.
+pub(crate) const PACKAGES_FIELD: &str = "packages";
+
+/// The name of the field that per-package results are recorded as, in JSON.
+///
+/// Human-readable output leaves these out, since each result has already been printed, and JSON
+/// output includes them as they are.
+pub(crate) const RESULTS_FIELD: &str = "results";
+
+/// A list of packages and their versions, recorded as the `packages` field of an output event.
+///
+/// Human-readable output lists these below the message, and JSON output includes them as an
+/// array of objects.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub(crate) struct PackageList(Vec<PackageVersion>);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PackageVersion {
+    pub(crate) name: String,
+    pub(crate) version: DirectoryVersion,
+    /// A newer version that's available, if one was found.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) available: Option<DirectoryVersion>,
+}
+
+impl PackageList {
+    /// Adds a package, along with a newer version that's available for it if one was found.
+    pub(crate) fn push(
+        &mut self,
+        name: impl Into<String>,
+        version: DirectoryVersion,
+        available: Option<DirectoryVersion>,
+    ) {
+        self.0.push(PackageVersion {
+            name: name.into(),
+            version,
+            available,
+        });
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Decodes a list recorded by an output event.
+    pub(super) fn decode(recorded: &str) -> Option<Self> {
+        serde_json::from_str(recorded).ok()
+    }
+
+    /// Writes out the list as bullet points, one per line.
+    pub(super) fn write_bullets(&self, mut f: impl fmt::Write) -> fmt::Result {
+        for package in &self.0 {
+            write!(
+                f,
+                "\n* {}",
+                NameVersionDisplay::dir_version(&package.name, &package.version)
+            )?;
+            if let Some(available) = &package.available {
+                let available = available.short_display();
+                write!(
+                    f,
+                    " ({} available, run `hasp install {}@{}`)",
+                    available, package.name, available
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PackageList {
+    /// Formats the list as JSON, which is how it's recorded by output events.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_list_roundtrip() {
+        colored::control::set_override(false);
+
+        let mut list = PackageList::default();
+        list.push("foo", "sem:1.0.0".parse().expect("valid version"), None);
+        list.push(
+            "npm:bar",
+            "sem:2.1.0".parse().expect("valid version"),
+            Some("sem:2.3.0".parse().expect("valid version")),
+        );
+        let recorded = list.to_string();
+        let value: serde_json::Value = serde_json::from_str(&recorded).expect("valid JSON");
+        assert_eq!(value[0]["name"], "foo");
+        assert_eq!(value[1]["version"], "sem:2.1.0");
+        assert!(value[0].get("available").is_none());
+
+        let decoded = PackageList::decode(&recorded).expect("decoded");
+        let mut bullets = String::new();
+        decoded.write_bullets(&mut bullets).expect("written");
+        assert_eq!(
+            bullets,
+            "\n* foo v1.0.0\n* npm:bar v2.1.0 (2.3.0 available, run `hasp install npm:bar@2.3.0`)"
+        );
+    }
+}
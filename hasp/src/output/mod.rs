@@ -1,17 +1,24 @@
 // Copyright (c) The hasp Contributors
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+pub(crate) mod catalog;
+mod fields;
 mod formatters;
+pub(crate) mod otlp;
+pub(crate) mod progress;
 mod subscriber;
 
+pub(crate) use fields::*;
 pub(crate) use formatters::*;
 
+use serde::Deserialize;
 use structopt::StructOpt;
 
 #[derive(Copy, Clone, Debug, StructOpt)]
 #[must_use]
 pub(crate) struct OutputOpts {
-    /// Suppress output
+    /// Suppress output, except that installs print `name version outcome` for each package to
+    /// standard output
     #[structopt(
         name = "outputquiet",
         global = true,
@@ -31,20 +38,45 @@ pub(crate) struct OutputOpts {
     )]
     pub(crate) verbose: usize,
 
-    /// Produce color output
+    /// Produce color output [default: auto]
     #[structopt(
         long,
         global = true,
-        default_value = "auto",
         possible_values = &["auto", "always", "never"],
     )]
-    pub(crate) color: Color,
+    pub(crate) color: Option<Color>,
+
+    /// Format for output messages [default: human]
+    #[structopt(
+        long,
+        global = true,
+        possible_values = &["human", "json"],
+    )]
+    pub(crate) message_format: Option<MessageFormat>,
 }
 
 impl OutputOpts {
-    pub(crate) fn init_logger(&self) {
-        self.make_subscriber();
-        self.color.init_colored();
+    /// Sets up output, and exports tracing data with `otlp` if it's configured.
+    pub(crate) fn init_logger(&self, otlp: Option<otlp::OtlpSettings>) {
+        self.make_subscriber(otlp);
+        catalog::init();
+        match self.message_format() {
+            MessageFormat::Human => self.color().init_colored(),
+            // Escape codes would end up within JSON strings.
+            MessageFormat::Json => Color::Never.init_colored(),
+        }
+    }
+
+    /// Returns the message format, defaulting to `human` if unset.
+    #[inline]
+    pub(crate) fn message_format(&self) -> MessageFormat {
+        self.message_format.unwrap_or(MessageFormat::Human)
+    }
+
+    /// Returns the color setting, defaulting to `auto` if unset.
+    #[inline]
+    pub(crate) fn color(&self) -> Color {
+        self.color.unwrap_or(Color::Auto)
     }
 
     #[allow(dead_code)]
@@ -53,7 +85,8 @@ impl OutputOpts {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
 #[must_use]
 pub enum Color {
     Auto,
@@ -94,3 +127,28 @@ impl std::str::FromStr for Color {
         }
     }
 }
+
+/// The format that output messages are written in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[must_use]
+pub enum MessageFormat {
+    /// Messages aligned like Cargo's, with lists of packages as bullet points.
+    Human,
+    /// One JSON object per message, including structured fields like lists of packages.
+    Json,
+}
+
+impl std::str::FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            s => Err(format!(
+                "{} is not a valid option, expected `human` or `json`",
+                s
+            )),
+        }
+    }
+}
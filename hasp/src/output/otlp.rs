@@ -0,0 +1,462 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Exporting tracing data to an OpenTelemetry collector, configured in `[telemetry]` or through the
+//! standard `OTEL_EXPORTER_OTLP_*` environment variables.
+//!
+//! Events are exported as log records and spans as spans, using OTLP's JSON encoding over HTTP.
+//! They're buffered while hasp runs and sent when it exits, so that exporting doesn't slow
+//! installs down. All spans in a run share a trace ID, which is the run ID recorded with events in
+//! the journal.
+
+use crate::{events::run_id, output::progress::strip_ansi, platform::TARGET};
+use color_eyre::{eyre::WrapErr, Result};
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::Field,
+    span::{Attributes, Id, Record},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{field::Visit, layer::Context, registry::LookupSpan, Layer};
+
+static OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+static OTLP_HEADERS_ENV: &str = "OTEL_EXPORTER_OTLP_HEADERS";
+static SERVICE_NAME_ENV: &str = "OTEL_SERVICE_NAME";
+
+static EXPORTER: OnceCell<OtlpExporter> = OnceCell::new();
+
+/// Where and how to export tracing data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct OtlpSettings {
+    /// The base URL of the collector, e.g. `http://localhost:4318`.
+    pub(crate) endpoint: String,
+    /// Headers sent with each export, e.g. for authentication.
+    pub(crate) headers: BTreeMap<String, String>,
+    /// The service name data is reported under.
+    pub(crate) service_name: String,
+}
+
+impl OtlpSettings {
+    /// The service name data is reported under, unless configured.
+    pub(crate) const DEFAULT_SERVICE_NAME: &'static str = "hasp";
+
+    /// Returns the settings to export with, or `None` if no endpoint is configured. The standard
+    /// OpenTelemetry environment variables take precedence over the config file.
+    pub(crate) fn new(
+        endpoint: Option<&str>,
+        headers: &BTreeMap<String, String>,
+        service_name: Option<&str>,
+    ) -> Option<Self> {
+        let endpoint = env::var(OTLP_ENDPOINT_ENV)
+            .ok()
+            .filter(|endpoint| !endpoint.is_empty())
+            .or_else(|| endpoint.map(str::to_owned))?;
+        let mut headers = headers.clone();
+        if let Ok(env_headers) = env::var(OTLP_HEADERS_ENV) {
+            headers.extend(parse_headers(&env_headers));
+        }
+        let service_name = env::var(SERVICE_NAME_ENV)
+            .ok()
+            .filter(|name| !name.is_empty())
+            .or_else(|| service_name.map(str::to_owned))
+            .unwrap_or_else(|| Self::DEFAULT_SERVICE_NAME.to_owned());
+        Some(Self {
+            endpoint,
+            headers,
+            service_name,
+        })
+    }
+}
+
+/// Parses headers in the format of `OTEL_EXPORTER_OTLP_HEADERS`, e.g. `key1=value1,key2=value2`.
+/// Malformed entries are skipped.
+fn parse_headers(s: &str) -> BTreeMap<String, String> {
+    s.split(',')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let key = key.trim();
+            (!key.is_empty()).then(|| (key.to_owned(), value.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// Starts buffering tracing data for export, returning a layer that records it. Returns `None` if
+/// exporting has already been set up.
+pub(super) fn layer(settings: OtlpSettings) -> Option<OtlpLayer> {
+    EXPORTER.set(OtlpExporter::new(settings)).ok()?;
+    Some(OtlpLayer { _private: () })
+}
+
+/// Sends buffered tracing data to the collector, if exporting is configured.
+///
+/// Failures are reported as warnings rather than returned: they shouldn't change the outcome of
+/// the command.
+pub(crate) async fn flush() {
+    let exporter = match EXPORTER.get() {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    let batch = std::mem::take(&mut *exporter.lock());
+    if let Err(err) = exporter.send(batch).await {
+        let endpoint = &exporter.settings.endpoint;
+        let error = format!("{:#}", err);
+        tracing::warn!(
+            target: "hasp::output::telemetry_failed",
+            %endpoint,
+            %error,
+            "Warning failed to export telemetry to {endpoint}: {error}",
+        );
+    }
+}
+
+#[derive(Debug)]
+struct OtlpExporter {
+    settings: OtlpSettings,
+    trace_id: String,
+    batch: Mutex<Batch>,
+}
+
+impl OtlpExporter {
+    /// The time limit for each request to the collector.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn new(settings: OtlpSettings) -> Self {
+        Self {
+            settings,
+            // Spans can be matched up with the events recorded in the journal by this run.
+            trace_id: run_id().to_owned(),
+            batch: Mutex::new(Batch::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Batch> {
+        self.batch.lock().expect("telemetry lock is never poisoned")
+    }
+
+    async fn send(&self, batch: Batch) -> Result<()> {
+        if batch.dropped > 0 {
+            tracing::debug!(
+                "dropped {} telemetry records over the limit of {}",
+                batch.dropped,
+                Batch::MAX_RECORDS
+            );
+        }
+        let resource = self.resource();
+        if !batch.logs.is_empty() {
+            self.post("v1/logs", &logs_payload(&resource, batch.logs))
+                .await?;
+        }
+        if !batch.spans.is_empty() {
+            self.post("v1/traces", &traces_payload(&resource, batch.spans))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn post(&self, path: &str, payload: &Value) -> Result<()> {
+        let url = format!("{}/{}", self.settings.endpoint.trim_end_matches('/'), path);
+        let mut request = reqwest::Client::new()
+            .post(&url)
+            .timeout(Self::TIMEOUT)
+            .header("content-type", "application/json")
+            .body(payload.to_string());
+        for (key, value) in &self.settings.headers {
+            request = request.header(key.as_str(), value.as_str());
+        }
+        request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .wrap_err_with(|| format!("failed to send telemetry to {}", url))?;
+        Ok(())
+    }
+
+    fn resource(&self) -> Value {
+        json!({
+            "attributes": [
+                attribute("service.name", json!({ "stringValue": self.settings.service_name })),
+                attribute("service.version", json!({ "stringValue": env!("CARGO_PKG_VERSION") })),
+                attribute("hasp.target", json!({ "stringValue": TARGET })),
+            ],
+        })
+    }
+}
+
+/// Tracing data waiting to be sent.
+#[derive(Debug, Default)]
+struct Batch {
+    logs: Vec<Value>,
+    spans: Vec<Value>,
+    /// The number of records dropped because the batch was full.
+    dropped: usize,
+}
+
+impl Batch {
+    /// The maximum number of log records and spans kept, to bound memory use in long runs.
+    const MAX_RECORDS: usize = 10_000;
+
+    fn push_log(&mut self, record: Value) {
+        if self.is_full() {
+            self.dropped += 1;
+        } else {
+            self.logs.push(record);
+        }
+    }
+
+    fn push_span(&mut self, record: Value) {
+        if self.is_full() {
+            self.dropped += 1;
+        } else {
+            self.spans.push(record);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.logs.len() + self.spans.len() >= Self::MAX_RECORDS
+    }
+}
+
+fn logs_payload(resource: &Value, logs: Vec<Value>) -> Value {
+    json!({
+        "resourceLogs": [{
+            "resource": resource,
+            "scopeLogs": [{ "scope": scope(), "logRecords": logs }],
+        }],
+    })
+}
+
+fn traces_payload(resource: &Value, spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{ "scope": scope(), "spans": spans }],
+        }],
+    })
+}
+
+fn scope() -> Value {
+    json!({ "name": "hasp", "version": env!("CARGO_PKG_VERSION") })
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+/// Records events as log records and spans as spans, for export when hasp exits.
+pub(super) struct OtlpLayer {
+    _private: (),
+}
+
+/// The start time and fields of a span that hasn't closed yet.
+struct OpenSpan {
+    start: SystemTime,
+    fields: FieldVisitor,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(OpenSpan {
+                start: SystemTime::now(),
+                fields,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(&mut open.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let exporter = match EXPORTER.get() {
+            Some(exporter) => exporter,
+            None => return,
+        };
+        let mut fields = FieldVisitor::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        let mut record = json!({
+            "timeUnixNano": unix_nanos(SystemTime::now()).to_string(),
+            "severityNumber": severity_number(*metadata.level()),
+            "severityText": metadata.level().to_string(),
+            "body": { "stringValue": strip_ansi(&fields.message) },
+            "attributes": fields.attributes(metadata.target()),
+        });
+        if let Some(span) = ctx.lookup_current() {
+            record["traceId"] = exporter.trace_id.clone().into();
+            record["spanId"] = span_id(&span.id()).into();
+        }
+
+        exporter.lock().push_log(record);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let (exporter, span) = match (EXPORTER.get(), ctx.span(&id)) {
+            (Some(exporter), Some(span)) => (exporter, span),
+            _ => return,
+        };
+        let open = match span.extensions_mut().remove::<OpenSpan>() {
+            Some(open) => open,
+            None => return,
+        };
+        let metadata = span.metadata();
+        let mut record = json!({
+            "traceId": exporter.trace_id,
+            "spanId": span_id(&id),
+            "name": metadata.name(),
+            // SPAN_KIND_INTERNAL.
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(open.start).to_string(),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()).to_string(),
+            "attributes": open.fields.attributes(metadata.target()),
+        });
+        if let Some(parent) = span.parent() {
+            record["parentSpanId"] = span_id(&parent.id()).into();
+        }
+
+        exporter.lock().push_span(record);
+    }
+}
+
+/// Collects the message and other fields of an event or span as OTLP attribute values.
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Vec<(String, Value)>,
+}
+
+impl FieldVisitor {
+    fn attributes(&self, target: &str) -> Vec<Value> {
+        let mut attributes = vec![attribute("hasp.target", json!({ "stringValue": target }))];
+        attributes.extend(
+            self.fields
+                .iter()
+                .map(|(key, value)| attribute(key, value.clone())),
+        );
+        attributes
+    }
+
+    fn push(&mut self, field: &Field, value: Value) {
+        self.fields.push((field.name().to_owned(), value));
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        // 64-bit integers are encoded as strings in OTLP's JSON encoding.
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, json!({ "boolValue": value }));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_owned();
+        } else {
+            self.push(field, json!({ "stringValue": strip_ansi(value) }));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        if field.name() == "message" {
+            self.message = value;
+        } else {
+            self.push(field, json!({ "stringValue": strip_ansi(&value) }));
+        }
+    }
+}
+
+fn severity_number(level: Level) -> u8 {
+    match level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+fn span_id(id: &Id) -> String {
+    hex(&id.into_u64().to_be_bytes())
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otlp_payloads() {
+        assert_eq!(
+            parse_headers("authorization=Bearer abc, x-team = build,malformed,=empty"),
+            BTreeMap::from([
+                ("authorization".to_owned(), "Bearer abc".to_owned()),
+                ("x-team".to_owned(), "build".to_owned()),
+            ])
+        );
+
+        let fields = FieldVisitor {
+            message: "Installing foo".to_owned(),
+            fields: vec![(
+                "package".to_owned(),
+                json!({ "stringValue": strip_ansi("\x1b[1mfoo\x1b[0m v1.0.0") }),
+            )],
+        };
+        let attributes = fields.attributes("hasp::output::working::installing");
+        assert_eq!(
+            attributes[0]["value"]["stringValue"],
+            "hasp::output::working::installing"
+        );
+        assert_eq!(attributes[1]["key"], "package");
+        assert_eq!(attributes[1]["value"]["stringValue"], "foo v1.0.0");
+
+        let resource = json!({ "attributes": [] });
+        let payload = logs_payload(&resource, vec![json!({ "severityNumber": 9 })]);
+        let scope_logs = &payload["resourceLogs"][0]["scopeLogs"][0];
+        assert_eq!(scope_logs["scope"]["name"], "hasp");
+        assert_eq!(scope_logs["logRecords"][0]["severityNumber"], 9);
+        let payload = traces_payload(&resource, vec![json!({ "name": "install" })]);
+        assert_eq!(
+            payload["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["name"],
+            "install"
+        );
+
+        assert_eq!(span_id(&Id::from_u64(0xabc)), "0000000000000abc");
+        assert_eq!(severity_number(Level::WARN), 13);
+    }
+}
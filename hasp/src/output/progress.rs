@@ -0,0 +1,550 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A consolidated status table for installing several packages at once.
+//!
+//! Installs run concurrently, so their progress messages would otherwise interleave. While a
+//! table is active, progress messages about packages update its rows instead of being printed,
+//! other messages are printed above it, and build output is only written to build logs. Once
+//! every install has finished, the table is left in place as a summary.
+//!
+//! The table is only shown for human-readable output to a terminal. Frontends that render
+//! progress themselves can instead receive the same updates as [`ProgressEvent`]s, whatever the
+//! output, and can take over from the table while they're shown.
+
+use crate::output::{MessageFormat, OutputOpts};
+use colored::{ColoredString, Colorize};
+use hasp_metadata::{ProgressEvent, ProgressPhase};
+use once_cell::sync::Lazy;
+use std::{
+    fmt,
+    io::{self, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{field::Field, Event, Subscriber};
+use tracing_subscriber::{field::Visit, layer::Context, Layer};
+
+static TABLE: Lazy<Mutex<Option<ProgressTable>>> = Lazy::new(Default::default);
+static LISTENERS: Lazy<Mutex<Listeners>> = Lazy::new(Default::default);
+static RENDERED_ELSEWHERE: AtomicBool = AtomicBool::new(false);
+
+/// Returns a channel that receives a [`ProgressEvent`] whenever a package being installed moves to
+/// another phase. Events stop being sent once the receiver is dropped.
+pub(crate) fn subscribe() -> Receiver<ProgressEvent> {
+    let (sender, receiver) = mpsc::channel();
+    listeners().senders.push(sender);
+    receiver
+}
+
+/// Returns a channel like [`subscribe`], for a frontend that shows progress in place of the table.
+/// Until the returned guard is dropped, no table is shown and progress messages about packages are
+/// only sent as events.
+pub(crate) fn render_elsewhere() -> (Receiver<ProgressEvent>, RenderGuard) {
+    RENDERED_ELSEWHERE.store(true, Ordering::SeqCst);
+    (subscribe(), RenderGuard { _private: () })
+}
+
+fn is_rendered_elsewhere() -> bool {
+    RENDERED_ELSEWHERE.load(Ordering::SeqCst)
+}
+
+/// Starts showing a table for these packages, keyed as in the config file, if output is to a
+/// terminal and there's more than one of them. The table stops updating when the returned guard
+/// is dropped.
+pub(crate) fn start(output_opts: OutputOpts, packages: Vec<String>) -> Option<ProgressGuard> {
+    listeners().start(&packages);
+    if packages.len() < 2
+        || is_rendered_elsewhere()
+        || output_opts.quiet
+        || output_opts.verbose > 0
+        || output_opts.message_format() != MessageFormat::Human
+        || !io::stderr().is_terminal()
+    {
+        return None;
+    }
+    let mut table = ProgressTable::new(packages);
+    table.redraw();
+    *lock() = Some(table);
+    Some(ProgressGuard { _private: () })
+}
+
+/// Returns true if a table is being shown.
+pub(crate) fn is_active() -> bool {
+    lock().is_some()
+}
+
+/// Marks a package as finished.
+pub(crate) fn finish(package: &str, outcome: Outcome) {
+    listeners().finish(package, outcome);
+    if let Some(table) = &mut *lock() {
+        table.finish(package, outcome);
+        table.redraw();
+    }
+}
+
+/// Returns true if events with this target update the table, or are shown by another frontend,
+/// rather than being printed.
+pub(super) fn suppresses(target: &str) -> bool {
+    Phase::from_target(target).is_some() && (is_active() || is_rendered_elsewhere())
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<ProgressTable>> {
+    TABLE.lock().expect("progress lock is never poisoned")
+}
+
+fn listeners() -> std::sync::MutexGuard<'static, Listeners> {
+    LISTENERS
+        .lock()
+        .expect("progress listeners lock is never poisoned")
+}
+
+/// Stops updating the table when dropped, leaving it in place as a summary.
+#[must_use]
+pub(crate) struct ProgressGuard {
+    _private: (),
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        if let Some(mut table) = lock().take() {
+            table.redraw();
+        }
+    }
+}
+
+/// Hands showing progress back to the table when dropped.
+#[must_use]
+pub(crate) struct RenderGuard {
+    _private: (),
+}
+
+impl Drop for RenderGuard {
+    fn drop(&mut self) {
+        RENDERED_ELSEWHERE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// How the install of a package turned out.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Outcome {
+    Installed,
+    AlreadyInstalled,
+    Failed,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Phase {
+    Resolving,
+    Fetching,
+    Building,
+    Finished(Outcome),
+}
+
+impl Phase {
+    /// Returns the phase a package is in once an event with this target is logged for it.
+    fn from_target(target: &str) -> Option<Self> {
+        match target.strip_prefix("hasp::output::working::")? {
+            "fetching" | "resuming" => Some(Phase::Fetching),
+            "installing" | "reusing_build" | "shared_store" | "remote_cache" => {
+                Some(Phase::Building)
+            }
+            _ => None,
+        }
+    }
+
+    fn event_phase(self) -> ProgressPhase {
+        match self {
+            Phase::Resolving => ProgressPhase::Resolving,
+            Phase::Fetching => ProgressPhase::Fetching,
+            Phase::Building => ProgressPhase::Building,
+            Phase::Finished(Outcome::Installed) => ProgressPhase::Installed,
+            Phase::Finished(Outcome::AlreadyInstalled) => ProgressPhase::AlreadyInstalled,
+            Phase::Finished(Outcome::Failed) => ProgressPhase::Failed,
+        }
+    }
+
+    fn header(self) -> ColoredString {
+        match self {
+            Phase::Resolving => "Resolving".bold().blue(),
+            Phase::Fetching => "Fetching".bold().blue(),
+            Phase::Building => "Building".bold().blue(),
+            Phase::Finished(Outcome::Installed) => "Installed".bold().green(),
+            Phase::Finished(Outcome::AlreadyInstalled) => "Up-to-date".bold().purple(),
+            Phase::Finished(Outcome::Failed) => "Failed".bold().red(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Row {
+    key: String,
+    /// The package and its version once it's been resolved, as in other messages.
+    display: Option<String>,
+    phase: Phase,
+    elapsed: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct ProgressTable {
+    rows: Vec<Row>,
+    start: Instant,
+    /// The number of lines drawn to the terminal, which are erased before drawing it again.
+    drawn: usize,
+}
+
+impl ProgressTable {
+    fn new(packages: Vec<String>) -> Self {
+        let rows = packages
+            .into_iter()
+            .map(|key| Row {
+                key,
+                display: None,
+                phase: Phase::Resolving,
+                elapsed: None,
+            })
+            .collect();
+        Self {
+            rows,
+            start: Instant::now(),
+            drawn: 0,
+        }
+    }
+
+    /// Updates the row for the package with this key from a progress message, in which the
+    /// package is formatted as `name vversion`.
+    fn update(&mut self, phase: Phase, key: &str, package: &str) {
+        let package = strip_ansi(package);
+        if let Some(row) = self
+            .rows
+            .iter_mut()
+            .find(|row| row.elapsed.is_none() && row.key == key)
+        {
+            row.phase = phase;
+            row.display = Some(package);
+        }
+    }
+
+    fn finish(&mut self, key: &str, outcome: Outcome) {
+        if let Some(row) = self.rows.iter_mut().find(|row| row.key == key) {
+            row.phase = Phase::Finished(outcome);
+            row.elapsed = Some(self.start.elapsed());
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let finished = self.rows.iter().filter(|row| row.elapsed.is_some()).count();
+        let mut lines = vec![format!(
+            "{:>12} {}/{} packages",
+            "Progress".bold().blue(),
+            finished,
+            self.rows.len()
+        )];
+        lines.extend(self.rows.iter().map(|row| {
+            let mut line = format!(
+                "{:>12} {}",
+                row.phase.header(),
+                row.display.as_deref().unwrap_or(&row.key)
+            );
+            if let Some(elapsed) = row.elapsed {
+                line.push_str(&format!(" in {:.1}s", elapsed.as_secs_f64()));
+            }
+            line
+        }));
+        lines
+    }
+
+    fn erase(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if self.drawn > 0 {
+            // Move to the start of the first line of the table, and clear to the end of the
+            // screen.
+            write!(out, "\x1b[{}A\r\x1b[J", self.drawn)?;
+            self.drawn = 0;
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let lines = self.lines();
+        for line in &lines {
+            writeln!(out, "{}", line)?;
+        }
+        self.drawn = lines.len();
+        out.flush()
+    }
+
+    fn redraw(&mut self) {
+        let mut stderr = io::stderr().lock();
+        // Ignore errors writing output -- there isn't much to be done about them.
+        let _ = self
+            .erase(&mut stderr)
+            .and_then(|()| self.draw(&mut stderr));
+    }
+}
+
+/// Frontends receiving progress events, and the packages being installed.
+#[derive(Debug, Default)]
+struct Listeners {
+    senders: Vec<Sender<ProgressEvent>>,
+    packages: Vec<ListenedPackage>,
+}
+
+#[derive(Debug)]
+struct ListenedPackage {
+    /// The package, keyed as in the config file.
+    key: String,
+    version: Option<String>,
+    finished: bool,
+}
+
+impl Listeners {
+    fn start(&mut self, packages: &[String]) {
+        self.packages = packages
+            .iter()
+            .map(|key| ListenedPackage {
+                key: key.clone(),
+                version: None,
+                finished: false,
+            })
+            .collect();
+        for idx in 0..self.packages.len() {
+            self.send(idx, Phase::Resolving);
+        }
+    }
+
+    /// Sends an event for the package with this key from a progress message, in which the
+    /// package is formatted as `name vversion`.
+    fn update(&mut self, phase: Phase, key: &str, package: &str) {
+        let version = strip_ansi(package)
+            .split_once(" v")
+            .map(|(_, version)| version.to_owned());
+        if let Some(idx) = self
+            .packages
+            .iter()
+            .position(|package| !package.finished && package.key == key)
+        {
+            self.packages[idx].version = version;
+            self.send(idx, phase);
+        }
+    }
+
+    fn finish(&mut self, key: &str, outcome: Outcome) {
+        if let Some(idx) = self.packages.iter().position(|package| package.key == key) {
+            self.packages[idx].finished = true;
+            self.send(idx, Phase::Finished(outcome));
+        }
+    }
+
+    fn send(&mut self, idx: usize, phase: Phase) {
+        if self.senders.is_empty() {
+            return;
+        }
+        let finished = self
+            .packages
+            .iter()
+            .filter(|package| package.finished)
+            .count();
+        let package = &self.packages[idx];
+        let event = ProgressEvent {
+            package: package.key.clone(),
+            version: package.version.clone(),
+            phase: phase.event_phase(),
+            percent: (finished * 100 / self.packages.len().max(1)) as u8,
+        };
+        // Stop sending events to frontends that are no longer listening.
+        self.senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+/// Removes escape codes for colors from a string.
+pub(crate) fn strip_ansi(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip to the end of the escape sequence.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Updates the table from progress messages.
+pub(super) struct ProgressLayer;
+
+impl<S: Subscriber> Layer<S> for ProgressLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let phase = match Phase::from_target(event.metadata().target()) {
+            Some(phase) => phase,
+            None => return,
+        };
+        let mut visitor = PackageVisitor::default();
+        event.record(&mut visitor);
+        let (key, package) = match (visitor.key, visitor.package) {
+            (Some(key), Some(package)) => (key, package),
+            _ => return,
+        };
+        listeners().update(phase, &key, &package);
+        if let Some(table) = &mut *lock() {
+            table.update(phase, &key, &package);
+            table.redraw();
+        }
+    }
+}
+
+/// Reads the package key and the package, as displayed in messages, from a progress message.
+#[derive(Default)]
+struct PackageVisitor {
+    key: Option<String>,
+    package: Option<String>,
+}
+
+impl Visit for PackageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "key" => self.key = Some(format!("{:?}", value)),
+            "package" => self.package = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Returns a writer to standard error that prints above the table, if one is being shown.
+pub(super) fn stderr() -> ProgressWriter {
+    ProgressWriter { erased: false }
+}
+
+pub(super) struct ProgressWriter {
+    erased: bool,
+}
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The table is always locked before standard error, to avoid deadlocks.
+        if !self.erased {
+            if let Some(table) = &mut *lock() {
+                table.erase(&mut io::stderr().lock())?;
+                self.erased = true;
+            }
+        }
+        io::stderr().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+impl Drop for ProgressWriter {
+    fn drop(&mut self) {
+        if self.erased {
+            if let Some(table) = &mut *lock() {
+                table.redraw();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_table_updates() {
+        let mut table = ProgressTable::new(vec![
+            "foo".to_owned(),
+            "npm:bar".to_owned(),
+            "npm:foo".to_owned(),
+        ]);
+        assert_eq!(
+            Phase::from_target("hasp::output::working::installing"),
+            Some(Phase::Building)
+        );
+        assert_eq!(Phase::from_target("hasp::output::install_success"), None);
+
+        table.update(Phase::Fetching, "npm:bar", "\x1b[35mbar\x1b[0m v2.0.0");
+        table.update(Phase::Building, "foo", "foo v1.0.0");
+        table.finish("foo", Outcome::Installed);
+        // Messages about finished packages are ignored, and packages with the same name in other
+        // namespaces are updated separately.
+        table.update(Phase::Fetching, "foo", "foo v1.0.0");
+        table.update(Phase::Building, "npm:foo", "foo v3.0.0");
+
+        let lines: Vec<_> = table.lines().iter().map(|line| strip_ansi(line)).collect();
+        assert_eq!(lines[0].trim(), "Progress 1/3 packages");
+        assert!(
+            lines[1].trim().starts_with("Installed foo v1.0.0 in "),
+            "unexpected line: {}",
+            lines[1]
+        );
+        assert_eq!(lines[2].trim(), "Fetching bar v2.0.0");
+        assert_eq!(lines[3].trim(), "Building foo v3.0.0");
+
+        let mut out = vec![];
+        table.draw(&mut out).expect("table drawn");
+        assert_eq!(table.drawn, 4);
+        out.clear();
+        table.erase(&mut out).expect("table erased");
+        assert_eq!(out, b"\x1b[4A\r\x1b[J");
+        assert_eq!(table.drawn, 0);
+    }
+
+    #[test]
+    fn progress_events_sent() {
+        let (sender, receiver) = mpsc::channel();
+        let mut listeners = Listeners {
+            senders: vec![sender],
+            packages: vec![],
+        };
+        listeners.start(&["foo".to_owned(), "npm:bar".to_owned()]);
+        listeners.update(Phase::Fetching, "npm:bar", "\x1b[35mbar\x1b[0m v2.0.0");
+        listeners.finish("foo", Outcome::Installed);
+        // Messages about finished packages are ignored, as are packages that aren't listened to.
+        listeners.update(Phase::Building, "foo", "foo v1.0.0");
+        listeners.update(Phase::Building, "npm:foo", "foo v1.0.0");
+        listeners.finish("npm:bar", Outcome::Failed);
+
+        let events: Vec<_> = receiver
+            .try_iter()
+            .map(|event| (event.package, event.version, event.phase, event.percent))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("foo".to_owned(), None, ProgressPhase::Resolving, 0),
+                ("npm:bar".to_owned(), None, ProgressPhase::Resolving, 0),
+                (
+                    "npm:bar".to_owned(),
+                    Some("2.0.0".to_owned()),
+                    ProgressPhase::Fetching,
+                    0
+                ),
+                ("foo".to_owned(), None, ProgressPhase::Installed, 50),
+                (
+                    "npm:bar".to_owned(),
+                    Some("2.0.0".to_owned()),
+                    ProgressPhase::Failed,
+                    100
+                ),
+            ]
+        );
+
+        drop(receiver);
+        listeners.start(&["foo".to_owned()]);
+        assert!(
+            listeners.senders.is_empty(),
+            "dropped receivers are forgotten"
+        );
+    }
+}
@@ -3,13 +3,21 @@
 
 //! Tracing subscribers to send data to internal logs and to format data.
 
-use crate::output::OutputOpts;
+use crate::output::{
+    catalog,
+    otlp::{self, OtlpSettings},
+    progress::{self, ProgressLayer},
+    MessageFormat, OutputOpts, PackageList, PACKAGES_FIELD, RESULTS_FIELD,
+};
 use colored::Colorize;
-use std::fmt::{self, Write};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+};
 use tracing::{field::Field, level_filters::LevelFilter, Event, Level, Subscriber};
 use tracing_subscriber::{
     field::Visit,
-    filter::{FilterFn, Targets},
+    filter::{DynFilterFn, FilterFn, Targets},
     fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
     prelude::*,
     registry::LookupSpan,
@@ -20,7 +28,7 @@ static HASP_LOG_ENV: &str = "HASP_LOG";
 static MESSAGE_FIELD: &str = "message";
 
 impl OutputOpts {
-    pub(super) fn make_subscriber(&self) {
+    pub(super) fn make_subscriber(&self, otlp: Option<OtlpSettings>) {
         let registry = tracing_subscriber::registry();
 
         let level_str = std::env::var_os(HASP_LOG_ENV).unwrap_or_default();
@@ -44,16 +52,32 @@ impl OutputOpts {
         // Environment-based and command-line based logging.
 
         let fmt_layer: Box<dyn Layer<Registry> + Send + Sync> = match self.verbose {
+            0..=1 if self.message_format() == MessageFormat::Json => {
+                let json_layer = tracing_subscriber::fmt::layer()
+                    .event_format(JsonOutputFormatter)
+                    .with_writer(std::io::stderr)
+                    .with_filter(FilterFn::new(|metadata| {
+                        metadata.is_event()
+                            && (metadata.target().starts_with("hasp::output::")
+                                || metadata.target().starts_with("hasp::alt_output::"))
+                    }))
+                    .with_filter(targets);
+                Box::new(json_layer)
+            }
             0..=1 => {
+                // Progress messages are shown in the progress table instead while there is one,
+                // so this filter can't be cached.
                 let output_layer = tracing_subscriber::fmt::layer()
                     .event_format(OutputFormatter)
-                    .with_writer(std::io::stderr)
-                    .with_filter(FilterFn::new(|metadata| {
-                        metadata.is_event() && metadata.target().starts_with("hasp::output::")
+                    .with_writer(progress::stderr)
+                    .with_filter(DynFilterFn::new(|metadata, _| {
+                        metadata.is_event()
+                            && metadata.target().starts_with("hasp::output::")
+                            && !progress::suppresses(metadata.target())
                     }));
                 let alt_layer = tracing_subscriber::fmt::layer()
                     .event_format(AltOutputFormatter)
-                    .with_writer(std::io::stderr)
+                    .with_writer(progress::stderr)
                     .with_filter(FilterFn::new(|metadata| {
                         metadata.is_event() && metadata.target().starts_with("hasp::alt_output::")
                     }));
@@ -78,7 +102,16 @@ impl OutputOpts {
             }
         };
 
-        registry.with(fmt_layer).init();
+        // Exported data isn't affected by how verbose output is.
+        let otlp_layer = otlp
+            .and_then(otlp::layer)
+            .map(|layer| layer.with_filter(Targets::new().with_target("hasp", Level::DEBUG)));
+
+        registry
+            .with(fmt_layer)
+            .with(ProgressLayer)
+            .with(otlp_layer)
+            .init();
     }
 }
 
@@ -98,13 +131,43 @@ where
         let kind = OutputKind::from_target(event.metadata().target());
         let level = *event.metadata().level();
 
-        let mut visitor = MessageVisitor {
-            kind,
-            level,
-            writer: &mut f,
-        };
+        let mut visitor = MessageVisitor::default();
         event.record(&mut visitor);
 
+        let packages = visitor.packages.take();
+        let mut text = visitor.into_message(event.metadata().target());
+        if let Some(packages) = &packages {
+            packages.write_bullets(&mut text)?;
+        }
+        let (header, text) = text.split_once(' ').unwrap_or(("", &text));
+
+        let header = if level == Level::ERROR {
+            header.bold().red()
+        } else if level == Level::WARN {
+            header.bold().yellow()
+        } else {
+            match kind {
+                OutputKind::Recording => header.bold().yellow(),
+                OutputKind::Working => header.bold().blue(),
+                OutputKind::Informational => header.bold().purple(),
+                OutputKind::Standard => header.bold().green(),
+            }
+        };
+        // This uses the same alignment as Cargo itself.
+        write!(f, "{:>12} ", header)?;
+        // Print out the first newline non-indented.
+        match text.split_once('\n') {
+            Some((first_line, later)) => {
+                writeln!(f, "{}", first_line)?;
+                write!(
+                    indenter::indented(&mut f).with_str("             "),
+                    "{}",
+                    later
+                )?;
+            }
+            None => write!(f, "{}", text)?,
+        }
+
         writeln!(f)?;
 
         Ok(())
@@ -133,46 +196,36 @@ impl OutputKind {
     }
 }
 
-struct MessageVisitor<'writer, 'a> {
-    kind: OutputKind,
-    level: Level,
-    writer: &'a mut Writer<'writer>,
+/// Collects the message and structured fields of an event.
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    packages: Option<PackageList>,
+    /// The other fields, which messages in the catalog refer to.
+    args: BTreeMap<String, String>,
+}
+
+impl MessageVisitor {
+    /// Returns the message from the catalog if it has one, or the message in the event otherwise.
+    fn into_message(self, target: &str) -> String {
+        catalog::render(target, &self.args).unwrap_or(self.message)
+    }
 }
 
-impl<'writer, 'a> Visit for MessageVisitor<'writer, 'a> {
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        // The Debug implementation of str would quote the value.
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
         if field.name() == MESSAGE_FIELD {
-            let message = format!("{:?}", value);
-            let (header, text) = message.split_once(' ').unwrap_or(("", &message));
-
-            let header = if self.level == Level::ERROR {
-                header.bold().red()
-            } else if self.level == Level::WARN {
-                header.bold().yellow()
-            } else {
-                match self.kind {
-                    OutputKind::Recording => header.bold().yellow(),
-                    OutputKind::Working => header.bold().blue(),
-                    OutputKind::Informational => header.bold().purple(),
-                    OutputKind::Standard => header.bold().green(),
-                }
-            };
-            // This uses the same alignment as Cargo itself.
-            let _ = write!(self.writer, "{:>12} ", header);
-            // Print out the first newline non-indented.
-            match text.split_once('\n') {
-                Some((first_line, later)) => {
-                    let _ = writeln!(self.writer, "{}", first_line);
-                    let _ = write!(
-                        indenter::indented(self.writer).with_str("             "),
-                        "{}",
-                        later
-                    );
-                }
-                None => {
-                    let _ = write!(self.writer, "{}", text);
-                }
-            }
+            self.message = format!("{:?}", value);
+        } else if field.name() == PACKAGES_FIELD {
+            self.packages = PackageList::decode(&format!("{:?}", value));
+        } else if field.name() != RESULTS_FIELD {
+            self.args
+                .insert(field.name().to_owned(), format!("{:?}", value));
         }
     }
 }
@@ -222,3 +275,105 @@ impl<'writer, 'a> Visit for AltMessageVisitor<'writer, 'a> {
         }
     }
 }
+
+/// Writes out each event as a JSON object on its own line.
+struct JsonOutputFormatter;
+
+impl<S, N> FormatEvent<S, N> for JsonOutputFormatter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut f: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut object = serde_json::Map::new();
+        object.insert(
+            "level".to_owned(),
+            metadata.level().as_str().to_lowercase().into(),
+        );
+        object.insert("target".to_owned(), metadata.target().into());
+
+        let mut visitor = JsonVisitor {
+            message: None,
+            object,
+        };
+        event.record(&mut visitor);
+
+        let mut object = visitor.object;
+        if let Some(message) = visitor.message {
+            let args = object
+                .iter()
+                .filter(|(name, _)| {
+                    name.as_str() != PACKAGES_FIELD && name.as_str() != RESULTS_FIELD
+                })
+                .map(|(name, value)| {
+                    let value = match value {
+                        serde_json::Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    };
+                    (name.clone(), value)
+                })
+                .collect();
+            let message = catalog::render(metadata.target(), &args).unwrap_or(message);
+            // Alternate output doesn't start with a header.
+            let (header, text) = match message.split_once(' ') {
+                Some((header, text)) if metadata.target().starts_with("hasp::output::") => {
+                    (header, text)
+                }
+                _ => ("", message.as_str()),
+            };
+            if !header.is_empty() {
+                object.insert("header".to_owned(), header.into());
+            }
+            object.insert(MESSAGE_FIELD.to_owned(), text.into());
+        }
+
+        let json = serde_json::to_string(&object).map_err(|_| fmt::Error)?;
+        writeln!(f, "{}", json)
+    }
+}
+
+struct JsonVisitor {
+    message: Option<String>,
+    object: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Visit for JsonVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.object.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.object.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.object.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.object.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        if field.name() == MESSAGE_FIELD {
+            self.message = Some(value);
+        } else if field.name() == PACKAGES_FIELD {
+            let packages = PackageList::decode(&value)
+                .and_then(|packages| serde_json::to_value(packages).ok())
+                .unwrap_or_else(|| value.into());
+            self.object.insert(PACKAGES_FIELD.to_owned(), packages);
+        } else if field.name() == RESULTS_FIELD {
+            let results = serde_json::from_str(&value).unwrap_or_else(|_| value.into());
+            self.object.insert(RESULTS_FIELD.to_owned(), results);
+        } else {
+            self.object.insert(field.name().to_owned(), value.into());
+        }
+    }
+}
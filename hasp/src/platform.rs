@@ -0,0 +1,525 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Platform-specific behavior.
+//!
+//! Everything that differs between platforms is gated here, behind interfaces that are the same
+//! on every platform. Other modules branch on these interfaces rather than using `cfg` directly,
+//! so that hasp builds the same way for every target it's shipped on.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use std::{env, fmt, fs, io, process::ExitStatus, thread, time::Duration};
+
+/// True if hasp was built for Windows.
+pub(crate) const IS_WINDOWS: bool = cfg!(windows);
+
+/// The target triple hasp was built for, e.g. `x86_64-unknown-linux-gnu`.
+pub(crate) const TARGET: &str = env!("HASP_TARGET");
+
+/// The extensions that executables found in `PATH` may have.
+pub(crate) const EXECUTABLE_EXTENSIONS: &[&str] = if IS_WINDOWS {
+    &[".exe", ".cmd", ".bat"]
+} else {
+    &[""]
+};
+
+/// Returns the name a binary is invoked as, without an executable extension like `.exe`.
+///
+/// Extensions are matched case-insensitively, as Windows does. Binaries on other platforms don't
+/// have extensions, so their names are returned as is.
+pub(crate) fn command_name(file_name: &str) -> &str {
+    strip_executable_extension(file_name, EXECUTABLE_EXTENSIONS)
+}
+
+fn strip_executable_extension<'a>(file_name: &'a str, extensions: &[&str]) -> &'a str {
+    extensions
+        .iter()
+        .filter(|ext| !ext.is_empty())
+        .find_map(|ext| {
+            let stem = file_name.get(..file_name.len().checked_sub(ext.len())?)?;
+            let matches = file_name[stem.len()..].eq_ignore_ascii_case(ext) && !stem.is_empty();
+            matches.then_some(stem)
+        })
+        .unwrap_or(file_name)
+}
+
+/// The kind of script used for shims and wrappers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ScriptFlavor {
+    /// POSIX shell scripts.
+    Sh,
+
+    /// Windows batch files.
+    Cmd,
+}
+
+impl ScriptFlavor {
+    /// The flavor of script used on this platform.
+    pub(crate) const CURRENT: Self = if IS_WINDOWS {
+        ScriptFlavor::Cmd
+    } else {
+        ScriptFlavor::Sh
+    };
+
+    /// Returns the file name for a script that's invoked as `command`.
+    pub(crate) fn file_name(self, command: &str) -> String {
+        match self {
+            ScriptFlavor::Sh => command.to_owned(),
+            ScriptFlavor::Cmd => format!("{}.cmd", command),
+        }
+    }
+
+    /// Returns true if `file_name` could be a script of this flavor. Batch files must have a
+    /// `.cmd` extension, while shell scripts can be called anything.
+    pub(crate) fn is_file_name(self, file_name: &str) -> bool {
+        match self {
+            ScriptFlavor::Sh => true,
+            ScriptFlavor::Cmd => strip_executable_extension(file_name, &[".cmd"]) != file_name,
+        }
+    }
+
+    /// Returns the contents of a script that runs `program` with `args`, followed by any
+    /// arguments passed to the script.
+    pub(crate) fn exec_script(self, program: &Utf8Path, args: &[&str]) -> String {
+        let mut script = match self {
+            ScriptFlavor::Sh => format!("#!/bin/sh\nexec {}", sh_quote(program.as_str())),
+            ScriptFlavor::Cmd => format!("@\"{}\"", program),
+        };
+        for arg in args {
+            match self {
+                ScriptFlavor::Sh => script.push_str(&format!(" {}", sh_quote(arg))),
+                ScriptFlavor::Cmd => script.push_str(&format!(" \"{}\"", arg)),
+            }
+        }
+        match self {
+            ScriptFlavor::Sh => script.push_str(" \"$@\"\n"),
+            ScriptFlavor::Cmd => script.push_str(" %*\r\n"),
+        }
+        script
+    }
+
+    /// Returns the program run by a script written by [`Self::exec_script`], or `None` if the
+    /// script wasn't written by it.
+    ///
+    /// Shell scripts written before programs were single-quoted are recognized too.
+    pub(crate) fn exec_script_program(self, script: &str) -> Option<Utf8PathBuf> {
+        let rest = match self {
+            ScriptFlavor::Sh => script.strip_prefix("#!/bin/sh\nexec ")?,
+            ScriptFlavor::Cmd => script.strip_prefix('@')?,
+        };
+        match rest.strip_prefix('"') {
+            Some(rest) => rest
+                .split_once('"')
+                .map(|(program, _)| Utf8PathBuf::from(program)),
+            None if self == ScriptFlavor::Sh => sh_unquote(rest).map(Utf8PathBuf::from),
+            None => None,
+        }
+    }
+}
+
+/// Quotes `s` as a single word for a POSIX shell, whatever characters it contains.
+pub(crate) fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Returns the word quoted by [`sh_quote`] at the start of `s`, or `None` if `s` doesn't start
+/// with one.
+fn sh_unquote(mut s: &str) -> Option<String> {
+    let mut word = String::new();
+    loop {
+        let (part, rest) = s.strip_prefix('\'')?.split_once('\'')?;
+        word.push_str(part);
+        match rest.strip_prefix(r"\'") {
+            Some(rest) => {
+                word.push('\'');
+                s = rest;
+            }
+            None => return Some(word),
+        }
+    }
+}
+
+/// Marks a file as executable. This is a no-op on platforms without an executable bit.
+pub(crate) fn set_executable(path: &Utf8Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .wrap_err_with(|| format!("failed to mark {} as executable", path))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Returns the signal that terminated a process, on platforms that have signals.
+pub(crate) fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// Waits for a signal asking hasp to terminate: Ctrl-C everywhere, and also `SIGTERM` on Unix.
+pub(crate) async fn termination_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+// ---
+// Renames
+// ---
+
+/// How many times a rename is attempted before giving up, on platforms where files that are in use
+/// can't be renamed.
+const RENAME_ATTEMPTS: u32 = if IS_WINDOWS { 8 } else { 1 };
+
+/// How long to wait before retrying a rename. This doubles after each attempt, up to the maximum.
+const RENAME_RETRY_DELAY: Duration = Duration::from_millis(50);
+const RENAME_MAX_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Renames a file or directory, replacing `dest` if it's a file.
+///
+/// On Windows, a directory can't be renamed while a file within it is open, e.g. by antivirus
+/// software scanning a newly written binary. Such renames are retried for a few seconds before the
+/// error is returned. A binary that's running stays open until it exits, so the error can be
+/// checked with [`is_file_in_use`] to suggest exiting it.
+pub(crate) fn rename(src: &Utf8Path, dest: &Utf8Path) -> io::Result<()> {
+    let mut delay = RENAME_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match fs::rename(src, dest) {
+            Err(err) if attempt < RENAME_ATTEMPTS && is_file_in_use(&err) => {
+                tracing::debug!("{} is in use, retrying rename in {:?}: {}", src, delay, err);
+                thread::sleep(delay);
+                delay = (delay * 2).min(RENAME_MAX_RETRY_DELAY);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Replaces the binary of the running hasp with `new`, which must be in the same directory.
+///
+/// On Unix, a running binary can be renamed over. On Windows it can't be replaced or deleted, but
+/// it can be renamed, so it's moved aside to `<exe>.old` first. That file is removed by the next
+/// update, once the old binary has exited.
+pub(crate) fn replace_current_exe(new: &Utf8Path, exe: &Utf8Path) -> Result<()> {
+    if IS_WINDOWS {
+        let old = Utf8PathBuf::from(format!("{}.old", exe));
+        match fs::remove_file(&old) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).wrap_err_with(|| format!("failed to remove {}", old)),
+        }
+        rename(exe, &old).wrap_err_with(|| format!("failed to move {} to {}", exe, old))?;
+        if let Err(err) = rename(new, exe) {
+            // Put the old binary back, so that hasp is still there.
+            let _ = rename(&old, exe);
+            return Err(err).wrap_err_with(|| format!("failed to move {} to {}", new, exe));
+        }
+        Ok(())
+    } else {
+        rename(new, exe).wrap_err_with(|| format!("failed to move {} to {}", new, exe))
+    }
+}
+
+/// Flushes a directory's entries to disk, so that renames within it survive a crash.
+///
+/// Windows doesn't support syncing directories, and NTFS journals renames, so this does nothing
+/// there.
+pub(crate) fn sync_dir(dir: &Utf8Path) -> io::Result<()> {
+    if IS_WINDOWS {
+        return Ok(());
+    }
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Returns true if an operation failed because a file is open in another process. This only
+/// happens on Windows, where open files are locked against renames and deletion by default.
+pub(crate) fn is_file_in_use(err: &io::Error) -> bool {
+    // ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION.
+    IS_WINDOWS && matches!(err.raw_os_error(), Some(5 | 32 | 33))
+}
+
+// ---
+// File locking
+// ---
+
+// These call fs2 explicitly, since newer versions of std have inherent methods on `File` with the
+// same names but different semantics.
+//
+// On Unix, these are advisory locks through flock(2), which only affect other attempts to lock
+// the file. On Windows, they're mandatory locks over the whole file through LockFileEx, so while
+// a process holds an exclusive lock, other processes can't read or write the file either. Data
+// stored in a lockfile is only for diagnostics, and readers must expect to fail to read it.
+
+/// Blocks until an exclusive lock is obtained on this file.
+pub(crate) fn lock_exclusive(file: &fs::File) -> io::Result<()> {
+    fs2::FileExt::lock_exclusive(file)
+}
+
+/// Attempts to obtain an exclusive lock on this file, returning false if another process holds a
+/// lock.
+pub(crate) fn try_lock_exclusive(file: &fs::File) -> io::Result<bool> {
+    match fs2::FileExt::try_lock_exclusive(file) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Attempts to obtain a shared lock on this file, returning false if another process holds an
+/// exclusive lock.
+pub(crate) fn try_lock_shared(file: &fs::File) -> io::Result<bool> {
+    match fs2::FileExt::try_lock_shared(file) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Releases any locks held on this file.
+pub(crate) fn unlock(file: &fs::File) -> io::Result<()> {
+    fs2::FileExt::unlock(file)
+}
+
+// ---
+// Platform information and self-tests
+// ---
+
+/// A description of the platform hasp was built for.
+#[derive(Clone, Debug)]
+pub(crate) struct PlatformInfo {
+    pub(crate) os: &'static str,
+    pub(crate) arch: &'static str,
+    pub(crate) target_env: &'static str,
+    /// True if the C runtime is statically linked, e.g. for musl builds.
+    pub(crate) static_crt: bool,
+    pub(crate) script_flavor: ScriptFlavor,
+}
+
+impl PlatformInfo {
+    pub(crate) fn current() -> Self {
+        let target_env = if cfg!(target_env = "musl") {
+            "musl"
+        } else if cfg!(target_env = "gnu") {
+            "gnu"
+        } else if cfg!(target_env = "msvc") {
+            "msvc"
+        } else {
+            "none"
+        };
+        Self {
+            os: env::consts::OS,
+            arch: env::consts::ARCH,
+            target_env,
+            static_crt: cfg!(target_feature = "crt-static"),
+            script_flavor: ScriptFlavor::CURRENT,
+        }
+    }
+}
+
+impl fmt::Display for PlatformInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{} (env: {}, C runtime: {}, scripts: {:?})",
+            self.arch,
+            self.os,
+            self.target_env,
+            if self.static_crt { "static" } else { "dynamic" },
+            self.script_flavor,
+        )
+    }
+}
+
+/// Runs checks for platform-specific behavior within `temp_dir`, returning the name and result of
+/// each check.
+pub(crate) fn self_test(temp_dir: &Utf8Path) -> Vec<(&'static str, Result<()>)> {
+    vec![
+        ("file locking", check_locking(temp_dir)),
+        ("scripts", check_scripts(temp_dir)),
+        ("signals", check_signals(temp_dir)),
+    ]
+}
+
+fn check_locking(temp_dir: &Utf8Path) -> Result<()> {
+    let lock_path = temp_dir.join("self-test.lock");
+    let open = || {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .wrap_err_with(|| format!("failed to open {}", lock_path))
+    };
+    let (first, second) = (open()?, open()?);
+
+    lock_exclusive(&first).wrap_err("failed to obtain exclusive lock")?;
+    if try_lock_shared(&second).wrap_err("failed to try shared lock")? {
+        bail!("shared lock was obtained while an exclusive lock was held");
+    }
+    unlock(&first).wrap_err("failed to release exclusive lock")?;
+    if !try_lock_shared(&second).wrap_err("failed to try shared lock")? {
+        bail!("shared lock couldn't be obtained after the exclusive lock was released");
+    }
+    Ok(())
+}
+
+fn check_scripts(temp_dir: &Utf8Path) -> Result<()> {
+    // Run hasp itself through a script, since it's the one program known to exist.
+    let current_exe = current_exe()?;
+    let script_path = temp_dir.join(ScriptFlavor::CURRENT.file_name("hasp-self-test"));
+    fs::write(
+        &script_path,
+        ScriptFlavor::CURRENT.exec_script(&current_exe, &["--version"]),
+    )
+    .wrap_err_with(|| format!("failed to write {}", script_path))?;
+    set_executable(&script_path)?;
+
+    let output = duct::cmd!(script_path.as_std_path())
+        .read()
+        .wrap_err_with(|| format!("failed to run {}", script_path))?;
+    if !output.contains(env!("CARGO_PKG_VERSION")) {
+        bail!("unexpected output from {}: {}", script_path, output);
+    }
+    Ok(())
+}
+
+fn check_signals(temp_dir: &Utf8Path) -> Result<()> {
+    // Run a script that terminates abnormally, and check that the way it terminated is reported
+    // correctly. Batch files can't be sent signals, so check their exit code instead.
+    let script_path = temp_dir.join(ScriptFlavor::CURRENT.file_name("hasp-self-test-signal"));
+    let contents = match ScriptFlavor::CURRENT {
+        ScriptFlavor::Sh => "#!/bin/sh\nkill -9 $$\n",
+        ScriptFlavor::Cmd => "@exit /b 3\r\n",
+    };
+    fs::write(&script_path, contents)
+        .wrap_err_with(|| format!("failed to write {}", script_path))?;
+    set_executable(&script_path)?;
+
+    let output = duct::cmd!(script_path.as_std_path())
+        .unchecked()
+        .run()
+        .wrap_err_with(|| format!("failed to run {}", script_path))?;
+    let expected = match ScriptFlavor::CURRENT {
+        ScriptFlavor::Sh => (None, Some(9)),
+        ScriptFlavor::Cmd => (Some(3), None),
+    };
+    let actual = (output.status.code(), exit_signal(&output.status));
+    if actual != expected {
+        bail!(
+            "expected (exit code, signal) {:?}, found {:?}",
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
+
+fn current_exe() -> Result<Utf8PathBuf> {
+    env::current_exe()
+        .wrap_err("failed to determine path to hasp")?
+        .try_into()
+        .wrap_err("path to hasp is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_script_basic() {
+        let program = Utf8Path::new("/opt/hasp/bin/tool");
+        assert_eq!(
+            ScriptFlavor::Sh.exec_script(program, &["run"]),
+            "#!/bin/sh\nexec '/opt/hasp/bin/tool' 'run' \"$@\"\n"
+        );
+        assert_eq!(
+            ScriptFlavor::Cmd.exec_script(program, &[]),
+            "@\"/opt/hasp/bin/tool\" %*\r\n"
+        );
+        assert_eq!(ScriptFlavor::Cmd.file_name("tool"), "tool.cmd");
+
+        for flavor in [ScriptFlavor::Sh, ScriptFlavor::Cmd] {
+            let script = flavor.exec_script(program, &[]);
+            assert_eq!(
+                flavor.exec_script_program(&script).as_deref(),
+                Some(program)
+            );
+        }
+        let quoted = Utf8Path::new("/home/o'brien/$(rm -rf)/tool");
+        let script = ScriptFlavor::Sh.exec_script(quoted, &[]);
+        assert_eq!(
+            script,
+            "#!/bin/sh\nexec '/home/o'\\''brien/$(rm -rf)/tool' \"$@\"\n"
+        );
+        assert_eq!(
+            ScriptFlavor::Sh.exec_script_program(&script).as_deref(),
+            Some(quoted)
+        );
+        assert_eq!(
+            ScriptFlavor::Sh
+                .exec_script_program("#!/bin/sh\nexec \"/opt/hasp/bin/tool\" \"$@\"\n")
+                .as_deref(),
+            Some(program),
+            "shims written before paths were single-quoted are recognized"
+        );
+        assert_eq!(
+            ScriptFlavor::Sh.exec_script_program("#!/bin/sh\necho hi\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn executable_names() {
+        let windows = &[".exe", ".cmd", ".bat"];
+        assert_eq!(strip_executable_extension("rg.exe", windows), "rg");
+        assert_eq!(strip_executable_extension("Tool.EXE", windows), "Tool");
+        assert_eq!(
+            strip_executable_extension("cargo-foo.v2", windows),
+            "cargo-foo.v2"
+        );
+        assert_eq!(strip_executable_extension(".exe", windows), ".exe");
+        assert_eq!(strip_executable_extension("rg.exe", &[""]), "rg.exe");
+        assert_eq!(command_name("rg"), "rg");
+
+        assert!(ScriptFlavor::Cmd.is_file_name("tool.CMD"));
+        assert!(!ScriptFlavor::Cmd.is_file_name("tool.exe"));
+        assert!(ScriptFlavor::Sh.is_file_name("tool"));
+    }
+
+    #[test]
+    fn check_locking_basic() {
+        let temp_dir = tempfile::tempdir().expect("temp dir created");
+        let dir = Utf8Path::from_path(temp_dir.path()).expect("temp dir is UTF-8");
+        check_locking(dir).expect("locks behave as expected");
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Single values printed by `hasp --print <key>`, for use in scripts.
+//!
+//! Keys are part of hasp's interface: once a key is added, its name and the format of its value
+//! don't change.
+
+use crate::{
+    config::{split_package_key, HaspConfig},
+    database::ConnectionCreator,
+    home::HaspHome,
+    output::catalog::BUILTIN_MESSAGES,
+    platform::TARGET,
+    state::HaspState,
+};
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+use std::str::FromStr;
+
+/// A value that can be printed with `hasp --print`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum PrintKey {
+    Home,
+    BinDir,
+    CacheDir,
+    InstallsDir,
+    ConfigPath,
+    DbPath,
+    PackagesDbPath,
+    EventsDbPath,
+    HaspVersion,
+    Target,
+    /// The version of an installed package.
+    VersionOf(String),
+    /// The install directory of an installed package.
+    InstallPathOf(String),
+    /// The English message catalog.
+    Messages,
+    /// The keys themselves.
+    Keys,
+}
+
+impl PrintKey {
+    /// The names of the keys and what they print. Keys ending with `:` are followed by a package,
+    /// as `name` for Cargo packages or `namespace:name` otherwise.
+    const KEYS: &'static [(&'static str, &'static str)] = &[
+        ("home", "the hasp home directory"),
+        (
+            "bin-dir",
+            "the directory shims are written to (alias: shim-dir)",
+        ),
+        ("cache-dir", "the directory fetched data is cached in"),
+        ("installs-dir", "the directory packages are installed into"),
+        (
+            "config-path",
+            "the path to the config file, which may not exist",
+        ),
+        ("db-path", "the path to the main database"),
+        ("packages-db-path", "the path to the packages database"),
+        ("events-db-path", "the path to the events database"),
+        ("hasp-version", "the version of hasp"),
+        ("target", "the target triple hasp was built for"),
+        ("version-of:", "the installed version of a package"),
+        ("install-path-of:", "the install directory of a package"),
+        (
+            "messages",
+            "the English message catalog, to translate hasp's output from",
+        ),
+        ("keys", "these keys"),
+    ];
+
+    /// Returns the value for this key, without a trailing newline.
+    ///
+    /// Keys for packages fail if the package isn't installed.
+    pub(crate) fn value(&self, home: HaspHome) -> Result<String> {
+        let value = match self {
+            PrintKey::Home => home.home_dir().to_string(),
+            PrintKey::BinDir => home.bin_dir().to_string(),
+            PrintKey::CacheDir => home.cache_dir().to_string(),
+            PrintKey::InstallsDir => home.installs_dir().to_string(),
+            PrintKey::ConfigPath => home.home_dir().join(HaspConfig::FILE_NAME).to_string(),
+            PrintKey::DbPath => db_path(&home, "main"),
+            PrintKey::PackagesDbPath => db_path(&home, "packages"),
+            PrintKey::EventsDbPath => db_path(&home, "events"),
+            PrintKey::HaspVersion => env!("CARGO_PKG_VERSION").to_owned(),
+            PrintKey::Target => TARGET.to_owned(),
+            PrintKey::Messages => BUILTIN_MESSAGES.trim_end().to_owned(),
+            PrintKey::VersionOf(package) | PrintKey::InstallPathOf(package) => {
+                let state = HaspState::load_or_init(home)?;
+                let (namespace, name) = split_package_key(package);
+                let (installed, _lock) = match state.installed_package_locked(namespace, name)? {
+                    Some(installed) => installed,
+                    None => bail!("{} is not installed", package),
+                };
+                let directory = &installed.directory_row.package;
+                match self {
+                    PrintKey::VersionOf(_) => directory.version.short_display().to_string(),
+                    _ => state
+                        .home()
+                        .install_path(namespace, name, directory.hash)
+                        .to_string(),
+                }
+            }
+            PrintKey::Keys => Self::KEYS
+                .iter()
+                .map(|(key, description)| {
+                    let key = match key.strip_suffix(':') {
+                        Some(key) => format!("{}:<package>", key),
+                        None => key.to_string(),
+                    };
+                    format!("{:<26} {}", key, description)
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        Ok(value)
+    }
+}
+
+impl FromStr for PrintKey {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let key = match s {
+            "home" => PrintKey::Home,
+            "bin-dir" | "shim-dir" => PrintKey::BinDir,
+            "cache-dir" => PrintKey::CacheDir,
+            "installs-dir" => PrintKey::InstallsDir,
+            "config-path" => PrintKey::ConfigPath,
+            "db-path" => PrintKey::DbPath,
+            "packages-db-path" => PrintKey::PackagesDbPath,
+            "events-db-path" => PrintKey::EventsDbPath,
+            "hasp-version" => PrintKey::HaspVersion,
+            "target" => PrintKey::Target,
+            "messages" => PrintKey::Messages,
+            "keys" => PrintKey::Keys,
+            _ => match s.split_once(':') {
+                Some(("version-of", package)) if !package.is_empty() => {
+                    PrintKey::VersionOf(package.to_owned())
+                }
+                Some(("install-path-of", package)) if !package.is_empty() => {
+                    PrintKey::InstallPathOf(package.to_owned())
+                }
+                _ => {
+                    return Err(eyre!(
+                        "unknown key '{}' (hint: run `hasp --print keys` to list keys)",
+                        s
+                    ))
+                }
+            },
+        };
+        Ok(key)
+    }
+}
+
+fn db_path(home: &HaspHome, name: &str) -> String {
+    ConnectionCreator::new(home)
+        .file_path(name)
+        .expect("database names are valid")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_parse() {
+        for (key, _) in PrintKey::KEYS {
+            let key = match key.strip_suffix(':') {
+                Some(_) => format!("{}ripgrep", key),
+                None => key.to_string(),
+            };
+            key.parse::<PrintKey>()
+                .unwrap_or_else(|err| panic!("key {} parsed: {}", key, err));
+        }
+
+        assert_eq!(
+            "version-of:npm:prettier"
+                .parse::<PrintKey>()
+                .expect("key parsed"),
+            PrintKey::VersionOf("npm:prettier".to_owned())
+        );
+        assert_eq!(
+            "shim-dir".parse::<PrintKey>().expect("key parsed"),
+            PrintKey::BinDir
+        );
+        assert!("version-of:".parse::<PrintKey>().is_err());
+        assert!("nonexistent".parse::<PrintKey>().is_err());
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Running external processes, and capturing details about them if they fail.
+
+use crate::{cancel, output::progress, platform::exit_signal};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use hasp_metadata::{FailureDetails, FailureStage};
+use std::{
+    collections::VecDeque,
+    error, fmt, fs,
+    io::{self, BufRead, BufReader, Write},
+    process::{ExitStatus, Output},
+    sync::Arc,
+};
+
+/// The number of lines of standard error kept around for failure reports.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// An external process exited unsuccessfully.
+#[derive(Clone, Debug)]
+pub(crate) struct ProcessFailed {
+    pub(crate) program: String,
+    /// The exit code, or `None` if the process was terminated by a signal.
+    pub(crate) exit_code: Option<i32>,
+    /// The signal that terminated the process, on platforms that have signals.
+    pub(crate) signal: Option<i32>,
+    pub(crate) stderr_tail: Vec<String>,
+}
+
+impl ProcessFailed {
+    /// Returns an error if the exit status indicates failure.
+    pub(crate) fn check(
+        program: impl Into<String>,
+        status: &ExitStatus,
+        stderr_tail: Vec<String>,
+    ) -> Result<(), Self> {
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Self {
+                program: program.into(),
+                exit_code: status.code(),
+                signal: exit_signal(status),
+                stderr_tail,
+            })
+        }
+    }
+}
+
+impl fmt::Display for ProcessFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.exit_code, self.signal) {
+            (Some(exit_code), _) => write!(f, "{} exited with code {}", self.program, exit_code),
+            (None, Some(signal)) => {
+                write!(f, "{} was terminated by signal {}", self.program, signal)
+            }
+            (None, None) => write!(f, "{} was terminated by a signal", self.program),
+        }
+    }
+}
+
+impl error::Error for ProcessFailed {}
+
+/// A file that the output of build processes is written to, so that it can be looked at later.
+#[derive(Debug)]
+pub(crate) struct BuildLog {
+    path: Utf8PathBuf,
+    file: fs::File,
+}
+
+impl BuildLog {
+    /// The extension used for build logs.
+    pub(crate) const EXTENSION: &'static str = "log";
+
+    /// Creates a new build log at this path, creating parent directories as necessary.
+    pub(crate) fn create(path: Utf8PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("failed to create directory at {}", parent))?;
+        }
+        let file = fs::File::create(&path)
+            .wrap_err_with(|| format!("failed to create build log at {}", path))?;
+        Ok(Self { path, file })
+    }
+
+    #[inline]
+    pub(crate) fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    /// Writes a line to the log, ignoring errors.
+    pub(crate) fn write_line(&self, line: impl fmt::Display) {
+        let _ = writeln!(&self.file, "{}", line);
+    }
+
+    fn try_clone_file(&self) -> Result<fs::File> {
+        self.file
+            .try_clone()
+            .wrap_err_with(|| format!("failed to open build log at {}", self.path))
+    }
+}
+
+/// Returns the most recent build log in this directory, if any.
+///
+/// Build log names start with the time the build was started, so the most recent log sorts last.
+pub(crate) fn latest_build_log(dir: &Utf8Path) -> Result<Option<Utf8PathBuf>> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("failed to read directory {}", dir));
+        }
+    };
+
+    let mut latest: Option<String> = None;
+    for entry in entries {
+        let entry = entry.wrap_err_with(|| format!("failed to read entry in {}", dir))?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if Utf8Path::new(&name).extension() == Some(BuildLog::EXTENSION) {
+            latest = latest.max(Some(name));
+        }
+    }
+    Ok(latest.map(|name| dir.join(name)))
+}
+
+/// Echoes the standard error of a process and writes it to a build log, while keeping its last
+/// few lines around for failure reports.
+#[derive(Debug)]
+pub(crate) struct StderrTail {
+    join_handle: jod_thread::JoinHandle<Vec<String>>,
+}
+
+impl StderrTail {
+    /// Redirects standard error for this expression through a pipe.
+    ///
+    /// The returned expression holds the write end of the pipe, so it must be dropped after the
+    /// process is started -- otherwise, `finish` will never return.
+    pub(crate) fn attach(
+        expression: duct::Expression,
+        build_log: &BuildLog,
+    ) -> Result<(duct::Expression, Self)> {
+        let mut log_file = build_log.try_clone_file()?;
+        let (reader, writer) = os_pipe::pipe().wrap_err("failed to create pipe for stderr")?;
+        let join_handle = jod_thread::Builder::new()
+            .name("hasp-stderr-tail".to_owned())
+            .spawn(move || {
+                let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+                let mut reader = BufReader::new(reader);
+                let mut line = vec![];
+                // Lines may not be valid UTF-8, so read raw bytes and echo them as-is.
+                while let Ok(n) = reader.read_until(b'\n', &mut line) {
+                    if n == 0 {
+                        break;
+                    }
+                    // Output from concurrent builds would garble the progress table, and is in the
+                    // build log anyway. Ignore errors writing output -- there isn't much to be
+                    // done about them.
+                    if !progress::is_active() {
+                        let _ = io::stderr().write_all(&line);
+                    }
+                    let _ = log_file.write_all(&line);
+
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(String::from_utf8_lossy(&line).trim_end().to_owned());
+                    line.clear();
+                }
+                tail.into()
+            })
+            .wrap_err("creating stderr reader thread failed")?;
+
+        Ok((expression.stderr_file(writer), Self { join_handle }))
+    }
+
+    /// Waits for standard error to be closed, and returns its last few lines.
+    pub(crate) fn finish(self) -> Vec<String> {
+        self.join_handle.join()
+    }
+}
+
+/// Runs an expression to completion, returning a [`ProcessFailed`] error if it fails.
+///
+/// Standard output is redirected to standard error, so that it's written to the build log as
+/// well.
+pub(crate) fn run_with_stderr_tail(
+    program: &str,
+    expression: duct::Expression,
+    build_log: &BuildLog,
+) -> Result<()> {
+    run_logged(program, expression.stdout_to_stderr(), build_log)?;
+    Ok(())
+}
+
+/// Runs an expression to completion like [`run_with_stderr_tail`], but captures standard output
+/// and returns it instead of writing it to the build log.
+pub(crate) fn read_with_stderr_tail(
+    program: &str,
+    expression: duct::Expression,
+    build_log: &BuildLog,
+) -> Result<String> {
+    let output = run_logged(program, expression.stdout_capture(), build_log)?;
+    String::from_utf8(output.stdout)
+        .wrap_err_with(|| format!("output of {} is not valid UTF-8", program))
+}
+
+fn run_logged(program: &str, expression: duct::Expression, build_log: &BuildLog) -> Result<Output> {
+    build_log.write_line(format_args!("Running {}", program));
+    let (expression, stderr_tail) = StderrTail::attach(expression.unchecked(), build_log)?;
+    let handle = Arc::new(
+        expression
+            .start()
+            .wrap_err_with(|| format!("failed to run {}", program))?,
+    );
+    drop(expression);
+    let _child_guard = cancel::register_child(handle.clone());
+
+    let output = handle
+        .wait()
+        .wrap_err_with(|| format!("failed to wait for {}", program))?;
+    // If the process was killed because hasp was cancelled, report that rather than the exit
+    // status.
+    cancel::check()?;
+    ProcessFailed::check(program, &output.status, stderr_tail.finish())?;
+    Ok(output.clone())
+}
+
+/// Returns the last lines of captured standard error, for failure reports.
+pub(crate) fn stderr_tail(stderr: &[u8]) -> Vec<String> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Converts an error into failure details suitable for recording in events.
+pub(crate) fn failure_details(stage: FailureStage, err: &Report) -> FailureDetails {
+    let process_failed = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<ProcessFailed>());
+    FailureDetails {
+        stage,
+        errors: err.chain().map(|cause| cause.to_string()).collect(),
+        exit_code: process_failed.and_then(|process_failed| process_failed.exit_code),
+        stderr_tail: process_failed
+            .map(|process_failed| process_failed.stderr_tail.clone())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_details_basic() {
+        let process_failed = ProcessFailed {
+            program: "cargo build".to_owned(),
+            exit_code: Some(101),
+            signal: None,
+            stderr_tail: vec!["error: could not compile `foo`".to_owned()],
+        };
+        let err = Err::<(), _>(process_failed)
+            .wrap_err("failed to build foo")
+            .expect_err("error was returned");
+
+        let details = failure_details(FailureStage::Build, &err);
+        assert_eq!(details.stage, FailureStage::Build);
+        assert_eq!(
+            details.errors,
+            vec![
+                "failed to build foo".to_owned(),
+                "cargo build exited with code 101".to_owned()
+            ]
+        );
+        assert_eq!(details.exit_code, Some(101));
+        assert_eq!(details.stderr_tail, vec!["error: could not compile `foo`"]);
+
+        let details = failure_details(FailureStage::Finish, &Report::msg("disk full"));
+        assert_eq!(details.errors, vec!["disk full".to_owned()]);
+        assert_eq!(details.exit_code, None);
+        assert!(details.stderr_tail.is_empty());
+    }
+
+    #[test]
+    fn latest_build_log_basic() {
+        let temp_dir = tempfile::tempdir().expect("temp dir created");
+        let dir = Utf8Path::from_path(temp_dir.path()).expect("temp dir is UTF-8");
+        assert_eq!(
+            latest_build_log(&dir.join("missing")).expect("missing dir is not an error"),
+            None
+        );
+
+        for name in [
+            "20261015-090000-0123456789abcdef.log",
+            "20261016-120000-0123456789abcdef.log",
+            "20261017-000000-0123456789abcdef.txt",
+        ] {
+            fs::write(dir.join(name), "").expect("file written");
+        }
+        assert_eq!(
+            latest_build_log(dir).expect("logs read"),
+            Some(dir.join("20261016-120000-0123456789abcdef.log"))
+        );
+    }
+}
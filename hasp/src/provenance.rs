@@ -0,0 +1,152 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Recording who or what requested a package, for `hasp why`.
+//!
+//! Each directory that was requested directly records the command line, manifest or project it
+//! was requested through, along with the user and the time. A directory keeps the provenance of
+//! the request that installed it, so asking for an installed package again through a manifest
+//! doesn't change why it was installed.
+
+use crate::helpers::absolute;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local};
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use std::{env, fmt};
+
+/// Who or what requested a package.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Provenance {
+    #[serde(flatten)]
+    pub(crate) requester: Requester,
+
+    /// The user hasp was run as, if known.
+    pub(crate) user: Option<String>,
+
+    pub(crate) time: DateTime<Local>,
+}
+
+impl Provenance {
+    /// Returns the provenance of a request made now by the current user.
+    pub(crate) fn new(requester: Requester) -> Self {
+        let user = ["USER", "USERNAME"]
+            .iter()
+            .filter_map(|var| env::var(var).ok())
+            .find(|user| !user.is_empty());
+        Self {
+            requester,
+            user,
+            time: Local::now(),
+        }
+    }
+
+    /// Returns the provenance of packages requested on the command line, given hasp's arguments.
+    pub(crate) fn command_line(args: &[String]) -> Self {
+        Self::new(Requester::CommandLine {
+            command: command(args),
+        })
+    }
+
+    /// Returns the provenance of packages listed in a manifest.
+    pub(crate) fn manifest(path: &Utf8Path, args: &[String]) -> Result<Self> {
+        Ok(Self::new(Requester::Manifest {
+            path: absolute(path)?,
+            command: command(args),
+        }))
+    }
+
+    /// Returns the provenance of tools declared by a project.
+    pub(crate) fn project(path: &Utf8Path) -> Result<Self> {
+        Ok(Self::new(Requester::Project {
+            path: absolute(path)?,
+        }))
+    }
+
+    /// Returns the provenance of packages imported from `cargo install`.
+    pub(crate) fn cargo_import(cargo_home: &Utf8Path) -> Self {
+        Self::new(Requester::CargoImport {
+            cargo_home: cargo_home.to_path_buf(),
+        })
+    }
+
+    /// Serializes this as it's stored in the database.
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serializing provenance succeeds")
+    }
+
+    /// Parses provenance stored in the database.
+    pub(crate) fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).wrap_err_with(|| format!("failed to parse provenance {}", json))
+    }
+}
+
+/// What a package was requested through.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(crate) enum Requester {
+    /// A command such as `hasp install`.
+    CommandLine { command: String },
+    /// An entry in a manifest, installed through `hasp sync`.
+    Manifest { path: Utf8PathBuf, command: String },
+    /// A tool declared by a project, installed through `hasp import-cargo-lock`.
+    Project { path: Utf8PathBuf },
+    /// A package installed through `cargo install`, imported with `hasp import`.
+    #[serde(rename_all = "kebab-case")]
+    CargoImport { cargo_home: Utf8PathBuf },
+}
+
+impl fmt::Display for Requester {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Requester::CommandLine { command } => {
+                write!(f, "requested on the command line: `{}`", command)
+            }
+            Requester::Manifest { path, command } => {
+                write!(f, "listed in the manifest at {} (`{}`)", path, command)
+            }
+            Requester::Project { path } => write!(f, "declared by the project at {}", path),
+            Requester::CargoImport { cargo_home } => {
+                write!(f, "imported from `cargo install` in {}", cargo_home)
+            }
+        }
+    }
+}
+
+fn command(args: &[String]) -> String {
+    let mut command = vec!["hasp"];
+    command.extend(args.iter().map(|arg| arg.as_str()));
+    command.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provenance_roundtrip() {
+        let provenance = Provenance::cargo_import(Utf8Path::new("/home/user/.cargo"));
+        let json = provenance.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).expect("valid JSON");
+        assert_eq!(value["kind"], "cargo-import");
+        assert_eq!(value["cargo-home"], "/home/user/.cargo");
+        assert_eq!(
+            Provenance::from_json(&json).expect("provenance parsed"),
+            provenance
+        );
+
+        let provenance = Provenance::manifest(
+            Utf8Path::new("hasp.toml"),
+            &["sync".to_owned(), "hasp.toml".to_owned()],
+        )
+        .expect("manifest path made absolute");
+        match &provenance.requester {
+            Requester::Manifest { path, command } => {
+                assert!(path.is_absolute(), "{} is absolute", path);
+                assert_eq!(command, "hasp sync hasp.toml");
+            }
+            other => panic!("unexpected requester {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Install receipts.
+//!
+//! A receipt is written into every install directory, describing what was installed there. This
+//! makes install directories self-describing even without the database, so that lost rows can be
+//! reconstructed by `hasp db fsck`.
+
+use crate::lockfile::LockedDependency;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local};
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::{FileHash, PackageDirectory};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+};
+
+/// A description of an install, stored as JSON in its install directory.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct InstallReceipt {
+    /// The version of hasp that performed the install.
+    pub(crate) hasp_version: String,
+
+    /// The package that was installed, including where it came from.
+    pub(crate) package: PackageDirectory,
+
+    /// The target hasp was built for, and so the target the install is for. `None` for receipts
+    /// written before this was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) target: Option<String>,
+
+    /// Namespace-specific metadata about the install.
+    pub(crate) metadata: serde_json::Value,
+
+    /// The files in the install directory, other than the receipt itself.
+    pub(crate) files: BTreeMap<String, ReceiptFile>,
+
+    /// The dependencies the package was built with, as resolved by its lockfile.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) dependencies: Vec<LockedDependency>,
+
+    pub(crate) start_time: DateTime<Local>,
+    pub(crate) install_time: DateTime<Local>,
+}
+
+/// A file listed in an [`InstallReceipt`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ReceiptFile {
+    pub(crate) hash: FileHash,
+    pub(crate) metadata: serde_json::Value,
+    pub(crate) is_binary: bool,
+}
+
+impl InstallReceipt {
+    /// The name of the receipt file within an install directory.
+    pub(crate) const FILE_NAME: &'static str = "hasp-receipt.json";
+
+    /// Returns the path to the receipt within this install directory.
+    pub(crate) fn path(install_dir: &Utf8Path) -> Utf8PathBuf {
+        install_dir.join(Self::FILE_NAME)
+    }
+
+    /// Reads the receipt from this install directory, returning `None` if there isn't one.
+    pub(crate) fn read(install_dir: &Utf8Path) -> Result<Option<Self>> {
+        let path = Self::path(install_dir);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read receipt at {}", path))
+            }
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .wrap_err_with(|| format!("failed to parse receipt at {}", path))
+    }
+
+    /// Writes the receipt into this install directory.
+    pub(crate) fn write(&self, install_dir: &Utf8Path) -> Result<()> {
+        let path = Self::path(install_dir);
+        let contents =
+            serde_json::to_string_pretty(self).expect("serializing a receipt should never fail");
+        // Sync the receipt to disk, since it's what an install is restored from after a crash.
+        let mut file =
+            fs::File::create(&path).wrap_err_with(|| format!("failed to create {}", path))?;
+        file.write_all(contents.as_bytes())
+            .and_then(|()| file.sync_all())
+            .wrap_err_with(|| format!("failed to write receipt at {}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hasp_metadata::{DirectoryHash, DirectoryVersion};
+
+    #[test]
+    fn receipt_roundtrip() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        assert!(
+            InstallReceipt::read(dir).expect("read succeeded").is_none(),
+            "no receipt written yet"
+        );
+
+        let mut files = BTreeMap::new();
+        files.insert(
+            "foo".to_owned(),
+            ReceiptFile {
+                hash: FileHash::Blake3(blake3::hash(b"binary").into()),
+                metadata: serde_json::Value::Null,
+                is_binary: true,
+            },
+        );
+        files.insert(
+            "foo.txt".to_owned(),
+            ReceiptFile {
+                hash: FileHash::Blake3(blake3::hash(b"text").into()),
+                metadata: serde_json::Value::Null,
+                is_binary: false,
+            },
+        );
+        let now = Local::now();
+        let receipt = InstallReceipt {
+            hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+            package: PackageDirectory {
+                namespace: "cargo".to_owned(),
+                name: "foo".to_owned(),
+                version: "sem:1.0.0"
+                    .parse::<DirectoryVersion>()
+                    .expect("valid version"),
+                hash: DirectoryHash::new(0x01234567),
+                metadata: serde_json::json!({ "features": [] }),
+            },
+            target: Some("x86_64-unknown-linux-gnu".to_owned()),
+            metadata: serde_json::json!({ "env-hash": "0123456789abcdef" }),
+            files,
+            dependencies: vec![],
+            start_time: now,
+            install_time: now,
+        };
+        receipt.write(dir).expect("receipt written");
+
+        let read = InstallReceipt::read(dir)
+            .expect("read succeeded")
+            .expect("receipt exists");
+        assert_eq!(read.package.name, "foo");
+        assert_eq!(read.package.hash, receipt.package.hash);
+        assert_eq!(read.package.version, receipt.package.version);
+        assert_eq!(read.metadata, receipt.metadata);
+        assert_eq!(read.install_time, now);
+        assert!(read.files["foo"].is_binary);
+        assert_eq!(
+            read.files["foo.txt"].hash.to_string(),
+            receipt.files["foo.txt"].hash.to_string()
+        );
+    }
+}
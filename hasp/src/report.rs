@@ -0,0 +1,394 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The JSON report written when hasp finishes, with `--report` or `--ci`.
+//!
+//! The report is meant to be read by other programs, e.g. to annotate a CI run, so fields are
+//! only ever added to it.
+
+use crate::failure::FailureClass;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use hasp_metadata::{
+    redact_env_value, DirectoryVersion, Event, PackageOutcome, PackageResult, RunFinished,
+};
+use serde::Serialize;
+use std::{fmt, fs};
+
+/// A report of a hasp run.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RunReport {
+    pub(crate) hasp_version: &'static str,
+
+    /// The command-line arguments hasp was run with, not including the program name. Values of
+    /// environment variables set with `--env` are replaced by hashes of them.
+    pub(crate) args: Vec<String>,
+
+    /// The exit code, or `None` if hasp exited with an error.
+    pub(crate) exit_code: Option<i32>,
+
+    /// The error hasp exited with, if any.
+    pub(crate) error: Option<String>,
+
+    /// The overall result, for commands that install packages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) status: Option<RunStatus>,
+
+    /// The results for each package that was installed, for commands that install packages.
+    pub(crate) packages: Vec<PackageReport>,
+}
+
+/// The result of installing a single package.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PackageReport {
+    pub(crate) name: String,
+    /// The version that was installed or attempted, if one was resolved.
+    pub(crate) version: Option<DirectoryVersion>,
+    pub(crate) outcome: PackageOutcome,
+    /// The binaries that were installed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) binaries: Vec<String>,
+    /// The cause of a failure, e.g. `network` or `compile`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) failure_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) build_log: Option<Utf8PathBuf>,
+    /// Why the package was skipped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<String>,
+}
+
+impl PackageReport {
+    /// Returns the line printed for this package with `--quiet`: its name, version and outcome,
+    /// separated by spaces, e.g. `ripgrep 14.1.0 installed`. The version is `-` if none was
+    /// resolved. Scripts may parse these lines, so their format doesn't change.
+    pub(crate) fn result_line(&self) -> String {
+        let version = self.version.as_ref().map_or_else(
+            || "-".to_owned(),
+            |version| version.short_display().to_string(),
+        );
+        format!("{} {} {}", self.name, version, self.outcome.name())
+    }
+}
+
+/// The overall result of a command that installs packages.
+///
+/// The exit code follows from it: 0 for `success`, and 2 plus a bit for each class of failure
+/// for `partial` and `failure` (see [`crate::failure`]). Packages that were already installed
+/// count as successes, unless `--strict` is passed, in which case hasp exits with 1 if there
+/// were no failures.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RunStatus {
+    /// Every package was installed, was already installed or was skipped.
+    Success,
+    /// Some packages failed to install, and others were installed or already installed.
+    Partial,
+    /// Packages failed to install, and none were installed or already installed.
+    Failure,
+}
+
+impl RunStatus {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RunStatus::Success => "success",
+            RunStatus::Partial => "partial",
+            RunStatus::Failure => "failure",
+        }
+    }
+}
+
+impl fmt::Display for RunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The number of packages with each outcome.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct OutcomeCounts {
+    pub(crate) installed: usize,
+    pub(crate) already_installed: usize,
+    pub(crate) failed: usize,
+    pub(crate) skipped: usize,
+}
+
+impl OutcomeCounts {
+    pub(crate) fn total(&self) -> usize {
+        self.installed + self.already_installed + self.failed + self.skipped
+    }
+
+    pub(crate) fn status(&self) -> RunStatus {
+        if self.failed == 0 {
+            RunStatus::Success
+        } else if self.installed + self.already_installed > 0 {
+            RunStatus::Partial
+        } else {
+            RunStatus::Failure
+        }
+    }
+}
+
+impl RunReport {
+    pub(crate) fn new(args: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            hasp_version: env!("CARGO_PKG_VERSION"),
+            args: redact_args(args),
+            exit_code: None,
+            error: None,
+            status: None,
+            packages: vec![],
+        }
+    }
+
+    /// Returns the number of packages recorded with each outcome.
+    pub(crate) fn counts(&self) -> OutcomeCounts {
+        let mut counts = OutcomeCounts::default();
+        for package in &self.packages {
+            match package.outcome {
+                PackageOutcome::Installed => counts.installed += 1,
+                PackageOutcome::AlreadyInstalled => counts.already_installed += 1,
+                PackageOutcome::Failed => counts.failed += 1,
+                PackageOutcome::Skipped => counts.skipped += 1,
+            }
+        }
+        counts
+    }
+
+    /// Records the packages of a command run through `hasp tui`, which are part of this run.
+    pub(crate) fn extend(&mut self, other: RunReport) {
+        self.packages.extend(other.packages);
+    }
+
+    /// Records a package that failed to install.
+    pub(crate) fn push_failure(
+        &mut self,
+        name: &str,
+        version: Option<&DirectoryVersion>,
+        class: FailureClass,
+        error: String,
+        build_log: Option<&Utf8Path>,
+    ) {
+        self.packages.push(PackageReport {
+            name: name.to_owned(),
+            version: version.cloned(),
+            outcome: PackageOutcome::Failed,
+            binaries: vec![],
+            failure_class: Some(class.name()),
+            error: Some(error),
+            build_log: build_log.map(|path| path.to_path_buf()),
+            reason: None,
+        });
+    }
+
+    /// Records a package that was installed, or was already installed.
+    pub(crate) fn push_success(
+        &mut self,
+        name: &str,
+        version: &DirectoryVersion,
+        outcome: PackageOutcome,
+        binaries: &[String],
+    ) {
+        self.packages.push(PackageReport {
+            name: name.to_owned(),
+            version: Some(version.clone()),
+            outcome,
+            binaries: binaries.to_vec(),
+            failure_class: None,
+            error: None,
+            build_log: None,
+            reason: None,
+        });
+    }
+
+    /// Records a package that was skipped, e.g. because its manifest entry doesn't apply to this
+    /// platform.
+    pub(crate) fn push_skipped(&mut self, name: &str, reason: &str) {
+        self.packages.push(PackageReport {
+            name: name.to_owned(),
+            version: None,
+            outcome: PackageOutcome::Skipped,
+            binaries: vec![],
+            failure_class: None,
+            error: None,
+            build_log: None,
+            reason: Some(reason.to_owned()),
+        });
+    }
+
+    /// Records how hasp exited.
+    pub(crate) fn finish(&mut self, result: &Result<i32>) {
+        match result {
+            Ok(exit_code) => self.exit_code = Some(*exit_code),
+            Err(err) => self.error = Some(format!("{:#}", err)),
+        }
+        if !self.packages.is_empty() {
+            self.status = Some(self.counts().status());
+        }
+    }
+
+    /// Returns the `run_finished` event this is recorded in the journal as.
+    pub(crate) fn to_event(&self) -> Event {
+        let packages = self
+            .packages
+            .iter()
+            .map(|package| PackageResult {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                outcome: package.outcome,
+                error: package.error.clone().or_else(|| package.reason.clone()),
+            })
+            .collect();
+        Event::RunFinished(RunFinished {
+            args: self.args.clone(),
+            exit_code: self.exit_code,
+            error: self.error.clone(),
+            packages,
+        })
+    }
+
+    pub(crate) fn write(&self, path: &Utf8Path) -> Result<()> {
+        let contents =
+            serde_json::to_string_pretty(self).expect("serializing a report should never fail");
+        fs::write(path, contents).wrap_err_with(|| format!("failed to write report to {}", path))
+    }
+}
+
+/// Replaces the values of environment variables set with `--env NAME=VALUE` with hashes of them.
+/// The arguments are recorded in the report, the event journal and the provenance of packages,
+/// and the values may be secrets.
+fn redact_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    fn redact_var(var: &str) -> String {
+        match var.split_once('=') {
+            Some((name, value)) => format!("{}={}", name, redact_env_value(value)),
+            None => var.to_owned(),
+        }
+    }
+
+    let mut redacted = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            redacted.push(arg);
+            redacted.extend(args);
+            break;
+        } else if arg == "--env" {
+            redacted.push(arg);
+            if let Some(var) = args.next() {
+                redacted.push(redact_var(&var));
+            }
+        } else if let Some(var) = arg.strip_prefix("--env=") {
+            redacted.push(format!("--env={}", redact_var(var)));
+        } else {
+            redacted.push(arg);
+        }
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_serialize() {
+        let mut report = RunReport::new(vec!["install".to_owned(), "foo".to_owned()]);
+        let version: DirectoryVersion = "sem:1.0.0".parse().expect("valid version");
+        report.push_success(
+            "foo",
+            &version,
+            PackageOutcome::Installed,
+            &["foo".to_owned()],
+        );
+        report.push_failure(
+            "bar",
+            None,
+            FailureClass::Network,
+            "timed out".to_owned(),
+            None,
+        );
+        report.finish(&Ok(6));
+        assert_eq!(
+            report
+                .packages
+                .iter()
+                .map(PackageReport::result_line)
+                .collect::<Vec<_>>(),
+            ["foo 1.0.0 installed", "bar - failed"]
+        );
+
+        let value = serde_json::to_value(&report).expect("serialized");
+        assert_eq!(value["exit-code"], 6);
+        assert_eq!(value["status"], "partial");
+        assert_eq!(value["packages"][0]["outcome"], "installed");
+        assert_eq!(value["packages"][0]["binaries"][0], "foo");
+        assert_eq!(value["packages"][1]["outcome"], "failed");
+        assert_eq!(value["packages"][1]["failure-class"], "network");
+        assert!(value["packages"][1].get("binaries").is_none());
+
+        // The run_finished event records the same results.
+        let payload = report.to_event().payload().expect("payload serialized");
+        assert_eq!(payload["args"][1], "foo");
+        assert_eq!(payload["exit-code"], 6);
+        assert_eq!(payload["packages"][1]["outcome"], "failed");
+        assert_eq!(payload["packages"][1]["error"], "timed out");
+
+        let mut report = RunReport::new(vec![]);
+        report.push_skipped("baz", "target doesn't match");
+        assert_eq!(report.counts().status(), RunStatus::Success);
+        report.push_failure("bar", None, FailureClass::Other, "failed".to_owned(), None);
+        assert_eq!(report.counts().status(), RunStatus::Failure);
+        report.push_success("foo", &version, PackageOutcome::AlreadyInstalled, &[]);
+        assert_eq!(report.counts().status(), RunStatus::Partial);
+        report.finish(&Ok(2));
+        assert_eq!(report.status, Some(RunStatus::Partial));
+        assert_eq!(report.counts().total(), 3);
+
+        let report = RunReport::new(vec!["export".to_owned()]);
+        let value = serde_json::to_value(&report).expect("serialized");
+        assert!(value.get("status").is_none());
+    }
+
+    #[test]
+    fn redact_env_args() {
+        let args = [
+            "install",
+            "--env",
+            "TOKEN=secret",
+            "--env=RUSTFLAGS=-C opt-level=3",
+            "--env",
+            "EMPTY=",
+            "foo",
+            "--",
+            "--env",
+            "ARG=kept",
+        ];
+        let report = RunReport::new(args.iter().map(|arg| (*arg).to_owned()));
+        let hashed = |value: &str| format!("blake3:{}", blake3::hash(value.as_bytes()).to_hex());
+        assert_eq!(
+            report.args,
+            vec![
+                "install".to_owned(),
+                "--env".to_owned(),
+                format!("TOKEN={}", hashed("secret")),
+                format!("--env=RUSTFLAGS={}", hashed("-C opt-level=3")),
+                "--env".to_owned(),
+                format!("EMPTY={}", hashed("")),
+                "foo".to_owned(),
+                "--".to_owned(),
+                "--env".to_owned(),
+                "ARG=kept".to_owned(),
+            ]
+        );
+        assert!(
+            !serde_json::to_string(&report)
+                .expect("serialized")
+                .contains("secret"),
+            "the value isn't in the report"
+        );
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Running installed binaries directly, for `hasp run`.
+//!
+//! Binaries are looked up through the installed files recorded in the packages database, so the
+//! bin directory doesn't need to be on `PATH`. If several packages provide a binary, the one its
+//! shim points to is run, unless a package is picked with `--package`.
+//!
+//! `hasp shell` instead starts a subshell with the bin directory, or the install directories of
+//! specific packages, at the front of `PATH`. This makes it possible to use a particular version of
+//! a tool for a while without changing which version the shims point to.
+
+use crate::{
+    config::{package_key, split_package_key},
+    models::directory::InstalledRow,
+    platform::{command_name, ScriptFlavor},
+    shims::shim_path,
+    state::HaspState,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use hasp_metadata::DirectoryVersion;
+use semver::VersionReq;
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    process::Command,
+    str::FromStr,
+};
+
+/// A package passed to `hasp run --package`, as `[namespace:]name[@version]`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct PackageFilter {
+    namespace: String,
+    name: String,
+    version: Option<String>,
+}
+
+impl PackageFilter {
+    fn matches(&self, installed: &InstalledRow) -> bool {
+        let package = &installed.directory_row.package;
+        package.namespace == self.namespace
+            && package.name == self.name
+            && self
+                .version
+                .as_deref()
+                .is_none_or(|version| version_matches(version, &package.version))
+    }
+}
+
+impl fmt::Display for PackageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", package_key(&self.namespace, &self.name))?;
+        if let Some(version) = &self.version {
+            write!(f, "@{}", version)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PackageFilter {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (namespace, rest) = split_package_key(s);
+        // A leading `@` is part of the name, as in `npm:@scope/name@1.0`.
+        let (name, version) = match rest.char_indices().skip(1).find(|&(_, c)| c == '@') {
+            Some((idx, _)) => (&rest[..idx], Some(rest[idx + 1..].to_owned())),
+            None => (rest, None),
+        };
+        if name.is_empty() || version.as_deref() == Some("") {
+            bail!(
+                "invalid package '{}' (hint: packages look like ripgrep@13 or npm:prettier)",
+                s
+            );
+        }
+        Ok(Self {
+            namespace: namespace.to_owned(),
+            name: name.to_owned(),
+            version,
+        })
+    }
+}
+
+/// Returns true if an installed version matches the version given on the command line: as a
+/// version requirement for semantic versions, and exactly otherwise. Floating versions such as
+/// image tags match either the tag or what it resolved to.
+fn version_matches(version: &str, installed: &DirectoryVersion) -> bool {
+    match installed {
+        DirectoryVersion::Semantic(semantic) => match version.parse::<VersionReq>() {
+            Ok(req) => req.matches(semantic),
+            Err(_) => false,
+        },
+        DirectoryVersion::Literal(literal) => literal == version,
+        DirectoryVersion::Floating(floating) => {
+            floating.spec() == version || floating.resolved() == version
+        }
+    }
+}
+
+/// Returns the path to an installed binary, picking the package that provides it as described in
+/// the module documentation.
+pub(crate) fn find_binary(
+    state: &HaspState,
+    binary: &str,
+    filter: Option<&PackageFilter>,
+) -> Result<Utf8PathBuf> {
+    // Only the most recent install of each package that matches the filter is considered, so
+    // that the filter can pick an older version.
+    let latest: BTreeMap<_, _> = state
+        .installed_packages()?
+        .into_iter()
+        .filter(|installed| filter.is_none_or(|filter| filter.matches(installed)))
+        .map(|installed| {
+            let package = &installed.directory_row.package;
+            (package_key(&package.namespace, &package.name), installed)
+        })
+        .collect();
+    let candidates: Vec<_> = latest
+        .values()
+        .filter_map(|installed| {
+            let file_name = installed
+                .binary_names()
+                .find(|name| binary_matches(name, binary))?;
+            let package = &installed.directory_row.package;
+            let path = state
+                .home()
+                .install_path(&package.namespace, &package.name, package.hash)
+                .join(file_name);
+            Some((installed, path))
+        })
+        .collect();
+
+    match candidates.as_slice() {
+        [] => match filter {
+            Some(filter) => bail!(
+                "no installed package matching {} provides binary {} (hint: run `hasp info \
+                 <package>` to list the binaries of a package)",
+                filter,
+                binary
+            ),
+            None => bail!("no installed package provides binary {}", binary),
+        },
+        [(_, path)] => Ok(path.clone()),
+        _ => {
+            // Prefer the binary the shim runs, as that's what running it from PATH would do.
+            let shim_path = shim_path(state.home().bin_dir(), binary);
+            if let Ok(contents) = fs::read_to_string(&shim_path) {
+                if let Some(program) = ScriptFlavor::CURRENT.exec_script_program(&contents) {
+                    if let Some((_, path)) = candidates.iter().find(|(_, path)| *path == program) {
+                        return Ok(path.clone());
+                    }
+                }
+            }
+            let packages: Vec<_> = candidates
+                .iter()
+                .map(|(installed, _)| {
+                    let package = &installed.directory_row.package;
+                    format!(
+                        "{}@{}",
+                        package_key(&package.namespace, &package.name),
+                        package.version.short_display()
+                    )
+                })
+                .collect();
+            bail!(
+                "binary {} is provided by several packages: {} (hint: pass --package to pick one)",
+                binary,
+                packages.join(", ")
+            )
+        }
+    }
+}
+
+/// Returns true if `file_name` is the binary called `binary`, with or without an extension like
+/// `.exe`.
+fn binary_matches(file_name: &str, binary: &str) -> bool {
+    file_name == binary || command_name(file_name) == binary
+}
+
+/// Returns the install directories of the packages passed to `hasp shell`, in the order given.
+///
+/// If several installed versions of a package match, the most recently installed one is picked.
+pub(crate) fn package_dirs(
+    state: &HaspState,
+    filters: &[PackageFilter],
+) -> Result<Vec<Utf8PathBuf>> {
+    let installed = state.installed_packages()?;
+    filters
+        .iter()
+        .map(|filter| {
+            // Installs are ordered oldest first within each package.
+            let installed = match installed.iter().rev().find(|row| filter.matches(row)) {
+                Some(installed) => installed,
+                None => bail!(
+                    "no installed package matches {} (hint: run `hasp list` to see installed \
+                     packages)",
+                    filter
+                ),
+            };
+            let package = &installed.directory_row.package;
+            Ok(state
+                .home()
+                .install_path(&package.namespace, &package.name, package.hash))
+        })
+        .collect()
+}
+
+/// Starts the user's shell with these directories prepended to `PATH`, returning its exit code.
+///
+/// `HASP_SHELL` is set in the shell so that prompts and scripts can tell they're running in one,
+/// and `HASP_SHELL_PACKAGES` lists the packages if any were picked.
+pub(crate) fn shell(dirs: &[Utf8PathBuf], packages: &[PackageFilter]) -> Result<i32> {
+    let program = shell_program();
+    let path = shell_path(dirs, env::var_os("PATH").as_deref())?;
+    let mut command = Command::new(&program);
+    command.env("PATH", path).env("HASP_SHELL", "1");
+    if packages.is_empty() {
+        command.env_remove("HASP_SHELL_PACKAGES");
+    } else {
+        let packages: Vec<_> = packages.iter().map(|filter| filter.to_string()).collect();
+        command.env("HASP_SHELL_PACKAGES", packages.join(" "));
+    }
+    exec_command(command, &program)
+}
+
+/// Returns the shell to start: `SHELL` on Unix and `COMSPEC` on Windows, with a fallback.
+fn shell_program() -> Utf8PathBuf {
+    let (env_var, fallback) = if cfg!(windows) {
+        ("COMSPEC", "cmd.exe")
+    } else {
+        ("SHELL", "sh")
+    };
+    match env::var(env_var) {
+        Ok(program) if !program.is_empty() => program.into(),
+        _ => fallback.into(),
+    }
+}
+
+/// Returns `PATH` with these directories prepended, in order.
+fn shell_path(dirs: &[Utf8PathBuf], path: Option<&OsStr>) -> Result<OsString> {
+    let existing = path.into_iter().flat_map(env::split_paths);
+    let dirs = dirs.iter().map(|dir| dir.as_std_path().to_owned());
+    env::join_paths(dirs.chain(existing)).wrap_err(
+        "failed to add directories to PATH (hint: directories can't contain the PATH separator)",
+    )
+}
+
+/// Runs a binary with these arguments, returning its exit code.
+///
+/// On Unix, hasp is replaced by the binary, so this only returns if it couldn't be run.
+pub(crate) fn exec(program: &Utf8Path, args: &[OsString]) -> Result<i32> {
+    let mut command = Command::new(program);
+    command.args(args);
+    exec_command(command, program)
+}
+
+fn exec_command(mut command: Command, program: &Utf8Path) -> Result<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        let err = command.exec();
+        Err(err).wrap_err_with(|| format!("failed to run {}", program))
+    }
+    #[cfg(not(unix))]
+    {
+        let status = command
+            .status()
+            .wrap_err_with(|| format!("failed to run {}", program))?;
+        Ok(status.code().unwrap_or(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::directory::DirectoryRow, platform::EXECUTABLE_EXTENSIONS, test_helpers::TestHome,
+    };
+
+    #[test]
+    fn package_filter_parse() {
+        let filter: PackageFilter = "ripgrep@13".parse().expect("filter parsed");
+        assert_eq!(
+            filter,
+            PackageFilter {
+                namespace: "cargo".to_owned(),
+                name: "ripgrep".to_owned(),
+                version: Some("13".to_owned()),
+            }
+        );
+        assert_eq!(filter.to_string(), "ripgrep@13");
+        let filter: PackageFilter = "npm:@scope/name".parse().expect("filter parsed");
+        assert_eq!(filter.namespace, "npm");
+        assert_eq!(filter.name, "@scope/name");
+        assert_eq!(filter.version, None);
+        assert!("ripgrep@".parse::<PackageFilter>().is_err());
+        assert!("npm:".parse::<PackageFilter>().is_err());
+
+        let semantic: DirectoryVersion = "sem:13.0.0".parse().expect("valid version");
+        assert!(version_matches("13", &semantic));
+        assert!(version_matches("=13.0.0", &semantic));
+        assert!(!version_matches("12", &semantic));
+        let literal = DirectoryVersion::new_literal("v2.8.0");
+        assert!(version_matches("v2.8.0", &literal));
+        assert!(!version_matches("2", &literal));
+        let floating = DirectoryVersion::new_floating("latest", "sha256:0123");
+        assert!(version_matches("latest", &floating));
+        assert!(version_matches("sha256:0123", &floating));
+
+        assert!(binary_matches(
+            &format!("rg{}", EXECUTABLE_EXTENSIONS[0]),
+            "rg"
+        ));
+        assert!(!binary_matches("rga", "rg"));
+    }
+
+    #[test]
+    fn shell_path_prepends() {
+        let dirs = vec![Utf8PathBuf::from("/a"), Utf8PathBuf::from("/b")];
+        let existing = env::join_paths(["/usr/bin", "/bin"]).expect("paths joined");
+        let path = shell_path(&dirs, Some(&existing)).expect("path built");
+        let split: Vec<_> = env::split_paths(&path).collect();
+        assert_eq!(
+            split,
+            ["/a", "/b", "/usr/bin", "/bin"].map(std::path::PathBuf::from)
+        );
+        let path = shell_path(&dirs, None).expect("path built");
+        assert_eq!(env::split_paths(&path).count(), 2);
+    }
+
+    #[test]
+    fn find_binary_filtered() {
+        let test_home = TestHome::new();
+        let v13 = test_home.insert_install("ripgrep", "sem:13.0.0", 0x13, &["rg"]);
+        let v14 = test_home.insert_install("ripgrep", "sem:14.0.0", 0x14, &["rg"]);
+        let state = HaspState::load_or_init(test_home.hasp_home.clone()).expect("state loaded");
+        let path_for = |row: &DirectoryRow| {
+            let package = &row.package;
+            state
+                .home()
+                .install_path(&package.namespace, &package.name, package.hash)
+                .join("rg")
+        };
+
+        assert_eq!(
+            find_binary(&state, "rg", None).expect("binary found"),
+            path_for(&v14),
+            "the most recent install is picked"
+        );
+        let filter: PackageFilter = "ripgrep@13".parse().expect("filter parsed");
+        assert_eq!(
+            find_binary(&state, "rg", Some(&filter)).expect("binary found"),
+            path_for(&v13),
+            "an older install matching the filter is picked"
+        );
+        let filter: PackageFilter = "ripgrep@12".parse().expect("filter parsed");
+        let err = find_binary(&state, "rg", Some(&filter)).expect_err("no install matches");
+        assert!(
+            err.to_string()
+                .starts_with("no installed package matching ripgrep@12"),
+            "{}",
+            err
+        );
+    }
+}
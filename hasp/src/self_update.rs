@@ -0,0 +1,70 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Updating hasp itself, for `hasp self update`.
+//!
+//! The new version is installed like any other Cargo package, so it's built with the same settings
+//! and kept in the hasp home. Its binary is then copied next to the running binary and renamed
+//! over it.
+
+use crate::platform::{replace_current_exe, set_executable};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use semver::Version;
+use std::{env, fs};
+
+/// The crate hasp is published as.
+pub(crate) const SELF_CRATE: &str = "hasp";
+
+/// Returns the version of hasp that's running.
+pub(crate) fn current_version() -> Version {
+    env!("CARGO_PKG_VERSION")
+        .parse()
+        .expect("package version is valid semver")
+}
+
+/// Returns the path to the running hasp binary.
+pub(crate) fn current_exe() -> Result<Utf8PathBuf> {
+    let exe = env::current_exe().wrap_err("failed to find the running hasp binary")?;
+    Utf8PathBuf::from_path_buf(exe).map_err(|exe| eyre!("{} is not valid UTF-8", exe.display()))
+}
+
+/// Replaces the binary at `exe`, which is running, with a copy of `binary`.
+pub(crate) fn replace_binary(binary: &Utf8Path, exe: &Utf8Path) -> Result<()> {
+    // Renames are only atomic within a file system, so the copy is staged next to the binary.
+    let staged = Utf8PathBuf::from(format!("{}.new", exe));
+    fs::copy(binary, &staged)
+        .wrap_err_with(|| format!("failed to copy {} to {}", binary, staged))?;
+    let res = set_executable(&staged).and_then(|()| replace_current_exe(&staged, exe));
+    if res.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_binary_basic() {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let dir = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let binary = dir.join("installed");
+        let exe = dir.join("hasp");
+        fs::write(&binary, "new").expect("binary written");
+        fs::write(&exe, "old").expect("exe written");
+
+        replace_binary(&binary, &exe).expect("binary replaced");
+        assert_eq!(fs::read_to_string(&exe).expect("exe read"), "new");
+        assert!(binary.exists(), "installed binary is copied, not moved");
+        assert!(
+            !Utf8PathBuf::from(format!("{}.new", exe)).exists(),
+            "staged copy was renamed"
+        );
+        assert!(current_version() > Version::new(0, 0, 0));
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Shims for installed binaries.
+//!
+//! A shim is a small script in the hasp bin directory that executes a binary within an install
+//! directory. Putting the bin directory on `PATH` makes every installed binary available.
+
+use crate::{
+    helpers::write_script,
+    platform::{command_name, ScriptFlavor},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use std::{fs, io};
+
+/// Writes out shims in `bin_dir` for each of the binaries in `install_path`, returning the paths
+/// to the shims.
+///
+/// Existing shims with the same names are overwritten.
+pub(crate) fn write_shims<'a>(
+    bin_dir: &Utf8Path,
+    install_path: &Utf8Path,
+    binaries: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Utf8PathBuf>> {
+    binaries
+        .into_iter()
+        .map(|binary| {
+            let shim_path = shim_path(bin_dir, binary);
+            write_script(&shim_path, &shim_contents(&install_path.join(binary)))?;
+            Ok(shim_path)
+        })
+        .collect()
+}
+
+/// Returns the path to the shim for this binary.
+pub(crate) fn shim_path(bin_dir: &Utf8Path, binary: &str) -> Utf8PathBuf {
+    match ScriptFlavor::CURRENT {
+        ScriptFlavor::Sh => bin_dir.join(binary),
+        // Replace any extension like .exe with .cmd.
+        ScriptFlavor::Cmd => bin_dir.join(ScriptFlavor::Cmd.file_name(command_name(binary))),
+    }
+}
+
+fn shim_contents(target: &Utf8Path) -> String {
+    ScriptFlavor::CURRENT.exec_script(target, &[])
+}
+
+/// Removes shims in `bin_dir` for each of the binaries in `install_path`, returning the paths to
+/// the removed shims.
+///
+/// Shims that point to a different install directory are left alone.
+pub(crate) fn remove_shims<'a>(
+    bin_dir: &Utf8Path,
+    install_path: &Utf8Path,
+    binaries: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Utf8PathBuf>> {
+    let mut removed = vec![];
+    for binary in binaries {
+        let shim_path = shim_path(bin_dir, binary);
+        let contents = match fs::read_to_string(&shim_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read shim at {}", shim_path))
+            }
+        };
+        // Compare the programs rather than the contents, so that shims written by older versions
+        // of hasp are recognized.
+        if ScriptFlavor::CURRENT.exec_script_program(&contents) != Some(install_path.join(binary)) {
+            continue;
+        }
+
+        fs::remove_file(&shim_path)
+            .wrap_err_with(|| format!("failed to remove shim at {}", shim_path))?;
+        removed.push(shim_path);
+    }
+    Ok(removed)
+}
+
+/// Returns the shims in `bin_dir` that run binaries within `installs_dir` that don't exist, along
+/// with the binaries they run.
+///
+/// Other files in `bin_dir` are left alone.
+pub(crate) fn find_broken_shims(
+    bin_dir: &Utf8Path,
+    installs_dir: &Utf8Path,
+) -> Result<Vec<(Utf8PathBuf, Utf8PathBuf)>> {
+    let entries = bin_dir
+        .read_dir()
+        .wrap_err_with(|| format!("failed to read directory {}", bin_dir))?;
+    let mut broken = vec![];
+    for entry in entries {
+        let entry = entry.wrap_err_with(|| format!("failed to read directory {}", bin_dir))?;
+        let shim_path = match entry.file_name().into_string() {
+            Ok(name) if ScriptFlavor::CURRENT.is_file_name(&name) => bin_dir.join(name),
+            _ => continue,
+        };
+        // Shims are small, so anything that can't be read as a string isn't a shim.
+        let contents = match fs::read_to_string(&shim_path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        if let Some(target) = ScriptFlavor::CURRENT.exec_script_program(&contents) {
+            if target.starts_with(installs_dir) && !target.exists() {
+                broken.push((shim_path, target));
+            }
+        }
+    }
+    broken.sort();
+    Ok(broken)
+}
@@ -2,15 +2,54 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::{
-    database::{ConnectionCreator, DbContext},
-    events::EventLogger,
+    cancel,
+    config::package_key,
+    database::{ConnectionCreator, CorruptedFile, DbContext},
+    events::{
+        self, prune_events, read_events, EventLogger, JournalEntry, PruneSummary, RetentionPolicy,
+    },
+    failure::{describe_reason, FailureClass},
+    hints::HintList,
     home::HaspHome,
-    ops::{CargoMatcher, InstallStatus, PackageMatcher},
+    lockfile::LockedDependency,
+    models::{
+        directory::{DirectoryRow, InstalledRow},
+        install_dependency::InstallDependencyRow,
+        namespace::NamespaceRow,
+        trusted_source::TrustedSourceRow,
+    },
+    ops::{
+        AvailableUpdate, CargoAdoptFetcher, CargoBuildOpts, CargoFileFetcher, CargoMatcher,
+        CargoPathFetcher, CleanedDir, FsckSummary, InstallChecker, InstallReadLock, InstallStatus,
+        JournalRecovery, NpmMatcher, OciMatcher, PackageCleaner, PackageFetcher,
+        PackageFetcherImpl, PackageMatcher, PackageMatcherImpl, PackageUninstaller, PluginBackend,
+        PluginMatcher, PreviousInstalls, RolledBack,
+    },
     output::OutputOpts,
+    provenance::Provenance,
+    trust,
 };
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::{eyre::WrapErr, Result};
-use hasp_metadata::{CargoDirectory, DirectoryVersionReq};
+use futures::Stream;
+use hasp_metadata::{
+    CargoDirectory, Checksum, DirectoryVersion, DirectoryVersionReq, Event, LifecycleEvent,
+    NotInstalledDirectory, NpmDirectory, OciDirectory, PackageDirectory, ResolvedPackage,
+    SelfUpdated,
+};
+use semver::{Version, VersionReq};
+use std::{collections::HashMap, future::Future, time::Duration};
+
+/// The delay before attempting a failed install again. The delay doubles for each subsequent
+/// attempt, up to [`INSTALL_RETRY_BACKOFF_MAX`].
+const INSTALL_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+/// The longest delay between attempts at installing a package.
+const INSTALL_RETRY_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// How long to wait for a newer version of an installed package to be resolved, for the hint
+/// shown when it's already installed.
+const AVAILABLE_UPDATE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone, Debug)]
 pub(crate) struct HaspState {
@@ -19,68 +58,1320 @@ pub(crate) struct HaspState {
 }
 
 impl HaspState {
-    pub(crate) fn load_or_init() -> Result<Self> {
-        let hasp_home = HaspHome::discover()?;
-        Self::load_or_init_impl(hasp_home)
-    }
-
-    #[allow(dead_code)]
-    pub(crate) fn load_or_init_at(home_dir: impl Into<Utf8PathBuf>) -> Result<Self> {
-        let hasp_home = HaspHome::new(home_dir.into())?;
+    pub(crate) fn load_or_init(hasp_home: HaspHome) -> Result<Self> {
         Self::load_or_init_impl(hasp_home)
     }
 
     fn load_or_init_impl(home: HaspHome) -> Result<Self> {
-        let creator = ConnectionCreator::new(&home.home_dir());
+        let creator = ConnectionCreator::new(&home);
         let event_logger = EventLogger::new(&creator)?;
 
         // Run an initial create to initialize everything.
         creator
             .initialize(&event_logger)
             .wrap_err_with(|| format!("initializing database at {} failed", home.home_dir()))?;
-        Ok(Self {
+        event_logger.start_run();
+        let state = Self {
             home,
             ctx: DbContext {
                 creator,
                 event_logger,
             },
+        };
+
+        // Enforce the retention policy for the journal, if there is one. Failing to do so isn't
+        // fatal: it'll be tried again next time.
+        let retention = state.home.config().events.retention();
+        if !retention.is_unlimited() {
+            if let Err(err) = state.prune_events(retention, false) {
+                let error = format!("{:#}", err);
+                tracing::debug!(
+                    target: "hasp::output::recording::prune_failed",
+                    %error,
+                    "Failed to prune events: {error}",
+                );
+            }
+        }
+        Ok(state)
+    }
+
+    /// Removes events from the journal according to the policy, recording what was removed as an
+    /// `events_pruned` event.
+    pub(crate) fn prune_events(
+        &self,
+        policy: RetentionPolicy,
+        vacuum: bool,
+    ) -> Result<PruneSummary> {
+        let summary = prune_events(&self.ctx.creator, policy, vacuum)?;
+        if summary.pruned() > 0 || summary.vacuumed {
+            self.ctx.event_logger.log(summary.to_event());
+        }
+        Ok(summary)
+    }
+
+    /// Installs a package. If the install fails for a reason that might not happen again, like a
+    /// network failure, it's attempted again up to `request.retries` times, with backoff.
+    /// `provenance` is recorded for packages that are requested directly.
+    pub(crate) async fn install_package(
+        &self,
+        request: InstallRequest,
+        provenance: &Provenance,
+        build_opts: CargoBuildOpts,
+        output_opts: OutputOpts,
+    ) -> Result<InstallStatus> {
+        let namespace = request.namespace();
+        let name = request.name.clone();
+        self.print_hint(namespace, &name);
+
+        let status = retry_install(
+            &package_key(namespace, &name),
+            request.retries,
+            INSTALL_RETRY_BACKOFF,
+            || self.try_install_package(request.clone(), build_opts.clone(), output_opts),
+        )
+        .await?;
+        let status = match status {
+            InstallStatus::Success {
+                version,
+                binaries,
+                replaced,
+            } => {
+                self.record_req(namespace, &name, &version, &request.req)?;
+                InstallStatus::Success {
+                    version,
+                    binaries,
+                    replaced,
+                }
+            }
+            InstallStatus::AlreadyInstalled { version, .. } => {
+                let available = self
+                    .available_update(&request, &version, build_opts, output_opts)
+                    .await;
+                InstallStatus::AlreadyInstalled { version, available }
+            }
+            status => status,
+        };
+        if request.explicit {
+            self.mark_explicit(namespace, &name, &status, provenance)?;
+        }
+        Ok(status)
+    }
+
+    /// Records the requirement an installed version was resolved from, so that later installs can
+    /// check for newer versions matching it.
+    fn record_req(
+        &self,
+        namespace: &str,
+        name: &str,
+        version: &DirectoryVersion,
+        req: &DirectoryVersionReq,
+    ) -> Result<()> {
+        let mut conn = self.ctx.creator.create()?;
+        let txn = self.ctx.creator.write_transaction(&mut conn)?;
+        for row in DirectoryRow::all_matches_for_version(namespace, name, version, &txn)? {
+            if row.get_installed(&txn)? {
+                row.set_req(&txn, req)?;
+            }
+        }
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "failed to commit requirement for {}:{} (version {})",
+                namespace, name, version
+            )
         })
     }
 
-    pub(crate) async fn cargo_install(
+    /// Starts checking in the background for the newest version matching the requirement an
+    /// installed version was resolved from, which is reported if it's newer than the installed
+    /// version.
+    ///
+    /// This is only a hint, so hooks aren't run, failures are logged rather than returned, and
+    /// resolution is given up on if it doesn't finish quickly.
+    async fn available_update(
+        &self,
+        request: &InstallRequest,
+        installed: &DirectoryVersion,
+        build_opts: CargoBuildOpts,
+        output_opts: OutputOpts,
+    ) -> AvailableUpdate {
+        let installed_semver = match installed.as_semantic() {
+            Some(version) if !build_opts.offline => version.clone(),
+            _ => return AvailableUpdate::default(),
+        };
+        let key = package_key(request.namespace(), &request.name);
+        let original_req = self
+            .original_req(request.namespace(), &request.name, installed)
+            .await
+            .map_err(|err| {
+                let error = format!("{:#}", err);
+                tracing::debug!(
+                    target: "hasp::output::working::original_req_failed",
+                    %key,
+                    %error,
+                    "failed to get original requirement for {key}: {error}",
+                )
+            })
+            .ok()
+            .flatten();
+        let req = original_req.unwrap_or_else(|| request.req.clone());
+        let name = request.name.clone();
+        let resolver = self
+            .make_matcher(request.metadata.clone(), build_opts)
+            .make_resolver();
+
+        AvailableUpdate::spawn(async move {
+            let resolved = tokio::time::timeout(
+                AVAILABLE_UPDATE_TIMEOUT,
+                resolver.resolve(name, req.clone(), output_opts),
+            )
+            .await;
+            match resolved {
+                Ok(Ok(fetcher)) => {
+                    let version = fetcher.version();
+                    version
+                        .as_semantic()
+                        .filter(|version| *version > &installed_semver)
+                        .map(|_| version.clone())
+                }
+                Ok(Err(err)) => {
+                    let error = format!("{:#}", err);
+                    tracing::debug!(
+                        target: "hasp::output::working::available_update_failed",
+                        %key,
+                        %req,
+                        %error,
+                        "failed to check for newer versions of {key} matching {req}: {error}",
+                    );
+                    None
+                }
+                Err(_) => {
+                    tracing::debug!(
+                        target: "hasp::output::working::available_update_timed_out",
+                        %key,
+                        %req,
+                        "timed out checking for newer versions of {key} matching {req}",
+                    );
+                    None
+                }
+            }
+        })
+    }
+
+    /// Returns the requirement an installed version was resolved from, if it was recorded.
+    async fn original_req(
+        &self,
+        namespace: &str,
+        name: &str,
+        version: &DirectoryVersion,
+    ) -> Result<Option<DirectoryVersionReq>> {
+        let (namespace, name, version) = (namespace.to_owned(), name.to_owned(), version.clone());
+        self.ctx
+            .creator
+            .run(move |conn| {
+                for row in DirectoryRow::all_matches_for_version(&namespace, &name, &version, conn)?
+                {
+                    if row.get_installed(conn)? {
+                        if let Some(req) = row.get_req(conn)? {
+                            return Ok(Some(req));
+                        }
+                    }
+                }
+                Ok(None)
+            })
+            .await
+    }
+
+    /// Makes a single attempt at installing a package.
+    async fn try_install_package(
+        &self,
+        request: InstallRequest,
+        build_opts: CargoBuildOpts,
+        output_opts: OutputOpts,
+    ) -> Result<InstallStatus> {
+        if let PackageMetadata::Plugin(backend) = &request.metadata {
+            self.record_namespace(backend.namespace())?;
+        }
+        let matcher = self.make_matcher(request.metadata, build_opts);
+        self.install(
+            matcher,
+            request.name,
+            request.req,
+            request.checksum,
+            InstallSource::Resolve {
+                update: request.update,
+            },
+            request.force_bin_overwrite,
+            output_opts,
+        )
+        .await
+    }
+
+    /// Resolves a package to the version that would be installed, without fetching or installing
+    /// anything.
+    pub(crate) async fn resolve(
+        &self,
+        request: InstallRequest,
+        build_opts: CargoBuildOpts,
+        output_opts: OutputOpts,
+    ) -> Result<ResolvedPackage> {
+        let namespace = request.namespace();
+        let matcher = PackageMatcher::new(
+            self.home.clone(),
+            self.make_matcher(request.metadata, build_opts),
+            request.name.clone(),
+            request.req.clone(),
+            request.checksum,
+            output_opts,
+            self.ctx.clone(),
+        );
+        let fetcher = matcher.make_resolver().make_fetcher().await?;
+        Ok(ResolvedPackage {
+            namespace: namespace.to_owned(),
+            name: request.name,
+            req: request.req.as_str().to_owned(),
+            version: fetcher.version().clone(),
+            download_url: fetcher.download_url(),
+        })
+    }
+
+    fn make_matcher(
+        &self,
+        metadata: PackageMetadata,
+        build_opts: CargoBuildOpts,
+    ) -> Box<dyn PackageMatcherImpl> {
+        match metadata {
+            PackageMetadata::Cargo(metadata) => Box::new(CargoMatcher::new(
+                metadata,
+                build_opts,
+                self.ctx.creator.clone(),
+            )),
+            PackageMetadata::Npm(metadata) => Box::new(NpmMatcher::new(metadata)),
+            PackageMetadata::Oci(metadata) => Box::new(OciMatcher::new(metadata)),
+            PackageMetadata::Plugin(backend) => Box::new(PluginMatcher::new(backend)),
+        }
+    }
+
+    /// Records binaries previously installed with `cargo install` as an installation of this
+    /// exact version.
+    pub(crate) async fn cargo_adopt(
         &self,
         name: impl Into<String>,
-        req: DirectoryVersionReq,
+        version: Version,
         metadata: CargoDirectory,
+        binaries: Vec<Utf8PathBuf>,
+        provenance: &Provenance,
+        output_opts: OutputOpts,
+    ) -> Result<InstallStatus> {
+        let req = VersionReq::parse(&format!("={}", version))
+            .expect("exact version requirement is valid");
+        let fetcher = CargoAdoptFetcher::new(version, metadata.clone(), binaries);
+        let matcher = CargoMatcher::new(
+            metadata,
+            CargoBuildOpts::default(),
+            self.ctx.creator.clone(),
+        );
+        let name = name.into();
+        let status = self
+            .install(
+                Box::new(matcher),
+                name.clone(),
+                req.into(),
+                None,
+                InstallSource::Fetcher(Box::new(fetcher)),
+                false,
+                output_opts,
+            )
+            .await?;
+        // Packages installed through `cargo install` were requested directly.
+        self.mark_explicit("cargo", &name, &status, provenance)?;
+        Ok(status)
+    }
+
+    /// Installs a crate from a `.crate` file, skipping resolution and fetching.
+    pub(crate) async fn cargo_install_file(
+        &self,
+        fetcher: CargoFileFetcher,
+        force_bin_overwrite: bool,
+        provenance: &Provenance,
+        output_opts: OutputOpts,
+    ) -> Result<InstallStatus> {
+        let name = fetcher.name().to_owned();
+        let req = fetcher.req();
+        let checksum = self
+            .home
+            .config()
+            .package("cargo", &name)
+            .and_then(|package_config| package_config.checksum.clone());
+        let matcher = fetcher.matcher(self.ctx.creator.clone());
+        self.cargo_install_fetched(
+            matcher,
+            name,
+            req.into(),
+            checksum,
+            Box::new(fetcher),
+            force_bin_overwrite,
+            provenance,
+            output_opts,
+        )
+        .await
+    }
+
+    /// Builds a package in a local directory, skipping resolution and fetching.
+    pub(crate) async fn cargo_install_path(
+        &self,
+        fetcher: CargoPathFetcher,
+        force_bin_overwrite: bool,
+        provenance: &Provenance,
+        output_opts: OutputOpts,
+    ) -> Result<InstallStatus> {
+        let name = fetcher.name().to_owned();
+        let req = fetcher.req();
+        let matcher = fetcher.matcher(self.ctx.creator.clone());
+        // There's no artifact for a configured checksum to be checked against.
+        self.cargo_install_fetched(
+            matcher,
+            name,
+            req,
+            None,
+            Box::new(fetcher),
+            force_bin_overwrite,
+            provenance,
+            output_opts,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn cargo_install_fetched(
+        &self,
+        matcher: CargoMatcher,
+        name: String,
+        req: DirectoryVersionReq,
+        checksum: Option<Checksum>,
+        fetcher: Box<dyn PackageFetcherImpl>,
+        force_bin_overwrite: bool,
+        provenance: &Provenance,
+        output_opts: OutputOpts,
+    ) -> Result<InstallStatus> {
+        let status = self
+            .install(
+                Box::new(matcher),
+                name.clone(),
+                req,
+                checksum,
+                InstallSource::Fetcher(fetcher),
+                force_bin_overwrite,
+                output_opts,
+            )
+            .await?;
+        self.mark_explicit("cargo", &name, &status, provenance)?;
+        Ok(status)
+    }
+
+    /// Records a namespace implemented by a plugin, so that packages in it can be stored.
+    fn record_namespace(&self, namespace: &str) -> Result<()> {
+        let mut conn = self.ctx.creator.create()?;
+        let txn = self.ctx.creator.write_transaction(&mut conn)?;
+        NamespaceRow {
+            namespace: namespace.to_owned(),
+        }
+        .insert(&txn)?;
+        txn.commit()
+            .wrap_err_with(|| format!("failed to commit namespace {}", namespace))
+    }
+
+    /// Returns the most recent install of every installed package.
+    pub(crate) fn installed_packages(&self) -> Result<Vec<InstalledRow>> {
+        let conn = self.ctx.creator.create()?;
+        InstalledRow::all_installed(&conn)
+    }
+
+    /// Returns the most recent install of a package, if it's installed.
+    pub(crate) fn installed_package(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<Option<InstalledRow>> {
+        let conn = self.ctx.creator.create()?;
+        Ok(InstalledRow::all_installed_for(namespace, name, &conn)?.pop())
+    }
+
+    /// Returns the most recent install of a package, if it's installed, along with a shared lock
+    /// on its install directory so that it isn't replaced while it's being read.
+    pub(crate) fn installed_package_locked(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<Option<(InstalledRow, Option<InstallReadLock>)>> {
+        loop {
+            let installed = match self.installed_package(namespace, name)? {
+                Some(installed) => installed,
+                None => return Ok(None),
+            };
+            let lock = self.lock_installed(&installed)?;
+            // Another install may have finished while waiting for the lock.
+            match self.installed_package(namespace, name)? {
+                Some(current) if current.install_id == installed.install_id => {
+                    return Ok(Some((current, lock)))
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Obtains a shared lock on the install directory of an install, waiting for any install to
+    /// the same directory to finish.
+    pub(crate) fn lock_installed(
+        &self,
+        installed: &InstalledRow,
+    ) -> Result<Option<InstallReadLock>> {
+        InstallReadLock::acquire(&self.installed_path(installed), self.home().lock_wait())
+    }
+
+    /// Returns every installed package along with shared locks on their install directories, so
+    /// that no install can swap a directory until the locks are dropped.
+    ///
+    /// Locks are taken in sorted path order, like rollbacks take theirs, to avoid deadlocks.
+    pub(crate) fn installed_packages_locked(
+        &self,
+    ) -> Result<(Vec<InstalledRow>, Vec<InstallReadLock>)> {
+        loop {
+            let installed = self.installed_packages()?;
+            let mut install_paths: Vec<_> = installed
+                .iter()
+                .map(|installed| self.installed_path(installed))
+                .collect();
+            install_paths.sort();
+            install_paths.dedup();
+            let mut locks = Vec::with_capacity(install_paths.len());
+            for install_path in &install_paths {
+                locks.extend(InstallReadLock::acquire(
+                    install_path,
+                    self.home().lock_wait(),
+                )?);
+            }
+            // Installs may have finished while waiting for the locks.
+            let current = self.installed_packages()?;
+            let install_ids = |rows: &[InstalledRow]| {
+                rows.iter()
+                    .map(|installed| installed.install_id)
+                    .collect::<Vec<_>>()
+            };
+            if install_ids(&current) == install_ids(&installed) {
+                return Ok((current, locks));
+            }
+        }
+    }
+
+    fn installed_path(&self, installed: &InstalledRow) -> Utf8PathBuf {
+        let package = &installed.directory_row.package;
+        self.home()
+            .install_path(&package.namespace, &package.name, package.hash)
+    }
+
+    /// Returns the dependencies an install was built with.
+    pub(crate) fn install_dependencies(
+        &self,
+        installed: &InstalledRow,
+    ) -> Result<Vec<LockedDependency>> {
+        let conn = self.ctx.creator.create()?;
+        InstallDependencyRow::all_for(installed.install_id, &conn)
+    }
+
+    /// Returns whether an install was requested directly, and who or what requested it if that
+    /// was recorded.
+    pub(crate) fn requested_by(
+        &self,
+        installed: &InstalledRow,
+    ) -> Result<(bool, Option<Provenance>)> {
+        let conn = self.ctx.creator.create()?;
+        let row = &installed.directory_row;
+        Ok((row.get_explicit(&conn)?, row.get_provenance(&conn)?))
+    }
+
+    /// Returns the requirement the most recent install of a package was resolved from, if it's
+    /// installed and the requirement was recorded.
+    pub(crate) fn installed_req(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<Option<DirectoryVersionReq>> {
+        let conn = self.ctx.creator.create()?;
+        match InstalledRow::all_installed_for(namespace, name, &conn)?.last() {
+            Some(installed) => installed.directory_row.get_req(&conn),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the directories that hasp knows about but that aren't installed, along with why
+    /// the most recent attempt at installing each of them failed, if the journal records it.
+    pub(crate) fn not_installed_directories(&self) -> Result<Vec<NotInstalledDirectory>> {
+        let mut failures = HashMap::new();
+        for entry in self.events(None)? {
+            match entry.event.map(|envelope| envelope.event) {
+                Ok(Event::InstallFailed(failed)) => {
+                    let package = failed.package;
+                    failures.insert(
+                        (package.namespace, package.name, package.hash),
+                        describe_reason(&failed.reason),
+                    );
+                }
+                Ok(Event::InstallSuccess(success)) => {
+                    let package = success.package;
+                    failures.remove(&(package.namespace, package.name, package.hash));
+                }
+                _ => {}
+            }
+        }
+
+        let conn = self.ctx.creator.create()?;
+        DirectoryRow::all_not_installed(&conn)?
+            .into_iter()
+            .map(|row| {
+                let package = row.package.clone();
+                Ok(NotInstalledDirectory {
+                    last_failure: failures.remove(&(
+                        package.namespace.clone(),
+                        package.name.clone(),
+                        package.hash,
+                    )),
+                    previous_install: row.is_previous_install(&conn)?,
+                    package,
+                })
+            })
+            .collect()
+    }
+
+    /// Removes the records of directories that aren't installed, other than those kept as
+    /// previous installs, returning them. If `dry_run` is true, nothing is removed.
+    ///
+    /// Installs in progress have records like these, so this mustn't be called while any are.
+    pub(crate) fn purge_directories(&self, dry_run: bool) -> Result<Vec<DirectoryRow>> {
+        let mut conn = self.ctx.creator.create()?;
+        let txn = self.ctx.creator.write_transaction(&mut conn)?;
+        let mut purged = vec![];
+        for row in DirectoryRow::all_not_installed(&txn)? {
+            if row.is_previous_install(&txn)? {
+                continue;
+            }
+            if !dry_run {
+                // Installs recorded for a directory that isn't installed are only history.
+                row.delete_installs(&txn)?;
+                row.delete(&txn)?;
+            }
+            purged.push(row);
+        }
+        txn.commit()
+            .wrap_err("failed to commit removal of directories that aren't installed")?;
+        Ok(purged)
+    }
+
+    /// Holds an install at its version, or releases the hold on it and any other directories of
+    /// its package.
+    pub(crate) fn set_held(&self, installed: &InstalledRow, held: bool) -> Result<()> {
+        let package = &installed.directory_row.package;
+        let mut conn = self.ctx.creator.create()?;
+        let txn = self.ctx.creator.write_transaction(&mut conn)?;
+        if held {
+            installed.directory_row.set_held(&txn, true)?;
+        } else {
+            for row in DirectoryRow::all_matches_for(&package.namespace, &package.name, &txn)? {
+                row.set_held(&txn, false)?;
+            }
+        }
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "failed to commit held state for {}:{}",
+                package.namespace, package.name
+            )
+        })
+    }
+
+    /// Uninstalls a package, returning the names of the binaries that were removed.
+    pub(crate) fn uninstall(&self, installed: InstalledRow) -> Result<Vec<String>> {
+        PackageUninstaller::new(self.home.clone(), installed, self.ctx.clone()).uninstall()
+    }
+
+    /// Swaps the most recently superseded install of a package back in.
+    pub(crate) fn rollback(&self, namespace: &str, name: &str) -> Result<RolledBack> {
+        PreviousInstalls::new(self.home.clone(), self.ctx.clone(), namespace, name).rollback()
+    }
+
+    /// Removes the work directories, build cache and previous installs kept for a package,
+    /// leaving its current install alone.
+    pub(crate) fn clean(
+        &self,
+        namespace: &str,
+        name: &str,
+        dry_run: bool,
+    ) -> Result<Vec<CleanedDir>> {
+        PackageCleaner::new(self.home.clone(), self.ctx.clone(), namespace, name).clean(dry_run)
+    }
+
+    /// Checks install directories against the database and shims. If `repair` is true, lost
+    /// rows are restored from receipts and shims are fixed up.
+    pub(crate) fn fsck(&self, repair: bool) -> Result<FsckSummary> {
+        InstallChecker::new(self.home.clone(), self.ctx.clone()).check(repair)
+    }
+
+    /// Rebuilds the packages database from the receipts in install directories, for when it's
+    /// been lost or corrupted. The old database is moved aside.
+    pub(crate) fn rebuild_from_disk(hasp_home: HaspHome) -> Result<RebuildSummary> {
+        let backup_path = ConnectionCreator::new(&hasp_home).recreate_packages()?;
+        let state = Self::load_or_init(hasp_home)?;
+        let fsck = state.restore_from_receipts()?;
+        Ok(RebuildSummary { backup_path, fsck })
+    }
+
+    /// Repairs corrupted databases, for `hasp db repair`. Corrupted files are moved aside and
+    /// created afresh, and what they held is recovered from the receipts in install directories
+    /// and from the events journal.
+    pub(crate) fn repair(hasp_home: HaspHome) -> Result<RepairSummary> {
+        let creator = ConnectionCreator::new(&hasp_home);
+        let mut summary = RepairSummary {
+            corrupted: creator.find_corrupted()?,
+            ..RepairSummary::default()
+        };
+        let main = summary.is_corrupted("main");
+        let packages = summary.is_corrupted("packages");
+
+        // Read the journal before anything is recreated, since that records events of its own.
+        let recovery = if summary.is_corrupted("events") {
+            summary.backup_paths.extend(creator.move_aside("events")?);
+            JournalRecovery::default()
+        } else if !main && !packages {
+            return Ok(summary);
+        } else if creator
+            .file_path("events")
+            .is_some_and(|path| path.exists())
+        {
+            JournalRecovery::new(&read_events(&creator, None)?)
+        } else {
+            JournalRecovery::default()
+        };
+
+        if main {
+            // Migration state is tracked in the main database, so there's no telling which
+            // migrations the packages database is at without it: recreate both.
+            for name in ["main", "packages"] {
+                summary.backup_paths.extend(creator.move_aside(name)?);
+            }
+        } else if packages {
+            summary.backup_paths.extend(creator.recreate_packages()?);
+        }
+
+        let state = Self::load_or_init(hasp_home)?;
+        if main || packages {
+            summary.fsck = Some(state.restore_from_receipts()?);
+            if main {
+                summary.restored_sources = recovery.restore_trusted_sources(&state.ctx.creator)?;
+            }
+            let conn = state.ctx.creator.create()?;
+            summary.missing = recovery.missing_installs(&conn)?;
+        }
+        Ok(summary)
+    }
+
+    /// Restores installs from the receipts in install directories into a recreated packages
+    /// database.
+    fn restore_from_receipts(&self) -> Result<FsckSummary> {
+        let mut fsck = self.fsck(true)?;
+
+        // Previous installs aren't tracked in receipts, so they can't be rolled back to any more.
+        let previous_dir = self.home.previous_dir();
+        if previous_dir.exists() {
+            fsck.problems.push(format!(
+                "previous installs in {} can no longer be rolled back to (hint: remove the \
+                 directory to free up space)",
+                previous_dir,
+            ));
+        }
+        Ok(fsck)
+    }
+
+    /// Returns the sources approved through prompts or `hasp trust add`.
+    pub(crate) fn trusted_sources(&self) -> Result<Vec<TrustedSourceRow>> {
+        let conn = self.ctx.creator.create()?;
+        TrustedSourceRow::all(&conn)
+    }
+
+    /// Approves a source. Returns false if it was already approved.
+    pub(crate) fn trust_source(&self, source: &str) -> Result<bool> {
+        trust::approve(&self.ctx, source, "command")
+    }
+
+    /// Removes the approval for a source. Returns false if it wasn't approved.
+    pub(crate) fn untrust_source(&self, source: &str) -> Result<bool> {
+        trust::revoke(&self.ctx, source)
+    }
+
+    /// Records that hasp replaced its own binary, as a `self_updated` event.
+    pub(crate) fn record_self_update(&self, from: &Version, to: &Version, path: &Utf8Path) {
+        self.ctx.event_logger.log(Event::SelfUpdated(SelfUpdated {
+            from: from.to_string(),
+            to: to.to_string(),
+            path: path.to_string(),
+        }));
+    }
+
+    #[inline]
+    pub(crate) fn home(&self) -> &HaspHome {
+        &self.home
+    }
+
+    #[inline]
+    pub(crate) fn db_creator(&self) -> &ConnectionCreator {
+        &self.ctx.creator
+    }
+
+    /// Returns the most recent `limit` events in the journal, or all of them, oldest first.
+    pub(crate) fn events(&self, limit: Option<u64>) -> Result<Vec<JournalEntry>> {
+        read_events(&self.ctx.creator, limit)
+    }
+
+    /// Waits for pending events to be recorded in the journal, e.g. before hasp is replaced by
+    /// another program. Gives up after `timeout`.
+    pub(crate) fn flush_events(&self, timeout: std::time::Duration) {
+        if !self.ctx.event_logger.flush(timeout) {
+            let timeout = format!("{:?}", timeout);
+            tracing::debug!(
+                target: "hasp::output::recording::flush_timed_out",
+                %timeout,
+                "Gave up waiting for events to be recorded after {timeout}",
+            );
+        }
+    }
+
+    /// Returns a stream of install and uninstall events, as they're recorded in the journal. This
+    /// includes events for other states in this process, like those of commands run from the
+    /// dashboard.
+    ///
+    /// Only events after this call are returned: use the journal for earlier events.
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = LifecycleEvent> {
+        events::subscribe_lifecycle()
+    }
+
+    /// Prints a note if the package is deprecated or superseded by another package.
+    fn print_hint(&self, namespace: &str, name: &str) {
+        match HintList::load(&self.home) {
+            Ok(hints) => {
+                if let Some(hint) = hints.get(namespace, name) {
+                    let note = hint.note(name);
+                    tracing::info!(
+                        target: "hasp::output::informational::hint",
+                        %note,
+                        "Note {note}",
+                    );
+                }
+            }
+            Err(err) => {
+                let error = format!("{:#}", err);
+                tracing::warn!(
+                    target: "hasp::output::hints_failed",
+                    %error,
+                    "Warning failed to load hints: {error}",
+                );
+            }
+        }
+    }
+
+    /// Marks the directory that was installed, or was found to be installed already, as requested
+    /// directly, recording its provenance.
+    ///
+    /// All installed directories for the version are marked, even if they have different metadata
+    /// (e.g. features). That errs on the side of keeping directories around. Directories that
+    /// were already installed keep their provenance, if they have one.
+    fn mark_explicit(
+        &self,
+        namespace: &str,
+        name: &str,
+        status: &InstallStatus,
+        provenance: &Provenance,
+    ) -> Result<()> {
+        let (version, installed_now) = match status {
+            InstallStatus::Success { version, .. } => (version, true),
+            InstallStatus::AlreadyInstalled { version, .. } => (version, false),
+            InstallStatus::Failure { .. } => return Ok(()),
+        };
+
+        let mut conn = self.ctx.creator.create()?;
+        let txn = self.ctx.creator.write_transaction(&mut conn)?;
+        for row in DirectoryRow::all_matches_for_version(namespace, name, version, &txn)? {
+            if !row.get_installed(&txn)? {
+                continue;
+            }
+            if !row.get_explicit(&txn)? {
+                row.set_explicit(&txn, true)?;
+            }
+            if installed_now || row.get_provenance(&txn)?.is_none() {
+                row.set_provenance(&txn, provenance)?;
+            }
+        }
+        txn.commit().wrap_err_with(|| {
+            format!(
+                "failed to commit explicit state for {}:{} (version {})",
+                namespace, name, version
+            )
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn install(
+        &self,
+        matcher: Box<dyn PackageMatcherImpl>,
+        name: String,
+        req: DirectoryVersionReq,
+        checksum: Option<Checksum>,
+        source: InstallSource,
+        force_bin_overwrite: bool,
         output_opts: OutputOpts,
     ) -> Result<InstallStatus> {
-        let matcher = CargoMatcher::new(metadata);
+        let previous_installs = PreviousInstalls::new(
+            self.home.clone(),
+            self.ctx.clone(),
+            matcher.namespace(),
+            &name,
+        );
         let matcher = PackageMatcher::new(
             self.home.clone(),
-            Box::new(matcher),
-            name.into(),
+            matcher,
+            name,
             req,
+            checksum,
             output_opts,
             self.ctx.clone(),
         );
 
-        let mut conn = self.ctx.creator.create()?;
-        let txn = conn.transaction()?;
-
-        match matcher.best_installed_match(&txn)? {
+        // A floating version being updated is resolved again. If it still resolves to the
+        // installed version, the installer finds that it's already installed.
+        let update = matches!(source, InstallSource::Resolve { update: true });
+        let installed = matcher
+            .installed_match()
+            .await?
+            .filter(|row| !(update && row.directory_row.package.version.as_floating().is_some()));
+        match installed {
             Some(row) => {
-                // TODO: force install/update?
+                // TODO: force install?
                 Ok(InstallStatus::AlreadyInstalled {
                     version: row.directory_row.package.version,
+                    available: AvailableUpdate::default(),
                 })
             }
             None => {
-                // Perform the resolve/fetch/install operations.
-                let resolver = matcher.make_resolver();
-                let fetcher = resolver.make_fetcher().await?;
+                // Perform the resolve/fetch/install operations, skipping resolution if a fetcher
+                // was provided.
+                // Don't start new installs once hasp has been cancelled.
+                cancel::check()?;
+                let fetcher = match source {
+                    InstallSource::Fetcher(fetcher) => PackageFetcher::new(matcher, fetcher),
+                    InstallSource::Resolve { .. } => {
+                        trust::check_source(
+                            &self.ctx,
+                            &self.home.config().trust,
+                            self.home.interactive(),
+                            &matcher.source(),
+                            &package_key(matcher.namespace(), matcher.name()),
+                        )?;
+                        matcher.make_resolver().make_fetcher().await?
+                    }
+                };
                 let installer = fetcher.fetch().await?;
-                installer.install(false).await
+                cancel::check()?;
+                let status = installer.install(false, force_bin_overwrite).await?;
+                if let InstallStatus::Success { replaced, .. } = &status {
+                    // The install succeeded, so failing to clean up after it isn't fatal.
+                    if let Err(err) = previous_installs.retire_superseded(
+                        replaced.as_ref(),
+                        self.home.config().install.keep_previous(),
+                    ) {
+                        let error = format!("{:#}", err);
+                        tracing::warn!(
+                            target: "hasp::output::retire_failed",
+                            %error,
+                            "Warning failed to retire superseded installs: {error}",
+                        );
+                    }
+                }
+                Ok(status)
             }
         }
     }
 }
+
+impl Drop for HaspState {
+    fn drop(&mut self) {
+        // Let SQLite update the statistics the query planner uses, as it recommends before
+        // connections are closed. This comes first so that what it writes is checkpointed.
+        if let Err(err) = self.ctx.creator.optimize(false) {
+            let error = format!("{:#}", err);
+            tracing::debug!(
+                target: "hasp::output::recording::optimize_failed",
+                %error,
+                "Failed to optimize databases: {error}",
+            );
+        }
+
+        // Checkpoint write-ahead logs on exit, so that they don't grow without bound. Skip
+        // databases that are in use by other processes: they'll be checkpointed later.
+        match self.ctx.creator.checkpoint(false) {
+            Ok(statuses) => {
+                for status in statuses.iter().filter(|status| status.busy) {
+                    tracing::debug!(
+                        target: "hasp::output::recording::checkpoint_skipped",
+                        database = %status.name,
+                        "Skipped checkpointing database {database}: in use by another process",
+                        database = status.name,
+                    );
+                }
+            }
+            Err(err) => {
+                let error = format!("{:#}", err);
+                tracing::debug!(
+                    target: "hasp::output::recording::checkpoint_failed",
+                    %error,
+                    "Failed to checkpoint databases: {error}",
+                );
+            }
+        }
+    }
+}
+
+/// Makes attempts at installing a package until one succeeds or fails for a reason that isn't
+/// retryable, or `retries` more attempts have been made.
+///
+/// The delay before the first retry is `initial_backoff`, doubling for each subsequent retry up to
+/// [`INSTALL_RETRY_BACKOFF_MAX`].
+async fn retry_install<F, Fut>(
+    key: &str,
+    retries: u32,
+    initial_backoff: Duration,
+    mut attempt_install: F,
+) -> Result<InstallStatus>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<InstallStatus>>,
+{
+    let mut attempt = 1;
+    loop {
+        let res = attempt_install().await;
+        let err = match &res {
+            Ok(InstallStatus::Failure {
+                report,
+                retryable: true,
+                ..
+            }) => report,
+            Err(err) if FailureClass::is_retryable(err) => err,
+            _ => return res,
+        };
+        if attempt > retries || cancel::is_cancelled() {
+            return res;
+        }
+
+        let backoff = initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(INSTALL_RETRY_BACKOFF_MAX);
+        let seconds = format!("{:.1}", backoff.as_secs_f64());
+        let attempts = retries + 1;
+        let error = format!("{:#}", err);
+        tracing::warn!(
+            target: "hasp::output::install_retrying",
+            %key,
+            %seconds,
+            attempt,
+            attempts,
+            %error,
+            "Retrying install of {key} in {seconds}s (attempt {attempt} of {attempts} failed: \
+             {error})",
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// A request to install a single package.
+#[derive(Clone, Debug)]
+pub(crate) struct InstallRequest {
+    pub(crate) name: String,
+    pub(crate) req: DirectoryVersionReq,
+    pub(crate) metadata: PackageMetadata,
+
+    /// The checksum the fetched artifact is expected to have, if pinned.
+    pub(crate) checksum: Option<Checksum>,
+
+    /// Whether to re-resolve floating versions like image tags, even if the package is installed.
+    pub(crate) update: bool,
+
+    /// Whether the package was requested directly, through the command line or a manifest,
+    /// rather than being installed for another package.
+    pub(crate) explicit: bool,
+
+    /// Whether to replace binaries that other installed packages have, rather than failing.
+    pub(crate) force_bin_overwrite: bool,
+
+    /// How many more times to attempt the install if it fails for a retryable reason.
+    pub(crate) retries: u32,
+}
+
+impl InstallRequest {
+    #[inline]
+    pub(crate) fn namespace(&self) -> &'static str {
+        self.metadata.namespace()
+    }
+}
+
+/// How to obtain the package being installed.
+enum InstallSource {
+    /// Resolve the version requirement. If `update` is true, floating versions are resolved again
+    /// even if they're installed.
+    Resolve { update: bool },
+    /// Use this fetcher, skipping resolution.
+    Fetcher(Box<dyn PackageFetcherImpl>),
+}
+
+/// Namespace-specific metadata for a package.
+#[derive(Clone, Debug)]
+pub(crate) enum PackageMetadata {
+    Cargo(CargoDirectory),
+    Npm(NpmDirectory),
+    Oci(OciDirectory),
+    /// Packages in a namespace implemented by a plugin don't have any metadata of their own.
+    Plugin(&'static PluginBackend),
+}
+
+impl PackageMetadata {
+    pub(crate) fn namespace(&self) -> &'static str {
+        match self {
+            PackageMetadata::Cargo(_) => "cargo",
+            PackageMetadata::Npm(_) => "npm",
+            PackageMetadata::Oci(_) => "oci",
+            PackageMetadata::Plugin(backend) => {
+                let backend: &'static PluginBackend = backend;
+                backend.namespace()
+            }
+        }
+    }
+}
+
+/// The results of repairing corrupted databases.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RepairSummary {
+    /// The database files that were found to be corrupted.
+    pub(crate) corrupted: Vec<CorruptedFile>,
+    /// The paths that old database files were moved to.
+    pub(crate) backup_paths: Vec<Utf8PathBuf>,
+    /// The results of restoring install directories, if the packages database was recreated.
+    pub(crate) fsck: Option<FsckSummary>,
+    /// Sources whose approval was restored from the events journal.
+    pub(crate) restored_sources: Vec<String>,
+    /// Packages that were installed according to the events journal, but couldn't be restored
+    /// from their install directories.
+    pub(crate) missing: Vec<PackageDirectory>,
+}
+
+impl RepairSummary {
+    pub(crate) fn is_corrupted(&self, name: &str) -> bool {
+        self.corrupted.iter().any(|file| file.name == name)
+    }
+}
+
+/// The results of rebuilding the packages database.
+#[derive(Clone, Debug)]
+pub(crate) struct RebuildSummary {
+    /// The path the old database was moved to, if it existed.
+    pub(crate) backup_path: Option<Utf8PathBuf>,
+    /// The results of restoring install directories.
+    pub(crate) fsck: FsckSummary,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::previous_install::PreviousInstallRow, test_helpers::TestHome};
+    use chrono::Local;
+    use color_eyre::eyre::eyre;
+    use hasp_metadata::{FailureReason, FailureStage, InstallFailed, InstallSuccess};
+    use std::fs;
+
+    #[test]
+    fn installed_packages_locked() {
+        let test_home = TestHome::new();
+        let state = HaspState::load_or_init(test_home.hasp_home.clone()).expect("state loaded");
+        let foo = test_home.insert_install("foo", "sem:1.0.0", 0x10, &["foo"]);
+        test_home.insert_install("bar", "sem:1.0.0", 0x20, &["bar"]);
+
+        // Only foo has been installed to disk, so only it has a lockfile to lock.
+        let foo_path = state.home().install_path("cargo", "foo", foo.package.hash);
+        fs::create_dir_all(foo_path.parent().expect("install path has a parent"))
+            .expect("install directory created");
+        fs::write(foo_path.with_extension("lock"), "").expect("lockfile written");
+
+        let (installed, locks) = state
+            .installed_packages_locked()
+            .expect("installed packages read");
+        let mut names: Vec<_> = installed
+            .iter()
+            .map(|installed| installed.directory_row.package.name.as_str())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["bar", "foo"]);
+        assert_eq!(locks.len(), 1, "foo's install directory is locked");
+    }
+
+    #[test]
+    fn not_installed_last_failure() {
+        let test_home = TestHome::new();
+        let state = HaspState::load_or_init(test_home.hasp_home.clone()).expect("state loaded");
+        let retried = test_home.insert_install("foo", "sem:1.0.0", 0x10, &["foo"]);
+        let failed = test_home.insert_install("bar", "sem:1.0.0", 0x20, &["bar"]);
+        set_not_installed(&test_home, &[&retried, &failed]);
+
+        // foo failed, then succeeded when it was retried (and was uninstalled since).
+        let reason = FailureReason::Cancelled {
+            stage: FailureStage::Build,
+        };
+        for row in [&retried, &failed] {
+            state
+                .ctx
+                .event_logger
+                .log(Event::InstallFailed(InstallFailed {
+                    package: row.package.clone(),
+                    force: false,
+                    start_time: Local::now(),
+                    end_time: Local::now(),
+                    reason: reason.clone(),
+                    build_log: "build.log".into(),
+                }));
+        }
+        state
+            .ctx
+            .event_logger
+            .log(Event::InstallSuccess(InstallSuccess {
+                package: retried.package.clone(),
+                force: false,
+                start_time: Local::now(),
+                end_time: Local::now(),
+                timings: None,
+            }));
+        state.flush_events(Duration::from_secs(5));
+
+        let last_failures: Vec<_> = state
+            .not_installed_directories()
+            .expect("directories queried")
+            .into_iter()
+            .map(|dir| (dir.package.name, dir.last_failure))
+            .collect();
+        assert_eq!(
+            last_failures,
+            vec![
+                ("bar".to_owned(), Some(describe_reason(&reason))),
+                ("foo".to_owned(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn purge_keeps_previous_installs() {
+        let test_home = TestHome::new();
+        let state = HaspState::load_or_init(test_home.hasp_home.clone()).expect("state loaded");
+        let retired = test_home.insert_install("foo", "sem:1.0.0", 0x10, &["foo"]);
+        test_home.insert_install("foo", "sem:2.0.0", 0x20, &["foo"]);
+        let failed = test_home.insert_install("bar", "sem:1.0.0", 0x30, &["bar"]);
+        set_not_installed(&test_home, &[&failed]);
+
+        // Retire foo 1.0.0 as a previous install, the way a newer install of it does.
+        let creator = test_home.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+        let installed = InstalledRow::all_installed_for("cargo", "foo", &txn)
+            .expect("installs queried")
+            .into_iter()
+            .find(|row| row.directory_row.directory_id == retired.directory_id)
+            .expect("foo 1.0.0 is installed");
+        retired
+            .set_installed(&txn, false)
+            .expect("installed state set");
+        PreviousInstallRow::insert(&installed, &"previous".into(), &txn)
+            .expect("previous install recorded");
+        txn.commit().expect("transaction committed");
+
+        let purged_ids = |dry_run| {
+            state
+                .purge_directories(dry_run)
+                .expect("directories purged")
+                .into_iter()
+                .map(|row| row.directory_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(purged_ids(true), vec![failed.directory_id]);
+        assert_eq!(purged_ids(false), vec![failed.directory_id]);
+        assert!(purged_ids(false).is_empty(), "nothing left to purge");
+
+        let not_installed = state
+            .not_installed_directories()
+            .expect("directories queried");
+        assert_eq!(not_installed.len(), 1);
+        assert_eq!(not_installed[0].package.hash, retired.package.hash);
+        assert!(not_installed[0].previous_install);
+    }
+
+    #[tokio::test]
+    async fn retry_install_until_success() {
+        let version = DirectoryVersion::new_semantic(Version::new(1, 0, 0));
+        let flaky = |attempts: &mut u32, succeed_on: u32| {
+            *attempts += 1;
+            let status = if *attempts < succeed_on {
+                InstallStatus::Failure {
+                    version: version.clone(),
+                    report: eyre!("attempt {} failed", attempts),
+                    build_log: "build.log".into(),
+                    retryable: true,
+                }
+            } else {
+                InstallStatus::Success {
+                    version: version.clone(),
+                    binaries: vec!["foo".to_owned()],
+                    replaced: None,
+                }
+            };
+            async move { Ok(status) }
+        };
+
+        let mut attempts = 0;
+        let status = retry_install("foo", 3, Duration::ZERO, || flaky(&mut attempts, 3))
+            .await
+            .expect("install attempted");
+        assert!(
+            matches!(status, InstallStatus::Success { .. }),
+            "{:?}",
+            status
+        );
+        assert_eq!(attempts, 3, "succeeded on the third attempt");
+
+        // The last failure is returned once the retries run out.
+        let mut attempts = 0;
+        let status = retry_install("foo", 1, Duration::ZERO, || flaky(&mut attempts, 3))
+            .await
+            .expect("install attempted");
+        match status {
+            InstallStatus::Failure { report, .. } => {
+                assert_eq!(report.to_string(), "attempt 2 failed")
+            }
+            status => panic!("expected failure, found {:?}", status),
+        }
+        assert_eq!(attempts, 2);
+    }
+
+    fn set_not_installed(test_home: &TestHome, rows: &[&DirectoryRow]) {
+        let creator = test_home.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+        for row in rows {
+            row.set_installed(&txn, false).expect("installed state set");
+        }
+        txn.commit().expect("transaction committed");
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Aggregate numbers about installs for `hasp stats`, from the timings recorded with successful
+//! installs in the events journal.
+
+use crate::config::package_key;
+use hasp_metadata::{InstallStatsOutput, InstallSuccess, TimedInstall};
+
+/// Adds up the timings of these installs, keeping the `top` slowest. Installs recorded without
+/// timings are skipped.
+pub(crate) fn install_stats<'a>(
+    installs: impl IntoIterator<Item = &'a InstallSuccess>,
+    top: usize,
+) -> InstallStatsOutput {
+    let mut stats = InstallStatsOutput::default();
+    let mut timed = vec![];
+    for install in installs {
+        let timings = match &install.timings {
+            Some(timings) => timings,
+            None => continue,
+        };
+        stats.installs += 1;
+        if timings.build_source.is_reused() {
+            stats.reused_builds += 1;
+        }
+        stats.resolve_ms += timings.resolve_ms;
+        stats.fetch_ms += timings.fetch_ms;
+        stats.build_ms += timings.build_ms;
+        stats.finish_ms += timings.finish_ms;
+        timed.push(TimedInstall {
+            package: install.package.clone(),
+            end_time: install.end_time,
+            timings: timings.clone(),
+        });
+    }
+    // Sorting is stable, so among installs that took as long, earlier ones come first.
+    timed.sort_by_key(|install| std::cmp::Reverse(install.timings.total_ms()));
+    timed.truncate(top);
+    stats.slowest = timed;
+    stats
+}
+
+/// Returns the lines `hasp stats` prints for people to read.
+pub(crate) fn summary_lines(stats: &InstallStatsOutput) -> Vec<String> {
+    let hit_rate = match stats.cache_hit_rate() {
+        Some(hit_rate) => hit_rate,
+        None => {
+            return vec![
+                "no installs with recorded timings (hint: timings are recorded for installs by \
+                 this version of hasp onwards)"
+                    .to_owned(),
+            ]
+        }
+    };
+    let mut lines = vec![
+        format!(
+            "installs: {} ({} reused builds, {:.1}% cache hit rate)",
+            stats.installs,
+            stats.reused_builds,
+            hit_rate * 100.0
+        ),
+        format!(
+            "total time: {} (resolve {}, fetch {}, build {}, finish {})",
+            seconds(stats.total_ms()),
+            seconds(stats.resolve_ms),
+            seconds(stats.fetch_ms),
+            seconds(stats.build_ms),
+            seconds(stats.finish_ms),
+        ),
+    ];
+    if !stats.slowest.is_empty() {
+        lines.push("slowest installs:".to_owned());
+    }
+    for install in &stats.slowest {
+        let package = &install.package;
+        lines.push(format!(
+            "  {}@{}: {} (build {}, {}) at {}",
+            package_key(&package.namespace, &package.name),
+            package.version.short_display(),
+            seconds(install.timings.total_ms()),
+            seconds(install.timings.build_ms),
+            install.timings.build_source,
+            install.end_time.to_rfc3339(),
+        ));
+    }
+    lines
+}
+
+fn seconds(ms: u64) -> String {
+    format!("{:.1}s", ms as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use hasp_metadata::{BuildSource, DirectoryHash, InstallTimings, PackageDirectory};
+
+    #[test]
+    fn install_stats_basic() {
+        let install = |name: &str, build_ms, build_source| InstallSuccess {
+            package: PackageDirectory {
+                namespace: "cargo".to_owned(),
+                name: name.to_owned(),
+                version: "sem:1.0.0".parse().expect("valid version"),
+                hash: DirectoryHash::new(0x01234567),
+                metadata: serde_json::json!({}),
+            },
+            force: false,
+            start_time: Local::now(),
+            end_time: Local::now(),
+            timings: Some(InstallTimings {
+                resolve_ms: 100,
+                fetch_ms: 200,
+                build_ms,
+                finish_ms: 50,
+                build_source,
+            }),
+        };
+        let mut untimed = install("old", 0, BuildSource::Built);
+        untimed.timings = None;
+        let installs = [
+            install("foo", 10_000, BuildSource::Built),
+            install("bar", 500, BuildSource::SharedStore),
+            install("baz", 60_000, BuildSource::Built),
+            untimed,
+        ];
+
+        let stats = install_stats(&installs, 2);
+        assert_eq!(stats.installs, 3);
+        assert_eq!(stats.reused_builds, 1);
+        assert_eq!(stats.build_ms, 70_500);
+        assert_eq!(stats.total_ms(), 70_500 + 3 * 350);
+        let slowest: Vec<_> = stats
+            .slowest
+            .iter()
+            .map(|install| install.package.name.as_str())
+            .collect();
+        assert_eq!(slowest, ["baz", "foo"]);
+
+        let lines = summary_lines(&stats);
+        assert_eq!(
+            lines[0],
+            "installs: 3 (1 reused builds, 33.3% cache hit rate)"
+        );
+        assert!(
+            lines[3].starts_with("  baz@1.0.0: 60.4s (build 60.0s, built) at "),
+            "unexpected line: {}",
+            lines[3]
+        );
+        assert_eq!(summary_lines(&install_stats(&[], 5)).len(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Fixtures shared between tests.
+
+use crate::{
+    config::PluginConfig,
+    database::{ConnectionCreator, DbContext},
+    events::EventLogger,
+    home::HaspHome,
+    models::directory::DirectoryRow,
+    ops::PluginBackend,
+    receipt::{InstallReceipt, ReceiptFile},
+};
+use camino::Utf8Path;
+use chrono::Local;
+use hasp_metadata::{DirectoryHash, FileHash, PackageDirectory};
+use std::fs;
+use tempfile::TempDir;
+
+/// A hasp home in a temporary directory, with its databases initialized. The directory is removed
+/// when this is dropped.
+#[derive(Debug)]
+pub(crate) struct TestHome {
+    pub(crate) hasp_home: HaspHome,
+    pub(crate) db_ctx: DbContext,
+    _dir: TempDir,
+}
+
+impl TestHome {
+    pub(crate) fn new() -> Self {
+        let dir = tempfile::tempdir().expect("tempdir created");
+        let home = Utf8Path::from_path(dir.path()).expect("tempdir is UTF-8");
+        let hasp_home = HaspHome::new(home).expect("hasp home created");
+        let creator = ConnectionCreator::new(&hasp_home);
+        let event_logger = EventLogger::new(&creator).expect("event logger created");
+        creator
+            .initialize(&event_logger)
+            .expect("databases initialized");
+        Self {
+            hasp_home,
+            db_ctx: DbContext {
+                creator,
+                event_logger,
+            },
+            _dir: dir,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn creator(&self) -> &ConnectionCreator {
+        &self.db_ctx.creator
+    }
+
+    /// Records an install of a cargo package with these binaries in the database, without writing
+    /// anything to its install directory.
+    pub(crate) fn insert_install(
+        &self,
+        name: &str,
+        version: &str,
+        hash: u64,
+        binaries: &[&str],
+    ) -> DirectoryRow {
+        let package = PackageDirectory {
+            namespace: "cargo".to_owned(),
+            name: name.to_owned(),
+            version: version.parse().expect("valid version"),
+            hash: DirectoryHash::new(hash),
+            metadata: serde_json::json!({}),
+        };
+        let receipt = InstallReceipt {
+            hasp_version: env!("CARGO_PKG_VERSION").to_owned(),
+            package: package.clone(),
+            target: None,
+            metadata: serde_json::json!({}),
+            files: binaries
+                .iter()
+                .map(|&binary| {
+                    let file = ReceiptFile {
+                        hash: FileHash::Blake3(blake3::hash(binary.as_bytes()).into()),
+                        metadata: serde_json::Value::Null,
+                        is_binary: true,
+                    };
+                    (binary.to_owned(), file)
+                })
+                .collect(),
+            dependencies: vec![],
+            start_time: Local::now(),
+            install_time: Local::now(),
+        };
+
+        let creator = self.creator();
+        let mut conn = creator.create().expect("connection created");
+        let txn = creator
+            .write_transaction(&mut conn)
+            .expect("transaction started");
+        let row = DirectoryRow::insert(&package, true, &txn).expect("row inserted");
+        row.insert_install(&receipt, &txn)
+            .expect("install recorded");
+        txn.commit().expect("transaction committed");
+        row
+    }
+}
+
+/// A plugin that installs a single binary, `tool`. Its first argument changes how it misbehaves, if
+/// at all.
+const FAKE_PLUGIN: &str = r#"
+mode=$1
+request=$(cat)
+field() {
+    printf '%s' "$request" | sed -n "s/.*\"$1\":\"\([^\"]*\)\".*/\1/p"
+}
+if [ "$2" != resolve ] && [ "$(field url)" != "https://example.com/tool" ]; then
+    echo "data from resolve wasn't passed on" >&2
+    exit 1
+fi
+case "$2" in
+resolve)
+    if [ "$mode" = malformed ]; then
+        echo "version 1.2.3"
+    else
+        echo '{"version":"1.2.3","data":{"url":"https://example.com/tool"}}'
+    fi
+    ;;
+fetch)
+    echo contents > "$(field fetch-dir)/tool.tar"
+    if [ "$mode" = escape ]; then
+        echo '{"artifact":"../x"}'
+    else
+        echo '{"artifact":"tool.tar"}'
+    fi
+    ;;
+install)
+    cp "$(field fetch-dir)/tool.tar" "$(field install-dir)/tool"
+    if [ "$mode" = missing ]; then
+        echo '{"binaries":["tool","other"]}'
+    else
+        echo '{"binaries":["tool"]}'
+    fi
+    ;;
+esac
+"#;
+
+/// Writes the fake plugin to `dir`, and returns a backend for the `fake` namespace that runs it in
+/// this mode.
+pub(crate) fn fake_plugin(dir: &Utf8Path, mode: &str) -> &'static PluginBackend {
+    let script = dir.join("hasp-fake");
+    fs::write(&script, FAKE_PLUGIN).expect("plugin written");
+    let config = PluginConfig {
+        command: vec!["sh".to_owned(), script.into_string(), mode.to_owned()],
+        source: Some("example.com".to_owned()),
+    };
+    let backend = PluginBackend::new("fake", &config).expect("plugin is valid");
+    Box::leak(Box::new(backend))
+}
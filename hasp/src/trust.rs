@@ -0,0 +1,202 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Approving the sources packages are fetched from, such as registries.
+//!
+//! If `trust.require-approval` is set in the config file, packages are only fetched from sources
+//! listed in the config file or approved earlier. The first time another source is used, hasp
+//! asks for approval if it's running interactively in a terminal, and fails the install otherwise
+//! (for example with `--ci`). Approvals
+//! are stored in the main database, and can be managed through `hasp trust`.
+
+use crate::{
+    config::TrustConfig, database::DbContext, failure::PolicyViolation,
+    models::trusted_source::TrustedSourceRow, ops::registry,
+};
+use chrono::Local;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use hasp_metadata::{Event, SourceTrusted, SourceUntrusted};
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    sync::Mutex,
+};
+
+/// Installs run concurrently, so only one of them asks for approval at a time.
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Checks that a package may be fetched from a source, asking for approval if needed and
+/// `interactive` is true.
+pub(crate) fn check_source(
+    ctx: &DbContext,
+    config: &TrustConfig,
+    interactive: bool,
+    source: &str,
+    package: &str,
+) -> Result<()> {
+    if !config.require_approval || config.sources.iter().any(|trusted| trusted == source) {
+        return Ok(());
+    }
+    let conn = ctx.creator.create()?;
+    if TrustedSourceRow::get(source, &conn)?.is_some() {
+        return Ok(());
+    }
+
+    let _guard = PROMPT_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Another install may have asked about the same source while this one was waiting.
+    if TrustedSourceRow::get(source, &conn)?.is_some() {
+        return Ok(());
+    }
+    if !interactive || !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Err(PolicyViolation(format!(
+            "{} is fetched from {}, which is not trusted (hint: run `hasp trust add {}`, or add it \
+             to trust.sources in the config file)",
+            package, source, source,
+        ))
+        .into());
+    }
+    if !confirm(&format!(
+        "{} is fetched from {}, which hasn't been used before. Trust {}?",
+        package, source, source,
+    ))? {
+        return Err(PolicyViolation(format!(
+            "{} is fetched from {}, which was not approved",
+            package, source
+        ))
+        .into());
+    }
+    approve(ctx, source, "prompt")?;
+    Ok(())
+}
+
+/// Records an approval for a source. Returns false if it was already approved.
+pub(crate) fn approve(ctx: &DbContext, source: &str, approval: &str) -> Result<bool> {
+    let mut conn = ctx.creator.create()?;
+    let txn = ctx.creator.write_transaction(&mut conn)?;
+    let inserted = TrustedSourceRow {
+        source: source.to_owned(),
+        approval: approval.to_owned(),
+        approve_time: Local::now(),
+    }
+    .insert(&txn)?;
+    txn.commit()
+        .wrap_err_with(|| format!("failed to commit approval for source {}", source))?;
+    if inserted {
+        ctx.event_logger.log(Event::SourceTrusted(SourceTrusted {
+            source: source.to_owned(),
+            approval: approval.to_owned(),
+        }));
+    }
+    Ok(inserted)
+}
+
+/// Removes the approval for a source. Returns false if it wasn't approved.
+pub(crate) fn revoke(ctx: &DbContext, source: &str) -> Result<bool> {
+    let mut conn = ctx.creator.create()?;
+    let txn = ctx.creator.write_transaction(&mut conn)?;
+    let deleted = TrustedSourceRow::delete(source, &txn)?;
+    txn.commit()
+        .wrap_err_with(|| format!("failed to commit removal of source {}", source))?;
+    if deleted {
+        ctx.event_logger
+            .log(Event::SourceUntrusted(SourceUntrusted {
+                source: source.to_owned(),
+            }));
+    }
+    Ok(deleted)
+}
+
+/// Checks that a source passed on the command line looks like `namespace:location`.
+pub(crate) fn validate_source(source: &str) -> Result<()> {
+    match source.split_once(':') {
+        Some((namespace, location))
+            if registry().get(namespace).is_some() && !location.is_empty() =>
+        {
+            Ok(())
+        }
+        _ => bail!(
+            "invalid source '{}' (hint: sources look like cargo:crates.io, npm:registry.npmjs.org \
+             or oci:ghcr.io)",
+            source
+        ),
+    }
+}
+
+/// Asks a yes/no question on stderr, defaulting to no.
+fn confirm(question: &str) -> Result<bool> {
+    let mut stderr = io::stderr();
+    write!(stderr, "{} [y/N] ", question)?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .wrap_err("failed to read answer")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TestHome;
+
+    #[test]
+    fn check_source_non_interactive() {
+        let home = TestHome::new();
+        let config = TrustConfig {
+            require_approval: true,
+            sources: vec!["cargo:crates.io".to_owned()],
+        };
+        check_source(
+            &home.db_ctx,
+            &config,
+            false,
+            "cargo:crates.io",
+            "cargo:ripgrep",
+        )
+        .expect("configured source is trusted");
+
+        // Without approval, a new source fails rather than prompting.
+        let err = check_source(
+            &home.db_ctx,
+            &config,
+            false,
+            "npm:registry.npmjs.org",
+            "npm:foo",
+        )
+        .expect_err("untrusted source fails");
+        assert!(
+            err.downcast_ref::<PolicyViolation>().is_some(),
+            "untrusted source is a policy violation: {:?}",
+            err
+        );
+
+        approve(&home.db_ctx, "npm:registry.npmjs.org", "command").expect("source approved");
+        check_source(
+            &home.db_ctx,
+            &config,
+            false,
+            "npm:registry.npmjs.org",
+            "npm:foo",
+        )
+        .expect("approved source is trusted");
+    }
+
+    #[test]
+    fn validate_sources() {
+        for source in [
+            "cargo:crates.io",
+            "cargo:sparse+https://example.com/index/",
+            "oci:ghcr.io",
+        ] {
+            validate_source(source).unwrap_or_else(|err| panic!("{} is valid: {}", source, err));
+        }
+        for source in ["crates.io", "cargo:", "pip:pypi.org"] {
+            assert!(validate_source(source).is_err(), "{} is invalid", source);
+        }
+    }
+}
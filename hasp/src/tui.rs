@@ -0,0 +1,372 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A dashboard for managing installed packages interactively, through `hasp tui`.
+//!
+//! The dashboard shows installed packages and recent events, then reads commands from standard
+//! input: any hasp command, like `install ripgrep@14` or `hold ripgrep`, and a few of its own for
+//! searching the events journal. hasp doesn't depend on a terminal UI library, so the dashboard is
+//! made of plain lines, and installs show their progress as a line for each package that moves to
+//! another phase.
+
+use crate::{
+    event_line, events,
+    home::HaspHome,
+    output::{progress, NameVersionDisplay},
+    report::RunReport,
+    state::HaspState,
+    Command, GlobalOpts,
+};
+use color_eyre::{eyre::WrapErr, Result};
+use colored::Colorize;
+use futures::prelude::*;
+use hasp_metadata::{LifecycleEvent, ProgressEvent, ProgressPhase};
+use std::{
+    future::Future,
+    io::{self, BufRead, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use structopt::StructOpt;
+
+/// The number of events shown in the dashboard.
+const RECENT_EVENTS: &str = "5";
+
+/// How often progress is shown while packages are being installed.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shows the dashboard and runs commands entered into it until standard input is closed or the
+/// dashboard is quit. The packages installed through it are recorded in `report`.
+pub(crate) async fn run(
+    home: HaspHome,
+    global_opts: &GlobalOpts,
+    report: &mut RunReport,
+) -> Result<i32> {
+    show(&home, global_opts, report).await;
+    // Commands load their own states, whose lifecycle events are all sent to this stream.
+    let mut lifecycle = Box::pin(HaspState::load_or_init(home.clone())?.subscribe());
+    let stdin = io::stdin();
+    loop {
+        print!("{} ", "hasp>".bold());
+        io::stdout().flush().wrap_err("failed to write prompt")?;
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .wrap_err("failed to read command")?
+            == 0
+        {
+            println!();
+            return Ok(0);
+        }
+        match DashboardCommand::parse(&line) {
+            DashboardCommand::Quit => return Ok(0),
+            DashboardCommand::Help => print_help(),
+            DashboardCommand::Refresh => show(&home, global_opts, report).await,
+            DashboardCommand::Search(text) => search(&home, &text)?,
+            DashboardCommand::Hasp(args) => {
+                dispatch(home.clone(), global_opts, report, args).await;
+                show_build_logs(&mut lifecycle);
+            }
+        }
+    }
+}
+
+async fn show(home: &HaspHome, global_opts: &GlobalOpts, report: &mut RunReport) {
+    println!("{}", "Installed packages".bold());
+    dispatch(home.clone(), global_opts, report, words(&["list"])).await;
+    println!();
+    println!("{}", "Recent events".bold());
+    dispatch(
+        home.clone(),
+        global_opts,
+        report,
+        words(&["events", "list", "--limit", RECENT_EVENTS]),
+    )
+    .await;
+    println!();
+    println!("Enter a hasp command, or `help` for what the dashboard accepts");
+}
+
+fn print_help() {
+    println!(
+        "\
+Commands:
+  <hasp command>   run a command, e.g. `install ripgrep@14` to upgrade ripgrep or `hold ripgrep`
+                   to pin it at its version (run `help` with a command for its options)
+  search <text>    print the events in the journal that mention text
+  (empty line)     show the dashboard again
+  quit             leave the dashboard"
+    );
+}
+
+/// Prints the events in the journal that mention some text, ignoring case, oldest first.
+fn search(home: &HaspHome, text: &str) -> Result<()> {
+    let state = HaspState::load_or_init(home.clone())?;
+    let needle = text.to_lowercase();
+    let mut found = false;
+    for entry in state.events(None)? {
+        if let Ok(envelope) = &entry.event {
+            let line = event_line(envelope);
+            if line.to_lowercase().contains(&needle) {
+                println!("{}", line);
+                found = true;
+            }
+        }
+    }
+    if !found {
+        println!("No events mention '{}'", text);
+    }
+    Ok(())
+}
+
+/// Runs a hasp command, as if it were passed on the command line along with the global options
+/// the dashboard was started with.
+async fn dispatch(
+    home: HaspHome,
+    global_opts: &GlobalOpts,
+    report: &mut RunReport,
+    args: Vec<String>,
+) {
+    let command =
+        match Command::from_iter_safe(std::iter::once("hasp".to_owned()).chain(args.clone())) {
+            Ok(Command::Tui) => {
+                println!("Already in the dashboard");
+                return;
+            }
+            Ok(command) => command,
+            // This includes help for commands, which isn't an error here.
+            Err(err) => {
+                println!("{}", err.message);
+                return;
+            }
+        };
+    let installs_packages = command.installs_packages();
+    if installs_packages {
+        events::start_run(report.args.clone());
+    }
+    let mut command_report = RunReport::new(args.clone());
+    // Commands are run from within `hasp tui`, so the future is boxed to break the cycle.
+    let exec = Box::pin(command.exec(home, global_opts, &mut command_report));
+    let result = if installs_packages {
+        let (progress, _guard) = progress::render_elsewhere();
+        with_progress(exec, progress, |event| println!("{}", progress_line(event))).await
+    } else {
+        exec.await
+    };
+    report.extend(command_report);
+    match result {
+        Ok(0) => {}
+        Ok(exit_code) => println!("`hasp {}` exited with {}", args.join(" "), exit_code),
+        Err(err) => {
+            let command = args.join(" ");
+            let error = format!("{:#}", err);
+            tracing::error!(
+                target: "hasp::output::tui::command_failed",
+                %command,
+                %error,
+                "Error `hasp {command}` failed: {error}",
+            )
+        }
+    }
+}
+
+/// Prints where the build logs of installs that failed since the last call are, so that they can
+/// be looked at without searching the journal.
+fn show_build_logs(lifecycle: &mut (impl Stream<Item = LifecycleEvent> + Unpin)) {
+    // Events are sent as they're logged, so every event for the command is already here.
+    while let Some(Some(event)) = lifecycle.next().now_or_never() {
+        if let LifecycleEvent::InstallFailed(failed) = event {
+            println!(
+                "Build log for {}: {}",
+                NameVersionDisplay::dir_version(&failed.package.name, &failed.package.version),
+                failed.build_log
+            );
+        }
+    }
+}
+
+/// Runs a command, showing the progress of the packages it installs as it goes.
+///
+/// Builds can block the thread the command runs on, so progress is shown from another thread.
+async fn with_progress<T>(
+    command: impl Future<Output = T>,
+    progress: Receiver<ProgressEvent>,
+    mut show: impl FnMut(&ProgressEvent) + Send + 'static,
+) -> T {
+    let done = Arc::new(AtomicBool::new(false));
+    let renderer = thread::spawn({
+        let done = done.clone();
+        move || {
+            while !done.load(Ordering::SeqCst) {
+                if let Ok(event) = progress.recv_timeout(PROGRESS_INTERVAL) {
+                    show(&event);
+                }
+            }
+            progress.try_iter().for_each(|event| show(&event));
+        }
+    });
+    let result = command.await;
+    done.store(true, Ordering::SeqCst);
+    // The renderer only panics if showing progress does, which leaves nothing to clean up.
+    let _ = renderer.join();
+    result
+}
+
+/// Formats a progress event as a line, e.g. `[ 50%] Building ripgrep v14.1.0`.
+fn progress_line(event: &ProgressEvent) -> String {
+    let phase = match event.phase {
+        ProgressPhase::Resolving => "Resolving".blue(),
+        ProgressPhase::Fetching => "Fetching".blue(),
+        ProgressPhase::Building => "Building".blue(),
+        ProgressPhase::Installed => "Installed".green(),
+        ProgressPhase::AlreadyInstalled => "Up-to-date".purple(),
+        ProgressPhase::Failed => "Failed".red(),
+    };
+    let version = event
+        .version
+        .as_ref()
+        .map(|version| format!(" v{}", version))
+        .unwrap_or_default();
+    format!(
+        "[{:>3}%] {} {}{}",
+        event.percent,
+        phase.bold(),
+        event.package,
+        version
+    )
+}
+
+fn words(words: &[&str]) -> Vec<String> {
+    words.iter().map(|&word| word.to_owned()).collect()
+}
+
+/// A line entered into the dashboard.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum DashboardCommand {
+    Quit,
+    Help,
+    Refresh,
+    Search(String),
+    /// A hasp command, split into arguments.
+    Hasp(Vec<String>),
+}
+
+impl DashboardCommand {
+    fn parse(line: &str) -> Self {
+        let line = line.trim();
+        match line.split_once(char::is_whitespace) {
+            Some(("search", text)) => return DashboardCommand::Search(text.trim().to_owned()),
+            Some(("help", command)) => {
+                return DashboardCommand::Hasp(
+                    command
+                        .split_whitespace()
+                        .map(str::to_owned)
+                        .chain(std::iter::once("--help".to_owned()))
+                        .collect(),
+                )
+            }
+            _ => {}
+        }
+        match line {
+            "" => DashboardCommand::Refresh,
+            "quit" | "exit" | "q" => DashboardCommand::Quit,
+            "help" | "?" => DashboardCommand::Help,
+            _ => DashboardCommand::Hasp(line.split_whitespace().map(str::to_owned).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::progress::strip_ansi;
+    use std::sync::{mpsc, Mutex};
+
+    #[test]
+    fn parse_dashboard_commands() {
+        assert_eq!(DashboardCommand::parse("\n"), DashboardCommand::Refresh);
+        assert_eq!(DashboardCommand::parse(" quit\n"), DashboardCommand::Quit);
+        assert_eq!(DashboardCommand::parse("help"), DashboardCommand::Help);
+        assert_eq!(
+            DashboardCommand::parse("search  install_failed ripgrep\n"),
+            DashboardCommand::Search("install_failed ripgrep".to_owned())
+        );
+        assert_eq!(
+            DashboardCommand::parse("help hold"),
+            DashboardCommand::Hasp(words(&["hold", "--help"]))
+        );
+        assert_eq!(
+            DashboardCommand::parse("install  ripgrep@14 --keep-going\n"),
+            DashboardCommand::Hasp(words(&["install", "ripgrep@14", "--keep-going"]))
+        );
+        assert!(
+            Command::from_iter_safe(words(&["hasp", "tui"])).is_ok(),
+            "the dashboard is a command"
+        );
+    }
+
+    #[test]
+    fn progress_lines() {
+        let mut event = ProgressEvent {
+            package: "npm:prettier".to_owned(),
+            version: None,
+            phase: ProgressPhase::Resolving,
+            percent: 0,
+        };
+        assert_eq!(
+            strip_ansi(&progress_line(&event)),
+            "[  0%] Resolving npm:prettier"
+        );
+        event.version = Some("3.0.0".to_owned());
+        event.phase = ProgressPhase::Installed;
+        event.percent = 50;
+        assert_eq!(
+            strip_ansi(&progress_line(&event)),
+            "[ 50%] Installed npm:prettier v3.0.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn progress_shown_while_installing() {
+        let shown = Arc::new(Mutex::new(vec![]));
+        let (sender, receiver) = mpsc::channel();
+        let command = {
+            let shown = shown.clone();
+            async move {
+                let event = |phase| ProgressEvent {
+                    package: "ripgrep".to_owned(),
+                    version: Some("14.1.0".to_owned()),
+                    phase,
+                    percent: 0,
+                };
+                sender
+                    .send(event(ProgressPhase::Building))
+                    .expect("receiver is alive");
+                tokio::time::sleep(PROGRESS_INTERVAL * 3).await;
+                let shown_while_running = shown.lock().expect("lock isn't poisoned").len();
+                sender
+                    .send(event(ProgressPhase::Installed))
+                    .expect("receiver is alive");
+                shown_while_running
+            }
+        };
+
+        let shown_while_running = with_progress(command, receiver, {
+            let shown = shown.clone();
+            move |event| shown.lock().expect("lock isn't poisoned").push(event.phase)
+        })
+        .await;
+        assert_eq!(shown_while_running, 1, "progress is shown as it happens");
+        assert_eq!(
+            *shown.lock().expect("lock isn't poisoned"),
+            [ProgressPhase::Building, ProgressPhase::Installed],
+            "progress sent at the end is shown"
+        );
+    }
+}
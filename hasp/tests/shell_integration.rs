@@ -0,0 +1,257 @@
+// Copyright (c) The hasp Contributors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! End-to-end tests that run installed binaries through their shims from real shells.
+//!
+//! Each test builds a tiny fixture crate, records it in a fake `$CARGO_HOME` as if it had been
+//! installed through `cargo install`, and imports it into a hasp home within a temporary `HOME`.
+//! The fixture is then run by name from each shell that's available on this system, with only the
+//! hasp bin directory added to `PATH`. Shells that aren't installed are skipped.
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+use tempfile::TempDir;
+
+const FIXTURE_NAME: &str = "hasp-fixture";
+
+/// The fixture prints out its arguments, so that tests can check they're passed through intact.
+const FIXTURE_MAIN: &str = r#"fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    println!("fixture: {}", args.join("|"));
+}
+"#;
+
+struct Fixture {
+    temp_dir: TempDir,
+}
+
+impl Fixture {
+    /// Builds the fixture crate and imports it into a fresh hasp home.
+    fn new() -> Self {
+        let temp_dir = tempfile::tempdir().expect("tempdir created");
+        let fixture = Self { temp_dir };
+        fs::create_dir_all(fixture.home()).expect("home created");
+
+        let binary = fixture.build_crate();
+        let cargo_bin_dir = fixture.cargo_home().join("bin");
+        fs::create_dir_all(&cargo_bin_dir).expect("cargo bin dir created");
+        let file_name = binary.file_name().expect("binary has a file name");
+        fs::copy(&binary, cargo_bin_dir.join(file_name)).expect("binary copied");
+        let crates2 = serde_json::json!({
+            "installs": {
+                format!(
+                    "{} 0.1.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    FIXTURE_NAME
+                ): {
+                    "bins": [file_name.to_str().expect("file name is UTF-8")],
+                },
+            },
+        });
+        fs::write(
+            fixture.cargo_home().join(".crates2.json"),
+            crates2.to_string(),
+        )
+        .expect(".crates2.json written");
+
+        let output = fixture.hasp(&["import"]);
+        assert_success("hasp import", &output);
+        fixture
+    }
+
+    fn home(&self) -> PathBuf {
+        self.temp_dir.path().join("home")
+    }
+
+    fn cargo_home(&self) -> PathBuf {
+        self.temp_dir.path().join("cargo-home")
+    }
+
+    /// The bin directory within the default hasp home, `~/.hasp`.
+    fn bin_dir(&self) -> PathBuf {
+        self.home().join(".hasp").join("bin")
+    }
+
+    fn build_crate(&self) -> PathBuf {
+        let crate_dir = self.temp_dir.path().join("fixture");
+        fs::create_dir_all(crate_dir.join("src")).expect("fixture src created");
+        fs::write(
+            crate_dir.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+                FIXTURE_NAME
+            ),
+        )
+        .expect("Cargo.toml written");
+        fs::write(crate_dir.join("src").join("main.rs"), FIXTURE_MAIN).expect("main.rs written");
+
+        let target_dir = self.temp_dir.path().join("target");
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let output = Command::new(cargo)
+            .args(["build", "--offline", "--quiet", "--manifest-path"])
+            .arg(crate_dir.join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .output()
+            .expect("cargo build ran");
+        assert_success("cargo build", &output);
+
+        target_dir
+            .join("debug")
+            .join(format!("{}{}", FIXTURE_NAME, env::consts::EXE_SUFFIX))
+    }
+
+    /// Runs hasp with the temporary home directory.
+    fn hasp(&self, args: &[&str]) -> Output {
+        self.command(env!("CARGO_BIN_EXE_hasp"))
+            .args(args)
+            .output()
+            .expect("hasp ran")
+    }
+
+    /// Returns a command that runs within the temporary home directory, with the hasp bin
+    /// directory at the front of `PATH`.
+    fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        command
+            .env("HOME", self.home())
+            .env("USERPROFILE", self.home())
+            .env("CARGO_HOME", self.cargo_home())
+            .env("PATH", self.path_var())
+            .env_remove("HASP_HOME")
+            .current_dir(self.home());
+        command
+    }
+
+    fn path_var(&self) -> OsString {
+        let path = env::var_os("PATH").unwrap_or_default();
+        let dirs = std::iter::once(self.bin_dir()).chain(env::split_paths(&path));
+        env::join_paths(dirs).expect("PATH joined")
+    }
+
+    /// Runs the fixture through its shim from the shell at `program`, returning its output.
+    fn run_in_shell(&self, shell: Shell, program: &Path) -> String {
+        let output = self
+            .command(program)
+            .args(shell.args())
+            .arg(shell.script())
+            .output()
+            .expect("shell ran");
+        assert_success(shell.program(), &output);
+        String::from_utf8(output.stdout).expect("output is UTF-8")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Shell {
+    Bash,
+    Zsh,
+    Pwsh,
+}
+
+impl Shell {
+    fn program(self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Pwsh => "pwsh",
+        }
+    }
+
+    /// Arguments that skip user and system startup files, which could change `PATH`.
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Shell::Bash => &["--noprofile", "--norc", "-c"],
+            Shell::Zsh => &["-f", "-c"],
+            Shell::Pwsh => &["-NoProfile", "-NonInteractive", "-Command"],
+        }
+    }
+
+    /// A script that checks the fixture resolves to its shim, then runs it.
+    fn script(self) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => format!(
+                "printf 'resolved: %s\\n' \"$(command -v {0})\" && {0} 'hello world' again",
+                FIXTURE_NAME
+            ),
+            Shell::Pwsh => format!(
+                "Write-Output \"resolved: $((Get-Command {0}).Source)\"; \
+                 & {0} 'hello world' again; exit $LASTEXITCODE",
+                FIXTURE_NAME
+            ),
+        }
+    }
+}
+
+fn find_program(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path).find_map(|dir| {
+        let candidate = dir.join(format!("{}{}", name, env::consts::EXE_SUFFIX));
+        candidate.is_file().then_some(candidate)
+    })
+}
+
+fn assert_success(what: &str, output: &Output) {
+    assert!(
+        output.status.success(),
+        "{} failed with {}\n--- stdout:\n{}\n--- stderr:\n{}",
+        what,
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr),
+    );
+}
+
+fn check_shell(shell: Shell) {
+    let program = match find_program(shell.program()) {
+        Some(program) => program,
+        None => {
+            eprintln!("skipping: {} is not installed", shell.program());
+            return;
+        }
+    };
+    let fixture = Fixture::new();
+    let stdout = fixture.run_in_shell(shell, &program);
+
+    let mut lines = stdout.lines();
+    let resolved = lines.next().expect("resolved line printed");
+    let resolved = Path::new(
+        resolved
+            .strip_prefix("resolved: ")
+            .expect("resolved line has prefix"),
+    );
+    assert!(
+        resolved.starts_with(fixture.bin_dir()),
+        "{} should resolve to the shim in {}, but resolved to {}",
+        FIXTURE_NAME,
+        fixture.bin_dir().display(),
+        resolved.display(),
+    );
+    assert_eq!(lines.next(), Some("fixture: hello world|again"));
+}
+
+#[test]
+fn shim_runs_from_bash() {
+    check_shell(Shell::Bash);
+}
+
+#[test]
+fn shim_runs_from_zsh() {
+    check_shell(Shell::Zsh);
+}
+
+#[test]
+fn shim_runs_from_powershell() {
+    check_shell(Shell::Pwsh);
+}
+
+#[test]
+fn doctor_passes_with_bin_dir_on_path() {
+    let fixture = Fixture::new();
+    let output = fixture.hasp(&["doctor"]);
+    assert_success("hasp doctor", &output);
+}